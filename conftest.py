@@ -146,6 +146,10 @@ def db(request, pgsql):
 
     db_.database_init()
 
+    from sogs.model import presence
+
+    presence.clear()
+
     web.appdb = db_.get_conn()
 
     yield db_
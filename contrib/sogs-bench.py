@@ -0,0 +1,415 @@
+#!/usr/bin/env python3
+
+# Load test scenario runner: simulates a number of Session clients using a room of a running SOGS,
+# each starting up (fetching capabilities, room info, and recent messages in one /sequence request,
+# as Session does) and then polling for new messages and occasionally posting, with exponentially
+# distributed delays between actions.  A fraction of the requests can be sent as v4 onion requests
+# (posted directly to the server's /oxen/v4/lsrpc endpoint, as the last snode of an onion path
+# does) to include the cost of onion request decryption.
+#
+# At the end it reports the request rate and, for each kind of request, the latency percentiles,
+# error rate, and response status codes, for capacity planning and performance regression testing.
+#
+# Example:
+#
+#     contrib/sogs-bench.py --url http://localhost:8080 --room test \
+#         --server-pubkey c3b3c6f32f0ab5a57f853cc4f30f5da7fda5624b0c77b3fb0829de562ada081d \
+#         --clients 100 --duration 300 --onion 0.5
+#
+# With --soak the clients also delete their own messages and react to messages, and check
+# invariants of what they see as they go: message seqnos from polling must always increase, deleted
+# messages must never come back, and every deletion must reach every client that was polling at the
+# time.  At the end (after the load stops) each client's incrementally built view of the room is
+# also compared against a fresh poll of the same range from the server, to catch updates that never
+# got a new seqno or caches that went stale.  Violations are printed as they are found and make the
+# script exit with status 1.  This is meant to be left running for a long time, e.g.:
+#
+#     contrib/sogs-bench.py --url http://localhost:8080 --room test --server-pubkey ... --soak \
+#         --clients 50 --duration 86400 --post-fraction 0.2 --delete-fraction 0.1 \
+#         --react-fraction 0.1
+#
+# Posting clients will be rate-limited by the server's post rate limits (showing up as 429 errors)
+# unless these are relaxed for the test server.  Requires the `requests` and `PyNaCl` modules.
+
+from base64 import b64encode
+from collections import Counter, defaultdict
+from hashlib import blake2b, sha512
+import argparse
+import json
+import random
+import statistics
+import struct
+import sys
+import threading
+import time
+
+import nacl.bindings as sodium
+from nacl.public import PrivateKey
+from nacl.signing import SigningKey
+import nacl.utils
+import requests
+
+
+parser = argparse.ArgumentParser(description="SOGS load test scenario runner")
+parser.add_argument('--url', required=True, help="Base URL of the server, e.g. http://sogs:8080")
+parser.add_argument('--server-pubkey', required=True, help="Server X25519 pubkey (hex)")
+parser.add_argument('--room', required=True, help="Token of the room to use")
+parser.add_argument('--clients', type=int, default=20, help="Number of simulated clients")
+parser.add_argument('--duration', type=float, default=60, help="Test duration, in seconds")
+parser.add_argument(
+    '--poll-interval', type=float, default=5, help="Mean time between client actions, in seconds"
+)
+parser.add_argument(
+    '--post-fraction', type=float, default=0.05, help="Fraction of client actions that are posts"
+)
+parser.add_argument(
+    '--delete-fraction',
+    type=float,
+    default=0,
+    help="Fraction of client actions that delete one of the client's own messages",
+)
+parser.add_argument(
+    '--react-fraction', type=float, default=0, help="Fraction of client actions that add a reaction"
+)
+parser.add_argument(
+    '--message-size', type=int, default=300, help="Median size of posted messages, in bytes"
+)
+parser.add_argument(
+    '--onion', type=float, default=0, help="Fraction of requests sent as v4 onion requests"
+)
+parser.add_argument('--unblinded', action='store_true', help="Use unblinded (05) session ids")
+parser.add_argument('--soak', action='store_true', help="Check invariants of what clients see")
+parser.add_argument('--seed', type=int, help="Random seed, for reproducible scenarios")
+parser.add_argument('--json', action='store_true', help="Print the results as JSON")
+args = parser.parse_args()
+
+server_pk = bytes.fromhex(args.server_pubkey)
+base_url = args.url.rstrip('/')
+reactions = ('👍', '❤️', '😂', '🎉', '🍆')
+
+violations = []
+violations_lock = threading.Lock()
+
+
+def violation(what):
+    with violations_lock:
+        violations.append(what)
+    print(f"INVARIANT VIOLATION: {what}", file=sys.stderr)
+
+
+def blinded_keys(s: SigningKey):
+    # See contrib/auth-example.py for an explanation of blinded key derivation and signing
+    k = sodium.crypto_core_ed25519_scalar_reduce(blake2b(server_pk, digest_size=64).digest())
+    ka = sodium.crypto_core_ed25519_scalar_mul(k, s.to_curve25519_private_key().encode())
+    return ka, sodium.crypto_scalarmult_ed25519_base_noclamp(ka)
+
+
+def blinded_sign(msg: bytes, s: SigningKey, ka: bytes, kA: bytes):
+    H_rh = sha512(s.encode()).digest()[32:]
+    r = sodium.crypto_core_ed25519_scalar_reduce(sha512(H_rh + kA + msg).digest())
+    sig_R = sodium.crypto_scalarmult_ed25519_base_noclamp(r)
+    HRAM = sodium.crypto_core_ed25519_scalar_reduce(sha512(sig_R + kA + msg).digest())
+    return sig_R + sodium.crypto_core_ed25519_scalar_add(
+        r, sodium.crypto_core_ed25519_scalar_mul(HRAM, ka)
+    )
+
+
+class Client:
+    def __init__(self, rng):
+        self.rng = rng
+        self.key = SigningKey.generate()
+        if args.unblinded:
+            self.pubkey = '00' + self.key.verify_key.encode().hex()
+            self.sign = lambda msg: self.key.sign(msg).signature
+        else:
+            ka, kA = blinded_keys(self.key)
+            self.pubkey = '15' + kA.hex()
+            self.sign = lambda msg: blinded_sign(msg, self.key, ka, kA)
+
+        # Ephemeral onion request key, and the resulting xchacha20 shared key H(aB || A || B):
+        a = PrivateKey.generate()
+        self.onion_pubkey = a.public_key.encode()
+        self.onion_key = blake2b(
+            sodium.crypto_scalarmult(a.encode(), server_pk) + self.onion_pubkey + server_pk,
+            digest_size=32,
+        ).digest()
+
+        self.http = requests.Session()
+        self.seqno = 0
+        self.started = None  # When startup finished (and so polling from self.start_seqno began)
+        self.start_seqno = 0
+        self.view = {}  # Message id => latest update seen (with --soak)
+        self.posted = []  # Ids of our not-yet-deleted messages
+        self.deleted = []  # (time, id) of our deletions
+        self.reacted = set()  # (id, reaction) we have added
+
+    def auth_headers(self, method, path, body):
+        nonce, ts = nacl.utils.random(16), str(int(time.time()))
+        to_sign = server_pk + nonce + ts.encode() + method.encode() + path.encode()
+        if body:
+            to_sign += blake2b(body, digest_size=64).digest()
+        return {
+            'X-SOGS-Pubkey': self.pubkey,
+            'X-SOGS-Timestamp': ts,
+            'X-SOGS-Nonce': b64encode(nonce).decode(),
+            'X-SOGS-Signature': b64encode(self.sign(to_sign)).decode(),
+        }
+
+    def request(self, method, path, js=None):
+        """Makes a request (possibly as an onion request); returns the status code and json body"""
+        body = json.dumps(js).encode() if js is not None else None
+        headers = self.auth_headers(method, path, body)
+        if body is not None:
+            headers['Content-Type'] = 'application/json'
+
+        if self.rng.random() >= args.onion:
+            r = self.http.request(method, base_url + path, headers=headers, data=body)
+            return r.status_code, r.json() if r.ok else None
+
+        meta = json.dumps({'method': method, 'endpoint': path, 'headers': headers}).encode()
+        parts = (meta,) if body is None else (meta, body)
+        inner = b'l' + b''.join(str(len(p)).encode() + b':' + p for p in parts) + b'e'
+        nonce = nacl.utils.random(24)
+        enc = nonce + sodium.crypto_aead_xchacha20poly1305_ietf_encrypt(
+            inner, None, nonce, self.onion_key
+        )
+        outer = json.dumps(
+            {'ephemeral_key': self.onion_pubkey.hex(), 'enc_type': 'xchacha20'}
+        ).encode()
+        r = self.http.post(
+            base_url + '/oxen/v4/lsrpc', data=struct.pack('<i', len(enc)) + enc + outer
+        )
+        if not r.ok:
+            return r.status_code, None
+        reply = sodium.crypto_aead_xchacha20poly1305_ietf_decrypt(
+            r.content[24:], None, r.content[:24], self.onion_key
+        )
+        # Reply is l{N}:{json}{M}:{body}e
+        meta_len, rest = reply[1:].split(b':', 1)
+        meta, rest = json.loads(rest[: int(meta_len)]), rest[int(meta_len) :]
+        body_len, rest = rest.split(b':', 1)
+        code = meta['code']
+        return code, json.loads(rest[: int(body_len)]) if 200 <= code < 300 else None
+
+    def startup(self):
+        code, res = self.request(
+            'POST',
+            '/sequence',
+            [
+                {'method': 'GET', 'path': '/capabilities'},
+                {'method': 'GET', 'path': f'/room/{args.room}'},
+                {'method': 'GET', 'path': f'/room/{args.room}/messages/recent'},
+            ],
+        )
+        if res:
+            for sub in res:
+                if sub['code'] != 200:
+                    return sub['code']
+            self.seqno = res[1]['body']['message_sequence']
+        self.start_seqno = self.seqno
+        self.started = time.time()
+        return code
+
+    def fetch_since(self, seqno):
+        """Polls for all updates since `seqno`; returns the status code and the updates"""
+        updates = []
+        while True:
+            code, msgs = self.request(
+                'GET', f'/room/{args.room}/messages/since/{seqno}?t=r&limit=256'
+            )
+            if not msgs:
+                return code, updates
+            updates += msgs
+            seqno = msgs[-1]['seqno']
+            if len(msgs) < 256:
+                return code, updates
+
+    def poll(self):
+        code, msgs = self.fetch_since(self.seqno)
+        if args.soak:
+            apply_updates(self.view, self.seqno, msgs, f"client {self.pubkey[:8]}")
+        for m in msgs:
+            self.seqno = max(self.seqno, m['seqno'])
+        return code
+
+    def post(self):
+        size = max(1, int(self.rng.lognormvariate(0, 0.75) * args.message_size))
+        data = nacl.utils.random(size)
+        js = {'data': b64encode(data).decode(), 'signature': b64encode(self.sign(data)).decode()}
+        code, msg = self.request('POST', f'/room/{args.room}/message', js)
+        if msg:
+            self.posted.append(msg['id'])
+        return code
+
+    def delete(self):
+        if not self.posted:
+            return self.poll()
+        msg_id = self.posted.pop(self.rng.randrange(len(self.posted)))
+        sent = time.time()
+        code, _ = self.request('DELETE', f'/room/{args.room}/message/{msg_id}')
+        if code == 200:
+            self.deleted.append((sent, msg_id))
+        return code
+
+    def react(self):
+        live = [i for i, m in self.view.items() if not m.get('deleted')] or self.posted
+        if not live:
+            return self.poll()
+        msg_id, reaction = self.rng.choice(live), self.rng.choice(reactions)
+        code, res = self.request('PUT', f'/room/{args.room}/reaction/{msg_id}/{reaction}', {})
+        if res:
+            self.reacted.add((msg_id, reaction))
+        return code
+
+
+def apply_updates(view, since, updates, who):
+    """
+    Applies polled message updates to `view` (a dict of message id => latest update), checking that
+    the updates are consistent with one another and with what was already in `view`.
+    """
+    last = since
+    for m in updates:
+        msg_id, seqno = m['id'], m['seqno']
+        if seqno <= last:
+            violation(f"{who}: message {msg_id} update has seqno {seqno} after seqno {last}")
+        last = max(last, seqno)
+        old = view.get(msg_id)
+        if old is not None:
+            if seqno <= old['seqno']:
+                violation(f"{who}: message {msg_id} seqno went from {old['seqno']} to {seqno}")
+            if old.get('deleted') and m.get('data') is not None:
+                violation(f"{who}: deleted message {msg_id} came back at seqno {seqno}")
+        if 'data' in m or old is None:
+            view[msg_id] = m
+        else:
+            # A reaction-only update: the message itself is unchanged
+            view[msg_id] = {**old, 'seqno': seqno, 'reactions': m.get('reactions')}
+
+
+def check_final(client, all_deletions):
+    """Checks a client's view of the room, once all clients have stopped"""
+    who = f"client {client.pubkey[:8]}"
+    if client.started is None:
+        return
+    client.poll()
+
+    for sent, msg_id in all_deletions:
+        if sent > client.started and not client.view.get(msg_id, {}).get('deleted'):
+            violation(f"{who}: never saw deletion of message {msg_id}")
+
+    for msg_id, reaction in client.reacted:
+        m = client.view.get(msg_id)
+        if m is not None and not m.get('deleted'):
+            if not (m.get('reactions') or {}).get(reaction, {}).get('you'):
+                violation(f"{who}: message {msg_id} is missing our {reaction} reaction")
+
+    code, updates = client.fetch_since(client.start_seqno)
+    if code != 200:
+        violation(f"{who}: final fetch failed with status {code}")
+        return
+    fresh = {}
+    apply_updates(fresh, client.start_seqno, updates, f"{who} (final fetch)")
+    for msg_id in sorted(fresh.keys() | client.view.keys()):
+        if fresh.get(msg_id) != client.view.get(msg_id):
+            violation(
+                f"{who}: message {msg_id} is {fresh.get(msg_id)} on the server but polling gave "
+                f"{client.view.get(msg_id)}"
+            )
+
+
+results = defaultdict(list)  # action => [(latency, status code or exception name)]
+results_lock = threading.Lock()
+
+
+def run_client(client, deadline):
+    def timed(name, f):
+        start = time.perf_counter()
+        try:
+            outcome = f()
+        except Exception as e:
+            outcome = type(e).__name__
+        with results_lock:
+            results[name].append((time.perf_counter() - start, outcome))
+
+    timed('startup', client.startup)
+    while True:
+        time.sleep(client.rng.expovariate(1 / args.poll_interval))
+        if time.time() >= deadline:
+            break
+        x = client.rng.random()
+        if x < args.post_fraction:
+            timed('post', client.post)
+        elif x < args.post_fraction + args.delete_fraction:
+            timed('delete', client.delete)
+        elif x < args.post_fraction + args.delete_fraction + args.react_fraction:
+            timed('react', client.react)
+        else:
+            timed('poll', client.poll)
+
+
+rng = random.Random(args.seed)
+clients = [Client(random.Random(rng.random())) for _ in range(args.clients)]
+start = time.time()
+deadline = start + args.duration
+threads = [threading.Thread(target=run_client, args=(c, deadline), daemon=True) for c in clients]
+for t in threads:
+    # Stagger client startup (real clients don't all start at once):
+    time.sleep(rng.uniform(0, args.poll_interval / max(args.clients, 1)))
+    t.start()
+for t in threads:
+    t.join()
+elapsed = time.time() - start
+
+if args.soak:
+    deletions = [d for c in clients for d in c.deleted]
+    for c in clients:
+        try:
+            check_final(c, deletions)
+        except Exception as e:
+            violation(f"client {c.pubkey[:8]}: final check failed: {e!r}")
+
+
+def percentile(sorted_values, p):
+    return sorted_values[min(int(p / 100 * len(sorted_values)), len(sorted_values) - 1)]
+
+
+report = {'clients': args.clients, 'seconds': elapsed, 'requests': 0, 'actions': {}}
+for name, rs in sorted(results.items()):
+    latencies = sorted(lat * 1000 for lat, _ in rs)
+    outcomes = Counter(str(o) for _, o in rs)
+    errors = sum(n for o, n in outcomes.items() if not (o.isdigit() and 200 <= int(o) < 300))
+    report['requests'] += len(rs)
+    report['actions'][name] = {
+        'count': len(rs),
+        'error_rate': errors / len(rs),
+        'mean_ms': statistics.mean(latencies),
+        **{f'p{p}_ms': percentile(latencies, p) for p in (50, 90, 99)},
+        'max_ms': latencies[-1],
+        'outcomes': dict(outcomes),
+    }
+report['requests_per_second'] = report['requests'] / elapsed
+if args.soak:
+    report['violations'] = violations
+
+if args.json:
+    print(json.dumps(report, indent=2))
+    sys.exit(1 if violations else 0)
+
+print(
+    f"{report['requests']} requests from {args.clients} clients in {elapsed:.1f}s "
+    f"({report['requests_per_second']:.1f} requests/s)\n"
+)
+print(
+    f"{'action':<8} {'count':>7} {'errors':>7} {'mean':>8} {'p50':>8} {'p90':>8} {'p99':>8} "
+    f"{'max':>8}"
+)
+for name, a in report['actions'].items():
+    print(
+        f"{name:<8} {a['count']:>7} {a['error_rate']:>7.1%} {a['mean_ms']:>6.1f}ms "
+        f"{a['p50_ms']:>6.1f}ms {a['p90_ms']:>6.1f}ms {a['p99_ms']:>6.1f}ms {a['max_ms']:>6.1f}ms"
+    )
+    print("         outcomes: " + ', '.join(f"{o}: {n}" for o, n in sorted(a['outcomes'].items())))
+
+if args.soak:
+    print(f"\n{len(violations)} invariant violations")
+    sys.exit(1 if violations else 0)
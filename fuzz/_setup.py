@@ -0,0 +1,6 @@
+# Common setup for the fuzz targets: loads sogs without a database (the targets only exercise
+# parsing code), as the test suite does.
+from sogs import config
+
+config.DB_URL = 'defer-init'
+config.REQUIRE_BLIND_KEYS = False
@@ -0,0 +1,45 @@
+#!/usr/bin/env python3
+"""
+Fuzz target for the parsing of X-SOGS-* auth headers.  The input is split on newlines into the
+Pubkey, Nonce, Timestamp, and Signature header values; an invalid request must be rejected with an
+HTTP error (via abort), never an unexpected exception.
+"""
+
+import atheris
+import sys
+
+with atheris.instrument_imports():
+    import _setup  # noqa: F401
+    from sogs.routes import auth
+    from sogs.web import app
+    from werkzeug.exceptions import HTTPException
+
+
+class Parsed(Exception):
+    """Raised in place of looking up the user, which is where header parsing ends."""
+
+
+def _parsed(*args, **kwargs):
+    raise Parsed()
+
+
+auth.User = _parsed
+
+
+def TestOneInput(data: bytes):
+    values = data.decode('latin-1').split('\n', 3)
+    headers = {
+        f'X-SOGS-{h}': v
+        for h, v in zip(auth.SessionAuth.headers, values)
+        if '\r' not in v and '\n' not in v
+    }
+    with app.test_request_context('/', headers=headers):
+        try:
+            auth.SessionAuth().authenticate()
+        except (HTTPException, Parsed):
+            pass
+
+
+if __name__ == '__main__':
+    atheris.Setup(sys.argv, TestOneInput)
+    atheris.Fuzz()
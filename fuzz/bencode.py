@@ -0,0 +1,29 @@
+#!/usr/bin/env python3
+"""Fuzz target for the bencoded string parser and the JSON decoding of onion request metadata."""
+
+import atheris
+import json
+import sys
+
+with atheris.instrument_imports():
+    import _setup  # noqa: F401
+    from sogs import utils
+
+
+def TestOneInput(data: bytes):
+    rest = memoryview(data)
+    while len(rest):
+        try:
+            s, rest = utils.bencode_consume_string(rest)
+        except ValueError:
+            return
+        assert len(s) + len(rest) < len(data)
+        try:
+            json.loads(s.tobytes())
+        except ValueError:
+            pass
+
+
+if __name__ == '__main__':
+    atheris.Setup(sys.argv, TestOneInput)
+    atheris.Fuzz()
@@ -0,0 +1,24 @@
+#!/usr/bin/env python3
+"""Fuzz target for the decrypted v4 onion request envelope (bencoded metadata JSON and body)."""
+
+import atheris
+import sys
+
+with atheris.instrument_imports():
+    import _setup  # noqa: F401
+    from sogs.routes import onion_request
+
+
+def TestOneInput(data: bytes):
+    try:
+        meta, body = onion_request.parse_v4_onionreq_plaintext(data)
+    except ValueError:
+        return
+    assert isinstance(meta['method'], str) and meta['endpoint'].startswith('/')
+    assert isinstance(meta['headers'], dict)
+    assert len(body) <= len(data)
+
+
+if __name__ == '__main__':
+    atheris.Setup(sys.argv, TestOneInput)
+    atheris.Fuzz()
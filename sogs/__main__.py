@@ -1,5 +1,7 @@
 from argparse import ArgumentParser as AP, RawDescriptionHelpFormatter, Action
 import atexit
+import getpass
+import os
 import re
 import sys
 
@@ -64,6 +66,19 @@ ap.add_argument(
     action=CrudeStringUnescape,
     help="Sets or updates a room's description (with --add-room or --rooms)",
 )
+ap.add_argument(
+    '--tags',
+    nargs='*',
+    metavar='TAG',
+    help="Sets or replaces a room's directory tags, such as languages or topics (with --add-room "
+    "or --rooms); give no tags to remove all tags",
+)
+ap.add_argument(
+    '--ignore-limits',
+    action='store_true',
+    help="With --add-room, create the room even if the server already has the maximum number of "
+    "rooms ([rooms].max_rooms)",
+)
 ap.add_argument('--delete-room', help="Delete the room with the given token", metavar='TOKEN')
 ap.add_argument(
     '--add-moderators',
@@ -134,6 +149,74 @@ ap.add_argument(
 ap.add_argument(
     '--list-global-mods', '-M', action='store_true', help="List global moderators/admins"
 )
+ap.add_argument(
+    '--file-stats', action='store_true', help="Show attachment storage statistics by storage tier"
+)
+ap.add_argument(
+    '--compress-messages',
+    nargs='+',
+    metavar='TOKEN',
+    help="Train a new compression dictionary for each of the given rooms (or all rooms, if the "
+    "single token '*' is given) and recompress the room's stored messages with it.  New messages "
+    "are compressed with the dictionary if [messages].compression is enabled.",
+)
+ap.add_argument(
+    '--restore-journal',
+    metavar='PATH',
+    help="Replay the event journal at PATH (see [journal] in the config) on top of a database "
+    "restored from a backup.  Events recorded after the most recent message activity in the "
+    "database are replayed, and room checkpoints recorded in the journal are verified.",
+)
+ap.add_argument(
+    '--restore-until',
+    metavar='TIMESTAMP',
+    type=float,
+    help="Used with --restore-journal to stop replaying at the given unix timestamp",
+)
+ap.add_argument(
+    '--restore-since',
+    metavar='TIMESTAMP',
+    type=float,
+    help="Used with --restore-journal to replay events after the given unix timestamp, rather "
+    "than after the most recent message activity in the database",
+)
+ap.add_argument(
+    '--migrate-key',
+    metavar='PATH',
+    help="Store the raw private key file PATH (e.g. an existing key_x25519) in the key storage "
+    "configured by [crypto].key_storage, such as an encrypted key file or PKCS#11 token",
+)
+ap.add_argument(
+    '--relocate',
+    nargs=2,
+    metavar=('WHAT', 'PATH'),
+    help="Copy server data to a new location, e.g. on another disk: WHAT is one of `db` (the "
+    "SQLite database), `uploads` (the uploads directory), `cold` (the cold storage directory), or "
+    "`journal` (the event journal).  The stored paths of copied uploads are updated in the "
+    "database.  The server should be stopped first, and the corresponding config setting updated "
+    "before restarting it.  The original data is not removed.",
+)
+ap.add_argument(
+    '--backup',
+    metavar='DIR',
+    help="Create a backup in the new directory DIR: a consistent copy of the SQLite database, made "
+    "while the server keeps running, plus a snapshot of the uploaded files",
+)
+ap.add_argument(
+    '--import-backup',
+    metavar='DIR',
+    help="Import the rooms of the backup in DIR (created with --backup, e.g. on another server) "
+    "into this server: their settings, messages, moderators, and bans, plus the backup's global "
+    "moderators and bans.  None of the backup's rooms may already exist on this server.",
+)
+ap.add_argument(
+    '--keep-ids',
+    action='store_true',
+    help="With --import-backup: keep the backup's message and file ids (and sequence counters) so "
+    "that clients of the old server can carry on where they left off, and also import deleted "
+    "and system messages, reactions, pinned messages, and attachments.  For moving a whole "
+    "server's rooms onto a new server that takes over the old server's URL and keys.",
+)
 ap.add_argument(
     "--verbose",
     "-v",
@@ -143,6 +226,13 @@ ap.add_argument(
 ap.add_argument(
     "--yes", action='store_true', help="Don't prompt for confirmation for some commands, just do it"
 )
+ap.add_argument(
+    "--actor",
+    help="Session ID of the global admin running this command, recorded in the journal (and event "
+    "hooks) and in system messages as the `actor` of the changes it makes.  Must be the Session ID "
+    "of an existing global admin.  The default is `cli:LOGIN`, where LOGIN is the invoking (or "
+    "sudo-invoking) user's login name.",
+)
 ap.add_argument(
     "--initialize",
     action='store_true',
@@ -168,6 +258,7 @@ args = ap.parse_args()
 
 update_room = not args.add_room and (
     args.description is not None
+    or args.tags is not None
     or args.name is not None
     or args.add_moderators
     or args.delete_moderators
@@ -181,6 +272,13 @@ incompat = [
     ('room modifiers', update_room),
     ('--list-rooms', args.list_rooms),
     ('--list-global-mods', args.list_global_mods),
+    ('--file-stats', args.file_stats),
+    ('--compress-messages', args.compress_messages),
+    ('--restore-journal', args.restore_journal),
+    ('--migrate-key', args.migrate_key),
+    ('--relocate', args.relocate),
+    ('--backup', args.backup),
+    ('--import-backup', args.import_backup),
     ('--initialize', args.initialize),
     ('--upgrade', args.upgrade),
     ('--check-upgrades', args.check_upgrades),
@@ -203,6 +301,28 @@ if args.rooms and not update_room:
     print("Error: --rooms specified without a room modification option", file=sys.stderr)
     sys.exit(1)
 
+if args.actor is not None and not re.fullmatch(r'[01]5[A-Fa-f0-9]{64}', args.actor):
+    print(f"Error: --actor '{args.actor}' is not a valid session id", file=sys.stderr)
+    sys.exit(1)
+
+if args.migrate_key:
+    from . import config, keystore
+
+    try:
+        with open(args.migrate_key, 'rb') as f:
+            privkey = f.read()
+        store = keystore.get()
+        if store.load() is not None:
+            raise keystore.KeyStoreError(f"the {config.KEY_STORAGE} key storage already has a key")
+        store.store(privkey)
+    except (OSError, keystore.KeyStoreError) as e:
+        print(f"Unable to migrate {args.migrate_key}: {e}", file=sys.stderr)
+        sys.exit(1)
+    print(f"Stored the private key from {args.migrate_key} in {config.KEY_STORAGE} key storage.")
+    if config.KEY_STORAGE != 'file':
+        print(f"You should now securely delete {args.migrate_key}.")
+    sys.exit(0)
+
 from . import config, crypto, db
 from .migrations.exc import DatabaseUpgradeRequired
 from sqlalchemy_utils import database_exists
@@ -239,14 +359,38 @@ Try --help for additional information.
     )
     sys.exit(1)
 
-from . import web
+from . import journal, web
 from .model.room import Room, get_rooms
 from .model.user import User, SystemUser, get_all_global_moderators
-from .model.exc import AlreadyExists, NoSuchRoom, NoSuchUser
+from .model.exc import AlreadyExists, InvalidData, NoSuchRoom, NoSuchUser, RoomLimitReached
 
 web.appdb = db.get_conn()
 
 
+def cli_actor():
+    if args.actor is not None:
+        # Only a known admin can be named, so that changes made here can't be passed off as having
+        # been made by some other Session user.
+        try:
+            admin = User(session_id=args.actor, autovivify=False, try_blinding=True)
+        except NoSuchUser:
+            admin = None
+        if admin is None or not admin.global_admin:
+            print(f"Error: --actor {args.actor} is not a global admin", file=sys.stderr)
+            sys.exit(1)
+        return admin.session_id
+    login = os.environ.get('SUDO_USER')
+    if not login:
+        try:
+            login = getpass.getuser()
+        except Exception:
+            login = None
+    return f"cli:{login}" if login else "cli"
+
+
+journal.set_actor(cli_actor())
+
+
 @atexit.register
 def close_conn():
     web.appdb.close()
@@ -261,6 +405,9 @@ def print_room(room: Room):
 
     msgs_size /= 1_000_000
     files_size /= 1_000_000
+    egress = room.egress_used() / 1_000_000
+    egress_cap = room.egress_cap
+    egress_cap = f"{egress_cap / 1_000_000:.1f} MB cap" if egress_cap else "no cap"
 
     active = [room.active_users_last(x * 86400) for x in (1, 7, 14, 30)]
     m, a, hm, ha = room.get_all_moderators()
@@ -280,9 +427,11 @@ def print_room(room: Room):
 {"=" * len(room.token)}
 Name: {room.name}
 Description: {room.description}
+Tags: {', '.join(room.tags) if room.tags else '(none)'}
 URL: {config.URL_BASE}/{room.token}?public_key={crypto.server_pubkey_hex}
 Messages: {msgs} ({msgs_size:.1f} MB)
 Attachments: {files} ({files_size:.1f} MB)
+Downloads this month: {egress:.1f} MB ({egress_cap})
 Reactions: {r_total}; top 5: {', '.join(f"{r} ({c})" for r, c in reactions[0:5])}
 Active users: {active[0]} (1d), {active[1]} (7d), {active[2]} (14d), {active[3]} (30d)
 Default permissions: {perms}
@@ -363,7 +512,10 @@ elif args.add_room:
 
     try:
         room = Room.create(
-            token=args.add_room, name=args.name or args.add_room, description=args.description
+            token=args.add_room,
+            name=args.name or args.add_room,
+            description=args.description,
+            ignore_limits=args.ignore_limits,
         )
         if "read" in perms:
             room.default_read = perms["read"]
@@ -373,10 +525,20 @@ elif args.add_room:
             room.default_accessible = perms["accessible"]
         if "upload" in perms:
             room.default_upload = perms["upload"]
+        if args.tags is not None:
+            room.tags = args.tags
 
     except AlreadyExists:
         print(f"Error: room '{args.add_room}' already exists!", file=sys.stderr)
         sys.exit(1)
+    except RoomLimitReached as e:
+        print(f"Error: {e}; use --ignore-limits to create it anyway", file=sys.stderr)
+        sys.exit(1)
+    except InvalidData:
+        print(
+            f"Error: invalid tags {' '.join(args.tags)}; room created without tags", file=sys.stderr
+        )
+        sys.exit(1)
     print(f"Created room {args.add_room}:")
     print_room(room)
 
@@ -574,6 +736,22 @@ elif update_room:
             room.description = None if not args.description else args.description
             print(f"Updated {room.token} description to:\n\n{room.description}\n")
 
+    if args.tags is not None:
+        if global_rooms or all_rooms:
+            print(
+                "Error: --rooms cannot be '+' or '*' (i.e. global/all) with --tags",
+                file=sys.stderr,
+            )
+            sys.exit(1)
+
+        for room in rooms:
+            try:
+                room.tags = args.tags
+            except InvalidData:
+                print(f"Error: invalid tags {' '.join(args.tags)}", file=sys.stderr)
+                sys.exit(1)
+            print(f"Updated {room.token} tags to: {', '.join(room.tags) or '(none)'}")
+
     if args.name is not None:
         if global_rooms or all_rooms:
             print(
@@ -610,6 +788,118 @@ elif args.list_global_mods:
     for u in hm:
         print(f"- {u.session_id} (hidden moderator)")
 
+elif args.file_stats:
+    from .storage import tier_stats
+
+    stats = tier_stats()
+    hot, cold = stats['hot'], stats['cold']
+    print(f"Regular storage: {hot['files']} files ({hot['size'] / 1_000_000:.1f} MB)")
+    print(
+        f"Cold storage: {cold['files']} files ({cold['size'] / 1_000_000:.1f} MB; "
+        f"{cold['stored_size'] / 1_000_000:.1f} MB stored)"
+    )
+
+elif args.compress_messages:
+    from . import compress
+
+    if compress.zstandard is None:
+        print("Error: the zstandard module is required for message compression", file=sys.stderr)
+        sys.exit(1)
+
+    if args.compress_messages == ['*']:
+        rooms = get_rooms()
+    else:
+        try:
+            rooms = [Room(token=r) for r in args.compress_messages]
+        except NoSuchRoom as nsr:
+            print(f"No such room: '{nsr.token}'", file=sys.stderr)
+            sys.exit(1)
+
+    for room in rooms:
+        stats = compress.compress_room(room.id)
+        if stats is None:
+            print(f"{room.token}: not enough messages to train a compression dictionary")
+        else:
+            before, after = stats['before'] / 1_000_000, stats['after'] / 1_000_000
+            print(
+                f"{room.token}: recompressed {stats['messages']} messages (from {before:.1f} MB "
+                f"to {after:.1f} MB)"
+            )
+
+elif args.restore_journal:
+    from .restore import replay
+
+    result = replay(args.restore_journal, since=args.restore_since, until=args.restore_until)
+    print(
+        f"Replayed {result['replayed']} events ({result['skipped']} skipped); "
+        f"verified {result['verified']} room checkpoints"
+    )
+    for m in result['mismatched']:
+        print(
+            f"Error: room {m['room']} does not match the checkpoint at {m['time']}: expected root "
+            f"{m['expected']}, found {m['actual']}",
+            file=sys.stderr,
+        )
+    if result['mismatched']:
+        sys.exit(2)
+
+elif args.relocate:
+    from . import layout
+
+    what, dest = args.relocate
+    if what not in layout.LOCATIONS:
+        print(
+            f"Error: --relocate WHAT must be one of {', '.join(layout.LOCATIONS)}", file=sys.stderr
+        )
+        sys.exit(1)
+
+    try:
+        if what == 'db':
+            setting = ('db', 'url', layout.relocate_db(dest))
+            print(f"Copied the database to {dest}")
+        elif what == 'journal':
+            print(f"Copied {layout.relocate_journal(dest)} journal file(s) to {dest}")
+            setting = ('journal', 'path', dest)
+        else:
+            updated = layout.relocate_files(dest, cold=what == 'cold')
+            print(f"Copied files to {dest} and updated {updated} stored file paths")
+            setting = ('files', 'cold_dir' if what == 'cold' else 'uploads_dir', dest)
+    except (OSError, RuntimeError) as e:
+        print(f"Unable to relocate {what} to {dest}: {e}", file=sys.stderr)
+        sys.exit(1)
+
+    print(
+        f"Now set [{setting[0]}].{setting[1]} = {setting[2]} in the config before restarting sogs, "
+        "then remove the original once everything works."
+    )
+
+elif args.backup:
+    from . import backup
+
+    try:
+        manifest = backup.create(args.backup)
+    except (OSError, RuntimeError) as e:
+        print(f"Unable to create backup in {args.backup}: {e}", file=sys.stderr)
+        sys.exit(1)
+    print(
+        f"Backed up {manifest['rooms']} rooms ({manifest['messages']} messages) and "
+        f"{len(manifest['files'])} file directories to {args.backup}"
+    )
+
+elif args.import_backup:
+    from . import backup
+
+    try:
+        result = backup.import_backup(args.import_backup, keep_ids=args.keep_ids)
+    except (OSError, RuntimeError, AlreadyExists) as e:
+        print(f"Unable to import {args.import_backup}: {e}", file=sys.stderr)
+        sys.exit(1)
+    print(
+        f"Imported {result['rooms']} rooms, {result['messages']} messages, "
+        f"{result['files']} files, {result['moderators']} moderators/admins, and "
+        f"{result['bans']} bans"
+    )
+
 else:
     print("Error: no action given", file=sys.stderr)
     ap.print_usage()
@@ -0,0 +1,76 @@
+from . import config, digest, journal
+from .db import query
+from .model.room import Room
+from .web import app
+
+import time
+
+# Inactive room policy, configured via the [rooms] `archive_*` settings.  When [rooms].archive_after
+# is set, the `room_archive` job archives (i.e. makes read-only; see Room.archive) rooms that have
+# had no activity for that long, where activity is the posting or editing of a (non-system) message,
+# or the room being unarchived by an admin.  Rooms that were created more recently than that are
+# left alone.  If [rooms].archive_delete_after is also set then rooms that have been archived for
+# that much longer are permanently deleted.
+#
+# Each automatic archiving and deletion is logged and recorded in the event journal (if enabled);
+# if [rooms].archive_notify is set (and email is configured, see [digest]) then a summary of the
+# actions is also emailed to that address.  Individual rooms can be exempted by setting `archive =
+# no` in the room's [room:TOKEN] section.
+
+
+def _last_activity(room_id):
+    row = query(
+        """
+        SELECT
+            (SELECT MAX(posted) FROM messages WHERE room = :r AND kind != 'system'),
+            (SELECT MAX(edited) FROM messages WHERE room = :r AND kind != 'system'),
+            created, unarchived
+        FROM rooms WHERE id = :r
+        """,
+        r=room_id,
+    ).first()
+    return max(t for t in row if t is not None)
+
+
+def run(now=None):
+    """
+    Applies the inactive room policy.  Returns a dict of the `archived` and `deleted` room tokens,
+    or None if the policy is disabled.
+    """
+    if not config.ROOM_ARCHIVE_AFTER:
+        return None
+    if now is None:
+        now = time.time()
+
+    archived, deleted = [], []
+    for room_id, token, archived_at in query("SELECT id, token, archived FROM rooms ORDER BY id"):
+        if not config.ROOM_OVERRIDES.get(token, {}).get('archive', True):
+            continue
+
+        if archived_at is None:
+            last = _last_activity(room_id)
+            if last <= now - config.ROOM_ARCHIVE_AFTER:
+                Room(id=room_id).archive()
+                archived.append(token)
+        elif (
+            config.ROOM_ARCHIVE_DELETE_AFTER
+            and archived_at <= now - config.ROOM_ARCHIVE_DELETE_AFTER
+        ):
+            Room(id=room_id).delete()
+            app.logger.warning(f"Inactive room policy deleted room {token}")
+            journal.record('room_deleted', room=token, by=None)
+            deleted.append(token)
+
+    if (archived or deleted) and config.ROOM_ARCHIVE_NOTIFY and digest.enabled():
+        lines = [f"Archived inactive room {t}" for t in archived]
+        lines += [f"Deleted archived room {t}" for t in deleted]
+        try:
+            digest.send_email(
+                config.ROOM_ARCHIVE_NOTIFY,
+                f"SOGS: {len(archived)} room(s) archived, {len(deleted)} room(s) deleted",
+                "\n".join(lines) + "\n",
+            )
+        except Exception as e:
+            app.logger.error(f"Unable to send room archiving notification: {e}")
+
+    return {'archived': archived, 'deleted': deleted}
@@ -0,0 +1,122 @@
+from .web import app
+from .db import query
+from . import db
+
+# Lazy backfill of message history imported from an old (0.1.x) SOGS database.  With
+# [db].import_backfill enabled, the startup import only brings over each room's most recent message
+# (along with everything else: files, moderators, bans, etc.), so that an upgraded server can start
+# serving right away.  The rest of the old messages (whose new ids are reserved by the import) are
+# then copied over from newest to oldest by the `import_backfill` job, while new posts go straight
+# into the new schema.
+#
+# Reads of room history that reach into not-yet-imported messages (i.e. `recent`, `before`,
+# `around`, and single message fetches) backfill the messages they need on demand before querying,
+# so clients see the complete history throughout.  Sequence polling is unaffected, since clients
+# already have the old messages.
+
+BATCH_SIZE = 1000
+
+# room id => [id offset, old id before which messages remain to be imported].  Loaded on first use;
+# rooms are removed once fully backfilled.
+_pending = None
+
+
+def _load():
+    global _pending
+    if _pending is None:
+        _pending = {}
+        if 'room_import_backfill' in db.metadata.tables:
+            for room, offset, before in query(
+                "SELECT room, id_offset, before_id FROM room_import_backfill"
+            ):
+                _pending[room] = [offset, before]
+    return _pending
+
+
+def pending(room_id):
+    """Returns true if room `room_id` has imported messages still waiting to be backfilled"""
+    return room_id in _load()
+
+
+def backfill_room(room_id, limit=BATCH_SIZE):
+    """
+    Imports the next (i.e. most recent) `limit` not-yet-imported messages of room `room_id`.
+    Returns the number of messages imported.
+    """
+    from .migrations.v_0_1_x import import_messages, sqlite_connect_readonly
+
+    row = query(
+        "SELECT db_path, id_offset, before_id FROM room_import_backfill WHERE room = :r", r=room_id
+    ).first()
+    if row is None:
+        _load().pop(room_id, None)
+        return 0
+    path, offset, before = row
+
+    with sqlite_connect_readonly(path) as rconn, db.transaction():
+        # Lock the backfill row, making sure another worker hasn't already imported this batch:
+        if not query(
+            """
+            UPDATE room_import_backfill SET before_id = :b WHERE room = :r AND before_id = :b
+            """,
+            r=room_id,
+            b=before,
+        ).rowcount:
+            return 0
+
+        imported, _, remaining = import_messages(
+            None, rconn, room_id, offset, before=before, limit=limit
+        )
+        if remaining is None:
+            query("DELETE FROM room_import_backfill WHERE room = :r", r=room_id)
+        else:
+            query(
+                "UPDATE room_import_backfill SET before_id = :b WHERE room = :r",
+                r=room_id,
+                b=remaining,
+            )
+
+    if remaining is None:
+        _load().pop(room_id, None)
+        app.logger.warning(f"Finished backfilling imported messages of room {room_id}")
+    else:
+        _load()[room_id] = [offset, remaining]
+    return imported
+
+
+def ensure(room_id, *, limit, before=None, posted_before=None, single=None):
+    """
+    Backfills messages of room `room_id`, as needed, so that the message with id `single` (if
+    given) has been imported, and so that at least `limit` messages with ids less than `before` (or
+    posted before `posted_before`; or just the most recent if neither is given) are available.
+    """
+    while pending(room_id):
+        offset, remaining = _pending[room_id]
+        if single is None or not 0 < single - offset < remaining:
+            bound = (
+                'AND id < :before'
+                if before is not None
+                else 'AND posted < :posted_before'
+                if posted_before is not None
+                else ''
+            )
+            # Any messages not yet imported are older than all the ones we have, so if we already
+            # have enough then they are the right ones:
+            have = query(
+                f"SELECT COUNT(*) FROM messages WHERE room = :r AND data IS NOT NULL {bound}",
+                r=room_id,
+                before=before,
+                posted_before=posted_before,
+            ).first()[0]
+            if have >= limit:
+                return
+        if not backfill_room(room_id, max(limit, BATCH_SIZE // 10)):
+            return
+
+
+def run():
+    """Backfills the next batch of messages of each room being backfilled.  Returns the count."""
+    total = 0
+    for room_id in list(_load()):
+        total += backfill_room(room_id)
+    return total
@@ -0,0 +1,407 @@
+from . import __version__, compress, config, crypto, db, layout, storage
+from .db import query
+from .model.exc import AlreadyExists
+from .model.room import Room
+from .model.user import User, SystemUser
+
+import json
+import lzma
+import os
+import shutil
+import sqlite3
+import time
+
+# Online backups, and importing rooms from them.
+#
+# `python3 -msogs --backup DIR` creates DIR containing:
+# - `sogs.db` — a consistent copy of the (SQLite) database, made with SQLite's online backup API so
+#   that the server does not need to be stopped;
+# - `files/uploads` and `files/cold` — snapshots of the uploads and cold storage directories, taken
+#   after the database copy (so that every file referenced by the copy is included, other than
+#   files deleted in the meantime);
+# - `manifest.json` — the sogs version, server pubkey, and time of the backup, and the original
+#   locations of the file directories.
+#
+# For disaster recovery a backup can be put back in place as a whole (copying sogs.db and the file
+# directories back to their configured locations).  `python3 -msogs --import-backup DIR` instead
+# merges the rooms of a backup into the current server, e.g. when migrating rooms onto another
+# server: it recreates each room (with its settings), its messages, and its moderators, admins,
+# permission overrides, and bans, plus the backup's global moderators and bans.  Messages get new
+# ids on the importing server, and attachments, reactions, and pinned messages are not imported.
+#
+# Importing with `--keep-ids` is for moving a whole server's communities onto a new server (which
+# takes over the old server's URL and key): messages and attachments keep their ids, and messages
+# and rooms keep their seqno/update counters, so that clients simply carry on polling where they
+# left off instead of re-fetching everything.  It also imports deleted messages (so that clients
+# still learn of deletions made after their last poll), system messages, reactions, pinned
+# messages, attachments, and room images.  It fails (importing nothing) if any of the ids are
+# already in use.
+
+MANIFEST = 'manifest.json'
+
+
+def create(dest):
+    """
+    Creates a backup in directory `dest`, which must not exist or be empty.  Returns the backup's
+    manifest.
+    """
+    layout.check_dest(dest)
+    os.makedirs(dest, exist_ok=True)
+    layout.copy_db(os.path.join(dest, 'sogs.db'))
+
+    files = {}
+    if config.STORAGE_BACKEND == 'local':
+        dirs = {'uploads': config.UPLOAD_PATH}
+        cold = config.UPLOAD_COLD_PATH
+        uploads = os.path.join(os.path.abspath(config.UPLOAD_PATH), '')
+        if cold and not os.path.abspath(cold).startswith(uploads):
+            dirs['cold'] = cold
+        for name, src in dirs.items():
+            if os.path.isdir(src):
+                shutil.copytree(src, os.path.join(dest, 'files', name), symlinks=True)
+                files[name] = os.path.abspath(src)
+
+    with sqlite3.connect(os.path.join(dest, 'sogs.db')) as conn:
+        rooms, messages = conn.execute(
+            "SELECT (SELECT COUNT(*) FROM rooms), (SELECT COUNT(*) FROM messages)"
+        ).fetchone()
+    manifest = {
+        'version': __version__,
+        'server_pubkey': crypto.server_pubkey_hex,
+        'created': time.time(),
+        'rooms': rooms,
+        'messages': messages,
+        'files': files,
+    }
+    with open(os.path.join(dest, MANIFEST), 'w') as f:
+        json.dump(manifest, f, indent=2)
+    return manifest
+
+
+_ROOM_FLAGS = ('read', 'accessible', 'write', 'upload', 'sensitive')
+_OVERRIDES = (
+    'banned',
+    'read',
+    'accessible',
+    'write',
+    'upload',
+    'moderator',
+    'admin',
+    'visible_mod',
+)
+
+
+def _bool(v):
+    # SQLite booleans are integers, which postgresql won't accept for boolean columns
+    return None if v is None else bool(v)
+
+
+def _message_data(backup, m):
+    # Returns the data of backed up message row `m`, decompressing it (with the backup's own
+    # dictionary) if it was stored compressed.  Imported messages are stored uncompressed until the
+    # room's messages are recompressed.
+    if 'data_dict' not in m.keys() or m['data_dict'] is None:
+        return m['data']
+    (zdict,) = backup.execute(
+        "SELECT dict FROM message_dicts WHERE id = ?", (m['data_dict'],)
+    ).fetchone()
+    return compress.decode_with(m['data'], zdict)
+
+
+def _file_content(src, backup, path):
+    # Returns the backed up content of the file stored at `path`, or None if it isn't in the backup.
+    # Stored paths end in `{token}/{filename}`, and cold copies in `cold/{xx}/{hash}[.xz]`, beneath
+    # the uploads (or cold) directory, which is (respectively) files/uploads or files/cold in the
+    # backup.
+    cold = backup.execute(
+        "SELECT cold_path, compressed FROM cold_files WHERE path = ? AND cold_path IS NOT NULL",
+        (path,),
+    ).fetchone()
+    if cold is not None:
+        rel, compressed, dirs = cold['cold_path'].split('/')[-3:], cold['compressed'], ('cold',)
+    else:
+        rel, compressed, dirs = path.split('/')[-2:], False, ()
+    for d in (*dirs, 'uploads'):
+        p = os.path.join(src, 'files', d, *rel)
+        if os.path.exists(p):
+            with open(p, 'rb') as f:
+                content = f.read()
+            return lzma.decompress(content) if compressed else content
+    return None
+
+
+def _import_files(src, backup, r, room, user, written):
+    # Imports (with their ids) the unexpired attachments and image of backup room `r` into `room`.
+    # Returns the number of imported files; the stored paths are appended to `written`.
+    count = 0
+    store = storage.get()
+    for f in backup.execute(
+        "SELECT * FROM files WHERE room = ? AND (expiry IS NULL OR expiry > ?) ORDER BY id",
+        (r['id'], time.time()),
+    ).fetchall():
+        content = _file_content(src, backup, f['path'])
+        if content is None:
+            config.logger.warning(f"Not importing file {f['id']}: content missing from backup")
+            continue
+        if query("SELECT COUNT(*) FROM files WHERE id = :f", f=f['id']).first()[0]:
+            raise AlreadyExists(f"File id {f['id']} already exists", value=f['id'])
+        name = os.path.basename(f['path']).split('_', 1)[-1]
+        path = store.write(f"{room.token}/{f['id']}_{name}", content)
+        written.append(path)
+        query(
+            """
+            INSERT INTO files
+                (id, room, uploader, message, size, uploaded, expiry, filename, content_type,
+                path, phash, quarantined)
+            VALUES (:id, :r, :u, :m, :size, :uploaded, :expiry, :filename, :content_type,
+                :path, :phash, :quarantined)
+            """,
+            id=f['id'],
+            r=room.id,
+            u=user(f['uploader']).id if f['uploader'] is not None else None,
+            m=f['message'],
+            size=f['size'],
+            uploaded=f['uploaded'],
+            expiry=f['expiry'],
+            filename=f['filename'],
+            # Not present in backups made before content types were stored:
+            content_type=f['content_type'] if 'content_type' in f.keys() else None,
+            path=path,
+            phash=f['phash'],
+            quarantined=_bool(f['quarantined']),
+        )
+        count += 1
+        if f['id'] == r['image']:
+            query("UPDATE rooms SET image = :f WHERE id = :r", f=f['id'], r=room.id)
+    return count
+
+
+def import_backup(src, *, keep_ids=False):
+    """
+    Imports the rooms of the backup in directory `src` into the current database, all in one
+    transaction.  Raises model.exc.AlreadyExists (importing nothing) if any of the backup's rooms
+    already exist.
+
+    If `keep_ids` is true then messages and files keep their ids (and messages and rooms their
+    seqno and update counters), and deleted and system messages, reactions, pinned messages, and
+    attachments are imported as well; this raises AlreadyExists if any of the message or file ids
+    are in use.
+
+    Returns a dict of the number of imported `rooms`, `messages`, `files`, `moderators` (room and
+    global moderators and admins), and `bans` (room and global).
+    """
+    path = os.path.join(src, 'sogs.db')
+    if not os.path.exists(path):
+        raise RuntimeError(f"{src} is not a sogs backup (no sogs.db)")
+    backup = sqlite3.connect(f"file:{path}?mode=ro", uri=True)
+    backup.row_factory = sqlite3.Row
+
+    sysadmin = SystemUser()
+    users = {0: sysadmin}  # The backup server's system user is ours
+
+    def user(id):
+        if id not in users:
+            (sid,) = backup.execute("SELECT session_id FROM users WHERE id = ?", (id,)).fetchone()
+            users[id] = User(session_id=sid)
+        return users[id]
+
+    result = {'rooms': 0, 'messages': 0, 'files': 0, 'moderators': 0, 'bans': 0}
+    written = []
+    try:
+        with db.transaction():
+            for r in backup.execute("SELECT * FROM rooms ORDER BY id").fetchall():
+                room = Room.create(r['token'], r['name'], r['description'], ignore_limits=True)
+                query(
+                    f"""
+                    UPDATE rooms SET created = :created, rules = :rules,
+                        {', '.join(f'{c} = :{c}' for c in _ROOM_FLAGS)}
+                    WHERE id = :r
+                    """,
+                    r=room.id,
+                    created=r['created'],
+                    rules=r['rules'],
+                    **{c: _bool(r[c]) for c in _ROOM_FLAGS},
+                )
+                result['rooms'] += 1
+
+                for m in backup.execute(
+                    f"""
+                    SELECT * FROM messages
+                    WHERE room = ? {'' if keep_ids else "AND data IS NOT NULL AND kind != 'system'"}
+                    ORDER BY id
+                    """,
+                    (r['id'],),
+                ).fetchall():
+                    if keep_ids and query(
+                        "SELECT COUNT(*) FROM messages WHERE id = :m", m=m['id']
+                    ).first()[0]:
+                        raise AlreadyExists(f"Message id {m['id']} already exists", value=m['id'])
+                    query(
+                        f"""
+                        INSERT INTO messages
+                            ({'id, ' if keep_ids else ''}room, "user", posted, edited, data,
+                            data_size, signature, filtered, whisper, whisper_mods, kind)
+                        VALUES ({':id, ' if keep_ids else ''}:r, :u, :posted, :edited, :data,
+                            :data_size, :signature, :filtered, :whisper, :whisper_mods, :kind)
+                        """,
+                        id=m['id'],
+                        r=room.id,
+                        u=user(m['user']).id,
+                        posted=m['posted'],
+                        edited=m['edited'],
+                        data=_message_data(backup, m),
+                        data_size=m['data_size'],
+                        signature=m['signature'],
+                        filtered=_bool(m['filtered']),
+                        whisper=user(m['whisper']).id if m['whisper'] is not None else None,
+                        whisper_mods=_bool(m['whisper_mods']),
+                        kind=m['kind'],
+                    )
+                    result['messages'] += 1
+                    if keep_ids:
+                        _import_reactions(backup, m['id'], user)
+                        # Restore the seqnos (replacing the ones set by the insert triggers):
+                        query(
+                            """
+                            UPDATE messages SET seqno = :seqno, seqno_data = :data,
+                                seqno_reactions = :reactions, seqno_creation = :creation
+                            WHERE id = :m
+                            """,
+                            m=m['id'],
+                            seqno=m['seqno'],
+                            data=m['seqno_data'],
+                            reactions=m['seqno_reactions'],
+                            creation=m['seqno_creation'],
+                        )
+
+                if keep_ids:
+                    result['files'] += _import_files(src, backup, r, room, user, written)
+                    for p in backup.execute(
+                        'SELECT * FROM pinned_messages WHERE room = ?', (r['id'],)
+                    ):
+                        query(
+                            """
+                            INSERT INTO pinned_messages
+                                (room, message, pinned_by, pinned_at, position)
+                            VALUES (:r, :m, :u, :at, :pos)
+                            """,
+                            r=room.id,
+                            m=p['message'],
+                            u=user(p['pinned_by']).id,
+                            at=p['pinned_at'],
+                            pos=p['position'] if 'position' in p.keys() else 0,
+                        )
+                    query(
+                        """
+                        UPDATE rooms SET
+                            message_sequence = CASE WHEN message_sequence > :seq
+                                THEN message_sequence ELSE :seq END,
+                            info_updates = CASE WHEN info_updates > :info
+                                THEN info_updates ELSE :info END
+                        WHERE id = :r
+                        """,
+                        r=room.id,
+                        seq=r['message_sequence'],
+                        info=r['info_updates'],
+                    )
+
+                for o in backup.execute(
+                    'SELECT * FROM user_permission_overrides WHERE room = ?', (r['id'],)
+                ):
+                    query(
+                        f"""
+                        INSERT INTO user_permission_overrides
+                            (room, "user", {', '.join(_OVERRIDES)})
+                        VALUES (:r, :u, {', '.join(f':{c}' for c in _OVERRIDES)})
+                        """,
+                        r=room.id,
+                        u=user(o['user']).id,
+                        **{c: _bool(o[c]) for c in _OVERRIDES},
+                    )
+                    result['moderators'] += bool(o['moderator'] or o['admin'])
+                    result['bans'] += bool(o['banned'])
+
+                for f in backup.execute(
+                    'SELECT * FROM user_ban_futures WHERE room = ?', (r['id'],)
+                ):
+                    query(
+                        """
+                        INSERT INTO user_ban_futures (room, "user", at, banned)
+                        VALUES (:r, :u, :at, :banned)
+                        """,
+                        r=room.id,
+                        u=user(f['user']).id,
+                        at=f['at'],
+                        banned=_bool(f['banned']),
+                    )
+
+            # Global moderators and bans (other than the backup server's system user, id 0):
+            for u in backup.execute(
+                "SELECT id, moderator, admin, visible_mod, banned FROM users"
+                " WHERE id != 0 AND (moderator OR admin OR banned)"
+            ).fetchall():
+                target = user(u['id'])
+                if u['banned']:
+                    if not target.global_moderator:
+                        target.ban(banned_by=sysadmin)
+                        result['bans'] += 1
+                else:
+                    target.set_moderator(
+                        added_by=sysadmin, admin=bool(u['admin']), visible=bool(u['visible_mod'])
+                    )
+                    result['moderators'] += 1
+            for f in backup.execute('SELECT * FROM user_ban_futures WHERE room IS NULL'):
+                query(
+                    'INSERT INTO user_ban_futures ("user", at, banned) VALUES (:u, :at, :banned)',
+                    u=user(f['user']).id,
+                    at=f['at'],
+                    banned=_bool(f['banned']),
+                )
+
+            if keep_ids and db.engine.name == 'postgresql':
+                # Restart the id sequences past the ids we inserted (sqlite does this itself)
+                for table in ('messages', 'files'):
+                    (max_id,) = query(f"SELECT MAX(id) FROM {table}").first()
+                    if max_id is not None:
+                        query(f"ALTER TABLE {table} ALTER COLUMN id RESTART WITH {max_id + 1}")
+    except Exception:
+        store = storage.get()
+        for path in written:
+            try:
+                store.delete(path)
+            except Exception:
+                pass
+        raise
+    finally:
+        backup.close()
+
+    return result
+
+
+def _import_reactions(backup, msg_id, user):
+    for ur in backup.execute(
+        """
+        SELECT reactions.reaction, "user", at
+        FROM user_reactions JOIN reactions ON reactions.id = user_reactions.reaction
+        WHERE message = ? ORDER BY at
+        """,
+        (msg_id,),
+    ).fetchall():
+        react, u = ur['reaction'], user(ur['user']).id
+        query(
+            'INSERT INTO message_reactions (message, reaction, "user") VALUES (:m, :react, :u)',
+            m=msg_id,
+            react=react,
+            u=u,
+        )
+        query(
+            """
+            UPDATE user_reactions SET at = :at
+            WHERE reaction = (SELECT id FROM reactions WHERE message = :m AND reaction = :react)
+                AND "user" = :u
+            """,
+            at=ur['at'],
+            m=msg_id,
+            react=react,
+            u=u,
+        )
@@ -0,0 +1,125 @@
+from . import config, metrics, utils
+from .hashing import blake2b
+from .web import app
+
+from flask import g, request
+from werkzeug.exceptions import HTTPException
+import functools
+
+# Canary rollouts of new endpoint implementations.  A rewritten implementation of an endpoint (for
+# example one using a new storage layer) is registered as the `candidate` of a named rollout, and
+# the existing handler is wrapped with `rollout`:
+#
+#     @rooms.get("/room/<Room:room>/thing")
+#     @auth.read_required
+#     @canary.rollout('thing')
+#     def get_thing(room):
+#         ...
+#
+#     @canary.candidate('thing')
+#     def get_thing_v2(room):
+#         ...
+#
+# [canary].rollouts configures the percentage of clients whose requests are handled by the
+# candidate.  Clients are assigned by a hash of their session id (or, for unauthenticated requests,
+# their IP address) and the rollout name, so that a given client consistently gets the same
+# implementation, and raising the percentage only adds clients.  If the candidate raises an
+# exception (other than an HTTP abort) the request falls back to the existing handler.
+#
+# With [canary].compare enabled, GET requests handled by the candidate are also handled by the
+# existing handler and the two responses compared; mismatches are logged (without the response
+# bodies, which may contain private data).  The counts of requests, errors, and mismatches of each
+# rollout are available in the /admin/metrics counters as `canary.NAME.candidate`,
+# `canary.NAME.error`, and `canary.NAME.mismatch`.
+#
+# Because the decorator goes beneath the route's auth decorators, the candidate gets the same
+# (already authorized) arguments as the existing handler.
+#
+# The message retrieval endpoints, which are the most heavily polled, and so the ones a storage
+# rewrite most needs to validate, are wrapped in rollouts named after their handlers:
+# `messages_since`, `messages_before`, `messages_posted_before`, `messages_around`,
+# `messages_recent`, and `message_single` (see sogs/routes/messages.py).
+
+_candidates = {}
+
+
+def candidate(name: str):
+    """Decorator registering the decorated function as the new implementation of rollout `name`"""
+
+    def decorator(f):
+        _candidates[name] = f
+        return f
+
+    return decorator
+
+
+def client_id():
+    """Returns the identifier of the requesting client used for assigning it to rollouts."""
+    user = g.get('user')
+    return user.session_id if user is not None else utils.client_addr()
+
+
+def in_rollout(name: str, client: str):
+    """Returns true if `client` is in the configured percentage of clients of rollout `name`."""
+    percent = config.CANARY_ROLLOUTS.get(name, 0)
+    if percent <= 0:
+        return False
+    if percent >= 100:
+        return True
+    h = blake2b((name.encode(), b'\0', client.encode()), digest_size=8, person=b'sogs.canary')
+    return int.from_bytes(h, 'big') % 10000 < percent * 100
+
+
+def _compare(name, candidate_resp, existing, args, kwargs):
+    """Runs the existing handler and logs (and counts) any difference from the candidate response"""
+    try:
+        existing_resp = app.make_response(existing(*args, **kwargs))
+    except HTTPException as e:
+        existing_resp = e.get_response()
+    except Exception as e:
+        app.logger.warning(f"Canary {name}: existing handler failed during comparison: {e}")
+        return
+    if existing_resp.is_streamed:
+        return
+    if (candidate_resp.status_code, candidate_resp.get_data()) != (
+        existing_resp.status_code,
+        existing_resp.get_data(),
+    ):
+        metrics.incr(f'canary.{name}.mismatch')
+        app.logger.warning(
+            f"Canary {name}: response mismatch for {request.method} {request.path}: candidate "
+            f"returned {candidate_resp.status_code} ({len(candidate_resp.get_data())} bytes), "
+            f"existing returned {existing_resp.status_code} ({len(existing_resp.get_data())} bytes)"
+        )
+
+
+def rollout(name: str):
+    """
+    Decorator for an existing endpoint handler that routes the configured percentage of clients to
+    the candidate implementation of rollout `name`, if one is registered.
+    """
+
+    def decorator(f):
+        @functools.wraps(f)
+        def wrapper(*args, **kwargs):
+            new = _candidates.get(name)
+            if new is None or not in_rollout(name, client_id()):
+                return f(*args, **kwargs)
+
+            metrics.incr(f'canary.{name}.candidate')
+            try:
+                resp = app.make_response(new(*args, **kwargs))
+            except HTTPException:
+                raise
+            except Exception as e:
+                metrics.incr(f'canary.{name}.error')
+                app.logger.error(f"Canary {name} failed; falling back to existing handler: {e}")
+                return f(*args, **kwargs)
+
+            if config.CANARY_COMPARE and request.method == 'GET' and not resp.is_streamed:
+                _compare(name, resp, f, args, kwargs)
+            return resp
+
+        return wrapper
+
+    return decorator
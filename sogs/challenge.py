@@ -0,0 +1,64 @@
+from . import crypto
+from .hashing import blake2b
+
+import hmac
+import os
+import time
+
+# Proof-of-work challenges for first posts.  Rooms can require (via [rooms].first_post_pow) that a
+# Session id's first post to the room includes the solution to a proof-of-work challenge, which
+# makes flooding a room from many freshly created ids expensive while costing a real user only a
+# moment of computation, once.
+#
+# Challenges are stateless: a challenge is an expiry timestamp and a random nonce, authenticated
+# with a server key over the room token and the session id it was issued to.  A solution is any
+# string of bytes such that BLAKE2b(challenge || solution) (with a 32-byte digest and the
+# personalization `sogs.pow`) begins with at least `difficulty` zero bits.
+
+# How long, in seconds, an issued challenge remains valid
+LIFETIME = 600
+
+# Maximum accepted solution length, in bytes
+MAX_SOLUTION = 64
+
+_key = blake2b(crypto.server_signkey.encode(), person=b'sogs.pow.key')
+
+
+def _mac(token: str, session_id: str, body: bytes):
+    return blake2b((body, token.encode(), b'\0', session_id.encode()), digest_size=16, key=_key)
+
+
+def issue(token: str, session_id: str, *, now=None):
+    """
+    Issues a new challenge (as bytes) for `session_id` to post in room `token`.  Returns a tuple of
+    the challenge and its expiry timestamp.
+    """
+    expiry = int((now or time.time()) + LIFETIME)
+    body = expiry.to_bytes(8, 'big') + os.urandom(16)
+    return body + _mac(token, session_id, body), expiry
+
+
+def leading_zero_bits(h: bytes):
+    """Returns the number of leading zero bits of `h`."""
+    bits = 0
+    for b in h:
+        if b:
+            return bits + 8 - b.bit_length()
+        bits += 8
+    return bits
+
+
+def verify(token: str, session_id: str, challenge: bytes, solution: bytes, difficulty: int):
+    """
+    Returns true if `challenge` is an unexpired challenge issued to `session_id` for room `token`
+    and `solution` solves it with at least `difficulty` bits of work.
+    """
+    if len(challenge) != 40 or not 0 < len(solution) <= MAX_SOLUTION:
+        return False
+    body, mac = challenge[:24], challenge[24:]
+    if not hmac.compare_digest(mac, _mac(token, session_id, body)):
+        return False
+    if int.from_bytes(body[:8], 'big') < time.time():
+        return False
+    h = blake2b((challenge, solution), person=b'sogs.pow')
+    return leading_zero_bits(h) >= difficulty
@@ -1,27 +1,29 @@
-import os
 import time
 
 from .web import app
 from .db import query
-from . import config, db
+from . import config, db, storage
 
-# Cleanup interval, in seconds.
-INTERVAL = 10
+# This is run periodically by the mule, as the `cleanup` job (see sogs.scheduler).
 
 
 def cleanup():
     with app.app_context():
         try:
             app.logger.debug("Pruning expired items")
+            # Before pruning files, so that the attachments of expired messages are pruned too:
+            expired_msgs = prune_expired_messages()
             files = prune_files()
             msg_hist = prune_message_history()
             dms = prune_expired_dms()
+            translations = prune_stale_translations()
             room_act = prune_room_activity()
             perm_upd = apply_permission_updates()
             exp_nonces = expire_nonce_history()
             app.logger.debug(
-                f"Pruned {files} files, {msg_hist} msg hist, {room_act} room activity, "
-                f"{exp_nonces} nonces, {dms} inbox msgs; applied {perm_upd} perm updates."
+                f"Pruned {expired_msgs} expired msgs, {files} files, {msg_hist} msg hist, "
+                f"{room_act} room activity, {exp_nonces} nonces, {dms} inbox msgs, "
+                f"{translations} translations; applied {perm_upd} perm updates."
             )
             return (files, msg_hist, room_act, perm_upd, exp_nonces)
         except Exception as e:
@@ -29,41 +31,72 @@ def cleanup():
             return None
 
 
+def prune_expired_messages():
+    """Deletes messages past their room's retention period (see Room.message_retention)"""
+    from .model.room import get_rooms
+
+    return sum(len(room.delete_expired_posts()) for room in get_rooms())
+
+
 def prune_files():
     now = time.time()
     if db.have_returning:
-        to_remove = [
-            row[0] for row in query("DELETE FROM files WHERE expiry < :exp RETURNING path", exp=now)
-        ]
+        pruned = list(
+            query("DELETE FROM files WHERE expiry < :exp RETURNING path, content_hash", exp=now)
+        )
     else:
         with db.transaction():
-            to_remove = [
-                row[0] for row in query("SELECT path FROM files WHERE expiry < :exp", exp=now)
-            ]
+            pruned = list(
+                query("SELECT path, content_hash FROM files WHERE expiry < :exp", exp=now)
+            )
 
-            if not to_remove:
+            if not pruned:
                 return 0
 
             query("DELETE FROM files WHERE expiry < :exp", exp=now)
 
-    # Committed the transaction, so the files are gone: now go ahead and remove them from disk.
+    to_remove = [path for path, content_hash in pruned if content_hash is None]
+
+    # Deduplicated content is only removed once the last file using it is gone:
+    hashes = list({content_hash for path, content_hash in pruned if content_hash is not None})
+    if hashes:
+        unused = """
+            hash IN :hashes
+            AND NOT EXISTS (SELECT * FROM files WHERE content_hash = file_blobs.hash)
+            """
+        with db.transaction():
+            to_remove += [
+                row[0]
+                for row in query(
+                    f"SELECT path FROM file_blobs WHERE {unused}",
+                    hashes=hashes,
+                    bind_expanding=['hashes'],
+                )
+            ]
+            query(
+                f"DELETE FROM file_blobs WHERE {unused}", hashes=hashes, bind_expanding=['hashes']
+            )
+
+    # Committed the transaction, so the files are gone: now go ahead and remove them from storage.
     unlink_count = 0
     for path in to_remove:
         try:
-            os.unlink(path)
+            storage.delete_file(path)
             unlink_count += 1
         except FileNotFoundError:
             pass
         except Exception as e:
-            app.logger.error("Unable to remove expired upload '{}' from disk: {}".format(path, e))
+            app.logger.error(
+                "Unable to remove expired upload '{}' from storage: {}".format(path, e)
+            )
 
     app.logger.info(
         "Pruned {} expired/deleted files{}".format(
-            len(to_remove),
+            len(pruned),
             " ({} unlinked)".format(unlink_count) if unlink_count != len(to_remove) else "",
         )
     )
-    return len(to_remove)
+    return len(pruned)
 
 
 def prune_message_history():
@@ -85,6 +118,19 @@ def prune_expired_dms():
     return count
 
 
+def prune_stale_translations():
+    count = query(
+        """
+        DELETE FROM message_translations WHERE seqno != (
+            SELECT seqno_data FROM messages WHERE id = message)
+        """
+    ).rowcount
+
+    if count > 0:
+        app.logger.info(f"Removed {count} stale message translations")
+    return count
+
+
 def prune_room_activity():
     count = query(
         "DELETE FROM room_users WHERE last_active < :t",
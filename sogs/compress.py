@@ -0,0 +1,281 @@
+from . import config, db
+from .db import query
+
+import contextlib
+import logging
+import threading
+
+try:
+    import zstandard
+except ModuleNotFoundError:
+    zstandard = None
+
+# Optional zstd compression of stored message bodies ([messages].compression).  Individual Session
+# messages are too short to compress well on their own, but messages within a room are very similar
+# to each other, so we compress them with a dictionary trained on the room's own messages.
+#
+# A compressed message (or message history entry) has `data_dict` set to the id of the
+# `message_dicts` row holding the dictionary it was compressed with; when `data_dict` is null the
+# data is stored as-is.  Dictionaries are never modified once created, so they can be cached
+# indefinitely.  New posts and edits are compressed with the room's most recent dictionary, if it
+# has one: dictionaries are trained (and existing messages recompressed) with `python3 -m sogs
+# --compress-messages`.
+
+logger = logging.getLogger(__name__)
+
+# Maximum size, in bytes, of trained dictionaries
+DICT_SIZE = 65536
+
+# How many of a room's most recent messages to train a dictionary from, and the minimum number
+# required to train one at all
+TRAIN_MESSAGES = 10000
+MIN_TRAIN_MESSAGES = 100
+
+# Maximum size of a decompressed message body; anything larger is treated as corrupt
+MAX_SIZE = 1 << 20
+
+_dicts_lock = threading.Lock()
+# dictionary id => zstandard.ZstdCompressionDict
+_dicts = {}
+
+if config.MESSAGE_COMPRESSION and zstandard is None:
+    logger.warning(
+        "[messages] compression is enabled, but the zstandard module is not installed: new "
+        "messages will be stored uncompressed"
+    )
+
+
+def enabled():
+    """True if new messages should be compressed."""
+    return config.MESSAGE_COMPRESSION and zstandard is not None
+
+
+def _get_dict(dict_id: int):
+    with _dicts_lock:
+        zdict = _dicts.get(dict_id)
+    if zdict is None:
+        row = query("SELECT dict FROM message_dicts WHERE id = :d", d=dict_id).first()
+        if row is None:
+            raise RuntimeError(f"Message compression dictionary {dict_id} does not exist")
+        zdict = zstandard.ZstdCompressionDict(bytes(row[0]))
+        with _dicts_lock:
+            _dicts[dict_id] = zdict
+    return zdict
+
+
+def _compress(data: bytes, dict_id: int):
+    return zstandard.ZstdCompressor(
+        level=config.MESSAGE_COMPRESSION_LEVEL,
+        dict_data=_get_dict(dict_id),
+        write_checksum=False,
+        write_dict_id=False,
+    ).compress(data)
+
+
+def _decompress(data, zdict):
+    return zstandard.ZstdDecompressor(dict_data=zdict).decompress(
+        bytes(data), max_output_size=MAX_SIZE
+    )
+
+
+def room_dict(room_id: int):
+    """Returns the id of the room's most recent compression dictionary, or None if it has none."""
+    return query("SELECT MAX(id) FROM message_dicts WHERE room = :r", r=room_id).first()[0]
+
+
+def encode(room_id: int, data: bytes):
+    """
+    Returns a `(data, data_dict)` tuple of the message body `data` as it should be stored in room
+    `room_id`.  If compression is enabled and the room has a dictionary then this is the compressed
+    data and the dictionary id; otherwise (or if compression wouldn't save anything) it is `data`
+    itself and None.
+    """
+    if not enabled():
+        return data, None
+    dict_id = room_dict(room_id)
+    if dict_id is None:
+        return data, None
+    compressed = _compress(data, dict_id)
+    if len(compressed) >= len(data):
+        return data, None
+    return compressed, dict_id
+
+
+def decode(data, data_dict: int):
+    """
+    Returns the original message body of stored message `data` compressed with dictionary id
+    `data_dict`.  If `data_dict` is None (i.e. the data isn't compressed) or `data` is None (i.e.
+    the message is deleted) then `data` is returned as-is.
+    """
+    if data is None or data_dict is None:
+        return data
+    if zstandard is None:
+        raise RuntimeError("Unable to decompress message: the zstandard module is not installed")
+    return _decompress(data, _get_dict(data_dict))
+
+
+def decode_with(data, raw_dict: bytes):
+    """
+    Decompresses `data` using the raw dictionary content `raw_dict`, which need not be one of ours
+    (e.g. when importing messages from a backup of another server).
+    """
+    if zstandard is None:
+        raise RuntimeError("Unable to decompress message: the zstandard module is not installed")
+    return _decompress(data, zstandard.ZstdCompressionDict(bytes(raw_dict)))
+
+
+def train(room_id: int):
+    """
+    Trains a new compression dictionary from the room's recent messages and stores it as the room's
+    current dictionary.  Returns the new dictionary id, or None if the room doesn't have enough
+    messages to train a dictionary from.
+    """
+    samples = [
+        bytes(decode(data, data_dict))
+        for data, data_dict in query(
+            """
+            SELECT data, data_dict FROM messages
+            WHERE room = :r AND data IS NOT NULL
+            ORDER BY id DESC LIMIT :limit
+            """,
+            r=room_id,
+            limit=TRAIN_MESSAGES,
+        ).fetchall()
+    ]
+    if len(samples) < MIN_TRAIN_MESSAGES:
+        return None
+
+    # A dictionary much larger than a tenth of the training data is mostly wasted space:
+    size = min(DICT_SIZE, max(sum(len(x) for x in samples) // 10, 1024))
+    try:
+        zdict = zstandard.train_dictionary(size, samples)
+    except zstandard.ZstdError as e:
+        logger.warning(f"Unable to train a message compression dictionary for room {room_id}: {e}")
+        return None
+
+    return db.insert_and_get_pk(
+        "INSERT INTO message_dicts (room, dict) VALUES (:r, :d)",
+        "id",
+        r=room_id,
+        d=zdict.as_bytes(),
+    )
+
+
+@contextlib.contextmanager
+def _history_trigger_suspended():
+    """
+    Context manager that removes the trigger that records message edits for the duration of the
+    current transaction, so that recompressing a message doesn't count as an edit.  The trigger is
+    restored on exit (or by the transaction rolling back, if an exception is raised).
+    """
+    if db.engine.name == 'sqlite':
+        sql = query(
+            "SELECT sql FROM sqlite_master WHERE type = 'trigger' AND name = :t",
+            t='messages_insert_history',
+        ).first()[0]
+        query("DROP TRIGGER messages_insert_history")
+        yield
+        query(sql)
+    else:
+        query("ALTER TABLE messages DISABLE TRIGGER messages_insert_history")
+        yield
+        query("ALTER TABLE messages ENABLE TRIGGER messages_insert_history")
+
+
+def compress_room(room_id: int, *, batch: int = 1000):
+    """
+    Trains a new compression dictionary for a room, recompresses all of the room's stored messages
+    and message history with it, and deletes any of the room's older dictionaries that are no
+    longer used.  This works whether or not [messages].compression is enabled (which only controls
+    whether new messages are compressed).
+
+    Returns None if the room doesn't have enough messages to train a dictionary; otherwise returns
+    a dict of `messages` (the number of messages processed), `before` and `after` (the total size
+    in bytes of the stored message and history data before and after recompression).
+    """
+    if zstandard is None:
+        raise RuntimeError("the zstandard module is not installed")
+
+    with db.transaction():
+        dict_id = train(room_id)
+    if dict_id is None:
+        return None
+
+    stats = {'messages': 0, 'before': 0, 'after': 0}
+
+    def recompress(data, data_dict):
+        raw = bytes(decode(data, data_dict))
+        compressed = _compress(raw, dict_id)
+        stats['before'] += len(data)
+        if len(compressed) >= len(raw):
+            stats['after'] += len(raw)
+            return raw, None
+        stats['after'] += len(compressed)
+        return compressed, dict_id
+
+    # Each batch is its own transaction so that we don't block posting for the whole time:
+    last = 0
+    while True:
+        with db.transaction(), _history_trigger_suspended():
+            rows = query(
+                """
+                SELECT id, data, data_dict FROM messages
+                WHERE room = :r AND id > :last
+                ORDER BY id LIMIT :batch
+                """,
+                r=room_id,
+                last=last,
+                batch=batch,
+            ).fetchall()
+            if not rows:
+                break
+
+            for msg_id, data, data_dict in rows:
+                if data is None:
+                    continue
+                data, data_dict = recompress(data, data_dict)
+                query(
+                    "UPDATE messages SET data = :data, data_dict = :dd WHERE id = :m",
+                    m=msg_id,
+                    data=data,
+                    dd=data_dict,
+                )
+
+            for msg_id, sig, data, data_dict in query(
+                """
+                SELECT message, signature, data, data_dict FROM message_history
+                WHERE message IN :ids
+                """,
+                bind_expanding=['ids'],
+                ids=[r[0] for r in rows],
+            ).fetchall():
+                data, data_dict = recompress(data, data_dict)
+                query(
+                    """
+                    UPDATE message_history SET data = :data, data_dict = :dd
+                    WHERE message = :m AND signature = :sig
+                    """,
+                    m=msg_id,
+                    sig=sig,
+                    data=data,
+                    dd=data_dict,
+                )
+
+            stats['messages'] += len(rows)
+            last = rows[-1][0]
+
+    with db.transaction():
+        query(
+            """
+            DELETE FROM message_dicts WHERE room = :r AND id != :d
+                AND id NOT IN (
+                    SELECT data_dict FROM messages WHERE room = :r AND data_dict IS NOT NULL)
+                AND id NOT IN (
+                    SELECT h.data_dict FROM message_history h JOIN messages m ON h.message = m.id
+                    WHERE m.room = :r AND h.data_dict IS NOT NULL)
+            """,
+            r=room_id,
+            d=dict_id,
+        )
+
+    return stats
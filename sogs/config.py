@@ -1,4 +1,5 @@
 import configparser
+import datetime
 import os
 import re
 import logging
@@ -12,33 +13,188 @@ coloredlogs.install(milliseconds=True, isatty=True, logger=logger)
 # Default config settings; most of these are configurable via config.ini (see it for details).
 DB_URL = 'sqlite:///sogs.db'
 KEY_FILE = 'key_x25519'
+KEY_STORAGE = 'file'
+PKCS11_MODULE = None
+PKCS11_TOKEN = None
+PKCS11_KEY_LABEL = 'sogs'
+PKCS11_PIN = None
+CORE_DUMPS = False
 URL_BASE = 'http://example.net'
 HTTP_SHOW_INDEX = True
 HTTP_SHOW_RECENT = True
+STRICT_QUERY_PARAMS = False
+EXTENDED_TIMESTAMPS = False
+TRUSTED_PROXIES = set()
+ANON_READ_LIMIT = 0
+ANON_READ_INTERVAL = 60.0
+ANON_TOKEN_LIMIT = 20
+POST_RATE_LIMIT = 0
+POST_RATE_INTERVAL = 60.0
+UPLOAD_RATE_LIMIT = 0
+UPLOAD_RATE_INTERVAL = 60.0
+PUBLIC_API_LIMIT = 30
+PUBLIC_API_INTERVAL = 60.0
+PUBLIC_API_CACHE = 60.0
+AUTH_CACHE_SIZE = 10000
+SHUTDOWN_TIMEOUT = 10.0  # Seconds
 OMQ_LISTEN = 'tcp://*:22028'
 OMQ_INTERNAL = 'ipc://./omq.sock'
 LOG_LEVEL = 'WARNING'
+DB_CONN_WAIT_WARNING = 1.0  # Seconds
+DB_CONN_HOLD_WARNING = 30.0  # Seconds
+DB_QUERY_TIMEOUT = 20.0  # Seconds
 DM_EXPIRY = 15 * 86400.0  # Seconds, but specified in config file as days
+DM_INBOX_MAX_MESSAGES = 1000  # None for no limit
+DM_INBOX_MAX_BYTES = 10_000_000  # None for no limit
+DM_BLOCKED_SENDS = 'reject'
 UPLOAD_DEFAULT_EXPIRY = 15 * 86400.0  # Seconds (or None), but specified in config file as days
 UPLOAD_FILENAME_MAX = 60
 UPLOAD_FILENAME_KEEP_PREFIX = 40
 UPLOAD_FILENAME_KEEP_SUFFIX = 17
 UPLOAD_FILE_MAX_SIZE = 6_000_000
+UPLOAD_CONTENT_TYPES = set()  # Empty for any content type
+FILES_INLINE_TYPES = set()
+FILES_CONTENT_URL = None
+ONION_MAX_SIZE = 10_000_000
+ONION_WORKERS = 4
+ONION_QUEUE = 32
+ONION_DECRYPT_TIMEOUT = 5.0  # Seconds
 UPLOAD_FILENAME_BAD = re.compile(r"[^\w+\-.'()@\[\]]+")
+UPLOAD_DEDUP = True
+IMAGE_HASHING = True
+IMAGE_BAN_THRESHOLD = 6
+IMAGE_BAN_ACTION = 'reject'
+ROOM_EGRESS_CAP = None  # Bytes per month
+ROOM_STORAGE_CAP = None  # Bytes
+SERVER_STORAGE_CAP = None  # Bytes
+USER_STORAGE_CAP = None  # Bytes
+THUMBNAIL_SIZE = 256  # Pixels; 0 to disable thumbnails
+FILE_INLINE_MAX_SIZE = 4096  # Bytes; 0 to disable inlining
+UPLOAD_COLD_AFTER = None  # Seconds (or None), but specified in config file as days
+UPLOAD_COLD_PATH = None
+UPLOAD_COLD_COMPRESS = True
+STORAGE_BACKEND = 'local'
+S3_ENDPOINT = None
+S3_REGION = None
+S3_BUCKET = None
+S3_PREFIX = ''
+S3_ACCESS_KEY = None
+S3_SECRET_KEY = None
+S3_PRESIGN = False
+S3_PRESIGN_EXPIRY = 300  # Seconds
+S3_COLD_STORAGE_CLASS = None
 ROOM_ACTIVE_PRUNE_THRESHOLD = 60 * 86400.0  # Seconds, but specified in config file as days
 ROOM_DEFAULT_ACTIVE_THRESHOLD = 7 * 86400.0  # Seconds, but specified in config file as days
+ROOM_ACTIVE_WINDOWS = []  # Seconds, but specified in config file as days
+ROOM_PRESENCE_TIMEOUT = 60.0  # Seconds
+ROOM_FEED_SIZE = 20
+ROOM_POLL_LIMIT = 100
+ROOM_POLL_LIMIT_MAX = 256
+ROOM_MIN_ACCOUNT_AGE = None  # Seconds, but specified in config file as hours
+RAID_MODE_DURATION = 3600.0  # Seconds, but specified in config file as minutes
+RAID_MODE_SLOW = 30.0  # Seconds
+RAID_MODE_MIN_ACCOUNT_AGE = 86400.0  # Seconds, but specified in config file as hours
+ROOM_SYSTEM_MESSAGES = False
+ROOM_ROLE_BADGES = False
+ROOM_FIRST_POST_POW = 0
+ROOM_MESSAGE_CACHE = 0
+ROOM_INFO_CACHE = False
+ROOM_ANONYMOUS_WINDOW = 86400.0  # Seconds, but specified in config file as hours
+ROOM_TRANSFER_EXPIRY = 2 * 86400.0  # Seconds, but specified in config file as days
+ROOM_MESSAGE_RETENTION = None  # Seconds (or None), but specified in config file as days
+ROOM_MAX_COUNT = None
+ROOM_MAX_OWNED = None
+ROOM_MAX_PINS = None
+ROOM_ARCHIVE_AFTER = None  # Seconds, but specified in config file as days
+ROOM_ARCHIVE_DELETE_AFTER = None  # Seconds, but specified in config file as days
+ROOM_ARCHIVE_NOTIFY = None
 MESSAGE_HISTORY_PRUNE_THRESHOLD = 30 * 86400.0  # Seconds, but specified in config file as days
 IMPORT_ADJUST_MS = 0
+IMPORT_BACKFILL = False
+IMPORT_VERIFY = False
+IMPORT_VERIFY_WORKERS = 4
 PROFANITY_FILTER = False
 PROFANITY_SILENT = True
 PROFANITY_CUSTOM = None
+PREVIEW_MASK = False
+PREVIEW_MASK_WORDS = None
 ALPHABET_FILTERS = set()
 ALPHABET_SILENT = True
 FILTER_MODS = False
+LINK_POLICY = 'allow'  # One of: allow, allowlist, denylist, none
+LINK_DOMAINS = set()
+MESSAGE_MAX_GRAPHEMES = None
+DISPLAY_NAME_MAX_GRAPHEMES = None
+DISPLAY_NAME_STRICT = False
+MESSAGE_VERIFY_SIGNATURES = False
+MESSAGE_COMPRESSION = False
+MESSAGE_COMPRESSION_LEVEL = 3
+TRANSLATE_URL = None
+TRANSLATE_API_KEY = None
+TRANSLATE_TIMEOUT = 10.0  # Seconds
+FEATURES_DISABLED = set()
+CANARY_ROLLOUTS = {}
+CANARY_COMPARE = False
 REQUIRE_BLIND_KEYS = True
+BRIDGE_IDS = set()
+BRIDGE_MEDIA_TIMEOUT = 30.0  # Seconds
+DIGEST_SMTP_HOST = None
+DIGEST_SMTP_PORT = 25
+DIGEST_SMTP_STARTTLS = False
+DIGEST_SMTP_USER = None
+DIGEST_SMTP_PASSWORD = None
+DIGEST_SMTP_FROM = 'sogs@localhost'
+DIGEST_INTERVAL = 86400.0  # Seconds, but specified in config file as days
+DIGEST_FLOOD_POSTS = 10
+JOURNAL_PATH = None
+JOURNAL_ROTATE_SIZE = 100_000_000  # Bytes, but specified in config file as MB
+JOURNAL_KEEP = 10
+JOURNAL_FSYNC = False
+EVENT_HOOK_URLS = []
+EVENT_HOOK_EVENTS = {'message_posted', 'messages_deleted', 'user_banned'}
+EVENT_HOOK_SECRET = None
+EVENT_HOOK_MAX_ATTEMPTS = 10
+EVENT_HOOK_TIMEOUT = 10.0  # Seconds
+TWO_PERSON_RULE = False
+TWO_PERSON_WINDOW = 3600.0  # Seconds, but specified in config file as minutes
+ADMIN_CLIENT_CERTS = set()
+ADMIN_CLIENT_CERT_HEADER = 'X-SSL-Client-Fingerprint'
+ADMIN_CLIENT_CERT_GROUPS = {'admin'}
+METRICS_TOKEN = None
+OTLP_ENDPOINT = None
+OTLP_SAMPLE_RATE = 0.01
+OTLP_SERVICE_NAME = 'sogs'
+OTLP_METRICS_INTERVAL = 60.0  # Seconds
+SCHEDULE_CLEANUP = '*/10 * * * * *'
+SCHEDULE_COLD_STORAGE = '*/10 * * * * *'
+SCHEDULE_DIGESTS = '* * * * *'
+SCHEDULE_STATS_ROLLUP = '15 0 * * *'
+SCHEDULE_IMPORT_BACKFILL = '*/5 * * * * *'
+SCHEDULE_JOURNAL_CHECKPOINT = '0 * * * *'
+SCHEDULE_VACUUM = None
+SCHEDULE_DIRECTORY = '0 * * * *'
+SCHEDULE_UPGRADE_CHECK = '40 */6 * * *'
+SCHEDULE_ROOM_ARCHIVE = '20 3 * * *'
+SCHEDULE_EVENT_HOOKS = '*/5 * * * * *'
+DIRECTORY_URL = None
+DIRECTORY_TIMEOUT = 10.0
+OUTBOUND_FAILURE_THRESHOLD = 5
+OUTBOUND_COOLDOWN = 60.0  # Seconds
+OUTBOUND_RATE_LIMIT = 300
+OUTBOUND_RATE_INTERVAL = 60.0  # Seconds
+OUTBOUND_PROXY = None
+UPGRADE_FEED_URL = None
+UPGRADE_TIMEOUT = 10.0
 TEMPLATE_PATH = 'templates'
 STATIC_PATH = 'static'
+WEB_STATIC_MAX_AGE = 86400  # Seconds
+WEB_ROBOTS = 'index'  # One of: index, noindex, deny
 UPLOAD_PATH = 'uploads'
+AUTH_PROVIDERS = {'session', 'api_key'}
+AUTH_PLUGINS = set()
+AUTH_GROUPS = {}
+AUTH_LEGACY_TOKENS = True
+AUTH_LEGACY_CUTOFF = None  # Unix timestamp, but specified in config file as a YYYY-MM-DD date
 ROOM_OVERRIDES = {}
 FILTER_SETTINGS = {}
 
@@ -88,9 +244,61 @@ def load_config():
     def days_to_seconds_or_none(v):
         return days_to_seconds(v) if v else None
 
+    def date_to_timestamp_or_none(v):
+        if not v:
+            return None
+        date = datetime.datetime.strptime(v, '%Y-%m-%d')
+        return date.replace(tzinfo=datetime.timezone.utc).timestamp()
+
+    def valid_date(v):
+        try:
+            date_to_timestamp_or_none(v)
+        except ValueError:
+            return False
+        return True
+
+    def cron_schedule(v):
+        if not v:
+            return True
+        from .cron import Schedule
+
+        try:
+            Schedule(v)
+        except ValueError as e:
+            logger.error(str(e))
+            return False
+        return True
+
+    def schedule_opt(name):
+        return (name, cron_schedule, val_or_none)
+
     def set_of_strs(v):
         return {s for s in re.split('[,\\s]+', v) if s != ''}
 
+    link_policies = ('allow', 'allowlist', 'denylist', 'none')
+
+    def feature_set(room=False):
+        # See sogs.features.FEATURES (which we can't import here)
+        known = {'reactions', 'uploads', 'translate', 'reports'}
+        if not room:
+            known.add('dms')
+        return lambda x: set_of_strs(x) <= known
+
+    def rollout_percents(v):
+        return {n: float(p) for n, p in (r.split(':', 1) for r in set_of_strs(v))}
+
+    def valid_rollouts(v):
+        try:
+            return all(0 <= p <= 100 for p in rollout_percents(v).values())
+        except ValueError:
+            return False
+
+    def fingerprint_set(v):
+        return {f.replace(':', '').lower() for f in set_of_strs(v)}
+
+    def domain_set(v):
+        return {d.lower().strip('.') for d in set_of_strs(v)}
+
     truthy = ('y', 'yes', 'Y', 'Yes', 'true', 'True', 'on', 'On', '1')
     falsey = ('n', 'no', 'N', 'No', 'false', 'False', 'off', 'Off', '0')
     booly = truthy + falsey
@@ -123,9 +331,23 @@ def load_config():
     # value lambda extracts the value (if None/omitted use str value as-is)
     setting_map = {
         'db': {
-            'url': ('DB_URL', lambda x: x.startswith('sqlite:///') or x.startswith('postgresql'))
+            'url': ('DB_URL', lambda x: x.startswith('sqlite:///') or x.startswith('postgresql')),
+            'conn_wait_warning': ('DB_CONN_WAIT_WARNING', lambda x: float(x) > 0, float),
+            'conn_hold_warning': ('DB_CONN_HOLD_WARNING', lambda x: float(x) > 0, float),
+            'query_timeout': ('DB_QUERY_TIMEOUT', lambda x: float(x) >= 0, float),
+            'import_backfill': bool_opt('IMPORT_BACKFILL'),
+            'import_verify': bool_opt('IMPORT_VERIFY'),
+            'import_verify_workers': ('IMPORT_VERIFY_WORKERS', lambda x: int(x) > 0, int),
+        },
+        'crypto': {
+            'key_file': ('KEY_FILE',),
+            'key_storage': ('KEY_STORAGE', lambda x: x in ('file', 'encrypted', 'pkcs11')),
+            'pkcs11_module': ('PKCS11_MODULE', path_exists, val_or_none),
+            'pkcs11_token': ('PKCS11_TOKEN', None, val_or_none),
+            'pkcs11_key_label': ('PKCS11_KEY_LABEL', lambda x: len(x) > 0),
+            'pkcs11_pin': ('PKCS11_PIN', None, val_or_none),
+            'core_dumps': bool_opt('CORE_DUMPS'),
         },
-        'crypto': {'key_file': ('KEY_FILE',)},
         'net': {
             'base_url': ('URL_BASE', lambda x: re.search('^https?://.', x)),
             'omq_listen': (
@@ -136,30 +358,298 @@ def load_config():
             'omq_internal': ('OMQ_INTERNAL', lambda x: re.search('^(?:tcp|ipc)://.', x)),
             'http_show_index': bool_opt('HTTP_SHOW_INDEX'),
             'http_show_recent': bool_opt('HTTP_SHOW_RECENT'),
+            'strict_query_params': bool_opt('STRICT_QUERY_PARAMS'),
+            'extended_timestamps': bool_opt('EXTENDED_TIMESTAMPS'),
+            'trusted_proxies': ('TRUSTED_PROXIES', None, set_of_strs),
+            'anon_read_limit': ('ANON_READ_LIMIT', lambda x: int(x) >= 0, int),
+            'anon_read_interval': ('ANON_READ_INTERVAL', lambda x: float(x) > 0, float),
+            'anon_token_limit': ('ANON_TOKEN_LIMIT', lambda x: int(x) >= 0, int),
+            'post_rate_limit': ('POST_RATE_LIMIT', lambda x: int(x) >= 0, int),
+            'post_rate_interval': ('POST_RATE_INTERVAL', lambda x: float(x) > 0, float),
+            'upload_rate_limit': ('UPLOAD_RATE_LIMIT', lambda x: int(x) >= 0, int),
+            'upload_rate_interval': ('UPLOAD_RATE_INTERVAL', lambda x: float(x) > 0, float),
+            'public_api_limit': ('PUBLIC_API_LIMIT', lambda x: int(x) >= 0, int),
+            'public_api_interval': ('PUBLIC_API_INTERVAL', lambda x: float(x) > 0, float),
+            'public_api_cache': ('PUBLIC_API_CACHE', lambda x: float(x) >= 0, float),
+            'auth_cache_size': ('AUTH_CACHE_SIZE', lambda x: int(x) >= 0, int),
+            'shutdown_timeout': ('SHUTDOWN_TIMEOUT', lambda x: float(x) >= 0, float),
+        },
+        'onion': {
+            'max_size': ('ONION_MAX_SIZE', lambda x: int(x) > 0, int),
+            'workers': ('ONION_WORKERS', lambda x: int(x) > 0, int),
+            'queue': ('ONION_QUEUE', lambda x: int(x) >= 0, int),
+            'decrypt_timeout': ('ONION_DECRYPT_TIMEOUT', lambda x: float(x) > 0, float),
         },
         'files': {
             'expiry': ('UPLOAD_DEFAULT_EXPIRY', None, days_to_seconds_or_none),
             'max_size': ('UPLOAD_FILE_MAX_SIZE', None, int),
+            'content_types': ('UPLOAD_CONTENT_TYPES', None, set_of_strs),
+            'inline_types': ('FILES_INLINE_TYPES', None, set_of_strs),
+            'content_url': (
+                'FILES_CONTENT_URL',
+                lambda x: not x or re.search('^https?://[^/]+/?$', x),
+                lambda x: x.rstrip('/') or None,
+            ),
             'uploads_dir': ('UPLOAD_PATH', path_exists, val_or_none),
+            'dedup': bool_opt('UPLOAD_DEDUP'),
+            'image_hashing': bool_opt('IMAGE_HASHING'),
+            'image_ban_threshold': ('IMAGE_BAN_THRESHOLD', lambda x: 0 <= int(x) <= 32, int),
+            'image_ban_action': ('IMAGE_BAN_ACTION', lambda x: x in ('reject', 'quarantine')),
+            'egress_cap': ('ROOM_EGRESS_CAP', lambda x: int(x) >= 0, lambda x: int(x) or None),
+            'storage_cap': ('ROOM_STORAGE_CAP', lambda x: int(x) >= 0, lambda x: int(x) or None),
+            'server_storage_cap': (
+                'SERVER_STORAGE_CAP',
+                lambda x: int(x) >= 0,
+                lambda x: int(x) or None,
+            ),
+            'user_storage_cap': (
+                'USER_STORAGE_CAP',
+                lambda x: int(x) >= 0,
+                lambda x: int(x) or None,
+            ),
+            'thumbnail_size': ('THUMBNAIL_SIZE', lambda x: 0 <= int(x) <= 2048, int),
+            'inline_max_size': ('FILE_INLINE_MAX_SIZE', lambda x: 0 <= int(x) <= 65536, int),
+            'cold_after': ('UPLOAD_COLD_AFTER', None, days_to_seconds_or_none),
+            'cold_dir': ('UPLOAD_COLD_PATH', path_exists, val_or_none),
+            'cold_compress': bool_opt('UPLOAD_COLD_COMPRESS'),
+            'storage': ('STORAGE_BACKEND', lambda x: x in ('local', 's3')),
+            's3_endpoint': ('S3_ENDPOINT', lambda x: re.search('^https?://.', x)),
+            's3_region': ('S3_REGION', None, val_or_none),
+            's3_bucket': ('S3_BUCKET', None, val_or_none),
+            's3_prefix': ('S3_PREFIX',),
+            's3_access_key': ('S3_ACCESS_KEY', None, val_or_none),
+            's3_secret_key': ('S3_SECRET_KEY', None, val_or_none),
+            's3_presign': bool_opt('S3_PRESIGN'),
+            's3_presign_expiry': ('S3_PRESIGN_EXPIRY', lambda x: int(x) > 0, int),
+            's3_cold_storage_class': ('S3_COLD_STORAGE_CLASS', None, val_or_none),
         },
         'rooms': {
             'active_threshold': ('ROOM_DEFAULT_ACTIVE_THRESHOLD', None, days_to_seconds),
+            'active_windows': (
+                'ROOM_ACTIVE_WINDOWS',
+                lambda x: all(float(d) > 0 for d in set_of_strs(x)),
+                lambda x: sorted(float(d) * 86400 for d in set_of_strs(x)),
+            ),
             'active_prune_threshold': ('ROOM_ACTIVE_PRUNE_THRESHOLD', None, days_to_seconds),
+            'presence_timeout': ('ROOM_PRESENCE_TIMEOUT', lambda x: float(x) > 0, float),
+            'feed_size': ('ROOM_FEED_SIZE', lambda x: 1 <= int(x) <= 256, int),
+            'poll_limit': ('ROOM_POLL_LIMIT', lambda x: int(x) >= 1, int),
+            'poll_limit_max': ('ROOM_POLL_LIMIT_MAX', lambda x: int(x) >= 1, int),
+            'min_account_age': (
+                'ROOM_MIN_ACCOUNT_AGE',
+                lambda x: float(x) >= 0,
+                lambda x: float(x) * 3600 or None,
+            ),
+            'raid_mode_duration': (
+                'RAID_MODE_DURATION',
+                lambda x: float(x) > 0,
+                lambda x: float(x) * 60,
+            ),
+            'raid_mode_slow': ('RAID_MODE_SLOW', lambda x: float(x) >= 0, float),
+            'raid_mode_min_account_age': (
+                'RAID_MODE_MIN_ACCOUNT_AGE',
+                lambda x: float(x) >= 0,
+                lambda x: float(x) * 3600,
+            ),
+            'system_messages': bool_opt('ROOM_SYSTEM_MESSAGES'),
+            'role_badges': bool_opt('ROOM_ROLE_BADGES'),
+            'first_post_pow': ('ROOM_FIRST_POST_POW', lambda x: 0 <= int(x) <= 32, int),
+            'message_cache': ('ROOM_MESSAGE_CACHE', lambda x: int(x) >= 0, int),
+            'info_cache': bool_opt('ROOM_INFO_CACHE'),
+            'anonymous_window': (
+                'ROOM_ANONYMOUS_WINDOW',
+                lambda x: float(x) > 0,
+                lambda x: float(x) * 3600,
+            ),
+            'transfer_expiry': ('ROOM_TRANSFER_EXPIRY', lambda x: float(x) > 0, days_to_seconds),
+            'message_retention': (
+                'ROOM_MESSAGE_RETENTION',
+                lambda x: float(x) >= 0,
+                days_to_seconds_or_none,
+            ),
+            'max_rooms': ('ROOM_MAX_COUNT', lambda x: int(x) >= 0, lambda x: int(x) or None),
+            'max_owned': ('ROOM_MAX_OWNED', lambda x: int(x) >= 0, lambda x: int(x) or None),
+            'max_pins': ('ROOM_MAX_PINS', lambda x: int(x) >= 0, lambda x: int(x) or None),
+            'archive_after': (
+                'ROOM_ARCHIVE_AFTER',
+                lambda x: not x or float(x) >= 0,
+                lambda x: days_to_seconds_or_none(x) or None,
+            ),
+            'archive_delete_after': (
+                'ROOM_ARCHIVE_DELETE_AFTER',
+                lambda x: not x or float(x) >= 0,
+                lambda x: days_to_seconds_or_none(x) or None,
+            ),
+            'archive_notify': ('ROOM_ARCHIVE_NOTIFY', None, val_or_none),
+        },
+        'direct_messages': {
+            'expiry': ('DM_EXPIRY', None, days_to_seconds),
+            'inbox_max_messages': (
+                'DM_INBOX_MAX_MESSAGES',
+                lambda x: int(x) >= 0,
+                lambda x: int(x) or None,
+            ),
+            'inbox_max_bytes': (
+                'DM_INBOX_MAX_BYTES',
+                lambda x: int(x) >= 0,
+                lambda x: int(x) or None,
+            ),
+            'blocked_sends': ('DM_BLOCKED_SENDS', lambda x: x in ('reject', 'drop')),
+        },
+        'features': {'disabled': ('FEATURES_DISABLED', feature_set(), set_of_strs)},
+        'canary': {
+            'rollouts': ('CANARY_ROLLOUTS', valid_rollouts, rollout_percents),
+            'compare': bool_opt('CANARY_COMPARE'),
         },
-        'direct_messages': {'expiry': ('DM_EXPIRY', None, days_to_seconds)},
         'users': {'require_blind_keys': bool_opt('REQUIRE_BLIND_KEYS')},
+        'bridge': {
+            'bridge_ids': (
+                'BRIDGE_IDS',
+                lambda x: all(re.search('^[01]5[0-9a-f]{64}$', y) for y in set_of_strs(x)),
+                set_of_strs,
+            ),
+            'media_timeout': ('BRIDGE_MEDIA_TIMEOUT', lambda x: float(x) > 0, float),
+        },
         'messages': {
             'history_prune_threshold': ('MESSAGE_HISTORY_PRUNE_THRESHOLD', None, days_to_seconds),
             'profanity_filter': bool_opt('PROFANITY_FILTER'),
             'profanity_silent': bool_opt('PROFANITY_SILENT'),
             'profanity_custom': ('PROFANITY_CUSTOM', path_exists, val_or_none),
+            'preview_mask': bool_opt('PREVIEW_MASK'),
+            'preview_mask_words': ('PREVIEW_MASK_WORDS', path_exists, val_or_none),
             'alphabet_filters': ('ALPHABET_FILTERS', None, set_of_strs),
             'alphabet_silent': bool_opt('ALPHABET_SILENT'),
             'filter_mods': bool_opt('FILTER_MODS'),
+            'link_policy': ('LINK_POLICY', lambda x: x in link_policies),
+            'link_domains': ('LINK_DOMAINS', None, domain_set),
+            'max_graphemes': (
+                'MESSAGE_MAX_GRAPHEMES',
+                lambda x: int(x) >= 0,
+                lambda x: int(x) or None,
+            ),
+            'name_max_graphemes': (
+                'DISPLAY_NAME_MAX_GRAPHEMES',
+                lambda x: int(x) >= 0,
+                lambda x: int(x) or None,
+            ),
+            'strict_names': bool_opt('DISPLAY_NAME_STRICT'),
+            'verify_signatures': bool_opt('MESSAGE_VERIFY_SIGNATURES'),
+            'compression': bool_opt('MESSAGE_COMPRESSION'),
+            'compression_level': ('MESSAGE_COMPRESSION_LEVEL', lambda x: 1 <= int(x) <= 22, int),
+            'translate_url': (
+                'TRANSLATE_URL',
+                lambda x: not x or re.search('^https?://.', x),
+                val_or_none,
+            ),
+            'translate_api_key': ('TRANSLATE_API_KEY', None, val_or_none),
+            'translate_timeout': ('TRANSLATE_TIMEOUT', lambda x: float(x) > 0, float),
+        },
+        'digest': {
+            'smtp_host': ('DIGEST_SMTP_HOST', None, val_or_none),
+            'smtp_port': ('DIGEST_SMTP_PORT', lambda x: 0 < int(x) < 65536, int),
+            'smtp_starttls': bool_opt('DIGEST_SMTP_STARTTLS'),
+            'smtp_user': ('DIGEST_SMTP_USER', None, val_or_none),
+            'smtp_password': ('DIGEST_SMTP_PASSWORD', None, val_or_none),
+            'from': ('DIGEST_SMTP_FROM', lambda x: '@' in x),
+            'interval': ('DIGEST_INTERVAL', lambda x: float(x) > 0, days_to_seconds),
+            'flood_posts': ('DIGEST_FLOOD_POSTS', lambda x: int(x) > 0, int),
+        },
+        'journal': {
+            'path': ('JOURNAL_PATH', None, val_or_none),
+            'rotate_size': (
+                'JOURNAL_ROTATE_SIZE',
+                lambda x: float(x) >= 0,
+                lambda x: int(float(x) * 1_000_000),
+            ),
+            'keep': ('JOURNAL_KEEP', lambda x: int(x) >= 0, int),
+            'fsync': bool_opt('JOURNAL_FSYNC'),
+        },
+        'event_hooks': {
+            'urls': (
+                'EVENT_HOOK_URLS',
+                lambda x: all(re.search('^https?://.', y) for y in x.split()),
+                lambda x: x.split(),
+            ),
+            'events': ('EVENT_HOOK_EVENTS', None, set_of_strs),
+            'secret': ('EVENT_HOOK_SECRET', None, val_or_none),
+            'max_attempts': ('EVENT_HOOK_MAX_ATTEMPTS', lambda x: int(x) > 0, int),
+            'timeout': ('EVENT_HOOK_TIMEOUT', lambda x: float(x) > 0, float),
+        },
+        'admin': {
+            'two_person_rule': bool_opt('TWO_PERSON_RULE'),
+            'confirm_window': (
+                'TWO_PERSON_WINDOW',
+                lambda x: float(x) > 0,
+                lambda x: float(x) * 60,
+            ),
+            'client_certs': (
+                'ADMIN_CLIENT_CERTS',
+                lambda x: all(
+                    re.search('^[0-9a-f]{40}(?:[0-9a-f]{24})?$', f) for f in fingerprint_set(x)
+                ),
+                fingerprint_set,
+            ),
+            'client_cert_header': ('ADMIN_CLIENT_CERT_HEADER',),
+            'client_cert_groups': ('ADMIN_CLIENT_CERT_GROUPS', None, set_of_strs),
+            'metrics_token': ('METRICS_TOKEN', None, val_or_none),
+        },
+        'otlp': {
+            'endpoint': (
+                'OTLP_ENDPOINT',
+                lambda x: not x or re.search('^https?://.', x),
+                val_or_none,
+            ),
+            'sample_rate': ('OTLP_SAMPLE_RATE', lambda x: 0 <= float(x) <= 1, float),
+            'service_name': ('OTLP_SERVICE_NAME', lambda x: len(x) > 0),
+            'metrics_interval': ('OTLP_METRICS_INTERVAL', lambda x: float(x) >= 0, float),
+        },
+        'directory': {
+            'url': ('DIRECTORY_URL', lambda x: not x or re.search('^https?://.', x), val_or_none),
+            'timeout': ('DIRECTORY_TIMEOUT', lambda x: float(x) > 0, float),
+        },
+        'outbound': {
+            'failure_threshold': ('OUTBOUND_FAILURE_THRESHOLD', lambda x: int(x) > 0, int),
+            'cooldown': ('OUTBOUND_COOLDOWN', lambda x: float(x) > 0, float),
+            'rate_limit': ('OUTBOUND_RATE_LIMIT', lambda x: int(x) >= 0, int),
+            'rate_interval': ('OUTBOUND_RATE_INTERVAL', lambda x: float(x) > 0, float),
+            'proxy': (
+                'OUTBOUND_PROXY',
+                lambda x: not x or re.search('^(?:https?|socks5h?)://[^/]', x),
+                val_or_none,
+            ),
+        },
+        'upgrade': {
+            'feed_url': (
+                'UPGRADE_FEED_URL',
+                lambda x: not x or re.search('^https?://.', x),
+                val_or_none,
+            ),
+            'timeout': ('UPGRADE_TIMEOUT', lambda x: float(x) > 0, float),
+        },
+        'schedule': {
+            'cleanup': schedule_opt('SCHEDULE_CLEANUP'),
+            'cold_storage': schedule_opt('SCHEDULE_COLD_STORAGE'),
+            'digests': schedule_opt('SCHEDULE_DIGESTS'),
+            'stats_rollup': schedule_opt('SCHEDULE_STATS_ROLLUP'),
+            'import_backfill': schedule_opt('SCHEDULE_IMPORT_BACKFILL'),
+            'journal_checkpoint': schedule_opt('SCHEDULE_JOURNAL_CHECKPOINT'),
+            'vacuum': schedule_opt('SCHEDULE_VACUUM'),
+            'directory': schedule_opt('SCHEDULE_DIRECTORY'),
+            'upgrade_check': schedule_opt('SCHEDULE_UPGRADE_CHECK'),
+            'room_archive': schedule_opt('SCHEDULE_ROOM_ARCHIVE'),
+            'event_hooks': schedule_opt('SCHEDULE_EVENT_HOOKS'),
+        },
+        'auth': {
+            'providers': ('AUTH_PROVIDERS', None, set_of_strs),
+            'plugins': ('AUTH_PLUGINS', None, set_of_strs),
+            'legacy_tokens': bool_opt('AUTH_LEGACY_TOKENS'),
+            'legacy_cutoff': ('AUTH_LEGACY_CUTOFF', valid_date, date_to_timestamp_or_none),
         },
         'web': {
             'template_path': ('TEMPLATE_PATH', path_exists, val_or_none),
             'static_path': ('STATIC_PATH', path_exists, val_or_none),
+            'static_max_age': ('WEB_STATIC_MAX_AGE', lambda x: int(x) >= 0, int),
+            'robots': ('WEB_ROBOTS', lambda x: x in ('index', 'noindex', 'deny')),
         },
         'log': {'level': ('LOG_LEVEL',)},
     }
@@ -168,16 +658,40 @@ def load_config():
         'profanity_filter': bool_opt('profanity_filter'),
         'profanity_silent': bool_opt('profanity_silent'),
         'alphabet_filters': ('alphabet_filters', None, set_of_strs),
+        'preview_mask': bool_opt('preview_mask'),
+        'feed': bool_opt('feed'),
+        'egress_cap': ('egress_cap', lambda x: int(x) >= 0, int),
+        'storage_cap': ('storage_cap', lambda x: int(x) >= 0, int),
+        'message_retention': ('message_retention', lambda x: float(x) >= 0, days_to_seconds),
+        'poll_limit': ('poll_limit', lambda x: int(x) >= 1, int),
+        'poll_limit_max': ('poll_limit_max', lambda x: int(x) >= 1, int),
+        'max_pins': ('max_pins', lambda x: int(x) >= 0, int),
+        'min_account_age': ('min_account_age', lambda x: float(x) >= 0, lambda x: float(x) * 3600),
+        'link_policy': ('link_policy', lambda x: x in link_policies),
+        'link_domains': ('link_domains', None, domain_set),
+        'system_messages': bool_opt('system_messages'),
+        'role_badges': bool_opt('role_badges'),
+        'anonymous': bool_opt('anonymous'),
+        'first_post_pow': ('first_post_pow', lambda x: 0 <= int(x) <= 32, int),
+        'public_api': bool_opt('public_api'),
+        'directory': bool_opt('directory'),
+        'extended_timestamps': bool_opt('extended_timestamps'),
+        'archive': bool_opt('archive'),
+        'disabled_features': ('disabled_features', feature_set(room=True), set_of_strs),
     }
 
+    auth_setting_map = {'providers': ('providers', None, set_of_strs)}
+
     filter_setting_map = {
         'public': bool_opt('public'),
         'profile_name': ('profile_name',),
         'reply': ('reply', None, reply_to_format),
     }
 
-    def parse_option(fields, s, opt, *, room=None, filt=None):
-        conf_type = 'room-specific ' if room else 'filter ' if filt else ''
+    def parse_option(fields, s, opt, *, room=None, filt=None, group=None):
+        conf_type = (
+            'room-specific ' if room else 'filter ' if filt else 'auth group ' if group else ''
+        )
         if opt not in fields:
             logger.warning(f"Ignoring unknown {conf_type} config setting [{s}].{opt} in {conf_ini}")
             return
@@ -185,7 +699,7 @@ def load_config():
         value = cp[s][opt]
 
         assert isinstance(conf, tuple) and 1 <= len(conf) <= 3
-        if not room and not filt:
+        if not room and not filt and not group:
             assert conf[0] in globals()
 
         logger.debug(f"Loaded {'room-specific ' if room else ''}config setting [{s}].{opt}={value}")
@@ -203,6 +717,9 @@ def load_config():
         elif filt:
             logger.debug(f"Set config.FILTER_SETTINGS[{filt[0]}][{filt[1]}][{conf[0]}] = {value}")
             FILTER_SETTINGS.setdefault(filt[0], {}).setdefault(filt[1], {})[conf[0]] = value
+        elif group:
+            logger.debug(f"Set config.AUTH_GROUPS[{group}][{conf[0]}] = {value}")
+            AUTH_GROUPS.setdefault(group, {})[conf[0]] = value
         else:
             logger.debug(f"Set config.{conf[0]} = {value}")
             globals()[conf[0]] = value
@@ -215,6 +732,10 @@ def load_config():
             for opt in cp[s]:
                 parse_option(room_setting_map, s, opt, room=token)
 
+        elif len(s) > 5 and s.startswith('auth:'):
+            for opt in cp[s]:
+                parse_option(auth_setting_map, s, opt, group=s[5:])
+
         elif s.startswith('filter:'):
             filt = s.split(':')[1:]
             if len(filt) != 2:
@@ -231,6 +752,9 @@ def load_config():
         else:
             logger.warning(f"Ignoring unknown section [{s}] in {conf_ini}")
 
+    if STORAGE_BACKEND == 's3' and not S3_BUCKET:
+        raise RuntimeError(f"Invalid config in {conf_ini}: [files].storage=s3 requires s3_bucket")
+
 
 try:
     load_config()
@@ -0,0 +1,110 @@
+import calendar
+import time
+
+# Parsing and evaluation of cron-style schedule expressions, used for the background job schedules
+# in the [schedule] config section.  We accept standard 5-field expressions:
+#
+#     minute hour day-of-month month day-of-week
+#
+# or 6-field expressions with an extra leading seconds field (for jobs that need to run more often
+# than once per minute).  Each field may be `*`, a number, a range (`1-5`), a list (`1,3,5`), or any
+# of these followed by a step (`*/10`, `0-30/5`).  Day-of-week 0 and 7 are both Sunday.  As with
+# cron, if both day-of-month and day-of-week are restricted then a time matching *either* matches.
+# All times are evaluated in UTC.
+
+_FIELDS = (
+    # name, min, max
+    ('second', 0, 59),
+    ('minute', 0, 59),
+    ('hour', 0, 23),
+    ('day', 1, 31),
+    ('month', 1, 12),
+    ('weekday', 0, 7),
+)
+
+
+class Schedule:
+    """
+    A parsed cron-style schedule.  Raises ValueError on construction if the expression is invalid.
+    """
+
+    def __init__(self, expr: str):
+        self.expr = expr
+        parts = expr.split()
+        if len(parts) == 5:
+            parts = ['0'] + parts
+        if len(parts) != 6:
+            raise ValueError(f"Invalid schedule '{expr}': expected 5 or 6 fields")
+
+        self.any_day = parts[3] == '*'
+        self.any_weekday = parts[5] == '*'
+        (
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days,
+            self.months,
+            self.weekdays,
+        ) = (_parse_field(p, *f) for p, f in zip(parts, _FIELDS))
+        if 7 in self.weekdays:
+            self.weekdays = (self.weekdays - {7}) | {0}
+
+    def _day_matches(self, year, month, day):
+        weekday = (calendar.weekday(year, month, day) + 1) % 7  # cron uses 0 = Sunday
+        if self.any_day or self.any_weekday:
+            return day in self.days and weekday in self.weekdays
+        return day in self.days or weekday in self.weekdays
+
+    def next_after(self, when: float):
+        """
+        Returns the unix timestamp of the first time strictly after `when` that matches this
+        schedule, or None if the schedule never matches (e.g. February 31st).
+        """
+        t = int(when) + 1
+        # Search day-by-day (for up to 5 years, to cover leap days) for a matching day, then within
+        # that day for the first matching time.
+        for _ in range(5 * 366 + 1):
+            tm = time.gmtime(t)
+            if tm.tm_mon in self.months and self._day_matches(tm.tm_year, tm.tm_mon, tm.tm_mday):
+                day_start = t - (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec)
+                for h in sorted(self.hours):
+                    for m in sorted(self.minutes):
+                        for s in sorted(self.seconds):
+                            candidate = day_start + h * 3600 + m * 60 + s
+                            if candidate >= t:
+                                return candidate
+            # Move to the start of the next day:
+            t = t - (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) + 86400
+        return None
+
+    def __repr__(self):
+        return f"Schedule('{self.expr}')"
+
+
+def _parse_field(field: str, name: str, lo: int, hi: int):
+    values = set()
+    for item in field.split(','):
+        step = 1
+        if '/' in item:
+            item, step = item.split('/', 1)
+            step = _parse_int(step, name)
+            if step < 1:
+                raise ValueError(f"Invalid {name} step {step}")
+        if item == '*':
+            first, last = lo, hi
+        elif '-' in item:
+            first, last = (_parse_int(x, name) for x in item.split('-', 1))
+        else:
+            first = _parse_int(item, name)
+            last = hi if step > 1 else first
+        if not lo <= first <= last <= hi:
+            raise ValueError(f"Invalid {name} value '{item}': must be in [{lo}, {hi}]")
+        values.update(range(first, last + 1, step))
+    return values
+
+
+def _parse_int(val: str, name: str):
+    try:
+        return int(val)
+    except ValueError:
+        raise ValueError(f"Invalid {name} value '{val}'")
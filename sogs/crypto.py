@@ -1,8 +1,8 @@
-from . import config
+from . import config, keystore
 
-import os
 
 import nacl
+import nacl.secret
 from nacl.public import PrivateKey
 from nacl.signing import SigningKey, VerifyKey
 from nacl.encoding import Base64Encoder, HexEncoder
@@ -26,25 +26,42 @@ if [int(v) for v in nacl.__version__.split('.')] < [1, 4]:
 
 def persist_privkey():
     """
-    Writes the current private key to disk if it is ephemeral.  This is done automatically when a
-    private key is generated in uwsgi application mode; for other interfaces it needs to be called
-    manually if the key should be persisted.
+    Writes the current private key to the key storage (see sogs.keystore) if it is ephemeral.  This
+    is done automatically when a private key is generated in uwsgi application mode; for other
+    interfaces it needs to be called manually if the key should be persisted.
 
-    If the key was loaded from disk originally then this does nothing.
+    If the key was loaded from the key storage originally then this does nothing.
     """
     global ephemeral_privkey
     if ephemeral_privkey:
-        with open(os.open(config.KEY_FILE, os.O_CREAT | os.O_WRONLY, 0o400), 'wb') as f:
-            f.write(_privkey.encode())
+        keystore.get().store(_privkey.encode())
         ephemeral_privkey = False
 
 
+def disable_core_dumps():
+    """
+    Prevents the process from writing core dumps (unless enabled with [crypto] `core_dumps`).  Key
+    material lives in immutable Python objects (which nacl and the other crypto libraries copy
+    freely) that can't be zeroed after use, so this at least keeps it out of crash dumps.
+    """
+    if config.CORE_DUMPS:
+        return
+    try:
+        import resource
+
+        resource.setrlimit(resource.RLIMIT_CORE, (0, 0))
+    except (ImportError, ValueError, OSError) as e:
+        config.logger.warning(f"Unable to disable core dumps: {e}")
+
+
+disable_core_dumps()
+
 ephemeral_privkey = True
 
 # generate seed as needed
-if os.path.exists(config.KEY_FILE):
-    with open(config.KEY_FILE, 'rb') as f:
-        _privkey = PrivateKey(f.read())
+_stored_privkey = keystore.get().load()
+if _stored_privkey is not None:
+    _privkey = PrivateKey(_stored_privkey)
     ephemeral_privkey = False
 else:
     _privkey = PrivateKey.generate()
@@ -53,6 +70,7 @@ else:
     # place wherever sogs is imported.
     if config.RUNNING_AS_APP:
         persist_privkey()
+del _stored_privkey
 
 _privkey_bytes = _privkey.encode()
 
@@ -151,3 +169,34 @@ def blinded_neg(blinded_id: str):
     if msn & 0x8:
         return blinded_id
     return blinded_id[0:64] + f"{msn | 0x8:x}" + blinded_id[65:]
+
+
+def anonymous_signkey(token: str, session_id: str, window: int):
+    """
+    Returns the Ed25519 SigningKey of the pseudonym used for `session_id`'s posts in the anonymous
+    room `token` during time window number `window`.  The key is derived from the server's private
+    key, so it is stable for the window but unlinkable to the session id (or to the pseudonyms of
+    other windows) by anyone else.  The pseudonym's session id is `15` followed by the hex public
+    key, which clients verify signatures against just as they do for blinded ids.
+    """
+    return SigningKey(
+        blake2b(
+            [_privkey_bytes, token.encode(), b'\0', session_id.encode(), window.to_bytes(8, 'big')],
+            person=b'sogs.anonymous',
+        )
+    )
+
+
+# Key for sealing the real authors of anonymous posts, so that the mapping is stored encrypted and
+# can only be opened by the server (for admins investigating abuse).
+_anonymous_box = nacl.secret.SecretBox(blake2b(_privkey_bytes, person=b'sogs.anon.seal'))
+
+
+def seal_author(session_id: str) -> bytes:
+    """Encrypts the real author `session_id` of an anonymous post for storage."""
+    return _anonymous_box.encrypt(session_id.encode())
+
+
+def unseal_author(sealed: bytes) -> str:
+    """Decrypts a real author session id sealed by `seal_author`."""
+    return _anonymous_box.decrypt(bytes(sealed)).decode()
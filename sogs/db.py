@@ -1,11 +1,18 @@
 from . import config
 from . import crypto
+from . import metrics
+from . import tracing
 from .postfork import postfork
+from .shutdown import on_shutdown
+import contextlib
 import os
 import logging
 import importlib.resources
 import sqlalchemy
 from sqlalchemy.sql.expression import bindparam
+import threading
+import time
+import traceback
 
 HAVE_FILE_ID_HACKS = False
 # roomid => (max, offset).  Max is the highest message id that was in the old table; offset is the
@@ -16,7 +23,157 @@ ROOM_IMPORT_HACKS = {}
 def get_conn():
     """Gets a connection from the database engine connection pool.  This is not intended to be used
     by flask endpoints: they should use web.appdb instead (which calls this upon first use)."""
-    return engine.connect()
+    start = time.time()
+    try:
+        conn = engine.connect()
+    except sqlalchemy.exc.TimeoutError:
+        metrics.incr('db.pool.exhausted')
+        logging.error(
+            f"Database connection pool exhausted ({engine.pool.status()}); connections held by: "
+            + ", ".join(
+                f"{owner} ({start - since:.1f}s)" for since, owner, _ in list(_checked_out.values())
+            )
+        )
+        raise
+
+    wait = time.time() - start
+    metrics.observe('db.pool.checkout_wait', wait)
+    if wait >= config.DB_CONN_WAIT_WARNING:
+        logging.warning(f"Waited {wait:.2f}s for a database connection ({engine.pool.status()})")
+    return conn
+
+
+# Connections currently checked out of the pool, keyed by the id() of the pool's connection record,
+# with values of [checkout time, description of the code holding the connection, warned].
+_checked_out = {}
+
+
+def _conn_owner():
+    """Describes the code currently checking out a connection, for long-held connection warnings"""
+    import flask
+
+    if flask.has_request_context():
+        return f"{flask.request.method} {flask.request.path} [{flask.request.endpoint}]"
+
+    # Not in a request, so find the first caller outside sqlalchemy and this file:
+    for frame in reversed(traceback.extract_stack()[:-2]):
+        if 'sqlalchemy' not in frame.filename and frame.filename != __file__:
+            return (
+                f"{frame.name} ({frame.filename}:{frame.lineno}) "
+                f"[thread {threading.current_thread().name}]"
+            )
+    return f"unknown [thread {threading.current_thread().name}]"
+
+
+def check_held_connections(now=None):
+    """
+    Warns (once) about each pooled connection that has been checked out for longer than
+    config.DB_CONN_HOLD_WARNING seconds without being returned, which likely indicates a leak.
+    Returns the number of such connections.
+    """
+    if now is None:
+        now = time.time()
+    count = 0
+    for co in list(_checked_out.values()):
+        if now - co[0] >= config.DB_CONN_HOLD_WARNING:
+            count += 1
+            if not co[2]:
+                co[2] = True
+                metrics.incr('db.pool.long_held')
+                logging.warning(
+                    f"Database connection checked out by {co[1]} has been held for "
+                    f"{now - co[0]:.1f}s without being returned; possible connection leak"
+                )
+    return count
+
+
+def _instrument_pool(engine):
+    @sqlalchemy.event.listens_for(engine, "checkout")
+    def pool_checkout(dbapi_connection, connection_record, connection_proxy):
+        now = time.time()
+        _checked_out[id(connection_record)] = [now, _conn_owner(), False]
+        metrics.incr('db.pool.checkouts')
+        check_held_connections(now)
+
+    @sqlalchemy.event.listens_for(engine, "checkin")
+    def pool_checkin(dbapi_connection, connection_record):
+        co = _checked_out.pop(id(connection_record), None)
+        if co is None:
+            return
+        held = time.time() - co[0]
+        metrics.observe('db.pool.hold_time', held)
+        if held >= config.DB_CONN_HOLD_WARNING and not co[2]:
+            metrics.incr('db.pool.long_held')
+            logging.warning(f"Database connection was held for {held:.1f}s by {co[1]}")
+
+
+def pool_stats():
+    """
+    Returns a dict of connection pool statistics for this process: `checked_out` (the number of
+    connections currently checked out), `longest_held` (the number of seconds the
+    longest-outstanding connection has been checked out, or 0), `checkouts`, `exhausted` (the
+    number of times a connection could not be obtained because the pool was exhausted), and
+    `long_held` (the number of connections held longer than the warning threshold), plus
+    `checkout_wait` and `hold_time` observations (see metrics.observe), and the pool's own `status`
+    description.
+    """
+    now = time.time()
+    held = [now - co[0] for co in list(_checked_out.values())]
+    m = metrics.snapshot()
+    empty = {'count': 0, 'total': 0.0, 'max': 0.0}
+    return {
+        'checked_out': len(held),
+        'longest_held': max(held, default=0),
+        'checkouts': m['counters'].get('db.pool.checkouts', 0),
+        'exhausted': m['counters'].get('db.pool.exhausted', 0),
+        'long_held': m['counters'].get('db.pool.long_held', 0),
+        'checkout_wait': m['observations'].get('db.pool.checkout_wait', empty),
+        'hold_time': m['observations'].get('db.pool.hold_time', empty),
+        'status': engine.pool.status(),
+    }
+
+
+class QueryTimeout(RuntimeError):
+    """Thrown when a query is aborted because it exceeded the query timeout"""
+
+    def __init__(self, timeout):
+        self.timeout = timeout
+        super().__init__(f"Database query exceeded the {timeout:g}s query timeout")
+
+
+def _query_timeout():
+    """
+    Returns the timeout to apply to queries made now: config.DB_QUERY_TIMEOUT while handling a
+    request, and None (i.e. unlimited) otherwise, or if the timeout is disabled.
+    """
+    import flask
+
+    if config.DB_QUERY_TIMEOUT and flask.has_request_context():
+        return config.DB_QUERY_TIMEOUT
+    return None
+
+
+def _set_query_timeout(dbconn, timeout):
+    """Applies the query timeout (None for no timeout) to the next statement(s) on `dbconn`"""
+    info = dbconn.info
+    if engine.name == 'sqlite':
+        # Enforced by the progress handler installed when the connection is created
+        info['sogs_deadline'] = None if timeout is None else time.time() + timeout
+    elif engine.name == 'postgresql':
+        ms = 0 if timeout is None else max(int(timeout * 1000), 1)
+        if info.get('sogs_statement_timeout', 0) != ms:
+            dbconn.execute(
+                sqlalchemy.text("SELECT set_config('statement_timeout', :ms, FALSE)"), ms=str(ms)
+            )
+            info['sogs_statement_timeout'] = ms
+
+
+def _is_timeout(e):
+    """Returns true if sqlalchemy exception `e` is the result of an aborted (timed out) query"""
+    if engine.name == 'postgresql':
+        # 57014 = query_canceled
+        return getattr(e.orig, 'pgcode', None) == '57014'
+    return 'interrupted' in str(e.orig)
 
 
 def query(query, *, dbconn=None, bind_expanding=None, **params):
@@ -39,6 +196,10 @@ def query(query, *, dbconn=None, bind_expanding=None, **params):
 
     Can execute on a specific connection by passing it as dbconn; if omitted, uses web.appdb.  (Note
     that dbconn *cannot* be used as a placeholder bind name).
+
+    Queries made while handling a request are aborted if they run for longer than
+    config.DB_QUERY_TIMEOUT seconds, in which case this raises a QueryTimeout.  (For sqlite the
+    limit also applies to time spent fetching the rows from the result).
     """
 
     if dbconn is None:
@@ -51,22 +212,108 @@ def query(query, *, dbconn=None, bind_expanding=None, **params):
     if bind_expanding:
         q = q.bindparams(*(bindparam(c, expanding=True) for c in bind_expanding))
 
-    return dbconn.execute(q, **params)
+    timeout = _query_timeout()
+    _set_query_timeout(dbconn, timeout)
+    try:
+        return dbconn.execute(q, **params)
+    except sqlalchemy.exc.OperationalError as e:
+        if timeout is None or not _is_timeout(e):
+            raise
+        metrics.incr('db.query_timeouts')
+        logging.warning(
+            f"Aborted database query after exceeding the {timeout:g}s query timeout "
+            f"({_conn_owner()}): {' '.join(query.split())[:500]}"
+        )
+        raise QueryTimeout(timeout) from e
 
 
 # Begins a (potentially nested) transaction.  Takes an optional connection; if omitted uses
-# web.appdb.
+# web.appdb.  Once the outermost transaction commits, this runs any callbacks queued during it with
+# `after_commit`.
+@contextlib.contextmanager
 def transaction(dbconn=None):
     if dbconn is None:
         from . import web
 
         dbconn = web.appdb
-    return dbconn.begin_nested()
+    outermost = not dbconn.in_transaction()
+    if outermost:
+        # Anything left here is from a transaction that never finished (e.g. a connection that was
+        # returned to the pool in the middle of one):
+        dbconn.info.pop('sogs_after_commit', None)
+        dbconn.info.pop('sogs_savepoints', None)
+    with dbconn.begin_nested() as t:
+        yield t
+    if outermost:
+        for _, callback in dbconn.info.pop('sogs_after_commit', []):
+            try:
+                callback()
+            except Exception as e:
+                logging.warning(f"After-commit callback failed: {e}")
+
+
+def after_commit(callback, *, dbconn=None):
+    """
+    Calls `callback` (with no arguments) once the transaction currently in progress on `dbconn` (if
+    omitted, web.appdb) has been committed, or immediately if there isn't one.  The callback is
+    dropped if the transaction, or the savepoint (i.e. nested transaction) in progress when this was
+    called, is rolled back instead.  Used for side effects, such as journalling, that must only
+    happen for changes that actually get made.
+    """
+    if dbconn is None:
+        from . import web
+
+        dbconn = web.appdb
+    if not dbconn.in_transaction():
+        callback()
+        return
+    depth = len(dbconn.info.get('sogs_savepoints', ()))
+    dbconn.info.setdefault('sogs_after_commit', []).append((depth, callback))
+
+
+def _track_transactions(engine):
+    """Installs the event handlers that `after_commit` uses to drop rolled back callbacks"""
+
+    @sqlalchemy.event.listens_for(engine, "savepoint")
+    def track_savepoint(conn, name):
+        conn.info.setdefault('sogs_savepoints', []).append(name)
+
+    @sqlalchemy.event.listens_for(engine, "release_savepoint")
+    def track_release_savepoint(conn, name, context):
+        savepoints = conn.info.get('sogs_savepoints', [])
+        if name in savepoints:
+            del savepoints[savepoints.index(name) :]
+
+    @sqlalchemy.event.listens_for(engine, "rollback_savepoint")
+    def track_rollback_savepoint(conn, name, context):
+        savepoints = conn.info.get('sogs_savepoints', [])
+        if name in savepoints:
+            depth = savepoints.index(name)
+            del savepoints[depth:]
+            conn.info['sogs_after_commit'] = [
+                cb for cb in conn.info.get('sogs_after_commit', []) if cb[0] <= depth
+            ]
+
+    @sqlalchemy.event.listens_for(engine, "rollback")
+    def track_rollback(conn):
+        conn.info.pop('sogs_savepoints', None)
+        conn.info.pop('sogs_after_commit', None)
 
 
 have_returning = True
 
 
+def floor_div(numerator: str, denominator: str):
+    """
+    Returns an SQL expression for the integer division (rounded down) of two non-negative numeric
+    SQL expressions, such as for bucketing timestamps: e.g. `floor_div('posted', ':size')`.
+    """
+    # sqlite's integer cast truncates, but postgresql's rounds:
+    if engine.name == 'postgresql':
+        return f"FLOOR(({numerator}) / ({denominator}))"
+    return f"CAST(({numerator}) / ({denominator}) AS INTEGER)"
+
+
 def insert_and_get_pk(insert, _pk, *, dbconn=None, **params):
     """
     Performs an insert and returns the value of the primary key by appending a RETURNING clause, if
@@ -275,6 +522,10 @@ def init_engine(*args, **kwargs):
     engine = sqlalchemy.create_engine(*args, **kwargs).execution_options(**exec_opts_args)
     engine_initial_pid = os.getpid()
     metadata = sqlalchemy.MetaData()
+    _checked_out.clear()
+    _instrument_pool(engine)
+    _track_transactions(engine)
+    tracing.instrument_engine(engine)
 
     if engine.name == "sqlite":
         import sqlite3
@@ -297,6 +548,16 @@ def init_engine(*args, **kwargs):
             cursor.execute("PRAGMA foreign_keys=ON")
             cursor.close()
 
+            # Query timeouts: sqlite periodically calls this during query execution, and interrupts
+            # the query if it returns true.  `query()` sets the deadline before each query.
+            info = connection_record.info
+
+            def check_deadline():
+                deadline = info.get('sogs_deadline')
+                return deadline is not None and time.time() > deadline
+
+            dbapi_connection.set_progress_handler(check_deadline, 10000)
+
         @sqlalchemy.event.listens_for(engine, "begin")
         def do_begin(conn):
             # emit our own BEGIN
@@ -314,6 +575,16 @@ def init_engine(*args, **kwargs):
         if 'citext' not in ischema_names:
             ischema_names['citext'] = ischema_names['text']
 
+        # Rolling back can revert a statement_timeout we set (see _set_query_timeout), so forget
+        # what we set whenever that happens.
+        @sqlalchemy.event.listens_for(engine, "rollback")
+        def forget_timeout_rollback(conn):
+            conn.info.pop('sogs_statement_timeout', None)
+
+        @sqlalchemy.event.listens_for(engine, "rollback_savepoint")
+        def forget_timeout_rollback_savepoint(conn, name, context):
+            conn.info.pop('sogs_statement_timeout', None)
+
     if preinit:
         preinit()
 
@@ -322,6 +593,9 @@ def init_engine(*args, **kwargs):
 
 
 if config.RUNNING_AS_APP:
+    from . import layout
+
+    layout.validate()
     init_engine()
 
 
@@ -331,3 +605,10 @@ def reset_db_postfork():
     if engine is None or os.getpid() == engine_initial_pid:
         return
     engine.dispose()
+
+
+@on_shutdown
+def dispose_engine():
+    """Closes the engine's pooled connections when the process shuts down."""
+    if engine is not None:
+        engine.dispose()
@@ -0,0 +1,224 @@
+import smtplib
+import time
+from email.message import EmailMessage
+
+from .web import app
+from .db import query
+from . import config, db, journal
+from .model.room import get_rooms_with_permission
+from .model.user import User
+
+# Periodic email digests for moderators.  Moderators opt in (via the /user/digest endpoint) by
+# registering an email address; every config.DIGEST_INTERVAL seconds we email each subscribed
+# moderator a summary of moderation-relevant activity in the rooms they moderate since their
+# previous digest: open reports, post floods, and (when the journal is enabled) the moderation
+# actions recorded in the journal.  This does nothing unless an SMTP server is configured
+# ([digest].smtp_host).
+
+# Journal events included in digests as audit highlights
+AUDIT_EVENTS = {
+    'user_banned',
+    'user_unbanned',
+    'permissions_changed',
+    'moderator_added',
+    'moderator_removed',
+    'messages_deleted',
+    'raid_mode_started',
+    'raid_mode_ended',
+    'room_archived',
+    'room_unarchived',
+    'room_transfer_started',
+    'room_transfer_cancelled',
+    'room_transferred',
+    'anonymous_author_revealed',
+}
+
+
+def enabled():
+    return bool(config.DIGEST_SMTP_HOST)
+
+
+def room_summary(room, since):
+    """Returns a dict of moderation activity statistics for `room` since timestamp `since`"""
+    posts, filtered = query(
+        """
+        SELECT COUNT(*), COALESCE(SUM(CASE WHEN filtered THEN 1 ELSE 0 END), 0)
+        FROM messages WHERE room = :r AND posted >= :since
+        """,
+        r=room.id,
+        since=since,
+    ).first()
+    deleted = query(
+        """
+        SELECT COUNT(DISTINCT message) FROM message_history mh JOIN messages m ON mh.message = m.id
+        WHERE m.room = :r AND m.data IS NULL AND mh.replaced >= :since
+        """,
+        r=room.id,
+        since=since,
+    ).first()[0]
+    banned = query(
+        "SELECT COUNT(*) FROM user_permission_overrides WHERE room = :r AND banned",
+        r=room.id,
+    ).first()[0]
+    scheduled = query(
+        """
+        SELECT (SELECT COUNT(*) FROM user_permission_futures WHERE room = :r)
+             + (SELECT COUNT(*) FROM user_ban_futures WHERE room = :r)
+        """,
+        r=room.id,
+    ).first()[0]
+    open_reports, new_reports, resolved_reports = query(
+        """
+        SELECT COALESCE(SUM(CASE WHEN resolved IS NULL THEN 1 ELSE 0 END), 0),
+            COALESCE(SUM(CASE WHEN reported >= :since THEN 1 ELSE 0 END), 0),
+            COALESCE(SUM(CASE WHEN resolved >= :since THEN 1 ELSE 0 END), 0)
+        FROM message_reports WHERE room = :r
+        """,
+        r=room.id,
+        since=since,
+    ).first()
+    # A flood is a minute in which a single user made at least [digest].flood_posts posts
+    floods, flooders = query(
+        f"""
+        SELECT COUNT(*), COUNT(DISTINCT "user") FROM (
+            SELECT "user" FROM messages WHERE room = :r AND posted >= :since
+            GROUP BY "user", {db.floor_div('posted', '60')}
+            HAVING COUNT(*) >= :flood
+        ) bursts
+        """,
+        r=room.id,
+        since=since,
+        flood=config.DIGEST_FLOOD_POSTS,
+    ).first()
+
+    return {
+        'posts': posts,
+        'filtered': filtered,
+        'deleted': deleted,
+        'banned': banned,
+        'scheduled': scheduled,
+        'open_reports': open_reports,
+        'new_reports': new_reports,
+        'resolved_reports': resolved_reports,
+        'floods': floods,
+        'flooders': flooders,
+    }
+
+
+def audit_highlights(since):
+    """
+    Returns a dict of room token to the list of journal events from AUDIT_EVENTS recorded in that
+    room since timestamp `since`, oldest first.  Returns an empty dict if the journal is disabled.
+    """
+    highlights = {}
+    if not journal.enabled():
+        return highlights
+    for ev in journal.read_events(config.JOURNAL_PATH):
+        if ev.get('event') in AUDIT_EVENTS and ev.get('room') and ev.get('time', 0) >= since:
+            highlights.setdefault(ev['room'], []).append(ev)
+    return highlights
+
+
+def describe_event(ev):
+    """Returns a one-line description of a journal event for a digest"""
+    when = time.strftime('%Y-%m-%d %H:%M', time.gmtime(ev['time']))
+    desc = f"{when} {ev['event'].replace('_', ' ')}"
+    if 'session_id' in ev:
+        desc += f" {ev['session_id']}"
+    if ev['event'] == 'messages_deleted':
+        desc += f" ({len(ev.get('ids', ()))})"
+    by = ev.get('actor') or ev.get('by')
+    if by:
+        desc += f" by {by}"
+    return desc
+
+
+def digest_text(user, since):
+    """
+    Builds the plain text digest for moderator `user` covering activity since `since`.  Returns
+    None if the user no longer moderates any rooms.
+    """
+    rooms = get_rooms_with_permission(user, moderator=True)
+    if not rooms:
+        return None
+
+    lines = [
+        f"Moderation digest for {config.URL_BASE}",
+        f"Activity since {time.strftime('%Y-%m-%d %H:%M UTC', time.gmtime(since))}",
+        "",
+    ]
+    highlights = audit_highlights(since)
+    for room in rooms:
+        s = room_summary(room, since)
+        lines += [
+            f"{room.name} ({room.token}):",
+            f"  New posts: {s['posts']}",
+            f"  Posts held by filters: {s['filtered']}",
+            f"  Posts deleted: {s['deleted']}",
+            f"  Banned users: {s['banned']}",
+            f"  Scheduled permission changes: {s['scheduled']}",
+            f"  Open reports: {s['open_reports']} ({s['new_reports']} new, "
+            f"{s['resolved_reports']} resolved)",
+            f"  Floods: {s['floods']} (from {s['flooders']} users)",
+        ]
+        if room.raid_mode_until is not None:
+            until = time.strftime('%Y-%m-%d %H:%M UTC', time.gmtime(room.raid_mode_until))
+            lines.append(f"  In raid mode until {until}")
+        if room.token in highlights:
+            lines.append("  Moderation actions:")
+            lines += [f"    {describe_event(ev)}" for ev in highlights[room.token]]
+        lines.append("")
+    lines.append("To stop receiving these digests, unsubscribe via DELETE /user/digest.")
+    return "\n".join(lines)
+
+
+def send_email(to, subject, body):
+    msg = EmailMessage()
+    msg['From'] = config.DIGEST_SMTP_FROM
+    msg['To'] = to
+    msg['Subject'] = subject
+    msg.set_content(body)
+
+    with smtplib.SMTP(config.DIGEST_SMTP_HOST, config.DIGEST_SMTP_PORT, timeout=30) as smtp:
+        if config.DIGEST_SMTP_STARTTLS:
+            smtp.starttls()
+        if config.DIGEST_SMTP_USER:
+            smtp.login(config.DIGEST_SMTP_USER, config.DIGEST_SMTP_PASSWORD or '')
+        smtp.send_message(msg)
+
+
+def send_digests():
+    """Sends any due moderator digests.  Returns the number of digests sent."""
+    if not enabled():
+        return 0
+
+    now = time.time()
+    due = list(
+        query(
+            """
+            SELECT "user", email, COALESCE(last_sent, subscribed) AS since FROM moderator_digests
+            WHERE COALESCE(last_sent, subscribed) <= :cutoff
+            """,
+            cutoff=now - config.DIGEST_INTERVAL,
+        )
+    )
+
+    sent = 0
+    for uid, email, since in due:
+        with db.transaction():
+            body = digest_text(User(id=uid), since)
+            if body is None:
+                # No longer a moderator anywhere, so drop the subscription
+                query('DELETE FROM moderator_digests WHERE "user" = :u', u=uid)
+                continue
+            query('UPDATE moderator_digests SET last_sent = :now WHERE "user" = :u', now=now, u=uid)
+
+        try:
+            send_email(email, "SOGS moderation digest", body)
+            sent += 1
+        except Exception as e:
+            app.logger.warning(f"Failed to send moderation digest to user {uid}: {e}")
+
+    if sent:
+        app.logger.info(f"Sent {sent} moderator digests")
+    return sent
@@ -0,0 +1,110 @@
+from . import config, crypto, outbound, utils
+from .model.room import get_rooms
+from .web import app
+
+import base64
+import json
+import time
+import urllib.request
+
+# Announcement of the server's public rooms to a community directory, configured via [directory].
+# When enabled, the scheduler periodically POSTs a JSON body of:
+#
+#     {
+#         "server": {"url": "https://...", "pubkey": "...", "ed25519_pubkey": "..."},
+#         "timestamp": 1700000000.123,
+#         "rooms": [
+#             {"token": "...", "name": "...", "description": "...", "active_users": 12,
+#              "tags": ["..."], "join_url": "https://.../token?public_key=..."},
+#             ...
+#         ]
+#     }
+#
+# to the directory.  The request carries an X-SOGS-Signature header containing the base64-encoded
+# Ed25519 signature of the exact request body by the server's key (`ed25519_pubkey`, also sent in
+# the X-SOGS-Pubkey header), so that the directory can verify that the announcement really comes
+# from the server it describes, and the timestamp lets it reject replayed announcements.
+#
+# Only publicly readable rooms are announced; individual rooms can be left out by setting
+# `directory = no` in the room's [room:TOKEN] section.
+
+SIGNATURE_HEADER = 'X-SOGS-Signature'
+PUBKEY_HEADER = 'X-SOGS-Pubkey'
+
+
+class AnnounceFailed(RuntimeError):
+    """Raised when the directory could not be reached or rejected the announcement."""
+
+
+def enabled():
+    return bool(config.DIRECTORY_URL)
+
+
+def listed(room):
+    """Returns true if `room` should be announced to the directory."""
+    return room.default_read and config.ROOM_OVERRIDES.get(room.token, {}).get('directory', True)
+
+
+def payload(*, now=None):
+    """Returns the announcement dict of the server and its listed rooms."""
+    rooms = []
+    for room in get_rooms():
+        if not listed(room):
+            continue
+        r = {
+            'token': room.token,
+            'name': room.name,
+            'active_users': room.active_users,
+            'join_url': utils.server_url(room.token),
+        }
+        if room.description:
+            r['description'] = room.description
+        if room.tags:
+            r['tags'] = room.tags
+        if room.sensitive:
+            r['sensitive'] = True
+        rooms.append(r)
+
+    return {
+        'server': {
+            'url': config.URL_BASE,
+            'pubkey': crypto.server_pubkey_hex,
+            'ed25519_pubkey': crypto.server_verifykey.encode().hex(),
+        },
+        'timestamp': time.time() if now is None else now,
+        'rooms': rooms,
+    }
+
+
+def sign(body: bytes):
+    """Returns the base64-encoded signature of an announcement body."""
+    return base64.b64encode(crypto.server_signkey.sign(body).signature).decode()
+
+
+def announce():
+    """
+    Sends the announcement to the configured directory.  Returns the number of rooms announced (0
+    if no directory is configured); raises AnnounceFailed if the request fails.
+    """
+    if not enabled():
+        return 0
+
+    data = payload()
+    body = json.dumps(data, separators=(',', ':')).encode()
+    req = urllib.request.Request(
+        config.DIRECTORY_URL,
+        data=body,
+        headers={
+            'Content-Type': 'application/json',
+            PUBKEY_HEADER: crypto.server_verifykey.encode().hex(),
+            SIGNATURE_HEADER: sign(body),
+        },
+        method='POST',
+    )
+    try:
+        outbound.fetch(req, timeout=config.DIRECTORY_TIMEOUT)
+    except outbound.OutboundError as e:
+        raise AnnounceFailed(f"Directory announcement to {config.DIRECTORY_URL} failed: {e}")
+
+    app.logger.info(f"Announced {len(data['rooms'])} room(s) to {config.DIRECTORY_URL}")
+    return len(data['rooms'])
@@ -0,0 +1,179 @@
+import hmac
+import json
+import time
+import urllib.request
+
+from . import config, db, metrics, outbound, utils
+from .db import query
+from .web import app
+
+# Outbound event webhooks for bridges and moderation bots.  When [event_hooks].urls is set, every
+# recorded room event (see sogs.journal) whose name is in [event_hooks].events is POSTed, as a
+# JSON object of the same form as a journal line, to each configured URL.
+#
+# Deliveries are queued in the database (once the action they report has been committed, so an
+# action that is rolled back is never reported) and sent by the `event_hooks` scheduled job, so a
+# slow or unreachable receiver never holds up the request that triggered an event, and queued events
+# survive restarts.  A failed delivery is retried with exponential backoff, up to
+# [event_hooks].max_attempts attempts, after which it is dropped; while a receiver is failing its
+# other queued deliveries are held back by the same delay, so that it can't crowd other receivers'
+# deliveries out of the job's batches.  A retried delivery can arrive after later events, so
+# receivers should order events by their `time` field; they can use the X-SOGS-Event-Id header to
+# discard duplicates (a delivery is repeated if the receiver accepted it but its response didn't
+# reach us).
+#
+# If [event_hooks].secret is set then each request carries an X-SOGS-Signature header of
+# `sha256=HEX`, where HEX is the HMAC-SHA256, keyed with the secret, of the X-SOGS-Timestamp header
+# value, a `.`, and the request body.
+
+# The delay before the first retry of a failed delivery, doubled for each subsequent retry up to
+# MAX_RETRY_DELAY (all in seconds)
+RETRY_DELAY = 10.0
+MAX_RETRY_DELAY = 3600.0
+
+# How many deliveries each run of the `event_hooks` job attempts
+BATCH_SIZE = 100
+
+
+def enabled():
+    return bool(config.EVENT_HOOK_URLS)
+
+
+def _wanted(event: str):
+    return '*' in config.EVENT_HOOK_EVENTS or event in config.EVENT_HOOK_EVENTS
+
+
+def _encode(value):
+    if isinstance(value, (bytes, memoryview)):
+        return utils.encode_base64(value)
+    raise TypeError(f"Cannot encode value of type {type(value).__name__} in event")
+
+
+def enqueue(event: str, created: float, fields: dict):
+    """
+    Queues delivery of an event to each configured URL, if enabled and wanted.  Failures to queue
+    the event are logged, but otherwise ignored (i.e. they don't fail the action being reported).
+    """
+    if not enabled() or not _wanted(event):
+        return
+    try:
+        body = json.dumps({'event': event, 'time': created, **fields}, default=_encode)
+        with db.transaction():
+            for url in config.EVENT_HOOK_URLS:
+                query(
+                    """
+                    INSERT INTO event_deliveries (url, body, next_attempt)
+                    VALUES (:url, :body, :now)
+                    """,
+                    url=url,
+                    body=body,
+                    now=created,
+                )
+    except Exception as e:
+        app.logger.warning(f"Failed to queue {event} event for event hooks: {e}")
+        return
+    metrics.incr('event_hooks.queued', len(config.EVENT_HOOK_URLS))
+
+
+def signature(timestamp: str, body: bytes):
+    """Returns the X-SOGS-Signature header value for a request."""
+    mac = hmac.new(config.EVENT_HOOK_SECRET.encode(), timestamp.encode() + b'.' + body, 'sha256')
+    return f"sha256={mac.hexdigest()}"
+
+
+def _post(url: str, event_id: int, body: bytes):
+    timestamp = str(int(time.time()))
+    headers = {
+        'Content-Type': 'application/json',
+        'X-SOGS-Event-Id': str(event_id),
+        'X-SOGS-Timestamp': timestamp,
+    }
+    if config.EVENT_HOOK_SECRET:
+        headers['X-SOGS-Signature'] = signature(timestamp, body)
+    outbound.fetch(
+        urllib.request.Request(url, data=body, headers=headers, method='POST'),
+        timeout=config.EVENT_HOOK_TIMEOUT,
+        limit=0,
+    )
+
+
+def _hold_back(url: str, now: float):
+    """
+    Pushes back the due deliveries to a failing receiver, so that they don't fill up the following
+    runs' batches ahead of other receivers' deliveries.
+    """
+    query(
+        """
+        UPDATE event_deliveries SET next_attempt = :next
+        WHERE url = :url AND next_attempt <= :now
+        """,
+        url=url,
+        now=now,
+        next=now + RETRY_DELAY,
+    )
+
+
+def deliver():
+    """
+    Attempts delivery of the queued events that are due; called by the `event_hooks` job.  Returns
+    the number of events delivered.
+    """
+    now = time.time()
+    rows = query(
+        """
+        SELECT id, url, body, attempts FROM event_deliveries
+        WHERE next_attempt <= :now
+        ORDER BY id LIMIT :limit
+        """,
+        now=now,
+        limit=BATCH_SIZE,
+    ).fetchall()
+
+    delivered = 0
+    failed = set()
+    for row in rows:
+        url = row['url']
+        if url not in config.EVENT_HOOK_URLS:
+            # The URL was removed from the configuration, so there is no one left to deliver to
+            query("DELETE FROM event_deliveries WHERE id = :id", id=row['id'])
+            continue
+        if url in failed:
+            # Don't keep trying a receiver that is failing
+            continue
+
+        try:
+            _post(url, row['id'], row['body'].encode())
+        except (outbound.CircuitOpen, outbound.RateLimited):
+            # Not attempted, so we'll just try again later without counting an attempt
+            _hold_back(url, now)
+            failed.add(url)
+            continue
+        except outbound.OutboundError as e:
+            failed.add(url)
+            attempts = row['attempts'] + 1
+            if attempts >= config.EVENT_HOOK_MAX_ATTEMPTS:
+                app.logger.warning(f"Dropping event {row['id']} after {attempts} attempts: {e}")
+                query("DELETE FROM event_deliveries WHERE id = :id", id=row['id'])
+                metrics.incr('event_hooks.dropped')
+            else:
+                query(
+                    """
+                    UPDATE event_deliveries
+                    SET attempts = :attempts, next_attempt = :next, last_error = :err
+                    WHERE id = :id
+                    """,
+                    id=row['id'],
+                    attempts=attempts,
+                    next=now + min(RETRY_DELAY * 2 ** (attempts - 1), MAX_RETRY_DELAY),
+                    err=str(e),
+                )
+                metrics.incr('event_hooks.failed')
+            _hold_back(url, now)
+            continue
+
+        query("DELETE FROM event_deliveries WHERE id = :id", id=row['id'])
+        delivered += 1
+
+    if delivered:
+        metrics.incr('event_hooks.delivered', delivered)
+    return delivered
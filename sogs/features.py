@@ -0,0 +1,122 @@
+from . import config, db, http
+from .db import query
+from .model.exc import InvalidData
+
+from flask import abort
+from typing import Optional
+
+# Feature flags: individual features can be turned off server-wide or in individual rooms, either
+# in the config (the [features] `disabled` setting, and `disabled_features` in a [room:TOKEN]
+# section) or at runtime by global admins via the /admin/features endpoints.  Runtime flags are
+# stored in the database, so that they apply to all the server's workers and survive restarts, and
+# take precedence over the config at the same level; a room's flags take precedence over the
+# server-wide ones.
+#
+# The endpoints of a disabled feature return 404 Not Found, as if the server didn't support it, and
+# features that have a capability (see sogs.model.capabilities) that are disabled server-wide are
+# omitted from /capabilities.
+
+FEATURES = ('reactions', 'dms', 'uploads', 'translate', 'reports')
+
+# The features that can be turned off in individual rooms (DMs aren't associated with a room).
+ROOM_FEATURES = tuple(f for f in FEATURES if f != 'dms')
+
+
+def _flags(feature, room=None):
+    """Returns a dict of the runtime flags of `feature`, keyed by room id (None for server-wide)"""
+    return {
+        r: bool(e)
+        for r, e in query(
+            "SELECT room, enabled FROM feature_flags WHERE feature = :f"
+            + (" AND (room IS NULL OR room = :r)" if room is not None else " AND room IS NULL"),
+            f=feature,
+            r=room.id if room is not None else None,
+        )
+    }
+
+
+def enabled(feature: str, room=None):
+    """
+    Returns whether `feature` is enabled, in `room` if given (and the feature is a room feature),
+    otherwise server-wide.
+    """
+    flags = _flags(feature, room)
+    if room is not None and feature in ROOM_FEATURES:
+        if room.id in flags:
+            return flags[room.id]
+        if feature in config.ROOM_OVERRIDES.get(room.token, {}).get('disabled_features', ()):
+            return False
+    if None in flags:
+        return flags[None]
+    return feature not in config.FEATURES_DISABLED
+
+
+def require(feature: str, room=None):
+    """Aborts the request with a 404 Not Found if `feature` is disabled (in `room`, if given)."""
+    if not enabled(feature, room):
+        abort(http.NOT_FOUND)
+
+
+def disabled(room=None):
+    """
+    Returns the list of the features disabled in `room`, if given (which includes the room
+    features disabled server-wide), otherwise of the features disabled server-wide.
+    """
+    return [f for f in (ROOM_FEATURES if room is not None else FEATURES) if not enabled(f, room)]
+
+
+def set_flag(feature: str, on: Optional[bool], *, room=None, by=None):
+    """
+    Sets the runtime flag of `feature` in `room` (or server-wide, if `room` is None) to enable
+    (`on=True`) or disable (`on=False`) it, overriding the config; `on=None` removes the runtime
+    flag, reverting to the configured setting.  `by` is the user making the change, if any.
+
+    Raises InvalidData if `feature` is not a known feature, or not a room feature when `room` is
+    given.
+    """
+    if feature not in (ROOM_FEATURES if room is not None else FEATURES):
+        raise InvalidData(f"Unknown {'room ' if room is not None else ''}feature {feature}")
+    with db.transaction():
+        query(
+            "DELETE FROM feature_flags WHERE feature = :f AND "
+            + ("room = :r" if room is not None else "room IS NULL"),
+            f=feature,
+            r=room.id if room is not None else None,
+        )
+        if on is not None:
+            query(
+                """
+                INSERT INTO feature_flags (feature, room, enabled, updated_by)
+                VALUES (:f, :r, :on, :by)
+                """,
+                f=feature,
+                r=room.id if room is not None else None,
+                on=on,
+                by=by.id if by is not None else None,
+            )
+
+
+def runtime_flags():
+    """
+    Returns the runtime flags (set via set_flag) as a list of dicts with keys `feature`, `room`
+    (the room token, or None for a server-wide flag), `enabled`, `updated` (unix timestamp), and
+    `updated_by` (the session id of the user who set it, or None).
+    """
+    return [
+        {
+            'feature': row['feature'],
+            'room': row['token'],
+            'enabled': bool(row['enabled']),
+            'updated': row['updated'],
+            'updated_by': row['session_id'],
+        }
+        for row in query(
+            """
+            SELECT feature, rooms.token, enabled, updated, users.session_id
+            FROM feature_flags
+                LEFT JOIN rooms ON rooms.id = feature_flags.room
+                LEFT JOIN users ON users.id = feature_flags.updated_by
+            ORDER BY feature, rooms.token IS NOT NULL, rooms.token
+            """
+        )
+    ]
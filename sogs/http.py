@@ -1,6 +1,7 @@
 # success codes:
 OK = 200
 CREATED = 201
+ACCEPTED = 202
 
 # 3xx codes:
 NOT_MODIFIED = 304
@@ -11,12 +12,16 @@ UNAUTHORIZED = 401
 FORBIDDEN = 403
 NOT_FOUND = 404
 NOT_ACCEPTABLE = 406
+CONFLICT = 409
 PRECONDITION_FAILED = 412
 PAYLOAD_TOO_LARGE = 413
+UNSUPPORTED_MEDIA_TYPE = 415
 TOO_EARLY = 425
+PRECONDITION_REQUIRED = 428
 TOO_MANY_REQUESTS = 429
 INTERNAL_SERVER_ERROR = 500
 BAD_GATEWAY = 502
+SERVICE_UNAVAILABLE = 503
 INSUFFICIENT_STORAGE = 507
 
 
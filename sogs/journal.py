@@ -0,0 +1,301 @@
+import contextlib
+import contextvars
+import fcntl
+import json
+import os
+import threading
+import time
+
+from . import compress, config, db, eventhooks, utils
+from .db import query
+from .hashing import blake2b
+from .shutdown import on_shutdown
+from .web import app
+
+# Optional append-only event journal.  When [journal].path is set, every message post, edit, and
+# deletion, and every moderation action (moderator changes, bans, permission changes, raid mode),
+# is appended to the journal file as a single line of JSON, for consumption by external stream
+# processing, backups, or auditing, without needing to query the live database.  Each line is an
+# object with an `event` name, the `time` at which it was recorded, and event-specific fields; byte
+# values (such as message data and signatures) are base64-encoded.
+#
+# Actions taken by administrators outside of a Session client (e.g. from the command line) are made
+# by the server's system user, so their `by` fields all carry the same server session id.  To keep
+# such actions accountable on deployments with several administrators, events recorded while an
+# actor is set (see `acting_as()`) also get an `actor` field identifying who really made them, as
+# do the system messages they post (see Room.add_system_message); the command line sets this to
+# `cli:LOGIN`, or to the Session ID of the global admin given as its `--actor` argument.
+#
+# Multiple uwsgi workers append to the same file, so writes (and rotation) are serialized through
+# an exclusive lock on the journal file.  When the journal exceeds [journal].rotate_size it is
+# renamed to `PATH.1` (shifting older journals to `PATH.2`, etc., and dropping the oldest beyond
+# [journal].keep) and a new journal is started.
+#
+# The `journal_checkpoint` job also periodically records `room_checkpoint` events containing a
+# merkle root of each room's messages, which lets a point-in-time recovery (see sogs.restore) verify
+# that replaying the journal reproduced the room exactly.  The tree's leaves are stored in the
+# database, so that each checkpoint only has to hash the messages changed since the last one (and
+# rooms without changes just repeat their last root).
+
+_lock = threading.Lock()
+_file = None
+
+# The administrator on whose behalf the current actions are being made, if set
+_actor = contextvars.ContextVar('journal_actor', default=None)
+
+
+def enabled():
+    return bool(config.JOURNAL_PATH)
+
+
+def _open():
+    global _file
+    if _file is not None:
+        _file.close()
+    _file = open(config.JOURNAL_PATH, 'ab')
+    return _file
+
+
+def _replaced(f):
+    """True if the journal path no longer refers to the open file `f` (i.e. it was rotated)"""
+    try:
+        return os.stat(config.JOURNAL_PATH).st_ino != os.fstat(f.fileno()).st_ino
+    except FileNotFoundError:
+        return True
+
+
+def _rotate():
+    path = config.JOURNAL_PATH
+    if config.JOURNAL_KEEP > 0:
+        for i in range(config.JOURNAL_KEEP - 1, 0, -1):
+            if os.path.exists(f"{path}.{i}"):
+                os.replace(f"{path}.{i}", f"{path}.{i + 1}")
+        os.replace(path, f"{path}.1")
+    else:
+        os.unlink(path)
+
+
+def _encode(value):
+    if isinstance(value, (bytes, memoryview)):
+        return utils.encode_base64(value)
+    raise TypeError(f"Cannot journal value of type {type(value).__name__}")
+
+
+def set_actor(actor: str):
+    """
+    Sets the actor recorded with subsequent events of the current thread; used by the command line,
+    which acts on behalf of a single administrator for its whole run.
+    """
+    _actor.set(actor)
+
+
+def current_actor():
+    """Returns the actor set by `set_actor()` or `acting_as()`, or None if there isn't one."""
+    return _actor.get()
+
+
+@contextlib.contextmanager
+def acting_as(actor: str):
+    """Context manager recording `actor` as the actor of the events recorded within it."""
+    token = _actor.set(actor)
+    try:
+        yield
+    finally:
+        _actor.reset(token)
+
+
+def record(event: str, **fields):
+    """
+    Appends an event to the journal, if enabled, and queues it for delivery to any configured event
+    hooks (see sogs.eventhooks).  When called inside a database transaction this happens only once
+    the transaction commits, and not at all if it is rolled back.  Failures to write to the journal
+    are logged, but otherwise ignored (i.e. they don't fail the action being recorded).
+    """
+    now = time.time()
+    actor = _actor.get()
+    if actor is not None and 'actor' not in fields:
+        fields['actor'] = actor
+    db.after_commit(lambda: _record(event, now, fields))
+
+
+def _record(event, now, fields):
+    eventhooks.enqueue(event, now, fields)
+    if not enabled():
+        return
+
+    line = json.dumps({'event': event, 'time': now, **fields}, default=_encode).encode() + b'\n'
+    try:
+        with _lock:
+            while True:
+                f = _file if _file is not None else _open()
+                fcntl.flock(f, fcntl.LOCK_EX)
+                if _replaced(f):
+                    # Another process rotated the journal since we opened it
+                    _open()
+                    continue
+                size = os.fstat(f.fileno()).st_size
+                if config.JOURNAL_ROTATE_SIZE and 0 < size and (
+                    size + len(line) > config.JOURNAL_ROTATE_SIZE
+                ):
+                    _rotate()
+                    _open()
+                    continue
+                break
+
+            try:
+                f.write(line)
+                f.flush()
+                if config.JOURNAL_FSYNC:
+                    os.fsync(f.fileno())
+            finally:
+                fcntl.flock(f, fcntl.LOCK_UN)
+    except Exception as e:
+        app.logger.warning(f"Failed to write {event} event to journal: {e}")
+
+
+@on_shutdown
+def close():
+    """Closes the journal file, if open (it is reopened by the next `record`)."""
+    global _file
+    with _lock:
+        if _file is not None:
+            _file.close()
+            _file = None
+
+
+def room_root(room):
+    """
+    Returns the merkle root (in hex) of the messages of `room`: a binary hash tree built over the
+    room's messages in id order, where each leaf is the hash of a message's id, data, and signature
+    (so edits and deletions change the root, but sequence numbers and timestamps don't).  The data
+    is hashed uncompressed, so that the root doesn't depend on how the messages are stored.
+    """
+    return _merkle_root(
+        [
+            _leaf(*row)
+            for row in query(
+                "SELECT id, data, data_dict, signature FROM messages WHERE room = :r ORDER BY id",
+                r=room.id,
+            )
+        ]
+    )
+
+
+def _leaf(id, data, data_dict, sig):
+    return blake2b(
+        (id.to_bytes(8, 'big'), compress.decode(data, data_dict) or b'', sig or b''),
+        person=b'sogs.journalleaf',
+    )
+
+
+def _merkle_root(level):
+    if not level:
+        return blake2b(b'', person=b'sogs.journalroot').hex()
+    while len(level) > 1:
+        level = [
+            blake2b(level[i : i + 2], person=b'sogs.journalnode') for i in range(0, len(level), 2)
+        ]
+    return level[0].hex()
+
+
+def checkpoint_root(room):
+    """
+    Returns the same merkle root as `room_root`, but computed from the stored leaves of the room's
+    messages, updated for just the messages changed since the room's last checkpoint (or for all of
+    them, the first time).  Rooms that haven't changed at all since their last checkpoint return the
+    stored root without even loading the leaves.
+    """
+    with db.transaction():
+        seqno = query("SELECT message_sequence FROM rooms WHERE id = :r", r=room.id).first()[0]
+        last = query(
+            "SELECT seqno, root FROM journal_checkpoints WHERE room = :r", r=room.id
+        ).first()
+        if last is not None and last[0] == seqno:
+            return last[1]
+
+        since = last[0] if last is not None else -1
+        for row in query(
+            """
+            SELECT id, data, data_dict, signature FROM messages
+            WHERE room = :r AND seqno > :since AND seqno_data > :since
+            """,
+            r=room.id,
+            since=since,
+        ).fetchall():
+            query(
+                """
+                INSERT INTO journal_leaves (message, room, hash) VALUES (:m, :r, :h)
+                ON CONFLICT (message) DO UPDATE SET hash = excluded.hash
+                """,
+                m=row[0],
+                r=room.id,
+                h=_leaf(*row),
+            )
+
+        leaves = [
+            row[0]
+            for row in query(
+                "SELECT hash FROM journal_leaves WHERE room = :r ORDER BY message", r=room.id
+            )
+        ]
+        messages = query("SELECT COUNT(*) FROM messages WHERE room = :r", r=room.id).first()[0]
+        if len(leaves) != messages:
+            # Messages were added without bumping the room's sequence (e.g. by a backup import), so
+            # we can't trust the stored leaves: start over.
+            app.logger.warning(f"Rebuilding journal checkpoint leaves of {room}")
+            query("DELETE FROM journal_leaves WHERE room = :r", r=room.id)
+            query("DELETE FROM journal_checkpoints WHERE room = :r", r=room.id)
+            return checkpoint_root(room)
+
+        root = _merkle_root([bytes(h) for h in leaves])
+        query(
+            """
+            INSERT INTO journal_checkpoints (room, seqno, root) VALUES (:r, :s, :root)
+            ON CONFLICT (room) DO UPDATE SET seqno = excluded.seqno, root = excluded.root
+            """,
+            r=room.id,
+            s=seqno,
+            root=root,
+        )
+    return root
+
+
+def checkpoint():
+    """Records a `room_checkpoint` event for each room, if the journal is enabled"""
+    if not enabled():
+        return 0
+
+    from .model.room import get_rooms
+
+    rooms = get_rooms()
+    for room in rooms:
+        record('room_checkpoint', room=room.token, root=checkpoint_root(room))
+    return len(rooms)
+
+
+def journal_files(path):
+    """Returns the journal at `path` along with any rotated journals, from oldest to newest"""
+    files = []
+    while os.path.exists(f"{path}.{len(files) + 1}"):
+        files.append(f"{path}.{len(files) + 1}")
+    files.reverse()
+    if os.path.exists(path):
+        files.append(path)
+    return files
+
+
+def read_events(path):
+    """
+    Yields the events (as dicts) recorded in the journal at `path` (and its rotated predecessors),
+    from oldest to newest.  Unparseable lines (e.g. the partially written last line of a journal
+    after a crash) are logged and skipped.
+    """
+    for filename in journal_files(path):
+        with open(filename) as f:
+            for lineno, line in enumerate(f, 1):
+                if not line.strip():
+                    continue
+                try:
+                    yield json.loads(line)
+                except ValueError as e:
+                    app.logger.warning(f"Skipping invalid journal line {filename}:{lineno}: {e}")
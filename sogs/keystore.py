@@ -0,0 +1,210 @@
+from . import config
+
+import getpass
+import os
+import sys
+import nacl.exceptions
+import nacl.pwhash
+import nacl.secret
+import nacl.utils
+
+# Storage of the server's private key, selected by [crypto] `key_storage`:
+#
+# - `file` (the default) stores the raw 32-byte X25519 private key in [crypto] `key_file`.
+# - `encrypted` stores the key in `key_file` encrypted with a passphrase (stretched with argon2id).
+#   The passphrase is taken from the SOGS_KEY_PASSPHRASE environment variable if set, and is
+#   otherwise prompted for when running interactively.
+# - `pkcs11` stores the key as a secret key object on a PKCS#11 token (e.g. an HSM or smartcard),
+#   using the optional `python-pkcs11` module.  Note that SOGS needs the raw key to decrypt onion
+#   requests and derive blinded keys, so the key has to be extractable: this keeps the key off the
+#   server's disk, but not out of the server's memory.
+#
+# Each storage type implements the KeyStore interface; crypto loads the key via get().
+
+PASSPHRASE_ENV = 'SOGS_KEY_PASSPHRASE'
+PIN_ENV = 'SOGS_PKCS11_PIN'
+
+
+class KeyStoreError(RuntimeError):
+    """Raised when the key cannot be loaded from or stored into the key storage."""
+
+
+class KeyStore:
+    """Interface of private key storage back-ends."""
+
+    def load(self):
+        """Returns the stored 32-byte private key, or None if no key has been stored yet."""
+        raise NotImplementedError
+
+    def store(self, privkey: bytes):
+        """Stores `privkey`.  Raises KeyStoreError if a key is already stored."""
+        raise NotImplementedError
+
+    @staticmethod
+    def _check(privkey):
+        if len(privkey) != 32:
+            raise KeyStoreError(f"Invalid private key: expected 32 bytes, not {len(privkey)}")
+        return privkey
+
+
+def _write_new(path, data):
+    try:
+        with open(os.open(path, os.O_CREAT | os.O_EXCL | os.O_WRONLY, 0o400), 'wb') as f:
+            f.write(data)
+    except FileExistsError:
+        raise KeyStoreError(f"Not overwriting existing key file {path}")
+
+
+class FileKeyStore(KeyStore):
+    """Stores the raw private key in a file."""
+
+    def __init__(self, path):
+        self.path = path
+
+    def load(self):
+        if not os.path.exists(self.path):
+            return None
+        with open(self.path, 'rb') as f:
+            return self._check(f.read())
+
+    def store(self, privkey):
+        _write_new(self.path, self._check(privkey))
+
+
+class EncryptedFileKeyStore(KeyStore):
+    """
+    Stores the private key in a file encrypted with a passphrase.  The file contains MAGIC, the
+    argon2id salt, and the secretbox-encrypted key (nonce included).  `passphrase`, if given, is
+    used instead of looking up or prompting for the passphrase.
+    """
+
+    MAGIC = b'SOGSKEY1'
+    OPSLIMIT = nacl.pwhash.argon2id.OPSLIMIT_MODERATE
+    MEMLIMIT = nacl.pwhash.argon2id.MEMLIMIT_MODERATE
+
+    def __init__(self, path, *, passphrase=None):
+        self.path = path
+        self._passphrase = passphrase
+
+    def passphrase(self, *, confirm=False):
+        if self._passphrase is None:
+            self._passphrase = os.environ.get(PASSPHRASE_ENV)
+        if self._passphrase is None:
+            if not sys.stdin.isatty():
+                raise KeyStoreError(
+                    f"The private key passphrase must be set in ${PASSPHRASE_ENV} when not "
+                    "running interactively"
+                )
+            self._passphrase = getpass.getpass(f"Passphrase for {self.path}: ")
+            if confirm and getpass.getpass("Confirm passphrase: ") != self._passphrase:
+                self._passphrase = None
+                raise KeyStoreError("Passphrases do not match")
+        if not self._passphrase:
+            raise KeyStoreError("The private key passphrase must not be empty")
+        return self._passphrase.encode()
+
+    def _box(self, salt, **kwargs):
+        key = nacl.pwhash.argon2id.kdf(
+            nacl.secret.SecretBox.KEY_SIZE,
+            self.passphrase(**kwargs),
+            salt,
+            opslimit=self.OPSLIMIT,
+            memlimit=self.MEMLIMIT,
+        )
+        return nacl.secret.SecretBox(key)
+
+    def load(self):
+        if not os.path.exists(self.path):
+            return None
+        with open(self.path, 'rb') as f:
+            data = f.read()
+        if not data.startswith(self.MAGIC):
+            raise KeyStoreError(f"{self.path} is not an encrypted SOGS key file")
+        salt_end = len(self.MAGIC) + nacl.pwhash.argon2id.SALTBYTES
+        try:
+            privkey = self._box(data[len(self.MAGIC) : salt_end]).decrypt(data[salt_end:])
+        except nacl.exceptions.CryptoError:
+            raise KeyStoreError(f"Unable to decrypt {self.path}: wrong passphrase?")
+        return self._check(privkey)
+
+    def store(self, privkey):
+        salt = nacl.utils.random(nacl.pwhash.argon2id.SALTBYTES)
+        encrypted = self._box(salt, confirm=True).encrypt(self._check(privkey))
+        _write_new(self.path, self.MAGIC + salt + encrypted)
+
+
+class Pkcs11KeyStore(KeyStore):
+    """
+    Stores the private key as a generic secret key object with label `label` on the PKCS#11 token
+    `token` of the PKCS#11 library `module`.  The user PIN is `pin` if given, otherwise the value of
+    the SOGS_PKCS11_PIN environment variable.
+    """
+
+    def __init__(self, module, token, label, *, pin=None):
+        try:
+            import pkcs11
+        except ImportError:
+            raise KeyStoreError("PKCS#11 key storage requires the python-pkcs11 module")
+        self.pkcs11 = pkcs11
+        self.module, self.token, self.label = module, token, label
+        self.pin = pin if pin is not None else os.environ.get(PIN_ENV)
+
+    def _session(self):
+        try:
+            token = self.pkcs11.lib(self.module).get_token(token_label=self.token)
+            return token.open(user_pin=self.pin, rw=True)
+        except self.pkcs11.PKCS11Error as e:
+            raise KeyStoreError(f"Unable to open PKCS#11 token {self.token}: {e!r}")
+
+    def _find(self, session):
+        p = self.pkcs11
+        try:
+            return session.get_key(
+                object_class=p.ObjectClass.SECRET_KEY,
+                key_type=p.KeyType.GENERIC_SECRET,
+                label=self.label,
+            )
+        except p.NoSuchKey:
+            return None
+
+    def load(self):
+        with self._session() as session:
+            key = self._find(session)
+            if key is None:
+                return None
+            try:
+                return self._check(key[self.pkcs11.Attribute.VALUE])
+            except self.pkcs11.PKCS11Error as e:
+                raise KeyStoreError(f"Unable to read key {self.label} (not extractable?): {e!r}")
+
+    def store(self, privkey):
+        A = self.pkcs11.Attribute
+        with self._session() as session:
+            if self._find(session) is not None:
+                raise KeyStoreError(f"PKCS#11 token {self.token} already has key {self.label}")
+            session.create_object(
+                {
+                    A.CLASS: self.pkcs11.ObjectClass.SECRET_KEY,
+                    A.KEY_TYPE: self.pkcs11.KeyType.GENERIC_SECRET,
+                    A.TOKEN: True,
+                    A.PRIVATE: True,
+                    A.SENSITIVE: False,
+                    A.EXTRACTABLE: True,
+                    A.LABEL: self.label,
+                    A.VALUE: self._check(privkey),
+                }
+            )
+
+
+def get():
+    """Returns the KeyStore configured by the [crypto] settings."""
+    if config.KEY_STORAGE == 'encrypted':
+        return EncryptedFileKeyStore(config.KEY_FILE)
+    if config.KEY_STORAGE == 'pkcs11':
+        return Pkcs11KeyStore(
+            config.PKCS11_MODULE,
+            config.PKCS11_TOKEN,
+            config.PKCS11_KEY_LABEL,
+            pin=config.PKCS11_PIN,
+        )
+    return FileKeyStore(config.KEY_FILE)
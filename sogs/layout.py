@@ -0,0 +1,181 @@
+from . import config
+
+import os
+import shutil
+import sqlite3
+import sqlalchemy.engine
+
+# On-disk layout of the server's data.  Each kind of data has its own configurable location, so
+# that it can be put on a different disk:
+#
+# - the SQLite database: [db].url (SQLite always keeps its rollback journal or WAL file alongside
+#   the database file, so these cannot be placed separately, and the directory containing the
+#   database must be writable);
+# - uploaded files: [files].uploads_dir;
+# - cold storage files: [files].cold_dir (if unset, cold files are kept beneath uploads_dir);
+# - the event journal: [journal].path (and its rotated copies, PATH.1, PATH.2, etc.).
+#
+# validate() is called at startup to check that every configured location is usable, so that a
+# misconfigured (or unmounted) disk is reported immediately rather than when something is first
+# written there.  To change the layout of an existing server, stop it, relocate the data with
+# `python3 -msogs --relocate WHAT PATH`, then update the config setting as instructed.  Relocating
+# copies the data (updating the stored paths of uploaded files in the database, where needed) and
+# never deletes the original, which can be removed once the server has been restarted with the new
+# layout.
+
+LOCATIONS = ('db', 'uploads', 'cold', 'journal')
+
+
+def sqlite_path():
+    """Returns the path of the SQLite database file, or None if not using a file-based SQLite db."""
+    url = sqlalchemy.engine.make_url(config.DB_URL)
+    if url.get_backend_name() != 'sqlite' or url.database in (None, '', ':memory:'):
+        return None
+    return url.database
+
+
+def _check_writable(path, what, *, is_dir):
+    """Returns a problem description if `path` cannot be used for `what`, otherwise None."""
+    if os.path.exists(path):
+        if is_dir and not os.path.isdir(path):
+            return f"{what} {path} is not a directory"
+        if not os.access(path, os.W_OK | (os.X_OK if is_dir else 0)):
+            return f"{what} {path} is not writable"
+        return None
+
+    # Doesn't exist yet (and will be created when needed), so the nearest existing parent directory
+    # has to be writable:
+    parent = os.path.dirname(os.path.abspath(path))
+    while not os.path.exists(parent):
+        parent = os.path.dirname(parent)
+    if not os.path.isdir(parent) or not os.access(parent, os.W_OK | os.X_OK):
+        return f"{what} {path} does not exist and cannot be created in {parent}"
+    return None
+
+
+def problems():
+    """Returns a list of descriptions of configured data locations that are not usable."""
+    result = []
+    db = sqlite_path()
+    if db is not None:
+        if os.path.exists(db):
+            result.append(_check_writable(db, "The database file", is_dir=False))
+        db_dir = os.path.dirname(os.path.abspath(db))
+        result.append(_check_writable(db_dir, "The database directory", is_dir=True))
+    if config.STORAGE_BACKEND == 'local':
+        result.append(_check_writable(config.UPLOAD_PATH, "The uploads directory", is_dir=True))
+        if config.UPLOAD_COLD_PATH:
+            result.append(
+                _check_writable(config.UPLOAD_COLD_PATH, "The cold storage directory", is_dir=True)
+            )
+    if config.JOURNAL_PATH:
+        result.append(_check_writable(config.JOURNAL_PATH, "The event journal", is_dir=False))
+    return [p for p in result if p is not None]
+
+
+def validate():
+    """Raises a RuntimeError describing any unusable data locations."""
+    p = problems()
+    if p:
+        raise RuntimeError("Invalid data layout: " + "; ".join(p))
+
+
+def check_dest(dest):
+    if os.path.exists(dest) and not (os.path.isdir(dest) and not os.listdir(dest)):
+        raise RuntimeError(f"{dest} already exists")
+    parent = os.path.dirname(os.path.abspath(dest))
+    if not os.path.isdir(parent):
+        raise RuntimeError(f"{parent} does not exist")
+
+
+def copy_db(dest):
+    """
+    Copies the SQLite database to `dest` (a new file), using SQLite's online backup so that the copy
+    is consistent (even while the server is running), and checks the copy's integrity.
+    """
+    src = sqlite_path()
+    if src is None:
+        raise RuntimeError("Only SQLite databases can be copied; use PostgreSQL's own tools")
+    check_dest(dest)
+    if os.path.isdir(dest):
+        raise RuntimeError(f"{dest} is a directory")
+
+    src_conn, dest_conn = sqlite3.connect(src), sqlite3.connect(dest)
+    try:
+        src_conn.backup(dest_conn)
+        (result,) = dest_conn.execute("PRAGMA integrity_check").fetchone()
+    finally:
+        src_conn.close()
+        dest_conn.close()
+    if result != 'ok':
+        raise RuntimeError(f"Integrity check of {dest} failed: {result}")
+
+
+def relocate_db(dest):
+    """Copies the SQLite database to `dest` (see copy_db).  Returns the [db].url of the copy."""
+    copy_db(dest)
+    return f"sqlite:///{os.path.abspath(dest)}"
+
+
+# Columns holding local storage paths of file content:
+_PATH_COLUMNS = (
+    ('files', 'path'),
+    ('file_blobs', 'path'),
+    ('cold_files', 'path'),
+    ('cold_files', 'cold_path'),
+)
+
+
+def relocate_files(dest, *, cold=False):
+    """
+    Copies the uploaded files (or, with `cold`, the cold storage directory) to directory `dest`,
+    which must not exist or be empty, and updates the paths of the copied files in the database.
+    Returns the number of database file paths updated.
+    """
+    from . import db
+
+    if config.STORAGE_BACKEND != 'local':
+        raise RuntimeError("Only local file storage can be relocated")
+    src = config.UPLOAD_COLD_PATH if cold else config.UPLOAD_PATH
+    if not src:
+        raise RuntimeError("[files].cold_dir is not set")
+    check_dest(dest)
+
+    if not os.path.exists(src):
+        os.makedirs(dest, exist_ok=True)
+        return 0
+    if os.path.isdir(dest):
+        os.rmdir(dest)
+    shutil.copytree(src, dest, symlinks=True)
+
+    old, new = os.path.join(src, ''), os.path.join(dest, '')
+    updated = 0
+    with db.transaction():
+        for table, col in _PATH_COLUMNS:
+            updated += db.query(
+                f"""
+                UPDATE {table} SET {col} = :new || substr({col}, :n + 1)
+                WHERE substr({col}, 1, :n) = :old
+                """,
+                old=old,
+                new=new,
+                n=len(old),
+            ).rowcount
+    return updated
+
+
+def relocate_journal(dest):
+    """Copies the event journal and its rotated copies to `dest`.  Returns the number copied."""
+    src = config.JOURNAL_PATH
+    if not src:
+        raise RuntimeError("[journal].path is not set")
+    check_dest(dest)
+
+    copied = 0
+    for suffix in [''] + [f'.{i}' for i in range(1, config.JOURNAL_KEEP + 1)]:
+        if os.path.exists(src + suffix):
+            if os.path.exists(dest + suffix):
+                raise RuntimeError(f"{dest + suffix} already exists")
+            shutil.copy2(src + suffix, dest + suffix)
+            copied += 1
+    return copied
@@ -0,0 +1,145 @@
+from .normalize import normalize
+
+import html
+import re
+
+# Room descriptions and rules are stored as (a small subset of) markdown, which Session clients
+# display as-is, and which we render to HTML for the web pages and for web dashboards (via the
+# `html` room info flag).  Rendering escapes *all* HTML in the source text before applying the
+# markdown formatting, so the result only ever contains the tags we generate ourselves, and links
+# are limited to http, https, and mailto URLs; the output is thus safe to embed directly into a
+# page.
+#
+# Supported formatting:
+# - paragraphs (separated by blank lines); single newlines become line breaks
+# - `# `, `## `, `### `, and `#### ` headings
+# - `- `, `* `, or `+ ` bullet lists and `1. ` numbered lists
+# - `> ` block quotes
+# - ``` fenced code blocks and `inline code`
+# - **bold** (or __bold__), *italic* (or _italic_), and ~~strikethrough~~
+# - [links](https://example.com) and bare https://example.com links
+
+_heading = re.compile(r'(#{1,4})\s+(.*?)\s*#*$')
+_bullet = re.compile(r'\s*[-*+]\s+(.*)')
+_numbered = re.compile(r'\s*\d{1,9}[.)]\s+(.*)')
+_quote = re.compile(r'>\s?(.*)')
+
+_code = re.compile(r'`([^`\n]+)`')
+_link = re.compile(r'\[([^\]\n]+)\]\(((?:https?://|mailto:)[^\s()]+)\)', re.I)
+_autolink = re.compile(r'\bhttps?://[^\s<>()\[\]]*[^\s<>()\[\].,;:!?\'"]', re.I)
+_emphasis = [
+    (re.compile(r'\*\*(?=\S)(.+?)(?<=\S)\*\*'), 'strong'),
+    (re.compile(r'\b__(?=\S)(.+?)(?<=\S)__\b'), 'strong'),
+    (re.compile(r'~~(?=\S)(.+?)(?<=\S)~~'), 'del'),
+    (re.compile(r'\*(?=\S)(.+?)(?<=\S)\*'), 'em'),
+    (re.compile(r'\b_(?=\S)(.+?)(?<=\S)_\b'), 'em'),
+]
+# Placeholder for already-rendered fragments; NUL can't appear in sanitized text.
+_placeholder = re.compile('\x00(\\d+)\x00')
+
+
+def sanitize(text):
+    """
+    Sanitizes markdown text for storage: strips out control characters other than newlines and
+    tabs and invisible spoofing characters, and NFC-normalizes it (see sogs.normalize).  Returns
+    None if `text` is None or the sanitized text is empty.
+    """
+    text = normalize(text)
+    return text if text else None
+
+
+def _render_inline(text):
+    frags = []
+
+    def stash(h):
+        frags.append(h)
+        return f'\x00{len(frags) - 1}\x00'
+
+    def link(url, label):
+        return stash(
+            f'<a href="{html.escape(url)}" rel="nofollow noopener noreferrer">{label}</a>'
+        )
+
+    def emphasize(escaped):
+        for pattern, tag in _emphasis:
+            escaped = pattern.sub(lambda m: f'<{tag}>{m[1]}</{tag}>', escaped)
+        return escaped
+
+    text = _code.sub(lambda m: stash(f'<code>{html.escape(m[1])}</code>'), text)
+    text = _link.sub(lambda m: link(m[2], emphasize(html.escape(m[1]))), text)
+    text = _autolink.sub(lambda m: link(m[0], html.escape(m[0])), text)
+    text = emphasize(html.escape(text))
+
+    # Fragments can contain placeholders of their own (e.g. code inside link text):
+    while _placeholder.search(text):
+        text = _placeholder.sub(lambda m: frags[int(m[1])], text)
+    return text
+
+
+def render(text):
+    """
+    Renders markdown `text` to safe HTML (see the top of this file for the supported subset).
+    Returns None if the text is None or empty.
+    """
+    text = sanitize(text)
+    if text is None:
+        return None
+
+    out = []
+    para = []
+
+    def end_paragraph():
+        if para:
+            out.append('<p>' + '<br>'.join(_render_inline(line) for line in para) + '</p>')
+            para.clear()
+
+    lines = text.split('\n')
+    i = 0
+    while i < len(lines):
+        line = lines[i]
+
+        if line.startswith('```'):
+            end_paragraph()
+            code = []
+            i += 1
+            while i < len(lines) and not lines[i].startswith('```'):
+                code.append(lines[i])
+                i += 1
+            out.append('<pre><code>' + html.escape('\n'.join(code)) + '</code></pre>')
+            i += 1
+            continue
+
+        heading = _heading.match(line)
+        if heading:
+            end_paragraph()
+            tag = f'h{len(heading[1]) + 2}'
+            out.append(f'<{tag}>{_render_inline(heading[2])}</{tag}>')
+            i += 1
+            continue
+
+        for pattern, tag in ((_bullet, 'ul'), (_numbered, 'ol')):
+            if pattern.match(line):
+                end_paragraph()
+                items = []
+                while i < len(lines) and pattern.match(lines[i]):
+                    items.append('<li>' + _render_inline(pattern.match(lines[i])[1]) + '</li>')
+                    i += 1
+                out.append(f'<{tag}>' + ''.join(items) + f'</{tag}>')
+                break
+        else:
+            if _quote.match(line):
+                end_paragraph()
+                quoted = []
+                while i < len(lines) and _quote.match(lines[i]):
+                    quoted.append(_quote.match(lines[i])[1])
+                    i += 1
+                out.append('<blockquote>' + (render('\n'.join(quoted)) or '') + '</blockquote>')
+            elif line.strip():
+                para.append(line.strip())
+                i += 1
+            else:
+                end_paragraph()
+                i += 1
+
+    end_paragraph()
+    return '\n'.join(out)
@@ -0,0 +1,100 @@
+import threading
+
+# Simple in-process metrics: named counters, and "observations" which track the count, total, and
+# maximum of some measured value (such as a wait time).  As with room presence these live in the
+# memory of each worker process, so with multiple uwsgi workers each worker reports its own values.
+#
+# There are also labeled counters and histograms (e.g. of request durations per endpoint), which are
+# exported by the Prometheus endpoint (see sogs/prometheus.py).
+
+_lock = threading.Lock()
+_counters = {}
+_observations = {}
+_labeled_counters = {}
+_histograms = {}
+
+# Histogram bucket upper bounds; suitable for durations, in seconds.
+BUCKETS = (0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0)
+
+
+def incr(name: str, amount=1):
+    """Increments counter `name` by `amount`."""
+    with _lock:
+        _counters[name] = _counters.get(name, 0) + amount
+
+
+def observe(name: str, value: float):
+    """Records an observed value of `name`."""
+    with _lock:
+        o = _observations.get(name)
+        if o is None:
+            _observations[name] = {'count': 1, 'total': value, 'max': value}
+        else:
+            o['count'] += 1
+            o['total'] += value
+            if value > o['max']:
+                o['max'] = value
+
+
+def incr_labeled(name: str, labels: dict, amount=1):
+    """Increments the counter `name` with the given label values by `amount`."""
+    key = (name, tuple(sorted(labels.items())))
+    with _lock:
+        _labeled_counters[key] = _labeled_counters.get(key, 0) + amount
+
+
+def histogram(name: str, value: float, labels: dict):
+    """Records `value` in the histogram `name` with the given label values."""
+    key = (name, tuple(sorted(labels.items())))
+    with _lock:
+        h = _histograms.get(key)
+        if h is None:
+            h = _histograms[key] = {'buckets': [0] * len(BUCKETS), 'count': 0, 'sum': 0.0}
+        for i, bound in enumerate(BUCKETS):
+            if value <= bound:
+                h['buckets'][i] += 1
+        h['count'] += 1
+        h['sum'] += value
+
+
+def counter(name: str):
+    """Returns the current value of counter `name`."""
+    with _lock:
+        return _counters.get(name, 0)
+
+
+def snapshot():
+    """
+    Returns a dict containing the current values of all metrics: `counters` contains a dict of
+    counter values; `observations` contains a dict of dicts with `count`, `total`, and `max` keys.
+    """
+    with _lock:
+        return {
+            'counters': dict(_counters),
+            'observations': {k: dict(v) for k, v in _observations.items()},
+        }
+
+
+def labeled_snapshot():
+    """
+    Returns a dict containing the current values of the labeled metrics: `counters` and
+    `histograms` are dicts keyed by (name, labels) pairs, where labels is a sorted tuple of (label,
+    value) pairs.  Histogram values are dicts with keys `buckets` (the number of values less than
+    or equal to each of the BUCKETS bounds), `count`, and `sum`.
+    """
+    with _lock:
+        return {
+            'counters': dict(_labeled_counters),
+            'histograms': {
+                k: {**v, 'buckets': list(v['buckets'])} for k, v in _histograms.items()
+            },
+        }
+
+
+def reset():
+    """Clears all metrics."""
+    with _lock:
+        _counters.clear()
+        _observations.clear()
+        _labeled_counters.clear()
+        _histograms.clear()
@@ -4,12 +4,16 @@ import coloredlogs
 from .. import config
 
 from . import (
+    file_dedup,
     file_message,
     fix_info_update_triggers,
     import_hacks,
+    message_history_dict,
+    message_signatures,
     message_views,
     new_columns,
     new_tables,
+    reaction_counts,
     reactions,
     room_accessible,
     room_moderators,
@@ -49,8 +53,12 @@ def migrate(conn, *, check_only=False):
         room_moderators,
         user_permissions,
         file_message,
+        file_dedup,
         fix_info_update_triggers,
         import_hacks,
+        message_signatures,
+        message_history_dict,
+        reaction_counts,
     ):
         changes = False
         if check_only:
@@ -0,0 +1,23 @@
+from .exc import DatabaseUpgradeRequired
+import logging
+
+
+def migrate(conn, *, check_only):
+    """Adds the files.content_hash column used for deduplicated file storage."""
+
+    from .. import db
+
+    if 'content_hash' in db.metadata.tables['files'].c:
+        return False
+
+    logging.warning("DB migration: adding file content deduplication")
+    if check_only:
+        raise DatabaseUpgradeRequired("Add file content deduplication")
+
+    if db.engine.name == "sqlite":
+        conn.execute("ALTER TABLE files ADD COLUMN content_hash TEXT REFERENCES file_blobs(hash)")
+    else:
+        conn.execute("ALTER TABLE files ADD COLUMN content_hash TEXT REFERENCES file_blobs")
+    conn.execute("CREATE INDEX files_content_hash ON files(content_hash)")
+
+    return True
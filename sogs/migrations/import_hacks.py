@@ -39,6 +39,14 @@ def migrate(conn, *, check_only):
             logging.warning("Keeping file_id_hacks old sogs import table (still required)")
             db.HAVE_FILE_ID_HACKS = True
 
+    if 'room_import_backfill' in db.metadata.tables:
+        # The backfill of lazily imported messages (see sogs.backfill) removes rows as rooms finish
+        n = conn.execute("SELECT COUNT(*) FROM room_import_backfill").first()[0]
+        if not check_only and n == 0:
+            logging.warning("Dropping room_import_backfill old sogs import table (all imported)")
+            db.metadata.tables['room_import_backfill'].drop(db.engine)
+            changed = True
+
     if 'room_import_hacks' in db.metadata.tables:
         rows = conn.execute(
             "SELECT room, old_message_id_max, message_id_offset FROM room_import_hacks"
@@ -0,0 +1,72 @@
+import logging
+from .exc import DatabaseUpgradeRequired
+
+
+def migrate(conn, *, check_only):
+    """
+    Makes the message history trigger record the compression dictionary (messages.data_dict) and
+    padded size (messages.data_size) of the replaced message data along with the data itself.
+    """
+
+    from .. import db
+
+    has_trigger = db.query(
+        """
+        SELECT COUNT(*) FROM sqlite_master
+            WHERE type = 'trigger' AND name = :trigger
+            AND sql LIKE :dict
+        """
+        if db.engine.name == "sqlite"
+        else """
+        SELECT COUNT(*) FROM information_schema.routines
+            WHERE routine_name = :function
+            AND routine_definition LIKE :dict
+        """,
+        trigger='messages_insert_history',
+        function='trigger_messages_insert_history',
+        dict='%OLD.data_size%',
+        dbconn=conn,
+    ).first()[0]
+
+    if has_trigger:
+        return False
+
+    logging.warning("DB migration: updating message history trigger for compressed messages")
+    if check_only:
+        raise DatabaseUpgradeRequired("message history trigger needs to be recreated")
+
+    if db.engine.name == "sqlite":
+        conn.execute("DROP TRIGGER IF EXISTS messages_insert_history")
+        conn.execute(
+            """
+CREATE TRIGGER messages_insert_history AFTER UPDATE OF data ON messages
+FOR EACH ROW WHEN NEW.data IS NOT OLD.data
+BEGIN
+    INSERT INTO message_history (message, data, signature, data_dict, data_size)
+        VALUES (NEW.id, OLD.data, OLD.signature, OLD.data_dict, OLD.data_size);
+    UPDATE rooms SET message_sequence = message_sequence + 1 WHERE id = NEW.room;
+    UPDATE messages SET
+        seqno_data = (SELECT message_sequence FROM rooms WHERE id = NEW.room),
+        edited = (julianday('now') - 2440587.5)*86400.0
+    WHERE id = NEW.id;
+END
+"""
+        )
+
+    else:  # postgresql
+        conn.execute(
+            """
+CREATE OR REPLACE FUNCTION trigger_messages_insert_history()
+RETURNS TRIGGER LANGUAGE PLPGSQL AS $$BEGIN
+    INSERT INTO message_history (message, data, signature, data_dict, data_size)
+        VALUES (NEW.id, OLD.data, OLD.signature, OLD.data_dict, OLD.data_size);
+    UPDATE messages SET
+        seqno_data = increment_room_sequence(NEW.room),
+        edited = (extract(epoch from now()))
+    WHERE id = NEW.id;
+    RETURN NULL;
+END;$$;
+"""
+        )
+
+    return True
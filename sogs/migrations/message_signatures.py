@@ -0,0 +1,20 @@
+from .exc import DatabaseUpgradeRequired
+import logging
+
+
+def migrate(conn, *, check_only):
+    """Adds the indices on message signatures used for message lookups."""
+
+    from .. import db
+
+    if 'messages_signature' in {i.name for i in db.metadata.tables['messages'].indexes}:
+        return False
+
+    logging.warning("DB migration: adding message signature indices")
+    if check_only:
+        raise DatabaseUpgradeRequired("Add message signature indices")
+
+    conn.execute("CREATE INDEX messages_signature ON messages(signature)")
+    conn.execute("CREATE INDEX message_history_signature ON message_history(signature)")
+
+    return True
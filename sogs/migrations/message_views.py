@@ -5,9 +5,15 @@ from .exc import DatabaseUpgradeRequired
 def migrate(conn, *, check_only):
     from .. import db
 
-    if 'message_metadata' in db.metadata.tables and all(
-        x in db.metadata.tables['message_metadata'].c
-        for x in ('whisper_to', 'whisper_mods', 'filtered', 'seqno', 'seqno_data')
+    if (
+        'message_metadata' in db.metadata.tables
+        and all(
+            x in db.metadata.tables['message_metadata'].c
+            for x in ('whisper_to', 'whisper_mods', 'filtered', 'seqno', 'seqno_data', 'kind')
+        )
+        # Postgresql expands `messages.*` when the view is created, so it needs to be recreated
+        # for new messages columns:
+        and 'data_dict' in db.metadata.tables['message_details'].c
     ):
         query_bad_trigger = (
             """
@@ -62,7 +68,7 @@ END
             """
 CREATE VIEW message_metadata AS
 SELECT id, room, "user", session_id, posted, edited, seqno, seqno_data, seqno_reactions, seqno_creation,
-        filtered, whisper_to, whisper_mods,
+        filtered, whisper_to, whisper_mods, kind,
         length(data) AS data_unpadded, data_size, length(signature) as signature_length
     FROM message_details
 """  # noqa: E501
@@ -101,7 +107,7 @@ EXECUTE PROCEDURE trigger_message_details_deleter();
 -- length (rather than raw bytes) for data/signature.
 CREATE VIEW message_metadata AS
 SELECT id, room, "user", session_id, posted, edited, seqno, seqno_data, seqno_reactions, seqno_creation,
-        filtered, whisper_to, whisper_mods,
+        filtered, whisper_to, whisper_mods, kind,
         length(data) AS data_unpadded, data_size, length(signature) as signature_length
     FROM message_details;
 """  # noqa: E501
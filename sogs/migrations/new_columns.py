@@ -15,8 +15,39 @@ def migrate(conn, *, check_only):
             'whisper': 'INTEGER REFERENCES users(id)',
             'whisper_mods': 'BOOLEAN NOT NULL DEFAULT FALSE',
             'filtered': 'BOOLEAN NOT NULL DEFAULT FALSE',
+            'kind': "TEXT NOT NULL DEFAULT 'text'",
+            'data_dict': 'BIGINT',
+        },
+        'message_history': {
+            'data_dict': 'BIGINT',
+            'data_size': 'BIGINT',
+        },
+        'pinned_messages': {
+            'position': 'INTEGER NOT NULL DEFAULT 0',
+        },
+        'rooms': {
+            'active_users': 'BIGINT NOT NULL DEFAULT 0',
+            'raid_mode_until': 'FLOAT',
+            'rules': 'TEXT',
+            'owner': 'BIGINT REFERENCES users(id) ON DELETE SET NULL',
+            'archived': 'FLOAT',
+            'unarchived': 'FLOAT',
+            'sensitive': 'BOOLEAN NOT NULL DEFAULT FALSE',
+        },
+        'files': {
+            'downloads': 'BIGINT NOT NULL DEFAULT 0',
+            'egress': 'BIGINT NOT NULL DEFAULT 0',
+            'phash': 'TEXT',
+            'quarantined': 'BOOLEAN NOT NULL DEFAULT FALSE',
+            'content_type': 'TEXT',
+        },
+        'room_daily_stats': {
+            'reports': 'INTEGER NOT NULL DEFAULT 0',
+            'reports_resolved': 'INTEGER NOT NULL DEFAULT 0',
+            'reports_actioned': 'INTEGER NOT NULL DEFAULT 0',
+            'median_claim': 'FLOAT',
+            'median_resolution': 'FLOAT',
         },
-        'rooms': {'active_users': 'BIGINT NOT NULL DEFAULT 0'},
     }
 
     added = False
@@ -52,6 +52,502 @@ CREATE TABLE inbox (
     expiry FLOAT DEFAULT (extract(epoch from now() + '15 days'))
 );
 CREATE INDEX inbox_recipient ON inbox(recipient);
+""",
+    },
+    'message_translations': {
+        'sqlite': [
+            """
+CREATE TABLE message_translations (
+    message INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    lang TEXT NOT NULL,
+    seqno INTEGER NOT NULL,
+    translated TEXT NOT NULL,
+    PRIMARY KEY(message, lang)
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE message_translations (
+    message BIGINT NOT NULL REFERENCES messages ON DELETE CASCADE,
+    lang TEXT NOT NULL,
+    seqno BIGINT NOT NULL,
+    translated TEXT NOT NULL,
+    PRIMARY KEY(message, lang)
+)
+""",
+    },
+    'bridged_messages': {
+        'sqlite': [
+            """
+CREATE TABLE bridged_messages (
+    message INTEGER NOT NULL PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+    network TEXT NOT NULL,
+    external_id TEXT NOT NULL,
+    external_user TEXT,
+    display_name TEXT,
+    UNIQUE(network, external_id)
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE bridged_messages (
+    message BIGINT NOT NULL PRIMARY KEY REFERENCES messages ON DELETE CASCADE,
+    network TEXT NOT NULL,
+    external_id TEXT NOT NULL,
+    external_user TEXT,
+    display_name TEXT,
+    UNIQUE(network, external_id)
+)
+""",
+    },
+    'moderator_digests': {
+        'sqlite': [
+            """
+CREATE TABLE moderator_digests (
+    "user" INTEGER NOT NULL PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    subscribed FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    last_sent FLOAT
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE moderator_digests (
+    "user" BIGINT NOT NULL PRIMARY KEY REFERENCES users ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    subscribed FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    last_sent FLOAT
+)
+""",
+    },
+    'file_blobs': {
+        'sqlite': [
+            """
+CREATE TABLE file_blobs (
+    hash TEXT NOT NULL PRIMARY KEY,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE file_blobs (
+    hash TEXT NOT NULL PRIMARY KEY,
+    path TEXT NOT NULL,
+    size BIGINT NOT NULL
+)
+""",
+    },
+    'cold_files': {
+        'sqlite': [
+            """
+CREATE TABLE cold_files (
+    path TEXT NOT NULL PRIMARY KEY,
+    cold_path TEXT,
+    compressed BOOLEAN NOT NULL,
+    size INTEGER NOT NULL,
+    stored_size INTEGER NOT NULL,
+    archived FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    restored FLOAT
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE cold_files (
+    path TEXT NOT NULL PRIMARY KEY,
+    cold_path TEXT,
+    compressed BOOLEAN NOT NULL,
+    size BIGINT NOT NULL,
+    stored_size BIGINT NOT NULL,
+    archived FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    restored FLOAT
+)
+""",
+    },
+    'room_egress': {
+        'sqlite': [
+            """
+CREATE TABLE room_egress (
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    period TEXT NOT NULL,
+    bytes INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY(room, period)
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE room_egress (
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    period TEXT NOT NULL,
+    bytes BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY(room, period)
+)
+""",
+    },
+    'room_webhooks': {
+        'sqlite': [
+            """
+CREATE TABLE room_webhooks (
+    id INTEGER NOT NULL PRIMARY KEY,
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    "user" INTEGER NOT NULL REFERENCES users(id),
+    secret_hash BLOB NOT NULL UNIQUE,
+    display_name TEXT NOT NULL,
+    created FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    last_used FLOAT
+)
+""",
+            """
+CREATE INDEX room_webhooks_room ON room_webhooks(room)
+""",
+        ],
+        'pgsql': """
+CREATE TABLE room_webhooks (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    "user" BIGINT NOT NULL REFERENCES users,
+    secret_hash BYTEA NOT NULL UNIQUE,
+    display_name TEXT NOT NULL,
+    created FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    created_by BIGINT REFERENCES users ON DELETE SET NULL,
+    last_used FLOAT
+);
+CREATE INDEX room_webhooks_room ON room_webhooks(room);
+""",
+    },
+    'room_daily_stats': {
+        'sqlite': [
+            """
+CREATE TABLE room_daily_stats (
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    day INTEGER NOT NULL,
+    messages INTEGER NOT NULL DEFAULT 0,
+    posters INTEGER NOT NULL DEFAULT 0,
+    uploads INTEGER NOT NULL DEFAULT 0,
+    upload_bytes INTEGER NOT NULL DEFAULT 0,
+    reports INTEGER NOT NULL DEFAULT 0,
+    reports_resolved INTEGER NOT NULL DEFAULT 0,
+    reports_actioned INTEGER NOT NULL DEFAULT 0,
+    median_claim FLOAT,
+    median_resolution FLOAT,
+    PRIMARY KEY(room, day)
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE room_daily_stats (
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    day BIGINT NOT NULL,
+    messages INTEGER NOT NULL DEFAULT 0,
+    posters INTEGER NOT NULL DEFAULT 0,
+    uploads INTEGER NOT NULL DEFAULT 0,
+    upload_bytes BIGINT NOT NULL DEFAULT 0,
+    reports INTEGER NOT NULL DEFAULT 0,
+    reports_resolved INTEGER NOT NULL DEFAULT 0,
+    reports_actioned INTEGER NOT NULL DEFAULT 0,
+    median_claim FLOAT,
+    median_resolution FLOAT,
+    PRIMARY KEY(room, day)
+)
+""",
+    },
+    'room_api_keys': {
+        'sqlite': [
+            """
+CREATE TABLE room_api_keys (
+    id INTEGER NOT NULL PRIMARY KEY,
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    key_hash BLOB NOT NULL UNIQUE,
+    description TEXT NOT NULL,
+    created FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    last_used FLOAT
+)
+""",
+            """
+CREATE INDEX room_api_keys_room ON room_api_keys(room)
+""",
+        ],
+        'pgsql': """
+CREATE TABLE room_api_keys (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    key_hash BYTEA NOT NULL UNIQUE,
+    description TEXT NOT NULL,
+    created FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    created_by BIGINT REFERENCES users ON DELETE SET NULL,
+    last_used FLOAT
+);
+CREATE INDEX room_api_keys_room ON room_api_keys(room);
+""",
+    },
+    'room_invites': {
+        'sqlite': [
+            """
+CREATE TABLE room_invites (
+    id INTEGER NOT NULL PRIMARY KEY,
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    code_hash BLOB NOT NULL UNIQUE,
+    write BOOLEAN NOT NULL DEFAULT TRUE,
+    max_uses INTEGER,
+    uses INTEGER NOT NULL DEFAULT 0,
+    created FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    expires FLOAT,
+    revoked FLOAT
+)
+""",
+            """
+CREATE INDEX room_invites_room ON room_invites(room)
+""",
+        ],
+        'pgsql': """
+CREATE TABLE room_invites (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    code_hash BYTEA NOT NULL UNIQUE,
+    write BOOLEAN NOT NULL DEFAULT TRUE,
+    max_uses INTEGER,
+    uses INTEGER NOT NULL DEFAULT 0,
+    created FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    created_by BIGINT REFERENCES users ON DELETE SET NULL,
+    expires FLOAT,
+    revoked FLOAT
+);
+CREATE INDEX room_invites_room ON room_invites(room);
+""",
+    },
+    'room_invite_uses': {
+        'sqlite': [
+            """
+CREATE TABLE room_invite_uses (
+    invite INTEGER NOT NULL REFERENCES room_invites(id) ON DELETE CASCADE,
+    "user" INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    used FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    PRIMARY KEY(invite, "user")
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE room_invite_uses (
+    invite BIGINT NOT NULL REFERENCES room_invites ON DELETE CASCADE,
+    "user" BIGINT NOT NULL REFERENCES users ON DELETE CASCADE,
+    used FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    PRIMARY KEY(invite, "user")
+);
+""",
+    },
+    'room_acknowledgments': {
+        'sqlite': [
+            """
+CREATE TABLE room_acknowledgments (
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    "user" INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    acknowledged FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    PRIMARY KEY(room, "user")
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE room_acknowledgments (
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    "user" BIGINT NOT NULL REFERENCES users ON DELETE CASCADE,
+    acknowledged FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    PRIMARY KEY(room, "user")
+);
+""",
+    },
+    'message_reports': {
+        'sqlite': [
+            """
+CREATE TABLE message_reports (
+    id INTEGER NOT NULL PRIMARY KEY,
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    message INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    reporter INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    reason TEXT,
+    reported FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    claimed_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    claimed FLOAT,
+    resolved FLOAT,
+    resolved_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    resolution TEXT CHECK(resolution IN ('actioned', 'dismissed'))
+)
+""",
+            """
+CREATE INDEX message_reports_room ON message_reports(room, resolved)
+""",
+            """
+CREATE INDEX message_reports_message ON message_reports(message)
+""",
+            """
+CREATE INDEX message_reports_reporter ON message_reports(reporter)
+""",
+        ],
+        'pgsql': """
+CREATE TABLE message_reports (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    message BIGINT NOT NULL REFERENCES messages ON DELETE CASCADE,
+    reporter BIGINT NOT NULL REFERENCES users ON DELETE CASCADE,
+    reason TEXT,
+    reported FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    claimed_by BIGINT REFERENCES users ON DELETE SET NULL,
+    claimed FLOAT,
+    resolved FLOAT,
+    resolved_by BIGINT REFERENCES users ON DELETE SET NULL,
+    resolution TEXT CHECK(resolution IN ('actioned', 'dismissed'))
+);
+CREATE INDEX message_reports_room ON message_reports(room, resolved);
+CREATE INDEX message_reports_message ON message_reports(message);
+CREATE INDEX message_reports_reporter ON message_reports(reporter);
+""",
+    },
+    'dm_blocks': {
+        'sqlite': [
+            """
+CREATE TABLE dm_blocks (
+    "user" INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    blocked TEXT NOT NULL,
+    blocked_at FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    PRIMARY KEY("user", blocked)
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE dm_blocks (
+    "user" BIGINT NOT NULL REFERENCES users ON DELETE CASCADE,
+    blocked TEXT NOT NULL,
+    blocked_at FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    PRIMARY KEY("user", blocked)
+);
+""",
+    },
+    'feature_flags': {
+        'sqlite': [
+            """
+CREATE TABLE feature_flags (
+    feature TEXT NOT NULL,
+    room INTEGER REFERENCES rooms(id) ON DELETE CASCADE,
+    enabled BOOLEAN NOT NULL,
+    updated FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    updated_by INTEGER REFERENCES users(id) ON DELETE SET NULL
+)
+""",
+            """
+CREATE INDEX feature_flags_feature ON feature_flags(feature)
+""",
+        ],
+        'pgsql': """
+CREATE TABLE feature_flags (
+    feature TEXT NOT NULL,
+    room BIGINT REFERENCES rooms ON DELETE CASCADE,
+    enabled BOOLEAN NOT NULL,
+    updated FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    updated_by BIGINT REFERENCES users ON DELETE SET NULL
+);
+CREATE INDEX feature_flags_feature ON feature_flags(feature);
+""",
+    },
+    'image_bans': {
+        'sqlite': [
+            """
+CREATE TABLE image_bans (
+    id INTEGER NOT NULL PRIMARY KEY,
+    phash TEXT NOT NULL,
+    room INTEGER REFERENCES rooms(id) ON DELETE SET NULL,
+    banned_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    banned_at FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    reason TEXT
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE image_bans (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    phash TEXT NOT NULL,
+    room BIGINT REFERENCES rooms ON DELETE SET NULL,
+    banned_by BIGINT REFERENCES users ON DELETE SET NULL,
+    banned_at FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    reason TEXT
+)
+""",
+    },
+    'room_tags': {
+        'sqlite': [
+            """
+CREATE TABLE room_tags (
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY(room, tag)
+)
+""",
+            """
+CREATE INDEX room_tags_tag ON room_tags(tag)
+""",
+        ],
+        'pgsql': """
+CREATE TABLE room_tags (
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY(room, tag)
+);
+CREATE INDEX room_tags_tag ON room_tags(tag);
+""",
+    },
+    'room_transfers': {
+        'sqlite': [
+            """
+CREATE TABLE room_transfers (
+    room INTEGER NOT NULL PRIMARY KEY REFERENCES rooms(id) ON DELETE CASCADE,
+    from_user INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    to_user INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    transfer_admins BOOLEAN NOT NULL DEFAULT FALSE,
+    nonce BLOB NOT NULL,
+    created FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    expires FLOAT NOT NULL
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE room_transfers (
+    room BIGINT NOT NULL PRIMARY KEY REFERENCES rooms ON DELETE CASCADE,
+    from_user BIGINT NOT NULL REFERENCES users ON DELETE CASCADE,
+    to_user BIGINT NOT NULL REFERENCES users ON DELETE CASCADE,
+    transfer_admins BOOLEAN NOT NULL DEFAULT FALSE,
+    nonce BYTEA NOT NULL,
+    created FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    expires FLOAT NOT NULL
+)
+""",
+    },
+    'pending_actions': {
+        'sqlite': [
+            """
+CREATE TABLE pending_actions (
+    id INTEGER NOT NULL PRIMARY KEY,
+    action TEXT NOT NULL,
+    room INTEGER REFERENCES rooms(id) ON DELETE CASCADE,
+    target INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    params TEXT,
+    requested_by INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    requested FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    expires FLOAT NOT NULL
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE pending_actions (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    action TEXT NOT NULL,
+    room BIGINT REFERENCES rooms ON DELETE CASCADE,
+    target BIGINT REFERENCES users ON DELETE CASCADE,
+    params TEXT,
+    requested_by BIGINT NOT NULL REFERENCES users ON DELETE CASCADE,
+    requested FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    expires FLOAT NOT NULL
+)
 """,
     },
     'needs_blinding': {
@@ -68,6 +564,162 @@ CREATE TABLE needs_blinding (
     blinded_abs TEXT NOT NULL PRIMARY KEY,
     "user" BIGINT NOT NULL UNIQUE REFERENCES users ON DELETE CASCADE
 )
+""",
+    },
+    'upgrade_advisory': {
+        'sqlite': [
+            """
+CREATE TABLE upgrade_advisory (
+    id INTEGER NOT NULL PRIMARY KEY CHECK(id = 0),
+    checked FLOAT NOT NULL,
+    version TEXT,
+    security BOOLEAN NOT NULL DEFAULT FALSE,
+    url TEXT,
+    error TEXT
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE upgrade_advisory (
+    id SMALLINT NOT NULL PRIMARY KEY CHECK(id = 0),
+    checked FLOAT NOT NULL,
+    version TEXT,
+    security BOOLEAN NOT NULL DEFAULT FALSE,
+    url TEXT,
+    error TEXT
+)
+""",
+    },
+    'anonymous_posts': {
+        'sqlite': [
+            """
+CREATE TABLE anonymous_posts (
+    message INTEGER NOT NULL PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+    author BLOB NOT NULL
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE anonymous_posts (
+    message BIGINT NOT NULL PRIMARY KEY REFERENCES messages ON DELETE CASCADE,
+    author BYTEA NOT NULL
+)
+""",
+    },
+    'message_idempotency': {
+        'sqlite': [
+            """
+CREATE TABLE message_idempotency (
+    message INTEGER NOT NULL PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    "user" INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    idempotency_key TEXT NOT NULL
+)
+""",
+            """
+CREATE UNIQUE INDEX message_idempotency_key ON message_idempotency(room, "user", idempotency_key)
+""",
+        ],
+        'pgsql': """
+CREATE TABLE message_idempotency (
+    message BIGINT NOT NULL PRIMARY KEY REFERENCES messages ON DELETE CASCADE,
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    "user" BIGINT NOT NULL REFERENCES users ON DELETE CASCADE,
+    idempotency_key TEXT NOT NULL
+);
+CREATE UNIQUE INDEX message_idempotency_key ON message_idempotency(room, "user", idempotency_key)
+""",
+    },
+    'message_dicts': {
+        'sqlite': [
+            """
+CREATE TABLE message_dicts (
+    id INTEGER NOT NULL PRIMARY KEY,
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    created FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0),
+    dict BLOB NOT NULL
+)
+""",
+            """
+CREATE INDEX message_dicts_room ON message_dicts(room)
+""",
+        ],
+        'pgsql': """
+CREATE TABLE message_dicts (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    created FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    dict BYTEA NOT NULL
+);
+CREATE INDEX message_dicts_room ON message_dicts(room)
+""",
+    },
+    'event_deliveries': {
+        'sqlite': [
+            """
+CREATE TABLE event_deliveries (
+    id INTEGER NOT NULL PRIMARY KEY,
+    url TEXT NOT NULL,
+    body TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt FLOAT NOT NULL,
+    last_error TEXT
+)
+""",
+            """
+CREATE INDEX event_deliveries_next ON event_deliveries(next_attempt)
+""",
+        ],
+        'pgsql': """
+CREATE TABLE event_deliveries (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    url TEXT NOT NULL,
+    body TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt FLOAT NOT NULL,
+    last_error TEXT
+);
+CREATE INDEX event_deliveries_next ON event_deliveries(next_attempt)
+""",
+    },
+    'journal_leaves': {
+        'sqlite': [
+            """
+CREATE TABLE journal_leaves (
+    message INTEGER NOT NULL PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+    room INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    hash BLOB NOT NULL
+)
+""",
+            """
+CREATE INDEX journal_leaves_room ON journal_leaves(room, message)
+""",
+        ],
+        'pgsql': """
+CREATE TABLE journal_leaves (
+    message BIGINT NOT NULL PRIMARY KEY REFERENCES messages ON DELETE CASCADE,
+    room BIGINT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    hash BYTEA NOT NULL
+);
+CREATE INDEX journal_leaves_room ON journal_leaves(room, message)
+""",
+    },
+    'journal_checkpoints': {
+        'sqlite': [
+            """
+CREATE TABLE journal_checkpoints (
+    room INTEGER NOT NULL PRIMARY KEY REFERENCES rooms(id) ON DELETE CASCADE,
+    seqno INTEGER NOT NULL,
+    root TEXT NOT NULL
+)
+"""
+        ],
+        'pgsql': """
+CREATE TABLE journal_checkpoints (
+    room BIGINT NOT NULL PRIMARY KEY REFERENCES rooms ON DELETE CASCADE,
+    seqno BIGINT NOT NULL,
+    root TEXT NOT NULL
+)
 """,
     },
 }
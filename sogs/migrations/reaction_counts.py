@@ -0,0 +1,101 @@
+import logging
+from .exc import DatabaseUpgradeRequired
+
+
+def migrate(conn, *, check_only):
+    """
+    Adds the reaction_counts table of precomputed reaction counts, along with the triggers that
+    maintain it, and fills it in from the existing user reactions.
+    """
+
+    from .. import db
+
+    if 'reaction_counts' in db.metadata.tables:
+        return False
+
+    logging.warning("DB migration: adding reaction_counts table")
+    if check_only:
+        raise DatabaseUpgradeRequired("reaction_counts table needs to be created")
+
+    if db.engine.name == "sqlite":
+        conn.execute(
+            """
+CREATE TABLE reaction_counts (
+    reaction INTEGER NOT NULL PRIMARY KEY REFERENCES reactions ON DELETE CASCADE,
+    count INTEGER NOT NULL DEFAULT 0
+)
+"""
+        )
+        conn.execute(
+            """
+CREATE TRIGGER reaction_counts_add AFTER INSERT ON reactions
+FOR EACH ROW
+BEGIN
+    INSERT INTO reaction_counts (reaction) VALUES (NEW.id);
+END
+"""
+        )
+        conn.execute(
+            """
+CREATE TRIGGER reaction_counts_incr AFTER INSERT ON user_reactions
+FOR EACH ROW
+BEGIN
+    UPDATE reaction_counts SET count = count + 1 WHERE reaction = NEW.reaction;
+END
+"""
+        )
+        conn.execute(
+            """
+CREATE TRIGGER reaction_counts_decr AFTER DELETE ON user_reactions
+FOR EACH ROW
+BEGIN
+    UPDATE reaction_counts SET count = count - 1 WHERE reaction = OLD.reaction;
+END
+"""
+        )
+
+    else:  # postgresql
+        conn.execute(
+            """
+CREATE TABLE reaction_counts (
+    reaction BIGINT NOT NULL PRIMARY KEY REFERENCES reactions ON DELETE CASCADE,
+    count BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE OR REPLACE FUNCTION trigger_reaction_counts_add()
+RETURNS TRIGGER LANGUAGE PLPGSQL AS $$BEGIN
+    INSERT INTO reaction_counts (reaction) VALUES (NEW.id);
+    RETURN NULL;
+END;$$;
+CREATE TRIGGER reaction_counts_add AFTER INSERT ON reactions
+FOR EACH ROW
+EXECUTE PROCEDURE trigger_reaction_counts_add();
+
+CREATE OR REPLACE FUNCTION trigger_reaction_counts_incr()
+RETURNS TRIGGER LANGUAGE PLPGSQL AS $$BEGIN
+    UPDATE reaction_counts SET count = count + 1 WHERE reaction = NEW.reaction;
+    RETURN NULL;
+END;$$;
+CREATE TRIGGER reaction_counts_incr AFTER INSERT ON user_reactions
+FOR EACH ROW
+EXECUTE PROCEDURE trigger_reaction_counts_incr();
+
+CREATE OR REPLACE FUNCTION trigger_reaction_counts_decr()
+RETURNS TRIGGER LANGUAGE PLPGSQL AS $$BEGIN
+    UPDATE reaction_counts SET count = count - 1 WHERE reaction = OLD.reaction;
+    RETURN NULL;
+END;$$;
+CREATE TRIGGER reaction_counts_decr AFTER DELETE ON user_reactions
+FOR EACH ROW
+EXECUTE PROCEDURE trigger_reaction_counts_decr();
+"""
+        )
+
+    conn.execute(
+        """
+INSERT INTO reaction_counts (reaction, count)
+SELECT id, (SELECT COUNT(*) FROM user_reactions WHERE reaction = reactions.id) FROM reactions
+"""
+    )
+
+    return True
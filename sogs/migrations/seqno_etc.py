@@ -40,6 +40,7 @@ CREATE TABLE pinned_messages (
     message INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
     pinned_by INTEGER NOT NULL REFERENCES users(id),
     pinned_at FLOAT NOT NULL DEFAULT ((julianday('now') - 2440587.5)*86400.0), /* unix epoch when pinned */
+    position INTEGER NOT NULL DEFAULT 0,
     PRIMARY KEY(room, message)
 )
 """  # noqa: E501
@@ -81,6 +82,7 @@ CREATE TABLE pinned_messages (
     message BIGINT NOT NULL REFERENCES messages ON DELETE CASCADE,
     pinned_by BIGINT NOT NULL REFERENCES users,
     pinned_at FLOAT NOT NULL DEFAULT (extract(epoch from now())),
+    position INTEGER NOT NULL DEFAULT 0,
     PRIMARY KEY(room, message)
 );
 
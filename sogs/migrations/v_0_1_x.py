@@ -38,10 +38,238 @@ def sqlite_connect_readonly(path):
     return conn
 
 
-def import_from_0_1_x(conn):
+def insert_user(conn, session_id):
+    """Inserts a user with the given session_id, if one doesn't already exist"""
+
+    from .. import db
+
+    if not db.query(
+        "SELECT COUNT(*) FROM users WHERE session_id = :session_id",
+        dbconn=conn,
+        session_id=session_id,
+    ).first()[0]:
+        db.query(
+            "INSERT INTO users (session_id, last_active) VALUES (:session_id, 0.0)",
+            session_id=session_id,
+            dbconn=conn,
+        )
+
+
+def count_deletions(rconn, up_to=None):
+    """
+    Counts the deleted messages in old room database connection `rconn` (optionally only those with
+    ids <= `up_to`).  This is the seqno that the imported message with id `up_to` gets.
+    """
+    return rconn.execute(
+        """
+        SELECT COUNT(*) FROM messages
+        WHERE is_deleted AND id IN (SELECT deleted_message_id FROM deleted_messages)
+            AND (? IS NULL OR id <= ?)
+        """,
+        (up_to, up_to),
+    ).fetchone()[0]
+
+
+def verified_rows(cursor, *, chunk_size=1000):
+    """
+    Yields the old message rows (id, session_id, timestamp, data, signature, deleted) of `cursor`
+    with a `valid` value appended, which is False if [db] import_verify is enabled and the row is a
+    regular message whose signature does not verify, and True otherwise.  Rows are fetched and
+    verified `chunk_size` at a time, using the sigcache worker pool.
+    """
+
+    from .. import config, crypto, sigcache, utils
+
+    while True:
+        rows = cursor.fetchmany(chunk_size)
+        if not rows:
+            return
+        valid = [True] * len(rows)
+
+        if config.IMPORT_VERIFY:
+            checks, indices = [], []
+            for i, (_, session_id, _, data, signature, deleted) in enumerate(rows):
+                if data is None or signature is None or deleted is not None:
+                    continue
+                signature = utils.decode_base64(signature)
+                if len(signature) != 64:
+                    continue  # import_messages fails on these
+                try:
+                    pubkey = crypto.xed25519_pubkey(bytes.fromhex(session_id[2:]))
+                except Exception:
+                    valid[i] = False
+                    continue
+                # Old SOGS stored the padded data, which is what the client signed:
+                checks.append((pubkey, utils.decode_base64(data), signature))
+                indices.append(i)
+
+            for i, ok in zip(indices, sigcache.verify_batch(checks)):
+                valid[i] = ok
+
+        for row, ok in zip(rows, valid):
+            yield (*row, ok)
+
+
+def import_messages(conn, rconn, room_id, id_offset, *, before=None, limit=None, progress=None):
+    """
+    Imports messages from old room database connection `rconn` into room `room_id`, from newest to
+    oldest, giving them new ids of their old id plus `id_offset`.  If `before` is given then only
+    messages with old ids less than it are imported; if `limit` is given then at most that many
+    messages are imported.  `progress`, if given, is the total number of messages being imported,
+    for periodic progress logging.
+
+    Returns a tuple of: the number of messages imported; the number of duplicate deletion rows
+    ignored; and, if older messages remain to be imported, the old id before which they remain (or
+    None if there are none).
+    """
 
     from .. import config, db, utils
 
+    imported, dupe_dels, invalid, last_id = 0, 0, 0, None
+    seqno = None
+
+    rows = rconn.execute(
+        """
+        SELECT messages.id, public_key AS session_id, timestamp, data, signature,
+            CASE WHEN is_deleted THEN deleted_messages.id ELSE NULL END AS deleted
+        FROM messages LEFT JOIN deleted_messages
+            ON messages.id = deleted_messages.deleted_message_id
+        WHERE ? IS NULL OR messages.id < ?
+        ORDER BY messages.id DESC
+        """,
+        (before, before),
+    )
+    for id, session_id, timestamp, data, signature, deleted, valid in verified_rows(rows):
+        if id == last_id:
+            # There are duplicates in the deleted_messages table (WTF) that can give us multiple
+            # rows through the join, so skip duplicates if they occur.
+            dupe_dels += 1
+            continue
+        if limit is not None and imported >= limit:
+            break
+        last_id = id
+
+        # Each message's seqno is the number of deletions up to and including it (because, as
+        # described above, old SOGS deletion ids become our seqnos):
+        if seqno is None:
+            seqno = count_deletions(rconn, id)
+
+        # NB: the old database cleared the session ID when deleting a message (which is bad,
+        # because no auditability at all), but also cleared it by setting it to the fixed string
+        # 'deleted' because I guess the author didn't know NULL was a thing?  We import them as
+        # such because our session_ids *can't* be null (and we no longer clear it when deleting),
+        # but the data is gone from the imported table so there's not much else we can do.
+
+        insert_user(conn, session_id)
+
+        if config.IMPORT_ADJUST_MS:
+            timestamp += config.IMPORT_ADJUST_MS
+
+        # Timestamp is in unix epoch *milliseconds* for some non-standard reason.
+        timestamp /= 1000.0
+
+        if valid and data is not None and signature is not None and deleted is None:
+            # Regular message
+
+            # Data was pointlessly store padded *and* base64 encoded, so decode and unpad it:
+            data = utils.decode_base64(data)
+            data_size = len(data)
+            data = utils.remove_session_message_padding(data)
+
+            # Signature was just base64 encoded:
+            signature = utils.decode_base64(signature)
+            if len(signature) != 64:
+                raise RuntimeError(
+                    f"Unexpected data: room {room_id} message id={id} has invalid signature"
+                )
+
+            db.query(
+                """
+                INSERT INTO messages
+                    (id, room, "user", posted, data, data_size, signature)
+                VALUES (:m, :r, (SELECT id FROM users WHERE session_id = :session_id),
+                    :posted, :data, :data_size, :signature)
+                """,
+                m=id + id_offset,
+                r=room_id,
+                session_id=session_id,
+                posted=timestamp,
+                data=data,
+                data_size=data_size,
+                signature=signature,
+                dbconn=conn,
+            )
+
+        elif not valid or (
+            deleted is not None
+            # Deleted messages are usually set to the fixed string "deleted" (why not NULL?) for
+            # data and signature, so accept either null or that string if the other columns
+            # indicate a deleted message.
+            and data in (None, "deleted")
+            and signature in (None, "deleted")
+        ):
+
+            # Deleted message (or one with a bad signature); we still need to insert a tombstone for
+            # it, and copy the deletion id as the "seqno" field.  (We do this with a second query
+            # because the first query is going to trigger an automatic update of the field).
+            if not valid:
+                invalid += 1
+                logging.warning(
+                    f"Room {room_id} message id={id} has an invalid signature; importing it as "
+                    "deleted"
+                )
+
+            db.query(
+                """
+                INSERT INTO messages (id, room, "user", posted)
+                VALUES (:m, :r, (SELECT id FROM users WHERE session_id = :session_id), :posted)
+                """,
+                m=id + id_offset,
+                r=room_id,
+                session_id=session_id,
+                posted=timestamp,
+                dbconn=conn,
+            )
+
+        else:
+            raise RuntimeError(
+                "Inconsistent message in room {} database: message id={} has inconsistent "
+                "deletion state (data: {}, signature: {}, del row: {})".format(
+                    room_id, id, data is not None, signature is not None, deleted is not None
+                )
+            )
+
+        db.query(
+            "UPDATE messages SET seqno = :s, seqno_creation = 0, seqno_data = :s WHERE id = :m",
+            s=seqno,
+            m=id + id_offset,
+            dbconn=conn,
+        )
+        if deleted is not None:
+            seqno -= 1
+
+        imported += 1
+        if progress and imported % 5000 == 0:
+            logging.info(f"- ... imported {imported}/{progress} messages")
+
+    if invalid:
+        logging.warning(f"- {invalid} imported messages had invalid signatures")
+
+    remaining = None
+    if (
+        limit is not None
+        and imported >= limit
+        and rconn.execute("SELECT 1 FROM messages WHERE id < ? LIMIT 1", (last_id,)).fetchone()
+    ):
+        remaining = last_id
+
+    return imported, dupe_dels, remaining
+
+
+def import_from_0_1_x(conn):
+
+    from .. import config, db
+
     # Old database database.db is a single table database containing just the list of rooms:
     #    CREATE TABLE IF NOT EXISTS main (
     #        id TEXT PRIMARY KEY, -- now called token
@@ -76,19 +304,20 @@ def import_from_0_1_x(conn):
         dbconn=conn,
     )
 
-    used_room_hacks, used_file_hacks = False, False
-
-    def ins_user(session_id):
-        if not db.query(
-            "SELECT COUNT(*) FROM users WHERE session_id = :session_id",
-            dbconn=conn,
-            session_id=session_id,
-        ).first()[0]:
-            db.query(
-                "INSERT INTO users (session_id, last_active) VALUES (:session_id, 0.0)",
-                session_id=session_id,
-                dbconn=conn,
+    if config.IMPORT_BACKFILL:
+        db.query(
+            """
+            CREATE TABLE IF NOT EXISTS room_import_backfill (
+                room BIGINT PRIMARY KEY NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+                db_path TEXT NOT NULL,
+                id_offset BIGINT NOT NULL,
+                before_id BIGINT NOT NULL
             )
+            """,
+            dbconn=conn,
+        )
+
+    used_room_hacks, used_file_hacks, used_backfill = False, False, False
 
     total_rooms, total_msgs, total_files = 0, 0, 0
 
@@ -148,135 +377,44 @@ def import_from_0_1_x(conn):
             # a monotonic message_sequence field that we can make conform (for imported rows) to
             # the imported deletion ids.
 
-            id_offset = db.query(
+            next_id = db.query(
                 "SELECT COALESCE( MAX(id), 0 ) + 1 FROM messages", dbconn=conn
             ).first()[0]
-            top_old_id, seqno, imported_msgs = -1, 0, 0
+            first_old_id, top_old_id = rconn.execute(
+                "SELECT MIN(id), MAX(id) FROM messages"
+            ).fetchone()
+            if first_old_id is None:
+                id_offset, top_old_id = next_id, -1
+            else:
+                id_offset = next_id - first_old_id
 
             n_msgs = rconn.execute("SELECT COUNT(*) FROM messages").fetchone()[0]
 
-            last_id, dupe_dels = -1, 0
-
-            for id, session_id, timestamp, data, signature, deleted in rconn.execute(
-                """
-                SELECT messages.id, public_key AS session_id, timestamp, data, signature,
-                    CASE WHEN is_deleted THEN deleted_messages.id ELSE NULL END AS deleted
-                FROM messages LEFT JOIN deleted_messages
-                    ON messages.id = deleted_messages.deleted_message_id
-                ORDER BY messages.id
-                """
-            ):
-                if top_old_id == -1:
-                    id_offset -= id
-                if id > top_old_id:
-                    top_old_id = id
-                if id == last_id:
-                    # There are duplicates in the deleted_messages table (WTF) that can give us
-                    # multiple rows through the join, so skip duplicates if they occur.
-                    dupe_dels += 1
-                    continue
-                else:
-                    last_id = id
-
-                # NB: the old database cleared the session ID when deleting a message (which is
-                # bad, because no auditability at all), but also cleared it by setting it to the
-                # fixed string 'deleted' because I guess the author didn't know NULL was a
-                # thing?  We import them as such because our session_ids *can't* be null (and we
-                # no longer clear it when deleting), but the data is gone from the imported
-                # table so there's not much else we can do.
-
-                ins_user(session_id)
-
-                if config.IMPORT_ADJUST_MS:
-                    timestamp += config.IMPORT_ADJUST_MS
-
-                # Timestamp is in unix epoch *milliseconds* for some non-standard reason.
-                timestamp /= 1000.0
-
-                if data is not None and signature is not None and deleted is None:
-                    # Regular message
-
-                    # Data was pointlessly store padded *and* base64 encoded, so decode and
-                    # unpad it:
-                    data = utils.decode_base64(data)
-                    data_size = len(data)
-                    data = utils.remove_session_message_padding(data)
-
-                    # Signature was just base64 encoded:
-                    signature = utils.decode_base64(signature)
-                    if len(signature) != 64:
-                        raise RuntimeError(
-                            f"Unexpected data: {room_db_path} message id={id} "
-                            "has invalid signature"
-                        )
-
-                    db.query(
-                        """
-                        INSERT INTO messages
-                            (id, room, "user", posted, data, data_size, signature)
-                        VALUES (:m, :r, (SELECT id FROM users WHERE session_id = :session_id),
-                            :posted, :data, :data_size, :signature)
-                        """,
-                        m=id + id_offset,
-                        r=room_id,
-                        session_id=session_id,
-                        posted=timestamp,
-                        data=data,
-                        data_size=data_size,
-                        signature=signature,
-                        dbconn=conn,
-                    )
-
-                elif (
-                    deleted is not None
-                    # Deleted messages are usually set to the fixed string "deleted" (why not
-                    # NULL?) for data and signature, so accept either null or that string if the
-                    # other columns indicate a deleted message.
-                    and data in (None, "deleted")
-                    and signature in (None, "deleted")
-                ):
-
-                    # Deleted message; we still need to insert a tombstone for it, and copy the
-                    # deletion id as the "seqno" field.  (We do this with a second query
-                    # because the first query is going to trigger an automatic update of the
-                    # field).
-
-                    seqno += 1
+            if config.IMPORT_BACKFILL:
+                # Import just the most recent message now (which also reserves the new message ids
+                # of the rest), and leave the rest to be backfilled once the server is running (see
+                # sogs.backfill).
+                imported_msgs, dupe_dels, remaining = import_messages(
+                    conn, rconn, room_id, id_offset, limit=1
+                )
+                if remaining is not None:
+                    used_backfill = True
                     db.query(
                         """
-                        INSERT INTO messages (id, room, "user", posted)
-                        VALUES (:m, :r, (SELECT id FROM users WHERE session_id = :session_id),
-                            :posted)
+                        INSERT INTO room_import_backfill (room, db_path, id_offset, before_id)
+                        VALUES (:r, :path, :offset, :before)
                         """,
-                        m=id + id_offset,
                         r=room_id,
-                        session_id=session_id,
-                        posted=timestamp,
+                        path=os.path.abspath(room_db_path),
+                        offset=id_offset,
+                        before=remaining,
                         dbconn=conn,
                     )
-
-                else:
-                    raise RuntimeError(
-                        "Inconsistent message in {} database: message id={} has inconsistent "
-                        "deletion state (data: {}, signature: {}, del row: {})".format(
-                            room_db_path,
-                            id,
-                            data is not None,
-                            signature is not None,
-                            deleted is not None,
-                        )
-                    )
-
-                db.query(
-                    "UPDATE messages SET seqno = :s, seqno_creation = 0, seqno_data = :s"
-                    " WHERE id = :m",
-                    s=seqno,
-                    m=id + id_offset,
-                    dbconn=conn,
+                    logging.info(f"- {n_msgs - imported_msgs} messages will be backfilled")
+            else:
+                imported_msgs, dupe_dels, _ = import_messages(
+                    conn, rconn, room_id, id_offset, progress=n_msgs
                 )
-                imported_msgs += 1
-                if imported_msgs % 5000 == 0:
-                    logging.info(f"- ... imported {imported_msgs}/{n_msgs} messages")
 
             logging.info(
                 f"- migrated {imported_msgs} messages, {dupe_dels} duplicate deletions ignored"
@@ -294,7 +432,7 @@ def import_from_0_1_x(conn):
 
             db.query(
                 "UPDATE rooms SET message_sequence = :ms WHERE id = :r",
-                ms=max(seqno, top_del_id),
+                ms=max(count_deletions(rconn), top_del_id),
                 r=room_id,
                 dbconn=conn,
             )
@@ -453,7 +591,7 @@ def import_from_0_1_x(conn):
 
             imported_bans = 0
             for (session_id,) in rconn.execute("SELECT public_key FROM block_list"):
-                ins_user(session_id)
+                insert_user(conn, session_id)
                 db.query(
                     """
                     INSERT INTO user_permission_overrides (room, "user", banned)
@@ -472,7 +610,7 @@ def import_from_0_1_x(conn):
 
             imported_mods = 0
             for (session_id,) in rconn.execute("SELECT public_key from moderators"):
-                ins_user(session_id)
+                insert_user(conn, session_id)
                 db.query(
                     """
                     INSERT INTO user_permission_overrides
@@ -508,7 +646,7 @@ def import_from_0_1_x(conn):
                 (import_cutoff,),
             ):
 
-                ins_user(session_id)
+                insert_user(conn, session_id)
                 db.query(
                     """
                     INSERT INTO room_users (room, "user", last_active)
@@ -565,6 +703,8 @@ def import_from_0_1_x(conn):
         db.query("DROP TABLE room_import_hacks", dbconn=conn)
     if not used_file_hacks:
         db.query("DROP TABLE file_id_hacks", dbconn=conn)
+    if config.IMPORT_BACKFILL and not used_backfill:
+        db.query("DROP TABLE room_import_backfill", dbconn=conn)
 
     logging.warning(
         "Import finished!  Imported {} messages/{} files in {} rooms".format(
@@ -18,6 +18,10 @@ capabilities = {
     # 'newcap',  # Add here
 }
 
+if config.TRANSLATE_URL:
+    # server-side message translation is available
+    capabilities.add('translate')
+
 if config.REQUIRE_BLIND_KEYS:
     # indicate blinding required if configured to do so
     capabilities.add('blind')
@@ -0,0 +1,140 @@
+from .. import db
+from ..db import query
+from ..hashing import blake2b
+from .exc import BadPermission, InvalidData, NoSuchApiKey
+from .user import User
+import secrets
+import time
+
+
+class ApiKey:
+    """
+    Class representing a read-only room API key: a secret token that lets an integration (such as a
+    dashboard or archive viewer) read a single room without a Session identity.  API keys are
+    separate from user authentication: a request made with an API key has no user, and is only
+    permitted to make read-only requests of the key's room.
+
+    Properties:
+        id - the numeric API key id
+        room - the Room that this key grants read access to (only retrieved on demand)
+        description - the admin-provided description of the key
+        created - unix timestamp when the key was created
+        created_by - the id of the user who created the key (None if since deleted)
+        last_used - unix timestamp when the key was last used, or None if never used
+    """
+
+    # Don't update last_used more often than this (in seconds), to avoid a database write on every
+    # request made with the key.
+    TOUCH_INTERVAL = 60
+
+    def __init__(self, row=None, *, id=None, key=None):
+        """
+        Constructs an API key from a pre-retrieved row, an API key id, *or* the secret key itself.
+        Raises NoSuchApiKey if there is no such key.
+        """
+        if sum(x is not None for x in (row, id, key)) != 1:
+            raise ValueError("ApiKey() error: exactly one of row/id/key is required")
+        if id is not None:
+            row = query("SELECT * FROM room_api_keys WHERE id = :id", id=id).first()
+            if not row:
+                raise NoSuchApiKey(id)
+        elif key is not None:
+            row = query("SELECT * FROM room_api_keys WHERE key_hash = :h", h=_key_hash(key)).first()
+            if not row:
+                raise NoSuchApiKey()
+
+        (
+            self.id,
+            self._room_id,
+            self.description,
+            self.created,
+            self.created_by,
+            self.last_used,
+        ) = (row[c] for c in ('id', 'room', 'description', 'created', 'created_by', 'last_used'))
+        self._room = None
+
+    @staticmethod
+    def create(room, creator: User, description: str):
+        """
+        Issues a new read-only API key for `room`; `creator` must be an admin of the room.
+
+        Returns an (api_key, key) tuple; the secret key is not stored (only a hash of it is) and so
+        can only be obtained here.
+        """
+        if not room.check_admin(creator):
+            raise BadPermission()
+        if not description:
+            raise InvalidData("API key description cannot be empty")
+
+        key = secrets.token_urlsafe(32)
+        key_id = db.insert_and_get_pk(
+            """
+            INSERT INTO room_api_keys (room, key_hash, description, created_by)
+            VALUES (:r, :h, :desc, :u)
+            """,
+            "id",
+            r=room.id,
+            h=_key_hash(key),
+            desc=description,
+            u=creator.id,
+        )
+        return ApiKey(id=key_id), key
+
+    @property
+    def room(self):
+        """The Room that this key grants read access to."""
+        if self._room is None:
+            from .room import Room
+
+            self._room = Room(id=self._room_id)
+        return self._room
+
+    @property
+    def room_id(self):
+        """The id of the Room that this key grants read access to."""
+        return self._room_id
+
+    def touch(self):
+        """Updates the key's last used timestamp (if not updated within the last TOUCH_INTERVAL)"""
+        now = time.time()
+        if self.last_used is not None and now - self.last_used < ApiKey.TOUCH_INTERVAL:
+            return
+        query("UPDATE room_api_keys SET last_used = :now WHERE id = :id", now=now, id=self.id)
+        self.last_used = now
+
+    def revoke(self, user: User):
+        """Revokes (i.e. deletes) this API key; `user` must be an admin of the room."""
+        if not self.room.check_admin(user):
+            raise BadPermission()
+        query("DELETE FROM room_api_keys WHERE id = :id", id=self.id)
+
+    def info(self):
+        """Returns a dict of API key details suitable for returning to a room admin."""
+        return {
+            'id': self.id,
+            'description': self.description,
+            'created': self.created,
+            'last_used': self.last_used,
+        }
+
+
+def _key_hash(key: str):
+    return blake2b(key.encode(), digest_size=32, person=b'sogs.apikey')
+
+
+def revoke_all(room=None):
+    """
+    Revokes all API keys of `room`, or of all rooms if `room` is None.  This does not check
+    permissions.  Returns the number of keys revoked.
+    """
+    if room is None:
+        return query("DELETE FROM room_api_keys").rowcount
+    return query("DELETE FROM room_api_keys WHERE room = :r", r=room.id).rowcount
+
+
+def get_room_api_keys(room):
+    """Returns a list of all API keys of `room`, ordered by id."""
+    return [
+        ApiKey(row)
+        for row in query("SELECT * FROM room_api_keys WHERE room = :r ORDER BY id", r=room.id)
+    ]
@@ -37,6 +37,51 @@ class NoSuchPost(NotFound):
         super().__init__(f"No such post: {id}")
 
 
+class NoSuchWebhook(NotFound):
+    """Thrown when attempting to retrieve a room webhook that doesn't exist"""
+
+    def __init__(self, id=None):
+        self.id = id
+        super().__init__("No such webhook" if id is None else f"No such webhook: {id}")
+
+
+class NoSuchApiKey(NotFound):
+    """Thrown when attempting to retrieve a room API key that doesn't exist"""
+
+    def __init__(self, id=None):
+        self.id = id
+        super().__init__("No such API key" if id is None else f"No such API key: {id}")
+
+
+class NoSuchInvite(NotFound):
+    """
+    Thrown when attempting to retrieve or redeem a room invite code that doesn't exist (or, when
+    redeeming, that has expired, been revoked, or been used up)
+    """
+
+    def __init__(self, id=None):
+        self.id = id
+        super().__init__("No such invite" if id is None else f"No such invite: {id}")
+
+
+class NoSuchReport(NotFound):
+    """Thrown when attempting to claim or resolve the reports of a message without open reports"""
+
+    def __init__(self, id=None):
+        self.id = id
+        super().__init__("No open reports" if id is None else f"No open reports of message {id}")
+
+
+class NoSuchPendingAction(NotFound):
+    """Thrown when attempting to retrieve a pending action that doesn't exist (or has expired)"""
+
+    def __init__(self, id=None):
+        self.id = id
+        super().__init__(
+            "No such pending action" if id is None else f"No such pending action: {id}"
+        )
+
+
 class AlreadyExists(RuntimeError):
     """
     Thrown when attempting to create a record (e.g. a Room) that already exists.
@@ -59,6 +104,54 @@ class BadPermission(RuntimeError):
         super().__init__("Permission denied" if msg is None else msg)
 
 
+class AccountTooNew(BadPermission):
+    """
+    Thrown when a user attempts to post or upload in a room that requires accounts to have existed
+    for longer than the user's has.  e.allowed_at is the unix timestamp from which the user may
+    post.
+    """
+
+    def __init__(self, allowed_at):
+        self.allowed_at = allowed_at
+        super().__init__(f"Account is too new to post in this room until {allowed_at:.0f}")
+
+
+class ChallengeRequired(BadPermission):
+    """
+    Thrown when a user's first post to a room doesn't include a valid solution to the room's
+    proof-of-work challenge (see sogs.challenge).  e.difficulty is the required difficulty, in bits.
+    """
+
+    def __init__(self, difficulty):
+        self.difficulty = difficulty
+        super().__init__("A solved proof-of-work challenge is required to post in this room")
+
+
+class RoomLimitReached(RuntimeError):
+    """
+    Thrown when creating a room, or transferring a room to a new owner, would exceed the server's
+    configured room limits ([rooms] `max_rooms` and `max_owned`).
+    """
+
+
+class PinLimitReached(RuntimeError):
+    """
+    Thrown when pinning a message would exceed the room's maximum number of pinned messages ([rooms]
+    `max_pins`).
+    """
+
+
+class ReportClaimed(RuntimeError):
+    """
+    Thrown when a moderator attempts to claim, release, or resolve message reports that another
+    moderator has claimed.  e.claimed_by is the session id of the moderator holding the claim.
+    """
+
+    def __init__(self, claimed_by):
+        self.claimed_by = claimed_by
+        super().__init__(f"Reports already claimed by {claimed_by}")
+
+
 class InvalidData(RuntimeError):
     """Thrown if something in model was fed invalid data, for example a signature of an invalid
     size, or an unparseable entity."""
@@ -75,7 +168,48 @@ class PostRejected(RuntimeError):
 
 
 class PostRateLimited(PostRejected):
-    """Thrown when attempting to post too frequently in a room"""
+    """
+    Thrown when attempting to post too frequently in a room, or to send a direct message to a user
+    whose inbox is full.  e.scope is what the limit applies to (`room_post` or `inbox_messages`),
+    e.limit is the number of posts permitted per rate limiting interval (or the inbox size), and
+    e.reset the unix timestamp at which posting will next be permitted, if known.
+    """
 
-    def __init__(self, msg=None):
+    def __init__(self, msg=None, *, scope='room_post', limit=None, reset=None):
         super().__init__("Rate limited" if msg is None else msg)
+        self.scope = scope
+        self.limit = limit
+        self.reset = reset
+
+
+class PostNotStored(RuntimeError):
+    """
+    Thrown when the database fails to store (or commit) a post, e.g. because the database is locked
+    or its connection was lost.  Nothing about the post was stored, so it can be retried.
+    """
+
+    def __init__(self, msg=None):
+        super().__init__("Post could not be stored; try again" if msg is None else msg)
+
+
+class UploadRejected(PostRejected):
+    """Thrown when an upload is refused, e.g. because it matches a banned image"""
+
+    def __init__(self, msg=None):
+        super().__init__("Upload rejected" if msg is None else msg)
+
+
+class QuotaExceeded(UploadRejected):
+    """
+    Thrown when an upload (or a direct message) is refused because it would exceed a storage quota.
+    e.quota is the quota reached (`room_storage` for a room's [room:TOKEN] or [files] `storage_cap`,
+    `server_storage` for the [files] `server_storage_cap`, `user_storage` for the [files]
+    `user_storage_cap`, or `inbox_storage` for the [direct_messages] `inbox_max_bytes`), e.limit its
+    size in bytes, and e.used the number of bytes already used.
+    """
+
+    def __init__(self, msg=None, *, quota, limit, used):
+        super().__init__("Storage quota reached" if msg is None else msg)
+        self.quota = quota
+        self.limit = limit
+        self.used = used
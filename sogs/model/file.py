@@ -1,5 +1,5 @@
 from ..db import query
-from .. import config, utils
+from .. import config, db, phash, storage, utils
 from .exc import NoSuchFile, NoSuchUser
 import time
 from typing import List
@@ -17,10 +17,17 @@ class File:
         size - the size (in bytes) of this file
         uploaded - unix timestamp when the file was uploaded
         expiry - unix timestamp when the file expires.  None for non-expiring files.
-        path - the path of this file on disk, relative to the base data directory.
+        path - the storage location of this file: for local storage this is the path of the file on
+            disk, relative to the base data directory; for S3 storage it is the object key.
         filename - the suggested filename provided by the user.  None for there is no suggestion
             (this will always be the case for files uploaded by legacy Session clients, and
             sometimes by newer Session clients, e.g. when uploading from a paste).
+        content_type - the content type declared by the uploader, if any.  As this comes from the
+            uploader it isn't to be trusted: files are only served with it for the content types
+            permitted by [files].inline_types.
+        phash - the perceptual hash of the file, if it is an image (see sogs.phash); None otherwise.
+        quarantined - True if the file matched a banned image when uploaded, and so is only served
+            to moderators.
     """
 
     def __init__(self, row=None, *, id=None):
@@ -44,7 +51,9 @@ class File:
             self.uploaded,
             self.expiry,
             self.filename,
+            self.content_type,
             self.path,
+            self.phash,
         ) = (
             row[c]
             for c in (
@@ -56,9 +65,12 @@ class File:
                 'uploaded',
                 'expiry',
                 'filename',
+                'content_type',
                 'path',
+                'phash',
             )
         )
+        self.quarantined = bool(row['quarantined'])
         self._room = None
         self._uploader = None
 
@@ -113,12 +125,22 @@ class File:
         return self._fetch_uploader_id if self._uploader is None else self._uploader.id
 
     def read(self):
-        """Reads the file from disk, as bytes."""
-        with open(self.path, 'rb') as f:
-            return f.read()
+        """Reads the file from storage, as bytes."""
+        return storage.read_file(self.path)
+
+    def open(self):
+        """Returns a file-like object for streaming the file content from storage."""
+        return storage.open_file(self.path)
+
+    def download_url(self, disposition=None, content_type='application/octet-stream'):
+        """
+        Returns a URL from which the file can be downloaded directly from storage, or None if the
+        file must be served by SOGS.  See storage.download_url.
+        """
+        return storage.download_url(self.path, disposition, content_type)
 
     def read_base64(self):
-        """Reads the file from disk and encodes as base64."""
+        """Reads the file from storage and encodes as base64."""
         return utils.encode_base64(self.read())
 
     def set_expiry(self, duration=None, forever=False):
@@ -138,6 +160,30 @@ class File:
         query("UPDATE files SET expiry = :when WHERE id = :f", when=expiry, f=self.id)
         self.expiry = expiry
 
+    def ban_image(self, *, by, reason=None):
+        """
+        Bans images matching this file's image (see sogs.phash.ban), as found in the file's room by
+        the moderator `by`, and quarantines the file itself.  Returns the id of the new ban, or None
+        if the file is not an image.
+        """
+        image_hash = self.phash
+        if image_hash is None:
+            # E.g. uploaded before image hashing was enabled
+            image_hash = phash.compute(self.read())
+        if image_hash is None:
+            return None
+
+        with db.transaction():
+            ban_id = phash.ban(image_hash, by=by, room=self.room, reason=reason)
+            query(
+                "UPDATE files SET phash = :h, quarantined = TRUE WHERE id = :f",
+                h=image_hash,
+                f=self.id,
+            )
+        self.phash = image_hash
+        self.quarantined = True
+        return ban_id
+
     @staticmethod
     def reset_expiries(file_ids: List[int]):
         query(
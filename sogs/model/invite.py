@@ -0,0 +1,206 @@
+from .. import db
+from ..db import query
+from ..hashing import blake2b
+from .exc import BadPermission, InvalidData, NoSuchInvite
+from .user import SystemUser, User
+import secrets
+import time
+
+
+class Invite:
+    """
+    Class representing a room invite code: a secret code, issued by a room moderator, that grants
+    the user who redeems it read (and optionally write) access to the room.  This allows a room that
+    is not publicly readable (or accessible) to admit new users without a moderator having to grant
+    each of them access individually.  A code can be limited to a number of uses and can expire, and
+    can be revoked at any time; the users who redeem each code are recorded.
+
+    Properties:
+        id - the numeric invite id
+        room - the Room that this invite grants access to (only retrieved on demand)
+        write - True if the invite grants write access in addition to read access
+        max_uses - the number of users that may redeem the invite, or None for no limit
+        uses - the number of users who have redeemed the invite
+        created - unix timestamp when the invite was created
+        created_by - the id of the user who created the invite (None if since deleted)
+        expires - unix timestamp when the invite expires, or None if it doesn't expire
+        revoked - unix timestamp when the invite was revoked, or None if not revoked
+    """
+
+    def __init__(self, row=None, *, id=None, code=None):
+        """
+        Constructs an invite from a pre-retrieved row, an invite id, *or* the secret code itself.
+        Raises NoSuchInvite if there is no such invite.
+        """
+        if sum(x is not None for x in (row, id, code)) != 1:
+            raise ValueError("Invite() error: exactly one of row/id/code is required")
+        if id is not None:
+            row = query("SELECT * FROM room_invites WHERE id = :id", id=id).first()
+            if not row:
+                raise NoSuchInvite(id)
+        elif code is not None:
+            row = query(
+                "SELECT * FROM room_invites WHERE code_hash = :h", h=_code_hash(code)
+            ).first()
+            if not row:
+                raise NoSuchInvite()
+
+        self.id = row['id']
+        self._room_id = row['room']
+        self.write = bool(row['write'])
+        self.max_uses, self.uses = row['max_uses'], row['uses']
+        self.created, self.created_by = row['created'], row['created_by']
+        self.expires, self.revoked = row['expires'], row['revoked']
+        self._room = None
+
+    @staticmethod
+    def create(room, creator: User, *, write=True, max_uses=None, lifetime=None):
+        """
+        Issues a new invite code for `room`; `creator` must be a moderator of the room.  `max_uses`,
+        if given, limits how many users may redeem the code, and `lifetime`, if given, is how long
+        (in seconds) until the code expires.
+
+        Returns an (invite, code) tuple; the secret code is not stored (only a hash of it is) and so
+        can only be obtained here.
+        """
+        if not room.check_moderator(creator):
+            raise BadPermission()
+        if max_uses is not None and max_uses < 1:
+            raise InvalidData("Invite max_uses must be at least 1")
+        if lifetime is not None and lifetime <= 0:
+            raise InvalidData("Invite lifetime must be positive")
+
+        code = secrets.token_urlsafe(12)
+        invite_id = db.insert_and_get_pk(
+            """
+            INSERT INTO room_invites (room, code_hash, write, max_uses, created_by, expires)
+            VALUES (:r, :h, :write, :max_uses, :u, :expires)
+            """,
+            "id",
+            r=room.id,
+            h=_code_hash(code),
+            write=bool(write),
+            max_uses=max_uses,
+            u=creator.id,
+            expires=None if lifetime is None else time.time() + lifetime,
+        )
+        return Invite(id=invite_id), code
+
+    @property
+    def room(self):
+        """The Room that this invite grants access to."""
+        if self._room is None:
+            from .room import Room
+
+            self._room = Room(id=self._room_id)
+        return self._room
+
+    @property
+    def room_id(self):
+        """The id of the Room that this invite grants access to."""
+        return self._room_id
+
+    @property
+    def usable(self):
+        """True if the invite can still be redeemed (by a new user)."""
+        return (
+            self.revoked is None
+            and (self.expires is None or self.expires > time.time())
+            and (self.max_uses is None or self.uses < self.max_uses)
+        )
+
+    def redeem(self, user: User):
+        """
+        Redeems the invite for `user`, granting the user accessible and read (and, if the invite
+        permits it, write) permission in the invite's room.  Redeeming an invite again that the user
+        has already redeemed doesn't count as another use, and grants nothing: the permissions are
+        only granted on the first redemption, so that a moderator's later changes to the user's
+        permissions can't be undone by redeeming the invite again.
+
+        Raises NoSuchInvite if the invite has expired, been revoked, or has no uses left, and
+        BadPermission if the user is banned from the room.
+        """
+        room = self.room
+        if not room.check_unbanned(user):
+            raise BadPermission()
+        if self.revoked is not None or (self.expires is not None and self.expires <= time.time()):
+            raise NoSuchInvite(self.id)
+
+        with db.transaction():
+            new_use = query(
+                """
+                INSERT INTO room_invite_uses (invite, "user") VALUES (:i, :u)
+                ON CONFLICT DO NOTHING
+                """,
+                i=self.id,
+                u=user.id,
+            ).rowcount
+            if new_use:
+                if not query(
+                    """
+                    UPDATE room_invites SET uses = uses + 1
+                    WHERE id = :i AND (max_uses IS NULL OR uses < max_uses)
+                    """,
+                    i=self.id,
+                ).rowcount:
+                    raise NoSuchInvite(self.id)
+                self.uses += 1
+
+                perms = {'accessible': True, 'read': True}
+                if self.write:
+                    perms['write'] = True
+                room.set_permissions(user, mod=SystemUser(), **perms)
+
+    def revoke(self, user: User):
+        """
+        Revokes this invite, so that it can no longer be redeemed; `user` must be a moderator of the
+        room.  (Permissions already granted by the invite are not affected).
+        """
+        if not self.room.check_moderator(user):
+            raise BadPermission()
+        now = time.time()
+        query(
+            "UPDATE room_invites SET revoked = :now WHERE id = :id AND revoked IS NULL",
+            now=now,
+            id=self.id,
+        )
+        if self.revoked is None:
+            self.revoked = now
+
+    def redeemed_by(self):
+        """Returns a list of (session_id, timestamp) tuples of the users who redeemed the invite."""
+        return [
+            (r[0], r[1])
+            for r in query(
+                """
+                SELECT session_id, used FROM room_invite_uses JOIN users ON "user" = users.id
+                WHERE invite = :i ORDER BY used
+                """,
+                i=self.id,
+            )
+        ]
+
+    def info(self):
+        """Returns a dict of invite details suitable for returning to a room moderator."""
+        return {
+            'id': self.id,
+            'write': self.write,
+            'max_uses': self.max_uses,
+            'uses': self.uses,
+            'created': self.created,
+            'expires': self.expires,
+            'revoked': self.revoked,
+            'usable': self.usable,
+        }
+
+
+def _code_hash(code: str):
+    return blake2b(code.encode(), digest_size=32, person=b'sogs.invite')
+
+
+def get_room_invites(room):
+    """Returns a list of all invites of `room`, ordered by id."""
+    return [
+        Invite(row)
+        for row in query("SELECT * FROM room_invites WHERE room = :r ORDER BY id", r=room.id)
+    ]
@@ -1,6 +1,7 @@
 from .. import config
 from ..db import insert_and_get_row, query
 
+from .exc import PostRateLimited, QuotaExceeded
 from .user import User
 
 import time
@@ -19,6 +20,10 @@ class Message:
     def __init__(self, row=None, *, sender=None, recip=None, data=None):
         """
         Constructs a Message from a pre-retrieved row *or* sender recipient and data.
+
+        When sending a new message, raises PostRateLimited if the recipient's inbox already holds
+        the maximum number of messages, or QuotaExceeded if the message would take the recipient's
+        inbox over the maximum size (see the [direct_messages] config section).
         """
         if row is None:
             if None in (sender, recip, data):
@@ -26,6 +31,7 @@ class Message:
             if not all(isinstance(arg, User) for arg in (sender, recip)):
                 raise ValueError("Message() error: sender or recipient was not a User model")
 
+            Message._check_inbox(recip, len(data))
             row = insert_and_get_row(
                 """
                 INSERT INTO inbox (sender, recipient, body, expiry)
@@ -42,6 +48,35 @@ class Message:
         assert row is not None
         self._row = row
 
+    @staticmethod
+    def _check_inbox(recip, size):
+        """Raises if delivering a message of `size` bytes would exceed `recip`'s inbox limits"""
+        max_msgs, max_bytes = config.DM_INBOX_MAX_MESSAGES, config.DM_INBOX_MAX_BYTES
+        if max_msgs is None and max_bytes is None:
+            return
+        count, used, oldest_expiry = query(
+            """
+            SELECT COUNT(*), COALESCE(SUM(LENGTH(body)), 0), MIN(expiry) FROM inbox
+            WHERE recipient = :r AND expiry > :now
+            """,
+            r=recip.id,
+            now=time.time(),
+        ).first()
+        if max_msgs is not None and count >= max_msgs:
+            raise PostRateLimited(
+                "Recipient's inbox is full",
+                scope='inbox_messages',
+                limit=max_msgs,
+                reset=oldest_expiry,
+            )
+        if max_bytes is not None and used + size > max_bytes:
+            raise QuotaExceeded(
+                "Recipient's inbox storage limit reached",
+                quota='inbox_storage',
+                limit=max_bytes,
+                used=used,
+            )
+
     @staticmethod
     def delete_all(*, recip=None, sender=None):
         """Delete all messages sent to a user or from a user.
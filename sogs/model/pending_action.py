@@ -0,0 +1,241 @@
+from .. import config, db
+from ..db import query
+from ..web import app
+from .exc import BadPermission, InvalidData, NoSuchPendingAction, NoSuchRoom
+from .user import User
+import json
+import time
+
+
+class PendingAction:
+    """
+    Class representing a destructive admin action awaiting confirmation under the two-person rule
+    (the [admin] `two_person_rule` setting).  When the rule is enabled a request for one of the
+    covered actions doesn't execute immediately, but instead creates a pending action that is only
+    carried out once a *second* user, with the same authority as would be required to perform the
+    action, confirms it within the [admin] `confirm_window`.
+
+    The covered actions are:
+    - `delete_room` -- deleting a room, which requires a global admin;
+    - `clear_room` -- deleting all the posts of a room, which requires a global admin;
+    - `purge` -- deleting all posts by a user (from one room, or from all rooms), which requires
+      moderator permission in each of the affected rooms;
+    - `global_ban` -- banning a user from the whole server, which requires a global moderator.
+
+    Properties:
+        id - the numeric pending action id
+        action - the action name (one of the above)
+        room_id - the id of the room to be deleted or cleared, for `delete_room` and `clear_room`
+        target - the User to be purged or banned, for `purge` and `global_ban`
+        params - dict of extra action parameters: `rooms` (a list of room tokens) for `purge`, and
+            `timeout` for `global_ban`
+        requested_by - the User who requested the action
+        requested - unix timestamp when the action was requested
+        expires - unix timestamp when the action expires if not confirmed
+    """
+
+    ACTIONS = ('delete_room', 'clear_room', 'purge', 'global_ban')
+
+    def __init__(self, row=None, *, id=None):
+        """
+        Constructs a pending action from a pre-retrieved row or an id.  Raises NoSuchPendingAction
+        if there is no such (unexpired) action.
+        """
+        if sum(x is not None for x in (row, id)) != 1:
+            raise ValueError("PendingAction() error: exactly one of row/id is required")
+        if id is not None:
+            row = query(
+                "SELECT * FROM pending_actions WHERE id = :id AND expires > :now",
+                id=id,
+                now=time.time(),
+            ).first()
+            if not row:
+                raise NoSuchPendingAction(id)
+
+        (
+            self.id,
+            self.action,
+            self.room_id,
+            target,
+            params,
+            requested_by,
+            self.requested,
+            self.expires,
+        ) = (
+            row[c]
+            for c in (
+                'id',
+                'action',
+                'room',
+                'target',
+                'params',
+                'requested_by',
+                'requested',
+                'expires',
+            )
+        )
+        self.target = User(id=target) if target is not None else None
+        self.params = json.loads(params) if params else {}
+        self.requested_by = User(id=requested_by)
+
+    @staticmethod
+    def create(action: str, *, requested_by: User, room=None, target: User = None, params=None):
+        """
+        Records a pending `action` requested by `requested_by`, who must have the authority to
+        perform the action.  `room` is the Room to delete or clear (for `delete_room` and
+        `clear_room`), `target` the user to
+        purge or ban, and `params` any extra action parameters (see the class description).
+        Returns the new PendingAction.
+        """
+        if action not in PendingAction.ACTIONS:
+            raise InvalidData(f"Invalid pending action {action}")
+
+        if params is None:
+            params = {}
+        if not _permitted(action, params, requested_by):
+            app.logger.warning(f"Cannot request {action}: {requested_by} lacks permission")
+            raise BadPermission()
+
+        now = time.time()
+        with db.transaction():
+            query("DELETE FROM pending_actions WHERE expires <= :now", now=now)
+            action_id = db.insert_and_get_pk(
+                """
+                INSERT INTO pending_actions
+                    (action, room, target, params, requested_by, requested, expires)
+                VALUES (:action, :r, :target, :params, :u, :now, :expires)
+                """,
+                "id",
+                action=action,
+                r=room.id if room is not None else None,
+                target=target.id if target is not None else None,
+                params=json.dumps(params) if params else None,
+                u=requested_by.id,
+                now=now,
+                expires=now + config.TWO_PERSON_WINDOW,
+            )
+        pending = PendingAction(id=action_id)
+        app.logger.warning(f"{requested_by} requested {pending}; awaiting confirmation")
+        return pending
+
+    def __str__(self):
+        return f"PendingAction[{self.id}: {self.action}]"
+
+    @property
+    def room(self):
+        """The Room that would be deleted or cleared, for `delete_room` and `clear_room` actions"""
+        if self.room_id is None:
+            return None
+        from .room import Room
+
+        return Room(id=self.room_id)
+
+    def permitted(self, user: User):
+        """True if `user` has the authority to perform this action."""
+        return _permitted(self.action, self.params, user)
+
+    def visible_to(self, user: User):
+        """True if `user` may see (and cancel) this pending action"""
+        return user.id == self.requested_by.id or self.permitted(user)
+
+    def confirm(self, user: User):
+        """
+        Confirms and executes this pending action.  `user` must have the authority to perform the
+        action, and must not be the user who requested it.  The action is carried out on behalf of
+        the requesting user (who must also still have the authority to perform it).
+
+        Returns a dict of the action's result: for `purge` this contains `total` (the total number
+        of deleted posts) and `rooms` (a dict of room tokens to deletion counts); for `clear_room`
+        it contains `deleted` (the number of deleted posts); for the other actions it is empty.
+        """
+        if user.id == self.requested_by.id:
+            app.logger.warning(f"Cannot confirm {self}: {user} requested it")
+            raise BadPermission("Pending actions must be confirmed by a second user")
+        if not self.permitted(user):
+            app.logger.warning(f"Cannot confirm {self}: {user} lacks permission")
+            raise BadPermission()
+
+        result = {}
+        with db.transaction():
+            if query("DELETE FROM pending_actions WHERE id = :id", id=self.id).rowcount != 1:
+                raise NoSuchPendingAction(self.id)
+
+            if not self.permitted(self.requested_by):
+                raise BadPermission()
+
+            if self.action == 'delete_room':
+                room = self.room
+                room.delete()
+                app.logger.warning(f"Deleted {room} (requested by {self.requested_by})")
+            elif self.action == 'clear_room':
+                result = {'deleted': self.room.clear_posts(deleter=self.requested_by)}
+            elif self.action == 'global_ban':
+                self.target.ban(banned_by=self.requested_by, timeout=self.params.get('timeout'))
+            else:
+                from .room import Room
+
+                result = {'total': 0, 'rooms': {}}
+                for token in self.params['rooms']:
+                    count, _ = Room(token=token).delete_all_posts(
+                        self.target, deleter=self.requested_by
+                    )
+                    result['total'] += count
+                    result['rooms'][token] = count
+
+        app.logger.warning(f"{user} confirmed {self} (requested by {self.requested_by})")
+        return result
+
+    def cancel(self, user: User):
+        """Cancels this pending action; `user` must be the requester or be permitted to confirm."""
+        if not self.visible_to(user):
+            raise BadPermission()
+        query("DELETE FROM pending_actions WHERE id = :id", id=self.id)
+        app.logger.warning(f"{user} cancelled {self}")
+
+    def info(self):
+        """Returns a dict of the pending action's details."""
+        info = {
+            'id': self.id,
+            'action': self.action,
+            'requested_by': self.requested_by.session_id,
+            'requested': self.requested,
+            'expires': self.expires,
+        }
+        if self.room_id is not None:
+            info['room'] = self.room.token
+        if self.target is not None:
+            info['session_id'] = self.target.session_id
+        if self.action == 'purge':
+            info['rooms'] = self.params['rooms']
+        elif self.action == 'global_ban' and self.params.get('timeout') is not None:
+            info['timeout'] = self.params['timeout']
+        return info
+
+
+def _permitted(action, params, user: User):
+    if action in ('delete_room', 'clear_room'):
+        return user.global_admin
+    if action == 'global_ban':
+        return user.global_moderator
+    from .room import Room
+
+    try:
+        return all(Room(token=t).check_moderator(user) for t in params['rooms'])
+    except NoSuchRoom:
+        return False
+
+
+def required():
+    """True if destructive admin actions require confirmation by a second user"""
+    return config.TWO_PERSON_RULE
+
+
+def get_pending_actions(user: User):
+    """Returns a list of the unexpired pending actions that `user` may see, ordered by id."""
+    actions = [
+        PendingAction(row)
+        for row in query(
+            "SELECT * FROM pending_actions WHERE expires > :now ORDER BY id", now=time.time()
+        )
+    ]
+    return [a for a in actions if a.visible_to(user)]
@@ -0,0 +1,74 @@
+from .. import config
+from ..hashing import blake2b
+
+import os
+import time
+
+# Ephemeral, in-memory tracking of users currently polling rooms.  Nothing here is ever written to
+# the database: we only keep an opaque, salted hash of the user's session id along with the last
+# time that user polled the room.  The salt is regenerated on every startup so the keys are not
+# linkable across restarts (or to anything stored elsewhere).
+#
+# Note that this is tracked per-process: when running multiple uwsgi workers each worker only sees
+# the requests it handles itself, and so the resulting count is approximate.
+
+_salt = os.urandom(16)
+
+# room id -> { opaque user key -> last seen timestamp }
+_present = {}
+_last_prune = 0.0
+
+
+def _key(user):
+    return blake2b(user.session_id.encode(), digest_size=16, key=_salt, person=b'sogs.presence')
+
+
+def touch(room_id: int, user):
+    """
+    Records that `user` is currently polling the room with the given room id.  Stale entries (for
+    all rooms) are decayed here periodically, since presence lives in the worker process memory and
+    so can't be cleaned up by the mule's periodic cleanup job.
+    """
+    global _last_prune
+    now = time.time()
+    _present.setdefault(room_id, {})[_key(user)] = now
+    if now - _last_prune >= config.ROOM_PRESENCE_TIMEOUT:
+        _last_prune = now
+        prune()
+
+
+def prune():
+    """
+    Removes presence entries that have not been refreshed within config.ROOM_PRESENCE_TIMEOUT
+    seconds.  Returns the number of entries removed.
+    """
+    cutoff = time.time() - config.ROOM_PRESENCE_TIMEOUT
+    removed = 0
+    for room_id in list(_present.keys()):
+        seen = _present[room_id]
+        stale = [k for k, t in seen.items() if t < cutoff]
+        for k in stale:
+            del seen[k]
+        removed += len(stale)
+        if not seen:
+            del _present[room_id]
+    return removed
+
+
+def online_count(room_id: int):
+    """
+    Returns the approximate number of users who have polled the given room within the last
+    config.ROOM_PRESENCE_TIMEOUT seconds.
+    """
+    seen = _present.get(room_id)
+    if not seen:
+        return 0
+    cutoff = time.time() - config.ROOM_PRESENCE_TIMEOUT
+    return sum(t >= cutoff for t in seen.values())
+
+
+def clear():
+    """Drops all presence information."""
+    global _last_prune
+    _present.clear()
+    _last_prune = 0.0
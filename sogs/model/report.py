@@ -0,0 +1,242 @@
+from .. import db
+from ..db import query
+from .exc import BadPermission, InvalidData, NoSuchPost, NoSuchReport, ReportClaimed
+from .user import User
+from typing import Optional
+import time
+
+# Message reports: room users can report messages to the room's moderators.  The open reports of a
+# room form its moderation queue, in which all the open reports of a message are handled together:
+# a moderator claims them (or assigns them to another moderator), so that two moderators don't end
+# up handling the same reports, and then resolves them as `actioned` or `dismissed`.
+#
+# The queue is ordered by priority: the sum, over the message's open reports, of each reporter's
+# trust score.  A reporter's trust is (1 + A) / (2 + R), where R is the number of the reporter's
+# earlier reports (in any room) that have been resolved and A the number of those that moderators
+# acted on: a new reporter's report counts for 0.5, while reporters whose reports are usually acted
+# on approach 1, and those whose reports are usually dismissed approach 0.
+
+MAX_REASON_LENGTH = 500
+
+RESOLUTIONS = ('actioned', 'dismissed')
+
+
+def file_report(room, user: User, msg_id: int, reason: Optional[str] = None):
+    """
+    Reports message `msg_id` of `room` to the room's moderators on behalf of `user`, who must be
+    able to see the message.  Returns True if the report was filed, False if the user already has
+    an open report of the message.  A new report of a message whose reports are already claimed
+    joins the claim (as of the time the report is filed).  Whitespace in the reason is collapsed,
+    so that otherwise identical reasons are merged in the queue.
+
+    Raises NoSuchPost if the message doesn't exist or isn't visible to the user, and InvalidData if
+    the reason is too long.
+    """
+    if not room.check_read(user):
+        raise BadPermission()
+    if reason is not None:
+        reason = ' '.join(reason.split()) or None
+    if reason is not None and len(reason) > MAX_REASON_LENGTH:
+        raise InvalidData(f"Report reason exceeds {MAX_REASON_LENGTH} characters")
+    if not room.get_messages_for(user, single=msg_id, reactions=False):
+        raise NoSuchPost(msg_id)
+
+    with db.transaction():
+        if query(
+            """
+            SELECT 1 FROM message_reports
+            WHERE message = :m AND reporter = :u AND resolved IS NULL
+            """,
+            m=msg_id,
+            u=user.id,
+        ).first():
+            return False
+        claim = query(
+            """
+            SELECT claimed_by FROM message_reports
+            WHERE message = :m AND resolved IS NULL LIMIT 1
+            """,
+            m=msg_id,
+        ).first()
+        claimed_by = claim[0] if claim else None
+        now = time.time()
+        query(
+            """
+            INSERT INTO message_reports
+                (room, message, reporter, reason, reported, claimed_by, claimed)
+            VALUES (:r, :m, :u, :reason, :now, :claimed_by, :claimed)
+            """,
+            r=room.id,
+            m=msg_id,
+            u=user.id,
+            reason=reason,
+            now=now,
+            claimed_by=claimed_by,
+            # Not the claim's own time: the report can't have been claimed before it was filed.
+            claimed=now if claimed_by is not None else None,
+        )
+    return True
+
+
+def report_queue(room):
+    """
+    Returns the moderation queue of `room`: a list with a dict for each message with open reports,
+    highest priority first (and then oldest first), containing keys:
+
+    - `id` -- the message id.
+    - `reports` -- the number of open reports of the message.
+    - `priority` -- the priority score of the message (see above).
+    - `first_reported`, `last_reported` -- unix timestamps of the first and latest open reports.
+    - `reasons` -- the reasons given by the reporters (that gave one), in the order first given:
+      a list of dicts with keys `reason` and `count`, the number of reporters who gave the reason,
+      so that a pile-on of identical reports shows up as a single reason.
+    - `claimed_by` -- the session id of the moderator who claimed the reports, or None.
+    - `claimed` -- unix timestamp when the reports were claimed, or None.
+    """
+    queue = [
+        {
+            'id': row['message'],
+            'reports': row['reports'],
+            'priority': row['priority'],
+            'first_reported': row['first_reported'],
+            'last_reported': row['last_reported'],
+            'reasons': [],
+            'claimed_by': row['claimed_by'],
+            'claimed': row['claimed'],
+        }
+        for row in query(
+            """
+            SELECT r.message, COUNT(*) AS reports,
+                SUM(CAST(1 + trust.actioned AS FLOAT) / (2 + trust.resolved)) AS priority,
+                MIN(r.reported) AS first_reported, MAX(r.reported) AS last_reported,
+                MAX(users.session_id) AS claimed_by, MAX(r.claimed) AS claimed
+            FROM message_reports r
+                JOIN (
+                    SELECT reporter,
+                        SUM(CASE WHEN resolution = 'actioned' THEN 1 ELSE 0 END) AS actioned,
+                        COUNT(resolved) AS resolved
+                    FROM message_reports
+                    WHERE reporter IN (
+                        SELECT reporter FROM message_reports WHERE room = :r AND resolved IS NULL
+                    )
+                    GROUP BY reporter
+                ) trust ON trust.reporter = r.reporter
+                LEFT JOIN users ON users.id = r.claimed_by
+            WHERE r.room = :r AND r.resolved IS NULL
+            GROUP BY r.message
+            ORDER BY priority DESC, first_reported
+            """,
+            r=room.id,
+        )
+    ]
+    by_id = {q['id']: q for q in queue}
+    for msg_id, reason, count in query(
+        """
+        SELECT message, reason, COUNT(*) FROM message_reports
+        WHERE room = :r AND resolved IS NULL AND reason IS NOT NULL
+        GROUP BY message, reason
+        ORDER BY MIN(reported)
+        """,
+        r=room.id,
+    ):
+        by_id[msg_id]['reasons'].append({'reason': reason, 'count': count})
+    return queue
+
+
+def _claimed_by(room, msg_id: int):
+    """
+    Returns the (user id, session id) of the moderator who claimed the open reports of message
+    `msg_id`, or (None, None) if unclaimed.  Raises NoSuchReport if the message has no open reports.
+    """
+    row = query(
+        """
+        SELECT r.claimed_by, users.session_id
+        FROM message_reports r LEFT JOIN users ON users.id = r.claimed_by
+        WHERE r.room = :r AND r.message = :m AND r.resolved IS NULL LIMIT 1
+        """,
+        r=room.id,
+        m=msg_id,
+    ).first()
+    if row is None:
+        raise NoSuchReport(msg_id)
+    return row[0], row[1]
+
+
+def claim_reports(room, mod: User, msg_id: int, assignee: Optional[User] = None):
+    """
+    Claims the open reports of message `msg_id` for `mod`, or assigns them to `assignee` (who must
+    also be a moderator of the room).  Reports claimed by another moderator can only be claimed or
+    reassigned by a room admin.
+
+    Raises BadPermission if `mod` is not a moderator, InvalidData if `assignee` is not a moderator,
+    NoSuchReport if the message has no open reports, and ReportClaimed if the reports are claimed by
+    another moderator.
+    """
+    if not room.check_moderator(mod):
+        raise BadPermission()
+    if assignee is None:
+        assignee = mod
+    elif assignee.id != mod.id and not room.check_moderator(assignee):
+        raise InvalidData("Reports can only be assigned to a room moderator")
+
+    if not query(
+        """
+        UPDATE message_reports SET claimed_by = :a, claimed = :now
+        WHERE room = :r AND message = :m AND resolved IS NULL
+            AND (claimed_by IS NULL OR claimed_by = :mod OR :override)
+        """,
+        a=assignee.id,
+        now=time.time(),
+        r=room.id,
+        m=msg_id,
+        mod=mod.id,
+        override=room.check_admin(mod),
+    ).rowcount:
+        raise ReportClaimed(_claimed_by(room, msg_id)[1])
+
+
+def release_reports(room, mod: User, msg_id: int):
+    """
+    Releases the claim on the open reports of message `msg_id`, returning them to the unclaimed
+    queue.  Only the moderator holding the claim, or a room admin, may release it.
+    """
+    if not room.check_moderator(mod):
+        raise BadPermission()
+    claimer, claimer_sid = _claimed_by(room, msg_id)
+    if claimer is not None and claimer != mod.id and not room.check_admin(mod):
+        raise ReportClaimed(claimer_sid)
+    query(
+        """
+        UPDATE message_reports SET claimed_by = NULL, claimed = NULL
+        WHERE room = :r AND message = :m AND resolved IS NULL
+        """,
+        r=room.id,
+        m=msg_id,
+    )
+
+
+def resolve_reports(room, mod: User, msg_id: int, resolution: str):
+    """
+    Resolves the open reports of message `msg_id` as `actioned` or `dismissed`.  Unclaimed reports
+    may be resolved by any moderator; claimed reports only by the moderator holding the claim, or
+    by a room admin.  Returns the number of reports resolved.
+    """
+    if not room.check_moderator(mod):
+        raise BadPermission()
+    if resolution not in RESOLUTIONS:
+        raise InvalidData(f"Invalid report resolution {resolution}")
+    with db.transaction():
+        claimer, claimer_sid = _claimed_by(room, msg_id)
+        if claimer is not None and claimer != mod.id and not room.check_admin(mod):
+            raise ReportClaimed(claimer_sid)
+        return query(
+            """
+            UPDATE message_reports SET resolved = :now, resolved_by = :mod, resolution = :res
+            WHERE room = :r AND message = :m AND resolved IS NULL
+            """,
+            now=time.time(),
+            mod=mod.id,
+            res=resolution,
+            r=room.id,
+            m=msg_id,
+        ).rowcount
@@ -1,29 +1,58 @@
-from .. import config, crypto, db, utils, session_pb2 as protobuf
+from .. import (
+    backfill,
+    challenge,
+    compress,
+    config,
+    crypto,
+    db,
+    journal,
+    markup,
+    normalize,
+    phash,
+    roomcache,
+    sigcache,
+    storage,
+    tracing,
+    translate,
+    utils,
+    session_pb2 as protobuf,
+)
 from ..db import query
 from ..hashing import blake2b
 from ..omq import send_mule
 from ..web import app
-from .user import User
+from .user import User, SystemUser
 from .file import File
 from .post import Post
-from nacl.signing import SigningKey
+from . import presence
+from nacl.signing import SigningKey, VerifyKey
+import nacl.exceptions
 from .exc import (
     NoSuchRoom,
     NoSuchFile,
     NoSuchPost,
     AlreadyExists,
     BadPermission,
+    AccountTooNew,
+    ChallengeRequired,
     PostRejected,
     PostRateLimited,
+    PostNotStored,
+    UploadRejected,
+    QuotaExceeded,
     InvalidData,
+    RoomLimitReached,
+    PinLimitReached,
 )
 
+import calendar
+import json
 import os
 import random
 import re
 import sqlalchemy.exc
 import time
-from typing import Optional, Union, List
+from typing import Optional, Union, List, Tuple
 
 
 # TODO: These really should be room properties, not random global constants (these
@@ -31,6 +60,67 @@ from typing import Optional, Union, List
 rate_limit_size = 5
 rate_limit_interval = 16.0
 
+# Cache of the moderator roles used by Room.author_roles: (room id, room creation time, includes
+# hidden mods) => (info_updates, roles).  The room's moderator list (including global moderators)
+# only changes along with its `info_updates` counter, so a cached map is good until that changes.
+_role_cache = {}
+
+# Matches text that looks like a link, for enforcing link policies and rejecting links from
+# non-moderators in raid mode.  The `host` group captures the link's domain (which is empty for
+# links such as `file:///...`), and the `www` and `bare` groups the domains of links without a
+# scheme, such as `www.example.com`, `example.com/x`, or just `example.com` (but not the domains
+# of email addresses).
+link_pattern = re.compile(
+    r'(?i)\b(?:[a-z][a-z0-9+.-]*://(?:[^\s/@]*@)?(?P<host>[^\s/:?#]*)'
+    r'|(?P<www>www\.[a-z0-9-]+(?:\.[a-z0-9-]+)*)'
+    r'|(?<![@.-])(?P<bare>[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,63})(?![\w-]|\.[a-z0-9]))'
+)
+
+
+def link_domains(text: str):
+    """Returns the (lower-cased) domains of all links found in `text`, in order."""
+    return [
+        (m.group('host') or m.group('www') or m.group('bare') or '').lower().rstrip('.')
+        for m in link_pattern.finditer(text)
+    ]
+
+
+def _domain_listed(domain: str, domains):
+    """True if `domain` is, or is a subdomain of, one of `domains`"""
+    return any(domain == d or domain.endswith('.' + d) for d in domains)
+
+
+def transfer_message(token: str, nonce: bytes):
+    """
+    Returns the message that the recipient of a room ownership transfer must sign (with the Ed25519
+    key of their session id) to accept the transfer; see Room.accept_transfer.
+    """
+    return b'sogs-room-transfer' + crypto.server_pubkey_bytes + token.encode() + nonce
+
+
+def _pubkey_matches(pubkey: bytes, session_id: str):
+    """True if the Ed25519 `pubkey` is the key of (blinded or unblinded) `session_id`"""
+    try:
+        if session_id.startswith('15'):
+            return pubkey.hex() == session_id[2:]
+        if session_id.startswith('05'):
+            return VerifyKey(pubkey).to_curve25519_public_key().encode().hex() == session_id[2:]
+    except Exception:
+        pass
+    return False
+
+
+# Message kinds that a poster may declare for their message; server-generated room events have kind
+# `system`.
+post_kinds = ('text', 'image', 'bot')
+
+# Room tags (e.g. languages and topics): lower-case letters, digits, `_` and `-`
+tag_pattern = re.compile(r'^[a-z0-9][a-z0-9_-]{0,31}$')
+max_room_tags = 16
+
+# Supported bucket sizes (in seconds) for Room.message_counts
+message_count_granularity = {'hour': 3600, 'day': 86400, 'week': 7 * 86400}
+
 
 # Character ranges for different filters.  This is ordered because some are subsets of each other
 # (e.g. persian is a subset of the arabic character range).
@@ -48,6 +138,45 @@ alphabet_filter_patterns = [
 filter_privkeys = {}
 
 
+def _whisper_clause(user, mod, *, table=''):
+    """
+    Returns the SQL clause (starting with AND) restricting messages to those visible to `user`
+    (whose id must be bound as `:user`); `mod` is whether the user is a moderator of the room.
+    """
+    t = table
+    return (
+        # For a mod we want to see:
+        # - all whisper_mods messsages
+        # - anything directed to us specifically
+        # - anything we sent (i.e. outbound whispers)
+        # - non-whispers
+        f'AND ({t}whisper_mods OR {t}whisper = :user OR {t}"user" = :user OR {t}whisper IS NULL)'
+        if mod
+        # For a regular user we want to see:
+        # - anything with whisper_to sent to us
+        # - non-whispers
+        else f"AND ({t}whisper = :user OR ({t}whisper IS NULL AND NOT {t}whisper_mods))"
+        if user
+        # Otherwise for public, non-user access we want to see:
+        # - non-whispers
+        else f"AND {t}whisper IS NULL AND NOT {t}whisper_mods"
+    )
+
+
+def _whisper_visible(row, user, mod):
+    """The equivalent of `_whisper_clause` for an already fetched message row."""
+    if mod:
+        return (
+            row['whisper_mods']
+            or row['whisper'] is None
+            or row['whisper'] == user.id
+            or row['user'] == user.id
+        )
+    if user and row['whisper'] == user.id:
+        return True
+    return row['whisper'] is None and not row['whisper_mods']
+
+
 class Room:
     """
     Class representing a room stored in the database.
@@ -67,6 +196,8 @@ class Room:
             metadata (name, description, image, etc.) changes for the room.
         active_users - count of the number of active users in the past
             config.ROOM_DEFAULT_ACTIVE_THRESHOLD seconds.
+        online_users - approximate count of users currently polling the room (i.e. within the past
+            config.ROOM_PRESENCE_TIMEOUT seconds).  This is tracked in memory only.
         default_read - True if default user permissions includes read permission
         default_accessible - True if default user permissions include accessible permission
         default_write - True if default user permissions includes write permission
@@ -115,6 +246,10 @@ class Room:
             self.message_sequence,
             self.info_updates,
             self.active_users,
+            self._raid_mode_until,
+            self._rules,
+            self._owner_id,
+            self._archived,
         ) = (
             row[c]
             for c in (
@@ -127,11 +262,16 @@ class Room:
                 'message_sequence',
                 'info_updates',
                 'active_users',
+                'raid_mode_until',
+                'rules',
+                'owner',
+                'archived',
             )
         )
         self._default_read, self._default_accessible, self._default_write, self._default_upload = (
             bool(row[c]) for c in ('read', 'accessible', 'write', 'upload')
         )
+        self._sensitive = bool(row['sensitive'])
 
         if (
             hasattr(self, '_image')
@@ -143,6 +283,7 @@ class Room:
             self._image = None  # Retrieved on demand
 
         self._pinned = None  # Re-retrieved on demand
+        self._tags = None  # Re-retrieved on demand
 
         if perms or not hasattr(self, '_perm_cache'):
             self._perm_cache = {}
@@ -152,14 +293,26 @@ class Room:
         return f"Room[{self.token}]"
 
     @staticmethod
-    def create(token: str, name: str, description: Optional[str] = None):
+    def create(
+        token: str, name: str, description: Optional[str] = None, *, ignore_limits: bool = False
+    ):
         """
         Constructs a new room given the token, name, and (optional) description.  Returns a full Row
         object built from the constructed row.
 
+        Raises RoomLimitReached if the server already has the [rooms] `max_rooms` number of rooms,
+        unless `ignore_limits` is given.
+
         (This static method does not authenticate).
         """
 
+        if config.ROOM_MAX_COUNT and not ignore_limits:
+            count = query("SELECT COUNT(*) FROM rooms").first()[0]
+            if count >= config.ROOM_MAX_COUNT:
+                raise RoomLimitReached(
+                    f"The server already has the maximum number of rooms ({count})"
+                )
+
         try:
             room_id = db.insert_and_get_pk(
                 "INSERT INTO rooms(token, name, description) VALUES(:t, :n, :d)",
@@ -248,11 +401,13 @@ class Room:
 
     @name.setter
     def name(self, name: str):
-        """Sets the room's human-readable name."""
+        """Sets the room's human-readable name (normalized, see sogs.normalize)."""
+        name = normalize.normalize(name)
         if name != self._name:
             with db.transaction():
                 query("UPDATE rooms SET name = :n WHERE id = :r", r=self.id, n=name)
                 self._refresh()
+            self.add_system_message('room_renamed', name=name)
 
     @property
     def description(self):
@@ -261,11 +416,82 @@ class Room:
 
     @description.setter
     def description(self, desc):
-        """Sets the room's human-readable description."""
+        """Sets the room's human-readable description (normalized, see sogs.normalize)."""
+        desc = normalize.normalize(desc)
         if desc != self._description:
             with db.transaction():
                 query("UPDATE rooms SET description = :d WHERE id = :r", r=self.id, d=desc)
                 self._refresh()
+            self.add_system_message('settings_changed', description=desc)
+
+    @property
+    def description_html(self):
+        """The room's description, rendered from markdown to safe HTML, or None if not set."""
+        return markup.render(self._description)
+
+    @property
+    def rules(self):
+        """Accesses the room's rules (as markdown), or None if the room has no rules."""
+        return self._rules
+
+    @rules.setter
+    def rules(self, rules):
+        """Sets the room's rules; control characters other than tabs and newlines are removed."""
+        rules = markup.sanitize(rules)
+        if rules != self._rules:
+            with db.transaction():
+                query(
+                    """
+                    UPDATE rooms SET rules = :rules, info_updates = info_updates + 1
+                    WHERE id = :r
+                    """,
+                    r=self.id,
+                    rules=rules,
+                )
+                self._refresh()
+            self.add_system_message('settings_changed', rules=rules)
+
+    @property
+    def tags(self):
+        """
+        Accesses the room's list of tags (such as languages or topics, e.g. `de` or `gaming`), in
+        sorted order; this is fetched from the database the first time this is accessed.
+        """
+        if self._tags is None:
+            self._tags = roomcache.room_info(
+                self,
+                'tags',
+                lambda: [
+                    r[0]
+                    for r in query(
+                        "SELECT tag FROM room_tags WHERE room = :r ORDER BY tag", r=self.id
+                    )
+                ],
+            )
+        return self._tags
+
+    @tags.setter
+    def tags(self, tags: List[str]):
+        """
+        Replaces the room's tags.  Tags are converted to lower case, and must consist of letters,
+        digits, `_` and `-` (and at most 32 characters); raises InvalidData if any tag is invalid,
+        or if given more than 16 tags.
+        """
+        tags = sorted({t.lower() for t in tags})
+        if len(tags) > max_room_tags or not all(tag_pattern.match(t) for t in tags):
+            raise InvalidData()
+        if tags != self.tags:
+            with db.transaction():
+                query("DELETE FROM room_tags WHERE room = :r", r=self.id)
+                for t in tags:
+                    query("INSERT INTO room_tags (room, tag) VALUES (:r, :t)", r=self.id, t=t)
+                query("UPDATE rooms SET info_updates = info_updates + 1 WHERE id = :r", r=self.id)
+                self._refresh()
+
+    @property
+    def rules_html(self):
+        """The room's rules, rendered from markdown to safe HTML, or None if not set."""
+        return markup.render(self._rules)
 
     @property
     def image_id(self):
@@ -317,6 +543,7 @@ class Room:
             self._fetch_image_id, self._image = None, file
 
             self._refresh()
+        self.add_system_message('settings_changed', image=file.id)
 
     @property
     def pinned_messages(self):
@@ -324,23 +551,27 @@ class Room:
         Accesses the list of pinned messages for this room; this is fetched from the database the
         first time this is accessed.  Each element is a dict such as:
         {"id": 1234, "pinned_at": 1642701309.5007384, "pinned_by": "05123456....."}
-        The returned list should not be modified; instead call pin() or unpin() to add/remove pinned
-        messages.
+        in display order (see reorder_pins()).  The returned list should not be modified; instead
+        call pin() or unpin() to add/remove pinned messages.
         """
 
         if self._pinned is None:
-            self._pinned = [
-                {'id': r[0], 'pinned_at': r[1], 'pinned_by': r[2]}
-                for r in query(
-                    """
-                    SELECT message, pinned_at, users.session_id
-                    FROM pinned_messages JOIN users ON pinned_by = users.id
-                    WHERE room = :r
-                    ORDER BY pinned_at
-                    """,
-                    r=self.id,
-                )
-            ]
+            self._pinned = roomcache.room_info(
+                self,
+                'pinned',
+                lambda: [
+                    {'id': r[0], 'pinned_at': r[1], 'pinned_by': r[2]}
+                    for r in query(
+                        """
+                        SELECT message, pinned_at, users.session_id
+                        FROM pinned_messages JOIN users ON pinned_by = users.id
+                        WHERE room = :r
+                        ORDER BY position, pinned_at
+                        """,
+                        r=self.id,
+                    )
+                ],
+            )
 
         return self._pinned
 
@@ -377,6 +608,7 @@ class Room:
             with db.transaction():
                 query("UPDATE rooms SET read = :read WHERE id = :r", r=self.id, read=read)
                 self._refresh(perms=True)
+            self.add_system_message('settings_changed', read=read)
 
     @default_accessible.setter
     def default_accessible(self, accessible: bool):
@@ -390,6 +622,7 @@ class Room:
                     accessible=accessible,
                 )
                 self._refresh(perms=True)
+            self.add_system_message('settings_changed', accessible=accessible)
 
     @default_write.setter
     def default_write(self, write: bool):
@@ -399,6 +632,7 @@ class Room:
             with db.transaction():
                 query("UPDATE rooms SET write = :write WHERE id = :r", r=self.id, write=write)
                 self._refresh(perms=True)
+            self.add_system_message('settings_changed', write=write)
 
     @default_upload.setter
     def default_upload(self, upload: bool):
@@ -408,6 +642,65 @@ class Room:
             with db.transaction():
                 query("UPDATE rooms SET upload = :upload WHERE id = :r", r=self.id, upload=upload)
                 self._refresh(perms=True)
+            self.add_system_message('settings_changed', upload=upload)
+
+    @property
+    def sensitive(self):
+        """
+        Returns True if this room is flagged as containing sensitive content.  Users other than
+        moderators can only read or post in a sensitive room after acknowledging the flag (see
+        `acknowledge`).
+        """
+        return self._sensitive
+
+    @sensitive.setter
+    def sensitive(self, sensitive: bool):
+        """Sets or clears the room's sensitive content flag"""
+
+        if sensitive != self._sensitive:
+            with db.transaction():
+                query(
+                    """
+                    UPDATE rooms SET sensitive = :sensitive, info_updates = info_updates + 1
+                    WHERE id = :r
+                    """,
+                    r=self.id,
+                    sensitive=sensitive,
+                )
+                self._refresh(perms=True)
+            self.add_system_message('settings_changed', sensitive=sensitive)
+
+    def acknowledged(self, user: Optional[User]):
+        """Returns True if `user` has acknowledged the room's sensitive content flag."""
+        if user is None:
+            return False
+        return (
+            query(
+                'SELECT COUNT(*) FROM room_acknowledgments WHERE room = :r AND "user" = :u',
+                r=self.id,
+                u=user.id,
+            ).first()[0]
+            > 0
+        )
+
+    def acknowledge(self, user: User):
+        """
+        Records that `user` has acknowledged the room's sensitive content flag, granting the user
+        access to the room as if the room were not flagged.  The user must have accessible
+        permission in the room.  Acknowledgments are kept if the flag is later cleared, and so
+        apply again if the room is flagged again.
+        """
+        if not self.check_accessible(user):
+            raise BadPermission()
+        query(
+            """
+            INSERT INTO room_acknowledgments (room, "user") VALUES (:r, :u)
+            ON CONFLICT DO NOTHING
+            """,
+            r=self.id,
+            u=user.id,
+        )
+        self._perm_cache.pop(user.id, None)
 
     def active_users_last(self, cutoff: float):
         """
@@ -426,6 +719,54 @@ class Room:
             since=time.time() - cutoff,
         ).first()[0]
 
+    @property
+    def online_users(self):
+        """
+        Approximate number of users currently polling the room.  Unlike `active_users` this is never
+        stored in the database and decays after config.ROOM_PRESENCE_TIMEOUT seconds of inactivity.
+        """
+        return presence.online_count(self.id)
+
+    def message_counts(
+        self,
+        granularity: str = 'day',
+        *,
+        since: Optional[float] = None,
+        until: Optional[float] = None,
+    ):
+        """
+        Returns the number of (non-deleted, non-filtered, non-whisper) messages posted to the room
+        in each time bucket of the given granularity (one of the `message_count_granularity` keys),
+        optionally limited to messages posted at or after `since` and before `until`.  Buckets are
+        aligned to the unix epoch (so daily buckets are UTC days).
+
+        Returns a list of `(bucket_start, count)` tuples, in ascending time order, containing only
+        non-empty buckets.
+        """
+        size = message_count_granularity[granularity]
+        bucket = db.floor_div('posted', ':size')
+        range_clause = ''
+        if since is not None:
+            range_clause += ' AND posted >= :since'
+        if until is not None:
+            range_clause += ' AND posted < :until'
+        return [
+            (int(b) * size, count)
+            for b, count in query(
+                f"""
+                SELECT {bucket} AS bucket, COUNT(*) FROM messages
+                WHERE room = :r{range_clause}
+                    AND data IS NOT NULL AND NOT filtered AND whisper IS NULL AND NOT whisper_mods
+                GROUP BY bucket
+                ORDER BY bucket
+                """,
+                r=self.id,
+                size=size,
+                since=since,
+                until=until,
+            )
+        ]
+
     def check_permission(
         self,
         user: Optional[User] = None,
@@ -454,7 +795,8 @@ class Room:
           accessible).
         - write -- if true then the user must have write access
         - upload -- if true then the user must have upload access; this should usually be combined
-          with write=True.
+          with write=True.  Uploads are not permitted for non-moderators while the room is in raid
+          mode.
 
         You can specify multiple permissions as True, in which case all must be satisfied.  If you
         specify no permissions as required then the check only checks whether a user is banned but
@@ -471,6 +813,7 @@ class Room:
                 False,
                 False,
             )
+            acknowledged = False
         else:
             if user.id not in self._perm_cache:
                 row = query(
@@ -482,7 +825,9 @@ class Room:
                     r=self.id,
                     u=user.id,
                 ).first()
-                self._perm_cache[user.id] = [bool(c) for c in row]
+                self._perm_cache[user.id] = [bool(c) for c in row] + [
+                    self.sensitive and self.acknowledged(user)
+                ]
 
             (
                 is_banned,
@@ -492,16 +837,23 @@ class Room:
                 can_upload,
                 is_mod,
                 is_admin,
+                acknowledged,
             ) = self._perm_cache[user.id]
 
         if is_admin:
             return True
         if admin:
             return False
+        if (write or upload) and self.archived is not None:
+            return False
         if is_mod:
             return True
         if moderator:
             return False
+        if upload and self.raid_mode_until is not None:
+            return False
+        if (read or write or upload) and self.sensitive and not acknowledged:
+            return False
         return (
             not is_banned
             and (not accessible or can_access or can_read)
@@ -534,6 +886,363 @@ class Room:
     def check_admin(self, user: Optional[User]):
         return self.check_permission(user, admin=True)
 
+    @property
+    def min_account_age(self):
+        """
+        The minimum time, in seconds, that a user must have been known to the server (i.e. since the
+        user was first seen) before they can post or upload in this room, or None if there is no
+        minimum.  This is the room's [room:TOKEN] `min_account_age` config setting, if set,
+        otherwise the server-wide [rooms] `min_account_age` setting; while the room is in raid mode
+        it is at least the [rooms] `raid_mode_min_account_age` setting.
+        """
+        age = config.ROOM_OVERRIDES.get(self.token, {}).get(
+            'min_account_age', config.ROOM_MIN_ACCOUNT_AGE
+        )
+        if self.raid_mode_until is not None:
+            age = max(age or 0, config.RAID_MODE_MIN_ACCOUNT_AGE)
+        return age or None
+
+    def check_account_age(self, user: User):
+        """
+        Raises AccountTooNew if the room has a minimum account age that `user` does not yet meet.
+        Moderators, bridge users, and users who have been explicitly granted write permission in the
+        room (such as webhook bots) are exempt.
+        """
+        min_age = self.min_account_age
+        if min_age is None or user.created + min_age <= time.time():
+            return
+        if self.check_moderator(user) or user.is_bridge:
+            return
+        if query(
+            """
+            SELECT COUNT(*) FROM user_permission_overrides
+            WHERE room = :r AND "user" = :u AND write
+            """,
+            r=self.id,
+            u=user.id,
+        ).first()[0]:
+            return
+        raise AccountTooNew(user.created + min_age)
+
+    @property
+    def max_pins(self):
+        """
+        The maximum number of messages that can be pinned in this room at once, or None if
+        unlimited.  This is the room's [room:TOKEN] `max_pins` config setting, if set, otherwise the
+        server-wide [rooms] setting.
+        """
+        return (
+            config.ROOM_OVERRIDES.get(self.token, {}).get('max_pins', config.ROOM_MAX_PINS) or None
+        )
+
+    @property
+    def first_post_pow(self):
+        """
+        The difficulty, in bits, of the proof-of-work challenge required with a user's first post to
+        this room (see sogs.challenge), or 0 if none is required.  This is the room's [room:TOKEN]
+        `first_post_pow` config setting, if set, otherwise the server-wide [rooms] setting.
+        """
+        return config.ROOM_OVERRIDES.get(self.token, {}).get(
+            'first_post_pow', config.ROOM_FIRST_POST_POW
+        )
+
+    def pow_required(self, user: User, poster: Optional[User] = None):
+        """
+        Returns the difficulty of the proof-of-work challenge that `user` must solve to post to this
+        room, or 0 if `user` doesn't need to solve one: because the room doesn't require it, because
+        `user` is exempt (as for check_account_age), or because `poster` (the user posting, which is
+        a pseudonym of `user` in anonymous rooms; defaults to `user`) has posted to the room before.
+        """
+        difficulty = self.first_post_pow
+        if not difficulty or self.check_moderator(user) or user.is_bridge:
+            return 0
+        if query(
+            """
+            SELECT COUNT(*) FROM user_permission_overrides
+            WHERE room = :r AND "user" = :u AND write
+            """,
+            r=self.id,
+            u=user.id,
+        ).first()[0]:
+            return 0
+        posted = query(
+            'SELECT id FROM messages WHERE room = :r AND "user" = :u LIMIT 1',
+            r=self.id,
+            u=(poster or user).id,
+        ).first()
+        return 0 if posted is not None else difficulty
+
+    def issue_pow_challenge(self, user: User):
+        """
+        Returns a dict of a new proof-of-work challenge for `user`'s first post to this room, with
+        keys `challenge` (bytes), `difficulty`, and `expires`; for a user that doesn't need to solve
+        one (see pow_required), the dict contains just `difficulty` set to 0.
+        """
+        difficulty = self.pow_required(user)
+        if not difficulty:
+            return {'difficulty': 0}
+        token, expiry = challenge.issue(self.token, user.session_id)
+        return {'challenge': token, 'difficulty': difficulty, 'expires': expiry}
+
+    @property
+    def raid_mode_until(self):
+        """
+        The unix timestamp at which the room's current raid mode ends, or None if the room is not in
+        raid mode.  See `start_raid_mode`.
+        """
+        until = self._raid_mode_until
+        return until if until is not None and until > time.time() else None
+
+    def start_raid_mode(self, mod: User, duration: Optional[float] = None):
+        """
+        Puts the room into raid mode for `duration` seconds (default: the [rooms]
+        `raid_mode_duration` setting), replacing the end time of any raid mode already in effect.
+        `mod` must be a moderator of the room.  While in raid mode, for users other than moderators:
+
+        - posting is limited to one post per [rooms] `raid_mode_slow` seconds;
+        - users must meet the [rooms] `raid_mode_min_account_age` (see `min_account_age`);
+        - file uploads are not permitted;
+        - messages containing links are rejected.
+
+        Raid mode ends automatically once the duration has passed.
+        """
+        if not self.check_moderator(mod):
+            app.logger.warning(f"Unable to start raid mode in {self}: {mod} is not a moderator")
+            raise BadPermission()
+        if duration is None:
+            duration = config.RAID_MODE_DURATION
+
+        until = time.time() + duration
+        query(
+            """
+            UPDATE rooms SET raid_mode_until = :until, info_updates = info_updates + 1
+            WHERE id = :r
+            """,
+            r=self.id,
+            until=until,
+        )
+        app.logger.warning(f"{mod} started raid mode in {self} for {duration:.0f} seconds")
+        journal.record('raid_mode_started', room=self.token, until=until, by=mod.session_id)
+        self._refresh(perms=True)
+
+    def end_raid_mode(self, mod: User):
+        """Ends raid mode in the room early, if active.  `mod` must be a moderator of the room."""
+        if not self.check_moderator(mod):
+            app.logger.warning(f"Unable to end raid mode in {self}: {mod} is not a moderator")
+            raise BadPermission()
+        if self.raid_mode_until is None:
+            return
+
+        query(
+            """
+            UPDATE rooms SET raid_mode_until = NULL, info_updates = info_updates + 1
+            WHERE id = :r
+            """,
+            r=self.id,
+        )
+        app.logger.warning(f"{mod} ended raid mode in {self}")
+        journal.record('raid_mode_ended', room=self.token, by=mod.session_id)
+        self._refresh(perms=True)
+
+    @property
+    def archived(self):
+        """
+        The unix timestamp at which the room was archived, or None if the room is not archived.
+        Archived rooms are read-only: nobody other than room admins can post, upload, or react.
+        """
+        return self._archived
+
+    def archive(self, admin: Optional[User] = None):
+        """
+        Archives the room, making it read-only (see `archived`).  `admin`, if given, must be an
+        admin of the room; if omitted, the room is being archived by the inactive room policy (see
+        sogs.archive).  Does nothing if the room is already archived.
+        """
+        if admin is not None and not self.check_admin(admin):
+            app.logger.warning(f"Unable to archive {self}: {admin} is not a room admin")
+            raise BadPermission()
+        if self.archived is not None:
+            return
+
+        query(
+            """
+            UPDATE rooms SET archived = :now, info_updates = info_updates + 1
+            WHERE id = :r AND archived IS NULL
+            """,
+            r=self.id,
+            now=time.time(),
+        )
+        by = admin.session_id if admin is not None else None
+        app.logger.warning(f"{admin or 'Inactive room policy'} archived {self}")
+        journal.record('room_archived', room=self.token, by=by)
+        self._refresh(perms=True)
+
+    def unarchive(self, admin: User):
+        """
+        Unarchives the room, making it writable again.  `admin` must be an admin of the room.  The
+        inactive room policy treats unarchiving as room activity, so the room will not be archived
+        again until it has been inactive for the configured time.  Does nothing if the room is not
+        archived.
+        """
+        if not self.check_admin(admin):
+            app.logger.warning(f"Unable to unarchive {self}: {admin} is not a room admin")
+            raise BadPermission()
+        if self.archived is None:
+            return
+
+        query(
+            """
+            UPDATE rooms SET archived = NULL, unarchived = :now, info_updates = info_updates + 1
+            WHERE id = :r
+            """,
+            r=self.id,
+            now=time.time(),
+        )
+        app.logger.warning(f"{admin} unarchived {self}")
+        journal.record('room_unarchived', room=self.token, by=admin.session_id)
+        self._refresh(perms=True)
+
+    @property
+    def link_policy(self):
+        """
+        Returns a tuple of the room's link policy (one of `allow`, `allowlist`, `denylist`, or
+        `none`) and the set of domains the `allowlist`/`denylist` policy applies to.
+        """
+        overrides = config.ROOM_OVERRIDES.get(self.token, {})
+        return (
+            overrides.get('link_policy', config.LINK_POLICY),
+            overrides.get('link_domains', config.LINK_DOMAINS),
+        )
+
+    @property
+    def system_messages(self):
+        """
+        True if room events (bans, moderator deletions, setting changes, etc.) are added to this
+        room's message stream as `system` messages; this is the room's [room:TOKEN]
+        `system_messages` setting, if set, otherwise [rooms].system_messages.
+        """
+        return bool(
+            config.ROOM_OVERRIDES.get(self.token, {}).get(
+                'system_messages', config.ROOM_SYSTEM_MESSAGES
+            )
+        )
+
+    @property
+    def role_badges(self):
+        """
+        True if retrieved messages of this room include the author's `role`; this is the room's
+        [room:TOKEN] `role_badges` setting, if set, otherwise [rooms].role_badges.
+        """
+        return bool(
+            config.ROOM_OVERRIDES.get(self.token, {}).get('role_badges', config.ROOM_ROLE_BADGES)
+        )
+
+    @property
+    def public_api(self):
+        """
+        True if this room is available through the public archive API (via `public_api = yes` in the
+        room's [room:TOKEN] config section) and is publicly readable (and not sensitive, as it could
+        then only be read after acknowledging that).
+        """
+        return (
+            self.default_read
+            and not self.sensitive
+            and bool(config.ROOM_OVERRIDES.get(self.token, {}).get('public_api'))
+        )
+
+    @property
+    def anonymous(self):
+        """
+        True if posts to this room by non-moderators are made under a pseudonym (see
+        [rooms].anonymous_window); this is the room's [room:TOKEN] `anonymous` setting.
+        """
+        return bool(config.ROOM_OVERRIDES.get(self.token, {}).get('anonymous', False))
+
+    def author_roles(self, user: Optional[User] = None):
+        """
+        Returns a dict of {session_id: role} of the room's admins, moderators (including global
+        ones), and bridge bots, with role one of `admin`, `moderator`, or `bot`; authors not in the
+        dict are `regular`.  Hidden admins and moderators are only included if `user` is a
+        moderator (as in get_mods).
+        """
+        key = (self.id, self.created, self.check_moderator(user))
+        cached = _role_cache.get(key)
+        if cached is not None and cached[0] == self.info_updates:
+            mod_roles = cached[1]
+        else:
+            m, a, hm, ha = self.get_mods(user)
+            mod_roles = {sid: 'moderator' for sid in m + hm}
+            mod_roles.update((sid, 'admin') for sid in a + ha)
+            _role_cache[key] = (self.info_updates, mod_roles)
+
+        roles = {sid: 'bot' for sid in config.BRIDGE_IDS}
+        roles.update(mod_roles)
+        return roles
+
+    def _check_links(self, user: User, data: bytes):
+        """
+        Raises PostRejected if a non-moderator's message contains links not permitted by the room's
+        link policy, or contains any link while the room is in raid mode.
+        """
+        policy, domains = self.link_policy
+        raid = self.raid_mode_until is not None
+        if (policy == 'allow' and not raid) or self.check_moderator(user):
+            return
+        try:
+            text = Post(raw=data).text
+        except Exception:
+            return
+        links = link_domains(text)
+        if not links:
+            return
+        if raid:
+            raise PostRejected("links are not permitted while the room is in raid mode")
+        if policy == 'none':
+            raise PostRejected("links are not permitted in this room")
+        for domain in links:
+            if (policy == 'allowlist') != _domain_listed(domain, domains):
+                raise PostRejected(
+                    f"links to {domain or 'this location'} are not permitted in this room"
+                )
+
+    def _check_signature(self, user: User, data: bytes, sig: bytes):
+        """
+        If [messages].verify_signatures is enabled, raises InvalidData unless `sig` is a valid
+        signature of `data` by `user`'s key: the Ed25519 key of a blinded id, or the XEd25519
+        converted key of an unblinded id.
+        """
+        if not config.MESSAGE_VERIFY_SIGNATURES:
+            return
+        try:
+            key = bytes.fromhex(user.session_id[2:])
+            if user.session_id.startswith('05'):
+                key = crypto.xed25519_pubkey(key)
+            sigcache.verify(key, data, sig)
+        except Exception:
+            app.logger.warning(f"Rejecting message to {self} from {user}: invalid signature")
+            raise InvalidData("Invalid message signature")
+
+    def _check_text(self, user: User, data: bytes):
+        """
+        Raises PostRejected if a non-moderator's message body or display name exceeds the configured
+        [messages] length limits, or if the display name contains invisible or control characters
+        and [messages].strict_names is enabled (see sogs.normalize).
+        """
+        limit, name_limit = config.MESSAGE_MAX_GRAPHEMES, config.DISPLAY_NAME_MAX_GRAPHEMES
+        if not (limit or name_limit or config.DISPLAY_NAME_STRICT) or self.check_moderator(user):
+            return
+        try:
+            post = Post(raw=data)
+        except Exception:
+            return
+        if limit and normalize.graphemes(post.text) > limit:
+            raise PostRejected(f"message is too long (maximum {limit} characters)")
+        name = post.username or ''
+        if name_limit and normalize.graphemes(name) > name_limit:
+            raise PostRejected(f"display name is too long (maximum {name_limit} characters)")
+        if config.DISPLAY_NAME_STRICT and normalize.suspicious(name):
+            raise PostRejected("display name contains invalid characters")
+
     def messages_size(self):
         """Returns the number and total size (in bytes) of non-deleted messages currently stored in
         this room.  Size is reflects the size of uploaded message bodies, not necessarily the size
@@ -557,12 +1266,16 @@ class Room:
         sequence: Optional[int] = None,
         after: Optional[int] = None,
         before: Optional[int] = None,
+        posted_before: Optional[float] = None,
+        around: Optional[int] = None,
         recent: bool = False,
         single: Optional[int] = None,
         limit: int = 256,
         reactions: bool = True,
         reaction_updates: bool = True,
         reactor_limit: int = 0,
+        system: bool = True,
+        inline_files: bool = False,
     ):
         """
         Returns up to `limit` message updates that `user` should see:
@@ -573,13 +1286,18 @@ class Room:
         - whispers directed to moderators (only applicable if the user is a moderator)
         - reaction updates (if `reaction_updates` is true, and using `sequence`)
 
-        Exactly one of `sequence`, `after`, `before`, `recent` or `single` must be specified:
+        Exactly one of `sequence`, `after`, `before`, `posted_before`, `around`, `recent` or
+        `single` must be specified:
         - `sequence=N` returns updates made since the given `seqno` (that is: the have seqno greater
           than N).  Messages and reactions are returned in sequence order.
         - `after=N` returns messages with ids greater than N in ascending order.  This is normally
           *not* what you want for fetching messages as it omits edits and deletions; typically you
           want to retrieve by seqno instead.
         - `before=N` returns messages with ids less than N in descending order
+        - `posted_before=T` returns messages posted before unix timestamp T in descending order
+        - `around=N` returns the message with id N (if it exists) along with the messages
+          immediately preceding and following it, in descending order.  Roughly half of `limit` is
+          used for the following messages, and the rest for the message itself and preceding ones.
         - `recent=True` returns the most recent messages in descending order
         - `single=123` returns a singleton list containing the single message with the given message
           id, or an empty list if the message doesn't exist or isn't readable by the user.
@@ -599,6 +1317,16 @@ class Room:
         - `reactor_limit` controls how many of the reactor list to return with reaction info; this
           is passed through to `get_reactions`.  This field has no effect when `reactions` is false.
 
+        - `system` controls whether `system` room event messages are included.  Defaults to `True`.
+
+        - `inline_files` adds an `inline_files` key to messages with attached files no larger than
+          [files].inline_max_size: a list of dicts with the `id` and the content (in `data`) of each
+          such file.  Inlined files count as downloads towards the room's egress.  Defaults to
+          `False`.
+
+        If the room has role badges enabled then each message also has a `role` key with the
+        author's current role (see `author_roles`).
+
         Note that data and signature are returned as bytes, *not* base64 encoded.  Session message
         padding *is* appended to the data field (i.e. this returns the full value, not the
         padding-trimmed value actually stored in the database).
@@ -606,15 +1334,20 @@ class Room:
 
         mod = self.check_moderator(user)
         msgs = []
+        roles = self.author_roles(user) if self.role_badges else None
 
-        opt_count = sum(arg is not None for arg in (sequence, after, before, single)) + bool(recent)
+        opt_count = sum(
+            arg is not None for arg in (sequence, after, before, posted_before, around, single)
+        ) + bool(recent)
         if opt_count == 0:
             raise RuntimeError(
-                "Exactly one of sequence=, before=, after=, recent=, or single= is required"
+                "Exactly one of sequence=, before=, after=, posted_before=, around=, recent=, or "
+                "single= is required"
             )
         if opt_count > 1:
             raise RuntimeError(
-                "Cannot specify more than one of sequence=, before=, after=, recent=, single="
+                "Cannot specify more than one of sequence=, before=, after=, posted_before=, "
+                "around=, recent=, single="
             )
 
         if sequence is None:
@@ -623,6 +1356,19 @@ class Room:
         if limit <= 0:
             limit = 256
 
+        # Make sure any history we are about to read has been imported, if this room is still being
+        # backfilled from an old database import:
+        if backfill.pending(self.id):
+            if single is not None or around is not None:
+                backfill.ensure(self.id, single=single or around, limit=0)
+            if single is None and sequence is None and after is None:
+                backfill.ensure(
+                    self.id,
+                    before=before if around is None else around,
+                    posted_before=posted_before,
+                    limit=limit,
+                )
+
         # Handle id mapping from an old database import in case the client is requesting
         # messages since some id from the old db.
         if after is not None and db.ROOM_IMPORT_HACKS and self.id in db.ROOM_IMPORT_HACKS:
@@ -648,28 +1394,14 @@ class Room:
             if after is not None
             else 'AND id < :before'
             if before is not None
+            else 'AND posted < :posted_before'
+            if posted_before is not None
             else 'AND id = :single'
             if single is not None
             else ''
         )
 
-        whisper_clause = (
-            # For a mod we want to see:
-            # - all whisper_mods messsages
-            # - anything directed to us specifically
-            # - anything we sent (i.e. outbound whispers)
-            # - non-whispers
-            'AND (whisper_mods OR whisper = :user OR "user" = :user OR whisper IS NULL)'
-            if mod
-            # For a regular user we want to see:
-            # - anything with whisper_to sent to us
-            # - non-whispers
-            else "AND (whisper = :user OR (whisper IS NULL AND NOT whisper_mods))"
-            if user
-            # Otherwise for public, non-user access we want to see:
-            # - non-whispers
-            else "AND whisper IS NULL AND NOT whisper_mods"
-        )
+        whisper_clause = _whisper_clause(user, mod)
 
         order_limit = (
             'ORDER BY seqno ASC LIMIT :limit'
@@ -678,25 +1410,49 @@ class Room:
             if single is not None
             else 'ORDER BY id ASC LIMIT :limit'
             if after is not None
+            else 'ORDER BY posted DESC, id DESC LIMIT :limit'
+            if posted_before is not None
             else 'ORDER BY id DESC LIMIT :limit'
         )
-        for row in query(
-            f"""
+
+        visible = f"""
             SELECT * FROM message_details
             WHERE room = :r AND NOT filtered
+                {'' if system else "AND kind != 'system'"}
                 {not_deleted_clause}
                 {message_clause}
                 {whisper_clause}
-            {order_limit}
-            """,
-            r=self.id,
-            sequence=sequence,
-            after=after,
-            before=before,
-            single=single,
-            user=user.id if user else None,
-            limit=limit,
-        ):
+            """
+        if around is not None:
+            # Combine the messages up to and including the target with the ones following it:
+            sql = f"""
+                SELECT * FROM ({visible} AND id <= :around ORDER BY id DESC LIMIT :limit) older
+                UNION ALL
+                SELECT * FROM ({visible} AND id > :around ORDER BY id ASC LIMIT :limit_after) newer
+                ORDER BY id DESC
+                """
+        else:
+            sql = visible + order_limit
+
+        rows = None
+        if recent and limit <= config.ROOM_MESSAGE_CACHE:
+            rows = self._cached_recent(user, mod, system, limit)
+        if rows is None:
+            rows = query(
+                sql,
+                r=self.id,
+                sequence=sequence,
+                after=after,
+                before=before,
+                posted_before=posted_before,
+                around=around,
+                single=single,
+                user=user.id if user else None,
+                limit=limit - limit // 2 if around is not None else limit,
+                limit_after=limit // 2,
+            )
+
+        for row in rows:
             if sequence and row['seqno_reactions'] > sequence >= row['seqno_data']:
                 # This is a reaction-only update, so we only want to include the reaction info
                 # (added later) but not the full details.
@@ -704,15 +1460,21 @@ class Room:
                 continue
 
             msg = {x: row[x] for x in ('id', 'session_id', 'posted', 'seqno')}
+            if roles is not None:
+                msg['role'] = roles.get(row['session_id'], 'regular')
             data = row['data']
             if data is None:
                 msg['data'] = None
                 msg['deleted'] = True
             else:
-                msg['data'] = utils.add_session_message_padding(data, row['data_size'])
+                msg['data'] = utils.add_session_message_padding(
+                    compress.decode(data, row['data_dict']), row['data_size']
+                )
                 msg['signature'] = row['signature']
             if row['edited'] is not None:
                 msg['edited'] = row['edited']
+            if row['kind'] != 'text':
+                msg['kind'] = row['kind']
             if row['whisper_to'] is not None or row['whisper_mods']:
                 msg['whisper'] = True
                 msg['whisper_mods'] = row['whisper_mods']
@@ -733,8 +1495,158 @@ class Room:
                 if 'data' not in msg or msg['data'] is not None:
                     msg['reactions'] = reacts.get(msg['id'], {})
 
+        if inline_files and config.FILE_INLINE_MAX_SIZE:
+            self._inline_files([m for m in msgs if m.get('data') is not None])
+
         return msgs
 
+    def _cached_recent(self, user, mod, system, limit):
+        """
+        Returns the `limit` most recent message rows visible to `user` from the room's cache of
+        recent messages (see sogs.roomcache), or None if they aren't all in the cache.  Cached rows
+        are the same as message_details rows, except that `data` is already decompressed.
+        """
+
+        def fetch(n):
+            rows = []
+            for row in query(
+                """
+                SELECT * FROM message_details
+                WHERE room = :r AND NOT filtered AND data IS NOT NULL
+                ORDER BY id DESC LIMIT :limit
+                """,
+                r=self.id,
+                limit=n,
+            ):
+                row = {k: row[k] for k in row.keys()}
+                row['data'] = compress.decode(row['data'], row['data_dict'])
+                row['data_dict'] = None
+                rows.append(row)
+            return rows
+
+        cached = roomcache.recent_messages(self, fetch)
+        rows = [
+            r
+            for r in cached
+            if (system or r['kind'] != 'system') and _whisper_visible(r, user, mod)
+        ]
+        if len(rows) < limit and len(cached) >= config.ROOM_MESSAGE_CACHE:
+            # There are (or might be) older messages that aren't cached
+            return None
+        return rows[:limit]
+
+    def _inline_files(self, msgs):
+        """
+        Adds the content of the small (up to [files].inline_max_size) unquarantined attachments of
+        the given messages to them as `inline_files`, unless the room's egress cap has been reached.
+        """
+        if not msgs or self.egress_exceeded():
+            return
+        by_id = {m['id']: m for m in msgs}
+        for row in query(
+            """
+            SELECT * FROM files
+            WHERE room = :r AND message IN :ids AND size <= :max AND NOT quarantined
+            ORDER BY id
+            """,
+            r=self.id,
+            ids=list(by_id),
+            max=config.FILE_INLINE_MAX_SIZE,
+            bind_expanding=['ids'],
+        ):
+            f = File(row)
+            try:
+                data = f.read()
+            except FileNotFoundError:
+                app.logger.error(f"File {f.id} content is missing from storage")
+                continue
+            self.record_egress(f)
+            by_id[row['message']].setdefault('inline_files', []).append({'id': f.id, 'data': data})
+
+    def lookup_messages(self, user: Optional[User], signatures: List[bytes]):
+        """
+        Looks up messages of the room, visible to `user`, by the signatures of their current or
+        earlier (i.e. edited or deleted, for as long as the edit history is kept) versions.
+
+        Returns a dict of {signature: {'id': msg_id, 'status': status}} containing the given
+        signatures that were found, where status is `exists` if the signature is of the message's
+        current version, `edited` if of an earlier version, or `deleted` if the message has since
+        been deleted.
+        """
+        mod = self.check_moderator(user)
+        whisper_clause = _whisper_clause(user, mod, table='m.')
+        params = {
+            'r': self.id,
+            'sigs': [bytes(s) for s in signatures],
+            'user': user.id if user else None,
+            'bind_expanding': ['sigs'],
+        }
+
+        found = {}
+        for msg_id, sig in query(
+            f"""
+            SELECT m.id, m.signature FROM messages m
+            WHERE m.room = :r AND m.signature IN :sigs AND NOT m.filtered {whisper_clause}
+            """,
+            **params,
+        ):
+            found[bytes(sig)] = {'id': msg_id, 'status': 'exists'}
+
+        for msg_id, sig, deleted in query(
+            f"""
+            SELECT m.id, h.signature, m.data IS NULL
+            FROM message_history h JOIN messages m ON h.message = m.id
+            WHERE m.room = :r AND h.signature IN :sigs AND NOT m.filtered {whisper_clause}
+            """,
+            **params,
+        ):
+            found.setdefault(
+                bytes(sig), {'id': msg_id, 'status': 'deleted' if deleted else 'edited'}
+            )
+
+        return found
+
+    def message_history(self, user: User, msg_id: int):
+        """
+        Returns the earlier versions of a message, i.e. the data and signature replaced by each edit
+        (and by deletion) of the message, for as long as the edit history is kept.  Only the author
+        of the message or a moderator of the room may retrieve the history.
+
+        Returns a list of dicts with keys `replaced` (the unix timestamp when the version was
+        replaced), `data`, and `signature`, oldest first.
+
+        Raises:
+        - NoSuchPost() if the message does not exist in this room.
+        - BadPermission() if the user is neither the author nor a moderator.
+        """
+        author = query(
+            'SELECT "user" FROM messages WHERE id = :m AND room = :r', m=msg_id, r=self.id
+        ).first()
+        if author is None:
+            raise NoSuchPost()
+        if author[0] != user.id and not self.check_moderator(user):
+            raise BadPermission()
+
+        # As with current messages, the stored data has its padding stripped, but the signature is
+        # of the padded data.  (History recorded before sizes were kept has no size, and so can't be
+        # re-padded.)
+        return [
+            {
+                'replaced': replaced,
+                'data': utils.add_session_message_padding(
+                    compress.decode(data, data_dict), data_size or 0
+                ),
+                'signature': sig,
+            }
+            for replaced, data, sig, data_dict, data_size in query(
+                """
+                SELECT replaced, data, signature, data_dict, data_size FROM message_history
+                WHERE message = :m ORDER BY replaced
+                """,
+                m=msg_id,
+            )
+        ]
+
     def filtering(self):
         settings = {
             'profanity_filter': config.PROFANITY_FILTER,
@@ -753,6 +1665,29 @@ class Room:
                     settings[k] = config.ROOM_OVERRIDES[self.token][k]
         return settings
 
+    @property
+    def has_feed(self):
+        """
+        True if this room is configured as a public announcement channel with an Atom feed (via
+        `feed = yes` in the room's [room:TOKEN] config section) and is publicly readable (and not
+        sensitive, as it could then only be read after acknowledging that).
+        """
+        return (
+            self.default_read
+            and not self.sensitive
+            and bool(config.ROOM_OVERRIDES.get(self.token, {}).get('feed'))
+        )
+
+    @property
+    def preview_mask(self):
+        """
+        True if profanity should be masked in the public web and feed previews of this room; this is
+        the room's [room:TOKEN] `preview_mask` setting, if set, otherwise [messages].preview_mask.
+        """
+        return bool(
+            config.ROOM_OVERRIDES.get(self.token, {}).get('preview_mask', config.PREVIEW_MASK)
+        )
+
     def filter_should_reply(self, filter_type, filter_lang):
         """If the settings say we should reply to a filter, this returns a tuple of
 
@@ -801,7 +1736,8 @@ class Room:
     def should_filter(self, user: User, data: bytes):
         """
         Checks a message for disallowed alphabets and profanity (if the profanity
-        filter is enabled).
+        filter is enabled).  The filters are applied to the folded text (see sogs.normalize.fold),
+        so that they can't be evaded with invisible characters or lookalike characters.
 
         - Returns None if this message passes (i.e. didn't trigger any filter, or is
           being posted by an admin to whom the filters don't apply).
@@ -832,7 +1768,7 @@ class Room:
             if lang not in alphabets:
                 continue
 
-            if not pattern.search(msg().text):
+            if not pattern.search(normalize.fold(msg().text)):
                 continue
 
             # Filter it!
@@ -843,7 +1779,7 @@ class Room:
             import better_profanity
 
             for part in (msg().text, msg().username):
-                if better_profanity.profanity.contains_profanity(part):
+                if better_profanity.profanity.contains_profanity(normalize.fold(part or '')):
                     filter_type = 'profanity'
                     break
 
@@ -891,9 +1827,9 @@ class Room:
                 query(
                     """
                     INSERT INTO messages
-                        (room, "user", data, data_size, signature, whisper)
+                        (room, "user", data, data_size, signature, whisper, kind)
                         VALUES
-                        (:r, :u, :data, :data_size, :signature, :whisper)
+                        (:r, :u, :data, :data_size, :signature, :whisper, 'bot')
                     """,
                     r=self.id,
                     u=server_fake_user.id,
@@ -958,21 +1894,62 @@ class Room:
         whisper_to: Optional[Union[User, str]] = None,
         whisper_mods: bool = False,
         files: List[int] = [],
+        kind: str = 'text',
+        idempotency_key: Optional[str] = None,
+        pow_solution: Optional[Tuple[bytes, bytes]] = None,
+        bridged: Optional[dict] = None,
     ):
         """
         Adds a post to the room.  The user must have write permissions.
 
-        Raises BadPermission() if the user doesn't have posting permission; PostRejected() if the
+        `kind` is the kind of message declared by the poster: one of `text` (the default), `image`
+        (which requires at least one attached file), or `bot`.
+
+        `idempotency_key` is an optional client-chosen string (of up to 64 characters) identifying
+        the post: if the user has already made a post to the room with the same key (e.g. because
+        the client re-sent the post after a timeout) then nothing is posted and the details of the
+        existing message are returned instead.  If that message has since been deleted then
+        AlreadyExists is raised instead, with the deleted message's id as its value.
+
+        `pow_solution` is a `(challenge, solution)` tuple of a solved challenge issued by
+        issue_pow_challenge, which is required for the user's first post if the room requires one.
+
+        `bridged` is the external attribution (`network`, `external_id`, and optionally
+        `external_user` and `display_name`) of a post relayed by a bridge, which is recorded in the
+        same transaction as the post; see `add_bridged_post`.
+
+        In an anonymous room, posts by non-moderators are stored under a pseudonym of the user (see
+        `crypto.anonymous_signkey`) and re-signed with the pseudonym's key, and the real author is
+        stored sealed in `anonymous_posts`.
+
+        Raises BadPermission() if the user doesn't have posting permission (or subclass
+        AccountTooNew() if the user is too new to post in the room, or ChallengeRequired() if the
+        post requires a proof-of-work solution that was missing or invalid); PostRejected() if the
         post was rejected (such as subclass PostRateLimited() if the post was rejected for too
-        frequent posting).
+        frequent posting); PostNotStored() if the database failed to store (or commit) the post, in
+        which case nothing was stored and the post can be retried.
 
         Returns the message details.
         """
         if not self.check_write(user):
             raise BadPermission()
+        self.check_account_age(user)
 
         if data is None or sig is None or len(sig) != 64:
             raise InvalidData()
+        self._check_signature(user, data, sig)
+
+        if kind not in post_kinds or (kind == 'image' and not files):
+            app.logger.warning(f"Cannot post to {self}: invalid message kind {kind}")
+            raise InvalidData()
+
+        if idempotency_key is not None and not (
+            isinstance(idempotency_key, str) and 0 < len(idempotency_key) <= 64
+        ):
+            raise InvalidData("Invalid idempotency key")
+
+        self._check_links(user, data)
+        self._check_text(user, data)
 
         whisper_mods = bool(whisper_mods)
         if (whisper_to or whisper_mods) and not self.check_moderator(user):
@@ -984,75 +1961,360 @@ class Room:
 
         filtered = self.should_filter(user, data)
 
-        with db.transaction():
-            if rate_limit_size and not self.check_admin(user):
-                since_limit = time.time() - rate_limit_interval
-                recent_count = query(
+        poster = user
+        anonymous = self.anonymous and not self.check_moderator(user)
+        if anonymous:
+            key = crypto.anonymous_signkey(
+                self.token, user.session_id, int(time.time() // config.ROOM_ANONYMOUS_WINDOW)
+            )
+            poster = User(
+                session_id='15' + key.verify_key.encode().hex(), autovivify=True, touch=False
+            )
+            # A pseudonym banned by a moderator stays banned for the rest of its window:
+            if not self.check_unbanned(poster):
+                raise BadPermission()
+            sig = key.sign(data).signature
+
+        difficulty = self.pow_required(user, poster)
+        if difficulty and not (
+            pow_solution
+            and challenge.verify(self.token, user.session_id, *pow_solution, difficulty)
+        ):
+            app.logger.warning(f"Rejecting first post by {user} to {self}: no valid proof-of-work")
+            raise ChallengeRequired(difficulty)
+
+        try:
+            with db.transaction():
+                if idempotency_key is not None:
+                    existing = self._idempotent_post(user, poster, idempotency_key)
+                    if existing is not None:
+                        return existing
+
+                if rate_limit_size and not self.check_admin(user) and not user.is_bridge:
+                    since_limit = time.time() - rate_limit_interval
+                    recent_count, oldest = query(
+                        """
+                        SELECT COUNT(*), MIN(posted) FROM messages
+                        WHERE room = :r AND "user" = :u AND posted >= :since
+                        """,
+                        r=self.id,
+                        u=poster.id,
+                        since=since_limit,
+                    ).first()
+
+                    if recent_count >= rate_limit_size:
+                        raise PostRateLimited(
+                            limit=rate_limit_size, reset=oldest + rate_limit_interval
+                        )
+
+                if (
+                    self.raid_mode_until is not None
+                    and config.RAID_MODE_SLOW
+                    and not self.check_moderator(user)
+                    and not user.is_bridge
+                ):
+                    # Raid mode slow mode: one post per RAID_MODE_SLOW seconds
+                    last = query(
+                        'SELECT MAX(posted) FROM messages WHERE room = :r AND "user" = :u',
+                        r=self.id,
+                        u=poster.id,
+                    ).first()[0]
+                    if last is not None and last + config.RAID_MODE_SLOW > time.time():
+                        raise PostRateLimited(limit=1, reset=last + config.RAID_MODE_SLOW)
+
+                data_size = len(data)
+                stored_data, data_dict = compress.encode(
+                    self.id, utils.remove_session_message_padding(data)
+                )
+
+                msg_id = db.insert_and_get_pk(
                     """
-                    SELECT COUNT(*) FROM messages
-                    WHERE room = :r AND "user" = :u AND posted >= :since
+                    INSERT INTO messages
+                        (room, "user", data, data_size, signature, filtered, whisper, whisper_mods,
+                        kind, data_dict)
+                        VALUES
+                        (:r, :u, :data, :data_size, :signature, :filtered, :whisper, :whisper_mods,
+                        :kind, :data_dict)
                     """,
+                    "id",
                     r=self.id,
-                    u=user.id,
-                    since=since_limit,
-                ).first()[0]
+                    u=poster.id,
+                    data=stored_data,
+                    data_size=data_size,
+                    signature=sig,
+                    filtered=filtered is not None,
+                    whisper=whisper_to.id if whisper_to else None,
+                    whisper_mods=whisper_mods,
+                    kind=kind,
+                    data_dict=data_dict,
+                )
 
-                if recent_count >= rate_limit_size:
-                    raise PostRateLimited()
+                if files:
+                    # Take ownership of any uploaded files attached to the post:
+                    self._own_files(msg_id, files, user)
 
-            data_size = len(data)
-            unpadded_data = utils.remove_session_message_padding(data)
+                if idempotency_key is not None:
+                    query(
+                        """
+                        INSERT INTO message_idempotency (message, room, "user", idempotency_key)
+                        VALUES (:m, :r, :u, :k)
+                        """,
+                        m=msg_id,
+                        r=self.id,
+                        u=poster.id,
+                        k=idempotency_key,
+                    )
+
+                if anonymous:
+                    query(
+                        "INSERT INTO anonymous_posts (message, author) VALUES (:m, :a)",
+                        m=msg_id,
+                        a=crypto.seal_author(user.session_id),
+                    )
 
+                if bridged is not None:
+                    query(
+                        """
+                        INSERT INTO bridged_messages
+                            (message, network, external_id, external_user, display_name)
+                            VALUES (:m, :n, :e, :u, :d)
+                        """,
+                        m=msg_id,
+                        n=bridged['network'],
+                        e=bridged['external_id'],
+                        u=bridged.get('external_user'),
+                        d=bridged.get('display_name'),
+                    )
+
+                assert msg_id is not None
+                row = query("SELECT posted, seqno FROM messages WHERE id = :m", m=msg_id).first()
+                msg = {
+                    'id': msg_id,
+                    'session_id': poster.session_id,
+                    'posted': row[0],
+                    'seqno': row[1],
+                    'data': data,
+                    'signature': sig,
+                    'reactions': {},
+                }
+                if kind != 'text':
+                    msg['kind'] = kind
+                if filtered is not None:
+                    msg['filtered'] = True
+                if whisper_to or whisper_mods:
+                    msg['whisper'] = True
+                    msg['whisper_mods'] = whisper_mods
+                    if whisper_to:
+                        msg['whisper_to'] = whisper_to.session_id
+        except sqlalchemy.exc.IntegrityError:
+            # A concurrent request with the same idempotency key beat us to it:
+            existing = idempotency_key and self._idempotent_post(user, poster, idempotency_key)
+            if existing is None:
+                raise
+            return existing
+        except sqlalchemy.exc.OperationalError as e:
+            # The insert (or its commit) failed, so the message wasn't stored and its id (if we got
+            # one) doesn't refer to anything: the client can safely re-send the same post.
+            app.logger.warning(f"Failed to store post by {user} in {self}: {e}")
+            raise PostNotStored() from e
+
+        # Don't call this inside the transaction because, if it's inserting a reply, we want the
+        # reply to have a later timestamp for proper ordering (because the timestamp inside a
+        # transaction is the time when the transaction started).
+        if filtered is not None:
+            filtered()
+
+        send_mule("message_posted", msg['id'])
+        journal.record(
+            'message_posted', room=self.token, **{k: v for k, v in msg.items() if k != 'reactions'}
+        )
+        return msg
+
+    def _idempotent_post(self, user: User, poster: User, key: str):
+        """
+        Returns the message details (as seen by `user`) of the existing post by `poster` (which
+        differs from `user` for anonymous posts) with idempotency key `key`, or None if there is no
+        such post.  Raises AlreadyExists if there was such a post, but it has since been deleted.
+        """
+        row = query(
+            """
+            SELECT message FROM message_idempotency
+            WHERE room = :r AND "user" = :u AND idempotency_key = :k
+            """,
+            r=self.id,
+            u=poster.id,
+            k=key,
+        ).first()
+        if row is None:
+            return None
+        app.logger.debug(f"Not re-posting duplicate post {row[0]} by {user} to {self}")
+        msgs = self.get_messages_for(user, single=row[0])
+        if not msgs:
+            raise AlreadyExists(f"Post with idempotency key {key} was deleted", Post, row[0])
+        return msgs[0]
+
+    def anonymous_author(self, msg_id: int, admin: User):
+        """
+        Returns the real session id of the author of the anonymous post `msg_id` of this room, or
+        None if the message doesn't exist in this room or wasn't posted anonymously.  `admin` must
+        be an admin of the room; lookups are logged and recorded in the journal.
+        """
+        if not self.check_admin(admin):
+            app.logger.warning(f"Unable to look up author of {self} post {msg_id}: not an admin")
+            raise BadPermission()
+
+        row = query(
+            """
+            SELECT author FROM anonymous_posts JOIN messages ON messages.id = message
+            WHERE message = :m AND room = :r AND data IS NOT NULL
+            """,
+            m=msg_id,
+            r=self.id,
+        ).first()
+        if row is None:
+            return None
+
+        author = crypto.unseal_author(row[0])
+        app.logger.warning(f"{admin} looked up the author of anonymous post {msg_id} in {self}")
+        journal.record(
+            'anonymous_author_revealed', room=self.token, id=msg_id, by=admin.session_id
+        )
+        return author
+
+    def add_system_message(self, event: str, **fields):
+        """
+        Adds a `system` message describing a room event (e.g. `user_banned`) to the room's message
+        stream, if system messages are enabled for the room.  The message data is a JSON object of
+        the `event` name and the given `fields`, posted by the server's system user and signed by
+        the server key.  If an administrator is acting on the server's behalf (see
+        `journal.acting_as`) it also includes their identity as `actor`.
+
+        Returns the new message id, or None if system messages are not enabled.
+        """
+        if not self.system_messages:
+            return None
+
+        actor = journal.current_actor()
+        if actor is not None and 'actor' not in fields:
+            fields['actor'] = actor
+        data = json.dumps({'event': event, **fields}, separators=(',', ':')).encode()
+        sig = crypto.server_signkey.sign(data).signature
+        sysuser = SystemUser()
+        with db.transaction():
             msg_id = db.insert_and_get_pk(
                 """
-                INSERT INTO messages
-                    (room, "user", data, data_size, signature, filtered, whisper, whisper_mods)
-                    VALUES
-                    (:r, :u, :data, :data_size, :signature, :filtered, :whisper, :whisper_mods)
+                INSERT INTO messages (room, "user", data, data_size, signature, kind)
+                VALUES (:r, :u, :data, :data_size, :signature, 'system')
                 """,
                 "id",
                 r=self.id,
-                u=user.id,
-                data=unpadded_data,
-                data_size=data_size,
+                u=sysuser.id,
+                data=data,
+                data_size=len(data),
                 signature=sig,
-                filtered=filtered is not None,
-                whisper=whisper_to.id if whisper_to else None,
-                whisper_mods=whisper_mods,
             )
+            posted, seqno = query(
+                "SELECT posted, seqno FROM messages WHERE id = :m", m=msg_id
+            ).first()
 
-            if files:
-                # Take ownership of any uploaded files attached to the post:
-                self._own_files(msg_id, files, user)
+        send_mule("message_posted", msg_id)
+        journal.record(
+            'message_posted',
+            room=self.token,
+            id=msg_id,
+            session_id=sysuser.session_id,
+            posted=posted,
+            seqno=seqno,
+            data=data,
+            signature=sig,
+            kind='system',
+        )
+        return msg_id
 
-            assert msg_id is not None
-            row = query("SELECT posted, seqno FROM messages WHERE id = :m", m=msg_id).first()
-            msg = {
-                'id': msg_id,
-                'session_id': user.session_id,
-                'posted': row[0],
-                'seqno': row[1],
-                'data': data,
-                'signature': sig,
-                'reactions': {},
-            }
-            if filtered is not None:
-                msg['filtered'] = True
-            if whisper_to or whisper_mods:
-                msg['whisper'] = True
-                msg['whisper_mods'] = whisper_mods
-                if whisper_to:
-                    msg['whisper_to'] = whisper_to.session_id
+    def add_bridged_post(
+        self,
+        bridge: User,
+        data: bytes,
+        sig: bytes,
+        *,
+        network: str,
+        external_id: str,
+        external_user: Optional[str] = None,
+        display_name: Optional[str] = None,
+        files: List[int] = [],
+    ):
+        """
+        Adds a post made by a user of an external network (e.g. Matrix), relayed by the bridge bot
+        user `bridge`.  The post itself is posted and signed by the bridge, but the external
+        attribution is recorded and returned in the `bridged` key of the bridge event stream so that
+        the bridge can recognize its own messages and map them back to the external message ids.
+        The post and its attribution are added in a single transaction (by add_post, so that the
+        post's notifications are only sent once it is committed), and so a failure to record the
+        attribution doesn't leave an unattributed post behind.
+
+        Raises BadPermission if `bridge` is not a configured bridge user, AlreadyExists if a message
+        with the given network/external_id has already been posted, plus anything add_post raises.
+        """
+        if not bridge.is_bridge:
+            app.logger.warning(f"Cannot post bridged message to {self}: {bridge} is not a bridge")
+            raise BadPermission()
 
-        # Don't call this inside the transaction because, if it's inserting a reply, we want the
-        # reply to have a later timestamp for proper ordering (because the timestamp inside a
-        # transaction is the time when the transaction started).
-        if filtered is not None:
-            filtered()
+        def existing():
+            return query(
+                "SELECT message FROM bridged_messages WHERE network = :n AND external_id = :e",
+                n=network,
+                e=external_id,
+            ).first()
 
-        send_mule("message_posted", msg['id'])
+        def already_exists(msg_id):
+            return AlreadyExists(
+                f"Bridged message {network}:{external_id} already exists", Post, msg_id
+            )
+
+        dupe = existing()
+        if dupe:
+            raise already_exists(dupe[0])
+
+        bridged = {'network': network, 'external_id': external_id}
+        if external_user is not None:
+            bridged['external_user'] = external_user
+        if display_name is not None:
+            bridged['display_name'] = display_name
+
+        try:
+            msg = self.add_post(bridge, data, sig, files=files, bridged=bridged)
+        except sqlalchemy.exc.IntegrityError:
+            # A concurrent request for the same external message beat us to it (and our post was
+            # rolled back along with the mapping):
+            dupe = existing()
+            if dupe is None:
+                raise
+            raise already_exists(dupe[0])
+
+        msg['bridged'] = bridged
         return msg
 
+    def bridged_attributions(self, msg_ids: List[int]):
+        """
+        Returns a dict of message id to external attribution details (network, external_id, and
+        external_user/display_name, if set) for any of the given messages that were posted via the
+        bridge API.
+        """
+        if not msg_ids:
+            return {}
+        return {
+            row['message']: {
+                k: row[k]
+                for k in ('network', 'external_id', 'external_user', 'display_name')
+                if row[k] is not None
+            }
+            for row in query(
+                "SELECT * FROM bridged_messages WHERE message IN :ids",
+                ids=msg_ids,
+                bind_expanding=['ids'],
+            )
+        }
+
     def edit_post(self, user: User, msg_id: int, data: bytes, sig: bytes, *, files: List[int] = []):
         """
         Edits a post in the room.  The post must exist, must have been authored by the same user,
@@ -1073,7 +2335,10 @@ class Room:
 
         if data is None or sig is None or len(sig) != 64:
             raise InvalidData()
+        self._check_signature(user, data, sig)
 
+        self._check_links(user, data)
+        self._check_text(user, data)
         filtered = self.should_filter(user, data)
         with db.transaction():
             author = query(
@@ -1097,17 +2362,21 @@ class Room:
                 return
 
             data_size = len(data)
-            unpadded_data = utils.remove_session_message_padding(data)
+            stored_data, data_dict = compress.encode(
+                self.id, utils.remove_session_message_padding(data)
+            )
 
             query(
                 """
                 UPDATE messages SET
-                    data = :data, data_size = :data_size, signature = :sig WHERE id = :m
+                    data = :data, data_size = :data_size, signature = :sig, data_dict = :data_dict
+                WHERE id = :m
                 """,
                 m=msg_id,
-                data=unpadded_data,
+                data=stored_data,
                 data_size=data_size,
                 sig=sig,
+                data_dict=data_dict,
             )
 
             if files:
@@ -1115,6 +2384,14 @@ class Room:
                 self._own_files(msg_id, files, user)
 
         send_mule("message_edited", msg_id)
+        journal.record(
+            'message_edited',
+            room=self.token,
+            id=msg_id,
+            session_id=user.session_id,
+            data=data,
+            signature=sig,
+        )
 
     def delete_posts(self, message_ids: List[int], deleter: User):
         """
@@ -1176,6 +2453,26 @@ class Room:
                     deleted += ids
                 i += 50
 
+        if deleted:
+            send_mule("messages_deleted", deleted)
+            journal.record('messages_deleted', room=self.token, ids=deleted, by=deleter.session_id)
+            if self.system_messages:
+                # Announce deletions of other users' messages (but not of system messages):
+                removed = [
+                    r[0]
+                    for r in query(
+                        """
+                        SELECT id FROM messages
+                        WHERE id IN :ids AND "user" != :u AND kind != 'system'
+                        ORDER BY id
+                        """,
+                        ids=deleted,
+                        u=deleter.id,
+                        bind_expanding=['ids'],
+                    )
+                ]
+                if removed:
+                    self.add_system_message('messages_deleted', ids=removed)
         return deleted
 
     def delete_all_posts(self, poster: User, *, deleter: User):
@@ -1235,13 +2532,107 @@ class Room:
             )
             files_removed = result.rowcount
 
-        # FIXME: send `deleted` to mule
+        if deleted:
+            send_mule("messages_deleted", deleted)
+            journal.record('messages_deleted', room=self.token, ids=deleted, by=deleter.session_id)
+            if poster.id != deleter.id:
+                self.add_system_message('messages_deleted', ids=deleted)
+
+        app.logger.debug(
+            f"Delete all posts by {poster} from {self}: {len(deleted)} posts, {files_removed} files"
+        )
+
+        return len(deleted), files_removed
+
+    def clear_posts(self, *, deleter: User):
+        """
+        Deletes all the posts of the room (unpinning any pinned posts), and expires all of its files
+        other than the room image, leaving the room itself in place.  `deleter` must be a global
+        admin.  Returns the number of posts deleted.
+        """
+        if not deleter.global_admin:
+            app.logger.warning(f"Cannot clear posts of {self}: {deleter} is not a global admin")
+            raise BadPermission()
+
+        with db.transaction():
+            deleted = [
+                r[0]
+                for r in query(
+                    "SELECT id FROM messages WHERE room = :r AND data IS NOT NULL", r=self.id
+                )
+            ]
+            query("DELETE FROM message_details WHERE room = :r", r=self.id)
+            unpinned = query("DELETE FROM pinned_messages WHERE room = :r", r=self.id).rowcount
+
+            image = self.image
+            query(
+                f"""
+                UPDATE files SET expiry = 0.0 WHERE room = :r
+                    {'AND id != :omit' if image else ''}
+                """,
+                r=self.id,
+                omit=image.id if image else None,
+            )
+            if unpinned:
+                self._refresh()
+
+        if deleted:
+            send_mule("messages_deleted", deleted)
+            journal.record(
+                'messages_deleted', room=self.token, ids=deleted, by=deleter.session_id
+            )
+        app.logger.warning(f"{deleter} cleared all {len(deleted)} posts of {self}")
+        return len(deleted)
+
+    def translate_post(self, user: Optional[User], msg_id: int, lang: str):
+        """
+        Returns the text of the given message translated into `lang` via the configured translation
+        service.  Translations are cached in the database and reused until the message is edited or
+        deleted.
+
+        Raises NoSuchPost if the message does not exist or is not visible to `user` (e.g. a whisper
+        to someone else), and sogs.translate.TranslationFailed if the translation service fails.
+        """
+        msgs = self.get_messages_for(user, single=msg_id, reactions=False)
+        if not msgs:
+            raise NoSuchPost(msg_id)
+
+        seqno = query("SELECT seqno_data FROM messages WHERE id = :m", m=msg_id).first()[0]
+
+        cached = query(
+            """
+            SELECT translated FROM message_translations
+            WHERE message = :m AND lang = :lang AND seqno = :seqno
+            """,
+            m=msg_id,
+            lang=lang,
+            seqno=seqno,
+        ).first()
+        if cached:
+            return cached[0]
+
+        try:
+            text = Post(raw=msgs[0]['data']).text
+        except Exception as e:
+            app.logger.warning(f"Unable to parse message {msg_id} for translation: {e}")
+            raise InvalidData(f"Message {msg_id} has no translatable content")
+
+        translated = translate.translate(text, lang) if text else ''
 
-        app.logger.debug(
-            f"Delete all posts by {poster} from {self}: {len(deleted)} posts, {files_removed} files"
+        query(
+            """
+            INSERT INTO message_translations (message, lang, seqno, translated)
+            VALUES (:m, :lang, :seqno, :translated)
+            ON CONFLICT (message, lang) DO UPDATE
+            SET seqno = :seqno, translated = :translated
+            """,
+            m=msg_id,
+            lang=lang,
+            seqno=seqno,
+            translated=translated,
         )
 
-        return len(deleted), files_removed
+        return translated
 
     def attachments_size(self):
         """Returns the number and aggregate size of attachments currently stored in this room"""
@@ -1256,10 +2647,12 @@ class Room:
             (r, c)
             for r, c in query(
                 """
-                SELECT reaction, COUNT(*)
-                FROM message_reactions JOIN messages ON messages.id = message
+                SELECT r.reaction, CAST(SUM(c.count) AS BIGINT)
+                FROM reactions r
+                    JOIN reaction_counts c ON c.reaction = r.id
+                    JOIN messages ON messages.id = r.message
                 WHERE room = :r
-                GROUP BY reaction
+                GROUP BY r.reaction
                 """,
                 r=self.id,
             )
@@ -1298,13 +2691,8 @@ class Room:
         )
         for reactid, msgid, react, count, you in query(
             f"""
-            SELECT
-                id,
-                message,
-                reaction,
-                (SELECT COUNT(*) FROM user_reactions WHERE reaction = r.id) AS react_count,
-                {select_you}
-            FROM reactions r
+            SELECT id, message, r.reaction, c.count, {select_you}
+            FROM reactions r JOIN reaction_counts c ON c.reaction = r.id
             WHERE message IN :msgs
             ORDER BY id
             """,
@@ -1377,13 +2765,17 @@ class Room:
 
         The post must exist in the room (throws NoSuchPost if it does not).
 
-        The user must have read permission in the room (throws BadPermission if not).
+        The user must have read permission in the room, and the room must not be archived (throws
+        BadPermission if not).
 
         Returns a tuple of: bool indicating whether adding was successful (False = reaction already
         present), and the new message seqno value.
         """
 
         self._check_reaction_request(user, msg_id, reaction)
+        if self.archived is not None and not self.check_admin(user):
+            app.logger.warning(f"Cannot add reaction in archived room {self}")
+            raise BadPermission()
 
         with db.transaction():
             try:
@@ -1515,19 +2907,28 @@ class Room:
         ([public_mods], [public_admins], [hidden_mods], [hidden_admins])
         """
 
-        visible_clause = "" if self.check_moderator(user) else "AND visible_mod"
+        mod = self.check_moderator(user)
         m, hm, a, ha = [], [], [], []
-        for session_id, visible, admin in query(
-            f"""
-            SELECT session_id, visible_mod, admin FROM room_moderators
-            WHERE room = :r {visible_clause}
-            ORDER BY session_id
-            """,
-            r=self.id,
+        for session_id, visible, admin in roomcache.room_info(
+            self,
+            'mods',
+            lambda: [
+                tuple(r)
+                for r in query(
+                    """
+                    SELECT session_id, visible_mod, admin FROM room_moderators
+                    WHERE room = :r
+                    ORDER BY session_id
+                    """,
+                    r=self.id,
+                )
+            ],
         ):
             if session_id[0:2] == "ff" and session_id[2:] == crypto.server_pubkey_hex:
                 # Skip the system user which isn't really a moderator/admin account
                 continue
+            if not visible and not mod:
+                continue
 
             ((a if admin else m) if visible else (ha if admin else hm)).append(session_id)
 
@@ -1606,6 +3007,20 @@ class Room:
                 app.logger.info(
                     f"{added_by} set {u} as {'admin' if admin else 'moderator'} of {self}"
                 )
+                journal.record(
+                    'moderator_added',
+                    room=self.token,
+                    session_id=u.session_id,
+                    by=added_by.session_id,
+                    admin=admin,
+                    visible=visible,
+                )
+
+        if visible:
+            # Don't announce hidden mods/admins
+            self.add_system_message(
+                'moderator_added', session_id=u.session_id, admin=self.check_admin(u)
+            )
 
     def remove_moderator(self, user: User, *, removed_by: User, remove_admin_only: bool = False):
         """
@@ -1636,6 +3051,210 @@ class Room:
                     del self._perm_cache[user.id]
 
                 app.logger.info(f"{removed_by} removed {u} as mod/admin of {self}")
+                journal.record(
+                    'moderator_removed',
+                    room=self.token,
+                    session_id=u.session_id,
+                    by=removed_by.session_id,
+                    admin_only=remove_admin_only,
+                )
+
+    @property
+    def owner(self):
+        """
+        The User who owns the room (i.e. who accepted the most recent ownership transfer), or None
+        if ownership has never been transferred.
+        """
+        return None if self._owner_id is None else User(id=self._owner_id)
+
+    def _check_transfer_permission(self, user: User):
+        """
+        Raises BadPermission unless `user` may transfer ownership of the room: the owner (or, for a
+        room without an owner, any room admin) and global admins.
+        """
+        if user.global_admin:
+            return
+        if self._owner_id is not None and user.id != self._owner_id:
+            app.logger.warning(f"Unable to transfer {self}: {user} is not the room owner")
+            raise BadPermission()
+        if not self.check_admin(user):
+            app.logger.warning(f"Unable to transfer {self}: {user} is not an admin")
+            raise BadPermission()
+
+    def get_transfer(self):
+        """
+        Returns a dict describing the pending ownership transfer of the room, or None if there is no
+        (unexpired) pending transfer.  The dict contains keys `from` and `to` (the session ids of
+        the user who started the transfer and of the new owner), `transfer_admins`, `nonce` (the
+        bytes value that the acceptance must sign; see `transfer_message`), `created`, and
+        `expires`.
+        """
+        row = query(
+            """
+            SELECT f.session_id, t.session_id, transfer_admins, nonce, created, expires
+            FROM room_transfers
+                JOIN users f ON f.id = from_user
+                JOIN users t ON t.id = to_user
+            WHERE room = :r AND expires > :now
+            """,
+            r=self.id,
+            now=time.time(),
+        ).first()
+        if row is None:
+            return None
+        return {
+            'from': row[0],
+            'to': row[1],
+            'transfer_admins': bool(row[2]),
+            'nonce': bytes(row[3]),
+            'created': row[4],
+            'expires': row[5],
+        }
+
+    def _check_owned_limit(self, user: User):
+        """Raises RoomLimitReached if `user` may not own any more rooms (see [rooms] max_owned)."""
+        limit = config.ROOM_MAX_OWNED
+        if not limit or user.global_admin:
+            return
+        owned = query("SELECT COUNT(*) FROM rooms WHERE owner = :u", u=user.id).first()[0]
+        if owned >= limit:
+            app.logger.warning(f"Refusing transfer of {self} to {user}: already owns {owned} rooms")
+            raise RoomLimitReached(f"User already owns the maximum number of rooms ({limit})")
+
+    def start_transfer(self, to: User, *, by: User, transfer_admins: bool = False):
+        """
+        Starts a transfer of ownership of the room to `to`, replacing any pending transfer.  The
+        transfer does not take effect until the new owner accepts it (see `accept_transfer`) within
+        the [rooms] `transfer_expiry` setting.
+
+        If `transfer_admins` is true then, when the transfer is accepted, all other room admins
+        (including the current owner) lose their admin permission (but remain moderators); otherwise
+        they are unaffected.
+
+        `by` must be the room's owner or, if the room has no owner, a room admin; global admins may
+        always transfer rooms.  Raises BadPermission if not permitted, and InvalidData if `to` is
+        not a valid recipient (e.g. is banned, is already the owner, or is an unblinded id when
+        this server requires blinded ids), and RoomLimitReached if `to` already owns the [rooms]
+        `max_owned` number of rooms.  Returns the new transfer (as returned by `get_transfer()`).
+        """
+        self._check_transfer_permission(by)
+
+        with db.transaction():
+            with to.check_blinding() as to:
+                if to.id == self._owner_id or to.system_user:
+                    raise InvalidData("Invalid transfer recipient")
+                if not self.check_permission(to):
+                    raise InvalidData("Cannot transfer a room to a banned user")
+                if config.REQUIRE_BLIND_KEYS and to.session_id.startswith('05'):
+                    raise InvalidData("Transfer recipient must be given as a blinded id")
+                self._check_owned_limit(to)
+
+                expires = time.time() + config.ROOM_TRANSFER_EXPIRY
+                query("DELETE FROM room_transfers WHERE room = :r", r=self.id)
+                query(
+                    """
+                    INSERT INTO room_transfers (room, from_user, to_user, transfer_admins, nonce,
+                        expires)
+                    VALUES (:r, :f, :t, :admins, :nonce, :expires)
+                    """,
+                    r=self.id,
+                    f=by.id,
+                    t=to.id,
+                    admins=transfer_admins,
+                    nonce=os.urandom(16),
+                    expires=expires,
+                )
+
+            app.logger.warning(f"{by} started transfer of {self} to {to}")
+            journal.record(
+                'room_transfer_started',
+                room=self.token,
+                session_id=to.session_id,
+                by=by.session_id,
+                transfer_admins=transfer_admins,
+                expires=expires,
+            )
+            return self.get_transfer()
+
+    def cancel_transfer(self, by: User):
+        """
+        Cancels the pending ownership transfer of the room.  This can be done by anyone permitted to
+        start a transfer, or (to decline it) by the transfer's recipient.  Returns True if a
+        transfer was cancelled, False if there was no pending transfer; raises BadPermission if
+        `by` is not permitted to cancel it.
+        """
+        transfer = self.get_transfer()
+        if transfer is None:
+            return False
+        if by.session_id != transfer['to']:
+            self._check_transfer_permission(by)
+
+        query("DELETE FROM room_transfers WHERE room = :r", r=self.id)
+        app.logger.warning(f"{by} cancelled the transfer of {self} to {transfer['to']}")
+        journal.record(
+            'room_transfer_cancelled',
+            room=self.token,
+            session_id=transfer['to'],
+            by=by.session_id,
+        )
+        return True
+
+    def accept_transfer(self, user: User, *, pubkey: bytes, signature: bytes):
+        """
+        Accepts the pending transfer of ownership of the room to `user`.  `signature` must be an
+        Ed25519 signature of `transfer_message(token, nonce)` (where `nonce` is the transfer's
+        `nonce`) by `pubkey`, the Ed25519 key of `user`'s session id.  On success `user` becomes the
+        owner and an admin of the room; the acceptance (including the signature) is recorded in the
+        event journal.
+
+        Raises BadPermission if there is no pending transfer to `user`, InvalidData if the
+        signature is not valid, and RoomLimitReached if `user` already owns the maximum number of
+        rooms.
+        """
+        transfer = self.get_transfer()
+        if transfer is None or transfer['to'] != user.session_id:
+            raise BadPermission("No pending transfer of this room to this user")
+
+        if not _pubkey_matches(pubkey, user.session_id):
+            raise InvalidData("Public key does not match the session id")
+        try:
+            VerifyKey(pubkey).verify(transfer_message(self.token, transfer['nonce']), signature)
+        except nacl.exceptions.BadSignatureError:
+            raise InvalidData("Invalid transfer acceptance signature")
+
+        self._check_owned_limit(user)
+
+        sysadmin = SystemUser()
+        with db.transaction():
+            query("DELETE FROM room_transfers WHERE room = :r", r=self.id)
+            query(
+                "UPDATE rooms SET owner = :u, info_updates = info_updates + 1 WHERE id = :r",
+                r=self.id,
+                u=user.id,
+            )
+
+            m, a, hm, ha = self.get_all_moderators()
+            self.set_moderator(
+                user, added_by=sysadmin, admin=True, visible=user.session_id not in hm + ha
+            )
+            if transfer['transfer_admins']:
+                for sid in a + ha:
+                    if sid != user.session_id:
+                        self.remove_moderator(
+                            User(session_id=sid), removed_by=sysadmin, remove_admin_only=True
+                        )
+
+            self._refresh(perms=True)
+
+            app.logger.warning(f"{user} accepted the transfer of {self} from {transfer['from']}")
+            journal.record(
+                'room_transferred',
+                room=self.token,
+                session_id=user.session_id,
+                previous_owner=transfer['from'],
+                transfer_admins=transfer['transfer_admins'],
+                signature=utils.encode_base64(signature),
+            )
 
     def ban_user(self, to_ban: User, *, mod: User, timeout: Optional[float] = None):
         """
@@ -1667,8 +3286,6 @@ class Room:
                     app.logger.warning(f"Error banning {to_ban} from {self} by {mod}: {fail}")
                     raise BadPermission()
 
-                # TODO: log the banning action for auditing
-
                 query(
                     """
                     INSERT INTO user_permission_overrides (room, "user", banned, moderator, admin)
@@ -1704,6 +3321,15 @@ class Room:
                     f"Banned {to_ban} from {self} {f'for {timeout}s ' if timeout else ''}"
                     f"(banned by {mod})"
                 )
+                journal.record(
+                    'user_banned',
+                    room=self.token,
+                    session_id=to_ban.session_id,
+                    by=mod.session_id,
+                    timeout=timeout,
+                )
+
+        self.add_system_message('user_banned', session_id=to_ban.session_id, timeout=timeout)
 
     def unban_user(self, to_unban: User, *, mod: User):
         """
@@ -1730,6 +3356,12 @@ class Room:
                 )
                 if result.rowcount > 0:
                     app.logger.debug(f"{mod} unbanned {to_unban} from {self}")
+                    journal.record(
+                        'user_unbanned',
+                        room=self.token,
+                        session_id=to_unban.session_id,
+                        by=mod.session_id,
+                    )
 
                     if to_unban.id in self._perm_cache:
                         del self._perm_cache[to_unban.id]
@@ -1811,6 +3443,13 @@ class Room:
                     del self._perm_cache[user.id]
 
                 app.logger.debug(f"{mod} applied {self} permission(s) {perms} to {user}")
+                journal.record(
+                    'permissions_changed',
+                    room=self.token,
+                    session_id=user.session_id,
+                    by=mod.session_id,
+                    **perms,
+                )
 
     def clear_future_permissions(
         self,
@@ -1923,12 +3562,322 @@ class Room:
         if row['expiry'] is None or row['expiry'] > time.time():
             return File(row)
 
+    @property
+    def egress_cap(self):
+        """
+        The monthly file download (egress) limit of this room, in bytes, or None if unlimited.  This
+        is the room's [room:TOKEN] `egress_cap` config setting, if set, otherwise the server-wide
+        [files] `egress_cap` setting.
+        """
+        cap = config.ROOM_OVERRIDES.get(self.token, {}).get('egress_cap', config.ROOM_EGRESS_CAP)
+        return cap or None
+
+    def egress_used(self, period: Optional[str] = None):
+        """
+        Returns the number of bytes of file downloads served from this room during the given
+        accounting period (a UTC month, in `YYYY-MM` format).  Defaults to the current month.
+        """
+        return query(
+            "SELECT COALESCE(SUM(bytes), 0) FROM room_egress WHERE room = :r AND period = :p",
+            r=self.id,
+            p=period or egress_period(),
+        ).first()[0]
+
+    @property
+    def storage_cap(self):
+        """
+        The limit on the total size of this room's stored files, in bytes, or None if unlimited.
+        This is the room's [room:TOKEN] `storage_cap` config setting, if set, otherwise the
+        server-wide [files] `storage_cap` setting.
+        """
+        cap = config.ROOM_OVERRIDES.get(self.token, {}).get('storage_cap', config.ROOM_STORAGE_CAP)
+        return cap or None
+
+    @property
+    def message_retention(self):
+        """
+        How long, in seconds, messages are kept in this room before being automatically deleted, or
+        None if they are kept indefinitely.  This is the room's [room:TOKEN] `message_retention`
+        config setting, if set, otherwise the server-wide [rooms] `message_retention` setting.
+        """
+        retention = config.ROOM_OVERRIDES.get(self.token, {}).get(
+            'message_retention', config.ROOM_MESSAGE_RETENTION
+        )
+        return retention or None
+
+    def history_horizon(self, *, now: Optional[float] = None):
+        """
+        Returns the seqno up to which the room's messages have been removed by the room's message
+        retention period, or 0 if the room has no retention period (or if no messages are old
+        enough to have been removed).  A client that last polled for updates before this seqno has
+        missed messages which no longer exist (it would only see their deletions), and so needs to
+        resync its view of the room rather than continuing to poll.
+        """
+        retention = self.message_retention
+        if retention is None:
+            return 0
+        if now is None:
+            now = time.time()
+        row = query(
+            """
+            SELECT seqno_creation FROM messages
+            WHERE room = :r AND posted < :cutoff
+            ORDER BY posted DESC LIMIT 1
+            """,
+            r=self.id,
+            cutoff=now - retention,
+        ).first()
+        return row[0] if row else 0
+
+    @property
+    def poll_limit_max(self):
+        """
+        The maximum number of message updates returned by one poll of this room: the room's
+        [room:TOKEN] `poll_limit_max` config setting, if set, otherwise the server-wide [rooms]
+        `poll_limit_max` setting.
+        """
+        return config.ROOM_OVERRIDES.get(self.token, {}).get(
+            'poll_limit_max', config.ROOM_POLL_LIMIT_MAX
+        )
+
+    @property
+    def poll_limit(self):
+        """
+        The number of message updates returned by a poll of this room that doesn't specify a limit:
+        the room's [room:TOKEN] `poll_limit` config setting, if set, otherwise the server-wide
+        [rooms] `poll_limit` setting (but never more than `poll_limit_max`).
+        """
+        limit = config.ROOM_OVERRIDES.get(self.token, {}).get('poll_limit', config.ROOM_POLL_LIMIT)
+        return min(limit, self.poll_limit_max)
+
+    def delete_expired_posts(self, *, now: Optional[float] = None):
+        """
+        Deletes the messages of this room that were posted longer ago than the room's message
+        retention period, other than pinned messages, and expires their attachments (so that they
+        are removed by the next file pruning).  Returns the ids of the deleted messages.
+        """
+        retention = self.message_retention
+        if retention is None:
+            return []
+        if now is None:
+            now = time.time()
+
+        with db.transaction():
+            deleted = [
+                r[0]
+                for r in query(
+                    """
+                    SELECT id FROM messages
+                    WHERE room = :r AND data IS NOT NULL AND posted < :cutoff
+                        AND id NOT IN (SELECT message FROM pinned_messages WHERE room = :r)
+                    ORDER BY id
+                    """,
+                    r=self.id,
+                    cutoff=now - retention,
+                )
+            ]
+            # In slices to avoid hitting bind limits, as in delete_posts:
+            for i in range(0, len(deleted), 50):
+                ids = deleted[i : i + 50]
+                query(
+                    "DELETE FROM message_details WHERE id IN :ids", ids=ids, bind_expanding=['ids']
+                )
+                query(
+                    "UPDATE files SET expiry = 0.0 WHERE message IN :ids",
+                    ids=ids,
+                    bind_expanding=['ids'],
+                )
+
+        if deleted:
+            app.logger.info(f"Deleted {len(deleted)} messages past {self}'s retention period")
+            send_mule("messages_deleted", deleted)
+            journal.record('messages_deleted', room=self.token, ids=deleted, by=None)
+        return deleted
+
+    def storage_used(self):
+        """Returns the total size, in bytes, of the unexpired files stored in this room."""
+        return query(
+            """
+            SELECT COALESCE(SUM(size), 0) FROM files
+            WHERE room = :r AND (expiry IS NULL OR expiry > :now)
+            """,
+            r=self.id,
+            now=time.time(),
+        ).first()[0]
+
+    def check_storage(self, size: int, uploader: Optional[User] = None):
+        """
+        Throws QuotaExceeded if storing another `size` bytes of files in this room would exceed the
+        room's storage cap or the server-wide storage cap, or (if given) the uploader's per-user
+        storage cap.
+        """
+        cap = self.storage_cap
+        if cap is not None:
+            used = self.storage_used()
+            if used + size > cap:
+                app.logger.warning(f"Refusing upload to {self}: room storage cap reached")
+                raise QuotaExceeded(
+                    "Room file storage limit reached", quota='room_storage', limit=cap, used=used
+                )
+        cap = config.SERVER_STORAGE_CAP
+        if cap is not None:
+            used = storage_used()
+            if used + size > cap:
+                app.logger.warning(f"Refusing upload to {self}: server storage cap reached")
+                raise QuotaExceeded(
+                    "Server file storage limit reached",
+                    quota='server_storage',
+                    limit=cap,
+                    used=used,
+                )
+        cap = config.USER_STORAGE_CAP
+        if cap is not None and uploader is not None:
+            used = user_storage_used(uploader)
+            if used + size > cap:
+                app.logger.warning(f"Refusing upload to {self}: {uploader} storage cap reached")
+                raise QuotaExceeded(
+                    "User file storage limit reached", quota='user_storage', limit=cap, used=used
+                )
+
+    def egress_exceeded(self):
+        """True if this room has an egress cap which has been reached for the current month."""
+        cap = self.egress_cap
+        return cap is not None and self.egress_used() >= cap
+
+    def record_egress(self, file: File):
+        """Records a download of `file` from this room towards the room's egress usage."""
+        with db.transaction():
+            query(
+                """
+                INSERT INTO room_egress (room, period, bytes) VALUES (:r, :p, :size)
+                ON CONFLICT (room, period) DO UPDATE SET bytes = room_egress.bytes + :size
+                """,
+                r=self.id,
+                p=egress_period(),
+                size=file.size,
+            )
+            query(
+                "UPDATE files SET egress = egress + :size, downloads = downloads + 1 WHERE id = :f",
+                size=file.size,
+                f=file.id,
+            )
+
+    def egress_stats(self, *, limit: int = 10):
+        """
+        Returns a dict of file download statistics for this room containing keys:
+
+        - `period` -- the current accounting period, as a `YYYY-MM` UTC month string.
+        - `bytes` -- the number of bytes served from this room during the current period.
+        - `cap` -- the monthly egress cap of the room, in bytes, or None if unlimited.
+        - `history` -- dict of {period: bytes} for all recorded periods.
+        - `top_files` -- list of up to `limit` dicts of the (unexpired) files of this room with the
+          highest all-time egress, each containing keys `id`, `size`, `downloads`, and `egress`.
+        """
+        history = {
+            period: b
+            for period, b in query(
+                "SELECT period, bytes FROM room_egress WHERE room = :r ORDER BY period", r=self.id
+            )
+        }
+        period = egress_period()
+        return {
+            'period': period,
+            'bytes': history.get(period, 0),
+            'cap': self.egress_cap,
+            'history': history,
+            'top_files': [
+                {k: row[k] for k in ('id', 'size', 'downloads', 'egress')}
+                for row in query(
+                    """
+                    SELECT id, size, downloads, egress FROM files
+                    WHERE room = :r AND egress > 0
+                    ORDER BY egress DESC, id LIMIT :limit
+                    """,
+                    r=self.id,
+                    limit=limit,
+                )
+            ],
+        }
+
+    def daily_stats(self, *, since: Optional[float] = None, until: Optional[float] = None):
+        """
+        Returns the rolled-up daily activity statistics of this room (see sogs.stats), optionally
+        limited to days starting at or after `since` and before `until`.  Returns a list of dicts
+        in ascending day order, each containing keys `day` (the unix timestamp of the start of the
+        UTC day) and the columns listed in sogs.stats.COLUMNS.
+        """
+        from ..stats import COLUMNS
+
+        range_clause = ''
+        if since is not None:
+            range_clause += ' AND day >= :since'
+        if until is not None:
+            range_clause += ' AND day < :until'
+        return [
+            {k: row[k] for k in ('day', *COLUMNS)}
+            for row in query(
+                f"SELECT * FROM room_daily_stats WHERE room = :r{range_clause} ORDER BY day",
+                r=self.id,
+                since=since,
+                until=until,
+            )
+        ]
+
+    def member_stats(self):
+        """
+        Returns per-user activity statistics of this room, for moderators: a list of dicts, one for
+        each user who has posted in or accessed the room, containing keys `session_id` (always
+        blinded, i.e. `15...`, so that unblinded ids are not exposed), `posts` (the number of the
+        user's messages in the room, excluding deleted messages), `deleted` (the number of the
+        user's messages that have been deleted), `first_post` and `last_post` (unix timestamps, or
+        None if the user has not posted), and `last_active` (when the user last accessed the room,
+        or None if not recorded).
+        """
+        rows = query(
+            """
+            SELECT users.session_id, room_users.last_active,
+                stats.posts, stats.deleted, stats.first_post, stats.last_post
+            FROM users
+                LEFT JOIN room_users ON room_users."user" = users.id AND room_users.room = :r
+                LEFT JOIN (
+                    SELECT "user",
+                        SUM(CASE WHEN data IS NOT NULL THEN 1 ELSE 0 END) AS posts,
+                        SUM(CASE WHEN data IS NULL THEN 1 ELSE 0 END) AS deleted,
+                        MIN(posted) AS first_post,
+                        MAX(posted) AS last_post
+                    FROM messages WHERE room = :r GROUP BY "user"
+                ) stats ON stats."user" = users.id
+            WHERE room_users."user" IS NOT NULL OR stats."user" IS NOT NULL
+            ORDER BY users.id
+            """,
+            r=self.id,
+        )
+        result = []
+        for row in rows:
+            sid = row['session_id']
+            if sid.startswith('05'):
+                sid = crypto.compute_blinded_abs_id(sid)
+            elif sid.startswith('15'):
+                sid = crypto.blinded_abs(sid)
+            result.append(
+                {
+                    'session_id': sid,
+                    'posts': row['posts'] or 0,
+                    'deleted': row['deleted'] or 0,
+                    'first_post': row['first_post'],
+                    'last_post': row['last_post'],
+                    'last_active': row['last_active'],
+                }
+            )
+        return result
+
     def upload_file(
         self,
         content: bytes,
         uploader: User,
         *,
         filename: Optional[str] = None,
+        content_type: Optional[str] = None,
         lifetime: Optional[float] = config.UPLOAD_DEFAULT_EXPIRY,
     ):
         """
@@ -1938,18 +3887,30 @@ class Room:
 
         - content -- the file content in bytes
         - uploader -- the user who is uploading the file
-        - filename -- the filename as provided by the user, or None if no filename provided
+        - filename -- the filename as provided by the user, or None if no filename provided; this
+          is sanitized (see utils.sanitize_filename) before being stored.
+        - content_type -- the content type declared by the uploader, if any.  This is only used to
+          serve the file inline, if permitted by [files].inline_types.
         - lifetime -- how long (in seconds) the file should last before expiring; can be None for a
           file that should never expire.
 
+        If the same content has already been stored (and config.UPLOAD_DEDUP is enabled) then the
+        new file row shares the stored content rather than storing another copy.
+
+        If the content is an image matching a banned image (see sogs.phash) then, depending on
+        config.IMAGE_BAN_ACTION, this either throws UploadRejected, or stores the file as
+        quarantined (i.e. it will not be served to non-moderators).
+
+        Throws QuotaExceeded if the upload would exceed the room's, server's, or uploader's storage
+        cap.
+
         Returns the id of the newly inserted file row.  Throws on error.
         """
 
         if not self.check_upload(uploader):
             raise BadPermission()
-
-        files_dir = os.path.join(config.UPLOAD_PATH, self.token)
-        os.makedirs(files_dir, exist_ok=True)
+        self.check_account_age(uploader)
+        self.check_storage(len(content), uploader)
 
         if filename is None:
             upload_filename = None
@@ -1961,7 +3922,24 @@ class Room:
             # For the actual filename we write to disk we heavily sanitize:
             upload_filename = re.sub(config.UPLOAD_FILENAME_BAD, "_", filename)
 
+            # and the one we give to downloaders somewhat less so:
+            filename = utils.sanitize_filename(filename)
+
+        image_hash, quarantined = None, False
+        if config.IMAGE_HASHING:
+            image_hash = phash.compute(content)
+            ban = None if image_hash is None else phash.find_ban(image_hash)
+            if ban is not None:
+                app.logger.warning(f"Upload by {uploader} to {self} matches banned image {ban}")
+                if config.IMAGE_BAN_ACTION != 'quarantine':
+                    raise UploadRejected("upload matches a banned image")
+                quarantined = True
+
+        store = storage.get()
         file_id, file_path = None, None
+        content_hash = None
+        if config.UPLOAD_DEDUP:
+            content_hash = blake2b(content, digest_size=32, person=b'sogs.file').hex()
 
         try:
             # Begin a transaction; if this context exits with exception we want to roll back the
@@ -1974,8 +3952,11 @@ class Room:
                 # proper path, which we want to base on the resulting file id.
                 file_id = db.insert_and_get_pk(
                     """
-                    INSERT INTO files (room, uploader, size, expiry, filename, path)
-                    VALUES (:r, :u, :size, :expiry, :filename, 'tmp')
+                    INSERT INTO files
+                        (room, uploader, size, expiry, filename, content_type, path, phash,
+                        quarantined)
+                    VALUES (:r, :u, :size, :expiry, :filename, :content_type, 'tmp', :phash,
+                        :quarantined)
                     """,
                     "id",
                     r=self.id,
@@ -1983,6 +3964,9 @@ class Room:
                     size=len(content),
                     expiry=expiry,
                     filename=filename,
+                    content_type=content_type,
+                    phash=image_hash,
+                    quarantined=quarantined,
                 )
 
                 if upload_filename is None:
@@ -1995,12 +3979,45 @@ class Room:
                         + upload_filename[-config.UPLOAD_FILENAME_KEEP_SUFFIX :]
                     )
 
-                file_path = f"{files_dir}/{file_id}_{upload_filename}"
+                if content_hash is not None:
+                    blob = query(
+                        "SELECT path FROM file_blobs WHERE hash = :h", h=content_hash
+                    ).first()
+                    if blob is not None:
+                        # Identical content is already stored, so just reference it
+                        query(
+                            "UPDATE files SET path = :p, content_hash = :h WHERE id = :f",
+                            p=blob[0],
+                            h=content_hash,
+                            f=file_id,
+                        )
+                        return file_id
+
+                with tracing.span('storage.write', {'sogs.size': len(content)}):
+                    file_path = store.write(f"{self.token}/{file_id}_{upload_filename}", content)
 
-                with open(file_path, 'wb') as f:
-                    f.write(content)
+                if content_hash is not None and (
+                    query(
+                        """
+                        INSERT INTO file_blobs (hash, path, size) VALUES (:h, :p, :size)
+                        ON CONFLICT DO NOTHING
+                        """,
+                        h=content_hash,
+                        p=file_path,
+                        size=len(content),
+                    ).rowcount
+                    == 0
+                ):
+                    # Lost a race with a concurrent upload of the same content; we've already
+                    # written our own copy, so just keep it as a non-deduplicated file.
+                    content_hash = None
 
-                query("UPDATE files SET path = :p WHERE id = :f", p=file_path, f=file_id)
+                query(
+                    "UPDATE files SET path = :p, content_hash = :h WHERE id = :f",
+                    p=file_path,
+                    h=content_hash,
+                    f=file_id,
+                )
 
                 return file_id
 
@@ -2008,7 +4025,7 @@ class Room:
             app.logger.warning(f"Failed to write/update file {file_path}: {e}")
             if file_path is not None:
                 try:
-                    os.unlink(file_path)
+                    store.delete(file_path)
                 except Exception:
                     pass
             raise
@@ -2032,9 +4049,12 @@ class Room:
         """
         Pins a message to this room.  Requires admin room permissions.
 
-        Pinning a message that is already pinned will keep it pinned but update the
-        pinned_by/pinned_at properties to the current admin/current time.  (This can be useful to
-        reorder pins, which are always sorted oldest-to-newest).
+        New pins are placed after the room's existing pins.  Pinning a message that is already
+        pinned will keep it pinned but update the pinned_by/pinned_at properties to the current
+        admin/current time, and move it to the end of the pins.
+
+        Raises PinLimitReached if the room already has its maximum number of pinned messages (see
+        `max_pins`) and the message isn't one of them.
         """
 
         if not self.check_admin(admin):
@@ -2046,17 +4066,72 @@ class Room:
             if not self.is_regular_message(msg_id):
                 raise NoSuchPost(msg_id)
 
+            count, pinned, last = query(
+                """
+                SELECT COUNT(*), COUNT(CASE WHEN message = :m THEN 1 END), MAX(position)
+                FROM pinned_messages WHERE room = :r
+                """,
+                r=self.id,
+                m=msg_id,
+            ).first()
+            limit = self.max_pins
+            if limit is not None and not pinned and count >= limit:
+                raise PinLimitReached(f"{self} already has the maximum of {limit} pinned messages")
+
             query(
                 """
-                INSERT INTO pinned_messages (room, message, pinned_by) VALUES (:r, :m, :a)
-                ON CONFLICT (room, message) DO UPDATE SET pinned_by = :a, pinned_at = :now
+                INSERT INTO pinned_messages (room, message, pinned_by, position)
+                VALUES (:r, :m, :a, :pos)
+                ON CONFLICT (room, message) DO UPDATE
+                    SET pinned_by = :a, pinned_at = :now, position = :pos
                 """,
                 r=self.id,
                 m=msg_id,
                 a=admin.id,
                 now=time.time(),
+                pos=(last or 0) + 1,
             )
             self._refresh()
+        self.add_system_message('message_pinned', id=msg_id)
+
+    def reorder_pins(self, msg_ids: List[int], admin: User):
+        """
+        Sets the display order of this room's pinned messages.  `msg_ids` must contain exactly the
+        ids of the room's currently pinned messages, in the new order.  Requires admin privileges.
+        Pinned messages that are already in place are not updated, so that an unchanged order does
+        not signal a room update to clients.
+        """
+
+        if not self.check_admin(admin):
+            app.logger.warning(f"Unable to reorder pins of {self}: {admin} is not an admin")
+            raise BadPermission()
+
+        with db.transaction():
+            current = {
+                r[0]: r[1]
+                for r in query(
+                    "SELECT message, position FROM pinned_messages WHERE room = :r", r=self.id
+                )
+            }
+            if len(msg_ids) != len(current) or set(msg_ids) != current.keys():
+                raise InvalidData("Pin order must list each of the room's pinned messages once")
+
+            changed = False
+            for pos, msg_id in enumerate(msg_ids, 1):
+                if current[msg_id] != pos:
+                    query(
+                        """
+                        UPDATE pinned_messages SET position = :pos
+                        WHERE room = :r AND message = :m
+                        """,
+                        r=self.id,
+                        m=msg_id,
+                        pos=pos,
+                    )
+                    changed = True
+
+            if changed:
+                self._refresh()
 
     def unpin_all(self, admin: User):
         """
@@ -2222,6 +4297,40 @@ class Room:
         return result
 
 
+def storage_used():
+    """Returns the total size, in bytes, of the unexpired files stored across all rooms."""
+    return query(
+        "SELECT COALESCE(SUM(size), 0) FROM files WHERE expiry IS NULL OR expiry > :now",
+        now=time.time(),
+    ).first()[0]
+
+
+def user_storage_used(user: User):
+    """Returns the total size, in bytes, of the unexpired files uploaded by `user` to any room."""
+    return query(
+        """
+        SELECT COALESCE(SUM(size), 0) FROM files
+        WHERE uploader = :u AND (expiry IS NULL OR expiry > :now)
+        """,
+        u=user.id,
+        now=time.time(),
+    ).first()[0]
+
+
+def egress_period(when: Optional[float] = None):
+    """Returns the egress accounting period (i.e. `YYYY-MM` UTC month) of the given timestamp."""
+    return time.strftime('%Y-%m', time.gmtime(when))
+
+
+def egress_period_remaining(when: Optional[float] = None):
+    """Returns the number of seconds from the given (or current) time until the next period."""
+    if when is None:
+        when = time.time()
+    t = time.gmtime(when)
+    year, month = (t.tm_year + 1, 1) if t.tm_mon == 12 else (t.tm_year, t.tm_mon + 1)
+    return calendar.timegm((year, month, 1, 0, 0, 0)) - when
+
+
 def get_rooms():
     """Get a list of all rooms; does not check permissions."""
     return [Room(row) for row in query("SELECT * FROM rooms ORDER BY token")]
@@ -2287,6 +4396,38 @@ def get_rooms_with_permission(
     ]
 
 
+def get_all_bans():
+    """
+    Returns all the server's bans, global and room-specific, as a list of dicts with keys:
+
+    - `session_id` -- the banned user's session id.
+    - `room` -- the token of the room the user is banned from, or None for a global ban.
+    - `until` -- the unix timestamp when the ban is scheduled to end, or None if it is permanent.
+    """
+    bans = [
+        {'session_id': row[0], 'room': row[1], 'until': row[2]}
+        for row in query(
+            """
+            SELECT session_id, NULL AS token, (
+                    SELECT MIN(at) FROM user_ban_futures f
+                    WHERE f.room IS NULL AND f."user" = users.id AND NOT f.banned
+                ) AS until
+            FROM users WHERE banned
+            UNION ALL
+            SELECT session_id, token, (
+                    SELECT MIN(at) FROM user_ban_futures f
+                    WHERE f.room = upo.room AND f."user" = upo."user" AND NOT f.banned
+                )
+            FROM user_permission_overrides upo
+                JOIN users ON users.id = upo."user" JOIN rooms ON rooms.id = upo.room
+            WHERE upo.banned
+            """
+        )
+    ]
+    bans.sort(key=lambda b: (b['room'] is not None, b['room'] or '', b['session_id']))
+    return bans
+
+
 def get_accessible_rooms(user: Optional[User] = None):
     """
     Get a list of rooms that a user can access; if user is None then return all publicly accessible
@@ -2299,6 +4440,20 @@ def get_accessible_rooms(user: Optional[User] = None):
     return [Room(row) for row in result]
 
 
+def get_room_ids_with_tags(tags: List[str]):
+    """Returns the set of ids of rooms that have *all* of the given tags."""
+    tags = {t.lower() for t in tags}
+    return {
+        r[0]
+        for r in query(
+            "SELECT room FROM room_tags WHERE tag IN :tags GROUP BY room HAVING COUNT(*) = :n",
+            tags=tuple(tags),
+            n=len(tags),
+            bind_expanding=['tags'],
+        )
+    }
+
+
 def get_deletions_deprecated(room: Room, since):
     if since:
         result = query(
@@ -1,11 +1,13 @@
 from __future__ import annotations
 
-from .. import crypto, db, config
+from .. import crypto, db, config, journal
 from ..db import query
 from ..web import app
 from .exc import NoSuchUser, BadPermission
+from . import presence
 
 from typing import Optional
+import sqlalchemy.exc
 import time
 import contextlib
 
@@ -200,6 +202,21 @@ class User:
         )
         self._touched = True
 
+    def use_nonce(self, nonce: bytes):
+        """
+        Records the use of a request nonce by this user.  Returns True if the nonce was recorded,
+        False if this user has already used the nonce (i.e. the request is a replay).
+        """
+        try:
+            query(
+                'INSERT INTO user_request_nonces ("user", nonce) VALUES (:u, :n)',
+                u=self.id,
+                n=nonce,
+            )
+        except sqlalchemy.exc.IntegrityError:
+            return False
+        return True
+
     def touch(self, force=False):
         """
         Updates the last activity time of this user.  This method only updates the first time it is
@@ -220,6 +237,7 @@ class User:
             r=room.id,
             now=time.time(),
         )
+        presence.touch(room.id, self)
 
     def set_moderator(self, *, added_by: User, admin=False, visible=False):
         """
@@ -250,6 +268,14 @@ class User:
                     u=u.id,
                 )
 
+        journal.record(
+            'moderator_added',
+            room=None,
+            session_id=u.session_id,
+            by=added_by.session_id,
+            admin=admin,
+            visible=visible,
+        )
         u.global_admin = admin
         u.global_moderator = True
         u.visible_mod = visible
@@ -271,6 +297,13 @@ class User:
             """,
             u=self.id,
         )
+        journal.record(
+            'moderator_removed',
+            room=None,
+            session_id=self.session_id,
+            by=removed_by.session_id,
+            admin_only=remove_admin_only,
+        )
         self.global_admin = False
         self.global_moderator = False
 
@@ -311,6 +344,13 @@ class User:
                     )
 
         app.logger.debug(f"{banned_by} globally banned {u}{f' for {timeout}s' if timeout else ''}")
+        journal.record(
+            'user_banned',
+            room=None,
+            session_id=u.session_id,
+            by=banned_by.session_id,
+            timeout=timeout,
+        )
         u.banned = True
 
     def unban(self, *, unbanned_by: User):
@@ -327,6 +367,9 @@ class User:
         query('DELETE FROM user_ban_futures WHERE room IS NULL AND "user" = :u', u=self.id)
 
         app.logger.debug(f"{unbanned_by} removed global ban on {self}")
+        journal.record(
+            'user_unbanned', room=None, session_id=self.session_id, by=unbanned_by.session_id
+        )
         self.banned = False
 
     def verify(self, *, message: bytes, sig: bytes):
@@ -411,6 +454,88 @@ class User:
         """True if the user's session id is a derived key"""
         return self.session_id.startswith('15')
 
+    @property
+    def digest_subscription(self):
+        """
+        Returns a dict of the user's moderator digest subscription details (keys: email, subscribed,
+        last_sent), or None if the user is not subscribed.
+        """
+        row = query(
+            'SELECT email, subscribed, last_sent FROM moderator_digests WHERE "user" = :u',
+            u=self.id,
+        ).first()
+        if row is None:
+            return None
+        return {k: row[k] for k in ('email', 'subscribed', 'last_sent')}
+
+    def subscribe_digest(self, email: str):
+        """
+        Subscribes this user to periodic moderator digests sent to `email`, replacing any existing
+        subscription address.  (This does not authenticate or check moderator status).
+        """
+        query(
+            """
+            INSERT INTO moderator_digests ("user", email) VALUES (:u, :email)
+            ON CONFLICT ("user") DO UPDATE SET email = :email
+            """,
+            u=self.id,
+            email=email,
+        )
+
+    def unsubscribe_digest(self):
+        """Removes this user's moderator digest subscription.  Returns True if one was removed."""
+        return query('DELETE FROM moderator_digests WHERE "user" = :u', u=self.id).rowcount > 0
+
+    @property
+    def dm_blocks(self):
+        """
+        Returns a list of dicts (keys: session_id, blocked_at) of the senders this user has blocked
+        from sending them direct messages, in the order they were blocked.
+        """
+        return [
+            {'session_id': row[0], 'blocked_at': row[1]}
+            for row in query(
+                'SELECT blocked, blocked_at FROM dm_blocks WHERE "user" = :u ORDER BY blocked_at',
+                u=self.id,
+            )
+        ]
+
+    def block_dms(self, session_id: str):
+        """
+        Blocks direct messages to this user from the given session id.  Returns True if newly
+        blocked, False if already blocked.
+        """
+        return (
+            query(
+                'INSERT INTO dm_blocks ("user", blocked) VALUES (:u, :b) ON CONFLICT DO NOTHING',
+                u=self.id,
+                b=session_id,
+            ).rowcount
+            > 0
+        )
+
+    def unblock_dms(self, session_id: str):
+        """Removes the block of direct messages from `session_id`.  Returns True if one existed."""
+        return query(
+            'DELETE FROM dm_blocks WHERE "user" = :u AND blocked = :b', u=self.id, b=session_id
+        ).rowcount > 0
+
+    def blocks_dms_from(self, sender: User):
+        """True if this user has blocked direct messages from `sender`."""
+        return (
+            query(
+                'SELECT 1 FROM dm_blocks WHERE "user" = :u AND blocked = :b',
+                u=self.id,
+                b=sender.session_id,
+            ).first()
+            is not None
+        )
+
+    @property
+    def is_bridge(self):
+        """True if this user is a configured bridge bot (see config.BRIDGE_IDS)"""
+        return self.session_id in config.BRIDGE_IDS
+
     @property
     def system_user(self):
         """True if (and only if) this is the special SOGS system user
@@ -0,0 +1,176 @@
+from .. import crypto, db, normalize, session_pb2 as protobuf
+from ..db import query
+from ..hashing import blake2b
+from .exc import BadPermission, InvalidData, NoSuchWebhook
+from .user import User
+from nacl.signing import SigningKey
+import secrets
+import time
+
+
+class Webhook:
+    """
+    Class representing an inbound room webhook: a secret URL that external services can post simple
+    JSON messages to, which are then posted to the room by a bot user managed by the server.
+
+    Each webhook has its own bot user, with a signing key derived from the server's key and the
+    webhook id, so that Session clients see each webhook as a distinct (and consistent) sender.
+
+    Properties:
+        id - the numeric webhook id
+        room - the Room to which this webhook posts (only retrieved on demand)
+        bot - the User that webhook messages are posted as
+        display_name - the default display name of messages posted via this webhook
+        created - unix timestamp when the webhook was created
+        created_by - the id of the user who created the webhook (None if since deleted)
+        last_used - unix timestamp when the webhook last posted a message, or None if never used
+    """
+
+    def __init__(self, row=None, *, id=None, secret=None):
+        """
+        Constructs a webhook from a pre-retrieved row, a webhook id, *or* the webhook's secret
+        token.  Raises NoSuchWebhook if there is no such webhook.
+        """
+        if sum(x is not None for x in (row, id, secret)) != 1:
+            raise ValueError("Webhook() error: exactly one of row/id/secret is required")
+        if id is not None:
+            row = query("SELECT * FROM room_webhooks WHERE id = :id", id=id).first()
+            if not row:
+                raise NoSuchWebhook(id)
+        elif secret is not None:
+            row = query(
+                "SELECT * FROM room_webhooks WHERE secret_hash = :h", h=_secret_hash(secret)
+            ).first()
+            if not row:
+                raise NoSuchWebhook()
+
+        (
+            self.id,
+            self._room_id,
+            self._bot_id,
+            self.display_name,
+            self.created,
+            self.created_by,
+            self.last_used,
+        ) = (
+            row[c]
+            for c in ('id', 'room', 'user', 'display_name', 'created', 'created_by', 'last_used')
+        )
+        self._room = None
+
+    @staticmethod
+    def create(room, creator: User, display_name: str):
+        """
+        Creates a new webhook for `room`; `creator` must be an admin of the room.  The bot user of
+        the webhook is granted write permission in the room.
+
+        Returns a (webhook, secret) tuple; the secret token is not stored (only a hash of it is) and
+        so can only be obtained here.
+        """
+        if not room.check_admin(creator):
+            raise BadPermission()
+        display_name = normalize.normalize(display_name)
+        if not display_name:
+            raise InvalidData("Webhook display name cannot be empty")
+
+        secret = secrets.token_urlsafe(32)
+        with db.transaction():
+            # Insert with a placeholder bot (the creator) since the bot key depends on the id:
+            hook_id = db.insert_and_get_pk(
+                """
+                INSERT INTO room_webhooks (room, "user", secret_hash, display_name, created_by)
+                VALUES (:r, :u, :h, :name, :u)
+                """,
+                "id",
+                r=room.id,
+                u=creator.id,
+                h=_secret_hash(secret),
+                name=display_name,
+            )
+            bot = User(session_id=_bot_session_id(hook_id), autovivify=True, touch=False)
+            query('UPDATE room_webhooks SET "user" = :u WHERE id = :id', u=bot.id, id=hook_id)
+            room.set_permissions(bot, mod=creator, write=True)
+
+        return Webhook(id=hook_id), secret
+
+    @property
+    def room(self):
+        """The Room to which this webhook posts."""
+        if self._room is None:
+            from .room import Room
+
+            self._room = Room(id=self._room_id)
+        return self._room
+
+    @property
+    def bot(self):
+        """The bot User that messages posted via this webhook are posted as."""
+        return User(id=self._bot_id)
+
+    def post(self, text: str, display_name=None):
+        """
+        Posts a message with body `text` to the room as this webhook's bot user, with the given
+        display name (or the webhook's default display name, if None).  The text and display name
+        are normalized (see sogs.normalize).  Returns the message details as returned by
+        `Room.add_post`; raises whatever add_post raises.
+        """
+        msg = protobuf.Content()
+        msg.dataMessage.body = normalize.normalize(text)
+        msg.dataMessage.timestamp = int(time.time() * 1000)
+        msg.dataMessage.profile.displayName = normalize.normalize(display_name) or self.display_name
+        # Add two bytes padding so that session doesn't get confused by a lack of padding
+        data = msg.SerializeToString() + b'\x80\x00'
+        sig = _bot_signing_key(self.id).sign(data).signature
+
+        with db.transaction():
+            posted = self.room.add_post(self.bot, data, sig)
+            query(
+                "UPDATE room_webhooks SET last_used = :now WHERE id = :id",
+                now=time.time(),
+                id=self.id,
+            )
+        return posted
+
+    def delete(self, user: User):
+        """
+        Deletes this webhook, revoking its bot user's write permission; `user` must be an admin of
+        the room.
+        """
+        room = self.room
+        if not room.check_admin(user):
+            raise BadPermission()
+        with db.transaction():
+            room.set_permissions(self.bot, mod=user, write=None)
+            query("DELETE FROM room_webhooks WHERE id = :id", id=self.id)
+
+    def info(self):
+        """Returns a dict of webhook details suitable for returning to a room admin."""
+        return {
+            'id': self.id,
+            'session_id': _bot_session_id(self.id),
+            'display_name': self.display_name,
+            'created': self.created,
+            'last_used': self.last_used,
+        }
+
+
+def _secret_hash(secret: str):
+    return blake2b(secret.encode(), digest_size=32, person=b'sogs.webhook')
+
+
+def _bot_signing_key(hook_id: int):
+    return SigningKey(
+        blake2b(str(hook_id).encode() + crypto.server_signkey.encode(), key=b'sogswebhook')
+    )
+
+
+def _bot_session_id(hook_id: int):
+    return '15' + _bot_signing_key(hook_id).verify_key.encode().hex()
+
+
+def get_room_webhooks(room):
+    """Returns a list of all webhooks of `room`, ordered by id."""
+    return [
+        Webhook(row)
+        for row in query("SELECT * FROM room_webhooks WHERE room = :r ORDER BY id", r=room.id)
+    ]
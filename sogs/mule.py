@@ -1,18 +1,21 @@
 import traceback
 import oxenmq
-from oxenc import bt_deserialize
+from oxenc import bt_serialize, bt_deserialize
 import time
 from datetime import timedelta
 import functools
 
 from .web import app
-from . import cleanup
+from . import scheduler
 from . import config
 from . import omq as o
+from . import push
+from . import shutdown
 
 # This is the uwsgi "mule" that handles things not related to serving HTTP requests:
 # - it holds the oxenmq instance (with its own interface into sogs)
 # - it handles cleanup jobs (e.g. periodic deletions)
+# - it sends push notifications of room activity to subscribed clients (see sogs.push)
 
 
 def run():
@@ -56,8 +59,13 @@ def setup_omq():
     # Internal socket for workers to talk to us:
     omq.listen(config.OMQ_INTERNAL, curve=False, allow_connection=admin_conn)
 
-    # Periodic database cleanup timer:
-    omq.add_timer(cleanup.cleanup, timedelta(seconds=cleanup.INTERVAL))
+    # Scheduled background jobs (database cleanup, etc.); the scheduler checks for due jobs every
+    # second:
+    omq.add_timer(scheduler.tick, timedelta(seconds=1))
+
+    # Pending push notifications (see sogs.push) are sent from a timer rather than directly when
+    # notified of activity, so that a burst of activity can't hold up the mule:
+    omq.add_timer(flush_push, timedelta(milliseconds=100))
 
     # Commands other workers can send to us, e.g. for notifications of activity for us to know about
     worker = omq.add_category("worker", access_level=oxenmq.AuthLevel.admin)
@@ -65,6 +73,11 @@ def setup_omq():
     worker.add_command("messages_deleted", messages_deleted)
     worker.add_command("message_edited", message_edited)
 
+    # Commands for (curve-authenticated) clients connecting to our public listener:
+    room = omq.add_category("room", access_level=oxenmq.AuthLevel.basic)
+    room.add_request_command("subscribe", room_subscribe)
+    room.add_command("unsubscribe", room_unsubscribe)
+
     app.logger.debug("Mule starting omq")
     omq.start()
 
@@ -73,6 +86,9 @@ def setup_omq():
     app.logger.debug("Mule connecting to self")
     o.mule_conn = omq.connect_inproc(on_success=None, on_failure=inproc_fail)
 
+    # Send any push notifications still pending when the mule exits, rather than dropping them:
+    shutdown.on_shutdown(drain_push, phase=shutdown.FLUSH)
+
 
 def log_exceptions(f):
     @functools.wraps(f)
@@ -86,18 +102,65 @@ def log_exceptions(f):
     return wrapper
 
 
+def _push(event, ids):
+    for token, notification in push.notifications(event, ids):
+        push.enqueue(token, notification)
+
+
+def _send_push(conn, command, notification):
+    if notification is None:
+        o.omq.send(conn, command)
+    else:
+        o.omq.send(conn, command, bt_serialize(notification))
+
+
+@log_exceptions
+def flush_push():
+    push.flush(_send_push)
+
+
+def drain_push():
+    """Sends all pending push notifications, or as many as we can before the shutdown timeout."""
+    while push.flush(_send_push) and shutdown.remaining() > 0:
+        pass
+
+
 @log_exceptions
 def message_posted(m: oxenmq.Message):
     id = bt_deserialize(m.data()[0])
-    app.logger.debug(f"FIXME: mule -- message posted stub, id={id}")
+    _push('posted', [id])
 
 
 @log_exceptions
 def messages_deleted(m: oxenmq.Message):
     ids = bt_deserialize(m.data()[0])
-    app.logger.debug(f"FIXME: mule -- message delete stub, deleted messages: {ids}")
+    _push('deleted', ids)
 
 
 @log_exceptions
 def message_edited(m: oxenmq.Message):
-    app.logger.debug("FIXME: mule -- message edited stub")
+    id = bt_deserialize(m.data()[0])
+    _push('edited', [id])
+
+
+@log_exceptions
+def room_subscribe(m: oxenmq.Message):
+    """
+    Subscribes the client to push notifications of room activity.  Takes a single bt-encoded list
+    of room tokens; replies with a bt-encoded dict of each token to the room's current message
+    sequence number, or to an empty string if the room cannot be subscribed to.
+    """
+    tokens = [t.decode() for t in bt_deserialize(m.data()[0])]
+    if shutdown.stopping():
+        # We're about to go away, so the client will have to resubscribe (to our replacement):
+        return bt_serialize({t: b'' for t in tokens})
+    result = push.subscribe(m.conn, tokens)
+    return bt_serialize({t: b'' if seqno is None else seqno for t, seqno in result.items()})
+
+
+@log_exceptions
+def room_unsubscribe(m: oxenmq.Message):
+    """Unsubscribes from the given bt-encoded list of room tokens (or all rooms, if empty)."""
+    data = m.data()
+    tokens = [t.decode() for t in bt_deserialize(data[0])] if data else []
+    push.unsubscribe(m.conn, tokens or None)
@@ -0,0 +1,92 @@
+import unicodedata
+
+# Unicode normalization of text.  Message bodies and the display names they carry are signed by the
+# posting client, and so can't be altered by the server; for those, normalization is applied when
+# validating them (the content filters match against the `fold()`ed text, so that filters can't be
+# evaded by inserting invisible characters or by using lookalike compatibility characters, and the
+# [messages] length limits count graphemes rather than bytes or codepoints).  Text that the server
+# stores itself (room names, descriptions, and rules, and webhook display names) is `normalize()`d
+# before being stored.
+
+# Invisible formatting characters commonly used to spoof text or evade filters: soft hyphen, zero
+# width space/non-joiner, LTR/RTL marks, bidirectional embeddings/overrides/isolates, word joiner
+# and invisible operators, and the zero-width no-break space (BOM).  (The zero width joiner is not
+# included because it is an essential part of many emoji sequences).
+SPOOF_CHARS = frozenset(
+    [0xAD, 0x200B, 0x200C, 0x200E, 0x200F, 0xFEFF]
+    + list(range(0x202A, 0x202F))
+    + list(range(0x2060, 0x2065))
+    + list(range(0x2066, 0x206A))
+)
+
+# Control characters (C0, DEL, and C1) other than tab and newline
+CONTROL_CHARS = frozenset(
+    c for c in list(range(0x20)) + list(range(0x7F, 0xA0)) if c not in (0x09, 0x0A)
+)
+
+_strip = {c: None for c in SPOOF_CHARS | CONTROL_CHARS}
+
+ZWJ = '\u200d'
+
+
+def normalize(text):
+    """
+    Returns `text` in NFC normal form with control characters (other than tabs and newlines) and
+    invisible spoofing characters (see SPOOF_CHARS) removed.  Returns None if `text` is None.
+    """
+    if text is None:
+        return None
+    return unicodedata.normalize('NFC', text.translate(_strip))
+
+
+def suspicious(text):
+    """True if `text` contains characters that `normalize` would remove (or is not NFC)."""
+    return text is not None and normalize(text) != text
+
+
+def fold(text):
+    """
+    Returns a folded form of `text` for content filtering: compatibility (NFKC) normalized, case
+    folded, with accents and all invisible formatting characters (including zero width joiners)
+    removed.  The result is only suitable for matching, not for display.
+    """
+    text = unicodedata.normalize('NFKD', normalize(text).replace(ZWJ, ''))
+    text = ''.join(c for c in text if not unicodedata.combining(c))
+    return unicodedata.normalize('NFKC', text).casefold()
+
+
+def _extends(c):
+    """True if `c` extends the preceding grapheme (combining marks, variation selectors, etc.)"""
+    o = ord(c)
+    return (
+        unicodedata.category(c) in ('Mn', 'Mc', 'Me')
+        or 0xFE00 <= o <= 0xFE0F  # variation selectors
+        or 0x1F3FB <= o <= 0x1F3FF  # emoji skin tone modifiers
+        or 0xE0020 <= o <= 0xE007F  # emoji tag sequences
+        or 0xE0100 <= o <= 0xE01EF  # supplementary variation selectors
+    )
+
+
+def graphemes(text):
+    """
+    Returns the (approximate) number of user-perceived characters (i.e. extended grapheme clusters)
+    in `text`: combining marks, variation selectors, emoji modifiers and zero width joiner sequences
+    and regional indicator (flag) pairs are counted as part of the character they attach to, and
+    `\\r\\n` counts as one character.
+    """
+    count = 0
+    prev = None
+    join = False
+    flag = False
+    for c in text:
+        if join:
+            join = False
+        elif c == ZWJ or _extends(c) or (prev == '\r' and c == '\n'):
+            join = c == ZWJ
+        elif 0x1F1E6 <= ord(c) <= 0x1F1FF and flag:
+            flag = False
+        else:
+            flag = 0x1F1E6 <= ord(c) <= 0x1F1FF
+            count += 1
+        prev = c
+    return count
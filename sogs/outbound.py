@@ -0,0 +1,292 @@
+from . import config, metrics
+
+import collections
+import http.client
+import ipaddress
+import socket
+import threading
+import time
+import urllib.error
+import urllib.parse
+import urllib.request
+
+# Shared client for server-initiated outbound HTTP requests (translation, bridge media fetches,
+# directory announcements).  Each destination (i.e. URL host and port) gets:
+#
+# - a circuit breaker: after [outbound].failure_threshold consecutive failures (connection errors,
+#   timeouts, and 5xx responses) requests to the destination fail immediately for
+#   [outbound].cooldown seconds, after which a single trial request is let through to see whether
+#   the destination has recovered;
+# - a rate limit of at most [outbound].rate_limit requests per [outbound].rate_interval seconds.
+#
+# so that a hung or overloaded external service fails fast rather than tying up request threads and
+# scheduled jobs waiting on timeouts.  As with metrics, the breaker state is per worker process.
+#
+# If [outbound].proxy is set then all of these requests are made through the given HTTP or SOCKS5
+# proxy (e.g. a local Tor client), so that the external services don't learn the server's IP
+# address.  SOCKS proxies require the PySocks module.
+#
+# Requests to URLs that come from users rather than from the server configuration (such as bridge
+# media fetches) are made with `public_only`, which refuses to connect to anything other than public
+# unicast addresses (i.e. not loopback, private, link-local -- including cloud metadata endpoints
+# such as 169.254.169.254 -- shared, multicast, or reserved addresses, nor IPv6 addresses embedding
+# such IPv4 addresses).  To defeat DNS rebinding the host name is resolved exactly once per
+# connection, every resolved address is checked, and the connection is made to the checked address
+# (with the original host name still used for TLS verification); redirects are checked the same
+# way.  When a proxy is used the proxy does the final resolution, so we can only check the addresses
+# that the host resolves to for us.
+
+
+class OutboundError(RuntimeError):
+    """Raised when an outbound request fails or is not attempted."""
+
+
+class CircuitOpen(OutboundError):
+    """Raised when the destination's circuit breaker is open."""
+
+
+class RateLimited(OutboundError):
+    """Raised when the destination's outbound rate limit has been reached."""
+
+
+class UnsafeDestination(OutboundError):
+    """Raised when a `public_only` request would connect to a non-public address."""
+
+
+class _Destination:
+    def __init__(self):
+        self.failures = 0
+        self.open_until = None
+        self.trial = False
+        self.recent = collections.deque()
+
+
+_lock = threading.Lock()
+_destinations = {}
+_opener = None  # (proxy url, opener)
+
+
+def _proxy_opener(proxy):
+    url = urllib.parse.urlsplit(proxy)
+    if url.scheme in ('http', 'https'):
+        return urllib.request.build_opener(
+            urllib.request.ProxyHandler({'http': proxy, 'https': proxy}), _RedirectHandler
+        )
+
+    try:
+        import socks
+        import sockshandler
+    except ImportError:
+        raise OutboundError("SOCKS5 outbound proxies require the PySocks module")
+    return urllib.request.build_opener(
+        sockshandler.SocksiPyHandler(
+            socks.SOCKS5,
+            url.hostname,
+            url.port or 1080,
+            rdns=url.scheme == 'socks5h',
+            username=urllib.parse.unquote(url.username) if url.username else None,
+            password=urllib.parse.unquote(url.password) if url.password else None,
+        ),
+        _RedirectHandler,
+    )
+
+
+def is_public(addr):
+    """Returns true if IP address `addr` (a string) is a public unicast address."""
+    ip = ipaddress.ip_address(addr.split('%')[0])
+    if ip.version == 6:
+        embedded = ip.ipv4_mapped or ip.sixtofour or (ip.teredo[1] if ip.teredo else None)
+        if embedded is not None and not is_public(str(embedded)):
+            return False
+    return ip.is_global and not ip.is_multicast
+
+
+def public_address(host, port):
+    """
+    Resolves `host` and returns one of its addresses, provided that every address it resolves to is
+    public.  Raises UnsafeDestination otherwise.
+    """
+    try:
+        infos = socket.getaddrinfo(host, port, type=socket.SOCK_STREAM)
+    except socket.gaierror as e:
+        raise OutboundError(f"Unable to resolve {host}: {e}")
+    addrs = [info[4][0] for info in infos]
+    bad = [a for a in addrs if not is_public(a)]
+    if bad or not addrs:
+        metrics.incr('outbound.unsafe')
+        raise UnsafeDestination(f"{host} resolves to non-public address {bad[0] if bad else None}")
+    return addrs[0]
+
+
+class _PublicHTTPConnection(http.client.HTTPConnection):
+    def connect(self):
+        self.sock = socket.create_connection(
+            (public_address(self.host, self.port), self.port), self.timeout, self.source_address
+        )
+
+
+class _PublicHTTPSConnection(http.client.HTTPSConnection):
+    def connect(self):
+        sock = socket.create_connection(
+            (public_address(self.host, self.port), self.port), self.timeout, self.source_address
+        )
+        self.sock = self._context.wrap_socket(sock, server_hostname=self.host)
+
+
+class _PublicHTTPHandler(urllib.request.HTTPHandler):
+    def http_open(self, req):
+        return self.do_open(_PublicHTTPConnection, req)
+
+
+class _PublicHTTPSHandler(urllib.request.HTTPSHandler):
+    def https_open(self, req):
+        return self.do_open(_PublicHTTPSConnection, req, context=self._context)
+
+
+class _RedirectHandler(urllib.request.HTTPRedirectHandler):
+    # Checks the redirect targets of `public_only` requests (and marks the redirected request as
+    # `public_only`, too).  Without a proxy the pinned connection classes also check the addresses
+    # when connecting, so this only needs to check the scheme.
+    def redirect_request(self, req, fp, code, msg, headers, newurl):
+        public_only = getattr(req, 'sogs_public_only', False)
+        if public_only:
+            _check_url(newurl, resolve=bool(config.OUTBOUND_PROXY))
+        new = super().redirect_request(req, fp, code, msg, headers, newurl)
+        if new is not None:
+            new.sogs_public_only = public_only
+        return new
+
+
+def _check_url(url, *, resolve):
+    url = urllib.parse.urlsplit(url)
+    if url.scheme not in ('http', 'https') or not url.hostname:
+        metrics.incr('outbound.unsafe')
+        raise UnsafeDestination(f"Refusing to fetch non-http(s) URL {url.geturl()}")
+    if resolve:
+        public_address(url.hostname, url.port or (443 if url.scheme == 'https' else 80))
+
+
+_public_opener = urllib.request.build_opener(
+    urllib.request.ProxyHandler({}), _PublicHTTPHandler, _PublicHTTPSHandler, _RedirectHandler
+)
+
+
+def _open(req, timeout, public_only):
+    global _opener
+    if public_only:
+        if not isinstance(req, urllib.request.Request):
+            req = urllib.request.Request(req)
+        req.sogs_public_only = True
+        _check_url(req.full_url, resolve=bool(config.OUTBOUND_PROXY))
+
+    if not config.OUTBOUND_PROXY:
+        if public_only:
+            return _public_opener.open(req, timeout=timeout)
+        return urllib.request.urlopen(req, timeout=timeout)
+    with _lock:
+        if _opener is None or _opener[0] != config.OUTBOUND_PROXY:
+            _opener = (config.OUTBOUND_PROXY, _proxy_opener(config.OUTBOUND_PROXY))
+        opener = _opener[1]
+    return opener.open(req, timeout=timeout)
+
+
+def destination(url):
+    """Returns the destination (host[:port]) of a URL, to which breakers and limits apply."""
+    return urllib.parse.urlsplit(url).netloc.lower()
+
+
+def _acquire(dest, now):
+    with _lock:
+        d = _destinations.setdefault(dest, _Destination())
+        if d.open_until is not None:
+            if now < d.open_until or d.trial:
+                metrics.incr('outbound.circuit_open')
+                raise CircuitOpen(f"Circuit breaker for {dest} is open")
+            d.trial = True  # Half-open: let this request through to test the destination
+
+        if config.OUTBOUND_RATE_LIMIT:
+            while d.recent and d.recent[0] <= now - config.OUTBOUND_RATE_INTERVAL:
+                d.recent.popleft()
+            if len(d.recent) >= config.OUTBOUND_RATE_LIMIT:
+                d.trial = False
+                metrics.incr('outbound.rate_limited')
+                raise RateLimited(f"Outbound rate limit for {dest} reached")
+            d.recent.append(now)
+
+
+def _record(dest, ok):
+    with _lock:
+        d = _destinations.setdefault(dest, _Destination())
+        d.trial = False
+        if ok is None:
+            return
+        if ok:
+            d.failures, d.open_until = 0, None
+            return
+        d.failures += 1
+        metrics.incr('outbound.failures')
+        if d.failures >= config.OUTBOUND_FAILURE_THRESHOLD:
+            if d.open_until is None:
+                metrics.incr('outbound.circuits_opened')
+            d.open_until = time.time() + config.OUTBOUND_COOLDOWN
+
+
+def fetch(req, *, timeout, limit=-1, public_only=False):
+    """
+    Performs the outbound request `req` (a URL or urllib.request.Request) with the given timeout,
+    via the configured proxy (if any), subject to the destination's circuit breaker and rate limit.
+    Returns the response body (only reading up to `limit` bytes, if given).  Raises OutboundError
+    (or one of its subclasses) if the request fails or is not attempted.
+
+    `public_only` should be given for URLs that do not come from the server configuration: it
+    raises UnsafeDestination rather than connecting to a non-public address (see the top of this
+    file).
+    """
+    url = req.full_url if isinstance(req, urllib.request.Request) else req
+    dest = destination(url)
+    _acquire(dest, time.time())
+
+    metrics.incr('outbound.requests')
+    started = time.perf_counter()
+    try:
+        with _open(req, timeout, public_only) as resp:
+            data = resp.read(limit)
+    except OutboundError:
+        _record(dest, None)  # Refused before connecting, so says nothing about the destination
+        raise
+    except urllib.error.HTTPError as e:
+        # The destination is up, so only server errors count against it:
+        _record(dest, e.code < 500)
+        raise OutboundError(f"Request to {dest} failed: {e}") from e
+    except Exception as e:
+        _record(dest, False)
+        raise OutboundError(f"Request to {dest} failed: {e}") from e
+    finally:
+        metrics.observe('outbound.time', time.perf_counter() - started)
+
+    _record(dest, True)
+    return data
+
+
+def status():
+    """
+    Returns a dict of destinations to their breaker state, a dict of `failures` (consecutive
+    failures) and `open` (true if requests to the destination are currently failing fast).
+    """
+    now = time.time()
+    with _lock:
+        return {
+            dest: {
+                'failures': d.failures,
+                'open': d.open_until is not None and (now < d.open_until or d.trial),
+            }
+            for dest, d in _destinations.items()
+        }
+
+
+def reset():
+    """Resets all breakers and rate limits, and the proxy connection handler."""
+    global _opener
+    with _lock:
+        _destinations.clear()
+        _opener = None
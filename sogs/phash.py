@@ -0,0 +1,117 @@
+from . import config, db
+from .db import query
+from .web import app
+
+import io
+import warnings
+from typing import Optional
+
+import PIL.Image
+
+# Perceptual hashing of uploaded images, used to recognize re-uploads of banned images even when
+# they have been re-encoded, resized, or lightly edited (which defeats exact content hashes).
+#
+# We use a 64-bit difference hash ("dHash"): the image is reduced to a 9x8 grayscale thumbnail, and
+# each bit of the hash records whether a pixel is brighter than its right-hand neighbour.  Similar
+# images produce hashes that differ in only a few bits, so two images are considered the same if
+# the Hamming distance between their hashes is at most [files] `image_ban_threshold`.
+
+if hasattr(PIL.Image, 'Resampling'):
+    LANCZOS = PIL.Image.Resampling.LANCZOS
+else:
+    LANCZOS = PIL.Image.LANCZOS
+
+# Don't attempt to hash images larger than this, to avoid decompression bombs
+MAX_PIXELS = 50_000_000
+
+
+def compute(content: bytes) -> Optional[str]:
+    """
+    Returns the perceptual hash (as 16 hex digits) of `content`, or None if it isn't an image that
+    we can decode.
+    """
+    try:
+        with warnings.catch_warnings():
+            warnings.simplefilter('ignore', PIL.Image.DecompressionBombWarning)
+            img = PIL.Image.open(io.BytesIO(content))
+            if img.width * img.height > MAX_PIXELS:
+                return None
+            # Lets JPEG decoding skip most of the work:
+            img.draft('L', (64, 64))
+            img = img.convert('L').resize((9, 8), LANCZOS)
+    except Exception:
+        return None
+
+    px = list(img.getdata())
+    h = 0
+    for y in range(8):
+        for x in range(8):
+            h = (h << 1) | (px[y * 9 + x] > px[y * 9 + x + 1])
+    return f'{h:016x}'
+
+
+def distance(a: str, b: str):
+    """Returns the Hamming distance between two perceptual hashes."""
+    return bin(int(a, 16) ^ int(b, 16)).count('1')
+
+
+def find_ban(phash: str):
+    """
+    Returns the id of an image ban matching the perceptual hash `phash` (i.e. within the configured
+    threshold), or None if the image isn't banned.
+    """
+    for id, banned in query("SELECT id, phash FROM image_bans"):
+        if distance(phash, banned) <= config.IMAGE_BAN_THRESHOLD:
+            return id
+    return None
+
+
+def ban(phash: str, *, by, room=None, reason=None):
+    """
+    Bans images matching the perceptual hash `phash` from being uploaded to any room.  `by` is the
+    User adding the ban, and `room` the Room in which the banned image was found, if applicable.
+    Returns the id of the ban.
+    """
+    ban_id = db.insert_and_get_pk(
+        """
+        INSERT INTO image_bans (phash, room, banned_by, reason)
+        VALUES (:h, :r, :u, :reason)
+        """,
+        "id",
+        h=phash,
+        r=room.id if room is not None else None,
+        u=by.id,
+        reason=reason,
+    )
+    app.logger.warning(
+        f"{by} banned image hash {phash}" + (f" (from {room})" if room is not None else "")
+    )
+    return ban_id
+
+
+def get_bans():
+    """Returns a list of dicts describing all current image bans, ordered by id."""
+    return [
+        {
+            'id': id,
+            'phash': phash,
+            'room': room,
+            'banned_by': banned_by,
+            'banned_at': banned_at,
+            'reason': reason,
+        }
+        for id, phash, room, banned_by, banned_at, reason in query(
+            """
+            SELECT image_bans.id, phash, rooms.token, users.session_id, banned_at, reason
+            FROM image_bans
+                LEFT JOIN rooms ON rooms.id = image_bans.room
+                LEFT JOIN users ON users.id = image_bans.banned_by
+            ORDER BY image_bans.id
+            """
+        )
+    ]
+
+
+def unban(ban_id: int):
+    """Removes an image ban.  Returns True if removed, False if there was no such ban."""
+    return query("DELETE FROM image_bans WHERE id = :id", id=ban_id).rowcount > 0
@@ -0,0 +1,35 @@
+from . import config
+
+# Profanity masking for the public, unauthenticated room previews (the room web page and Atom
+# feeds).  This only affects how message text is rendered in those previews: it does not affect
+# the stored messages or what is delivered to Session clients.
+
+_masker = None
+
+
+def masker():
+    """Returns the `better_profanity.Profanity` instance used for masking, loading it if needed."""
+    global _masker
+    if _masker is None:
+        from better_profanity import Profanity
+
+        m = Profanity()
+        words = config.PREVIEW_MASK_WORDS or config.PROFANITY_CUSTOM
+        if words:
+            m.load_censor_words_from_file(words)
+        else:
+            m.load_censor_words()
+        _masker = m
+    return _masker
+
+
+def mask(text):
+    """Returns `text` with any words from the preview masking list replaced with `****`."""
+    if not text:
+        return text
+    return masker().censor(text)
+
+
+def render(room, text):
+    """Returns `text` as it should be shown in public previews of `room`."""
+    return mask(text) if room.preview_mask else text
@@ -0,0 +1,106 @@
+from . import db, metrics
+from .db import query
+from .web import app
+
+from flask import request
+import re
+import time
+
+# Prometheus metrics export (see the /metrics endpoint in sogs.routes.admin): renders the server's
+# metrics in the Prometheus text exposition format.  This includes the in-process metrics of
+# sogs.metrics (which, with multiple workers, are those of the worker handling the scrape), request
+# counts and per-endpoint latency histograms recorded here, and server-wide values read from the
+# database: the message counts and file storage usage of each room.
+
+
+@app.before_request
+def start_request_timer():
+    request.environ.setdefault('sogs.request_start', time.perf_counter())
+
+
+@app.after_request
+def record_request(response):
+    """Counts the request, by endpoint and status code, and records its duration."""
+    started = request.environ.get('sogs.request_start')
+    endpoint = request.endpoint or 'none'
+    metrics.incr_labeled(
+        'http.requests',
+        {'endpoint': endpoint, 'method': request.method, 'status': str(response.status_code)},
+    )
+    if started is not None:
+        metrics.histogram(
+            'http.request.duration', time.perf_counter() - started, {'endpoint': endpoint}
+        )
+    return response
+
+
+def _name(name):
+    return 'sogs_' + re.sub('[^a-zA-Z0-9_]', '_', name)
+
+
+def _labels(labels):
+    if not labels:
+        return ''
+    escaped = (
+        (k, str(v).replace('\\', '\\\\').replace('"', '\\"').replace('\n', '\\n'))
+        for k, v in labels
+    )
+    return '{' + ','.join(f'{k}="{v}"' for k, v in escaped) + '}'
+
+
+def render():
+    """Returns the current metrics in the Prometheus text exposition format."""
+    lines = []
+
+    def metric(name, kind, samples):
+        lines.append(f'# TYPE {name} {kind}')
+        lines.extend(f'{name}{sfx}{_labels(labels)} {value}' for sfx, labels, value in samples)
+
+    m = metrics.snapshot()
+    for name, value in sorted(m['counters'].items()):
+        metric(_name(name) + '_total', 'counter', [('', (), value)])
+    for name, o in sorted(m['observations'].items()):
+        metric(_name(name), 'summary', [('_sum', (), o['total']), ('_count', (), o['count'])])
+        metric(_name(name) + '_max', 'gauge', [('', (), o['max'])])
+
+    lm = metrics.labeled_snapshot()
+    for name in sorted({n for n, _ in lm['counters']}):
+        samples = [('', lbls, v) for (n, lbls), v in sorted(lm['counters'].items()) if n == name]
+        metric(_name(name) + '_total', 'counter', samples)
+    for name in sorted({n for n, _ in lm['histograms']}):
+        samples = []
+        for (n, labels), h in sorted(lm['histograms'].items()):
+            if n != name:
+                continue
+            for bound, count in zip(metrics.BUCKETS, h['buckets']):
+                samples.append(('_bucket', labels + (('le', str(bound)),), count))
+            samples.append(('_bucket', labels + (('le', '+Inf'),), h['count']))
+            samples.append(('_sum', labels, h['sum']))
+            samples.append(('_count', labels, h['count']))
+        metric(_name(name) + '_seconds', 'histogram', samples)
+
+    metric('sogs_db_connections_checked_out', 'gauge', [('', (), db.pool_stats()['checked_out'])])
+
+    rooms = list(
+        query(
+            """
+            SELECT token,
+                (SELECT COUNT(*) FROM messages WHERE room = rooms.id) AS posted,
+                (SELECT COUNT(*) FROM messages WHERE room = rooms.id AND data IS NOT NULL),
+                (SELECT COALESCE(SUM(size), 0) FROM files
+                    WHERE room = rooms.id AND (expiry IS NULL OR expiry > :now))
+            FROM rooms ORDER BY token
+            """,
+            now=time.time(),
+        )
+    )
+    metric(
+        'sogs_room_messages_posted_total',
+        'counter',
+        [('', (('room', r[0]),), r[1]) for r in rooms],
+    )
+    metric('sogs_room_messages', 'gauge', [('', (('room', r[0]),), r[2]) for r in rooms])
+    metric('sogs_room_file_storage_bytes', 'gauge', [('', (('room', r[0]),), r[3]) for r in rooms])
+    metric('sogs_file_storage_bytes', 'gauge', [('', (), sum(r[3] for r in rooms))])
+
+    return '\n'.join(lines) + '\n'
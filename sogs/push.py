@@ -0,0 +1,206 @@
+from . import metrics
+from .db import query
+from .model.room import Room
+from .model.exc import NoSuchRoom
+
+from collections import deque
+import threading
+import time
+
+# Push notifications of room activity, so that clients can learn of new activity within a second
+# rather than having to poll frequently.  Clients connect to the server's OxenMQ listener (see
+# [omq].listen) and subscribe to rooms with the `room.subscribe` request; whenever a message in a
+# subscribed room is posted, edited, or deleted the mule sends the client a `notify.room` message
+# with the room token, the event, the affected message ids, the room's new message sequence number,
+# and the sequence number of the previous notification sent for the room (`prev_seqno`).  The
+# notification doesn't include the messages themselves: the client fetches them with the usual
+# /room/TOKEN/messages/since/SEQNO request.
+#
+# A client that receives a notification whose `prev_seqno` isn't the `seqno` it last saw (from the
+# previous notification, or from the subscribe reply) has missed something (e.g. because of a
+# dropped connection, or activity such as a reaction that isn't notified) and should catch up by
+# fetching messages since the seqno it last saw.  (`prev_seqno` is null if the server hasn't sent a
+# notification for the room since it started).  `HEAD /room/TOKEN` also returns the current seqno
+# cheaply.
+#
+# Push subscriptions are anonymous, and so are only permitted for rooms that are readable without
+# authentication.  Subscriptions expire after SUBSCRIPTION_TTL seconds unless renewed by repeating
+# the `room.subscribe` request, and are dropped when sending to the subscriber fails.
+#
+# Notifications are not sent directly from the message insertion path: they are queued for each
+# subscriber and sent from a mule timer (see `flush`), at most SEND_BURST per subscriber per flush.
+# Each subscriber's queue holds at most QUEUE_SIZE notifications: a subscriber that falls further
+# behind than that (e.g. one subscribed to many very busy rooms) is dropped entirely and sent a
+# single `notify.resync` message, after which it must fetch any missed activity and subscribe again.
+# Drops are counted in the `push.dropped` metric (of the mule process).
+
+SUBSCRIPTION_TTL = 3600
+
+MAX_SUBSCRIPTIONS = 50  # rooms per subscribe request
+
+QUEUE_SIZE = 200  # per subscriber
+
+SEND_BURST = 20  # per subscriber per flush
+
+# {room_token: {conn: expiry}}
+_subscriptions = {}
+# {room_token: seqno of the last notification or subscription reply}
+_last_seqno = {}
+# {conn: deque of pending notifications}
+_queues = {}
+# Dropped subscribers that still need to be sent a resync message
+_resync = set()
+_lock = threading.Lock()
+
+
+def subscribe(conn, tokens, now=None):
+    """
+    Subscribes `conn` (an opaque connection identifier) to the rooms with the given tokens, or
+    renews the existing subscriptions.  Returns a dict of each given room token to the room's
+    current message sequence number, or None if the room doesn't exist or isn't readable without
+    authentication (in which case there is no subscription).
+    """
+    if now is None:
+        now = time.time()
+    result = {}
+    for token in tokens[:MAX_SUBSCRIPTIONS]:
+        try:
+            room = Room(token=token)
+        except NoSuchRoom:
+            room = None
+        if room is None or not room.check_read():
+            result[token] = None
+            continue
+        with _lock:
+            _subscriptions.setdefault(room.token, {})[conn] = now + SUBSCRIPTION_TTL
+            _last_seqno.setdefault(room.token, room.message_sequence)
+        result[token] = room.message_sequence
+    return result
+
+
+def unsubscribe(conn, tokens=None):
+    """Removes the subscriptions of `conn` to the given rooms (or to all rooms, if omitted)."""
+    with _lock:
+        for token in list(_subscriptions if tokens is None else tokens):
+            subs = _subscriptions.get(token)
+            if subs is not None:
+                subs.pop(conn, None)
+                if not subs:
+                    del _subscriptions[token]
+
+
+def subscribers(token, now=None):
+    """Returns the (unexpired) subscribers of the given room, dropping expired subscriptions."""
+    if now is None:
+        now = time.time()
+    with _lock:
+        subs = _subscriptions.get(token)
+        if not subs:
+            return []
+        for conn in [c for c, expiry in subs.items() if expiry <= now]:
+            del subs[conn]
+        if not subs:
+            del _subscriptions[token]
+        return list(subs)
+
+
+def notifications(event, message_ids):
+    """
+    Returns a list of `(room_token, notification)` pairs for the rooms of the given messages, where
+    `notification` is the `notify.room` dict to send to the room's subscribers.
+    """
+    if not message_ids:
+        return []
+    rooms = {}
+    for token, seqno, msg_id in query(
+        """
+        SELECT rooms.token, rooms.message_sequence, messages.id
+        FROM messages JOIN rooms ON messages.room = rooms.id
+        WHERE messages.id IN :ids ORDER BY messages.id
+        """,
+        ids=tuple(message_ids),
+        bind_expanding=['ids'],
+    ):
+        n = rooms.setdefault(token, {'room': token, 'event': event, 'ids': [], 'seqno': seqno})
+        n['ids'].append(msg_id)
+    with _lock:
+        for token, n in rooms.items():
+            n['prev_seqno'] = _last_seqno.get(token)
+            _last_seqno[token] = max(n['seqno'], n['prev_seqno'] or 0)
+    return list(rooms.items())
+
+
+def enqueue(token, notification, now=None):
+    """
+    Queues `notification` for each current subscriber of room `token`, dropping (and scheduling a
+    resync message for) any subscriber whose queue is full.  Returns the number of subscribers the
+    notification was queued for.
+    """
+    queued = 0
+    dropped = []
+    for conn in subscribers(token, now=now):
+        with _lock:
+            q = _queues.setdefault(conn, deque())
+            if len(q) >= QUEUE_SIZE:
+                dropped.append(conn)
+                continue
+            q.append(notification)
+        queued += 1
+    for conn in dropped:
+        drop(conn, resync=True)
+    return queued
+
+
+def drop(conn, *, resync=False):
+    """
+    Removes all subscriptions and pending notifications of `conn`.  If `resync` is true then `conn`
+    will be sent a `notify.resync` message by the next `flush`.
+    """
+    unsubscribe(conn)
+    with _lock:
+        _queues.pop(conn, None)
+        if resync:
+            _resync.add(conn)
+    if resync:
+        metrics.incr('push.dropped')
+
+
+def flush(send):
+    """
+    Sends pending notifications by calling `send(conn, command, notification)` (with `notification`
+    None for `notify.resync`), sending at most SEND_BURST notifications per subscriber.  If `send`
+    raises then the subscriber is dropped.  Returns the number of notifications sent.
+    """
+    with _lock:
+        resync = list(_resync)
+        _resync.clear()
+        batches = {}
+        for conn, q in list(_queues.items()):
+            batches[conn] = [q.popleft() for _ in range(min(len(q), SEND_BURST))]
+            if not q:
+                del _queues[conn]
+
+    sent = 0
+    for conn in resync:
+        try:
+            send(conn, "notify.resync", None)
+        except Exception:
+            pass
+    for conn, batch in batches.items():
+        try:
+            for n in batch:
+                send(conn, "notify.room", n)
+                sent += 1
+        except Exception:
+            drop(conn)
+    metrics.incr('push.sent', sent)
+    return sent
+
+
+def reset():
+    """Drops all subscriptions.  Used by the test suite."""
+    with _lock:
+        _subscriptions.clear()
+        _last_seqno.clear()
+        _queues.clear()
+        _resync.clear()
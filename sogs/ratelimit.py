@@ -0,0 +1,141 @@
+from . import config, crypto
+from .hashing import blake2b
+
+import base64
+import hmac
+import os
+import threading
+import time
+
+# Rate limiting of unauthenticated reads.  Requests without user authentication can't be limited
+# per user, and limiting them per IP address means that clients behind a shared NAT (or a busy
+# proxy) starve each other.  Instead each such client is issued an anonymous rate token (returned in
+# the X-SOGS-Rate-Token response header, and as a cookie for browsers) which identifies the client
+# for rate limiting purposes when sent back on subsequent requests.  Tokens are authenticated with
+# a server key, but carry no information beyond a random id and an expiry.
+#
+# Since a client could simply discard its token to obtain a fresh limit, the issuing of new tokens
+# is itself limited per IP address; requests from an address that has exhausted its token issuing
+# limit are rate limited per address instead.
+#
+# Posting and file uploads are separately throttled per client (see `take`) with token buckets,
+# which allow a client a short burst of activity but limit its sustained rate.  These clients are
+# keyed by their authenticated Session ID (or by IP address for requests without one).
+#
+# As with presence tracking, limits are tracked in the memory of each worker process, and so with
+# multiple uwsgi workers the effective limit is somewhat higher than configured.
+
+TOKEN_HEADER = 'X-SOGS-Rate-Token'
+TOKEN_COOKIE = 'sogs_rate_token'
+
+# How long an issued rate token remains valid, in seconds
+TOKEN_LIFETIME = 86400
+
+_lock = threading.Lock()
+# key -> [window start, count, interval]
+_windows = {}
+_last_prune = 0.0
+# key -> [tokens, last update, refill interval]
+_buckets = {}
+_last_bucket_prune = 0.0
+
+
+def enabled():
+    return config.ANON_READ_LIMIT > 0
+
+
+def allow(key, limit: int, interval: float, *, now=None):
+    """
+    Counts a request against the fixed-window limit of `limit` requests per `interval` seconds for
+    `key`.  Returns None if allowed, otherwise the number of seconds until the limit resets.
+    """
+    global _last_prune
+    if now is None:
+        now = time.time()
+    with _lock:
+        if now - _last_prune >= interval:
+            # Windows of different limits can have different intervals, so each expires by its own:
+            for k in [k for k, w in _windows.items() if now - w[0] >= w[2]]:
+                del _windows[k]
+            _last_prune = now
+
+        if limit <= 0:
+            return interval
+
+        w = _windows.get(key)
+        if w is None or now - w[0] >= interval:
+            _windows[key] = [now, 1, interval]
+            return None
+        if w[1] >= limit:
+            return w[0] + interval - now
+        w[1] += 1
+        return None
+
+
+def take(key, burst: int, interval: float, *, now=None):
+    """
+    Takes a token from the token bucket for `key`, which holds up to `burst` tokens and refills at
+    a rate of `burst` tokens per `interval` seconds.  Returns None if a token was available,
+    otherwise the number of seconds until one will be.
+    """
+    global _last_bucket_prune
+    if now is None:
+        now = time.time()
+    rate = burst / interval if burst > 0 else 0
+    with _lock:
+        if now - _last_bucket_prune >= interval:
+            # Buckets untouched for a full interval are full again, and so don't need tracking:
+            for k in [k for k, b in _buckets.items() if now - b[1] >= b[2]]:
+                del _buckets[k]
+            _last_bucket_prune = now
+
+        if burst <= 0:
+            return interval
+
+        b = _buckets.get(key)
+        tokens = burst if b is None else min(burst, b[0] + (now - b[1]) * rate)
+        if tokens < 1:
+            _buckets[key] = [tokens, now, interval]
+            return (1 - tokens) / rate
+        _buckets[key] = [tokens - 1, now, interval]
+        return None
+
+
+def reset():
+    """Clears all tracked rate limits."""
+    with _lock:
+        _windows.clear()
+        _buckets.clear()
+
+
+def _token_mac(nonce: bytes, expiry: bytes):
+    return blake2b(
+        (nonce, expiry, crypto.server_signkey.encode()), digest_size=16, key=b'sogsratetoken'
+    )
+
+
+def issue_token():
+    """Returns a new (token id, token) tuple for a new anonymous client."""
+    nonce = os.urandom(16)
+    expiry = int(time.time() + TOKEN_LIFETIME).to_bytes(8, 'big')
+    token = base64.urlsafe_b64encode(nonce + expiry + _token_mac(nonce, expiry)).rstrip(b'=')
+    return nonce.hex(), token.decode()
+
+
+def verify_token(token):
+    """
+    Returns the token id of a rate token previously returned by `issue_token`, or None if the token
+    is invalid or expired.
+    """
+    try:
+        raw = base64.urlsafe_b64decode(token + '=' * (-len(token) % 4))
+    except Exception:
+        return None
+    if len(raw) != 40:
+        return None
+    nonce, expiry, mac = raw[0:16], raw[16:24], raw[24:40]
+    if not hmac.compare_digest(mac, _token_mac(nonce, expiry)):
+        return None
+    if int.from_bytes(expiry, 'big') < time.time():
+        return None
+    return nonce.hex()
@@ -0,0 +1,73 @@
+import logging
+import re
+
+# Redaction of secrets from log output and error responses.  Log records (at INFO level and above;
+# debug logging, which has to be explicitly enabled, is left intact for troubleshooting) and the
+# error messages of model exceptions returned to clients are passed through `redact`, which replaces
+# anything that looks like an auth token, signature, or key, and the query parameters (which can
+# include message data) that database errors include in their messages.  Session ids (66 hex
+# digits) are public and are not redacted.
+
+REDACTED = '<redacted>'
+
+_PATTERNS = (
+    # SQLAlchemy errors include the statement's bound parameters, e.g. message data and signatures:
+    (re.compile(r'\[parameters: [^\n]*\]'), f'[parameters: {REDACTED}]'),
+    # Authorization and secret-bearing headers:
+    (re.compile(r'(?i)\b(bearer\s+)[^\s,;\'"]+'), rf'\1{REDACTED}'),
+    (
+        re.compile(r'(?i)\b(X-SOGS-(?:Signature|Api-Key)["\']?\s*[:=]\s*["\']?)[^\s,;\'"]+'),
+        rf'\1{REDACTED}',
+    ),
+    # Raw 32- or 64-byte values (private keys, signatures, hashes) in hex:
+    (re.compile(r'\b(?:[0-9a-fA-F]{128}|[0-9a-fA-F]{64})\b'), REDACTED),
+    # Base64 (standard or url-safe) values of 32 or more bytes, such as signatures, legacy tokens,
+    # and API keys (requiring mixed case and a digit keeps this from matching words and paths):
+    (
+        re.compile(
+            r'(?<![\w+/-])(?=[\w+/-]*[0-9])(?=[\w+/-]*[a-z])(?=[\w+/-]*[A-Z])[\w+/-]{43,}=*'
+        ),
+        REDACTED,
+    ),
+)
+
+
+def redact(text: str):
+    """Returns `text` with any tokens, signatures, keys, and query parameters replaced."""
+    for pattern, repl in _PATTERNS:
+        text = pattern.sub(repl, text)
+    return text
+
+
+class RedactingFilter(logging.Filter):
+    """Logging filter that redacts the messages (and tracebacks) of records above DEBUG level."""
+
+    def filter(self, record):
+        if record.levelno > logging.DEBUG:
+            record.msg = redact(record.getMessage())
+            record.args = ()
+            if record.exc_info and not record.exc_text:
+                record.exc_text = logging.Formatter().formatException(record.exc_info)
+            if record.exc_text:
+                record.exc_text = redact(record.exc_text)
+        return True
+
+
+def install():
+    """
+    Installs redaction of all log records, by wrapping the log record factory.  (A filter on a
+    logger doesn't see the records of its child loggers, and a filter on a handler misses handlers
+    added later, so we redact the records themselves as they are created).
+    """
+    factory = logging.getLogRecordFactory()
+    if getattr(factory, 'redacting', False):
+        return
+    f = RedactingFilter()
+
+    def redacting_factory(*args, **kwargs):
+        record = factory(*args, **kwargs)
+        f.filter(record)
+        return record
+
+    redacting_factory.redacting = True
+    logging.setLogRecordFactory(redacting_factory)
@@ -0,0 +1,241 @@
+import time
+
+from . import config, db, journal, utils
+from .db import query
+from .model.exc import BadPermission, NoSuchRoom
+from .model.room import Room
+from .model.user import User, SystemUser
+from .web import app
+
+# Point-in-time recovery from the event journal (see sogs.journal).  Given a database restored from
+# an older backup, this replays the journalled events recorded after the backup was taken (and,
+# optionally, only up to a given time) to bring the database back to its state at that time.  As
+# replay passes each `room_checkpoint` event it verifies that the room's messages match the merkle
+# root recorded at that point.
+#
+# Uploaded files, reactions, and room settings are not journalled, and so are not recovered.
+
+
+def last_activity():
+    """
+    Returns the timestamp of the most recent message post, edit, or deletion in the database, which
+    is where replay starts by default (i.e. the approximate time the backup was taken).
+    """
+    posted, edited = query("SELECT MAX(posted), MAX(edited) FROM messages").first()
+    return max(posted or 0, edited or 0)
+
+
+class _Replayer:
+    # The journal events we know how to replay, each handled by the method of the same name
+    EVENTS = {
+        'message_posted',
+        'message_edited',
+        'messages_deleted',
+        'moderator_added',
+        'moderator_removed',
+        'user_banned',
+        'user_unbanned',
+        'permissions_changed',
+        'raid_mode_started',
+        'raid_mode_ended',
+        'room_archived',
+        'room_unarchived',
+    }
+
+    def __init__(self):
+        self.sysadmin = SystemUser()
+        self.rooms = {}
+
+    def room(self, token):
+        if token not in self.rooms:
+            self.rooms[token] = Room(token=token)
+        return self.rooms[token]
+
+    def message_posted(self, ev):
+        room = self.room(ev['room'])
+        if query("SELECT COUNT(*) FROM messages WHERE id = :m", m=ev['id']).first()[0]:
+            return False  # Already in the backup
+
+        data = utils.decode_base64(ev['data'])
+        whisper_to = ev.get('whisper_to')
+        query(
+            """
+            INSERT INTO messages
+                (id, room, "user", posted, data, data_size, signature, filtered, whisper,
+                whisper_mods, kind)
+            VALUES (:m, :r, :u, :posted, :data, :data_size, :signature, :filtered, :whisper,
+                :whisper_mods, :kind)
+            """,
+            m=ev['id'],
+            r=room.id,
+            u=User(session_id=ev['session_id']).id,
+            posted=ev['posted'],
+            data=utils.remove_session_message_padding(data),
+            data_size=len(data),
+            signature=utils.decode_base64(ev['signature']),
+            filtered=ev.get('filtered', False),
+            whisper=User(session_id=whisper_to).id if whisper_to else None,
+            whisper_mods=ev.get('whisper_mods', False),
+            kind=ev.get('kind', 'text'),
+        )
+        return True
+
+    def message_edited(self, ev):
+        data = utils.decode_base64(ev['data'])
+        return (
+            query(
+                """
+                UPDATE messages SET data = :data, data_size = :data_size, signature = :sig,
+                    data_dict = NULL
+                WHERE id = :m AND room = :r
+                """,
+                m=ev['id'],
+                r=self.room(ev['room']).id,
+                data=utils.remove_session_message_padding(data),
+                data_size=len(data),
+                sig=utils.decode_base64(ev['signature']),
+            ).rowcount
+            > 0
+        )
+
+    def messages_deleted(self, ev):
+        self.room(ev['room'])
+        query("DELETE FROM message_details WHERE id IN :ids", ids=ev['ids'], bind_expanding=['ids'])
+        return True
+
+    def moderator_added(self, ev):
+        user = User(session_id=ev['session_id'])
+        if ev['room'] is None:
+            user.set_moderator(added_by=self.sysadmin, admin=ev['admin'], visible=ev['visible'])
+        else:
+            self.room(ev['room']).set_moderator(
+                user, added_by=self.sysadmin, admin=ev['admin'], visible=ev['visible']
+            )
+        return True
+
+    def moderator_removed(self, ev):
+        user = User(session_id=ev['session_id'])
+        if ev['room'] is None:
+            user.remove_moderator(removed_by=self.sysadmin, remove_admin_only=ev['admin_only'])
+        else:
+            self.room(ev['room']).remove_moderator(
+                user, removed_by=self.sysadmin, remove_admin_only=ev['admin_only']
+            )
+        return True
+
+    def user_banned(self, ev):
+        user = User(session_id=ev['session_id'])
+        timeout = None
+        if ev['timeout']:
+            # Ban timeouts are relative to when the ban happened (and might already be over, in
+            # which case the scheduled unban is applied at the next cleanup):
+            timeout = max(ev['time'] + ev['timeout'] - time.time(), 0.001)
+        if ev['room'] is None:
+            user.ban(banned_by=self.sysadmin, timeout=timeout)
+        else:
+            self.room(ev['room']).ban_user(user, mod=self.sysadmin, timeout=timeout)
+        return True
+
+    def user_unbanned(self, ev):
+        user = User(session_id=ev['session_id'])
+        if ev['room'] is None:
+            user.unban(unbanned_by=self.sysadmin)
+        else:
+            self.room(ev['room']).unban_user(user, mod=self.sysadmin)
+        return True
+
+    def permissions_changed(self, ev):
+        perms = {p: ev[p] for p in ('read', 'accessible', 'write', 'upload') if p in ev}
+        self.room(ev['room']).set_permissions(
+            User(session_id=ev['session_id']), mod=self.sysadmin, **perms
+        )
+        return True
+
+    def raid_mode_started(self, ev):
+        query(
+            "UPDATE rooms SET raid_mode_until = :until WHERE id = :r",
+            r=self.room(ev['room']).id,
+            until=ev['until'],
+        )
+        return True
+
+    def raid_mode_ended(self, ev):
+        query("UPDATE rooms SET raid_mode_until = NULL WHERE id = :r", r=self.room(ev['room']).id)
+        return True
+
+    def room_archived(self, ev):
+        query(
+            "UPDATE rooms SET archived = :t WHERE id = :r AND archived IS NULL",
+            r=self.room(ev['room']).id,
+            t=ev['time'],
+        )
+        return True
+
+    def room_unarchived(self, ev):
+        query(
+            "UPDATE rooms SET archived = NULL, unarchived = :t WHERE id = :r",
+            r=self.room(ev['room']).id,
+            t=ev['time'],
+        )
+        return True
+
+
+def replay(path, *, since=None, until=None):
+    """
+    Replays the events of the journal at `path` (see journal.read_events) recorded after timestamp
+    `since` (default: the last_activity() of the database) and, if given, no later than timestamp
+    `until`.
+
+    Returns a dict of: `replayed` (the number of events applied); `skipped` (events that didn't
+    apply, e.g. posts already present in the database); `verified` (the number of room checkpoints
+    that matched); and `mismatched` (a list of dicts of `room`, `time`, `expected`, and `actual`
+    for each room checkpoint that did not match).
+    """
+    if since is None:
+        since = last_activity()
+
+    replayer = _Replayer()
+    result = {'replayed': 0, 'skipped': 0, 'verified': 0, 'mismatched': []}
+
+    # Don't journal (or send to event hooks) the changes we make while replaying:
+    journal_path, config.JOURNAL_PATH = config.JOURNAL_PATH, None
+    hook_urls, config.EVENT_HOOK_URLS = config.EVENT_HOOK_URLS, []
+    try:
+        for ev in journal.read_events(path):
+            t = ev.get('time', 0)
+            if t <= since:
+                continue
+            if until is not None and t > until:
+                break
+
+            if ev['event'] == 'room_checkpoint':
+                try:
+                    actual = journal.room_root(replayer.room(ev['room']))
+                except NoSuchRoom:
+                    continue
+                if actual == ev['root']:
+                    result['verified'] += 1
+                else:
+                    app.logger.warning(
+                        f"Room {ev['room']} does not match the journal checkpoint at {t}"
+                    )
+                    result['mismatched'].append(
+                        {'room': ev['room'], 'time': t, 'expected': ev['root'], 'actual': actual}
+                    )
+                continue
+
+            applied = False
+            if ev['event'] not in _Replayer.EVENTS:
+                app.logger.warning(f"Skipping unknown journal event type {ev['event']}")
+            else:
+                try:
+                    with db.transaction():
+                        applied = getattr(replayer, ev['event'])(ev)
+                except (NoSuchRoom, BadPermission) as e:
+                    app.logger.warning(f"Unable to replay {ev['event']} event at {t}: {e}")
+            result['replayed' if applied else 'skipped'] += 1
+    finally:
+        config.JOURNAL_PATH = journal_path
+        config.EVENT_HOOK_URLS = hook_urls
+
+    return result
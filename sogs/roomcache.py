@@ -0,0 +1,90 @@
+from . import config, metrics
+from .db import query
+
+import collections
+import threading
+
+# In-memory caching of the hottest room read paths: every client polling a large room requests its
+# room details and (when first opening it) its most recent messages, so the same queries are made
+# over and over with the same results.
+#
+# - [rooms] `message_cache` keeps each room's most recent messages (including whispers, which are
+#   filtered per request).  The cache is keyed by the room's `message_sequence`, which the database
+#   triggers increment on every new post, edit, deletion, and reaction change, so it is replaced
+#   as soon as anything in the room changes, no matter which worker made the change.
+#
+# - [rooms] `info_cache` keeps the room's moderators, pinned messages, and tags, keyed by the
+#   room's `info_updates` (which is likewise incremented whenever any of these change).
+#
+# Each check of the cache key is a single primary key lookup of the room row, which is much cheaper
+# than the queries it replaces.  As with other caches, these live in the memory of each worker
+# process, and only the most recently used CACHED_ROOMS rooms are kept.
+
+CACHED_ROOMS = 100
+
+_lock = threading.Lock()
+# Rooms are keyed by id and creation time, as ids can be reused once a room is deleted.
+# (room id, created) => (message_sequence, [message rows])
+_messages = collections.OrderedDict()
+# (room id, created, name) => (info_updates, value)
+_info = collections.OrderedDict()
+
+
+def _lookup(cache, key, version, metric):
+    with _lock:
+        hit = cache.get(key)
+        if hit is not None and hit[0] == version:
+            cache.move_to_end(key)
+            metrics.incr(f'{metric}.cache_hits')
+            return hit
+    metrics.incr(f'{metric}.cache_misses')
+    return None
+
+
+def _store(cache, key, version, value, max_size):
+    with _lock:
+        cache[key] = (version, value)
+        cache.move_to_end(key)
+        while len(cache) > max_size:
+            cache.popitem(last=False)
+
+
+def recent_messages(room, fetch):
+    """
+    Returns the cached list of the [rooms].message_cache most recent message rows (as dicts, most
+    recent first) of Room `room`.  If the cache is out of date then `fetch(n)` is called to fetch
+    the `n` most recent rows to cache.
+    """
+    key = (room.id, room.created)
+    seq = query("SELECT message_sequence FROM rooms WHERE id = :r", r=room.id).first()[0]
+    hit = _lookup(_messages, key, seq, 'roomcache.messages')
+    if hit is not None:
+        return hit[1]
+    rows = fetch(config.ROOM_MESSAGE_CACHE)
+    _store(_messages, key, seq, rows, CACHED_ROOMS)
+    return rows
+
+
+def room_info(room, name: str, fetch):
+    """
+    Returns the cached room detail `name` (one of `mods`, `pinned`, or `tags`) of Room `room`,
+    calling `fetch()` to get it if not cached or out of date.  Returns `fetch()` without caching if
+    [rooms].info_cache is disabled.
+    """
+    if not config.ROOM_INFO_CACHE:
+        return fetch()
+    key = (room.id, room.created, name)
+    updates = query("SELECT info_updates FROM rooms WHERE id = :r", r=room.id).first()[0]
+    hit = _lookup(_info, key, updates, 'roomcache.info')
+    if hit is not None:
+        return hit[1]
+    value = fetch()
+    _store(_info, key, updates, value, 3 * CACHED_ROOMS)
+    return value
+
+
+def clear():
+    """Clears all cached room data."""
+    with _lock:
+        _messages.clear()
+        _info.clear()
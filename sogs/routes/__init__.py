@@ -1,17 +1,32 @@
 from ..web import app
 
 from .legacy import legacy as legacy_endpoints
+from .admin import admin as admin_endpoints
 from .general import general as general_endpoints
 from .onion_request import onion_request as onion_request_endpoints
 from .rooms import rooms as rooms_endpoints
 from .messages import messages as messages_endpoints
 from .users import users as users_endpoints
 from .dm import dm as dm_endpoints
+from .bridge import bridge as bridge_endpoints
 from .views import views as views_endpoints
+from .webhooks import webhooks as webhooks_endpoints
+from .api_keys import api_keys as api_keys_endpoints
+from .invites import invites as invites_endpoints
+from .reports import reports as reports_endpoints
+from .public import public as public_endpoints
 
 from . import exc  # noqa: F401
+from . import timestamps  # noqa: F401
 
+app.register_blueprint(admin_endpoints)
 app.register_blueprint(dm_endpoints)
+app.register_blueprint(bridge_endpoints)
+app.register_blueprint(webhooks_endpoints)
+app.register_blueprint(api_keys_endpoints)
+app.register_blueprint(invites_endpoints)
+app.register_blueprint(reports_endpoints)
+app.register_blueprint(public_endpoints)
 app.register_blueprint(rooms_endpoints)
 app.register_blueprint(messages_endpoints)
 app.register_blueprint(users_endpoints)
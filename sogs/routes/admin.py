@@ -0,0 +1,498 @@
+from .. import config, db, features, http, metrics, outbound, phash, prometheus, scheduler
+from .. import sigcache, upgrade, utils
+from ..model import api_key, pending_action
+from ..model.pending_action import PendingAction
+from ..model.room import Room, get_all_bans
+from ..web import app
+from . import auth
+
+from flask import abort, jsonify, g, Blueprint, request, Response
+import hmac
+import time
+
+# Server administration endpoints.  These are available only to global admins, except for the
+# pending action endpoints (which are available to the moderators who can confirm the actions) and
+# the Prometheus metrics endpoint (which uses its own token).
+
+
+admin = Blueprint('admin', __name__)
+
+
+@admin.get("/admin/jobs")
+@auth.global_admin_required
+def list_jobs():
+    """
+    Returns the background jobs that the server runs, and their schedules.
+
+    # Return value
+
+    A JSON object with job names as keys, and job details as values.  Each job details value is an
+    object containing keys:
+
+    - `description` — a short description of the job.
+    - `schedule` — the cron-style schedule of the job, as configured in the [schedule] config
+      section, or null if the job is disabled.
+    - `next_run` — the unix timestamp of the next scheduled run of the job, or null if disabled.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    """
+    return jsonify(scheduler.status())
+
+
+@admin.post("/admin/jobs/<name>")
+@auth.global_admin_required
+def run_job(name):
+    """
+    Runs a background job immediately, regardless of its schedule (disabled jobs can also be run
+    this way).  The job runs as part of the request, which does not return until the job completes.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing keys:
+
+    - `job` — the job name.
+    - `result` — the value returned by the job; this is job-specific (for example, the number of
+      files moved for the `cold_storage` job).  Null indicates that the job failed.
+    - `duration` — how long the job took to run, in seconds.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    - 404 Not Found — if there is no job with the given name.
+    """
+    if name not in scheduler.JOBS:
+        abort(http.NOT_FOUND)
+
+    app.logger.info(f"Running job {name} on demand for {g.user}")
+    started = time.time()
+    result = scheduler.run_job(name)
+    return jsonify({'job': name, 'result': result, 'duration': time.time() - started})
+
+
+@admin.get("/admin/metrics")
+@auth.global_admin_required
+def get_metrics():
+    """
+    Returns performance metrics of the worker process that handles the request.  (With multiple
+    worker processes each tracks its own metrics, so this only reflects one worker.)
+
+    # Return value
+
+    A JSON object containing keys:
+
+    - `auth` — request authentication statistics: `verifications` (the number of signatures
+      verified), `verify_time` (total seconds spent verifying signatures), `verify_rate`
+      (verifications per second of verification time), `cache_hits`, `cache_misses`, and `hit_rate`
+      of the pubkey cache, and `cached` (the number of cached pubkeys).
+    - `db_pool` — database connection pool statistics.
+    - `outbound` — the circuit breakers of outbound request destinations: a dict of each host to
+      its number of consecutive `failures` and whether the breaker is `open`.
+    - `counters` — all raw metrics counters, keyed by name.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    """
+    return jsonify(
+        {
+            'auth': sigcache.stats(),
+            'db_pool': db.pool_stats(),
+            'outbound': outbound.status(),
+            'counters': metrics.snapshot()['counters'],
+        }
+    )
+
+
+@admin.get("/metrics")
+def prometheus_metrics():
+    """
+    Returns the server's metrics in the Prometheus text exposition format, for scraping by
+    Prometheus.  This endpoint only exists if the server has a [admin].metrics_token configured,
+    which must be given as a bearer token (in an `Authorization: Bearer TOKEN` header).
+
+    # Return value
+
+    A `text/plain` body in the Prometheus exposition format, including:
+
+    - `sogs_http_requests_total` — request counts, labeled by `endpoint`, `method`, and `status`.
+    - `sogs_http_request_duration_seconds` — a histogram of request durations, by `endpoint`.
+    - `sogs_db_connections_checked_out` — the number of database connections currently in use.
+    - `sogs_room_messages_posted_total` and `sogs_room_messages` — the number of messages ever
+      posted to, and currently in, each room, labeled by `room`.
+    - `sogs_room_file_storage_bytes` and `sogs_file_storage_bytes` — the unexpired file storage
+      used by each room, and in total.
+    - the counters and observations of the [metrics endpoint](#get-adminmetrics).
+
+    # Error status codes
+
+    - 401 Unauthorized — if the bearer token is missing or incorrect.
+    - 404 Not Found — if no metrics token is configured.
+    """
+    if not config.METRICS_TOKEN:
+        abort(http.NOT_FOUND)
+    given = request.headers.get('Authorization', '')
+    if not hmac.compare_digest(given.encode(), f"Bearer {config.METRICS_TOKEN}".encode()):
+        abort(http.UNAUTHORIZED)
+    return Response(prometheus.render(), mimetype='text/plain; version=0.0.4')
+
+
+@admin.get("/admin/upgrade")
+@auth.global_admin_required
+def get_upgrade():
+    """
+    Returns whether a newer server version is available, according to the most recent check of the
+    release feed configured in [upgrade].feed_url.  (The check itself is the `upgrade_check` job.)
+
+    # Return value
+
+    A JSON object containing keys:
+
+    - `enabled` — true if a release feed is configured.
+    - `current` — the running server version.
+
+    and, if the release feed has been checked:
+
+    - `checked` — the unix timestamp of the most recent check.
+    - `latest` — the newest available version that is newer than the running version, or null if
+      the server is up to date.
+    - `security` — true if any of the newer versions include security fixes.
+    - `url` — the release notes URL of `latest`, or null if not available.
+    - `error` — why the most recent check failed, or null if it succeeded.  When a check fails the
+      other values are those of the last successful check, if any.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    """
+    return jsonify(upgrade.status())
+
+
+@admin.get("/admin/image_bans")
+@auth.global_admin_required
+def list_image_bans():
+    """
+    Lists the server's banned images (see [the image ban endpoint](#post-roomroomfilefileidban)).
+
+    # Return value
+
+    A JSON list of image ban objects, each containing keys:
+
+    - `id` — the numeric ban id.
+    - `phash` — the perceptual hash of the banned image, in hex.
+    - `room` — the token of the room in which the banned image was uploaded, or null if unknown.
+    - `banned_by` — the session id of the moderator who banned the image, or null if unknown.
+    - `banned_at` — the unix timestamp when the image was banned.
+    - `reason` — the reason given for the ban, or null if none was given.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    """
+    return jsonify(phash.get_bans())
+
+
+@admin.delete("/admin/image_ban/<int:ban_id>")
+@auth.global_admin_required
+def delete_image_ban(ban_id):
+    """
+    Removes an image ban, so that matching images may once again be uploaded.  Files already
+    quarantined because they matched the ban remain quarantined.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    - 404 Not Found — if there is no image ban with the given id.
+    """
+    if not phash.unban(ban_id):
+        abort(http.NOT_FOUND)
+    app.logger.info(f"{g.user} removed image ban {ban_id}")
+    return jsonify({})
+
+
+@admin.get("/admin/features")
+@auth.global_admin_required
+def list_features():
+    """
+    Returns the state of the server's [feature flags](#put-adminfeaturesname): which features are
+    enabled server-wide, and the flags set at runtime via this API.
+
+    # Return value
+
+    A JSON object containing keys:
+
+    - `features` — an object with each feature name (`reactions`, `dms`, `uploads`, `translate`,
+      and `reports`) as key and whether it is enabled server-wide as value.
+    - `flags` — a list of the runtime flags, each an object with keys `feature`, `room` (the room
+      token, or null for a server-wide flag), `enabled`, `updated` (the unix timestamp when the flag
+      was set), and `updated_by` (the session id of the admin who set it, or null).
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    """
+    return jsonify(
+        {
+            'features': {f: features.enabled(f) for f in features.FEATURES},
+            'flags': features.runtime_flags(),
+        }
+    )
+
+
+@admin.put("/admin/features/<name>")
+@auth.global_admin_required
+def set_feature_flag(name):
+    """
+    Enables or disables a feature at runtime, server-wide or in one room, overriding the [features]
+    and [room:TOKEN] config settings.  A disabled feature's endpoints return 404 Not Found, and
+    features disabled server-wide are omitted from [`/capabilities`](#get-capabilities).  A room's
+    flag takes precedence over the server-wide setting.  The change applies to all of the server's
+    workers, and persists until changed or [removed](#delete-adminfeaturesname).
+
+    # JSON parameters
+
+    - `enabled` — (required) true to enable the feature, false to disable it.
+    - `room` — optional token of the room to set the flag in.  If omitted the flag is server-wide.
+      (`dms` can only be set server-wide.)
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 400 Bad Request — if `enabled` is not a boolean, or the feature can't be set in a room.
+    - 403 Forbidden — if the invoking user is not a global admin.
+    - 404 Not Found — if `room` is given but there is no such room.
+    """
+    req = request.json
+    on = req.get('enabled') if isinstance(req, dict) else None
+    if not isinstance(on, bool):
+        app.logger.warning("Invalid feature flag request: `enabled` must be a boolean")
+        abort(http.BAD_REQUEST)
+    token = req.get('room')
+    if not isinstance(token, (str, type(None))):
+        abort(http.BAD_REQUEST)
+    room = Room(token=token) if token is not None else None
+
+    features.set_flag(name, on, room=room, by=g.user)
+    app.logger.info(
+        f"{g.user} {'enabled' if on else 'disabled'} {name}"
+        + (f" in room {room.token}" if room else " server-wide")
+    )
+    return jsonify({})
+
+
+@admin.delete("/admin/features/<name>")
+@utils.query_params('room')
+@auth.global_admin_required
+def delete_feature_flag(name):
+    """
+    Removes a runtime feature flag, so that the feature is again enabled or disabled as configured.
+
+    # Query Parameters
+
+    - `room` — the token of the room whose flag to remove.  If omitted the server-wide flag is
+      removed.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.  (It is not an error to
+    remove a flag that isn't set.)
+
+    # Error status codes
+
+    - 400 Bad Request — if `name` is not a feature that can be set (in a room, if `room` is given).
+    - 403 Forbidden — if the invoking user is not a global admin.
+    - 404 Not Found — if `room` is given but there is no such room.
+    """
+    token = request.args.get('room')
+    room = Room(token=token) if token else None
+    features.set_flag(name, None, room=room, by=g.user)
+    app.logger.info(f"{g.user} removed {name} flag" + (f" in room {room.token}" if room else ""))
+    return jsonify({})
+
+
+@admin.get("/admin/bans")
+@auth.global_admin_required
+def list_bans():
+    """
+    Lists all the bans on the server: global bans, and bans from individual rooms.
+
+    # Return value
+
+    A JSON list of ban objects, global bans first and then room bans ordered by room, each
+    containing keys:
+
+    - `session_id` — the session id of the banned user.
+    - `room` — the token of the room the user is banned from, or null for a global ban.
+    - `until` — the unix timestamp when the ban is scheduled to be lifted, or null if the ban is
+      permanent.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    """
+    return jsonify(get_all_bans())
+
+
+@admin.post("/admin/room/<Room:room>/clear")
+@auth.global_admin_required
+def clear_room(room):
+    """
+    Deletes all the posts of a room, and expires all of its attachments (other than the room
+    image), while leaving the room itself in place, e.g. to clean up after a spam attack.  The
+    deletions are seen by clients as ordinary message deletions.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing key:
+
+    - `deleted` — the number of posts deleted.
+
+    If the server requires confirmation of destructive actions by a second admin, this instead
+    returns a 202 status code with a JSON object containing the `pending_action` awaiting
+    confirmation; see [the pending actions endpoint](#get-adminpending).
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    """
+    pending = require_confirmation('clear_room', room=room)
+    if pending:
+        return pending
+    return jsonify({'deleted': room.clear_posts(deleter=g.user)})
+
+
+@admin.delete("/admin/api_keys")
+@utils.query_params('room')
+@auth.global_admin_required
+def revoke_api_keys():
+    """
+    Revokes all room API keys, e.g. after a suspected leak.  Requests made with a revoked key fail
+    with a 401 Unauthorized error.
+
+    # Query Parameters
+
+    - `room` — if given, only the API keys of the room with this token are revoked.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing key:
+
+    - `revoked` — the number of API keys revoked.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    - 404 Not Found — if `room` is given but there is no such room.
+    """
+    token = request.args.get('room')
+    room = Room(token=token) if token else None
+    revoked = api_key.revoke_all(room)
+    app.logger.warning(
+        f"{g.user} revoked {revoked} API keys" + (f" of room {room.token}" if room else "")
+    )
+    return jsonify({'revoked': revoked})
+
+
+def require_confirmation(action, **kwargs):
+    """
+    If the two-person rule is enabled, records a pending `action` (see
+    sogs.model.pending_action.PendingAction.create) requested by the current user and returns the
+    202 Accepted response to return from a route; otherwise returns None, in which case the caller
+    should carry out the action immediately.
+    """
+    if not pending_action.required():
+        return None
+    auth.require_user()
+    pending = PendingAction.create(action, requested_by=g.user, **kwargs)
+    return jsonify({'pending_action': pending.info()}), http.ACCEPTED
+
+
+@admin.get("/admin/pending")
+@auth.user_required
+def list_pending_actions():
+    """
+    Lists the destructive actions awaiting confirmation by a second moderator or admin.  These only
+    exist when the server has the two-person rule enabled, in which case requests to delete a room,
+    to delete all posts of a room or of a user, or to apply a global ban return a 202 status code
+    with a `pending_action` key containing the pending action (as described below) rather than
+    being carried out immediately.
+
+    Only actions requested by the invoking user, or that the invoking user has the authority to
+    confirm, are included.
+
+    # Return value
+
+    A JSON list of pending action objects, each containing keys:
+
+    - `id` — the numeric pending action id.
+    - `action` — `delete_room`, `clear_room` (deleting all posts of a room), `purge` (deleting all
+      posts of a user), or `global_ban`.
+    - `requested_by` — the session id of the user who requested the action.
+    - `requested` — the unix timestamp when the action was requested.
+    - `expires` — the unix timestamp when the action expires if not confirmed.
+    - `room` — the token of the room to be deleted or cleared, for `delete_room` and
+      `clear_room`.
+    - `session_id` — the session id of the user to be purged or banned, for `purge` and
+      `global_ban`.
+    - `rooms` — the tokens of the rooms from which posts will be deleted, for `purge`.
+    - `timeout` — the ban duration, in seconds, for a time-limited `global_ban`.
+    """
+    return jsonify([a.info() for a in pending_action.get_pending_actions(g.user)])
+
+
+@admin.post("/admin/pending/<int:action_id>")
+@auth.user_required
+def confirm_pending_action(action_id):
+    """
+    Confirms, and thereby carries out, a pending action.  The invoking user must have the authority
+    to perform the action (e.g. be a global admin, to confirm a room deletion), and must not be the
+    user who requested it.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing the result of the action:
+    for a `purge` this contains keys `total` (the number of deleted posts) and `rooms` (a dict of
+    room tokens to the number of posts deleted from each); for a `clear_room` it contains key
+    `deleted` (the number of deleted posts); for other actions it is empty.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user requested the action, or does not have the authority to
+      perform it.
+    - 404 Not Found — if there is no such pending action, or it has expired.
+    """
+    pending = PendingAction(id=action_id)
+    if not pending.visible_to(g.user):
+        abort(http.NOT_FOUND)
+    return jsonify(pending.confirm(g.user))
+
+
+@admin.delete("/admin/pending/<int:action_id>")
+@auth.user_required
+def cancel_pending_action(action_id):
+    """
+    Cancels a pending action.  This can be done by the user who requested it, or by anyone with the
+    authority to confirm it.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 404 Not Found — if there is no such pending action (or it has expired), or the invoking user
+      is not permitted to cancel it.
+    """
+    pending = PendingAction(id=action_id)
+    if not pending.visible_to(g.user):
+        abort(http.NOT_FOUND)
+    pending.cancel(g.user)
+    return jsonify({})
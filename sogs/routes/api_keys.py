@@ -0,0 +1,98 @@
+from .. import http
+from ..model.api_key import ApiKey, get_room_api_keys
+from ..web import app
+from . import auth
+
+from flask import abort, jsonify, g, Blueprint, request
+
+# Read-only room API keys: room admins can issue API keys that let integrations (dashboards,
+# archive viewers, etc.) read a room's messages and files without a Session identity, by passing
+# the key in an `X-SOGS-Api-Key` request header instead of the usual X-SOGS-* user authentication
+# headers.  Keys are scoped to a single room, only permit GET requests, and can be revoked at any
+# time.
+
+
+api_keys = Blueprint('api_keys', __name__)
+
+
+@api_keys.get("/room/<Room:room>/api_keys")
+@auth.admin_required
+def list_api_keys(room):
+    """
+    Lists the read-only API keys of a room.  Requires admin permission in the room.
+
+    # Return value
+
+    A JSON list of API key objects, each containing keys:
+
+    - `id` — the numeric API key id.
+    - `description` — the description given when the key was issued.
+    - `created` — unix timestamp when the key was issued.
+    - `last_used` — unix timestamp when the key was last used (accurate to about a minute), or null
+      if never used.
+
+    Note that the key itself is not included: it is only returned when issuing the key.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not an admin of the room.
+    """
+    return jsonify([key.info() for key in get_room_api_keys(room)])
+
+
+@api_keys.post("/room/<Room:room>/api_keys")
+@auth.admin_required
+def create_api_key(room):
+    """
+    Issues a new read-only API key for the room.  Requests that include this key in an
+    `X-SOGS-Api-Key` header (and no X-SOGS-* user authentication headers) may make GET requests of
+    the room's endpoints that require read (or accessible) permission, even if the room is not
+    publicly readable.  Requires admin permission in the room.
+
+    # JSON parameters
+
+    - `description` — (required) a description of the key, e.g. the integration that will use it.
+
+    # Return value
+
+    On success returns a 201 (Created) status code with the API key details, as returned by [the
+    API key list endpoint](#get-roomroomapi_keys), plus:
+
+    - `key` — the secret API key.  The key is not retrievable later, so must be recorded now.
+
+    # Error status codes
+
+    - 400 Bad Request — if `description` is missing or invalid.
+    - 403 Forbidden — if the invoking user is not an admin of the room.
+    """
+    req = request.json
+    description = req.get('description') if isinstance(req, dict) else None
+    if not isinstance(description, str) or not description:
+        app.logger.warning("Invalid API key creation: `description` must be a non-empty string")
+        abort(http.BAD_REQUEST)
+
+    api_key, key = ApiKey.create(room, g.user, description)
+    return jsonify({**api_key.info(), 'key': key}), http.CREATED
+
+
+@api_keys.delete("/room/<Room:room>/api_key/<int:key_id>")
+@auth.admin_required
+def revoke_api_key(room, key_id):
+    """
+    Revokes a read-only API key of the room, after which requests using the key are rejected.
+    Requires admin permission in the room.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not an admin of the room.
+    - 404 Not Found — if the room has no API key with the given id.
+    """
+    api_key = ApiKey(id=key_id)
+    if api_key.room_id != room.id:
+        abort(http.NOT_FOUND)
+    api_key.revoke(g.user)
+    return jsonify({})
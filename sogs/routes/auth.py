@@ -1,16 +1,19 @@
 from ..web import app
-from ..db import query
-from .. import config, crypto, http, utils
+from .. import config, crypto, http, metrics, ratelimit, sigcache, utils
+from ..model.api_key import ApiKey
+from ..model.exc import NoSuchApiKey
 from ..model.user import User
 from ..hashing import blake2b
+from .exc import rate_limited
 
 from flask import request, abort, Response, g
+from werkzeug.http import http_date
+import hmac
+import importlib
 import time
+import urllib.parse
 import nacl
-from nacl.signing import VerifyKey
 import nacl.exceptions
-import nacl.bindings as sodium
-import sqlalchemy.exc
 from functools import wraps
 
 # Authentication handling for incoming requests.
@@ -142,6 +145,47 @@ def blind_user_required(f):
     return blind_user_wrapper
 
 
+def require_bridge():
+    """Requires that the authenticated user is a configured bridge bot; aborts with 401
+    Unauthorized if there is no user in the request, and 403 Forbidden if the user is not a
+    bridge."""
+    require_user()
+    if not g.user.is_bridge:
+        abort_with_reason(http.FORBIDDEN, "This endpoint requires bridge permissions")
+
+
+def bridge_required(f):
+    """Decorator for an endpoint that requires a bridge bot user; this calls `require_bridge()` at
+    the beginning of the request."""
+
+    @wraps(f)
+    def required_bridge_wrapper(*args, **kwargs):
+        require_bridge()
+        return f(*args, **kwargs)
+
+    return required_bridge_wrapper
+
+
+def require_global_admin():
+    """Requires that the authenticated user is a global admin; aborts with 401 Unauthorized if
+    there is no user in the request, and 403 Forbidden if the user is not a global admin."""
+    require_user()
+    if not g.user.global_admin:
+        abort_with_reason(http.FORBIDDEN, "This endpoint requires global admin permissions")
+
+
+def global_admin_required(f):
+    """Decorator for an endpoint that requires a global admin user; this calls
+    `require_global_admin()` at the beginning of the request."""
+
+    @wraps(f)
+    def required_global_admin_wrapper(*args, **kwargs):
+        require_global_admin()
+        return f(*args, **kwargs)
+
+    return required_global_admin_wrapper
+
+
 def require_mod(room, *, admin=False):
     """Checks a room for moderator or admin permission; aborts with 401 Unauthorized if there is no
     user in the request, and 403 Forbidden if g.user does not have moderator (or admin, if
@@ -161,7 +205,7 @@ def accessible_required(f):
 
     @wraps(f)
     def required_accessible_wrapper(*args, room, **kwargs):
-        if not room.check_accessible(g.user):
+        if not room.check_accessible(g.user) and not api_key_allows(room):
             abort(http.NOT_FOUND)
         return f(*args, room=room, **kwargs)
 
@@ -175,7 +219,7 @@ def read_required(f):
 
     @wraps(f)
     def required_read_wrapper(*args, room, **kwargs):
-        if not room.check_read(g.user):
+        if not room.check_read(g.user) and not api_key_allows(room):
             abort_with_reason(
                 http.FORBIDDEN, "This endpoint requires room message 'read' permission"
             )
@@ -210,155 +254,510 @@ def admin_required(f):
     return required_admin_wrapper
 
 
+class AuthProvider:
+    """
+    Base class of request authentication providers.  A provider checks one kind of credentials
+    included in a request (such as signed request headers, or an API key) and, if they are valid,
+    sets the request's authenticated identity: g.user, to the model.user.User making the request,
+    and/or g.api_key, to a model.api_key.ApiKey.
+
+    Providers are registered (see `register_provider`) under their `name`, which is how they are
+    enabled in the [auth] config section, both server-wide and per endpoint group.  Deployments can
+    add their own providers (for example, for an invitation code gate) from a module listed in
+    [auth].plugins.
+    """
+
+    name = None
+
+    def present(self):
+        """
+        Returns true if the current request includes this provider's credentials (whether or not
+        they are valid).
+        """
+        raise NotImplementedError
+
+    def authenticate(self):
+        """
+        Authenticates the current request, which includes this provider's credentials (i.e.
+        `present()` returned true).  Sets g.user and/or g.api_key on success; aborts the request
+        (typically via `abort_with_reason`) if the credentials are not valid.
+        """
+        raise NotImplementedError
+
+
+class SessionAuth(AuthProvider):
+    """Authentication of X-SOGS-* request signatures made with the user's Session key."""
+
+    name = 'session'
+
+    headers = ('Pubkey', 'Nonce', 'Timestamp', 'Signature')
+
+    def present(self):
+        return any(request.headers.get(f"X-SOGS-{h}") for h in self.headers)
+
+    def authenticate(self):
+        """
+        Verifies the X-SOGS-* authentication headers.  If they are incomplete or unparseable (e.g.
+        wrong size nonce, or failure to decode) then this aborts with a 400 Bad Request; otherwise
+        it can abort with:
+        - 401 Unauthorized -- invalid signature, for example because of nonce reuse or signature
+          verification failure
+        - 425 Too Early -- if the timestamp is too far from the server time (more than 24h off), or
+          the client is attempting to reuse a nonce.
+        - 403 Forbidden -- if the user validated successfully but is globally banned from the
+          server.
+        In each case we write an error description as plain text body of the error response.
+        """
+        pk, nonce, ts_str, sig_in = (request.headers.get(f"X-SOGS-{h}") for h in self.headers)
+
+        missing = sum(x is None or x == '' for x in (pk, nonce, ts_str, sig_in))
+        if missing:
+            abort_with_reason(
+                http.BAD_REQUEST,
+                f"Invalid authentication headers: missing {missing}/4 required X-SOGS-* headers",
+            )
+
+        # Parameter input validation
+
+        try:
+            pk = utils.decode_hex_or_b64(pk, 33)
+        except Exception:
+            abort_with_reason(
+                http.BAD_REQUEST,
+                "Invalid authentication: X-SOGS-Pubkey is not a valid 66-hex digit id",
+            )
+
+        if pk[0] not in (0x00, 0x15):
+            abort_with_reason(
+                http.BAD_REQUEST,
+                "Invalid authentication: X-SOGS-Pubkey must be 00- or 15- prefixed",
+            )
+        blinded_pk = pk[0] == 0x15
+        pk = pk[1:]
+
+        session_id = sigcache.session_id(pk, blinded_pk)
+        if session_id is None:
+            abort_with_reason(
+                http.BAD_REQUEST,
+                "Invalid authentication: given X-SOGS-Pubkey is not a valid Ed25519 pubkey",
+            )
+        if not blinded_pk and config.REQUIRE_BLIND_KEYS:
+            abort_with_reason(
+                http.BAD_REQUEST,
+                "Invalid authentication: this server requires the use of blinded ids",
+            )
+
+        try:
+            nonce = utils.decode_hex_or_b64(nonce, 16)
+        except Exception:
+            abort_with_reason(
+                http.BAD_REQUEST,
+                "Invalid authentication: X-SOGS-Nonce must be 16 bytes (encoded as base64 or hex)",
+            )
+
+        try:
+            sig_in = utils.decode_hex_or_b64(sig_in, 64)
+        except Exception:
+            abort_with_reason(
+                http.BAD_REQUEST, "Invalid authentication: X-SOGS-Signature is not base64[88]"
+            )
+
+        try:
+            ts = int(ts_str)
+        except Exception:
+            abort_with_reason(
+                http.BAD_REQUEST,
+                "Invalid authentication: X-SOGS-Timestamp is not a valid timestamp",
+            )
+
+        # Parameter value validation
+
+        now = time.time()
+        if not now - 24 * 60 * 60 <= ts <= now + 24 * 60 * 60:
+            abort_with_reason(
+                http.TOO_EARLY,
+                "Invalid authentication: X-SOGS-Timestamp is too far from current time",
+            )
+
+        user = User(session_id=session_id, autovivify=True, touch=False)
+        if user.banned:
+            # If the user is banned don't even bother verifying the signature because we want to
+            # reject the request whether or not the signature validation passes.
+            abort_with_reason(http.FORBIDDEN, 'Banned', warn=False)
+
+        if not user.use_nonce(nonce):
+            abort_with_reason(
+                http.TOO_EARLY, "Invalid authentication: X-SOGS-Nonce cannot be reused"
+            )
+
+        # Signature validation
+
+        # Signature should be on:
+        #     SERVER_PUBKEY || NONCE || TIMESTAMP || METHOD || PATH || HBODY
+        to_verify = (
+            crypto.server_pubkey_bytes
+            + nonce
+            + ts_str.encode()
+            + request.method.encode()
+            + request.path.encode()
+        )
+
+        # Work around flask deficiency: we can't use request.full_path above because it *adds* a
+        # `?` even if there wasn't one in the original request.  So work around it by only
+        # appending if there is a query string and, officially, don't accept `?` followed by an
+        # empty query string in the auth request data (if you have no query string then don't
+        # append the ?).
+        if len(request.query_string):
+            to_verify = to_verify + b'?' + request.query_string
+
+        if len(request.data):
+            to_verify = to_verify + blake2b(request.data, digest_size=64)
+
+        try:
+            sigcache.verify(pk, to_verify, sig_in)
+        except nacl.exceptions.BadSignatureError:
+            abort_with_reason(
+                http.UNAUTHORIZED, "Invalid authentication: X-SOGS-Signature verification failed"
+            )
+
+        user.touch()
+        g.user = user
+
+
+class ApiKeyAuth(AuthProvider):
+    """Authentication with a read-only room API key (see handle_api_key_auth)."""
+
+    name = 'api_key'
+
+    def present(self):
+        return bool(request.headers.get('X-SOGS-Api-Key'))
+
+    def authenticate(self):
+        handle_api_key_auth(request.headers['X-SOGS-Api-Key'])
+
+
+providers = {}
+
+
+def register_provider(provider: AuthProvider):
+    """
+    Registers an authentication provider instance under its name, replacing any existing provider
+    with the same name.
+    """
+    providers[provider.name] = provider
+
+
+register_provider(SessionAuth())
+register_provider(ApiKeyAuth())
+
+for plugin in config.AUTH_PLUGINS:
+    importlib.import_module(plugin)
+
+for name in set().union(
+    config.AUTH_PROVIDERS, *(grp.get('providers', ()) for grp in config.AUTH_GROUPS.values())
+):
+    if name not in providers:
+        raise RuntimeError(f"Invalid config: unknown authentication provider '{name}' in [auth]")
+
+
+def group_providers(group):
+    """
+    Returns the names of the authentication providers accepted for the given endpoint group (i.e.
+    blueprint name), as configured in an [auth:GROUP] section or, by default, in [auth].providers.
+    """
+    return config.AUTH_GROUPS.get(group, {}).get('providers', config.AUTH_PROVIDERS)
+
+
+@app.before_request
+def require_client_cert():
+    """
+    Refuses requests to the endpoint groups of [admin].client_cert_groups that weren't made over a
+    connection presenting one of the TLS client certificates of [admin].client_certs (if set), as
+    reported by the reverse proxy in the [admin].client_cert_header header.  This is independent
+    of the request's authentication.  The fingerprint is taken from the outermost request, so that
+    the (client-supplied) headers of a subrequest can't provide it.
+    """
+    if 'client_cert' not in g:
+        fingerprint = request.headers.get(config.ADMIN_CLIENT_CERT_HEADER)
+        g.client_cert = fingerprint.replace(':', '').lower() if fingerprint else None
+
+    if not config.ADMIN_CLIENT_CERTS or request.blueprint not in config.ADMIN_CLIENT_CERT_GROUPS:
+        return
+    if g.client_cert not in config.ADMIN_CLIENT_CERTS:
+        abort_with_reason(http.FORBIDDEN, "This endpoint requires a TLS client certificate")
+
+
+# How long a content token (see `content_token()`) remains valid, in seconds
+CONTENT_TOKEN_LIFETIME = 60
+
+
+def content_host():
+    """Returns the host of the separate user content origin ([files].content_url), if set."""
+    if not config.FILES_CONTENT_URL:
+        return None
+    return urllib.parse.urlsplit(config.FILES_CONTENT_URL).netloc
+
+
+def _content_mac(path: str, expiry: int, session_id: str):
+    return blake2b(
+        (path.encode(), b'\n', str(expiry).encode(), b'\n', session_id.encode()),
+        digest_size=16,
+        key=crypto.server_signkey.encode(),
+        person=b'sogs.content',
+    ).hex()
+
+
+def content_token(path: str, user):
+    """
+    Returns a short-lived token authorizing `user` (None for an anonymous request) to download the
+    file at `path` from the user content origin.  Requests redirected there from the main address
+    carry this token (as the `content_token` query parameter) in place of their original request
+    signature, as a client following the redirect re-sends the signature headers, whose nonce has
+    already been used.
+    """
+    expiry = int(time.time()) + CONTENT_TOKEN_LIFETIME
+    session_id = user.session_id if user is not None else ''
+    return f"{expiry}.{session_id}.{_content_mac(path, expiry, session_id)}"
+
+
+def _content_token_user(token: str):
+    try:
+        expiry, session_id, mac = token.split('.')
+        expiry = int(expiry)
+    except ValueError:
+        abort_with_reason(http.UNAUTHORIZED, "Invalid content token")
+    if expiry < time.time() or not hmac.compare_digest(
+        mac, _content_mac(request.path, expiry, session_id)
+    ):
+        abort_with_reason(http.UNAUTHORIZED, "Invalid or expired content token")
+    return User(session_id=session_id, autovivify=False, touch=False) if session_id else None
+
+
 @app.before_request
 def handle_http_auth():
     """
-    Verifies authentication information from the request headers/body, if present.  If
-    authentication is present this sets g.user to the authenticated model.user.User (creating and/or
-    touching its last activity timestamp).  If there are no auth headers at all this sets g.user to
+    Verifies authentication information from the request headers/body, if present, using the
+    authentication providers accepted by the endpoint (see AuthProvider).  If authentication is
+    present this sets g.user to the authenticated model.user.User (creating and/or touching its last
+    activity timestamp) and/or g.api_key to the model.api_key.ApiKey used to make the request, and
+    g.auth_provider to the provider name.  If there are no auth headers at all this sets g.user to
     None.
 
-    If authentication headers are present but are unparseable (e.g. wrong size nonce, or failure to
-    decode, or one or more of the headers are missing) then this throws a flask abort with a 400 Bad
-    Request response.  Otherwise this can return a request error of:
-    - 401 Unauthorized -- invalid signature, for example because of nonce reuse or signature
-      verification failure
-    - 425 Too Early -- if the timestamp is too far from the server time (more than 24h off), or the
-      client is attempting to reuse a nonce.
-    - 403 Forbidden -- if the user validated successfully but is globally banned from the server.
-    In either case we write an error description as plain text body of the error response.
+    If credentials for more than one of the accepted providers are present this aborts with a 400
+    Bad Request; otherwise the provider is responsible for aborting if the credentials are not
+    valid (see e.g. SessionAuth.authenticate).
+
+    A subrequest (e.g. of a batch request) inherits the authentication of its parent request, but
+    aborts with a 401 Unauthorized if the parent's provider is not accepted by the subrequest's
+    endpoint.
+
+    A request to the user content origin that carries a `content_token` (see `content_token()`) is
+    authenticated as the user the token was issued to, ignoring any other credentials.
     """
 
     # If we already have a g.user then we are probably a subrequest and want to preserve it, unless
     # user_reauth has been specifically set (from sogs.routes.subrequest).
     if hasattr(g, 'user') and not g.user_reauth:
+        provider = g.get('auth_provider')
+        if provider is not None and provider not in group_providers(request.blueprint):
+            abort_with_reason(
+                http.UNAUTHORIZED,
+                f"Invalid authentication: {provider} authentication is not accepted here",
+            )
         return
 
     g.user_reauth = False
+    g.user = None
+    g.api_key = None
+    g.auth_provider = None
 
-    pk, nonce, ts_str, sig_in = (
-        request.headers.get(f"X-SOGS-{h}") for h in ('Pubkey', 'Nonce', 'Timestamp', 'Signature')
-    )
-
-    missing = sum(x is None or x == '' for x in (pk, nonce, ts_str, sig_in))
-    # If all are missing then we have no user
-    if missing == 4:
-        g.user = None
+    token = request.args.get('content_token')
+    if token is not None and content_host() is not None and request.host == content_host():
+        g.user = _content_token_user(token)
         return
 
-    if missing:
+    found = [
+        providers[name]
+        for name in sorted(group_providers(request.blueprint))
+        if name in providers and providers[name].present()
+    ]
+    if not found:
+        return
+    if len(found) > 1:
         abort_with_reason(
             http.BAD_REQUEST,
-            f"Invalid authentication headers: missing {missing}/4 required X-SOGS-* headers",
+            "Invalid authentication: cannot combine "
+            + " and ".join(p.name for p in found)
+            + " authentication",
         )
 
-    # Parameter input validation
+    found[0].authenticate()
+    g.auth_provider = found[0].name
 
-    try:
-        pk = utils.decode_hex_or_b64(pk, 33)
-    except Exception:
-        abort_with_reason(
-            http.BAD_REQUEST, "Invalid authentication: X-SOGS-Pubkey is not a valid 66-hex digit id"
-        )
 
-    if pk[0] not in (0x00, 0x15):
-        abort_with_reason(
-            http.BAD_REQUEST, "Invalid authentication: X-SOGS-Pubkey must be 00- or 15- prefixed"
-        )
-    blinded_pk = pk[0] == 0x15
-    pk = pk[1:]
+def legacy_tokens_accepted():
+    """
+    Returns true if legacy auth tokens (see sogs.routes.legacy) are currently accepted, i.e. if they
+    are enabled by [auth].legacy_tokens and the [auth].legacy_cutoff date (if any) hasn't passed.
+    """
+    return config.AUTH_LEGACY_TOKENS and (
+        config.AUTH_LEGACY_CUTOFF is None or time.time() < config.AUTH_LEGACY_CUTOFF
+    )
 
-    if not sodium.crypto_core_ed25519_is_valid_point(pk):
-        abort_with_reason(
-            http.BAD_REQUEST,
-            "Invalid authentication: given X-SOGS-Pubkey is not a valid Ed25519 pubkey",
-        )
 
-    pk = VerifyKey(pk)
-    if blinded_pk:
-        session_id = '15' + pk.encode().hex()
-    elif config.REQUIRE_BLIND_KEYS:
+def require_legacy_tokens():
+    """Aborts with a 401 Unauthorized if legacy auth tokens are not currently accepted."""
+    if not legacy_tokens_accepted():
         abort_with_reason(
-            http.BAD_REQUEST, "Invalid authentication: this server requires the use of blinded ids"
+            http.UNAUTHORIZED,
+            "Invalid authentication: legacy auth tokens are no longer accepted; "
+            "use signed requests",
+            warn=False,
         )
-    else:
-        try:
-            session_id = '05' + pk.to_curve25519_public_key().encode().hex()
-        except nacl.exceptions.RuntimeError:
-            abort_with_reason(
-                http.BAD_REQUEST,
-                "Invalid authentication: given X-SOGS-Pubkey is not a valid Ed25519 pubkey",
-            )
 
-    try:
-        nonce = utils.decode_hex_or_b64(nonce, 16)
-    except Exception:
-        abort_with_reason(
-            http.BAD_REQUEST,
-            "Invalid authentication: X-SOGS-Nonce must be 16 bytes (encoded as base64 or hex)",
-        )
 
-    try:
-        sig_in = utils.decode_hex_or_b64(sig_in, 64)
-    except Exception:
-        abort_with_reason(
-            http.BAD_REQUEST, "Invalid authentication: X-SOGS-Signature is not base64[88]"
-        )
+def auth_mode():
+    """
+    Returns the authentication mode of the current request: the name of the auth provider used,
+    `legacy_token` for a legacy endpoint request authenticated with a legacy auth token, or `none`.
+    """
+    if g.get('legacy_auth'):
+        return 'legacy_token'
+    return g.get('auth_provider') or 'none'
+
+
+@app.after_request
+def count_auth_mode(response):
+    """
+    Counts the request in the `auth.mode.*` metric of its authentication mode, and adds a Sunset
+    header with the legacy auth cutoff date to responses of requests using a legacy auth token.
+    """
+    mode = auth_mode()
+    metrics.incr(f'auth.mode.{mode}')
+    if mode == 'legacy_token' and config.AUTH_LEGACY_CUTOFF is not None:
+        response.headers['Sunset'] = http_date(config.AUTH_LEGACY_CUTOFF)
+    return response
 
+
+def handle_api_key_auth(key):
+    """
+    Authenticates a request made with a read-only room API key (given in the X-SOGS-Api-Key header)
+    rather than a user: sets g.api_key to the model.api_key.ApiKey and touches its last used
+    timestamp.  Such a request has no g.user, and only gains read access to the key's room via
+    `api_key_allows`.  Aborts with a 401 Unauthorized if the key is not valid (e.g. revoked).
+    """
     try:
-        ts = int(ts_str)
-    except Exception:
-        abort_with_reason(
-            http.BAD_REQUEST, "Invalid authentication: X-SOGS-Timestamp is not a valid timestamp"
-        )
+        g.api_key = ApiKey(key=key)
+    except NoSuchApiKey:
+        abort_with_reason(http.UNAUTHORIZED, "Invalid authentication: invalid X-SOGS-Api-Key")
+    g.api_key.touch()
 
-    # Parameter value validation
 
-    now = time.time()
-    if not now - 24 * 60 * 60 <= ts <= now + 24 * 60 * 60:
-        abort_with_reason(
-            http.TOO_EARLY, "Invalid authentication: X-SOGS-Timestamp is too far from current time"
-        )
+def api_key_allows(room):
+    """
+    Returns true if the current request was made with a read-only API key of `room` and is a
+    read-only (i.e. GET or HEAD) request.
+    """
+    key = g.get('api_key')
+    return key is not None and key.room_id == room.id and request.method in ('GET', 'HEAD')
 
-    user = User(session_id=session_id, autovivify=True, touch=False)
-    if user.banned:
-        # If the user is banned don't even bother verifying the signature because we want to reject
-        # the request whether or not the signature validation passes.
-        abort_with_reason(http.FORBIDDEN, 'Banned', warn=False)
 
-    try:
-        query('INSERT INTO user_request_nonces ("user", nonce) VALUES (:u, :n)', u=user.id, n=nonce)
-    except sqlalchemy.exc.IntegrityError:
-        abort_with_reason(http.TOO_EARLY, "Invalid authentication: X-SOGS-Nonce cannot be reused")
-
-    # Signature validation
-
-    # Signature should be on:
-    #     SERVER_PUBKEY || NONCE || TIMESTAMP || METHOD || PATH || HBODY
-    to_verify = (
-        crypto.server_pubkey_bytes
-        + nonce
-        + ts_str.encode()
-        + request.method.encode()
-        + request.path.encode()
+@app.before_request
+def limit_anonymous_reads():
+    """
+    Applies the per-client limit on unauthenticated read requests (see sogs.ratelimit), issuing the
+    client a new anonymous rate token if it didn't provide a valid one.  Aborts with a 429 Too Many
+    Requests error (see exc.rate_limited) if the client has exceeded the limit.
+    """
+    if not ratelimit.enabled() or request.method not in ('GET', 'HEAD'):
+        return
+    if g.user is not None or g.get('api_key') is not None:
+        return
+
+    interval = config.ANON_READ_INTERVAL
+    addr = utils.client_addr()
+    token = request.headers.get(ratelimit.TOKEN_HEADER) or request.cookies.get(
+        ratelimit.TOKEN_COOKIE
     )
+    token_id = ratelimit.verify_token(token) if token else None
+    if token_id is None and not ratelimit.allow(('issue', addr), config.ANON_TOKEN_LIMIT, interval):
+        token_id, token = ratelimit.issue_token()
+        request.environ['sogs.rate_token'] = token
+
+    key = ('token', token_id) if token_id is not None else ('addr', addr)
+    retry = ratelimit.allow(key, config.ANON_READ_LIMIT, interval)
+    if retry is not None:
+        abort(
+            rate_limited(
+                "Too many unauthenticated requests",
+                scope='anonymous_read',
+                limit=config.ANON_READ_LIMIT,
+                reset=time.time() + retry,
+            )
+        )
 
-    # Work around flask deficiency: we can't use request.full_path above because it *adds* a `?`
-    # even if there wasn't one in the original request.  So work around it by only appending if
-    # there is a query string and, officially, don't accept `?` followed by an empty query string in
-    # the auth request data (if you have no query string then don't append the ?).
-    if len(request.query_string):
-        to_verify = to_verify + b'?' + request.query_string
 
-    if len(request.data):
-        to_verify = to_verify + blake2b(request.data, digest_size=64)
+# Per-client token bucket limits (see sogs.ratelimit.take): scope => (burst, interval) config names
+throttles = {
+    'post': ('POST_RATE_LIMIT', 'POST_RATE_INTERVAL'),
+    'upload': ('UPLOAD_RATE_LIMIT', 'UPLOAD_RATE_INTERVAL'),
+}
 
-    try:
-        pk.verify(to_verify, sig_in)
-    except nacl.exceptions.BadSignatureError:
-        abort_with_reason(
-            http.UNAUTHORIZED, "Invalid authentication: X-SOGS-Signature verification failed"
+
+def throttle(scope, user=None):
+    """
+    Counts a request against the client's `scope` limit (one of the `throttles` keys), where the
+    client is `user` (defaulting to the request's authenticated user) or, for a request without a
+    user, the remote address.  Aborts with a 429 Too Many Requests error (see exc.rate_limited) if
+    the client has exceeded the limit.  Global moderators and bridges are not limited.
+    """
+    burst, interval = (getattr(config, c) for c in throttles[scope])
+    if burst <= 0:
+        return
+    if user is None:
+        user = g.user
+    if user is not None and (user.global_moderator or user.is_bridge):
+        return
+
+    key = (scope, user.session_id if user is not None else utils.client_addr())
+    retry = ratelimit.take(key, burst, interval)
+    if retry is not None:
+        app.logger.warning(f"Throttling {scope} request from {user or key[1]}")
+        abort(
+            rate_limited(
+                f"Too many {scope} requests",
+                scope=f"{scope}_rate",
+                limit=burst,
+                reset=time.time() + retry,
+            )
         )
 
-    user.touch()
-    g.user = user
+
+def throttled(scope):
+    """Decorator for an endpoint that applies the `throttle(scope)` limit before the endpoint."""
+
+    def decorator(f):
+        @wraps(f)
+        def throttled_wrapper(*args, **kwargs):
+            throttle(scope)
+            return f(*args, **kwargs)
+
+        return throttled_wrapper
+
+    return decorator
+
+
+@app.after_request
+def add_rate_token(response):
+    """Returns a newly issued anonymous rate token (if any) to the client."""
+    token = request.environ.get('sogs.rate_token')
+    if token is not None:
+        response.headers[ratelimit.TOKEN_HEADER] = token
+        response.set_cookie(
+            ratelimit.TOKEN_COOKIE,
+            token,
+            max_age=ratelimit.TOKEN_LIFETIME,
+            httponly=True,
+            samesite='Lax',
+        )
+    return response
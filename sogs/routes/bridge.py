@@ -0,0 +1,209 @@
+from .. import config, http, outbound, utils
+from ..model import exc
+from ..web import app
+from . import auth
+from .messages import qs_reactors
+
+from flask import abort, jsonify, g, Blueprint, request
+import posixpath
+import urllib.parse
+
+# Privileged endpoints for bridge bots (e.g. Matrix or IRC bridges) that relay messages between a
+# room and an external network.  Only users listed in the [bridge].bridge_ids config setting may
+# use these.
+
+
+bridge = Blueprint('bridge', __name__)
+
+
+def _bridge_event(room, msg, attributions):
+    ev = {**msg, 'event_id': f"{room.token}:{msg['id']}:{msg['seqno']}"}
+    if msg['id'] in attributions:
+        ev['bridged'] = attributions[msg['id']]
+    return ev
+
+
+@bridge.post("/bridge/room/<Room:room>/message")
+@auth.bridge_required
+def post_bridged_message(room):
+    """
+    Posts a message to a room on behalf of a user of an external network.
+
+    The message is posted (and signed) by the bridge bot, just like a regular message post, but the
+    server additionally records the external attribution of the message.  The bridge should include
+    the external author's display name in the message's profile so that Session clients show the
+    external user rather than the bridge bot.
+
+    # JSON parameters
+
+    Takes the same `data`, `signature`, and `files` parameters as [the regular message post
+    endpoint](#post-roomroommessage), plus:
+
+    - `network` — (required) identifier of the external network, e.g. `"matrix"`.
+    - `external_id` — (required) the external network's stable, unique id of the message.  A given
+      `network`/`external_id` pair can be posted only once, which makes it safe for the bridge to
+      retry a post.
+    - `external_user` — optional id of the external author, e.g. `"@user:example.org"`.
+    - `display_name` — optional display name of the external author.
+
+    # Return value
+
+    On success returns a 201 (Created) status code with the message details, as would be returned by
+    the regular message post endpoint, plus a `bridged` key containing the attribution details.
+
+    # Error status codes
+
+    - 400 Bad Request — if required parameters are missing or invalid.
+    - 403 Forbidden — if the invoking user is not a bridge, or does not have write permission in the
+      room.
+    - 409 Conflict — if a message with the given `network`/`external_id` has already been posted.
+      The response body is a JSON object containing the existing message's `id`.
+    """
+    req = request.json
+    if not isinstance(req, dict):
+        app.logger.warning(f"Invalid bridge post: expected a JSON object body, not {type(req)}")
+        abort(http.BAD_REQUEST)
+
+    network, external_id = req.get('network'), req.get('external_id')
+    external_user, display_name = req.get('external_user'), req.get('display_name')
+    for k, v in (('network', network), ('external_id', external_id)):
+        if not isinstance(v, str) or not v:
+            app.logger.warning(f"Invalid bridge post: `{k}` must be a non-empty string")
+            abort(http.BAD_REQUEST)
+    for k, v in (('external_user', external_user), ('display_name', display_name)):
+        if v is not None and not isinstance(v, str):
+            app.logger.warning(f"Invalid bridge post: `{k}` must be a string if given")
+            abort(http.BAD_REQUEST)
+
+    try:
+        msg = room.add_bridged_post(
+            g.user,
+            data=utils.decode_base64(req.get('data')),
+            sig=utils.decode_base64(req.get('signature')),
+            network=network,
+            external_id=external_id,
+            external_user=external_user,
+            display_name=display_name,
+            files=[int(x) for x in req.get('files', [])],
+        )
+    except exc.AlreadyExists as e:
+        return jsonify({'id': e.value}), http.CONFLICT
+
+    return utils.jsonify_with_base64(msg), http.CREATED
+
+
+@bridge.get("/bridge/room/<Room:room>/events/since/<int:seqno>")
+@utils.query_params('limit', 't', 'reactors')
+@auth.bridge_required
+@auth.read_required
+def bridge_events_since(room, seqno):
+    """
+    Retrieves the room event stream for a bridge.
+
+    This works like [the message polling endpoint](#get-roomroommessagessinceseqno) (and accepts
+    the same `limit`, `t`, and `reactors` query parameters), but additionally tags each event with:
+
+    - `event_id` — a stable, unique identifier of this event, suitable for use as a transaction or
+      deduplication id on the external network.  Each new version of a message (i.e. each edit or
+      deletion) has a distinct `event_id`.
+    - `bridged` — for messages posted via the [bridged post
+      endpoint](#post-bridgeroomroommessage), the external attribution details: `network`,
+      `external_id`, and (if provided when posting) `external_user` and `display_name`.  Omitted for
+      messages posted natively by Session users.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a bridge or does not have read access to the room.
+    """
+    g.user.update_room_activity(room)
+
+    limit = utils.get_int_param('limit', 100, min=1, max=256, truncate=True)
+    flags = request.args.get('t', '')
+
+    msgs = room.get_messages_for(
+        g.user,
+        limit=limit,
+        sequence=seqno,
+        reaction_updates='r' in flags,
+        reactor_limit=qs_reactors(),
+    )
+    attributions = room.bridged_attributions([m['id'] for m in msgs if 'data' in m])
+
+    return utils.jsonify_with_base64(
+        [_bridge_event(room, m, attributions) if 'data' in m else m for m in msgs]
+    )
+
+
+def fetch_media(url: str):
+    """
+    Fetches the media at a remote http/https URL for the media proxy endpoint.  Returns the content
+    bytes; aborts with an appropriate error code on failure.
+    """
+    try:
+        data = outbound.fetch(
+            url,
+            timeout=config.BRIDGE_MEDIA_TIMEOUT,
+            limit=config.UPLOAD_FILE_MAX_SIZE + 1,
+            public_only=True,
+        )
+    except outbound.UnsafeDestination as e:
+        app.logger.warning(f"Bridge media fetch of {url} refused: {e}")
+        abort(http.BAD_REQUEST)
+    except outbound.OutboundError as e:
+        app.logger.warning(f"Bridge media fetch of {url} failed: {e}")
+        abort(http.BAD_GATEWAY)
+
+    if len(data) > config.UPLOAD_FILE_MAX_SIZE:
+        app.logger.warning(f"Bridge media fetch of {url} failed: file too large")
+        abort(http.PAYLOAD_TOO_LARGE)
+
+    return data
+
+
+@bridge.post("/bridge/room/<Room:room>/file")
+@auth.bridge_required
+def bridge_upload_remote_file(room):
+    """
+    Proxies remote media from an external network into a room upload.
+
+    Rather than requiring the bridge to download and re-upload media, this instructs the server to
+    fetch the file from the given URL and store it as a regular room attachment; the returned id can
+    then be referenced in a subsequent bridged post.  The upload has the same one hour lifetime
+    before it must be referenced by a post as regular uploads.
+
+    # JSON parameters
+
+    - `url` — (required) the http or https URL of the media to fetch.
+    - `filename` — optional suggested filename for the attachment; if omitted the last component of
+      the URL path is used.
+
+    # Return value
+
+    On success returns a 201 (Created) status code with a JSON body containing the `id` of the new
+    upload, exactly as the [room file upload endpoint](#post-roomroomfile) does.
+
+    # Error status codes
+
+    - 400 Bad Request — if `url` is missing or not an http/https URL, or if its host is not a
+      public internet address (e.g. a loopback, private network, or link-local address).
+    - 403 Forbidden — if the bridge does not have upload permission in the room.
+    - 413 Payload Too Large — if the remote file exceeds the server's maximum upload size.
+    - 502 Bad Gateway — if the remote file could not be fetched.
+    """
+    req = request.json
+    url = req.get('url') if isinstance(req, dict) else None
+    if not isinstance(url, str) or urllib.parse.urlsplit(url).scheme not in ('http', 'https'):
+        app.logger.warning("Invalid bridge media request: an http/https `url` is required")
+        abort(http.BAD_REQUEST)
+
+    if not room.check_upload(g.user):
+        abort(http.FORBIDDEN)
+
+    filename = req.get('filename')
+    if not isinstance(filename, str) or not filename:
+        filename = urllib.parse.unquote(posixpath.basename(urllib.parse.urlsplit(url).path))
+
+    data = fetch_media(url)
+
+    id = room.upload_file(data, g.user, filename=filename or None, lifetime=3600.0)
+    return jsonify({"id": id}), http.CREATED
@@ -1,4 +1,4 @@
-from .. import db, http, utils
+from .. import config, db, features, http, utils
 from ..model.exc import NoSuchUser
 from ..model.user import User
 from ..model.message import Message
@@ -6,10 +6,17 @@ from ..web import app
 from . import auth
 
 from flask import abort, jsonify, g, Blueprint, request, Response
+import time
 
 dm = Blueprint('dm', __name__)
 
 
+@dm.before_request
+def check_enabled():
+    """All the DM endpoints return 404 Not Found when DMs are disabled (see sogs.features)."""
+    features.require('dms')
+
+
 def _serialize_message(msg, include_message=True):
     m = {
         "id": msg.id,
@@ -34,6 +41,7 @@ def _box(out: bool, *, since=None):
 
 
 @dm.get("/outbox")
+@utils.query_params('limit')
 @auth.blind_user_required
 def get_outbox():
     """
@@ -43,6 +51,7 @@ def get_outbox():
 
 
 @dm.get("/outbox/since/<int:msgid>")
+@utils.query_params('limit')
 @auth.blind_user_required
 def poll_outbox(msgid):
     """
@@ -52,6 +61,7 @@ def poll_outbox(msgid):
 
 
 @dm.get("/inbox")
+@utils.query_params('limit')
 @auth.blind_user_required
 def get_inbox():
     """
@@ -61,6 +71,7 @@ def get_inbox():
 
 
 @dm.get("/inbox/since/<int:msgid>")
+@utils.query_params('limit')
 @auth.blind_user_required
 def poll_inbox(msgid):
     """
@@ -90,8 +101,20 @@ def send_inbox(sid):
 
     400 Bad Request — if no message is provided.
 
+    403 Forbidden — if the recipient has [blocked](#put-inboxblockssid) the sender, and the server
+    is configured to reject such messages.  (If instead configured to silently drop them then this
+    request appears to succeed, but the returned details omit the message `id`, and the message is
+    not delivered).
+
     404 Not Found — if the given Session ID does not exist on this server, either because they have
     never accessed the server, or because they have been permanently banned.
+
+    429 Too Many Requests — if the recipient's inbox already holds the maximum number of messages
+    (see the [direct_messages] `inbox_max_messages` setting).  The Retry-After header indicates when
+    the oldest message in the inbox expires.
+
+    507 Insufficient Storage — if the message would take the recipient's inbox over its size limit
+    (the [direct_messages] `inbox_max_bytes` setting).
     """
     try:
         recip_user = User(session_id=sid, autovivify=False)
@@ -107,6 +130,22 @@ def send_inbox(sid):
         app.logger.warning("No message provided")
         abort(http.BAD_REQUEST)
 
+    if recip_user.blocks_dms_from(g.user):
+        if config.DM_BLOCKED_SENDS != 'drop':
+            abort(http.FORBIDDEN)
+        now = time.time()
+        return (
+            jsonify(
+                {
+                    "posted_at": now,
+                    "expires_at": now + config.DM_EXPIRY,
+                    "sender": g.user.session_id,
+                    "recipient": recip_user.session_id,
+                }
+            ),
+            http.CREATED,
+        )
+
     with db.transaction():
         msg = Message(data=utils.decode_base64(message), recip=recip_user, sender=g.user)
     return jsonify(_serialize_message(msg, include_message=False)), http.CREATED
@@ -127,3 +166,52 @@ def delete_inbox_items():
         ret['deleted'] = Message.delete_all(recip=g.user)
 
     return jsonify(ret), http.OK
+
+
+@dm.get("/inbox/blocks")
+@auth.blind_user_required
+def get_dm_blocks():
+    """
+    Retrieves the list of senders that the user has blocked from sending them direct messages.  The
+    list is stored on the server, so is shared by all of the user's devices.
+
+    # Return value
+
+    A JSON list of objects, in the order the senders were blocked, each containing keys:
+
+    - `session_id` — the blocked sender's session id.
+    - `blocked_at` — unix timestamp when the sender was blocked.
+    """
+    return jsonify(g.user.dm_blocks)
+
+
+@dm.put("/inbox/blocks/<AnySessionID:sid>")
+@auth.blind_user_required
+def block_dm_sender(sid):
+    """
+    Blocks the given session id from delivering direct messages to the user's inbox.  Messages
+    already in the inbox are not affected.  What happens to later messages from the blocked sender
+    depends on the server configuration: they are either refused, or silently discarded.
+
+    # Return value
+
+    A JSON object containing key:
+
+    - `blocked` — true if the sender was newly blocked, false if already blocked.
+    """
+    return jsonify({'blocked': g.user.block_dms(sid)})
+
+
+@dm.delete("/inbox/blocks/<AnySessionID:sid>")
+@auth.blind_user_required
+def unblock_dm_sender(sid):
+    """
+    Removes a block of the given session id, so that they can again send the user direct messages.
+
+    # Return value
+
+    A JSON object containing key:
+
+    - `unblocked` — true if the sender was blocked (and so has been unblocked), false if not.
+    """
+    return jsonify({'unblocked': g.user.unblock_dms(sid)})
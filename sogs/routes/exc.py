@@ -1,24 +1,211 @@
 from ..web import app
-from .. import http
+from .. import db, http, metrics, redact
 from ..model import exc
 
+from flask import g, jsonify, request
+from werkzeug.exceptions import HTTPException
+from http import HTTPStatus
+import json
+import secrets
+import sqlalchemy.exc
+import time
+import traceback
 
-# Map uncaught model exceptions into flask http exceptions
+
+# Map uncaught model exceptions into flask http exceptions.  Exception messages are passed through
+# redact.redact in case they include anything secret.
 @app.errorhandler(exc.NotFound)
 def abort_bad_room(e):
-    return str(e), http.NOT_FOUND
+    return redact.redact(str(e)), http.NOT_FOUND
 
 
 @app.errorhandler(exc.BadPermission)
 def abort_perm_denied(e):
-    return str(e), http.FORBIDDEN
+    return redact.redact(str(e)), http.FORBIDDEN
+
+
+@app.errorhandler(exc.ChallengeRequired)
+def abort_challenge_required(e):
+    return jsonify({'error': str(e), 'difficulty': e.difficulty}), http.PRECONDITION_REQUIRED
 
 
 @app.errorhandler(exc.PostRejected)
 def abort_post_rejected(e):
-    return str(e), http.TOO_MANY_REQUESTS
+    if isinstance(e, exc.PostRateLimited) and e.limit is not None:
+        return rate_limited(redact.redact(str(e)), scope=e.scope, limit=e.limit, reset=e.reset)
+    if isinstance(e, exc.QuotaExceeded):
+        return quota_exceeded(redact.redact(str(e)), scope=e.quota, limit=e.limit, used=e.used)
+    return redact.redact(str(e)), http.TOO_MANY_REQUESTS
+
+
+@app.errorhandler(exc.PostNotStored)
+def abort_post_not_stored(e):
+    return redact.redact(str(e)), http.SERVICE_UNAVAILABLE, {'Retry-After': '1'}
 
 
 @app.errorhandler(exc.InvalidData)
 def abort_invalid_data(e):
-    return str(e), http.BAD_REQUEST
+    return redact.redact(str(e)), http.BAD_REQUEST
+
+
+@app.errorhandler(exc.RoomLimitReached)
+def abort_room_limit(e):
+    return redact.redact(str(e)), http.CONFLICT
+
+
+@app.errorhandler(exc.PinLimitReached)
+def abort_pin_limit(e):
+    return redact.redact(str(e)), http.CONFLICT
+
+
+@app.errorhandler(exc.ReportClaimed)
+def abort_report_claimed(e):
+    return redact.redact(str(e)), http.CONFLICT
+
+
+@app.errorhandler(db.QueryTimeout)
+def abort_query_timeout(e):
+    return redact.redact(str(e)), http.SERVICE_UNAVAILABLE
+
+
+# The database being unreachable, locked, or out of space is a (probably) temporary server-side
+# condition rather than a bug, so we tell the client to retry rather than returning a 500.
+@app.errorhandler(sqlalchemy.exc.OperationalError)
+def abort_db_unavailable(e):
+    app.logger.error(f"Database error handling {request.method} {request.path}: {e}")
+    metrics.incr('http.db_errors')
+    return "Database temporarily unavailable", http.SERVICE_UNAVAILABLE, {'Retry-After': '5'}
+
+
+def rate_limited(error, *, scope, limit, reset, remaining=0):
+    """
+    Returns a 429 Too Many Requests response for a rate- or quota-limited request, so that clients
+    can back off appropriately.  The JSON body contains keys:
+
+    - `error` -- a description of the limit that was reached.
+    - `scope` -- what the limit applies to, e.g. `room_post` for the per-user room posting limit.
+    - `limit` -- the limit (e.g. a number of requests per interval, or a number of bytes).
+    - `remaining` -- how much of the limit remains.
+    - `reset` -- the unix timestamp at which the limit resets.
+    - `retry_after` -- the number of seconds until the limit resets.
+
+    The same values are also included in the standard Retry-After header and the RateLimit-Limit,
+    RateLimit-Remaining, and RateLimit-Reset (in seconds) headers.
+    """
+    retry_after = max(int(reset - time.time()) + 1, 1)
+    response = jsonify(
+        {
+            'error': error,
+            'scope': scope,
+            'limit': limit,
+            'remaining': remaining,
+            'reset': reset,
+            'retry_after': retry_after,
+        }
+    )
+    response.status_code = http.TOO_MANY_REQUESTS
+    response.headers['Retry-After'] = str(retry_after)
+    response.headers['RateLimit-Limit'] = str(limit)
+    response.headers['RateLimit-Remaining'] = str(remaining)
+    response.headers['RateLimit-Reset'] = str(retry_after)
+    return response
+
+
+def quota_exceeded(error, *, scope, limit, used):
+    """
+    Returns a 507 Insufficient Storage response for a request refused because of a storage quota.
+    The JSON body contains the same `error`, `scope`, `limit` and `remaining` keys as rate_limited
+    (with `scope` being `room_storage`, `server_storage`, `user_storage`, or `inbox_storage`), plus
+    `used`, the number of bytes of the quota currently used.  Storage quotas don't reset at a fixed
+    time (space is freed as files or messages expire or are deleted), so `reset` is always null.
+    """
+    response = jsonify(
+        {
+            'error': error,
+            'scope': scope,
+            'limit': limit,
+            'used': used,
+            'remaining': max(limit - used, 0),
+            'reset': None,
+        }
+    )
+    response.status_code = http.INSUFFICIENT_STORAGE
+    return response
+
+
+def request_id():
+    """
+    Returns the id of the current request, used to correlate error responses with the server logs.
+    Onion subrequests share the id of the request that contained them.
+    """
+    if 'request_id' not in g:
+        g.request_id = secrets.token_hex(8)
+    return g.request_id
+
+
+@app.after_request
+def add_request_id(response):
+    response.headers['X-Request-ID'] = request_id()
+    return response
+
+
+def wants_json_errors():
+    """
+    True if the request's Accept header prefers `application/json` over `text/plain`, in which
+    case error responses are given JSON bodies (see json_error_body).
+    """
+    accept = request.accept_mimetypes
+    return accept['application/json'] > accept['text/plain']
+
+
+@app.after_request
+def json_error_body(response):
+    """
+    Error responses (other than those that already have a JSON body) are plain text by default,
+    for compatibility with existing clients.  Clients that prefer JSON (see wants_json_errors)
+    instead get a JSON object body for every error, containing `status_code` (the HTTP status code)
+    and `error` (the error description).  Existing JSON error bodies (such as rate limit details)
+    get `status_code` added.
+    """
+    if response.status_code < 400 or response.direct_passthrough or not wants_json_errors():
+        return response
+
+    if response.is_json:
+        body = response.get_json(silent=True)
+        if isinstance(body, dict) and 'status_code' not in body:
+            body['status_code'] = response.status_code
+            response.set_data(json.dumps(body))
+        return response
+
+    try:
+        phrase = HTTPStatus(response.status_code).phrase
+    except ValueError:
+        phrase = "Error"
+    # Flask's own error pages (e.g. from `abort(404)`) are HTML, so we replace those with the
+    # status phrase:
+    error = phrase if response.mimetype == 'text/html' else response.get_data(as_text=True)
+    response.set_data(json.dumps({'status_code': response.status_code, 'error': error or phrase}))
+    response.mimetype = 'application/json'
+    return response
+
+
+# Catch-all for anything else: rather than letting an unexpected exception escape we log it (with
+# the traceback and request id) and return a structured error response.
+@app.errorhandler(Exception)
+def handle_unexpected_error(e):
+    if isinstance(e, HTTPException):
+        return e
+
+    from .auth import auth_mode  # (imported here because .auth imports us)
+
+    rid = request_id()
+    metrics.incr('http.unhandled_exceptions')
+    app.logger.error(
+        f"Unhandled exception in {request.method} {request.path} [{request.endpoint}] "
+        f"(request {rid}, auth {auth_mode()}):\n"
+        + "".join(traceback.format_exception(type(e), e, e.__traceback__))
+    )
+    return (
+        jsonify({'error': 'Internal server error', 'request_id': rid}),
+        http.INTERNAL_SERVER_ERROR,
+    )
@@ -1,9 +1,13 @@
 from ..web import app
 from ..model import capabilities
-from .. import http
+from .. import __version__, crypto, features, http
 from .. import utils
 from .subrequest import make_subrequest
 
+import functools
+import os
+import subprocess
+import time
 from flask import request, abort, jsonify, Blueprint
 
 # General purpose routes for things like capability retrieval and batching
@@ -11,19 +15,27 @@ from flask import request, abort, jsonify, Blueprint
 general = Blueprint('general', __name__)
 
 
+def current_capabilities():
+    """Returns the server's capabilities, less those of features disabled server-wide."""
+    return {c for c in capabilities if c not in features.FEATURES or features.enabled(c)}
+
+
 @general.get("/capabilities")
+@utils.query_params('required')
 def get_caps():
     """
     Return the list of server features/capabilities.  Optionally takes a required= parameter
     containing a comma-separated list of capabilites; if any are not satisfied we return a 412
     (Precondition Failed) response with missing requested capabilities in the `missing` key.
+    Capabilities of features that the server's operator has disabled are not included.
     """
 
-    res = {'capabilities': sorted(capabilities)}
+    caps = current_capabilities()
+    res = {'capabilities': sorted(caps)}
     needed = request.args.get('required')
     res_code = http.OK
     if needed is not None:
-        missing = [cap for cap in needed.split(',') if cap not in capabilities]
+        missing = [cap for cap in needed.split(',') if cap not in caps]
 
         if missing:
             res['missing'] = missing
@@ -32,6 +44,101 @@ def get_caps():
     return jsonify(res), res_code
 
 
+@functools.lru_cache(maxsize=None)
+def build_commit():
+    """
+    Returns the git commit hash of the running code, if running from a git checkout, otherwise
+    None.
+    """
+    try:
+        return subprocess.run(
+            ['git', 'rev-parse', 'HEAD'],
+            cwd=os.path.dirname(__file__),
+            capture_output=True,
+            check=True,
+            text=True,
+            timeout=5,
+        ).stdout.strip()
+    except Exception:
+        return None
+
+
+@general.get("/version")
+def get_version():
+    """
+    Returns version information about the running server, for identifying the exact build when
+    reporting issues, and for clients to decide which features to use.
+
+    # Return value
+
+    A dict containing:
+
+    - `version` — the SOGS version string, e.g. `"0.3.8"`.
+    - `commit` — the git commit hash of the running code, or null if not known (i.e. when not
+      running from a git checkout).
+    - `onion_request_versions` — list of supported onion request protocol versions, e.g. `[3, 4]`.
+    - `capabilities` — the list of server capabilities, as returned by
+      [`/capabilities`](#get-capabilities).
+    """
+    return jsonify(
+        {
+            'version': __version__,
+            'commit': build_commit(),
+            'onion_request_versions': [3, 4],
+            'capabilities': sorted(current_capabilities()),
+        }
+    )
+
+
+@general.get("/time")
+@utils.query_params('nonce')
+def get_time():
+    """
+    Returns the current server time, for clients to correct for local clock skew before making
+    requests that include a timestamp (such as the `X-SOGS-Timestamp` of authenticated requests,
+    which must be within 24 hours of the server's time).
+
+    # Query Parameters
+
+    - `nonce` — optional 16-byte value, encoded in hex or base64.  If given then the response is
+      signed by the server so that the time can be trusted; clients should use a new, random nonce
+      each time so that an old signed response cannot be replayed.
+
+    # Return value
+
+    A dict containing:
+
+    - `timestamp` — the current server time, in unix epoch seconds with sub-second precision.
+    - `timestamp_ms` — the same time, as integer unix epoch milliseconds.
+
+    If `nonce` is given then the dict also contains:
+
+    - `signature` — base64-encoded Ed25519 signature by the server's `ed25519_pubkey` of the bytes
+      `sogs.time` + NONCE + TIMESTAMP_MS, where NONCE is the decoded nonce and TIMESTAMP_MS is the
+      `timestamp_ms` value as an ASCII decimal string.
+    - `ed25519_pubkey` — the server's Ed25519 pubkey, in hex.
+
+    # Error status codes
+
+    - 400 Bad Request — if `nonce` is not a valid hex or base64 encoded 16-byte value.
+    """
+
+    now = time.time()
+    res = {'timestamp': now, 'timestamp_ms': int(now * 1000)}
+
+    nonce = request.args.get('nonce')
+    if nonce is not None:
+        try:
+            nonce = utils.decode_hex_or_b64(nonce, 16)
+        except Exception:
+            abort(http.BAD_REQUEST)
+        msg = b'sogs.time' + nonce + str(res['timestamp_ms']).encode()
+        res['signature'] = utils.encode_base64(crypto.server_signkey.sign(msg).signature)
+        res['ed25519_pubkey'] = crypto.server_verifykey.encode().hex()
+
+    return jsonify(res)
+
+
 batch_args = """
 """
 
@@ -0,0 +1,182 @@
+from .. import http
+from ..model.invite import Invite, get_room_invites
+from ..web import app
+from . import auth
+
+from flask import abort, jsonify, g, Blueprint, request
+
+# Room invite codes: room moderators can issue invite codes that grant read (and optionally write)
+# access to a room, typically one that is not publicly readable, to any user who redeems the code.
+# Codes can be limited to a number of uses and to a lifetime, and can be revoked at any time.
+
+
+invites = Blueprint('invites', __name__)
+
+
+@invites.get("/room/<Room:room>/invites")
+@auth.mod_required
+def list_invites(room):
+    """
+    Lists the invite codes of a room, including expired, used up, and revoked codes.  Requires
+    moderator permission in the room.
+
+    # Return value
+
+    A JSON list of invite objects, each containing keys:
+
+    - `id` — the numeric invite id.
+    - `write` — true if the invite grants write access as well as read access.
+    - `max_uses` — the number of users who may redeem the invite, or null if unlimited.
+    - `uses` — the number of users who have redeemed the invite.
+    - `created` — unix timestamp when the invite was issued.
+    - `expires` — unix timestamp when the invite expires, or null if it doesn't expire.
+    - `revoked` — unix timestamp when the invite was revoked, or null if not revoked.
+    - `usable` — true if the invite can still be redeemed by new users.
+
+    Note that the code itself is not included: it is only returned when issuing the invite.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a moderator of the room.
+    """
+    return jsonify([invite.info() for invite in get_room_invites(room)])
+
+
+@invites.post("/room/<Room:room>/invites")
+@auth.mod_required
+def create_invite(room):
+    """
+    Issues a new invite code for the room.  Requires moderator permission in the room.
+
+    # JSON parameters
+
+    - `write` — if false then the invite grants only read access; by default it also grants write
+      access.
+    - `max_uses` — the number of users who may redeem the invite.  If omitted or null there is no
+      limit.
+    - `lifetime` — how long, in seconds, until the invite expires.  If omitted or null the invite
+      doesn't expire.
+
+    # Return value
+
+    On success returns a 201 (Created) status code with the invite details, as returned by [the
+    invite list endpoint](#get-roomroominvites), plus:
+
+    - `code` — the secret invite code.  The code is not retrievable later, so must be recorded now.
+
+    # Error status codes
+
+    - 400 Bad Request — if one of the parameters is invalid.
+    - 403 Forbidden — if the invoking user is not a moderator of the room.
+    """
+    req = request.json if request.data else {}
+    if not isinstance(req, dict):
+        abort(http.BAD_REQUEST)
+    write, max_uses, lifetime = (req.get(k) for k in ('write', 'max_uses', 'lifetime'))
+    if (
+        not isinstance(write, (bool, type(None)))
+        or not isinstance(max_uses, (int, type(None)))
+        or isinstance(max_uses, bool)
+        or not isinstance(lifetime, (int, float, type(None)))
+        or isinstance(lifetime, bool)
+    ):
+        app.logger.warning("Invalid invite creation: invalid write, max_uses, or lifetime")
+        abort(http.BAD_REQUEST)
+
+    invite, code = Invite.create(
+        room, g.user, write=write is not False, max_uses=max_uses, lifetime=lifetime
+    )
+    return jsonify({**invite.info(), 'code': code}), http.CREATED
+
+
+@invites.delete("/room/<Room:room>/invite/<int:invite_id>")
+@auth.mod_required
+def revoke_invite(room, invite_id):
+    """
+    Revokes an invite code of the room, after which the code can no longer be redeemed.  Access
+    already granted to users who redeemed the code is not affected.  Requires moderator permission
+    in the room.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a moderator of the room.
+    - 404 Not Found — if the room has no invite with the given id.
+    """
+    invite = Invite(id=invite_id)
+    if invite.room_id != room.id:
+        abort(http.NOT_FOUND)
+    invite.revoke(g.user)
+    return jsonify({})
+
+
+@invites.get("/room/<Room:room>/invite/<int:invite_id>")
+@auth.mod_required
+def get_invite(room, invite_id):
+    """
+    Returns the details of an invite code of the room, including the users who have redeemed it.
+    Requires moderator permission in the room.
+
+    # Return value
+
+    The invite details, as returned by [the invite list endpoint](#get-roomroominvites), plus:
+
+    - `redeemed_by` — a list of the users who have redeemed the invite, in the order they redeemed
+      it, each an object with keys `session_id` and `redeemed` (the unix timestamp).
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a moderator of the room.
+    - 404 Not Found — if the room has no invite with the given id.
+    """
+    invite = Invite(id=invite_id)
+    if invite.room_id != room.id:
+        abort(http.NOT_FOUND)
+    return jsonify(
+        {
+            **invite.info(),
+            'redeemed_by': [
+                {'session_id': sid, 'redeemed': ts} for sid, ts in invite.redeemed_by()
+            ],
+        }
+    )
+
+
+@invites.post("/invite")
+@auth.user_required
+def redeem_invite():
+    """
+    Redeems a room invite code, granting the invoking user read access (and, unless the invite is
+    read-only, write access) to the invite's room.  Redeeming a code that the user has already
+    redeemed succeeds, but does not grant the permissions again.
+
+    # JSON parameters
+
+    - `code` — (required) the invite code.
+
+    # Return value
+
+    A JSON object with keys:
+
+    - `room` — the token of the room that the invite is for.
+    - `write` — true if the invite granted write access as well as read access.
+
+    # Error status codes
+
+    - 400 Bad Request — if `code` is missing or invalid.
+    - 403 Forbidden — if the invoking user is banned from the invite's room.
+    - 404 Not Found — if the code is not valid: for example, if it has expired, been revoked, or
+      already been redeemed by the maximum number of users.
+    """
+    req = request.json
+    code = req.get('code') if isinstance(req, dict) else None
+    if not isinstance(code, str) or not code:
+        app.logger.warning("Invalid invite redemption: `code` must be a non-empty string")
+        abort(http.BAD_REQUEST)
+
+    invite = Invite(code=code)
+    invite.redeem(g.user)
+    return jsonify({'room': invite.room.token, 'write': invite.write})
@@ -1,12 +1,13 @@
 from flask import abort, request, jsonify, g, Blueprint, Response
 from werkzeug.exceptions import HTTPException
 from ..web import app
-from .. import crypto, config, db, http, utils
-from ..omq import send_mule
+from .. import crypto, config, db, features, http, sigcache, utils
 from ..utils import jsonify_with_base64
 from ..model.room import Room, get_accessible_rooms, get_deletions_deprecated
 from ..model.user import User
 from ..model.exc import NoSuchRoom
+from . import auth
+from .rooms import check_egress
 
 # Legacy endpoints, to eventually be deleted.  These are invoked automatically if the client invokes
 # an endpoint (via onion request) that doesn't start with a `/` -- we prepend `/legacy/` and submit
@@ -18,13 +19,19 @@ legacy = Blueprint('legacy', __name__, url_prefix='/legacy')
 def get_pubkey_from_token(token):
     if not token:
         return
+    auth.require_legacy_tokens()
     try:
         rawtoken = utils.decode_hex_or_b64(token, utils.LEGACY_TOKEN_SIZE)
-        crypto.server_verify(rawtoken)
+        sigcache.verify(
+            crypto.server_verifykey.encode(),
+            rawtoken[utils.SIGNATURE_SIZE :],
+            rawtoken[: utils.SIGNATURE_SIZE],
+        )
     except Exception as ex:
         app.logger.error("failed to decode/verify token: {}".format(ex))
         abort(http.UNAUTHORIZED)
     else:
+        g.legacy_auth = True
         return rawtoken[utils.SIGNATURE_SIZE :].hex()
 
 
@@ -158,6 +165,7 @@ def legacy_auth_token_challenge():
     # legacy endpoint to give back an encrypted auth token bundle for the client to use to
     # authenticate.
 
+    auth.require_legacy_tokens()
     user, room = legacy_check_user_room(request.args.get("public_key", ""), read=False)
 
     token = utils.make_legacy_token(user.session_id)
@@ -188,6 +196,7 @@ def legacy_transform_message(m):
 def handle_post_legacy_message():
 
     user, room = legacy_check_user_room(write=True)
+    auth.throttle('post', user)
 
     req = request.json
     data = utils.decode_base64(req.get('data'))
@@ -203,7 +212,7 @@ def handle_post_legacy_message():
 
 @legacy.get("/messages")
 def handle_legacy_get_messages():
-    from_id = request.args.get('from_server_id')
+    from_id = utils.get_int_param('from_server_id', min=0, truncate=True)
     limit = utils.get_int_param('limit', 256, min=1, max=256, truncate=True)
 
     user, room = legacy_check_user_room(read=True)
@@ -213,7 +222,9 @@ def handle_legacy_get_messages():
             'status_code': http.OK,
             'messages': [
                 legacy_transform_message(m)
-                for m in room.get_messages_for(user, limit=limit, after=from_id, recent=not from_id)
+                for m in room.get_messages_for(
+                    user, limit=limit, after=from_id, recent=not from_id, system=False
+                )
             ],
         }
     )
@@ -249,7 +260,7 @@ def handle_one_compact_poll(req):
     after = req.get('from_message_server_id', None)
     messages = [
         legacy_transform_message(m)
-        for m in room.get_messages_for(user, after=after, recent=not after)
+        for m in room.get_messages_for(user, after=after, recent=not after, system=False)
     ]
 
     deletions = get_deletions_deprecated(room, req.get('from_deletion_server_id'))
@@ -287,6 +298,8 @@ def process_legacy_file_upload_for_room(user, room, lifetime=config.UPLOAD_DEFAU
 @legacy.post("/files")
 def handle_legacy_store_file():
     user, room = legacy_check_user_room(write=True, upload=True)
+    features.require('uploads', room)
+    auth.throttle('upload', user)
     file_id = process_legacy_file_upload_for_room(user, room)
     return jsonify({'status_code': http.OK, 'result': file_id})
 
@@ -304,12 +317,13 @@ def handle_legacy_get_file(file_id):
     user, room = legacy_check_user_room(read=True)
 
     file = room.get_file(file_id)
-    if not file:
+    if not file or (file.quarantined and not room.check_moderator(user)):
         abort(http.NOT_FOUND)
 
-    with open(file.path, 'rb') as f:
-        file_content = f.read()
-    return jsonify_with_base64({'status_code': http.OK, 'result': file_content})
+    check_egress(room)
+    content = file.read()
+    room.record_egress(file)
+    return jsonify_with_base64({'status_code': http.OK, 'result': content})
 
 
 @legacy.post("/delete_messages")
@@ -319,10 +333,7 @@ def handle_legacy_delete_messages(ids=None):
     if ids is None:
         ids = request.json['ids']
 
-    ids = room.delete_posts(ids, user)
-
-    if ids:
-        send_mule("messages_deleted", ids)
+    room.delete_posts(ids, user)
 
     return jsonify({'status_code': http.OK})
 
@@ -1,5 +1,9 @@
-from .. import http, utils
+from .. import canary, features, http, translate, utils
+from ..web import app
+from ..model import exc
+from ..model.room import message_count_granularity
 from . import auth
+from .rooms import get_room_info
 
 from flask import abort, jsonify, g, Blueprint, request
 
@@ -13,18 +17,24 @@ def qs_reactors():
     return utils.get_int_param('reactors', 4, min=0, max=20, truncate=True)
 
 
+def qs_inline():
+    return bool(utils.get_int_param('inline', 0, min=0, max=1, truncate=True))
+
+
 @messages.get("/room/<Room:room>/messages/since/<int:seqno>")
+@utils.query_params('limit', 't', 'reactors', 'inline')
 @auth.read_required
+@canary.rollout('messages_since')
 def messages_since(room, seqno):
     """
     Retrieves message *updates* from a room.  This is the main message polling endpoint in SOGS.
 
     This endpoint retrieves new, edited, and deleted messages or message reactions posted to this
     room since the given message sequence counter.  Returns `limit` messages at a time (100 if no
-    limit is given).  Returned messages include any new messages, updates to existing messages (i.e.
-    edits), and message deletions made to the room since the given update id.  Messages are returned
-    in "update" order, that is, in the order in which the change was applied to the room, from
-    oldest the newest.
+    limit is given, unless the server is configured otherwise).  Returned messages include any new
+    messages, updates to existing messages (i.e. edits), and message deletions made to the room
+    since the given update id.  Messages are returned in "update" order, that is, in the order in
+    which the change was applied to the room, from oldest the newest.
 
     # URL Parameters
 
@@ -34,8 +44,9 @@ def messages_since(room, seqno):
 
     # Query Parameters
 
-    - `limit` — if specified this indicates the number of messages to return (up to 256).  If
-      omitted, 100 messages are returned.
+    - `limit` — if specified this indicates the number of messages to return (up to 256, unless the
+      server is configured with a different maximum).  If omitted, 100 messages are returned (again,
+      unless the server is configured otherwise).
 
     - `t` — string indicating the types of updates that the client supports, thus allowing the
       client to opt-out of update types that it does not yet support.  Each letter of the string is
@@ -43,6 +54,8 @@ def messages_since(room, seqno):
       ignored by SOGS (to allow for backwards compatibility). Current flags:
 
       - `r` — include message reaction updates
+      - `h` — return a history truncation marker (see below) if the room's message retention period
+        has removed messages that the client has not yet seen
 
       Note that flags may be removed in the future, once a given feature is supported by all known
       clients.
@@ -70,7 +83,17 @@ def messages_since(room, seqno):
       and the `"reactions"` key, as would be returned by the single message retrieval endpoint, but
       without any of the other message data.
 
-    The endpoint always returns `limit` (or 100, if unspecified) message updates if they are
+    If the `t=h` flag is given and `seqno` is older than the room's retention horizon (i.e. messages
+    posted after `seqno` have since been removed by the room's message retention period, and so
+    can't be returned), then instead of updates this returns a single history truncation marker:
+
+        [{"history_truncated": true, "seqno": 1234}]
+
+    The client should then resync its view of the room (e.g. via [the recent messages
+    endpoint](#get-roomroommessagesrecent)) and resume polling from the given `seqno`, the room's
+    current sequence counter.
+
+    The endpoint always returns `limit` (or the default, if unspecified) message updates if they are
     available, so that a caller can determine whether it needs to issue additional room updates by
     seeing whether the returned value contains `limit` update: if this returns fewer than `limit`
     then there are currently no additional message updates.
@@ -82,10 +105,15 @@ def messages_since(room, seqno):
     if g.user:
         g.user.update_room_activity(room)
 
-    limit = utils.get_int_param('limit', 100, min=1, max=256, truncate=True)
+    limit = utils.get_int_param(
+        'limit', room.poll_limit, min=1, max=room.poll_limit_max, truncate=True
+    )
 
     flags = request.args.get('t', '')
 
+    if 'h' in flags and seqno < room.history_horizon():
+        return jsonify([{'history_truncated': True, 'seqno': room.message_sequence}])
+
     return utils.jsonify_with_base64(
         room.get_messages_for(
             g.user,
@@ -93,12 +121,15 @@ def messages_since(room, seqno):
             sequence=seqno,
             reaction_updates='r' in flags,
             reactor_limit=qs_reactors(),
+            inline_files=qs_inline(),
         )
     )
 
 
 @messages.get("/room/<Room:room>/messages/before/<int:msg_id>")
+@utils.query_params('limit', 'reactors', 'inline')
 @auth.read_required
+@canary.rollout('messages_before')
 def messages_before(room, msg_id):
     """
     Retrieves messages from the room preceding a given id.
@@ -136,12 +167,120 @@ def messages_before(room, msg_id):
     limit = utils.get_int_param('limit', 100, min=1, max=256, truncate=True)
 
     return utils.jsonify_with_base64(
-        room.get_messages_for(g.user, limit=limit, before=msg_id, reactor_limit=qs_reactors())
+        room.get_messages_for(
+            g.user,
+            limit=limit,
+            before=msg_id,
+            reactor_limit=qs_reactors(),
+            inline_files=qs_inline(),
+        )
+    )
+
+
+@messages.get("/room/<Room:room>/messages/posted_before/<int:timestamp>")
+@utils.query_params('limit', 'reactors', 'inline')
+@auth.read_required
+@canary.rollout('messages_posted_before')
+def messages_posted_before(room, timestamp):
+    """
+    Retrieves messages posted to the room before a given time.
+
+    This endpoint allows a client to jump to a specific date in a room's history; the client can
+    then use `.../before` and `.../since` to page backwards and forwards from that point.  As with
+    `.../recent`, messages are returned in order from most recent to least recent, and deleted
+    messages are not included.
+
+    # URL Parameters
+
+    - `timestamp` — an integer unix timestamp; the messages posted immediately *before* this time
+      are returned.
+
+    # Query Parameters
+
+    - `limit` — maximum number of messages to return; defaults to 100, maximum is 256.
+
+    - `reactors` — how many reactors to include in message reaction data.  Defaults to 4.
+
+    # Return value
+
+    On success this returns a 200 status code with a body consisting of a JSON array of the message
+    details.  Each message is the object that would be returned by [the single message retrieval
+    endpoint](#get-roomroommessagemsg_id).  Messages are sorted from newest to oldest.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user does not have read access to the room.
+    """
+    if g.user:
+        g.user.update_room_activity(room)
+
+    limit = utils.get_int_param('limit', 100, min=1, max=256, truncate=True)
+
+    return utils.jsonify_with_base64(
+        room.get_messages_for(
+            g.user,
+            limit=limit,
+            posted_before=timestamp,
+            reactor_limit=qs_reactors(),
+            inline_files=qs_inline(),
+        )
+    )
+
+
+@messages.get("/room/<Room:room>/messages/around/<int:msg_id>")
+@utils.query_params('limit', 'reactors', 'inline')
+@auth.read_required
+@canary.rollout('messages_around')
+def messages_around(room, msg_id):
+    """
+    Retrieves a message along with the messages surrounding it.
+
+    This endpoint allows a client to open a room at a specific older message (for example, a pinned
+    message or a search result) without having to page backwards from the most recent messages.
+
+    # URL Parameters
+
+    - `msg_id` — the numeric integer ID of the message around which to retrieve messages.  The
+      message itself is included in the result, if it exists and is visible to the user.
+
+    # Query Parameters
+
+    - `limit` — maximum number of messages to return; defaults to 100, maximum is 256.  Up to half
+      of these are messages following `msg_id`; the remainder are `msg_id` itself and the messages
+      preceding it.
+
+    - `reactors` — how many reactors to include in message reaction data.  Defaults to 4.
+
+    # Return value
+
+    On success this returns a 200 status code with a body consisting of a JSON array of the message
+    details.  Each message is the object that would be returned by [the single message retrieval
+    endpoint](#get-roomroommessagemsg_id).  Messages are sorted from newest to oldest.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user does not have read access to the room.
+    """
+    if g.user:
+        g.user.update_room_activity(room)
+
+    limit = utils.get_int_param('limit', 100, min=1, max=256, truncate=True)
+
+    return utils.jsonify_with_base64(
+        room.get_messages_for(
+            g.user,
+            limit=limit,
+            around=msg_id,
+            reactor_limit=qs_reactors(),
+            inline_files=qs_inline(),
+        )
     )
 
 
 @messages.get("/room/<Room:room>/messages/recent")
+@utils.query_params('limit', 'reactors', 'inline')
 @auth.read_required
+@canary.rollout('messages_recent')
 def messages_recent(room):
     """
     Retrieves recent messages posted to this room.
@@ -175,12 +314,71 @@ def messages_recent(room):
     limit = utils.get_int_param('limit', 100, min=1, max=256, truncate=True)
 
     return utils.jsonify_with_base64(
-        room.get_messages_for(g.user, limit=limit, recent=True, reactor_limit=qs_reactors())
+        room.get_messages_for(
+            g.user, limit=limit, recent=True, reactor_limit=qs_reactors(), inline_files=qs_inline()
+        )
+    )
+
+
+@messages.get("/room/<Room:room>/message_counts")
+@utils.query_params('granularity', 'since', 'until')
+@auth.read_required
+def message_counts(room):
+    """
+    Returns the number of messages posted to the room per time period.  This is intended to allow
+    clients to render a timeline or "jump to date" scroll bar, and to allow moderators to see room
+    activity trends.
+
+    Only extant, publicly visible messages are counted: deleted messages, whispers, and messages
+    held by the server's message filters are not included.
+
+    # Query Parameters
+
+    - `granularity` — the size of each time period; one of `hour`, `day` (the default), or `week`.
+      Periods are aligned to the unix epoch, i.e. days are UTC days and weeks begin on Thursdays.
+
+    - `since` — if given, only count messages posted at or after this unix timestamp.
+
+    - `until` — if given, only count messages posted before this unix timestamp.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing keys:
+
+    - `granularity` — the period size, as given in the request.
+    - `interval` — the period size, in seconds.
+    - `counts` — a list of `[start, count]` pairs, where `start` is the unix timestamp of the
+      beginning of the period and `count` is the number of messages posted during that period.
+      Periods without any messages are omitted.  The list is sorted from oldest to newest.
+
+    # Error status codes
+
+    - 400 Bad Request — if `granularity` is not a supported value.
+    - 403 Forbidden — if the invoking user does not have read access to the room.
+    """
+    granularity = request.args.get('granularity', 'day')
+    if granularity not in message_count_granularity:
+        utils.bad_param(
+            'granularity', f"expected one of: {', '.join(message_count_granularity.keys())}"
+        )
+
+    return jsonify(
+        {
+            'granularity': granularity,
+            'interval': message_count_granularity[granularity],
+            'counts': room.message_counts(
+                granularity,
+                since=utils.get_int_param('since', min=0),
+                until=utils.get_int_param('until', min=0),
+            ),
+        }
     )
 
 
 @messages.get("/room/<Room:room>/message/<int:msg_id>")
+@utils.query_params('reactors', 'inline')
 @auth.read_required
+@canary.rollout('message_single')
 def message_single(room, msg_id):
     """
     Returns a single message by ID.
@@ -195,6 +393,9 @@ def message_single(room, msg_id):
       `"reactions"` field.  Can be 0 to 20; the default, if omitted, is 4.  If 0 then the
       `"reactors"` key will be omitted entirely from the `"reactions"` field.
 
+    - `inline` — if set to 1 then the content of small attachments is included in the message (see
+      `inline_files`, below).  This is also accepted by the other message retrieval endpoints.
+
     # Return value
 
     On success this returns a 200 status code with a JSON body containing an object with keys:
@@ -215,6 +416,26 @@ def message_single(room, msg_id):
         example, an edit will increase this value so that polling clients will receive the edit, but
         the edit itself should change the content but not re-position the message.
 
+    - `kind` — The kind of message: `image` or `bot` if declared by the poster, or `system` for a
+      room event generated by the server (if enabled for the room).  Omitted for ordinary `text`
+      messages.  A `system` message is posted by the server's `ff...` id and its `data` is a JSON
+      object (rather than a Session message) containing an `event` key and event-specific fields:
+
+      - `user_banned` — `session_id` and `timeout` (null for a permanent ban).
+      - `room_renamed` — the new `name`.
+      - `message_pinned` — the `id` of the pinned message.
+      - `messages_deleted` — the `ids` of messages deleted by a moderator.
+      - `settings_changed` — the changed setting: one of `description`, `image` (the new image's
+        file id), `sensitive` (the room's sensitive content flag), or `read`, `accessible`, `write`,
+        or `upload` (the room's default permissions).
+      - `moderator_added` — the `session_id` of a new moderator, and `admin` (true if an admin).
+        Hidden moderators are not announced.
+
+      Events caused by an administrator acting through the server itself (e.g. from the command
+      line) also include an `actor` field identifying the administrator.
+    - `role` — The author's current role in the room: `admin`, `moderator`, `bot` (for a bridge
+      bot), or `regular`.  Hidden moderators are reported as `regular` unless the retrieving user is
+      a moderator.  Only included if the server has role badges enabled for the room.
     - `whisper` — If true then this message is a whisper, either directed at the retrieving user, or
       sent to all moderators (and the retrieving user is a moderator).
     - `whisper_mods` — If true then this message is a whisper visible to all moderators.  If false
@@ -230,6 +451,11 @@ def message_single(room, msg_id):
       Ed25519 key in the blinded session ID.  For unblinded IDs (`05...`) the signature is
       verifiable using the XEd25519-specified converted pubkey of the Session ID.  If `data` is null
       this field (i.e. for a deletion update) this field is omitted.
+    - `inline_files` — A list of the message's attachments that are small enough (as configured by
+      the server) to be included directly in the response, each an object with the file `id` and
+      its base64-encoded content in `data`; clients can use this instead of downloading such files
+      separately.  Only included when requested with the `inline=1` query parameter, and omitted if
+      the message has no such attachments.
     - `reactions` — A dict of reaction information for this message; the returned information is
       always current (i.e. requesting the same thing more than once can give different reaction
       information if reaction changes occur between requests).  Note that, when polling for message
@@ -301,15 +527,206 @@ def message_single(room, msg_id):
     if g.user:
         g.user.update_room_activity(room)
 
-    msgs = room.get_messages_for(g.user, single=msg_id, reactor_limit=qs_reactors())
+    msgs = room.get_messages_for(
+        g.user, single=msg_id, reactor_limit=qs_reactors(), inline_files=qs_inline()
+    )
     if not msgs:
         abort(http.NOT_FOUND)
 
     return utils.jsonify_with_base64(msgs[0])
 
 
+@messages.post("/room/<Room:room>/messages/lookup")
+@auth.read_required
+def messages_lookup(room):
+    """
+    Looks up whether the server has seen messages, identified by their signatures.  This allows
+    clients and mirrors that were offline to efficiently find out which of their cached messages
+    still exist unchanged, and which have since been edited or deleted, without re-fetching them.
+
+    The Ed25519 signature of a message serves as its hash: it is unique to the message content and
+    its author, and is known to anyone who has the message.  Signatures of earlier versions of an
+    edited or deleted message are recognized for as long as the server keeps the message's edit
+    history.
+
+    # JSON parameters
+
+    - `signatures` — (required) list of up to 256 base64-encoded message signatures.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON list containing one element for each given
+    signature, in the same order: null if no message visible to the user has the signature,
+    otherwise an object with keys:
+
+    - `id` — the id of the message.
+    - `status` — `exists` if this is the signature of the current version of the message; `edited`
+      if it is of an earlier version of a message that has since been edited; or `deleted` if the
+      message has since been deleted.
+
+    # Error status codes
+
+    - 400 Bad Request — if `signatures` is missing, too long, or contains invalid signatures.
+    - 403 Forbidden — if the invoking user does not have read access to the room.
+    """
+    req = request.json
+    sigs = req.get('signatures') if isinstance(req, dict) else None
+    if not isinstance(sigs, list) or not 1 <= len(sigs) <= 256:
+        app.logger.warning("Invalid message lookup: `signatures` must be a list of 1-256 values")
+        abort(http.BAD_REQUEST)
+    try:
+        sigs = [utils.decode_base64(s) for s in sigs]
+    except Exception:
+        sigs = None
+    if sigs is None or any(len(s) != 64 for s in sigs):
+        app.logger.warning("Invalid message lookup: `signatures` must be base64 signatures")
+        abort(http.BAD_REQUEST)
+
+    found = room.lookup_messages(g.user, sigs)
+    return jsonify([found.get(s) for s in sigs])
+
+
+@messages.get("/room/<Room:room>/permalink/<int:msg_id>")
+@utils.query_params('context', 'reactors')
+@auth.read_required
+def message_permalink(room, msg_id):
+    """
+    Resolves a message permalink (i.e. a room token and message id), returning everything a client
+    needs to render the linked message in context with a single request.
+
+    # URL Parameters
+
+    - `msg_id` — the numeric integer ID of the linked message.
+
+    # Query Parameters
+
+    - `context` — the number of messages to return from before and after the linked message.  Can
+      be 0 to 25; the default, if omitted, is 5.
+
+    - `reactors` — how many reactors to include in message reaction data.  Defaults to 4.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing keys:
+
+    - `room` — the room details, as would be returned by [the room info
+      endpoint](#get-roomroom).
+    - `message` — the linked message, as would be returned by [the single message retrieval
+      endpoint](#get-roomroommessagemsg_id).
+    - `author` — details of the message author: an object containing the author's `session_id`
+      and, if the author is a moderator or admin of the room, `moderator` and/or `admin` keys set
+      to `true`.  (Hidden moderators and admins are only indicated to moderators).
+    - `before` — a list of up to `context` messages immediately preceding the linked message, from
+      newest to oldest.
+    - `after` — a list of up to `context` messages immediately following the linked message, from
+      oldest to newest.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user does not have read access to the room.
+    - 404 Not Found — if the message does not exist, has been deleted, or is not visible to the
+      invoking user.
+    """
+    if g.user:
+        g.user.update_room_activity(room)
+
+    context = utils.get_int_param('context', 5, min=0, max=25, truncate=True)
+
+    msgs = room.get_messages_for(
+        g.user, limit=2 * context + 1, around=msg_id, reactor_limit=qs_reactors()
+    )
+    message = next((m for m in msgs if m['id'] == msg_id), None)
+    if message is None:
+        abort(http.NOT_FOUND)
+
+    room_info = get_room_info(room)
+    author = {'session_id': message['session_id']}
+    for key, role in (
+        ('moderators', 'moderator'),
+        ('hidden_moderators', 'moderator'),
+        ('admins', 'admin'),
+        ('hidden_admins', 'admin'),
+    ):
+        if message['session_id'] in room_info.get(key, ()):
+            author[role] = True
+    if author.get('admin'):
+        author['moderator'] = True
+
+    return utils.jsonify_with_base64(
+        {
+            'room': room_info,
+            'message': message,
+            'author': author,
+            'before': [m for m in msgs if m['id'] < msg_id],
+            'after': [m for m in reversed(msgs) if m['id'] > msg_id],
+        }
+    )
+
+
+@messages.get("/room/<Room:room>/message/<int:msg_id>/translate")
+@utils.query_params('lang')
+@auth.read_required
+def message_translate(room, msg_id):
+    """
+    Returns a translation of a message's text, performed by the server via its configured
+    translation service.  This endpoint is only available when the server advertises the
+    `translate` capability.
+
+    The request to the translation service is made by the SOGS server, so the translator never
+    learns the IP address of the requesting client.  Translations are cached by the server until the
+    message is edited or deleted.
+
+    # URL Parameters
+
+    - `msg_id` the numeric integer ID of the message to translate.
+
+    # Query Parameters
+
+    - `lang` — the language code to translate into, e.g. `de` or `pt-BR`.  Required.
+
+    # Return value
+
+    On success this returns a 200 status code with a JSON body containing an object with keys:
+
+    - `id` — The numeric message id.
+    - `lang` — The target language, as specified in the request.
+    - `text` — The translated message text.  Will be an empty string if the message has no text
+      body (e.g. an attachment-only message).
+
+    # Error status codes
+
+    - 400 Bad Request — if `lang` is missing or invalid, or the message content could not be parsed.
+
+    - 403 Forbidden — returned if the invoking user does not have read access to the room.
+
+    - 404 Not Found — returned if the message does not exist or is not visible to this user, or if
+      translation is not enabled on this server or in this room.
+
+    - 502 Bad Gateway — returned if the translation service could not be reached or failed to
+      translate the message.
+    """
+
+    if not translate.enabled():
+        abort(http.NOT_FOUND)
+    features.require('translate', room)
+
+    lang = request.args.get('lang')
+    if not translate.valid_lang(lang):
+        app.logger.warning(f"Invalid translation request: invalid lang={lang}")
+        abort(http.BAD_REQUEST)
+
+    try:
+        text = room.translate_post(g.user, msg_id, lang)
+    except translate.TranslationFailed as e:
+        app.logger.warning(f"Translation of message {msg_id} failed: {e}")
+        abort(http.BAD_GATEWAY)
+
+    return jsonify({'id': msg_id, 'lang': lang, 'text': text})
+
+
 @messages.post("/room/<Room:room>/message")
 @auth.user_required
+@auth.throttled('post')
 def post_message(room):
     """
     Posts a new message to a room.
@@ -347,6 +764,20 @@ def post_message(room):
         IDs of any newly uploaded files that are part of the edit.  Existing attachment IDs may also
         be included, but are not required.
 
+    - `kind` — the kind of message, which clients may use to render the message specially: `text`
+      (the default), `image` (requires at least one attached file), or `bot`.  The kind cannot be
+      changed by later edits.
+
+    - `idempotency_key` — optional string of up to 64 characters chosen by the client to identify
+      this post, such as a random UUID.  If the user has already posted a message with the same key
+      to the room (for instance because the client re-sent the post after a request timeout) then
+      the message is not posted again: the response instead contains the details of the original
+      message (or, if the original message has since been deleted, a 409 error).
+
+    - `challenge`, `challenge_solution` — the base64-encoded challenge issued by [the challenge
+      endpoint](#get-roomroomchallenge), and its solution.  Required with the user's first post to a
+      room that requires new posters to solve a proof-of-work challenge, and ignored otherwise.
+
     # Return value
 
     On success this returns a status **201** (Created), *not* the default 200 (OK) returned by most
@@ -355,18 +786,47 @@ def post_message(room):
 
     # Error status codes
 
+    - 400 Bad Request — if the message kind or idempotency key is invalid.
     - 403 Forbidden — if the invoking user does not have write permission to the room.
+    - 409 Conflict — if the user already posted a message with the same `idempotency_key`, but that
+      message has since been deleted.  The JSON body contains the deleted message's `id`.
+    - 428 Precondition Required — if this is the user's first post to a room that requires a
+      proof-of-work challenge, and a valid solved challenge was not included.  The JSON body
+      contains `error` and the required `difficulty`; the client should obtain a challenge from
+      [the challenge endpoint](#get-roomroomchallenge), solve it, and re-send the post.
+    - 429 Too Many Requests — if the user is posting too frequently, in which case the JSON body
+      contains `error`, `scope` (`room_post` for the room's limit, or `post_rate` for the server's
+      limit across all rooms), `limit`, `remaining`, `reset`, and `retry_after` fields describing
+      the limit (which are also provided in the `Retry-After` and `RateLimit-*` headers), or if the
+      message was rejected by the room's message filters.
+    - 503 Service Unavailable — if the database failed to store the message.  Nothing was stored,
+      so the client should re-send the same message after the `Retry-After` delay.
     """
     req = request.json
 
-    msg = room.add_post(
-        g.user,
-        data=utils.decode_base64(req.get('data')),
-        sig=utils.decode_base64(req.get('signature')),
-        whisper_to=req.get('whisper_to'),
-        whisper_mods=bool(req.get('whisper_mods')),
-        files=[int(x) for x in req.get('files', [])],
-    )
+    pow_solution = None
+    if req.get('challenge') is not None and req.get('challenge_solution') is not None:
+        try:
+            pow_solution = tuple(
+                utils.decode_base64(req[k]) for k in ('challenge', 'challenge_solution')
+            )
+        except Exception:
+            abort(http.BAD_REQUEST)
+
+    try:
+        msg = room.add_post(
+            g.user,
+            data=utils.decode_base64(req.get('data')),
+            sig=utils.decode_base64(req.get('signature')),
+            whisper_to=req.get('whisper_to'),
+            whisper_mods=bool(req.get('whisper_mods')),
+            files=[int(x) for x in req.get('files', [])],
+            kind=req.get('kind', 'text'),
+            idempotency_key=req.get('idempotency_key'),
+            pow_solution=pow_solution,
+        )
+    except exc.AlreadyExists as e:
+        return jsonify({'id': e.value}), http.CONFLICT
 
     return utils.jsonify_with_base64(msg), http.CREATED
 
@@ -418,6 +878,75 @@ def edit_message(room, msg_id):
     return jsonify({})
 
 
+@messages.get("/room/<Room:room>/message/<int:msg_id>/history")
+@auth.user_required
+@auth.read_required
+def message_history(room, msg_id):
+    """
+    Returns the edit history of a message: the content and signature replaced by each edit (or by
+    deletion) of the message.  Earlier versions are kept for the server's configured edit history
+    retention period and then discarded.
+
+    The history may only be retrieved by the author of the message or by a moderator of the room.
+
+    # URL Parameters
+
+    - `msg_id` the numeric integer ID of the message.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON list of the earlier versions of the message,
+    oldest first.  Each element is an object with keys:
+
+    - `replaced` — unix timestamp when this version was replaced by an edit or deletion.
+    - `data` — the base64-encoded message content of this version.
+    - `signature` — the base64-encoded signature of this version's `data`.
+
+    The list is empty if the message has never been edited (or its history has expired).  The
+    current version of the message is not included: it is available from [the single message
+    endpoint](#get-roomroommessagemsg_id), whose `edited` and `seqno` fields indicate that the
+    message has been edited.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is neither the author of the message nor a moderator.
+
+    - 404 Not Found — if the message does not exist in this room.
+    """
+
+    return utils.jsonify_with_base64(room.message_history(g.user, msg_id))
+
+
+@messages.get("/room/<Room:room>/message/<int:msg_id>/author")
+@auth.admin_required
+def message_anonymous_author(room, msg_id):
+    """
+    Looks up the real author of a post made under a pseudonym in an anonymous room, for handling
+    abuse.  Requires admin permission in the room.  Lookups are logged by the server.
+
+    # URL Parameters
+
+    - `msg_id` — the numeric integer ID of the anonymous post.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing:
+
+    - `session_id` — the Session id of the real author of the post.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user does not have admin permission in this room.
+
+    - 404 Not Found — if the message does not exist in this room, has been deleted, or was not
+      posted anonymously.
+    """
+    author = room.anonymous_author(msg_id, g.user)
+    if author is None:
+        abort(http.NOT_FOUND)
+    return jsonify({'session_id': author})
+
+
 @messages.delete("/room/<Room:room>/message/<int:msg_id>")
 @auth.user_required
 def remove_message(room, msg_id):
@@ -457,10 +986,9 @@ def message_pin(room, msg_id):
 
     The user must have admin (not just moderator) permissions in the room in order to pin messages.
 
-    Pinned messages that are already pinned will be re-pinned (that is, their pin timestamp and
-    pinning admin user will be updated).  Because pinned messages are returned in pinning-order this
-    allows admins to order multiple pinned messages in a room by re-pinning (via this endpoint) in
-    the order in which pinned messages should be displayed.
+    The new pin is placed after the room's existing pins.  Pinned messages that are already pinned
+    will be re-pinned (that is, their pin timestamp and pinning admin user will be updated) and
+    moved to the end of the pins.  To rearrange the room's pins, use `PUT /room/ROOM/pins`.
 
     # URL Parameters
 
@@ -484,15 +1012,58 @@ def message_pin(room, msg_id):
 
     - 404 Not Found — returned if the given post was not found in this room or is ineligible for
       pinning (e.g. a whisper or deleted post).
+
+    - 409 Conflict — returned if the room already has its maximum number of pinned messages (see
+      [rooms] `max_pins`).  An existing pin must be removed before a new message can be pinned.
     """
     room.pin(msg_id, g.user)
     return jsonify({"info_updates": room.info_updates})
 
 
+@messages.put("/room/<Room:room>/pins")
+def message_pins_order(room):
+    """
+    Rearranges the pinned messages of this room.  Pinned messages are returned (in the room's
+    `pinned_messages` details) in this order.
+
+    The user must have admin (not just moderator) permissions in the room.
+
+    # JSON parameters
+
+    Takes a JSON object as the request body with keys:
+
+    - `order` — list of the message IDs of all of the room's currently pinned messages, in the order
+      in which they should be displayed.
+
+    # Return value
+
+    On success returns a 200 status code and returns a JSON object as response containing keys:
+
+    - `info_updates` -- the new info_updates value of the room.  This value only changes if the
+      order of the pins was actually changed.
+
+    # Error status codes
+
+    - 400 Bad Request — returned if `order` is missing, or isn't exactly the list of the room's
+      pinned message IDs (e.g. because one was pinned or unpinned in the meantime, in which case the
+      client should refetch the room's pins and try again).
+
+    - 403 Forbidden — returned if the invoking user does not have admin permission in this room.
+    """
+    order = request.json.get('order')
+    if not isinstance(order, list) or not all(type(i) is int for i in order):
+        abort(http.BAD_REQUEST)
+
+    room.reorder_pins(order, g.user)
+    return jsonify({"info_updates": room.info_updates})
+
+
 @messages.post("/room/<Room:room>/unpin/<int:msg_id>")
+@messages.delete("/room/<Room:room>/pin/<int:msg_id>")
 def message_unpin(room, msg_id):
     """
-    Remove a message from this room's pinned message list.
+    Remove a message from this room's pinned message list.  This endpoint may also be invoked as
+    `DELETE /room/ROOM/pin/MSG_ID`.
 
     The user must have admin (not just moderator) permissions in the room.
 
@@ -592,12 +1163,14 @@ def message_react(room, msg_id, reaction):
     # Error status codes
 
     - 403 Forbidden — returned if the user doesn't have read permission in the room.
-    - 404 Not Found — returned if the given post does not exist
+    - 404 Not Found — returned if the given post does not exist, or if reactions are disabled in the
+      room.
     - 400 Bad Request — if the input does not contain a valid reaction
 
     Note that it is *not* an error to attempt to add a reaction that the user has already added
     (instead in such a case the success response return value includes `"added": false`).
     """
+    features.require('reactions', room)
 
     added, seqno = room.add_reaction(g.user, msg_id, reaction)
     return jsonify({"added": added, "seqno": seqno})
@@ -629,12 +1202,14 @@ def message_unreact(room, msg_id, reaction):
     # Error status codes
 
     - 403 Forbidden — returned if the user doesn't have read permission in the room.
-    - 404 Not Found — returned if the given post does not exist
+    - 404 Not Found — returned if the given post does not exist, or if reactions are disabled in the
+      room.
     - 400 Bad Request — if the input does not contain a valid reaction
 
     Note that it is *not* an error to attempt to remove a reaction that does not exist (instead in
     such a case the success response return value includes `"removed": false`).
     """
+    features.require('reactions', room)
     removed, seqno = room.delete_reaction(g.user, msg_id, reaction)
     return jsonify({"removed": removed, "seqno": seqno})
 
@@ -676,6 +1251,7 @@ def message_delete_reactions(room, msg_id, reaction=None):
 
 
 @messages.get("/room/<Room:room>/reactors/<int:msg_id>/<path:reaction>")
+@utils.query_params('limit')
 @auth.read_required
 def message_get_reactors(room, msg_id, reaction):
     """
@@ -706,6 +1282,7 @@ def message_get_reactors(room, msg_id, reaction):
     - 400 Bad Request — if the `reaction` value is not a valid reaction
     """
 
+    features.require('reactions', room)
     limit = utils.get_int_param('limit', 0)
     if limit <= 0:
         limit = None
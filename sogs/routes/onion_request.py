@@ -1,13 +1,25 @@
 from flask import request, abort, Blueprint
+import concurrent.futures
 import json
+import threading
+import time
 
 from ..web import app
-from .. import crypto, http, utils
+from .. import config, crypto, http, metrics, utils
 
 from .subrequest import make_subrequest
 
 onion_request = Blueprint('onion_request', __name__)
 
+# Onion requests are decrypted on a bounded pool of [onion].workers threads (per worker process),
+# with at most [onion].queue further requests waiting for a worker; requests beyond that, requests
+# larger than [onion].max_size, and requests that can't be decrypted within
+# [onion].decrypt_timeout are rejected without tying up the request thread, and counted in the
+# `onion.rejected.*` metrics (see /admin/metrics).
+_decrypt_pool = None
+_decrypt_slots = None
+_decrypt_lock = threading.Lock()
+
 
 def handle_v3_onionreq_plaintext(body):
     """
@@ -97,9 +109,52 @@ def handle_v3_onionreq_plaintext(body):
 
     except Exception as e:
         app.logger.warning("Invalid onion request: {}".format(e))
+        metrics.incr('onion.rejected.invalid')
         return json.dumps({'status_code': http.BAD_REQUEST}).encode()
 
 
+def parse_v4_onionreq_plaintext(body: bytes):
+    """
+    Parses the bencoded body of a decrypted v4 onion request (see handle_v4_onionreq_plaintext).
+    Returns a (meta, body) pair of the metadata dict (with `method`, `endpoint`, and `headers`
+    keys, `headers` defaulting to an empty dict) and the subrequest body (a memoryview, or b'' if
+    omitted).  Raises ValueError if the request is malformed.
+    """
+    if not (body.startswith(b'l') and body.endswith(b'e')):
+        raise ValueError("Invalid onion request body: expected bencoded list")
+
+    belems = memoryview(body)[1:-1]
+
+    # Metadata json; this element is always required:
+    meta, belems = utils.bencode_consume_string(belems)
+
+    meta = json.loads(meta.tobytes())
+    if not isinstance(meta, dict):
+        raise ValueError("Invalid v4 onion request: metadata must be a JSON object")
+
+    # Then we can have a second optional string containing the body:
+    if len(belems) > 1:
+        subreq_body, belems = utils.bencode_consume_string(belems)
+        if len(belems):
+            raise ValueError("Invalid v4 onion request: found more than 2 parts")
+    else:
+        subreq_body = b''
+
+    method, endpoint = meta.get('method'), meta.get('endpoint')
+    if not isinstance(method, str) or not isinstance(endpoint, str):
+        raise ValueError("Invalid v4 onion request: method and endpoint must be strings")
+    if not endpoint.startswith('/'):
+        raise ValueError("Invalid v4 onion request: endpoint must start with /")
+
+    headers = meta.setdefault('headers', {})
+    if not isinstance(headers, dict) or not all(
+        isinstance(k, str) and isinstance(v, str) for k, v in headers.items()
+    ):
+        raise ValueError("Invalid v4 onion request: headers must be a dict of strings")
+
+    return meta, subreq_body
+
+
 def handle_v4_onionreq_plaintext(body):
     """
     Handles a decrypted v4 onion request; this injects a subrequest to process it then returns the
@@ -195,32 +250,13 @@ def handle_v4_onionreq_plaintext(body):
     """  # noqa: E501
 
     try:
-        if not (body.startswith(b'l') and body.endswith(b'e')):
-            raise RuntimeError("Invalid onion request body: expected bencoded list")
-
-        belems = memoryview(body)[1:-1]
-
-        # Metadata json; this element is always required:
-        meta, belems = utils.bencode_consume_string(belems)
-
-        meta = json.loads(meta.tobytes())
-
-        # Then we can have a second optional string containing the body:
-        if len(belems) > 1:
-            subreq_body, belems = utils.bencode_consume_string(belems)
-            if len(belems):
-                raise RuntimeError("Invalid v4 onion request: found more than 2 parts")
-        else:
-            subreq_body = b''
+        meta, subreq_body = parse_v4_onionreq_plaintext(body)
 
         method, endpoint = meta['method'], meta['endpoint']
-        if not endpoint.startswith('/'):
-            raise RuntimeError("Invalid v4 onion request: endpoint must start with /")
-
         response, headers = make_subrequest(
             method,
             endpoint,
-            headers=meta.get('headers', {}),
+            headers=meta['headers'],
             body=subreq_body,
             user_reauth=True,  # Because onion requests have auth headers on the *inside*
         )
@@ -234,6 +270,7 @@ def handle_v4_onionreq_plaintext(body):
 
     except Exception as e:
         app.logger.warning("Invalid v4 onion request: {}".format(e))
+        metrics.incr('onion.rejected.invalid')
         meta = {'code': http.BAD_REQUEST, 'headers': {'content-type': 'text/plain; charset=utf-8'}}
         data = b'Invalid v4 onion request'
 
@@ -243,12 +280,57 @@ def handle_v4_onionreq_plaintext(body):
     )
 
 
+def _reject(reason, status):
+    metrics.incr(f'onion.rejected.{reason}')
+    abort(status)
+
+
 def decrypt_onionreq():
+    """
+    Decrypts the current onion request on the decryption pool, returning the junk parser result.
+    Aborts with a 413 if the request is too large, a 503 if the decryption workers are too busy or
+    decryption takes too long, and a 400 if the request can't be decrypted.
+    """
+    global _decrypt_pool, _decrypt_slots
+
+    metrics.incr('onion.requests')
+    if (request.content_length or 0) > config.ONION_MAX_SIZE:
+        app.logger.warning(f"Rejecting oversized onion request ({request.content_length} bytes)")
+        _reject('size', http.PAYLOAD_TOO_LARGE)
+    data = request.get_data()
+    if len(data) > config.ONION_MAX_SIZE:
+        app.logger.warning(f"Rejecting oversized onion request ({len(data)} bytes)")
+        _reject('size', http.PAYLOAD_TOO_LARGE)
+
+    with _decrypt_lock:
+        if _decrypt_pool is None:
+            _decrypt_pool = concurrent.futures.ThreadPoolExecutor(
+                config.ONION_WORKERS, thread_name_prefix='sogs-onion'
+            )
+            _decrypt_slots = threading.BoundedSemaphore(config.ONION_WORKERS + config.ONION_QUEUE)
+    if not _decrypt_slots.acquire(blocking=False):
+        app.logger.warning("Rejecting onion request: all onion decryption workers are busy")
+        _reject('busy', http.SERVICE_UNAVAILABLE)
+
+    started = time.perf_counter()
+    try:
+        future = _decrypt_pool.submit(crypto.parse_junk, data)
+    except Exception:
+        _decrypt_slots.release()
+        raise
+    future.add_done_callback(lambda _: _decrypt_slots.release())
+
     try:
-        return crypto.parse_junk(request.data)
+        return future.result(timeout=config.ONION_DECRYPT_TIMEOUT)
+    except concurrent.futures.TimeoutError:
+        future.cancel()
+        app.logger.warning("Rejecting onion request: decryption timed out")
+        _reject('timeout', http.SERVICE_UNAVAILABLE)
     except Exception as e:
         app.logger.warning("Failed to decrypt onion request: {}".format(e))
-    abort(http.BAD_REQUEST)
+        _reject('malformed', http.BAD_REQUEST)
+    finally:
+        metrics.observe('onion.decrypt.time', time.perf_counter() - started)
 
 
 @onion_request.post("/oxen/v3/lsrpc")
@@ -286,11 +368,7 @@ def handle_v4_onion_request():
     #
     # The parse_junk here takes care of decoding and decrypting this according to the fields *meant
     # for us* in the json (which include things like the encryption type and ephemeral key):
-    try:
-        junk = crypto.parse_junk(request.data)
-    except RuntimeError as e:
-        app.logger.warning("Failed to decrypt onion request: {}".format(e))
-        abort(http.BAD_REQUEST)
+    junk = decrypt_onionreq()
 
     # On the way back out we re-encrypt via the junk parser (which uses the ephemeral key and
     # enc_type that were specified in the outer request).  We then return that encrypted binary
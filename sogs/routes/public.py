@@ -0,0 +1,185 @@
+from flask import abort, jsonify, request, Blueprint
+
+from .. import config, http, preview, ratelimit, utils
+from ..model.room import get_accessible_rooms
+from ..model.post import Post
+from .exc import rate_limited
+from . import converters  # noqa: F401
+
+import threading
+import time
+
+# Read-only public API for third-party archive viewers (e.g. community websites mirroring a room's
+# history).  It is only available for publicly readable rooms configured with `public_api = yes`,
+# exposes only room details and decoded message text (no session ids or other user identifiers),
+# and has its own, much stricter, per-address rate limit.  Responses are cached for a short time
+# (see [net].public_api_cache) since archive viewers tend to repeat the same requests.
+
+public = Blueprint('public', __name__)
+
+_cache_lock = threading.Lock()
+# full request path => (expiry, response body)
+_cache = {}
+
+
+@public.before_request
+def limit_public_api():
+    """Applies the per-address [net].public_api_limit to public API requests."""
+    if config.PUBLIC_API_LIMIT <= 0:
+        return
+    addr = utils.client_addr()
+    retry = ratelimit.allow(
+        ('public_api', addr), config.PUBLIC_API_LIMIT, config.PUBLIC_API_INTERVAL
+    )
+    if retry is not None:
+        abort(
+            rate_limited(
+                "Too many public API requests",
+                scope='public_api',
+                limit=config.PUBLIC_API_LIMIT,
+                reset=time.time() + retry,
+            )
+        )
+
+
+def _cached(make_body):
+    """
+    Returns a JSON response of `make_body()`, re-using a cached body for the same request path if
+    made within the last [net].public_api_cache seconds.
+    """
+    ttl = config.PUBLIC_API_CACHE
+    key = request.full_path
+    now = time.time()
+    body = None
+    if ttl > 0:
+        with _cache_lock:
+            hit = _cache.get(key)
+            if hit is not None and hit[0] > now:
+                body = hit[1]
+
+    if body is None:
+        body = make_body()
+        if ttl > 0:
+            with _cache_lock:
+                for k in [k for k, v in _cache.items() if v[0] <= now]:
+                    del _cache[k]
+                _cache[key] = (now + ttl, body)
+
+    response = jsonify(body)
+    if ttl > 0:
+        response.headers['Cache-Control'] = f'public, max-age={int(ttl)}'
+    return response
+
+
+def _public_room(room):
+    if not room.public_api:
+        abort(http.NOT_FOUND)
+
+
+def _room_info(room):
+    return {
+        'token': room.token,
+        'name': room.name,
+        'description': room.description,
+        'created': room.created,
+        'active_users': room.active_users,
+    }
+
+
+@public.get("/public/rooms")
+def public_rooms():
+    """
+    Lists the rooms available through the public archive API.
+
+    # Return value
+
+    A JSON list of room details, ordered by token.  Each is an object with keys:
+
+    - `token` — the room token.
+    - `name` — the room name.
+    - `description` — the room description, or null if not set.
+    - `created` — unix timestamp when the room was created.
+    - `active_users` — the number of recently active users of the room.
+    """
+    return _cached(lambda: [_room_info(r) for r in get_accessible_rooms() if r.public_api])
+
+
+@public.get("/public/room/<Room:room>")
+def public_room(room):
+    """
+    Returns the details of a room available through the public archive API.
+
+    # Return value
+
+    A JSON object of the room details, with the keys described in [the public room
+    list](#get-publicrooms).
+
+    # Error status codes
+
+    - 404 Not Found — if the room does not exist or is not available through the public API.
+    """
+    _public_room(room)
+    return _cached(lambda: _room_info(room))
+
+
+@public.get("/public/room/<Room:room>/messages")
+@utils.query_params('before', 'limit')
+def public_room_messages(room):
+    """
+    Returns messages of a room available through the public archive API, most recent first.  Only
+    the decoded message text, display name, and timestamps are included: session ids, signatures,
+    attachments, and reactions are not.  Messages without text are omitted.
+
+    # Query Parameters
+
+    - `before` — if given, returns messages with ids less than this (for paging back through the
+      room's history); otherwise returns the most recent messages.
+
+    - `limit` — maximum number of messages to return; defaults to 50, maximum is 100.
+
+    # Return value
+
+    A JSON list of messages, each an object with keys:
+
+    - `id` — the message id.
+    - `posted` — unix timestamp when the message was posted.
+    - `edited` — unix timestamp when the message was last edited, or null if never edited.
+    - `author` — the display name of the author (as given in the message), or null.
+    - `text` — the message text.
+
+    # Error status codes
+
+    - 404 Not Found — if the room does not exist or is not available through the public API.
+    """
+    _public_room(room)
+    before = utils.get_int_param('before', min=1)
+    limit = utils.get_int_param('limit', 50, min=1, max=100, truncate=True)
+    opts = {'reactions': False, 'system': False}
+
+    def messages():
+        if before is None:
+            msgs = room.get_messages_for(None, recent=True, limit=limit, **opts)
+        else:
+            msgs = room.get_messages_for(None, before=before, limit=limit, **opts)
+        result = []
+        for msg in msgs:
+            if msg.get('data') is None:
+                continue
+            try:
+                post = Post(raw=msg['data'])
+            except Exception:
+                continue
+            if not post.text:
+                continue
+            result.append(
+                {
+                    'id': msg['id'],
+                    'posted': msg['posted'],
+                    'edited': msg.get('edited'),
+                    'author': preview.render(room, post.username) or None,
+                    'text': preview.render(room, post.text),
+                }
+            )
+        return result
+
+    return _cached(messages)
@@ -0,0 +1,187 @@
+from .. import features, http
+from ..model import report
+from ..model.user import User
+from ..model.exc import NoSuchUser
+from ..web import app
+from . import auth
+
+from flask import abort, jsonify, g, Blueprint, request
+
+# Message reports and the moderation queue: room users report messages to the room's moderators,
+# who claim (or assign) the reports of a message so that only one of them handles it, and then
+# resolve them.  See sogs.model.report for how the queue is prioritized.  Service level statistics
+# of the queue (such as the median time taken to resolve reports) are part of the room's daily
+# statistics (see sogs.stats).
+
+
+reports = Blueprint('reports', __name__)
+
+
+@reports.post("/room/<Room:room>/report/<int:msg_id>")
+@auth.user_required
+@auth.read_required
+def report_message(room, msg_id):
+    """
+    Reports a message to the room's moderators.  Reporting a message again while the earlier report
+    is still open has no effect.
+
+    # JSON parameters
+
+    - `reason` — optional string (of up to 500 characters) explaining why the message is reported.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing key:
+
+    - `reported` — true if a new report was filed, false if the user already has an open report of
+      the message.
+
+    # Error status codes
+
+    - 400 Bad Request — if `reason` is not a string or is too long.
+    - 403 Forbidden — if the invoking user does not have read access to the room.
+    - 404 Not Found — if the message does not exist or is not visible to the invoking user, or if
+      reports are disabled in the room.
+    """
+    features.require('reports', room)
+    req = request.json if request.data else {}
+    reason = req.get('reason') if isinstance(req, dict) else None
+    if not isinstance(reason, (str, type(None))):
+        app.logger.warning("Invalid report: `reason` must be a string")
+        abort(http.BAD_REQUEST)
+
+    return jsonify({'reported': report.file_report(room, g.user, msg_id, reason=reason or None)})
+
+
+@reports.get("/room/<Room:room>/reports")
+@auth.mod_required
+def report_queue(room):
+    """
+    Returns the room's moderation queue: the messages with open reports, highest priority first.
+    All the open reports of a message are merged into a single queue item, so that a pile-on of
+    reports of one message doesn't flood the queue.  Requires moderator permission in the room.
+
+    A message's priority is the sum of the trust scores of its reporters, where a reporter's trust
+    is higher the more often their earlier reports have been acted on (and lower the more often
+    they have been dismissed); a report from a new reporter counts for 0.5.
+
+    # Return value
+
+    A JSON list of objects, one for each reported message, containing keys:
+
+    - `id` — the id of the reported message.
+    - `reports` — the number of open reports of the message.
+    - `priority` — the priority score of the message.
+    - `first_reported`, `last_reported` — unix timestamps of the first and latest open reports.
+    - `reasons` — the reasons given by reporters, in the order first given.  Identical reasons are
+      merged, so this is a list of objects with keys `reason` and `count` (the number of reporters
+      who gave the reason).
+    - `claimed_by` — the session id of the moderator who has claimed the message's reports, or null
+      if unclaimed.
+    - `claimed` — unix timestamp when the reports were claimed, or null if unclaimed.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a moderator of the room.
+    """
+    return jsonify(report.report_queue(room))
+
+
+@reports.post("/room/<Room:room>/reports/<int:msg_id>/claim")
+@auth.mod_required
+def claim_reports(room, msg_id):
+    """
+    Claims the open reports of a message for the invoking moderator, or assigns them to another
+    moderator, so that other moderators know that they are being handled.  Reports of the message
+    filed after the claim join it.  Requires moderator permission in the room; taking over reports
+    that another moderator has claimed requires admin permission.
+
+    # JSON parameters
+
+    - `assign` — optional session id of the room moderator to assign the reports to.  If omitted
+      the reports are claimed by the invoking moderator.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 400 Bad Request — if `assign` is not the session id of a moderator of the room.
+    - 403 Forbidden — if the invoking user is not a moderator of the room.
+    - 404 Not Found — if the message has no open reports.
+    - 409 Conflict — if the reports have already been claimed by another moderator (and the invoking
+      user is not an admin).
+    """
+    req = request.json if request.data else {}
+    assign = req.get('assign') if isinstance(req, dict) else None
+    assignee = None
+    if assign is not None:
+        if not isinstance(assign, str):
+            abort(http.BAD_REQUEST)
+        try:
+            assignee = User(session_id=assign, autovivify=False, try_blinding=True)
+        except NoSuchUser:
+            abort(http.BAD_REQUEST)
+
+    report.claim_reports(room, g.user, msg_id, assignee=assignee)
+    return jsonify({})
+
+
+@reports.delete("/room/<Room:room>/reports/<int:msg_id>/claim")
+@auth.mod_required
+def release_reports(room, msg_id):
+    """
+    Releases the claim on the open reports of a message, returning them to the unclaimed queue.
+    Requires being the moderator holding the claim, or admin permission in the room.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a moderator of the room.
+    - 404 Not Found — if the message has no open reports.
+    - 409 Conflict — if the reports are claimed by another moderator (and the invoking user is not
+      an admin).
+    """
+    report.release_reports(room, g.user, msg_id)
+    return jsonify({})
+
+
+@reports.post("/room/<Room:room>/reports/<int:msg_id>/resolve")
+@auth.mod_required
+def resolve_reports(room, msg_id):
+    """
+    Resolves the open reports of a message, removing it from the moderation queue.  Unclaimed
+    reports can be resolved by any moderator; claimed reports only by the moderator holding the
+    claim, or by an admin.  Note that resolving reports doesn't itself act on the message: a
+    moderator deletes the message, bans the poster, etc. through the usual endpoints.
+
+    # JSON parameters
+
+    - `resolution` — (required) `actioned` if the reports were acted on, or `dismissed` if not.
+      This feeds into the trust scores of the reporters.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing key:
+
+    - `resolved` — the number of reports resolved.
+
+    # Error status codes
+
+    - 400 Bad Request — if `resolution` is missing or invalid.
+    - 403 Forbidden — if the invoking user is not a moderator of the room.
+    - 404 Not Found — if the message has no open reports.
+    - 409 Conflict — if the reports are claimed by another moderator (and the invoking user is not
+      an admin).
+    """
+    req = request.json
+    resolution = req.get('resolution') if isinstance(req, dict) else None
+    if resolution not in report.RESOLUTIONS:
+        app.logger.warning("Invalid report resolution: must be `actioned` or `dismissed`")
+        abort(http.BAD_REQUEST)
+
+    return jsonify({'resolved': report.resolve_reports(room, g.user, msg_id, resolution)})
@@ -1,11 +1,14 @@
-from .. import config, db, http
-from ..model import room as mroom, exc, user as muser
+from .. import config, db, features, http, markup, thumbnail, utils
+from ..model import room as mroom, exc, pending_action, user as muser
 from ..web import app
 from . import auth
+from .admin import require_confirmation
+from .exc import rate_limited
 
-from flask import abort, jsonify, g, Blueprint, request, make_response, Response
+from flask import abort, jsonify, g, Blueprint, request, make_response, redirect, Response
 from werkzeug.http import http_date, parse_options_header
-from os import path, fstat
+import csv
+import io
 import urllib.parse
 import time
 
@@ -14,6 +17,21 @@ import time
 
 rooms = Blueprint('rooms', __name__)
 
+# The endpoints served from the separate user content origin ([files].content_url), if configured;
+# everything else is refused there.
+CONTENT_ENDPOINTS = {
+    'rooms.serve_file',
+    'rooms.serve_file_with_ignored_filename',
+    'rooms.serve_thumbnail',
+}
+
+
+@app.before_request
+def restrict_content_host():
+    host = auth.content_host()
+    if host is not None and request.host == host and request.endpoint not in CONTENT_ENDPOINTS:
+        abort(http.NOT_FOUND)
+
 
 def get_room_info(room):
     mods, admins, h_mods, h_admins = room.get_mods(g.user)
@@ -26,6 +44,7 @@ def get_room_info(room):
         'created': room.created,
         'active_users': room.active_users,
         'active_users_cutoff': int(config.ROOM_DEFAULT_ACTIVE_THRESHOLD),
+        'online_users': room.online_users,
         'moderators': mods,
         'admins': admins,
         'read': room.check_read(g.user),
@@ -33,12 +52,47 @@ def get_room_info(room):
         'upload': room.check_upload(g.user),
     }
 
+    if config.ROOM_ACTIVE_WINDOWS:
+        rr['active_users_windows'] = [
+            {'cutoff': int(w), 'active_users': room.active_users_last(w)}
+            for w in config.ROOM_ACTIVE_WINDOWS
+        ]
+
     if room.description is not None:
         rr['description'] = room.description
 
+    if room.rules is not None:
+        rr['rules'] = room.rules
+
+    if room.tags:
+        rr['tags'] = room.tags
+
+    if request.args.get('html') in ('1', 'true'):
+        for field in ('description_html', 'rules_html'):
+            value = getattr(room, field)
+            if value is not None:
+                rr[field] = value
+
     if room.image_id is not None:
         rr['image_id'] = room.image_id
 
+    if room.min_account_age is not None:
+        rr['min_account_age'] = room.min_account_age
+
+    if room.raid_mode_until is not None:
+        rr['raid_mode_until'] = room.raid_mode_until
+
+    if room.archived is not None:
+        rr['archived'] = room.archived
+
+    disabled = features.disabled(room)
+    if disabled:
+        rr['disabled_features'] = disabled
+
+    if room.sensitive:
+        rr['sensitive'] = True
+        rr['acknowledged'] = room.acknowledged(g.user)
+
     pinned = room.pinned_messages
     if pinned:
         rr['pinned_messages'] = pinned
@@ -54,6 +108,9 @@ def get_room_info(room):
         rr['default_accessible'] = room.default_accessible
         rr['default_write'] = room.default_write
         rr['default_upload'] = room.default_upload
+        owner = room.owner
+        if owner is not None:
+            rr['owner'] = owner.session_id
     if room.check_admin(g.user):
         rr['admin'] = True
     if g.user:
@@ -71,13 +128,26 @@ def get_one_room(room):
     """
     Returns the details of a single room.
 
+    # Query Parameters
+
+    - `html` — if set to `1` then the returned details include pre-rendered HTML versions of the
+      room's description and rules (see below).
+
     # Return value
 
     A JSON object with keys:
 
     - `token` — The room token as used in a URL, e.g. `"sudoku"`.
     - `name` — The room name typically shown to users, e.g. `"Sodoku Solvers"`.
-    - `description` — Text description of the room, e.g. `"All the best sodoku discussion!"`.
+    - `description` — Text description of the room, e.g. `"All the best sodoku discussion!"`.  The
+      description may use simple markdown formatting.
+    - `rules` — The room's rules, which may use simple markdown formatting.  Omitted if the room
+      has no rules.
+    - `tags` — Sorted array of the room's tags, such as languages or topics (e.g. `["de",
+      "gaming"]`).  Omitted if the room has no tags.
+    - `description_html`, `rules_html` — The room description and rules rendered from markdown as
+      HTML, which is sanitized and safe to include directly in a web page.  These are only included
+      if the `html=1` query parameter is given (and the description/rules are set).
     - `info_updates` — Monotonic integer counter that increases whenever the room's metadata changes
     - `message_sequence` — Monotonic room post counter that increases each time a message is posted,
       edited, or deleted in this room.  (Note that changes to this field do *not* imply an update
@@ -91,8 +161,33 @@ def get_one_room(room):
       **Note:** changes to this field do *not* update the room's `info_updates` value.
     - `active_users_cutoff` — The length of time (in seconds) of the `active_users` period.
       Defaults to a week (604800), but the open group administrator can configure it.
+    - `active_users_windows` — Number of active users over other periods, if the open group
+      administrator has configured any (for example, daily and weekly active users).  This is a list
+      of objects, from shortest to longest period, each containing `cutoff` (the length of the
+      period, in seconds) and `active_users` (the number of users active in the room in the period).
+      Omitted if no extra periods are configured.  **Note:** changes to this field do *not* update
+      the room's `info_updates` value.
+    - `online_users` — Approximate number of users currently polling the room, i.e. users who have
+      checked the room for new messages within the last minute or so.  This value is kept only in
+      server memory and is approximate.  **Note:** changes to this field do *not* update the room's
+      `info_updates` value.
     - `image_id` — File ID of an uploaded file containing the room's image.  Omitted if there is no
       image.
+    - `min_account_age` — The time (in seconds) that a Session id must have been known to the server
+      before it may post or upload in the room.  Omitted if the room has no such requirement.
+    - `raid_mode_until` — If the room is in [raid mode](#post-roomroomraid_mode), the unix timestamp
+      at which raid mode ends.  Omitted if the room is not in raid mode.
+    - `archived` — If the room has been [archived](#post-roomroomarchive), the unix timestamp at
+      which it was archived.  Archived rooms are read-only.  Omitted if the room is not archived.
+    - `disabled_features` — list of the features (`reactions`, `uploads`, `translate`, `reports`)
+      that the server operator has disabled in the room, whose endpoints return 404 Not Found.
+      Omitted if none are disabled.
+    - `sensitive` — True if the room is flagged as containing sensitive content, in which case
+      users (other than moderators) can only read or post in the room after first
+      [acknowledging](#post-roomroomacknowledge) the flag; clients should show an interstitial
+      rather than the room content until then.  Omitted if the room is not flagged.
+    - `acknowledged` — Included (only) when `sensitive` is true: true if the invoking user has
+      acknowledged the room's sensitive content flag.
     - `pinned_messages` — Array of pinned message information (omitted entirely if there are no
       pinned messages).  Each array element is an object with keys:
         * `id` — The numeric message id.
@@ -154,11 +249,24 @@ def get_one_room(room):
 
     - 404 Not Found — Returned if the room does not exist, or is configured as inaccessible (and
       this user doesn't have access).
+
+    # HEAD requests
+
+    A `HEAD` request returns no body, but includes the room's current `message_sequence` and
+    `info_updates` values in the `X-SOGS-Message-Sequence` and `X-SOGS-Info-Updates` response
+    headers.  This is a cheap way for a client to check whether it has missed any activity (for
+    instance after a gap in push notifications) without fetching the full room details.
     """
+    if request.method == 'HEAD':
+        resp = make_response('')
+        resp.headers['X-SOGS-Message-Sequence'] = str(room.message_sequence)
+        resp.headers['X-SOGS-Info-Updates'] = str(room.info_updates)
+        return resp
     return jsonify(get_room_info(room))
 
 
 @rooms.get("/rooms")
+@utils.query_params('tag', 'html')
 def get_rooms():
     """
     Returns a list of available rooms on the server.
@@ -166,16 +274,27 @@ def get_rooms():
     Rooms to which the user does not have access (e.g. because they are banned, or the room has
     restricted access permissions) are not included.
 
+    # Query Parameters
+
+    - `tag` — if given, only rooms with this tag are returned.  May be specified multiple times
+      (e.g. `?tag=de&tag=gaming`) to return only rooms that have *all* of the given tags.
+    - `html` — includes pre-rendered HTML descriptions and rules; see [the single-room
+      version](#get-roomroom).
+
     # Return value
 
     Returns a json list of the rooms.  Each room is an JSON object as would be returned by [the
-    single-room version](#get-roomroom) of this call.
+    single-room version](#get-roomroom) of this call (including the same `html` query parameter).
     """
-    return jsonify([get_room_info(room=r) for r in mroom.get_accessible_rooms(g.user)])
+    rooms = mroom.get_accessible_rooms(g.user)
+    tags = request.args.getlist('tag')
+    if tags:
+        with_tags = mroom.get_room_ids_with_tags(tags)
+        rooms = [r for r in rooms if r.id in with_tags]
+    return jsonify([get_room_info(room=r) for r in rooms])
 
 
 BAD_NAME_CHARS = {c: None for c in range(32)}
-BAD_DESCRIPTION_CHARS = {c: None for c in range(32) if not (0x09 <= c <= 0x0A)}
 
 
 @rooms.put("/room/<Room:room>")
@@ -194,12 +313,21 @@ def update_room(room):
       tabs and other control characters (i.e. all codepoints below \u0020) will be stripped out.
     - `description` — Long description to show to users, typically in smaller text below the room
       name.  UTF-8 encoded, and permits newlines, tabs; other control characters below \u0020 will
-      be stripped out.  Can be `null` or an empty string to remove the description entirely.
+      be stripped out.  Can be `null` or an empty string to remove the description entirely.  May
+      use simple markdown formatting (see `sogs/markup.py` for the supported subset).
+    - `rules` — The room's rules, shown to users joining the room.  As with `description` this may
+      use markdown, control characters other than newlines and tabs are stripped, and `null` or an
+      empty string removes the rules.
+    - `tags` — Array of tags (such as languages or topics) for the room directory, replacing any
+      existing tags.  Tags are converted to lower case and may contain only letters, digits, `_`,
+      and `-`, up to 32 characters each; a room may have at most 16 tags.
     - `default_read`, `default_accessible`, `default_write`, `default_upload` — if specified these
       update the room's default read, access, write, and upload permissions for ordinary users (i.e.
       users who do not have any other user-specific permission applied).  See the description of
       Access permissions in the (room information)[#get-roomroom] endpoint for details.
     - `image` — The file id of an image that was uploaded in this room to use as the room icon.
+    - `sensitive` — Boolean; flags (or unflags) the room as containing sensitive content, which
+      users must [acknowledge](#post-roomroomacknowledge) before they can read or post in the room.
 
     # Return value
 
@@ -232,12 +360,25 @@ def update_room(room):
             if not (d is None or isinstance(d, str)):
                 app.logger.warning(f"Room update: invalid description: {type(d)} is not str, null")
                 abort(http.BAD_REQUEST)
-            if d is not None:
-                d = d.translate(BAD_DESCRIPTION_CHARS)
-                if len(d) == 0:
-                    d = None
-
-            room.description = d
+            room.description = markup.sanitize(d)
+            did = True
+        if 'rules' in req:
+            rules = req['rules']
+            if not (rules is None or isinstance(rules, str)):
+                app.logger.warning(f"Room update: invalid rules: {type(rules)} is not str, null")
+                abort(http.BAD_REQUEST)
+            room.rules = rules
+            did = True
+        if 'tags' in req:
+            tags = req['tags']
+            if not isinstance(tags, list) or not all(isinstance(t, str) for t in tags):
+                app.logger.warning("Room update: invalid tags: expected a list of strings")
+                abort(http.BAD_REQUEST)
+            try:
+                room.tags = tags
+            except exc.InvalidData:
+                app.logger.warning(f"Room update: invalid tags {tags}")
+                abort(http.BAD_REQUEST)
             did = True
         read, accessible, write, upload = (
             req.get('default_' + x) for x in ('read', 'accessible', 'write', 'upload')
@@ -253,6 +394,13 @@ def update_room(room):
                 app.logger.warning(f"Room image update invalid: {e}")
                 abort(http.NOT_ACCEPTABLE)
             did = True
+        if 'sensitive' in req:
+            sensitive = req['sensitive']
+            if not isinstance(sensitive, bool):
+                app.logger.warning(f"Room update: invalid sensitive: {type(sensitive)} != bool")
+                abort(http.BAD_REQUEST)
+            room.sensitive = sensitive
+            did = True
 
         for val in (read, accessible, write, upload):
             if not (val is None or isinstance(val, bool) or isinstance(val, int)):
@@ -282,6 +430,33 @@ def update_room(room):
     return jsonify({"info_updates": room.info_updates})
 
 
+@rooms.delete("/room/<Room:room>")
+@auth.global_admin_required
+def delete_room(room):
+    """
+    Permanently deletes the room, including all of its messages and files.  Requires global admin
+    permission.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    If the server requires confirmation of destructive actions by a second admin, this instead
+    returns a 202 status code with a JSON object containing the `pending_action` awaiting
+    confirmation; see [the pending actions endpoint](#get-adminpending).
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not a global admin.
+    """
+    pending = require_confirmation('delete_room', room=room)
+    if pending:
+        return pending
+    room.delete()
+    app.logger.warning(f"{g.user} deleted {room}")
+    return jsonify({})
+
+
 def addExtraPermInfo(perms):
     """
     Apply some cleanups/simplifications for more digestable permission indicators by clients.
@@ -703,6 +878,7 @@ def poll_room_info(room, info_updated):
 
     - `token`
     - `active_users`
+    - `online_users`
     - `read`, `write`, `upload`
     - `moderator`, `admin`, `global_moderator`, `global_admin`
     - `default_read`, `default_accessible`, `default_write`, `default_upload`
@@ -726,6 +902,7 @@ def poll_room_info(room, info_updated):
     result = {
         'token': room.token,
         'active_users': room.active_users,
+        'online_users': room.online_users,
         'read': room.check_read(g.user),
         'write': room.check_write(g.user),
         'upload': room.check_upload(g.user),
@@ -753,6 +930,7 @@ def poll_room_info(room, info_updated):
 
 @rooms.post("/room/<Room:room>/file")
 @auth.user_required
+@auth.throttled('upload')
 def upload_file(room):
     """
     Uploads a file to a room.
@@ -776,7 +954,8 @@ def upload_file(room):
 
     This should be set to application/octet-stream.  If the client has a strong reason to use
     another content type then it may do so, but it is acceptable to always use
-    `application/octet-stream`.
+    `application/octet-stream`.  The server may be configured to only accept certain content types
+    (via [files] `content_types`); a missing Content-Type is treated as `application/octet-stream`.
 
     ## Content-Disposition
 
@@ -800,7 +979,9 @@ def upload_file(room):
     image) but should be used when possible.
 
     The filename, if provided, will be provided in the same format in the download header for the
-    file.
+    file, with control characters, path separators, and bidirectional text formatting characters
+    replaced by U+FFFD, surrounding whitespace and leading dots removed, and overlong names
+    truncated.
 
     # Error status codes
 
@@ -808,7 +989,24 @@ def upload_file(room):
       upload files to the room.
 
     - 404 Not Found — Returned if the room does not exist, or is configured as inaccessible (and
-      this user doesn't have access).
+      this user doesn't have access), or if uploads are disabled in the room.
+
+    - 413 Payload Too Large — Returned if the file is larger than the server's maximum upload size.
+
+    - 415 Unsupported Media Type — Returned if the upload's Content-Type is not one of the content
+      types the server accepts.
+
+    - 429 Too Many Requests — Returned if the user is uploading files too frequently.  The JSON body
+      contains `error`, `scope` (`upload_rate`), `limit`, `remaining`, `reset`, and `retry_after`
+      fields describing the limit, which are also provided in the `Retry-After` and `RateLimit-*`
+      headers.
+
+    - 507 Insufficient Storage — Returned if the upload would exceed the room's, the server's, or
+      the user's file storage limit.  The JSON body contains `error` (a description), `scope`
+      (`room_storage`, `server_storage`, or `user_storage`), `limit` and `used` (the limit, and how
+      much of it is already used, in bytes), `remaining` (the bytes still available), and `reset`
+      (always null, as storage limits don't reset: space is freed as existing files expire or are
+      deleted).
 
     # Return value
 
@@ -819,9 +1017,18 @@ def upload_file(room):
       post edit, or room image request within one hour then the attachment will be deleted.
     """
 
+    features.require('uploads', room)
     if not room.check_upload(g.user):
         abort(http.FORBIDDEN)
 
+    if len(request.data) > config.UPLOAD_FILE_MAX_SIZE:
+        abort(http.PAYLOAD_TOO_LARGE)
+
+    content_type = request.mimetype or 'application/octet-stream'
+    if config.UPLOAD_CONTENT_TYPES and content_type not in config.UPLOAD_CONTENT_TYPES:
+        app.logger.warning(f"Refusing upload to {room.token}: content type {content_type}")
+        abort(http.UNSUPPORTED_MEDIA_TYPE)
+
     filename = None
     # parse filename, this is god awful
     for k, v in request.headers:
@@ -831,12 +1038,31 @@ def upload_file(room):
                 filename = cd[1]['filename']
 
     # 1 hour lifetime before link to post
-    id = room.upload_file(request.data, g.user, filename=filename, lifetime=3600.0)
+    id = room.upload_file(
+        request.data, g.user, filename=filename, content_type=request.mimetype, lifetime=3600.0
+    )
     resp = make_response(jsonify({"id": id}))
     resp.status_code = http.CREATED
     return resp
 
 
+def check_egress(room):
+    """
+    Aborts with a 429 Too Many Requests error (see exc.rate_limited, with the limit reset at the
+    start of the next accounting period) if the room has reached its monthly file download cap.
+    """
+    if room.egress_exceeded():
+        app.logger.warning(f"Refusing file download from {room.token}: monthly egress cap reached")
+        abort(
+            rate_limited(
+                "Room monthly file download limit reached",
+                scope='room_egress',
+                limit=room.egress_cap,
+                reset=time.time() + mroom.egress_period_remaining(),
+            )
+        )
+
+
 @rooms.get("/room/<Room:room>/file/<int:fileId>")
 @auth.read_required
 def serve_file(room, fileId):
@@ -862,12 +1088,15 @@ def serve_file(room, fileId):
 
     ## Content-Type
 
-    Always `application-octet-stream` (even if the uploader specified something else).
+    `application/octet-stream` (even if the uploader specified something else), unless the server
+    permits the content type specified by the uploader to be displayed inline (see [files]
+    `inline_types`), in which case it is that content type.
 
     ## Content-Disposition
 
-    This specifies the suggested filename as provided by the uploader, if present.  The filename is
-    encoded using standard RFC 5987 encoding, for example:
+    `attachment`, or `inline` for a file served with its uploaded content type, followed by the
+    suggested filename as provided by the uploader (with unsafe characters replaced), if present.
+    The filename is encoded using standard RFC 5987 encoding, for example:
 
         Content-Disposition: attachment; filename*=UTF-8''filename.txt
 
@@ -889,29 +1118,65 @@ def serve_file(room, fileId):
     - 403 Forbidden — Returned if the current user does not have permission to read messages in the
       room, e.g. because they are banned or the room permissions otherwise restrict access.
 
-    - 404 Not Found — Returned if the attachment does not exist in this room (or has expired).
+    - 404 Not Found — Returned if the attachment does not exist in this room (or has expired), or
+      if it matched a banned image when uploaded and the current user is not a moderator.
+
+    - 429 Too Many Requests — Returned if the room has reached its monthly file download limit.  The
+      `Retry-After` header contains the number of seconds until downloads will be available again,
+      and the JSON body contains the limit details (with `scope` set to `room_egress`).
     """
     room_file = room.get_file(fileId)
-    if not room_file:
+    if not room_file or (room_file.quarantined and not room.check_moderator(g.user)):
         abort(http.NOT_FOUND)
 
-    f = open(path.join(path.abspath(path.curdir), room_file.path), 'rb')
+    # Plain requests are sent off to the separate user content origin, if we have one.  We've
+    # already authenticated the request (using up its nonce), so the redirect carries a token
+    # authorizing the download instead:
+    host = auth.content_host()
+    if host is not None and request.host != host and not request.environ.get('sogs.subrequest'):
+        args = request.args.to_dict()
+        args['content_token'] = auth.content_token(request.path, g.user)
+        return redirect(
+            config.FILES_CONTENT_URL.rstrip('/') + request.path + '?' + urllib.parse.urlencode(args)
+        )
+
+    check_egress(room)
 
-    headers = {
-        'Date': http_date(room_file.uploaded),
-        'Content-Length': fstat(f.fileno()).st_size,
-        'Content-Disposition': 'attachment',
-    }
+    content_type, disposition = 'application/octet-stream', 'attachment'
+    if room_file.content_type in config.FILES_INLINE_TYPES:
+        content_type, disposition = room_file.content_type, 'inline'
     if room_file.filename:
-        headers['Content-Disposition'] = "attachment; filename*=UTF-8''{}".format(
+        disposition += "; filename*=UTF-8''{}".format(
             urllib.parse.quote(room_file.filename.encode('utf-8'))
         )
+
+    # Onion requests have to be answered by us, but plain requests can be sent off to the object
+    # store when using presigned S3 URLs.
+    if not request.environ.get('sogs.subrequest'):
+        url = room_file.download_url(disposition, content_type)
+        if url:
+            room.record_egress(room_file)
+            return redirect(url)
+
+    try:
+        f = room_file.open()
+    except FileNotFoundError:
+        app.logger.error(f"File {room_file.id} content is missing from storage")
+        abort(http.NOT_FOUND)
+
+    headers = {
+        'Date': http_date(room_file.uploaded),
+        'Content-Length': room_file.size,
+        'Content-Disposition': disposition,
+        'X-Content-Type-Options': 'nosniff',
+        'Content-Security-Policy': "default-src 'none'; sandbox",
+    }
     if room_file.expiry:
         headers["Expires"] = http_date(room_file.expiry)
 
-    return Response(
-        response=f, status=200, content_type='application/octet-stream', headers=headers
-    )
+    room.record_egress(room_file)
+
+    return Response(response=f, status=200, content_type=content_type, headers=headers)
 
 
 @rooms.get("/room/<Room:room>/file/<int:fileId>/<filename>")
@@ -937,18 +1202,561 @@ def serve_file_with_ignored_filename(room, fileId, filename):
     return serve_file(room=room, fileId=fileId)
 
 
+@rooms.get("/room/<Room:room>/thumbnail/<int:fileId>")
+@auth.read_required
+def serve_thumbnail(room, fileId):
+    """
+    Retrieves a downscaled thumbnail of an image uploaded to the room, such as the room image.
+
+    Thumbnails are only available for unencrypted images (Session attachments are encrypted, and so
+    cannot be thumbnailed).  The thumbnail is no larger than the server's configured thumbnail size
+    (256 pixels by default) in either dimension, preserving the image's aspect ratio.
+
+    # URL Parameters
+
+    - `fileId` — The id of the uploaded image.
+
+    # Return value
+
+    On success the thumbnail image is returned in the response body, with a `Content-Type` of
+    `image/png` (for images with transparency) or `image/jpeg`.  The `Date` and `Expires` headers
+    are set as for [the file endpoint](#get-roomroomfilefileid).
+
+    # Error status codes
+
+    - 403 Forbidden — Returned if the current user does not have permission to read messages in the
+      room.
+
+    - 404 Not Found — Returned if the file does not exist in this room (or has expired, or is
+      quarantined and the current user is not a moderator), if it is not an image, or if thumbnails
+      are disabled on this server.
+    """
+    room_file = room.get_file(fileId)
+    if not room_file or (room_file.quarantined and not room.check_moderator(g.user)):
+        abort(http.NOT_FOUND)
+
+    try:
+        thumb = thumbnail.get(room_file)
+    except FileNotFoundError:
+        app.logger.error(f"File {room_file.id} content is missing from storage")
+        abort(http.NOT_FOUND)
+    if thumb is None:
+        abort(http.NOT_FOUND)
+
+    data, content_type = thumb
+    headers = {'Date': http_date(room_file.uploaded)}
+    if room_file.expiry:
+        headers["Expires"] = http_date(room_file.expiry)
+    return Response(response=data, status=200, content_type=content_type, headers=headers)
+
+
+@rooms.post("/room/<Room:room>/file/<int:fileId>/ban")
+@auth.mod_required
+def ban_file_image(room, fileId):
+    """
+    Bans an image uploaded to the room: the image is no longer served to non-moderators, and any
+    future upload to any room on the server of an image that is perceptually similar (e.g. the same
+    image re-encoded or resized) is rejected or quarantined, depending on the server configuration.
+    Requires moderator permission.
+
+    # URL Parameters
+
+    - `fileId` — The id of the uploaded image to ban.
+
+    # JSON parameters
+
+    - `reason` — optional text describing the reason for the ban.
+
+    # Return value
+
+    On success returns a 201 (Created) status code with a JSON object containing keys:
+
+    - `id` — the id of the new image ban.
+    - `phash` — the perceptual hash of the banned image, in hex.
+
+    # Error status codes
+
+    - 400 Bad Request — if the file is not an image, or `reason` is invalid.
+    - 403 Forbidden — Returned if the invoking user does not have moderator permission in the room.
+    - 404 Not Found — Returned if the file does not exist in this room (or has expired).
+    """
+    req = request.json
+    reason = req.get('reason') if isinstance(req, dict) else None
+    if reason is not None and not isinstance(reason, str):
+        app.logger.warning(f"Invalid image ban reason: {type(reason)} is not a string")
+        abort(http.BAD_REQUEST)
+
+    room_file = room.get_file(fileId)
+    if not room_file:
+        abort(http.NOT_FOUND)
+
+    ban_id = room_file.ban_image(by=g.user, reason=reason or None)
+    if ban_id is None:
+        app.logger.warning(f"Cannot ban file {room_file.id}: not an image")
+        abort(http.BAD_REQUEST)
+    return jsonify({'id': ban_id, 'phash': room_file.phash}), http.CREATED
+
+
+@rooms.get("/room/<Room:room>/egress")
+@auth.mod_required
+def get_room_egress(room):
+    """
+    Retrieves file download (egress) statistics for the room.  Requires moderator permission.
+
+    # Return value
+
+    A JSON object with keys:
+
+    - `period` — the current accounting period, i.e. the current UTC month in `YYYY-MM` format.
+    - `bytes` — the number of bytes of file downloads served from the room in the current period.
+    - `cap` — the monthly egress limit of the room, in bytes, or null if the room has no limit.
+      Once `bytes` reaches `cap` the room's attachments cannot be downloaded until the next period.
+    - `history` — an object of `"YYYY-MM": bytes` pairs for each recorded period.
+    - `top_files` — a list of up to 10 of the room's files with the highest all-time egress, each
+      an object with keys `id`, `size`, `downloads`, and `egress` (in bytes).
+
+    # Error status codes
+
+    - 403 Forbidden — Returned if the invoking user does not have moderator permission in the room.
+    """
+    return jsonify(room.egress_stats())
+
+
+@rooms.get("/room/<Room:room>/stats")
+@utils.query_params('since', 'until')
+@auth.mod_required
+def get_room_stats(room):
+    """
+    Retrieves daily activity statistics for the room.  Requires moderator permission.
+
+    Statistics are rolled up nightly (see the `stats_rollup` job of the [schedule] config section),
+    and so are available only for completed UTC days, up to the most recent rollup.
+
+    # Query Parameters
+
+    - `since` — if given, only return statistics for days starting at or after this unix timestamp.
+
+    - `until` — if given, only return statistics for days starting before this unix timestamp.
+
+    # Return value
+
+    A JSON list of daily statistics objects, from oldest to newest, each containing keys:
+
+    - `day` — the unix timestamp of the start of the UTC day.
+    - `messages` — the number of messages posted to the room during the day (including messages
+      that have since been deleted, but not messages held by the room's message filters).
+    - `posters` — the number of distinct users who posted during the day.
+    - `uploads` — the number of files uploaded to the room during the day.
+    - `upload_bytes` — the total size of files uploaded to the room during the day, in bytes.
+    - `reports` — the number of message reports filed during the day.
+    - `reports_resolved` — the number of message reports resolved during the day.
+    - `reports_actioned` — how many of the reports resolved during the day were actioned (rather
+      than dismissed).
+    - `median_claim` — the median time, in seconds, from a report being filed until it was claimed
+      by a moderator, of the reports resolved during the day that were claimed; null if there are
+      none.
+    - `median_resolution` — the median time, in seconds, from a report being filed until it was
+      resolved, of the reports resolved during the day; null if there are none.
+
+    # Error status codes
+
+    - 403 Forbidden — Returned if the invoking user does not have moderator permission in the room.
+    """
+    return jsonify(
+        room.daily_stats(
+            since=utils.get_int_param('since', min=0), until=utils.get_int_param('until', min=0)
+        )
+    )
+
+
+@rooms.get("/room/<Room:room>/member_stats")
+@utils.query_params('format')
+@auth.mod_required
+def get_member_stats(room):
+    """
+    Exports per-user activity statistics of the room, to inform community governance decisions.
+    Requires moderator permission.  Users are identified only by their blinded session ids.
+
+    # Query Parameters
+
+    - `format` — `json` (the default) or `csv`.
+
+    # Return value
+
+    For `json`, a JSON list with one object for each user who has posted in or accessed the room,
+    containing keys:
+
+    - `session_id` — the user's blinded session id (`15...`).
+    - `posts` — the number of the user's messages in the room, not including deleted messages.
+    - `deleted` — the number of the user's messages in the room that have been deleted.
+    - `first_post`, `last_post` — the unix timestamps of the user's first and most recent posts in
+      the room, or null if the user has never posted.
+    - `last_active` — the unix timestamp of when the user last accessed the room, or null if not
+      recorded.
+
+    For `csv`, the same values as a `text/csv` attachment with a header row of these key names.
+
+    # Error status codes
+
+    - 400 Bad Request — if `format` is not `json` or `csv`.
+    - 403 Forbidden — Returned if the invoking user does not have moderator permission in the room.
+    """
+    fmt = request.args.get('format', 'json')
+    if fmt not in ('json', 'csv'):
+        abort(http.BAD_REQUEST)
+
+    stats = room.member_stats()
+    if fmt == 'json':
+        return jsonify(stats)
+
+    out = io.StringIO()
+    writer = csv.DictWriter(
+        out, ('session_id', 'posts', 'deleted', 'first_post', 'last_post', 'last_active')
+    )
+    writer.writeheader()
+    writer.writerows(stats)
+    return Response(
+        out.getvalue(),
+        mimetype='text/csv',
+        headers={'Content-Disposition': f'attachment; filename="{room.token}-members.csv"'},
+    )
+
+
+@rooms.post("/room/<Room:room>/raid_mode")
+@auth.mod_required
+def start_raid_mode(room):
+    """
+    Puts the room into raid mode: a temporary strict preset for fending off a flood of unwanted
+    posts.  While in raid mode, users other than moderators:
+
+    - may only post once every 30 seconds (configurable on the server);
+    - must have been known to the server for at least 24 hours (configurable on the server) to post
+      or upload;
+    - cannot upload files;
+    - cannot post messages containing links.
+
+    Raid mode ends automatically after the given duration, or can be ended early via [the DELETE
+    version](#delete-roomroomraid_mode) of this endpoint.  Starting raid mode while the room is
+    already in raid mode replaces the end time.  Requires moderator permission.
+
+    # JSON parameters
+
+    - `duration` — How long raid mode should last, in seconds.  If omitted the server default
+      (typically one hour) applies.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing:
+
+    - `raid_mode_until` — the unix timestamp at which raid mode will end.
+
+    # Error status codes
+
+    - 400 Bad Request — if `duration` is invalid.
+    - 403 Forbidden — Returned if the invoking user does not have moderator permission in the room.
+    """
+    req = request.json
+    duration = req.get('duration') if isinstance(req, dict) else None
+    if duration is not None and (
+        isinstance(duration, bool) or not isinstance(duration, (int, float)) or duration <= 0
+    ):
+        app.logger.warning(f"Invalid raid mode duration: {duration}")
+        abort(http.BAD_REQUEST)
+
+    room.start_raid_mode(g.user, duration)
+    return jsonify({'raid_mode_until': room.raid_mode_until})
+
+
+@rooms.delete("/room/<Room:room>/raid_mode")
+@auth.mod_required
+def end_raid_mode(room):
+    """
+    Ends raid mode in the room before its scheduled end.  Does nothing if the room is not in raid
+    mode.  Requires moderator permission.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 403 Forbidden — Returned if the invoking user does not have moderator permission in the room.
+    """
+    room.end_raid_mode(g.user)
+    return jsonify({})
+
+
+@rooms.post("/room/<Room:room>/acknowledge")
+@auth.user_required
+@auth.accessible_required
+def acknowledge_room(room):
+    """
+    Acknowledges the room's sensitive content flag (see the `sensitive` field of the [room
+    information](#get-roomroom) endpoint), after which the invoking user has their usual read and
+    write access to the room.  Acknowledging a room that is not flagged is permitted (and is
+    remembered in case the room is flagged later).
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing keys:
+
+    - `read`, `write`, `upload` — the invoking user's permissions in the room after acknowledging.
+
+    # Error status codes
+
+    - 404 Not Found — if the room does not exist, or is not accessible to the invoking user.
+    """
+    room.acknowledge(g.user)
+    return jsonify(
+        {
+            'read': room.check_read(g.user),
+            'write': room.check_write(g.user),
+            'upload': room.check_upload(g.user),
+        }
+    )
+
+
+@rooms.get("/room/<Room:room>/challenge")
+@auth.user_required
+@auth.read_required
+def room_pow_challenge(room):
+    """
+    Issues a proof-of-work challenge for the invoking user's first post to the room.  Rooms can
+    require new posters to solve such a challenge, as a measure against spam from freshly created
+    Session IDs; the solved challenge is then included with the user's [first
+    post](#post-roomroommessage).
+
+    A solution is any string of up to 64 bytes such that the BLAKE2b hash (with a 32-byte digest
+    and the personalization string `sogs.pow`) of the challenge bytes followed by the solution
+    bytes begins with at least `difficulty` zero bits.  Challenges are only valid for the user and
+    room they were issued for, and expire after 10 minutes.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing keys:
+
+    - `difficulty` — the number of leading zero bits required.  This is 0 if the user doesn't need
+      to solve a challenge (because the room doesn't require one, or the user has posted to the room
+      before, or is exempt), in which case the other keys are omitted.
+    - `challenge` — the base64-encoded challenge.
+    - `expires` — unix timestamp at which the challenge expires.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user does not have read access to the room.
+    """
+    return utils.jsonify_with_base64(room.issue_pow_challenge(g.user))
+
+
+@rooms.post("/room/<Room:room>/archive")
+@auth.admin_required
+def archive_room(room):
+    """
+    Archives the room, making it read-only: until the room is unarchived nobody other than room
+    admins can post, upload files, or add reactions.  Rooms can also be archived automatically by
+    the server after a period of inactivity.  Does nothing if the room is already archived.
+    Requires admin permission.
+
+    # Return value
+
+    On success returns a 200 status code with a JSON object containing:
+
+    - `archived` — the unix timestamp at which the room was archived.
+
+    # Error status codes
+
+    - 403 Forbidden — Returned if the invoking user does not have admin permission in the room.
+    """
+    room.archive(g.user)
+    return jsonify({'archived': room.archived})
+
+
+@rooms.delete("/room/<Room:room>/archive")
+@auth.admin_required
+def unarchive_room(room):
+    """
+    Unarchives the room, making it writable again.  Unarchiving counts as room activity, so the
+    server's inactive room policy will not archive the room again until it has been inactive for the
+    configured time.  Does nothing if the room is not archived.  Requires admin permission.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 403 Forbidden — Returned if the invoking user does not have admin permission in the room.
+    """
+    room.unarchive(g.user)
+    return jsonify({})
+
+
+def get_transfer_info(transfer):
+    return {
+        'from': transfer['from'],
+        'to': transfer['to'],
+        'transfer_admins': transfer['transfer_admins'],
+        'nonce': utils.encode_base64(transfer['nonce']),
+        'created': transfer['created'],
+        'expires': transfer['expires'],
+    }
+
+
+@rooms.get("/room/<Room:room>/transfer")
+@auth.user_required
+def get_room_transfer(room):
+    """
+    Returns the pending transfer of ownership of the room.  This is available to room admins and to
+    the recipient of the transfer.
+
+    # Return value
+
+    A JSON object with keys:
+
+    - `from` — the session id of the user who started the transfer.
+    - `to` — the session id of the new owner.
+    - `transfer_admins` — true if all other room admins will lose their admin permission when the
+      transfer is accepted.
+    - `nonce` — a random value (in base64 encoding) that must be signed to accept the transfer; see
+      [the accept endpoint](#post-roomroomtransferaccept).
+    - `created` — unix timestamp when the transfer was started.
+    - `expires` — unix timestamp when the transfer expires if not accepted.
+
+    # Error status codes
+
+    - 404 Not Found — if there is no pending transfer of the room, or the invoking user is neither
+      a room admin nor the recipient of the transfer.
+    """
+    transfer = room.get_transfer()
+    if transfer is None or not (transfer['to'] == g.user.session_id or room.check_admin(g.user)):
+        abort(http.NOT_FOUND)
+    return jsonify(get_transfer_info(transfer))
+
+
+@rooms.post("/room/<Room:room>/transfer/<SessionID:sid>")
+@auth.admin_required
+def start_room_transfer(room, sid):
+    """
+    Starts a transfer of ownership of the room to the given Session id, replacing any pending
+    transfer.  The transfer only takes effect once the new owner accepts it via [the accept
+    endpoint](#post-roomroomtransferaccept); if not accepted within a server-configured time
+    (typically two days) it expires.
+
+    Once a room has an owner, only the owner (or a global admin) can transfer it; until then any
+    room admin can.
+
+    # JSON parameters
+
+    - `transfer_admins` — if true then, when the transfer is accepted, all other room admins
+      (including the current owner) lose their admin permission, although they remain room
+      moderators.  The default, false, leaves other admins unchanged.
+
+    # Return value
+
+    On success returns a 201 status code with the new transfer, in the same format as [the GET
+    version](#get-roomroomtransfer) of this endpoint.
+
+    # Error status codes
+
+    - 400 Bad Request — if `transfer_admins` is invalid, or the Session id is not a permitted
+      recipient (e.g. is banned from the room, or is already the owner).
+    - 403 Forbidden — if the invoking user is not permitted to transfer the room.
+    - 409 Conflict — if the recipient already owns the maximum number of rooms permitted by the
+      server.
+    """
+    req = request.json
+    transfer_admins = req.get('transfer_admins', False) if isinstance(req, dict) else False
+    if not isinstance(transfer_admins, bool):
+        app.logger.warning(f"Invalid room transfer transfer_admins value: {transfer_admins}")
+        abort(http.BAD_REQUEST)
+
+    transfer = room.start_transfer(
+        muser.User(session_id=sid, try_blinding=True),
+        by=g.user,
+        transfer_admins=transfer_admins,
+    )
+    return jsonify(get_transfer_info(transfer)), http.CREATED
+
+
+@rooms.delete("/room/<Room:room>/transfer")
+@auth.user_required
+def cancel_room_transfer(room):
+    """
+    Cancels the pending ownership transfer of the room.  This can be done by anyone permitted to
+    start a transfer, or by the transfer's recipient to decline it.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not permitted to cancel the transfer.
+    - 404 Not Found — if there is no pending transfer of the room.
+    """
+    if not room.cancel_transfer(g.user):
+        abort(http.NOT_FOUND)
+    return jsonify({})
+
+
+@rooms.post("/room/<Room:room>/transfer/accept")
+@auth.user_required
+def accept_room_transfer(room):
+    """
+    Accepts the pending transfer of ownership of the room to the invoking user, who becomes the
+    room's owner and an admin.  The acceptance (including its signature) is recorded in the server's
+    audit journal.
+
+    # JSON parameters
+
+    - `pubkey` — the Ed25519 public key of the invoking user's Session id (hex or base64 encoded).
+      For a blinded id this is the blinded key.
+    - `signature` — the Ed25519 signature (hex or base64 encoded), by `pubkey`, of the bytes
+      `sogs-room-transfer` || `SERVER_PUBKEY` || `TOKEN` || `NONCE`, where `SERVER_PUBKEY` is the
+      server's 32-byte X25519 pubkey, `TOKEN` the room token (in UTF-8), and `NONCE` the decoded
+      `nonce` value of the pending transfer.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 400 Bad Request — if the `pubkey` does not belong to the invoking user, or the signature is
+      invalid.
+    - 403 Forbidden — if there is no pending transfer of the room to the invoking user.
+    - 409 Conflict — if the invoking user already owns the maximum number of rooms permitted by the
+      server.
+    """
+    req = request.json
+    try:
+        pubkey = utils.decode_hex_or_b64(req['pubkey'], 32)
+        signature = utils.decode_hex_or_b64(req['signature'], 64)
+    except Exception:
+        app.logger.warning("Invalid room transfer acceptance")
+        abort(http.BAD_REQUEST)
+
+    room.accept_transfer(g.user, pubkey=pubkey, signature=signature)
+    return jsonify({})
+
+
 @rooms.delete("/room/<Room:room>/all/<SessionID:sid>")
 def delete_all_posts(room, sid):
     """
-    Deletes all posts from a room made by a user
+    Deletes all posts from a room made by a user.  The posts are all deleted in one transaction,
+    and are returned as deletions by message polling just as individually deleted posts are.
 
     # URL Parameters
 
-    - `sid` — the session id of the user to ban
+    - `sid` — the session id of the user whose posts to delete
 
     # Return value
 
-    An empty json object is returned.
+    A JSON dict with key `deleted`, the number of posts deleted.
+
+    If the server requires confirmation of destructive actions by a second moderator, this instead
+    returns a 202 status code with a JSON object containing the `pending_action` awaiting
+    confirmation; see [the pending actions endpoint](#get-adminpending).
 
     # Error status codes
 
@@ -956,10 +1764,13 @@ def delete_all_posts(room, sid):
     - 404 Not Found — if the user we are deleting posts from made no posts in this room.
     """
     user = muser.User(session_id=sid, autovivify=False)
+    pending = require_confirmation('purge', target=user, params={'rooms': [room.token]})
+    if pending:
+        return pending
     deleted, _ = room.delete_all_posts(user, deleter=g.user)
     if not deleted:
         abort(http.NOT_FOUND)
-    return jsonify({})
+    return jsonify({'deleted': deleted})
 
 
 @rooms.delete("/rooms/all/<SessionID:sid>")
@@ -977,10 +1788,21 @@ def delete_user_posts_from_all_rooms(sid):
 
     - `total` — The total number of posts deleted across all rooms.
     - `rooms` — A dict of room tokens and their deletion counts.
+
+    If the server requires confirmation of destructive actions by a second moderator, this instead
+    returns a 202 status code with a JSON object containing the `pending_action` (covering all rooms
+    that the invoking user moderates) awaiting confirmation; see [the pending actions
+    endpoint](#get-adminpending).
     """
     deletions = {}
     total = 0
     user = muser.User(session_id=sid, autovivify=False)
+    if pending_action.required():
+        auth.require_user()
+        tokens = [r.token for r in mroom.get_accessible_rooms(g.user) if r.check_moderator(g.user)]
+        if not tokens:
+            abort(http.FORBIDDEN)
+        return require_confirmation('purge', target=user, params={'rooms': tokens})
     for room in mroom.get_accessible_rooms(g.user):
         try:
             count, _ = room.delete_all_posts(user, deleter=g.user)
@@ -89,6 +89,7 @@ def make_subrequest(
         **http_headers,
         'wsgi.input': body_input,
         'flask._preserve_context': False,
+        'sogs.subrequest': True,
     }
 
     try:
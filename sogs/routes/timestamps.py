@@ -0,0 +1,114 @@
+from .. import config
+from ..web import app
+
+from datetime import datetime, timezone
+from flask import request
+import json
+
+# Extended timestamp formats.  API responses give times as (float) unix epoch seconds.  With the
+# [net] `extended_timestamps` setting (or the per-room `extended_timestamps` setting, for the
+# endpoints of a room) enabled, every timestamp value in a JSON response is also accompanied by:
+#
+# - `KEY_ms` — the same time as integer unix epoch milliseconds (truncated, as for the /time
+#   endpoint's `timestamp_ms`);
+# - `KEY_iso` — the same time as an ISO-8601 UTC string with millisecond precision, such as
+#   `2023-01-02T03:04:05.678Z`.
+#
+# where KEY is the name of the timestamp field, e.g. `posted`, `edited`, or `created`.  This applies
+# at any depth of the response (so, for instance, to every message of a message list, and to the
+# subresponses of /batch and /sequence requests), to the fields named in TIMESTAMP_KEYS whose
+# values are numbers in the range of plausible unix timestamps.  Fields that are null (such as an
+# unset `expires`) don't get the extra fields, and existing fields are never replaced.
+
+TIMESTAMP_KEYS = {
+    'archived',
+    'at',
+    'banned_at',
+    'blocked_at',
+    'checked',
+    'claimed',
+    'created',
+    'edited',
+    'expires',
+    'expiry',
+    'last_active',
+    'last_sent',
+    'last_used',
+    'next_run',
+    'pinned_at',
+    'posted',
+    'raid_mode_until',
+    'reported',
+    'requested',
+    'reset',
+    'resolved',
+    'revoked',
+    'subscribed',
+    'timestamp',
+    'unarchived',
+    'uploaded',
+}
+
+# Timestamps before 2001 or after 2286 are assumed to be something else that shares a key name
+_MIN, _MAX = 1e9, 1e10
+
+# These responses contain other JSON responses, which get the extra fields (or not) themselves
+_CONTAINER_ENDPOINTS = {'general.batch', 'general.sequence'}
+
+
+def iso8601(t):
+    """Formats unix timestamp `t` as an ISO-8601 UTC string with millisecond precision."""
+    # Go through the integer milliseconds so that this always agrees with the `_ms` value
+    ms = int(t * 1000)
+    dt = datetime.fromtimestamp(ms // 1000, timezone.utc).replace(microsecond=ms % 1000 * 1000)
+    return dt.isoformat(timespec='milliseconds').replace('+00:00', 'Z')
+
+
+def add_formats(value):
+    """Adds `KEY_ms` and `KEY_iso` fields, as described above, to a decoded JSON value in place."""
+    if isinstance(value, list):
+        for v in value:
+            add_formats(v)
+    elif isinstance(value, dict):
+        for k, v in list(value.items()):
+            if isinstance(v, (dict, list)):
+                add_formats(v)
+            elif (
+                k in TIMESTAMP_KEYS
+                and isinstance(v, (int, float))
+                and not isinstance(v, bool)
+                and _MIN <= v < _MAX
+            ):
+                value.setdefault(f'{k}_ms', int(v * 1000))
+                value.setdefault(f'{k}_iso', iso8601(v))
+    return value
+
+
+def enabled():
+    """Returns whether extended timestamps are enabled for the current request."""
+    room = (request.view_args or {}).get('room')
+    if room is not None and hasattr(room, 'token'):
+        return bool(
+            config.ROOM_OVERRIDES.get(room.token, {}).get(
+                'extended_timestamps', config.EXTENDED_TIMESTAMPS
+            )
+        )
+    return config.EXTENDED_TIMESTAMPS
+
+
+@app.after_request
+def add_extended_timestamps(response):
+    if (
+        response.mimetype != 'application/json'
+        or response.direct_passthrough
+        or request.endpoint in _CONTAINER_ENDPOINTS
+        or not enabled()
+    ):
+        return response
+
+    try:
+        body = json.loads(response.get_data())
+    except ValueError:
+        return response
+    response.set_data(json.dumps(add_formats(body), separators=(',', ':')))
+    return response
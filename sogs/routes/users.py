@@ -1,10 +1,12 @@
-from .. import db, http
+from .. import db, digest, http
 from ..model import room as mroom
 from ..model.user import User
 from ..web import app
 from . import auth
+from .admin import require_confirmation
 
 from flask import abort, jsonify, g, Blueprint, request
+import re
 
 # User-related routes
 
@@ -291,6 +293,11 @@ def ban_user(sid):
 
     The request must include exactly one non-null value of `rooms` and `global`.
 
+    If the server requires confirmation of destructive actions by a second moderator, a global ban
+    is not applied immediately: instead this returns a 202 status code with a JSON object containing
+    the `pending_action` awaiting confirmation; see [the pending actions
+    endpoint](#get-adminpending).
+
     The user's messages are not deleted by this request.  In order to ban and delete all messages
     use the [`/sequence`](#post-sequence) endpoint to bundle a `/user/.../ban` with a
     [`/rooms/all/...`](#delete-roomsallsid) request.
@@ -324,6 +331,9 @@ def ban_user(sid):
             for room in rooms:
                 room.ban_user(to_ban=user, mod=g.user, timeout=timeout)
     else:
+        pending = require_confirmation('global_ban', target=user, params={'timeout': timeout})
+        if pending:
+            return pending
         user.ban(banned_by=g.user, timeout=timeout)
 
     return {}
@@ -389,3 +399,94 @@ def unban_user(sid):
         user.unban(unbanned_by=g.user)
 
     return {}
+
+
+EMAIL_RE = re.compile(r'^[^@\s]+@[^@\s]+\.[^@\s]+$')
+
+
+def require_digest_moderator():
+    """Aborts unless digests are enabled and the current user moderates at least one room"""
+    if not digest.enabled():
+        abort(http.NOT_FOUND)
+    if not mroom.get_rooms_with_permission(g.user, moderator=True):
+        abort(http.FORBIDDEN)
+
+
+@users.get("/user/digest")
+@auth.user_required
+def get_digest_subscription():
+    """
+    Returns the invoking moderator's email digest subscription.
+
+    # Return value
+
+    On success returns a JSON object with keys:
+
+    - `email` — the email address to which digests are sent.
+    - `subscribed` — unix timestamp when the subscription was created.
+    - `last_sent` — unix timestamp of the last digest sent, or `null` if none has been sent yet.
+
+    # Error status codes
+
+    403 Forbidden — if the invoking user is not a moderator of any room.
+
+    404 Not Found — if the user is not subscribed, or if email digests are not enabled on this
+    server.
+    """
+    require_digest_moderator()
+    sub = g.user.digest_subscription
+    if sub is None:
+        abort(http.NOT_FOUND)
+    return jsonify(sub)
+
+
+@users.post("/user/digest")
+@auth.user_required
+def subscribe_digest():
+    """
+    Subscribes the invoking moderator to periodic email digests of moderation activity (new,
+    filtered, and deleted posts, bans, and scheduled permission changes) in all rooms they moderate.
+    Calling this again with a different address replaces the existing subscription address.
+
+    # Body Parameters
+
+    Takes a JSON object as body with the following key:
+
+    - `email` — the email address to which digests should be sent.
+
+    # Return value
+
+    On success returns a 200 status code with the subscription details, as returned by [GET
+    /user/digest](#get-userdigest).
+
+    # Error status codes
+
+    400 Bad Request — if `email` is missing or not a valid email address.
+
+    403 Forbidden — if the invoking user is not a moderator of any room.
+
+    404 Not Found — if email digests are not enabled on this server.
+    """
+    require_digest_moderator()
+    req = request.json
+    email = req.get('email') if isinstance(req, dict) else None
+    if not isinstance(email, str) or not EMAIL_RE.match(email):
+        app.logger.warning("Invalid digest subscription: invalid or missing email")
+        abort(http.BAD_REQUEST)
+
+    g.user.subscribe_digest(email)
+    return jsonify(g.user.digest_subscription)
+
+
+@users.delete("/user/digest")
+@auth.user_required
+def unsubscribe_digest():
+    """
+    Removes the invoking user's email digest subscription, if any.
+
+    # Return value
+
+    Returns a JSON object with key `unsubscribed` set to true if a subscription was removed, false
+    if the user was not subscribed.
+    """
+    return jsonify({'unsubscribed': g.user.unsubscribe_digest()})
@@ -1,10 +1,12 @@
-from flask import abort, render_template, Response, Blueprint
+from flask import abort, jsonify, render_template, Response, Blueprint
 
-from .. import config, crypto, http
+from .. import config, crypto, http, preview
 from ..model.room import get_accessible_rooms
+from ..model.post import Post
 from . import auth, converters  # noqa: F401
 
 
+from datetime import datetime, timezone
 from io import BytesIO
 
 import qrcode
@@ -20,6 +22,27 @@ else:
 views = Blueprint('views', __name__)
 
 
+@views.after_request
+def robots_header(response):
+    """Asks search engines not to index the public web pages, if so configured ([web].robots)."""
+    if config.WEB_ROBOTS != 'index':
+        response.headers['X-Robots-Tag'] = 'noindex, nofollow'
+    return response
+
+
+@views.get("/robots.txt")
+def serve_robots():
+    """
+    Crawler policy, as configured by [web].robots: crawlers are either asked to stay out entirely,
+    or are permitted to crawl only the public web pages (not the client API endpoints).
+    """
+    if config.WEB_ROBOTS == 'deny':
+        rules = ["Disallow: /"]
+    else:
+        rules = ["Allow: /$", "Allow: /r/", "Allow: /static/", "Disallow: /"]
+    return Response("\n".join(["User-agent: *", *rules, ""]), mimetype="text/plain")
+
+
 @views.get("/")
 def serve_index():
     """
@@ -43,13 +66,44 @@ def view_room(room):
     Publicly accessible URL that displays a room (including recent messages) to a web browser.  This
     isn't a normal SOGS client endpoint, but rather a convenience web page for people who follow a
     SOGS pseudo-URL, that displays the SOGS URL and QR code along with a list of recent messages.
+    Not available for rooms flagged as sensitive, which can only be read after acknowledging the
+    flag.
     """
-    if not room.default_read:
+    if not room.default_read or room.sensitive:
         abort(http.FORBIDDEN)
 
     return render_template("view_room.html", room=room, show_recent=config.HTTP_SHOW_RECENT)
 
 
+@views.get("/r/<Room:room>/recent.json")
+def view_room_recent(room):
+    """
+    Returns the recent messages of a room as rendered for the public room web page: a JSON list of
+    objects with `id`, `posted`, `author` (display name), and `text` keys, from newest to oldest.
+    The web page uses this (rather than the raw message data from `.../messages/recent`) when
+    [messages].preview_mask is enabled for the room, in which case the author and text values have
+    profanity masked.
+    """
+    if not room.default_read or room.sensitive or not config.HTTP_SHOW_RECENT:
+        abort(http.FORBIDDEN)
+
+    msgs = []
+    for msg in room.get_messages_for(None, recent=True, limit=100):
+        try:
+            post = Post(raw=msg['data'])
+        except Exception:
+            continue
+        msgs.append(
+            {
+                'id': msg['id'],
+                'posted': msg['posted'],
+                'author': preview.render(room, post.username),
+                'text': preview.render(room, post.text),
+            }
+        )
+    return jsonify(msgs)
+
+
 @views.get("/r/<Room:room>/invite.png")
 def serve_invite_qr(room):
     """
@@ -58,7 +112,7 @@ def serve_invite_qr(room):
     people who view the SOGS URL in a browser and want to scan the URL into another device (i.e.
     mobile Session).
     """
-    if not room.default_read:
+    if not room.default_read or room.sensitive:
         abort(http.FORBIDDEN)
 
     img = qrcode.make(room.url)
@@ -66,3 +120,54 @@ def serve_invite_qr(room):
     img = img.resize((512, 512), NEAREST)
     img.save(data, "PNG")
     return Response(data.getvalue(), mimetype="image/png")
+
+
+def _atom_time(t):
+    return datetime.fromtimestamp(t, timezone.utc).strftime('%Y-%m-%dT%H:%M:%SZ')
+
+
+@views.get("/rooms/<Room:room>/feed.xml")
+def serve_room_feed(room):
+    """
+    Atom feed of the most recent messages of a public announcement room, for syndicating
+    announcements outside of Session.  This is only available for publicly readable rooms that
+    have been configured with `feed = yes` in the room's `[room:TOKEN]` config section; for any
+    other room this returns a 404.
+    """
+    if not room.has_feed:
+        abort(http.NOT_FOUND)
+
+    entries = []
+    for msg in room.get_messages_for(None, recent=True, limit=config.ROOM_FEED_SIZE):
+        try:
+            post = Post(raw=msg['data'])
+        except Exception:
+            continue
+        text = preview.render(room, post.text)
+        if not text:
+            continue
+        title = text.split('\n', 1)[0]
+        if len(title) > 80:
+            title = title[:79] + '…'
+        entries.append(
+            {
+                'id': msg['id'],
+                'title': title,
+                'text': text,
+                'author': preview.render(room, post.username) or msg['session_id'],
+                'published': _atom_time(msg['posted']),
+                'updated': _atom_time(msg.get('edited') or msg['posted']),
+            }
+        )
+
+    return Response(
+        render_template(
+            "feed.xml",
+            room=room,
+            entries=entries,
+            url_base=config.URL_BASE,
+            feed_id=f"urn:sogs:{crypto.server_pubkey_hex}:{room.token}",
+            updated=entries[0]['updated'] if entries else _atom_time(room.created),
+        ),
+        mimetype="application/atom+xml",
+    )
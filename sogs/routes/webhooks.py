@@ -0,0 +1,146 @@
+from .. import config, http
+from ..model.webhook import Webhook, get_room_webhooks
+from ..web import app
+from . import auth
+
+from flask import abort, jsonify, g, Blueprint, request
+
+# Inbound room webhooks: room admins can create secret webhook URLs that external services (CI
+# systems, RSS bots, etc.) can post simple JSON messages to, without needing to implement Session
+# message encoding and signing.  Messages are posted to the room by a bot user managed by the
+# server.
+
+
+webhooks = Blueprint('webhooks', __name__)
+
+
+def _webhook_url(secret):
+    return f"{config.URL_BASE}/webhook/{secret}"
+
+
+@webhooks.get("/room/<Room:room>/webhooks")
+@auth.admin_required
+def list_webhooks(room):
+    """
+    Lists the inbound webhooks of a room.  Requires admin permission in the room.
+
+    # Return value
+
+    A JSON list of webhook objects, each containing keys:
+
+    - `id` — the numeric webhook id.
+    - `session_id` — the session id of the bot user that webhook messages are posted as.
+    - `display_name` — the default display name of webhook messages.
+    - `created` — unix timestamp when the webhook was created.
+    - `last_used` — unix timestamp when the webhook last posted a message, or null if never used.
+
+    Note that the webhook URL is not included: it is only returned when creating the webhook.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not an admin of the room.
+    """
+    return jsonify([hook.info() for hook in get_room_webhooks(room)])
+
+
+@webhooks.post("/room/<Room:room>/webhooks")
+@auth.admin_required
+def create_webhook(room):
+    """
+    Creates a new inbound webhook for the room.  Requires admin permission in the room.
+
+    # JSON parameters
+
+    - `display_name` — (required) the display name to use for messages posted via the webhook that
+      do not specify their own display name.
+
+    # Return value
+
+    On success returns a 201 (Created) status code with the webhook details, as returned by [the
+    webhook list endpoint](#get-roomroomwebhooks), plus:
+
+    - `url` — the secret URL to which messages can be posted; see [the webhook post
+      endpoint](#post-webhooksecret).  This URL is not retrievable later, so must be recorded now.
+
+    # Error status codes
+
+    - 400 Bad Request — if `display_name` is missing or invalid.
+    - 403 Forbidden — if the invoking user is not an admin of the room.
+    """
+    req = request.json
+    display_name = req.get('display_name') if isinstance(req, dict) else None
+    if not isinstance(display_name, str) or not display_name:
+        app.logger.warning("Invalid webhook creation: `display_name` must be a non-empty string")
+        abort(http.BAD_REQUEST)
+
+    hook, secret = Webhook.create(room, g.user, display_name)
+    return jsonify({**hook.info(), 'url': _webhook_url(secret)}), http.CREATED
+
+
+@webhooks.delete("/room/<Room:room>/webhook/<int:hook_id>")
+@auth.admin_required
+def delete_webhook(room, hook_id):
+    """
+    Deletes an inbound webhook of the room, after which its URL can no longer be used to post
+    messages.  Messages already posted via the webhook are not affected.  Requires admin permission
+    in the room.
+
+    # Return value
+
+    On success returns a 200 status code with an empty JSON object as body.
+
+    # Error status codes
+
+    - 403 Forbidden — if the invoking user is not an admin of the room.
+    - 404 Not Found — if the room has no webhook with the given id.
+    """
+    hook = Webhook(id=hook_id)
+    if hook.room.id != room.id:
+        abort(http.NOT_FOUND)
+    hook.delete(g.user)
+    return jsonify({})
+
+
+@webhooks.post("/webhook/<secret>")
+def post_webhook(secret):
+    """
+    Posts a message to a room via an inbound webhook.  This endpoint requires no Session
+    authentication: the secret webhook URL (as returned when creating the webhook) is the
+    authentication, and so should be kept private.
+
+    # JSON parameters
+
+    - `text` — (required) the plain text body of the message.
+    - `display_name` — optional display name of the message; if omitted the webhook's default
+      display name is used.
+
+    # Return value
+
+    On success returns a 201 (Created) status code with a JSON object containing the `id` of the
+    posted message.
+
+    # Error status codes
+
+    - 400 Bad Request — if `text` is missing or invalid.
+    - 403 Forbidden — if the webhook's bot user is not permitted to post in the room.
+    - 404 Not Found — if the webhook URL is invalid (e.g. because the webhook has been deleted).
+    - 429 Too Many Requests — if the webhook is posting too frequently, or the message was rejected
+      by the room's message filters.
+    """
+    hook = Webhook(secret=secret)
+
+    req = request.json
+    if not isinstance(req, dict):
+        app.logger.warning(f"Invalid webhook post: expected a JSON object body, not {type(req)}")
+        abort(http.BAD_REQUEST)
+
+    text, display_name = req.get('text'), req.get('display_name')
+    if not isinstance(text, str) or not text:
+        app.logger.warning("Invalid webhook post: `text` must be a non-empty string")
+        abort(http.BAD_REQUEST)
+    if display_name is not None and not isinstance(display_name, str):
+        app.logger.warning("Invalid webhook post: `display_name` must be a string if given")
+        abort(http.BAD_REQUEST)
+
+    msg = hook.post(text, display_name)
+    return jsonify({'id': msg['id']}), http.CREATED
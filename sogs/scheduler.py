@@ -0,0 +1,157 @@
+import contextlib
+import flask
+import time
+import traceback
+
+from .web import app
+from . import archive, backfill, cleanup, config, db, digest, directory, eventhooks, journal
+from . import shutdown, stats, storage, upgrade
+from .cron import Schedule
+
+# Scheduling of the periodic background jobs run by the uwsgi mule.  Each job has a cron-style
+# schedule (see sogs.cron) configurable in the [schedule] config section; setting a job's schedule
+# to an empty value disables it.  The mule calls `tick()` every second, which runs any jobs that are
+# due.  Jobs can also be run on demand, e.g. by a global admin via the /admin/jobs endpoints.
+
+
+def vacuum():
+    """Reclaims unused database space and refreshes query planner statistics."""
+    if db.engine.name == 'sqlite':
+        db.query("VACUUM")
+    else:
+        # VACUUM can't run inside a transaction block:
+        with db.engine.connect().execution_options(isolation_level='AUTOCOMMIT') as conn:
+            conn.exec_driver_sql("VACUUM ANALYZE")
+    app.logger.info("Vacuumed database")
+    return True
+
+
+# name => (job function, config setting holding its schedule, description)
+JOBS = {
+    'cleanup': (
+        cleanup.cleanup,
+        'SCHEDULE_CLEANUP',
+        "Prunes expired data and applies scheduled permission changes",
+    ),
+    'cold_storage': (
+        storage.archive_cold_files,
+        'SCHEDULE_COLD_STORAGE',
+        "Moves old attachments into cold storage",
+    ),
+    'stats_rollup': (
+        stats.rollup,
+        'SCHEDULE_STATS_ROLLUP',
+        "Rolls up daily room activity statistics",
+    ),
+    'import_backfill': (
+        backfill.run,
+        'SCHEDULE_IMPORT_BACKFILL',
+        "Backfills message history imported from an old SOGS database",
+    ),
+    'journal_checkpoint': (
+        journal.checkpoint,
+        'SCHEDULE_JOURNAL_CHECKPOINT',
+        "Records room checkpoints in the event journal",
+    ),
+    'digests': (digest.send_digests, 'SCHEDULE_DIGESTS', "Sends moderator email digests"),
+    'vacuum': (vacuum, 'SCHEDULE_VACUUM', "Vacuums the database"),
+    'directory': (
+        directory.announce,
+        'SCHEDULE_DIRECTORY',
+        "Announces the server's public rooms to the community directory",
+    ),
+    'upgrade_check': (
+        upgrade.check,
+        'SCHEDULE_UPGRADE_CHECK',
+        "Checks the release feed for newer server versions",
+    ),
+    'room_archive': (
+        archive.run,
+        'SCHEDULE_ROOM_ARCHIVE',
+        "Archives (and optionally deletes) inactive rooms",
+    ),
+    'event_hooks': (
+        eventhooks.deliver,
+        'SCHEDULE_EVENT_HOOKS',
+        "Delivers queued room events to the configured event hook URLs",
+    ),
+}
+
+# name => {'schedule': Schedule, 'next': ts, 'last_run': ts, 'last_duration': s, 'last_error': str}
+_state = {}
+
+
+def _job_state(name):
+    st = _state.get(name)
+    expr = getattr(config, JOBS[name][1])
+    if st is None or st['expr'] != expr:
+        sched = Schedule(expr) if expr else None
+        st = _state[name] = {
+            'expr': expr,
+            'schedule': sched,
+            'next': sched.next_after(time.time()) if sched else None,
+            'last_run': None,
+            'last_duration': None,
+            'last_error': None,
+        }
+    return st
+
+
+def run_job(name):
+    """
+    Runs job `name` immediately, regardless of its schedule.  Returns the job's return value, or
+    None if the job failed (in which case the error is logged and recorded in the job status).
+    """
+    func = JOBS[name][0]
+    st = _job_state(name)
+    started = time.time()
+    result = None
+    with contextlib.nullcontext() if flask.has_app_context() else app.app_context():
+        try:
+            result = func()
+            st['last_error'] = None
+        except Exception as e:
+            app.logger.warning(f"Scheduled job {name} failed: {e}\n{traceback.format_exc()}")
+            st['last_error'] = str(e)
+    st['last_run'] = started
+    st['last_duration'] = time.time() - started
+    return result
+
+
+def tick():
+    """
+    Runs any jobs that are due; called periodically by the mule.  Does nothing once shutdown has
+    begun, so that a long-running job doesn't delay (or get cut off by) the shutdown.
+    """
+    if shutdown.stopping():
+        return
+    now = time.time()
+    for name in JOBS:
+        st = _job_state(name)
+        if st['next'] is not None and st['next'] <= now:
+            run_job(name)
+            st['next'] = st['schedule'].next_after(time.time())
+
+
+def status():
+    """
+    Returns a dict of job name => job status dict, containing keys `description`, `schedule` (the
+    cron expression, or None if disabled), and `next_run` (the timestamp of the next scheduled run,
+    or None).  In the mule process (where scheduled jobs run) this also includes `last_run`,
+    `last_duration`, and `last_error` details of the most recent run of each job, if any.
+    """
+    result = {}
+    for name, (func, setting, description) in JOBS.items():
+        st = _job_state(name)
+        info = {
+            'description': description,
+            'schedule': st['expr'] or None,
+            'next_run': st['next'],
+        }
+        if st['last_run'] is not None:
+            info['last_run'] = st['last_run']
+            info['last_duration'] = st['last_duration']
+            if st['last_error'] is not None:
+                info['last_error'] = st['last_error']
+        result[name] = info
+    return result
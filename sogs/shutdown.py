@@ -0,0 +1,100 @@
+from . import config
+
+import atexit
+import logging
+import threading
+import time
+
+# Cleanup hooks run when a process exits, so that a restart doesn't lose anything still held in
+# memory (e.g. pending push notifications) and leaves the database cleanly closed.
+#
+# Under uwsgi these run when a worker or mule exits: with the `gracefully_kill_them_all` hooks of
+# the contrib/uwsgi-sogs-*.ini configurations, a SIGTERM or SIGINT makes uwsgi stop accepting new
+# requests and lets the workers finish the requests they are handling before they exit (so no
+# request is cut off mid-transaction).  Otherwise (e.g. when running `python3 -m sogs`) the hooks
+# run at interpreter exit.
+#
+# Hooks run in phases, so that nothing accepted is lost between one subsystem stopping and
+# another:
+#
+# - INTAKE hooks stop accepting new work (new push subscriptions, scheduled jobs, etc.);
+# - FLUSH hooks deliver whatever is still queued in memory (push notifications, trace exports),
+#   but must finish within [net].shutdown_timeout seconds of shutdown starting (see `remaining()`);
+#   any FLUSH hooks not yet started by then are skipped;
+# - CLOSE hooks close files and database connections, and always run.
+#
+# `stopping()` becomes true as soon as shutdown begins, before any of the hooks run.
+
+INTAKE = 'intake'
+FLUSH = 'flush'
+CLOSE = 'close'
+PHASES = (INTAKE, FLUSH, CLOSE)
+
+# [(phase, f), ...] in order of registration
+_hooks = []
+_lock = threading.Lock()
+_done = False
+_deadline = None
+
+
+def on_shutdown(f=None, *, phase=CLOSE):
+    """
+    Decorator registering `f` to be called (without arguments) at process shutdown, as part of
+    shutdown phase `phase` (one of INTAKE, FLUSH, or CLOSE; the default is CLOSE).  Can be used
+    either as `@on_shutdown` or as `@on_shutdown(phase=FLUSH)`.  Within each phase, hooks are called
+    in the reverse order of registration; exceptions they raise are logged and ignored.
+    """
+    if phase not in PHASES:
+        raise ValueError(f"Invalid shutdown phase {phase}")
+
+    def register(f):
+        _hooks.append((phase, f))
+        return f
+
+    return register if f is None else register(f)
+
+
+def stopping():
+    """True once shutdown has begun."""
+    return _done
+
+
+def remaining():
+    """
+    Returns the number of seconds remaining for FLUSH hooks to finish their work; this is 0 once the
+    deadline has passed, and None if shutdown hasn't started.
+    """
+    if _deadline is None:
+        return None
+    return max(0.0, _deadline - time.monotonic())
+
+
+def run_hooks():
+    """Runs the registered shutdown hooks.  Only the first call does anything."""
+    global _done, _deadline
+    with _lock:
+        if _done:
+            return
+        _done = True
+        _deadline = time.monotonic() + config.SHUTDOWN_TIMEOUT
+
+    logger = logging.getLogger(__name__)
+    for phase in PHASES:
+        for p, f in reversed(_hooks):
+            if p != phase:
+                continue
+            if phase == FLUSH and remaining() <= 0:
+                logger.warning(f"Shutdown hook {f.__name__} skipped: shutdown timeout reached")
+                continue
+            try:
+                f()
+            except Exception as e:
+                logger.warning(f"Shutdown hook {f.__name__} failed: {e}")
+
+
+try:
+    import uwsgi
+except ModuleNotFoundError:
+    atexit.register(run_hooks)
+else:
+    uwsgi.atexit = run_hooks
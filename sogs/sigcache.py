@@ -0,0 +1,154 @@
+from . import config, metrics
+
+import collections
+import concurrent.futures
+import threading
+import time
+from nacl.signing import VerifyKey
+import nacl.bindings as sodium
+import nacl.exceptions
+
+# Caching of request authentication.  Ed25519 signature verification (and the validation and
+# conversion of a user's Ed25519 pubkey into a session id) is the most CPU-heavy part of handling
+# the requests of polling clients.  Clients of the current API send the same X-SOGS-Pubkey with
+# every request, so we keep a bounded LRU cache of *validated* pubkeys and their session ids;
+# invalid pubkeys are never cached.  The size of the cache is set by [net] `auth_cache_size` (0
+# disables caching).  Signatures themselves are not cached: every request signs a fresh nonce, so
+# the same signature is never seen twice.  We do, however, keep timing statistics of verifications.
+#
+# Batch verification doesn't help here: each request carries only a single signature, and
+# libsodium offers no batch verification API, so each request is verified individually.  Bulk
+# verification of many signatures at once (e.g. of imported message history) instead goes through
+# verify_batch(), which spreads the (uncached) verifications over a pool of worker threads; PyNaCl
+# releases the GIL while verifying, so these run in parallel.
+#
+# As with other metrics, caches and statistics live in the memory of each worker process.
+
+_lock = threading.Lock()
+_pubkeys = collections.OrderedDict()
+_pool = None
+
+
+def _lookup(cache, key, metric):
+    with _lock:
+        if key in cache:
+            cache.move_to_end(key)
+            metrics.incr(f'{metric}.cache_hits')
+            return cache[key]
+    metrics.incr(f'{metric}.cache_misses')
+    return None
+
+
+def _store(cache, key, value):
+    if config.AUTH_CACHE_SIZE <= 0:
+        return
+    with _lock:
+        cache[key] = value
+        cache.move_to_end(key)
+        while len(cache) > config.AUTH_CACHE_SIZE:
+            cache.popitem(last=False)
+
+
+def verify(pubkey: bytes, message: bytes, signature: bytes):
+    """
+    Verifies the Ed25519 `signature` of `message` by `pubkey` (32 bytes); raises
+    nacl.exceptions.BadSignatureError if invalid.
+    """
+    started = time.perf_counter()
+    try:
+        VerifyKey(pubkey).verify(message, signature)
+    finally:
+        metrics.observe('auth.verify.time', time.perf_counter() - started)
+
+
+def _verify_chunk(chunk):
+    results = []
+    for pubkey, message, signature in chunk:
+        try:
+            VerifyKey(pubkey).verify(message, signature)
+            results.append(True)
+        except (nacl.exceptions.BadSignatureError, nacl.exceptions.ValueError, TypeError):
+            results.append(False)
+    return results
+
+
+def verify_batch(items, *, chunk_size=100):
+    """
+    Verifies many Ed25519 signatures at once.  `items` is a sequence of (pubkey, message,
+    signature) tuples; returns a list of bools of whether each signature is valid, in the same
+    order.  Verification is split into chunks of `chunk_size` verified on the worker pool ([db]
+    `import_verify_workers` threads).  Results are not cached, as bulk verified signatures are
+    generally not seen again.
+    """
+    global _pool
+    if not items:
+        return []
+    with _lock:
+        if _pool is None:
+            _pool = concurrent.futures.ThreadPoolExecutor(
+                config.IMPORT_VERIFY_WORKERS, thread_name_prefix='sogs-verify'
+            )
+
+    started = time.perf_counter()
+    chunks = [items[i : i + chunk_size] for i in range(0, len(items), chunk_size)]
+    results = [r for chunk in _pool.map(_verify_chunk, chunks) for r in chunk]
+    metrics.observe('verify.batch.time', time.perf_counter() - started)
+    metrics.incr('verify.batch.signatures', len(items))
+    metrics.incr('verify.batch.invalid', results.count(False))
+    return results
+
+
+def session_id(pubkey: bytes, blinded: bool):
+    """
+    Returns the session id of the X-SOGS-Pubkey Ed25519 `pubkey` (32 bytes, without the prefix
+    byte): `15...` for a blinded key, otherwise the `05...` id of the converted X25519 pubkey.
+    Returns None if `pubkey` is not a valid Ed25519 pubkey.  Results for valid keys are cached.
+    """
+    key = (pubkey, blinded)
+    sid = _lookup(_pubkeys, key, 'auth.pubkey')
+    if sid is not None:
+        return sid
+
+    if not sodium.crypto_core_ed25519_is_valid_point(pubkey):
+        return None
+    if blinded:
+        sid = '15' + pubkey.hex()
+    else:
+        try:
+            sid = '05' + VerifyKey(pubkey).to_curve25519_public_key().encode().hex()
+        except nacl.exceptions.RuntimeError:
+            return None
+    _store(_pubkeys, key, sid)
+    return sid
+
+
+def stats():
+    """
+    Returns a dict of authentication statistics for this process: `verifications` (the number of
+    signatures actually verified), `verify_time` (the total time spent verifying, in seconds),
+    `verify_rate` (verified signatures per second of verification time), `cache_hits` and
+    `cache_misses` (of the pubkey cache), `hit_rate` (the fraction of lookups that were cache hits,
+    or None if there have been no lookups), and `cached` (the number of currently cached pubkeys).
+    """
+    m = metrics.snapshot()
+    c = m['counters']
+    timing = m['observations'].get('auth.verify.time', {'count': 0, 'total': 0.0})
+    hits = c.get('auth.pubkey.cache_hits', 0)
+    misses = c.get('auth.pubkey.cache_misses', 0)
+    with _lock:
+        cached = len(_pubkeys)
+    return {
+        'verifications': timing['count'],
+        'verify_time': timing['total'],
+        'verify_rate': timing['count'] / timing['total'] if timing['total'] > 0 else None,
+        'cache_hits': hits,
+        'cache_misses': misses,
+        'hit_rate': hits / (hits + misses) if hits + misses else None,
+        'cached': cached,
+    }
+
+
+def clear():
+    """Empties the pubkey cache."""
+    with _lock:
+        _pubkeys.clear()
@@ -20,7 +20,9 @@ const setup = async () => {
         const root = protobuf.parse(proto).root;
 
         const Message = root.lookupType("signalservice.Content");
-        const url = window.poll_room_url;
+        // If set, the server renders the messages for us (e.g. to mask profanity):
+        const rendered = !!window.preview_url;
+        const url = rendered ? window.preview_url : window.poll_room_url;
         const update = async () => {
             const req = await fetch(url);
             if(req.status != 200)
@@ -43,6 +45,12 @@ const setup = async () => {
             for(let msg of msgs.reverse())
             {
                 let e = document.createElement("li")
+                if(rendered)
+                {
+                    e.appendChild(document.createTextNode(msg.author +": "+msg.text));
+                    elem.appendChild(e);
+                    continue;
+                }
                 try
                 {
                     const data = makebuffer(msg.data);
@@ -0,0 +1,136 @@
+from statistics import median
+import time
+
+from .web import app
+from .db import query
+from . import db
+from .model.room import get_rooms
+
+# Nightly rollups of per-room daily activity statistics into the room_daily_stats table.  Each run
+# rolls up every completed UTC day since the last rolled-up day of each room; days without any
+# activity get rows of zeros so that the statistics of a room are contiguous from its first day.
+#
+# The rollup also records the service level of the room's moderation queue (see sogs.model.report):
+# how many reports were filed and resolved each day, and how long the reports resolved that day took
+# to be claimed and resolved.
+#
+# Note that uploaded files are usually pruned after [files].expiry days, so the rollup must run at
+# least that often for upload statistics to be complete.
+
+DAY = 86400
+
+# The columns of room_daily_stats, other than room and day
+COLUMNS = (
+    'messages',
+    'posters',
+    'uploads',
+    'upload_bytes',
+    'reports',
+    'reports_resolved',
+    'reports_actioned',
+    'median_claim',
+    'median_resolution',
+)
+
+
+def rollup_room(room, end: int):
+    """
+    Rolls up the statistics of `room` for all completed days before timestamp `end` (which must be
+    the start of a UTC day) that haven't been rolled up yet.  Returns the number of days rolled up.
+    """
+    last = query("SELECT MAX(day) FROM room_daily_stats WHERE room = :r", r=room.id).first()[0]
+    if last is not None:
+        start = last + DAY
+    else:
+        first_post = query("SELECT MIN(posted) FROM messages WHERE room = :r", r=room.id).first()[0]
+        start = int(min(room.created, first_post or room.created)) // DAY * DAY
+    if start >= end:
+        return 0
+
+    days = {
+        d: {c: None if c.startswith('median_') else 0 for c in COLUMNS}
+        for d in range(start, end, DAY)
+    }
+    params = {'r': room.id, 'start': start, 'end': end, 'day': DAY}
+    for d, messages, posters in query(
+        f"""
+        SELECT {db.floor_div('posted', ':day')} AS d, COUNT(*), COUNT(DISTINCT "user")
+        FROM messages
+        WHERE room = :r AND posted >= :start AND posted < :end AND NOT filtered
+        GROUP BY d
+        """,
+        **params,
+    ):
+        days[int(d) * DAY].update(messages=messages, posters=posters)
+    for d, uploads, size in query(
+        f"""
+        SELECT {db.floor_div('uploaded', ':day')} AS d, COUNT(*), COALESCE(SUM(size), 0)
+        FROM files
+        WHERE room = :r AND uploaded >= :start AND uploaded < :end
+        GROUP BY d
+        """,
+        **params,
+    ):
+        days[int(d) * DAY].update(uploads=uploads, upload_bytes=size)
+    for d, reports in query(
+        f"""
+        SELECT {db.floor_div('reported', ':day')} AS d, COUNT(*)
+        FROM message_reports
+        WHERE room = :r AND reported >= :start AND reported < :end
+        GROUP BY d
+        """,
+        **params,
+    ):
+        days[int(d) * DAY]['reports'] = reports
+
+    resolved = {}
+    for reported, claimed, when, resolution in query(
+        """
+        SELECT reported, claimed, resolved, resolution FROM message_reports
+        WHERE room = :r AND resolved >= :start AND resolved < :end
+        """,
+        r=room.id,
+        start=start,
+        end=end,
+    ):
+        resolved.setdefault(int(when) // DAY * DAY, []).append(
+            (reported, claimed, when, resolution)
+        )
+    for d, reports in resolved.items():
+        claim_times = [c - r for r, c, _, _ in reports if c is not None]
+        days[d].update(
+            reports_resolved=len(reports),
+            reports_actioned=sum(res == 'actioned' for _, _, _, res in reports),
+            median_claim=median(claim_times) if claim_times else None,
+            median_resolution=median([w - r for r, _, w, _ in reports]),
+        )
+
+    cols = ', '.join(COLUMNS)
+    with db.transaction():
+        for day, values in days.items():
+            query(
+                f"""
+                INSERT INTO room_daily_stats (room, day, {cols})
+                VALUES (:r, :day, {', '.join(f':{c}' for c in COLUMNS)})
+                ON CONFLICT (room, day) DO UPDATE SET
+                    {', '.join(f'{c} = excluded.{c}' for c in COLUMNS)}
+                """,
+                r=room.id,
+                day=day,
+                **values,
+            )
+    return len(days)
+
+
+def rollup(now=None):
+    """
+    Rolls up the statistics of all rooms for all completed UTC days (i.e. up to the start of the
+    current day).  Returns the number of room-days rolled up.
+    """
+    end = int(now if now is not None else time.time()) // DAY * DAY
+    total = 0
+    for room in get_rooms():
+        total += rollup_room(room, end)
+    if total:
+        app.logger.info(f"Rolled up {total} days of room statistics")
+    return total
@@ -0,0 +1,397 @@
+from . import config, db, tracing
+from .db import query
+from .hashing import blake2b
+from .web import app
+
+import io
+import lzma
+import os
+import time
+
+# Storage backends for uploaded file content.  The `path` column of the `files` table holds a
+# backend-specific location for the file content: for the local backend this is the path of the
+# file on disk (relative to the SOGS working directory); for the S3 backend it is the object key
+# within the configured bucket.
+#
+# Note that the backend is not recorded per-file, so changing [files].storage on an existing server
+# requires moving the existing uploads to the new backend (using the same paths/keys).
+#
+# Old attachments can also be moved into a cold storage tier (see [files].cold_after): a separate
+# directory (for local storage) or storage class (for S3), optionally compressed.  Cold files are
+# tracked in the `cold_files` table by their regular storage path, and are moved back into regular
+# storage the next time they are accessed (after which they won't go cold again until another
+# cold_after period has passed).  The module-level read_file/open_file/etc. functions take care of
+# this, and should be used rather than accessing the backends directly.
+
+
+class LocalStorage:
+    """
+    Stores uploaded files on the local filesystem, beneath config.UPLOAD_PATH (or, for the cold
+    storage tier, config.UPLOAD_COLD_PATH).
+    """
+
+    def __init__(self, cold=False):
+        self.cold = cold
+
+    @property
+    def base(self):
+        if self.cold and config.UPLOAD_COLD_PATH:
+            return config.UPLOAD_COLD_PATH
+        return config.UPLOAD_PATH
+
+    def write(self, name: str, content: bytes):
+        """
+        Stores `content` under `name` (which is of the form `{room_token}/{filename}`).  Returns
+        the path to store in the database for later access to the file.
+        """
+        path = os.path.join(self.base, name)
+        self.put(path, content)
+        return path
+
+    def put(self, path: str, content: bytes):
+        """Stores `content` at a path previously returned by `write()`."""
+        os.makedirs(os.path.dirname(path), exist_ok=True)
+        try:
+            with open(path, 'wb') as f:
+                f.write(content)
+        except Exception:
+            try:
+                os.unlink(path)
+            except Exception:
+                pass
+            raise
+
+    def read(self, path: str):
+        """Reads and returns the full content of the stored file, as bytes."""
+        with open(path, 'rb') as f:
+            return f.read()
+
+    def open(self, path: str):
+        """Returns a file-like object for streaming the stored file content."""
+        return open(path, 'rb')
+
+    def delete(self, path: str):
+        """Removes a stored file.  Raises FileNotFoundError if the file does not exist."""
+        os.unlink(path)
+
+    def download_url(self, path: str, disposition=None, content_type='application/octet-stream'):
+        """
+        Returns a URL from which the file can be downloaded directly, or None if the file must be
+        served by SOGS itself (as is always the case for local storage).
+        """
+        return None
+
+
+class S3Storage:
+    """
+    Stores uploaded files in an S3-compatible object store (such as AWS S3 or minio).  Requires the
+    `boto3` python module.  The cold storage tier uses the same bucket, but stores objects using
+    config.S3_COLD_STORAGE_CLASS.
+    """
+
+    def __init__(self, cold=False):
+        self.cold = cold
+        self._client = None
+
+    @property
+    def client(self):
+        if self._client is None:
+            import boto3
+
+            self._client = boto3.client(
+                's3',
+                endpoint_url=config.S3_ENDPOINT,
+                region_name=config.S3_REGION,
+                aws_access_key_id=config.S3_ACCESS_KEY,
+                aws_secret_access_key=config.S3_SECRET_KEY,
+            )
+        return self._client
+
+    def write(self, name: str, content: bytes):
+        key = config.S3_PREFIX + name
+        self.put(key, content)
+        return key
+
+    def put(self, path: str, content: bytes):
+        extra = None
+        if self.cold and config.S3_COLD_STORAGE_CLASS:
+            extra = {'StorageClass': config.S3_COLD_STORAGE_CLASS}
+        # upload_fileobj transparently switches to a multipart upload for large files
+        self.client.upload_fileobj(io.BytesIO(content), config.S3_BUCKET, path, ExtraArgs=extra)
+
+    def read(self, path: str):
+        return self.open(path).read()
+
+    def open(self, path: str):
+        try:
+            return self.client.get_object(Bucket=config.S3_BUCKET, Key=path)['Body']
+        except self.client.exceptions.NoSuchKey:
+            raise FileNotFoundError(path)
+
+    def delete(self, path: str):
+        # S3 deletion of a non-existent object is not an error, so this never raises
+        # FileNotFoundError.
+        self.client.delete_object(Bucket=config.S3_BUCKET, Key=path)
+
+    def download_url(self, path: str, disposition=None, content_type='application/octet-stream'):
+        """
+        Returns a presigned URL for direct download of the file from the object store, if enabled
+        via [files].s3_presign; otherwise None.  `disposition`, if given, is the Content-Disposition
+        header value, and `content_type` the Content-Type, the object store should return with the
+        file.
+        """
+        if not config.S3_PRESIGN:
+            return None
+
+        params = {'Bucket': config.S3_BUCKET, 'Key': path, 'ResponseContentType': content_type}
+        if disposition is not None:
+            params['ResponseContentDisposition'] = disposition
+        return self.client.generate_presigned_url(
+            'get_object', Params=params, ExpiresIn=config.S3_PRESIGN_EXPIRY
+        )
+
+
+BACKENDS = {'local': LocalStorage, 's3': S3Storage}
+
+_store = None
+_cold_store = None
+
+
+def get():
+    """Returns the configured storage backend instance."""
+    global _store
+    if _store is None or not isinstance(_store, BACKENDS[config.STORAGE_BACKEND]):
+        _store = BACKENDS[config.STORAGE_BACKEND]()
+    return _store
+
+
+def get_cold():
+    """Returns the storage backend instance for the cold storage tier."""
+    global _cold_store
+    if _cold_store is None or not isinstance(_cold_store, BACKENDS[config.STORAGE_BACKEND]):
+        _cold_store = BACKENDS[config.STORAGE_BACKEND](cold=True)
+    return _cold_store
+
+
+def rehydrate(path: str):
+    """
+    Moves the file content stored at `path` back from cold storage into regular storage, if it is
+    currently in cold storage.  Returns True if the file was restored, False if it wasn't cold.
+    """
+    row = query(
+        "SELECT cold_path, compressed FROM cold_files WHERE path = :p AND cold_path IS NOT NULL",
+        p=path,
+    ).first()
+    if row is None:
+        return False
+
+    cold_path, compressed = row
+    cold = get_cold()
+    try:
+        content = cold.read(cold_path)
+    except FileNotFoundError:
+        # Most likely a concurrent rehydration beat us to it
+        if query(
+            "SELECT COUNT(*) FROM cold_files WHERE path = :p AND cold_path = :cp",
+            p=path,
+            cp=cold_path,
+        ).first()[0]:
+            raise
+        return False
+    if compressed:
+        content = lzma.decompress(content)
+
+    get().put(path, content)
+    if not query(
+        """
+        UPDATE cold_files SET cold_path = NULL, restored = :now
+        WHERE path = :p AND cold_path = :cp
+        """,
+        now=time.time(),
+        p=path,
+        cp=cold_path,
+    ).rowcount:
+        # A concurrent rehydration beat us to it (and will delete the cold copy)
+        return False
+    try:
+        cold.delete(cold_path)
+    except FileNotFoundError:
+        pass
+
+    app.logger.debug(f"Restored {path} from cold storage")
+    return True
+
+
+def read_file(path: str):
+    """Reads the file content stored at `path`, restoring it from cold storage if needed."""
+    with tracing.span('storage.read', {'sogs.path': path}):
+        rehydrate(path)
+        try:
+            return get().read(path)
+        except FileNotFoundError:
+            # We might have raced with the content being moved into cold storage
+            if not rehydrate(path):
+                raise
+            return get().read(path)
+
+
+def open_file(path: str):
+    """
+    Returns a file-like object for streaming the file content stored at `path`, restoring it from
+    cold storage if needed.
+    """
+    with tracing.span('storage.open', {'sogs.path': path}):
+        rehydrate(path)
+        try:
+            return get().open(path)
+        except FileNotFoundError:
+            # We might have raced with the content being moved into cold storage
+            if not rehydrate(path):
+                raise
+            return get().open(path)
+
+
+def download_url(path: str, disposition=None, content_type='application/octet-stream'):
+    """
+    Returns a URL from which the content stored at `path` can be downloaded directly, or None if
+    it must be served by SOGS itself.  See S3Storage.download_url.
+    """
+    rehydrate(path)
+    return get().download_url(path, disposition, content_type)
+
+
+def delete_file(path: str):
+    """
+    Removes the file content stored at `path`, including any cold storage copy.  Raises
+    FileNotFoundError if the file does not exist.
+    """
+    row = query("SELECT cold_path FROM cold_files WHERE path = :p", p=path).first()
+    if row is not None:
+        query("DELETE FROM cold_files WHERE path = :p", p=path)
+    if row is None or row[0] is None:
+        get().delete(path)
+    else:
+        get_cold().delete(row[0])
+
+
+def archive_cold_files(limit=100):
+    """
+    Moves up to `limit` stored files that were uploaded (and, if previously restored from cold
+    storage, restored) more than config.UPLOAD_COLD_AFTER seconds ago, and aren't about to expire,
+    into cold storage.  Returns the number of files moved.
+    """
+    if not config.UPLOAD_COLD_AFTER:
+        return 0
+
+    now = time.time()
+    candidates = list(
+        query(
+            """
+            SELECT path, MAX(size) FROM files
+            WHERE path != 'tmp' AND path NOT IN (
+                SELECT path FROM cold_files WHERE cold_path IS NOT NULL OR restored >= :cutoff
+            )
+            GROUP BY path
+            HAVING MAX(uploaded) < :cutoff
+                AND SUM(CASE WHEN expiry IS NULL OR expiry > :soon THEN 1 ELSE 0 END) > 0
+            LIMIT :limit
+            """,
+            cutoff=now - config.UPLOAD_COLD_AFTER,
+            soon=now + 86400,
+            limit=limit,
+        )
+    )
+    if not candidates:
+        return 0
+
+    hot, cold = get(), get_cold()
+    moved = 0
+    for path, size in candidates:
+        try:
+            content = hot.read(path)
+        except FileNotFoundError:
+            app.logger.warning(f"Cannot move {path} to cold storage: file content is missing")
+            continue
+
+        compressed = False
+        if config.UPLOAD_COLD_COMPRESS:
+            packed = lzma.compress(content)
+            # Most media attachments are already compressed, so only keep it if it helps:
+            if len(packed) < len(content):
+                content, compressed = packed, True
+
+        h = blake2b(path.encode(), digest_size=16, person=b'sogs.coldfile').hex()
+        cold_path = None
+        try:
+            cold_path = cold.write(f"cold/{h[:2]}/{h}{'.xz' if compressed else ''}", content)
+            with db.transaction():
+                # Only claim the file if it isn't already cold (i.e. if a concurrent archiver
+                # didn't beat us to it), and delete the hot copy before committing the claim, so
+                # that a rehydration can't restore the hot copy before we delete it.
+                if not query(
+                    """
+                    INSERT INTO cold_files (path, cold_path, compressed, size, stored_size)
+                    VALUES (:p, :cp, :compressed, :size, :stored)
+                    ON CONFLICT (path) DO UPDATE SET
+                        cold_path = :cp, compressed = :compressed, size = :size,
+                        stored_size = :stored, archived = :now, restored = NULL
+                    WHERE cold_files.cold_path IS NULL
+                    """,
+                    p=path,
+                    cp=cold_path,
+                    compressed=compressed,
+                    size=size,
+                    stored=len(content),
+                    now=now,
+                ).rowcount:
+                    current = query("SELECT cold_path FROM cold_files WHERE path = :p", p=path)
+                    if current.first()[0] != cold_path:
+                        cold.delete(cold_path)
+                    continue
+
+                try:
+                    hot.delete(path)
+                except FileNotFoundError:
+                    pass
+        except Exception as e:
+            app.logger.warning(f"Failed to move {path} to cold storage: {e}")
+            if cold_path is not None:
+                try:
+                    cold.delete(cold_path)
+                except Exception:
+                    pass
+            continue
+
+        moved += 1
+
+    if moved:
+        app.logger.info(f"Moved {moved} files to cold storage")
+    return moved
+
+
+def tier_stats():
+    """
+    Returns a dict of storage tier statistics: `hot` and `cold` keys each containing a dict with
+    keys `files` (the number of stored files) and `size` (their total size, in bytes); the `cold`
+    value also contains `stored_size`, the total size actually stored (i.e. after compression).
+    Deduplicated content is counted only once.
+    """
+    hot_files, hot_size = query(
+        """
+        SELECT COUNT(*), COALESCE(SUM(size), 0) FROM (
+            SELECT path, MAX(size) AS size FROM files
+            WHERE path NOT IN (SELECT path FROM cold_files WHERE cold_path IS NOT NULL)
+            GROUP BY path
+        ) hot
+        """
+    ).first()
+    cold_files, cold_size, cold_stored = query(
+        """
+        SELECT COUNT(*), COALESCE(SUM(size), 0), COALESCE(SUM(stored_size), 0) FROM cold_files
+        WHERE cold_path IS NOT NULL
+        """
+    ).first()
+    return {
+        'hot': {'files': hot_files, 'size': hot_size},
+        'cold': {'files': cold_files, 'size': cold_size, 'stored_size': cold_stored},
+    }
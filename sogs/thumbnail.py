@@ -0,0 +1,59 @@
+from . import config, phash, storage
+
+import functools
+import io
+import warnings
+from typing import Optional, Tuple
+
+import PIL.Image
+
+# Downscaled thumbnails of uploaded images (such as room images), so that clients showing a room
+# list or a preview don't have to download and decode the full-size image.  Thumbnails are made on
+# demand and kept in a small in-memory cache: file content never changes once uploaded, so a cached
+# thumbnail never goes stale (callers are responsible for checking that the file still exists).
+#
+# Session attachments are encrypted, so thumbnails are only available for unencrypted uploads.
+
+
+def make(content: bytes, size: int) -> Optional[Tuple[bytes, str]]:
+    """
+    Returns a `(content, content_type)` tuple of a thumbnail of the image `content`, no larger than
+    `size` pixels in either dimension, or None if `content` isn't an image that we can decode.
+    Images with transparency are thumbnailed as PNG, others as JPEG.
+    """
+    try:
+        with warnings.catch_warnings():
+            warnings.simplefilter('ignore', PIL.Image.DecompressionBombWarning)
+            img = PIL.Image.open(io.BytesIO(content))
+            if img.width * img.height > phash.MAX_PIXELS:
+                return None
+            # Lets JPEG decoding skip most of the work:
+            img.draft('RGB', (size, size))
+            img.thumbnail((size, size), phash.LANCZOS)
+
+            out = io.BytesIO()
+            if img.mode in ('RGBA', 'LA', 'P'):
+                img.save(out, format='PNG', optimize=True)
+                return out.getvalue(), 'image/png'
+            img.convert('RGB').save(out, format='JPEG', quality=85)
+            return out.getvalue(), 'image/jpeg'
+    except Exception:
+        return None
+
+
+# Stored paths are based on file ids, which can be reused once a file is deleted, so the upload time
+# is part of the cache key, too.
+@functools.lru_cache(maxsize=256)
+def _cached(path: str, uploaded: float, size: int):
+    return make(storage.read_file(path), size)
+
+
+def get(file) -> Optional[Tuple[bytes, str]]:
+    """
+    Returns a `(content, content_type)` tuple of a thumbnail of the given File, no larger than
+    [files] `thumbnail_size` pixels in either dimension.  Returns None if thumbnails are disabled or
+    if the file isn't an image that we can decode.
+    """
+    if not config.THUMBNAIL_SIZE:
+        return None
+    return _cached(file.path, file.uploaded, config.THUMBNAIL_SIZE)
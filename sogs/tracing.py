@@ -0,0 +1,207 @@
+from . import __version__, config, metrics
+from .postfork import postfork
+from .shutdown import FLUSH, on_shutdown, remaining
+
+import contextlib
+import logging
+import time
+
+# OpenTelemetry export of request traces and metrics to an OTLP collector (such as Jaeger, Tempo,
+# or the OpenTelemetry collector), configured in the [otlp] section; this complements the
+# Prometheus endpoint (see sogs/prometheus.py).  A sampled fraction of requests is traced, with a
+# span for each request and child spans for the database queries and file storage operations made
+# while handling it.  Requests with a W3C `traceparent` header continue the caller's trace and
+# follow its sampling decision.  The in-process metrics of sogs.metrics, and the durations of all
+# requests, are exported periodically.
+#
+# This requires the opentelemetry-sdk and opentelemetry-exporter-otlp-proto-http modules, and does
+# nothing unless [otlp].endpoint is set.
+
+logger = logging.getLogger(__name__)
+
+_tracer = None
+_duration = None
+_providers = []
+
+
+def span(name: str, attributes: dict = None):
+    """
+    Returns a context manager that records a span (as a child of the current span) around its
+    block, if tracing is enabled; otherwise returns a no-op context manager.
+    """
+    if _tracer is None:
+        return contextlib.nullcontext()
+    return _tracer.start_as_current_span(name, attributes=attributes)
+
+
+def _observe_counters(options):
+    from opentelemetry.metrics import Observation
+
+    for name, value in metrics.snapshot()['counters'].items():
+        yield Observation(value, {'name': name})
+    for (name, labels), value in metrics.labeled_snapshot()['counters'].items():
+        yield Observation(value, {**dict(labels), 'name': name})
+
+
+def _observe_observations(options):
+    from opentelemetry.metrics import Observation
+
+    for name, o in metrics.snapshot()['observations'].items():
+        for stat in ('count', 'total', 'max'):
+            yield Observation(o[stat], {'name': name, 'stat': stat})
+
+
+def _setup():
+    global _tracer, _duration
+
+    from opentelemetry.exporter.otlp.proto.http.metric_exporter import OTLPMetricExporter
+    from opentelemetry.exporter.otlp.proto.http.trace_exporter import OTLPSpanExporter
+    from opentelemetry.sdk.metrics import MeterProvider
+    from opentelemetry.sdk.metrics.export import PeriodicExportingMetricReader
+    from opentelemetry.sdk.resources import Resource
+    from opentelemetry.sdk.trace import TracerProvider
+    from opentelemetry.sdk.trace.export import BatchSpanProcessor
+    from opentelemetry.sdk.trace.sampling import ParentBased, TraceIdRatioBased
+
+    endpoint = config.OTLP_ENDPOINT.rstrip('/')
+    resource = Resource.create(
+        {'service.name': config.OTLP_SERVICE_NAME, 'service.version': __version__}
+    )
+
+    tracer_provider = TracerProvider(
+        resource=resource, sampler=ParentBased(TraceIdRatioBased(config.OTLP_SAMPLE_RATE))
+    )
+    tracer_provider.add_span_processor(
+        BatchSpanProcessor(OTLPSpanExporter(endpoint=f"{endpoint}/v1/traces"))
+    )
+    _providers.append(tracer_provider)
+
+    if config.OTLP_METRICS_INTERVAL:
+        reader = PeriodicExportingMetricReader(
+            OTLPMetricExporter(endpoint=f"{endpoint}/v1/metrics"),
+            export_interval_millis=config.OTLP_METRICS_INTERVAL * 1000,
+        )
+        meter_provider = MeterProvider(resource=resource, metric_readers=[reader])
+        _providers.append(meter_provider)
+        meter = meter_provider.get_meter('sogs', __version__)
+        meter.create_observable_counter('sogs.counter', callbacks=[_observe_counters])
+        meter.create_observable_gauge('sogs.observation', callbacks=[_observe_observations])
+        _duration = meter.create_histogram(
+            'http.server.duration', unit='s', description="Duration of handled requests"
+        )
+
+    _tracer = tracer_provider.get_tracer('sogs', __version__)
+
+
+# The exporters send from background threads, which don't survive forking, so each uwsgi worker
+# needs to set up its own.
+@postfork
+def init_tracing():
+    if not config.OTLP_ENDPOINT or not config.RUNNING_AS_APP:
+        return
+    try:
+        _setup()
+    except ModuleNotFoundError as e:
+        logger.error(f"[otlp] endpoint is set, but OpenTelemetry is not available: {e}")
+
+
+@on_shutdown(phase=FLUSH)
+def flush_tracing():
+    """Sends any spans and metrics not yet exported."""
+    for provider in _providers:
+        provider.force_flush(int(remaining() * 1000))
+
+
+@on_shutdown
+def close_tracing():
+    for provider in _providers:
+        provider.shutdown()
+
+
+def install(app):
+    """Adds the request hooks that trace (and time) the requests handled by `app`."""
+    from flask import g, request
+
+    @app.before_request
+    def start_request_span():
+        if _tracer is None:
+            return
+        from opentelemetry import context, propagate, trace
+
+        route = request.url_rule.rule if request.url_rule is not None else 'none'
+        s = _tracer.start_span(
+            f"{request.method} {route}",
+            context=propagate.extract(request.headers),
+            kind=trace.SpanKind.SERVER,
+            attributes={'http.method': request.method, 'http.route': route},
+        )
+        # Stored in the environ rather than `g` because batch subrequests share the app context:
+        request.environ['sogs.otel'] = (
+            s,
+            context.attach(trace.set_span_in_context(s)),
+            time.perf_counter(),
+        )
+
+    @app.after_request
+    def end_request_timing(response):
+        otel = request.environ.get('sogs.otel')
+        if otel is None:
+            return response
+        s, _, started = otel
+        s.set_attribute('http.status_code', response.status_code)
+        if 'request_id' in g:
+            s.set_attribute('sogs.request_id', g.request_id)
+        if response.status_code >= 500:
+            from opentelemetry.trace import Status, StatusCode
+
+            s.set_status(Status(StatusCode.ERROR))
+        if _duration is not None:
+            _duration.record(
+                time.perf_counter() - started,
+                {
+                    'http.method': request.method,
+                    'http.route': request.url_rule.rule if request.url_rule is not None else 'none',
+                    'http.status_code': response.status_code,
+                },
+            )
+        return response
+
+    @app.teardown_request
+    def end_request_span(exc):
+        otel = request.environ.pop('sogs.otel', None)
+        if otel is None:
+            return
+        from opentelemetry import context
+
+        s, token, _ = otel
+        if exc is not None:
+            s.record_exception(exc)
+        context.detach(token)
+        s.end()
+
+
+def instrument_engine(engine):
+    """Adds child spans for the database queries made through `engine`, if [otlp] is configured."""
+    if not config.OTLP_ENDPOINT:
+        return
+    import sqlalchemy
+
+    @sqlalchemy.event.listens_for(engine, "before_cursor_execute")
+    def start_query_span(conn, cursor, statement, parameters, context, executemany):
+        if _tracer is not None:
+            context._sogs_span = _tracer.start_span(
+                'db.query', attributes={'db.system': engine.name, 'db.statement': statement}
+            )
+
+    @sqlalchemy.event.listens_for(engine, "after_cursor_execute")
+    def end_query_span(conn, cursor, statement, parameters, context, executemany):
+        s = getattr(context, '_sogs_span', None)
+        if s is not None:
+            s.end()
+
+    @sqlalchemy.event.listens_for(engine, "handle_error")
+    def end_failed_query_span(exception_context):
+        s = getattr(exception_context.execution_context, '_sogs_span', None)
+        if s is not None:
+            s.record_exception(exception_context.original_exception)
+            s.end()
@@ -0,0 +1,59 @@
+from . import config, outbound
+
+import json
+import re
+import urllib.request
+
+# Server-side translation of room messages.  This talks to a LibreTranslate-compatible service
+# configured via [messages].translate_url: we POST a JSON body of
+#
+#     {"q": "text", "source": "auto", "target": "de", "format": "text", "api_key": "..."}
+#
+# and expect a JSON response containing a `translatedText` key.  Requests go out from the SOGS
+# server itself, so the translation service never sees the client's IP address.
+
+# Language codes we accept: ISO 639-1/639-3 codes optionally followed by a region or script
+# subtag, e.g. `de`, `pt-BR`, `zh-Hant`.
+LANG_RE = re.compile(r'^[a-z]{2,3}(?:-[A-Za-z0-9]{2,8})?$')
+
+
+class TranslationFailed(RuntimeError):
+    """Raised when the translation service could not be reached or returned an invalid reply."""
+
+
+def enabled():
+    return bool(config.TRANSLATE_URL)
+
+
+def valid_lang(lang):
+    return isinstance(lang, str) and LANG_RE.match(lang) is not None
+
+
+def translate(text: str, lang: str):
+    """
+    Translates `text` into `lang` using the configured translation service.  Returns the
+    translated text; raises TranslationFailed on error.
+    """
+    if not enabled():
+        raise TranslationFailed("No translation service is configured")
+
+    req = {"q": text, "source": "auto", "target": lang, "format": "text"}
+    if config.TRANSLATE_API_KEY:
+        req["api_key"] = config.TRANSLATE_API_KEY
+
+    http_req = urllib.request.Request(
+        config.TRANSLATE_URL,
+        data=json.dumps(req).encode(),
+        headers={'Content-Type': 'application/json'},
+        method='POST',
+    )
+    try:
+        result = json.loads(outbound.fetch(http_req, timeout=config.TRANSLATE_TIMEOUT))
+    except Exception as e:
+        raise TranslationFailed(f"Translation request failed: {e}")
+
+    translated = result.get('translatedText') if isinstance(result, dict) else None
+    if not isinstance(translated, str):
+        raise TranslationFailed("Translation service returned an invalid response")
+
+    return translated
@@ -0,0 +1,143 @@
+from . import __version__, config, outbound
+from .db import query
+from .web import app
+
+import json
+import re
+import time
+
+# Opt-in checking for newer server releases, configured via [upgrade].  When a release feed URL is
+# set the scheduler periodically fetches it; the feed is a JSON document of the form:
+#
+#     {
+#         "releases": [
+#             {"version": "0.3.9", "security": true, "url": "https://.../releases/v0.3.9"},
+#             ...
+#         ]
+#     }
+#
+# where `security` (optional, default false) indicates that the release includes security fixes, and
+# `url` (optional) is a link to its release notes.  The result of the check (the newest release that
+# is newer than the running version, and whether any of the newer releases include security fixes)
+# is logged and stored in the database so that global admins can see it via /admin/upgrade.  This
+# only ever reports available releases: the server never updates itself.
+
+MAX_FEED_SIZE = 1_000_000
+
+
+def parse_version(v: str):
+    """
+    Parses a version string such as `0.3.8` or `0.3.8.dev0` into a value that compares in release
+    order (pre-release versions, i.e. those with a suffix, sort before the release itself).  Raises
+    ValueError if `v` is not a version string.
+    """
+    m = re.fullmatch(r'v?(\d+(?:\.\d+)*)(.*)', v.strip())
+    if not m:
+        raise ValueError(f"Invalid version {v!r}")
+    nums = [int(x) for x in m[1].split('.')]
+    while len(nums) > 1 and nums[-1] == 0:
+        nums.pop()
+    return tuple(nums), not m[2]
+
+
+def newer_releases(feed, current=None):
+    """
+    Returns the releases in release feed `feed` (parsed JSON) that are newer than version `current`
+    (default: the running version), newest first.  Raises ValueError if the feed is invalid.
+    """
+    releases = feed.get('releases') if isinstance(feed, dict) else None
+    if not isinstance(releases, list) or not all(
+        isinstance(r, dict) and isinstance(r.get('version'), str) for r in releases
+    ):
+        raise ValueError("Invalid release feed: expected a list of releases with versions")
+
+    cur = parse_version(current or __version__)
+    newer = [r for r in releases if parse_version(r['version']) > cur]
+    newer.sort(key=lambda r: parse_version(r['version']), reverse=True)
+    return newer
+
+
+def _store(version=None, security=False, url=None):
+    query(
+        """
+        INSERT INTO upgrade_advisory (id, checked, version, security, url, error)
+        VALUES (0, :checked, :version, :security, :url, NULL)
+        ON CONFLICT (id) DO UPDATE SET
+            checked = excluded.checked, version = excluded.version,
+            security = excluded.security, url = excluded.url, error = NULL
+        """,
+        checked=time.time(),
+        version=version,
+        security=security,
+        url=url,
+    )
+
+
+def _store_error(error):
+    # Keeps the result of the last successful check, if any:
+    query(
+        """
+        INSERT INTO upgrade_advisory (id, checked, error) VALUES (0, :checked, :error)
+        ON CONFLICT (id) DO UPDATE SET checked = excluded.checked, error = excluded.error
+        """,
+        checked=time.time(),
+        error=error,
+    )
+
+
+def check():
+    """
+    Checks the release feed for newer releases, and records the result.  Returns the version of the
+    newest newer release, an empty string if the server is up to date, or None if checking is
+    disabled.  Raises an exception (after recording the error) if the feed cannot be retrieved or
+    parsed.
+    """
+    if not config.UPGRADE_FEED_URL:
+        return None
+
+    try:
+        data = outbound.fetch(
+            config.UPGRADE_FEED_URL, timeout=config.UPGRADE_TIMEOUT, limit=MAX_FEED_SIZE
+        )
+        newer = newer_releases(json.loads(data))
+    except (outbound.OutboundError, ValueError) as e:
+        _store_error(str(e))
+        raise
+
+    if not newer:
+        _store()
+        app.logger.debug(f"SOGS {__version__} is up to date")
+        return ''
+
+    latest = newer[0]
+    security = any(r.get('security') is True for r in newer)
+    url = latest.get('url') if isinstance(latest.get('url'), str) else None
+    _store(version=latest['version'], security=security, url=url)
+
+    msg = f"A newer SOGS version ({latest['version']}) is available"
+    if url:
+        msg += f"; see {url}"
+    if security:
+        app.logger.warning(f"{msg}.  Upgrading is recommended as it includes security fixes.")
+    else:
+        app.logger.info(msg)
+    return latest['version']
+
+
+def status():
+    """
+    Returns a dict of the upgrade check status: `enabled` (whether a release feed is configured),
+    `current` (the running version), and, if a check has been made, `checked` (when the feed was
+    last checked), `latest` (the newest available release, or None if up to date), `security`
+    (whether any newer release includes security fixes), `url` (the newest release's release notes
+    URL, if given), and `error` (why the last check failed, if it did).
+    """
+    result = {'enabled': bool(config.UPGRADE_FEED_URL), 'current': __version__}
+    row = query("SELECT checked, version, security, url, error FROM upgrade_advisory").first()
+    if row is not None:
+        result['checked'] = row['checked']
+        result['latest'] = row['version']
+        result['security'] = bool(row['security'])
+        result['url'] = row['url']
+        result['error'] = row['error']
+    return result
@@ -5,6 +5,8 @@ from . import http
 import base64
 from flask import request, abort, Response
 import json
+import re
+from functools import wraps
 from typing import Union, Tuple
 
 
@@ -104,6 +106,24 @@ SESSION_ID_SIZE = 33
 LEGACY_TOKEN_SIZE = SIGNATURE_SIZE + SESSION_ID_SIZE
 
 
+def client_addr():
+    """
+    Returns the IP address of the client making the current request.  This is the remote address of
+    the request unless that is one of the configured [net].trusted_proxies, in which case it is the
+    last address in the X-Forwarded-For header that isn't itself a trusted proxy.
+    """
+    addr = request.remote_addr or ''
+    if addr not in config.TRUSTED_PROXIES:
+        return addr
+    for fwd in reversed(request.headers.get('X-Forwarded-For', '').split(',')):
+        fwd = fwd.strip()
+        if fwd:
+            addr = fwd
+            if addr not in config.TRUSTED_PROXIES:
+                break
+    return addr
+
+
 def make_legacy_token(session_id):
     session_id = bytes.fromhex(session_id)
     return crypto.server_sign(session_id)
@@ -114,10 +134,25 @@ def legacy_convert_time(float_time):
     return int(float_time * 1000)
 
 
+def bad_param(name, reason):
+    """
+    Aborts the current request with a Bad Request error status code and a plain text body
+    describing the problem with query parameter `name`.
+    """
+    abort(
+        Response(
+            f"Invalid query parameter `{name}`: {reason}",
+            status=http.BAD_REQUEST,
+            mimetype='text/plain',
+        )
+    )
+
+
 def get_int_param(name, default=None, *, required=False, min=None, max=None, truncate=False):
     """
     Returns a provided named parameter (typically a query string parameter) as an integer from the
-    current request.  On error we abort the request with a Bad Request error status code.
+    current request.  On error we abort the request with a Bad Request error status code and a
+    message describing the offending parameter.
 
     Parameters:
     - required -- if True then not specifying the argument is an error.
@@ -125,32 +160,67 @@ def get_int_param(name, default=None, *, required=False, min=None, max=None, tru
     - min -- the minimum acceptable value for the parameter; None means no minimum.
     - max -- the maximum acceptable value for the parameter; None means no maximum.
     - truncate -- if True then we truncate a >max or <min value to max or min, respectively.  When
-      False (the default) we error.
+      False (the default), or when [net] strict_query_params is enabled, we error.
     """
     val = request.args.get(name)
     if val is None:
         if required:
-            abort(http.BAD_REQUEST)
+            bad_param(name, "parameter is required")
         return default
 
     try:
         val = int(val)
     except Exception:
-        abort(http.BAD_REQUEST)
+        bad_param(name, "expected an integer")
+
+    if truncate and config.STRICT_QUERY_PARAMS:
+        truncate = False
 
     if min is not None and val < min:
         if truncate:
             val = min
         else:
-            abort(http.BAD_REQUEST)
+            bad_param(name, f"must be at least {min}")
     elif max is not None and val > max:
         if truncate:
             val = max
         else:
-            abort(http.BAD_REQUEST)
+            bad_param(name, f"must be at most {max}")
     return val
 
 
+def query_params(*names):
+    """
+    Decorator for a route declaring the query string parameters that it accepts.  If
+    [net] strict_query_params is enabled then requests containing any other query parameters are
+    rejected with a Bad Request error listing the unknown parameters; otherwise unknown parameters
+    are ignored.
+    """
+
+    def decorator(f):
+        @wraps(f)
+        def validated(*args, **kwargs):
+            if config.STRICT_QUERY_PARAMS:
+                unknown = sorted(k for k in request.args.keys() if k not in names)
+                if unknown:
+                    abort(
+                        Response(
+                            "Unknown query parameter{} {}; accepted parameters: {}".format(
+                                's' if len(unknown) > 1 else '',
+                                ', '.join(f"`{u}`" for u in unknown),
+                                ', '.join(f"`{n}`" for n in names) if names else '(none)',
+                            ),
+                            status=http.BAD_REQUEST,
+                            mimetype='text/plain',
+                        )
+                    )
+            return f(*args, **kwargs)
+
+        return validated
+
+    return decorator
+
+
 def remove_session_message_padding(data: bytes):
     """Removes the custom padding that Session may have added.  Returns the unpadded data."""
 
@@ -174,3 +244,29 @@ def add_session_message_padding(data: Union[bytes, memoryview], length):
             data = bytes(data)
         data += b'\x80' + b'\x00' * (length - len(data) - 1)
     return data
+
+
+# Control characters, path separators, and the bidirectional formatting characters that can make a
+# filename display as something other than what it is (e.g. `invoice\u202etxt.exe` displays as
+# `invoiceexe.txt`).
+_FILENAME_UNSAFE = re.compile('[\x00-\x1f\x7f-\x9f/\u061c\u200e\u200f\u202a-\u202e\u2066-\u2069]')
+
+FILENAME_MAX_LENGTH = 200
+
+
+def sanitize_filename(filename: str):
+    """
+    Returns the sanitized version of a user-provided attachment filename that we store and suggest
+    to downloaders: unsafe characters (see above) are replaced with U+FFFD (REPLACEMENT CHARACTER),
+    surrounding whitespace and leading dots (which would make a hidden file) are removed, and
+    overlong names are truncated to FILENAME_MAX_LENGTH characters, keeping the extension.  Returns
+    None if nothing is left of the filename.
+    """
+    name = re.sub(r'^[\s.]+|\s+$', '', _FILENAME_UNSAFE.sub('\uFFFD', filename))
+    if len(name) > FILENAME_MAX_LENGTH:
+        stem, dot, ext = name.rpartition('.')
+        if dot and len(ext) <= 16:
+            name = stem[: FILENAME_MAX_LENGTH - len(ext) - 1] + '.' + ext
+        else:
+            name = name[:FILENAME_MAX_LENGTH]
+    return name or None
@@ -1,10 +1,16 @@
 import flask
 from werkzeug.local import LocalProxy
-from . import config
+from . import __version__, config, redact, tracing
 import coloredlogs
 
 app = flask.Flask(__name__, template_folder=config.TEMPLATE_PATH, static_folder=config.STATIC_PATH)
+# Static files are referenced with a `?v=VERSION` query string (see the templates), so that they can
+# be cached for a long time without browsers keeping stale copies after an upgrade:
+app.config['SEND_FILE_MAX_AGE_DEFAULT'] = config.WEB_STATIC_MAX_AGE
+app.jinja_env.globals['sogs_version'] = __version__
 coloredlogs.install(milliseconds=True, isatty=True, logger=app.logger, level=config.LOG_LEVEL)
+redact.install()
+tracing.install(app)
 
 # Monkey-patch app.get/post/etc. for Flask <2 compatibility; this has to be before the imports,
 # below, because they depend on this existing.
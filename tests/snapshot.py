@@ -0,0 +1,51 @@
+import json
+import os
+import pytest
+
+# Snapshot testing of API response formats.  assert_snapshot() reduces a JSON response to its
+# "shape" (the same structure, but with each value replaced by the name of its JSON type) and
+# compares it against the shape stored in tests/snapshots/NAME.json, so that any change to the keys
+# or value types that clients see fails the test.  Values themselves (ids, timestamps, session ids,
+# etc.) are deliberately not compared, which keeps the snapshots deterministic.
+#
+# Missing snapshots are written out (and should be committed) rather than failing, except under CI.
+# After an intentional format change, rerun the tests with SOGS_UPDATE_SNAPSHOTS=1 to rewrite the
+# snapshots, and review the diff.
+
+SNAPSHOT_DIR = os.path.join(os.path.dirname(__file__), 'snapshots')
+
+
+def shape(value):
+    """Returns the shape of a JSON value, as described above"""
+    if isinstance(value, dict):
+        return {k: shape(v) for k, v in sorted(value.items())}
+    if isinstance(value, list):
+        return [shape(v) for v in value]
+    if value is None:
+        return 'null'
+    if isinstance(value, bool):
+        return 'bool'
+    if isinstance(value, int):
+        return 'int'
+    if isinstance(value, float):
+        return 'float'
+    return 'str'
+
+
+def assert_snapshot(name, value):
+    """Asserts that the shape of `value` matches snapshot `name`"""
+    actual = shape(value)
+    path = os.path.join(SNAPSHOT_DIR, f'{name}.json')
+    update = bool(os.environ.get('SOGS_UPDATE_SNAPSHOTS'))
+    if update or not os.path.exists(path):
+        if not update and os.environ.get('CI'):
+            pytest.fail(f"Snapshot {name} does not exist")
+        os.makedirs(SNAPSHOT_DIR, exist_ok=True)
+        with open(path, 'w') as f:
+            json.dump(actual, f, indent=2)
+            f.write('\n')
+        return
+
+    with open(path) as f:
+        expected = json.load(f)
+    assert actual == expected, f"{name} response format does not match tests/snapshots/{name}.json"
@@ -0,0 +1,76 @@
+from auth import x_sogs_for
+from request import sogs_get, sogs_post, sogs_delete
+from util import pad64
+from sogs.model.user import SystemUser
+
+
+def test_api_keys(client, room, room2, user, mod, admin, no_rate_limit):
+    room.default_accessible = False
+    room.default_read = False
+    msg = room.add_post(user, b'private', pad64('sig'))
+
+    url = f"/room/{room.token}/api_keys"
+    assert sogs_post(client, url, {'description': 'Dashboard'}, mod).status_code == 403
+    assert sogs_post(client, url, {}, admin).status_code == 400
+
+    r = sogs_post(client, url, {'description': 'Dashboard'}, admin)
+    assert r.status_code == 201
+    api_key = r.json
+    key = api_key.pop('key')
+    assert api_key['description'] == 'Dashboard'
+    assert api_key['last_used'] is None
+
+    assert sogs_get(client, url, mod).status_code == 403
+    assert sogs_get(client, url, admin).json == [api_key]
+
+    recent = f"/room/{room.token}/messages/recent"
+    assert client.get(recent).status_code == 403
+    assert client.get(f"/room/{room.token}").status_code == 404
+
+    headers = {'X-SOGS-Api-Key': key}
+    r = client.get(recent, headers=headers)
+    assert r.status_code == 200
+    assert [m['id'] for m in r.json] == [msg['id']]
+    r = client.get(f"/room/{room.token}", headers=headers)
+    assert r.status_code == 200
+    assert r.json['token'] == room.token
+
+    assert sogs_get(client, url, admin).json[0]['last_used'] is not None
+
+    # Keys are scoped to their room, and are read-only:
+    room2.default_read = False
+    assert client.get(f"/room/{room2.token}/messages/recent", headers=headers).status_code == 403
+    r = client.post(f"/room/{room.token}/message", headers=headers, json={})
+    assert r.status_code == 401
+    assert client.get(url, headers=headers).status_code == 401
+
+    # Can't be combined with user auth:
+    h = {**x_sogs_for(user, 'GET', recent), **headers}
+    assert client.get(recent, headers=h).status_code == 400
+
+    assert client.get(recent, headers={'X-SOGS-Api-Key': 'not-a-key'}).status_code == 401
+
+    revoke = f"/room/{room.token}/api_key/{api_key['id']}"
+    assert sogs_delete(client, revoke, mod).status_code == 403
+    # Can't revoke via another room:
+    room2.set_moderator(admin, added_by=SystemUser(), admin=True)
+    r = sogs_delete(client, f"/room/{room2.token}/api_key/{api_key['id']}", admin)
+    assert r.status_code == 404
+
+    r = sogs_delete(client, revoke, admin)
+    assert r.status_code == 200
+    assert sogs_get(client, url, admin).json == []
+    assert client.get(recent, headers=headers).status_code == 401
+
+
+def test_revoke_all_api_keys(client, room, room2, admin, global_admin):
+    room2.set_moderator(admin, added_by=SystemUser(), admin=True)
+    for r in (room, room, room2):
+        sogs_post(client, f"/room/{r.token}/api_keys", {'description': 'key'}, admin)
+
+    assert sogs_delete(client, "/admin/api_keys", admin).status_code == 403
+    r = sogs_delete(client, f"/admin/api_keys?room={room.token}", global_admin)
+    assert r.json == {'revoked': 2}
+    assert sogs_get(client, f"/room/{room.token}/api_keys", admin).json == []
+    assert len(sogs_get(client, f"/room/{room2.token}/api_keys", admin).json) == 1
+    assert sogs_delete(client, "/admin/api_keys", global_admin).json == {'revoked': 1}
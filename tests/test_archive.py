@@ -0,0 +1,67 @@
+import time
+from request import sogs_get, sogs_post, sogs_delete
+from util import config_override, pad64
+from sogs import archive, utils
+from sogs.db import query
+from sogs.model.room import Room
+
+
+def post(client, room, user, body):
+    d, s = (utils.encode_base64(x) for x in (body.encode(), pad64(body)))
+    return sogs_post(client, f"/room/{room.token}/message", {"data": d, "signature": s}, user)
+
+
+def test_archive_room(client, room, user, mod, admin, no_rate_limit):
+    url = f"/room/{room.token}/archive"
+    assert sogs_post(client, url, {}, mod).status_code == 403
+
+    info_updates = room.info_updates
+    r = sogs_post(client, url, {}, admin)
+    assert r.status_code == 200
+    archived = r.json['archived']
+    assert archived > time.time() - 60
+
+    r = sogs_get(client, f"/room/{room.token}", user)
+    assert r.json['archived'] == archived
+    assert r.json['info_updates'] > info_updates
+    assert not r.json['write']
+    assert not r.json['upload']
+
+    assert post(client, room, user, "hello").status_code == 403
+    assert post(client, room, mod, "hello").status_code == 403
+    assert post(client, room, admin, "admins can still post").status_code == 201
+
+    assert sogs_delete(client, url, mod).status_code == 403
+    assert sogs_delete(client, url, admin).status_code == 200
+    assert 'archived' not in sogs_get(client, f"/room/{room.token}", user).json
+    assert post(client, room, user, "hello").status_code == 201
+
+
+def test_archive_policy(client, room, room2, user, admin, no_rate_limit):
+    day = 86400
+    now = time.time()
+    room.add_post(user, b'hello', pad64(b'hello'))
+    query("UPDATE messages SET posted = :t WHERE room = :r", t=now - 40 * day, r=room.id)
+    query("UPDATE rooms SET created = :t", t=now - 100 * day)
+    room2.add_post(user, b'recent', pad64(b'recent'))
+
+    # Disabled by default:
+    assert archive.run() is None
+
+    with config_override(ROOM_ARCHIVE_AFTER=30 * day, ROOM_ARCHIVE_DELETE_AFTER=60 * day):
+        assert archive.run(now) == {'archived': [room.token], 'deleted': []}
+        assert Room(id=room.id).archived is not None
+        assert Room(id=room2.id).archived is None
+        assert archive.run(now) == {'archived': [], 'deleted': []}
+
+        # Unarchiving counts as activity:
+        Room(id=room.id).unarchive(admin)
+        assert archive.run(now + 29 * day) == {'archived': [], 'deleted': []}
+        assert archive.run(now + 31 * day) == {'archived': [room.token, room2.token], 'deleted': []}
+
+        # Exempted rooms are left alone:
+        with config_override(ROOM_OVERRIDES={room.token: {'archive': False}}):
+            assert archive.run(now + 200 * day) == {'archived': [], 'deleted': [room2.token]}
+        assert archive.run(now + 200 * day) == {'archived': [], 'deleted': [room.token]}
+
+    assert query("SELECT COUNT(*) FROM rooms").first()[0] == 0
@@ -2,11 +2,14 @@ from sogs.web import app
 from sogs.crypto import server_pubkey
 from sogs.routes.auth import user_required
 from auth import x_sogs_raw, x_sogs
+from util import config_override
 import sogs.utils
 
 import json
+import pytest
 from nacl.signing import SigningKey
 import nacl.bindings as sodium
+import nacl.exceptions
 
 
 @app.get("/auth_test/whoami")
@@ -539,3 +542,152 @@ def test_small_subgroups(client, db):
     r = client.get("/auth_test/whoami", headers=headers)
     assert r.status_code == 400
     assert r.data == b'Invalid authentication: given X-SOGS-Pubkey is not a valid Ed25519 pubkey'
+
+
+def test_auth_cache(client, db, global_admin):
+    from sogs import metrics, sigcache
+    from request import sogs_get
+
+    sigcache.clear()
+    metrics.reset()
+
+    a = SigningKey.generate()
+    pk = a.verify_key.encode()
+    sig = a.sign(b'hello').signature
+    sigcache.verify(pk, b'hello', sig)
+    sigcache.verify(pk, b'hello', sig)
+    with pytest.raises(nacl.exceptions.BadSignatureError):
+        sigcache.verify(pk, b'goodbye', sig)
+    with pytest.raises(nacl.exceptions.BadSignatureError):
+        sigcache.verify(pk, b'goodbye', sig)
+
+    # Signatures are verified every time (each request signs a new nonce, so caching them is
+    # pointless):
+    stats = sigcache.stats()
+    assert stats['verifications'] == 4
+    assert stats['cache_hits'] == 0
+    assert stats['cached'] == 0
+
+    # Repeated requests by the same user reuse the validated pubkey:
+    B = server_pubkey
+    for _ in range(3):
+        r = client.get("/auth_test/whoami", headers=x_sogs(a, B, 'GET', '/auth_test/whoami'))
+        assert r.status_code == 200
+    assert metrics.counter('auth.pubkey.cache_misses') == 1
+    assert metrics.counter('auth.pubkey.cache_hits') == 2
+
+    # Failures are never cached, and a cache size of 0 disables caching:
+    assert sigcache.session_id(b'\x00' * 32, False) is None
+    with config_override(AUTH_CACHE_SIZE=0):
+        sigcache.clear()
+        assert sigcache.session_id(pk, True) == '15' + pk.hex()
+        assert sigcache.stats()['cached'] == 0
+
+    r = sogs_get(client, '/admin/metrics', global_admin)
+    assert r.status_code == 200
+    assert r.json['auth']['verifications'] > 4
+    assert 0 < r.json['auth']['hit_rate'] < 1
+    assert 'checked_out' in r.json['db_pool']
+
+
+def test_auth_providers(client, db, user, global_admin):
+    from flask import g, request
+    from sogs.routes import auth
+
+    class TokenAuth(auth.AuthProvider):
+        name = 'test_token'
+
+        def present(self):
+            return 'X-Test-Token' in request.headers
+
+        def authenticate(self):
+            if request.headers['X-Test-Token'] != 'sekrit':
+                auth.abort_with_reason(401, 'Invalid authentication: bad token')
+            g.user = global_admin
+
+    auth.register_provider(TokenAuth())
+    token = {'X-Test-Token': 'sekrit'}
+    try:
+        # Not accepted unless enabled in the config:
+        r = client.get("/auth_test/whoami", headers=token)
+        assert r.json == {'user': None}
+
+        with config_override(AUTH_PROVIDERS={'session', 'test_token'}):
+            r = client.get("/auth_test/whoami", headers=token)
+            assert r.json['user']['session_id'] == global_admin.session_id
+            r = client.get("/auth_test/whoami", headers={'X-Test-Token': 'guess'})
+            assert r.status_code == 401
+
+            h = {**x_sogs(user.ed_key, server_pubkey, 'GET', '/auth_test/whoami'), **token}
+            assert client.get("/auth_test/whoami", headers=h).status_code == 400
+
+            assert client.get("/admin/jobs", headers=token).status_code == 200
+
+            # Providers can be restricted per endpoint group, including for subrequests:
+            with config_override(AUTH_GROUPS={'admin': {'providers': {'session'}}}):
+                assert client.get("/admin/jobs", headers=token).status_code == 401
+                r = client.post(
+                    "/sequence",
+                    json=[{"method": "GET", "path": "/admin/jobs"}],
+                    headers=token,
+                )
+                assert r.status_code == 200
+                assert r.json[0]['code'] == 401
+
+                h = x_sogs(global_admin.ed_key, server_pubkey, 'GET', '/admin/jobs')
+                assert client.get("/admin/jobs", headers=h).status_code == 200
+    finally:
+        del auth.providers['test_token']
+
+
+def test_legacy_token_cutoff(client, room, user):
+    from sogs import metrics
+    import time
+
+    token = sogs.utils.make_legacy_token(user.session_id).hex()
+    headers = {'Authorization': token, 'Room': room.token}
+    with config_override(REQUIRE_BLIND_KEYS=False):
+        legacy = metrics.counter('auth.mode.legacy_token')
+        r = client.get("/legacy/member_count", headers=headers)
+        assert r.status_code == 200
+        assert 'Sunset' not in r.headers
+        assert metrics.counter('auth.mode.legacy_token') == legacy + 1
+
+        with config_override(AUTH_LEGACY_CUTOFF=time.time() + 86400):
+            r = client.get("/legacy/member_count", headers=headers)
+            assert r.status_code == 200
+            assert 'Sunset' in r.headers
+
+        with config_override(AUTH_LEGACY_CUTOFF=time.time() - 1):
+            assert client.get("/legacy/member_count", headers=headers).status_code == 401
+            r = client.get(f"/legacy/auth_token_challenge?public_key={user.session_id}")
+            assert r.status_code == 401
+
+        with config_override(AUTH_LEGACY_TOKENS=False):
+            assert client.get("/legacy/member_count", headers=headers).status_code == 401
+
+
+def test_admin_client_certs(client, global_admin):
+    from request import sogs_get, sogs_post
+
+    fingerprint = 'ab' * 32
+    assert sogs_get(client, "/admin/jobs", global_admin).status_code == 200
+    with config_override(ADMIN_CLIENT_CERTS={fingerprint}):
+        assert sogs_get(client, "/admin/jobs", global_admin).status_code == 403
+        assert client.get("/capabilities").status_code == 200
+
+        h = x_sogs(global_admin.ed_key, server_pubkey, 'GET', '/admin/jobs')
+        cert = {'X-SSL-Client-Fingerprint': ':'.join(['AB'] * 32)}
+        assert client.get("/admin/jobs", headers={**h, **cert}).status_code == 200
+        r = client.get("/admin/jobs", headers={**h, 'X-SSL-Client-Fingerprint': 'cd' * 32})
+        assert r.status_code == 403
+
+        # Subrequest headers can't supply the certificate:
+        r = sogs_post(
+            client,
+            "/sequence",
+            [{"method": "GET", "path": "/admin/jobs", "headers": cert}],
+            global_admin,
+        )
+        assert r.status_code == 200
+        assert r.json[0]['code'] == 403
@@ -0,0 +1,156 @@
+import base64
+import sqlite3
+from util import config_override, pad64
+
+
+def _old_room_db(path):
+    """Creates an old (0.1.x) style room database containing messages with ids 1-10, of which
+    message 4 is deleted"""
+    conn = sqlite3.connect(path)
+    conn.executescript(
+        """
+        CREATE TABLE messages (
+            id INTEGER PRIMARY KEY, public_key TEXT, timestamp INTEGER, data TEXT,
+            signature TEXT, is_deleted INTEGER
+        );
+        CREATE TABLE deleted_messages (id INTEGER PRIMARY KEY, deleted_message_id INTEGER);
+        """
+    )
+    for i in range(1, 11):
+        session_id = '05' + f'{i % 3:02x}' * 32
+        if i == 4:
+            conn.execute(
+                "INSERT INTO messages VALUES (?, 'deleted', ?, 'deleted', 'deleted', 1)",
+                (i, 1_600_000_000_000 + i * 1000),
+            )
+            conn.execute("INSERT INTO deleted_messages (deleted_message_id) VALUES (?)", (i,))
+        else:
+            conn.execute(
+                "INSERT INTO messages VALUES (?, ?, ?, ?, ?, 0)",
+                (
+                    i,
+                    session_id,
+                    1_600_000_000_000 + i * 1000,
+                    base64.b64encode(f"old {i}".encode() + b'\x80' + b'\0' * 10).decode(),
+                    base64.b64encode(pad64(f"sig {i}")).decode(),
+                ),
+            )
+    conn.commit()
+    conn.close()
+
+
+def test_backfill(client, room, user, monkeypatch, tmp_path):
+    from sogs import backfill
+    from sogs.db import query
+    from sogs.migrations.v_0_1_x import import_messages, sqlite_connect_readonly
+
+    path = str(tmp_path / 'old-room.db')
+    _old_room_db(path)
+
+    offset = query("SELECT COALESCE(MAX(id), 0) FROM messages").first()[0]
+
+    # What the startup import does: import the newest message, and leave the rest for later
+    with sqlite_connect_readonly(path) as rconn:
+        assert import_messages(None, rconn, room.id, offset, limit=1) == (1, 0, 10)
+    query(
+        """
+        CREATE TABLE room_import_backfill (
+            room BIGINT PRIMARY KEY NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+            db_path TEXT NOT NULL,
+            id_offset BIGINT NOT NULL,
+            before_id BIGINT NOT NULL
+        )
+        """
+    )
+    query(
+        "INSERT INTO room_import_backfill VALUES (:r, :p, :o, 10)", r=room.id, p=path, o=offset
+    )
+    monkeypatch.setattr(backfill, '_pending', {room.id: [offset, 10]})
+    monkeypatch.setattr(backfill, 'BATCH_SIZE', 20)
+
+    new = room.add_post(user, b'new post', pad64('new sig'))
+    assert new['id'] == offset + 11
+
+    # Reading recent history backfills just what we need:
+    msgs = room.get_messages_for(user, recent=True, limit=3)
+    assert [m['id'] for m in msgs] == [offset + 11, offset + 10, offset + 9]
+    assert msgs[1]['data'].rstrip(b'\0') == b'old 10\x80'
+    assert backfill._pending[room.id] == [offset, 7]
+
+    # As does fetching an old message directly:
+    msgs = room.get_messages_for(user, single=offset + 5)
+    assert [m['id'] for m in msgs] == [offset + 5]
+    assert backfill._pending[room.id][1] <= 5
+
+    # The background job imports the rest:
+    assert backfill.run() > 0
+    assert not backfill.pending(room.id)
+    assert query("SELECT COUNT(*) FROM room_import_backfill").first()[0] == 0
+    assert backfill.run() == 0
+
+    rows = {
+        id - offset: (data is None, seqno)
+        for id, data, seqno in query(
+            "SELECT id, data, seqno_data FROM messages WHERE room = :r AND id <= :max",
+            r=room.id,
+            max=offset + 10,
+        )
+    }
+    # The deleted message is a tombstone, and the old deletion ids become seqnos:
+    assert rows == {i: (i == 4, 0 if i < 4 else 1) for i in range(1, 11)}
+
+
+def test_import_verify(client, room, tmp_path):
+    from sogs import sigcache
+    from sogs.db import query
+    from sogs.migrations.v_0_1_x import import_messages, sqlite_connect_readonly
+    from nacl.signing import SigningKey
+
+    # XEd25519 pubkeys derived from a session id always have the sign bit cleared:
+    keys = (SigningKey.generate() for _ in range(100))
+    good, other = [k for k in keys if k.verify_key.encode()[31] < 0x80][:2]
+
+    path = str(tmp_path / 'old-room.db')
+    conn = sqlite3.connect(path)
+    conn.executescript(
+        """
+        CREATE TABLE messages (
+            id INTEGER PRIMARY KEY, public_key TEXT, timestamp INTEGER, data TEXT,
+            signature TEXT, is_deleted INTEGER
+        );
+        CREATE TABLE deleted_messages (id INTEGER PRIMARY KEY, deleted_message_id INTEGER);
+        """
+    )
+    session_id = '05' + good.verify_key.to_curve25519_public_key().encode().hex()
+    for i, signer in ((1, good), (2, other), (3, good)):
+        data = f"msg {i}".encode() + b'\x80' + b'\0' * 10
+        conn.execute(
+            "INSERT INTO messages VALUES (?, ?, ?, ?, ?, 0)",
+            (
+                i,
+                session_id,
+                1_600_000_000_000 + i * 1000,
+                base64.b64encode(data).decode(),
+                base64.b64encode(signer.sign(data).signature).decode(),
+            ),
+        )
+    conn.commit()
+    conn.close()
+
+    offset = query("SELECT COALESCE(MAX(id), 0) FROM messages").first()[0]
+    with config_override(IMPORT_VERIFY=True), sqlite_connect_readonly(path) as rconn:
+        assert import_messages(None, rconn, room.id, offset) == (3, 0, None)
+
+    rows = {
+        id - offset: data
+        for id, data in query("SELECT id, data FROM messages WHERE room = :r", r=room.id)
+    }
+    # The message signed by someone else is imported as deleted:
+    assert rows == {1: b'msg 1', 2: None, 3: b'msg 3'}
+
+    assert sigcache.verify_batch([]) == []
+    msg = b'hello'
+    items = [(good.verify_key.encode(), msg, good.sign(msg).signature)] * 250
+    items.append((good.verify_key.encode(), msg, other.sign(msg).signature))
+    items.append((b'short', msg, good.sign(msg).signature))
+    assert sigcache.verify_batch(items) == [True] * 250 + [False, False]
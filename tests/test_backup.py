@@ -0,0 +1,107 @@
+import json
+import os
+import pytest
+import sqlite3
+from sogs import backup, db
+from sogs.model.exc import AlreadyExists
+from sogs.model.file import File
+from sogs.model.room import Room
+from util import config_override, pad64
+
+
+def _copy_test_db(path):
+    # The test database lives in memory, so we copy it through its connection:
+    raw = db.engine.raw_connection()
+    try:
+        with sqlite3.connect(path) as dest:
+            raw.connection.backup(dest)
+    finally:
+        raw.close()
+
+
+def test_backup(client, room, user, user2, mod, global_admin, tmp_path, no_rate_limit):
+    if db.engine.name != 'sqlite':
+        pytest.skip("backups are of SQLite databases")
+
+    room.add_post(user, b'one', pad64('sig1'))
+    m2 = room.add_post(user2, b'two', pad64('sig2'))
+    room.delete_posts([m2['id']], mod)
+    room.ban_user(user2, mod=mod)
+
+    src = str(tmp_path / 'sogs.db')
+    _copy_test_db(src)
+    uploads = tmp_path / 'uploads'
+    os.makedirs(uploads / room.token)
+    (uploads / room.token / '1_hello.txt').write_text('hello')
+
+    dest = str(tmp_path / 'backup')
+    with config_override(
+        DB_URL=f'sqlite:///{src}', STORAGE_BACKEND='local', UPLOAD_PATH=str(uploads)
+    ):
+        manifest = backup.create(dest)
+        with pytest.raises(RuntimeError):
+            backup.create(dest)
+    assert manifest['rooms'] == 1
+    assert manifest['files'] == {'uploads': str(uploads)}
+    with open(os.path.join(dest, 'manifest.json')) as f:
+        assert json.load(f) == manifest
+    with open(os.path.join(dest, 'files', 'uploads', room.token, '1_hello.txt')) as f:
+        assert f.read() == 'hello'
+
+    # Importing fails (and imports nothing) if a room already exists:
+    with pytest.raises(AlreadyExists):
+        backup.import_backup(dest)
+    assert db.query("SELECT COUNT(*) FROM rooms").first()[0] == 1
+
+    room.delete()
+    result = backup.import_backup(dest)
+    assert result == {'rooms': 1, 'messages': 1, 'files': 0, 'moderators': 2, 'bans': 1}
+
+    r = Room(token=room.token)
+    assert r.name == room.name and r.description == room.description
+    msgs = r.get_messages_for(user, recent=True)
+    assert [(m['session_id'], m['signature']) for m in msgs] == [(user.session_id, pad64('sig1'))]
+    assert r.check_moderator(mod)
+    assert not r.check_unbanned(user2)
+    assert r.check_admin(global_admin)
+
+
+def test_backup_keep_ids(client, room, user, user2, mod, admin, tmp_path, no_rate_limit):
+    if db.engine.name != 'sqlite':
+        pytest.skip("backups are of SQLite databases")
+
+    m1 = room.add_post(user, b'one', pad64('sig1'))
+    m2 = room.add_post(user2, b'two', pad64('sig2'))
+    room.delete_posts([m2['id']], mod)
+    room.add_reaction(user2, m1['id'], '🍆')
+    room.pin(m1['id'], admin)
+
+    src = str(tmp_path / 'sogs.db')
+    dest = str(tmp_path / 'backup')
+    with config_override(STORAGE_BACKEND='local', UPLOAD_PATH=str(tmp_path / 'uploads')):
+        file_id = room.upload_file(b'abc', user, filename='abc.txt', lifetime=None)
+        before = room.get_messages_for(user, sequence=0)
+        seqno = Room(token=room.token).message_sequence
+        _copy_test_db(src)
+        with config_override(DB_URL=f'sqlite:///{src}'):
+            backup.create(dest)
+
+    room.delete()
+    db.query("DELETE FROM files")
+
+    with config_override(STORAGE_BACKEND='local', UPLOAD_PATH=str(tmp_path / 'uploads2')):
+        result = backup.import_backup(dest, keep_ids=True)
+        assert result['messages'] == 2 and result['files'] == 1
+
+        r = Room(token=room.token)
+        assert r.message_sequence == seqno
+        # Clients polling from any seqno see exactly what they would have seen before:
+        assert r.get_messages_for(user, sequence=0) == before
+        assert [p['id'] for p in r.pinned_messages] == [m1['id']]
+        assert File(id=file_id).read() == b'abc'
+
+        # Keeping ids fails if they are already in use:
+        r.delete()
+        with pytest.raises(AlreadyExists):
+            backup.import_backup(dest, keep_ids=True)
+        assert db.query("SELECT COUNT(*) FROM rooms").first()[0] == 0
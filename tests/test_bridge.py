@@ -0,0 +1,136 @@
+from request import sogs_get, sogs_post
+from util import pad64, config_override
+from sogs import utils
+from sogs.model.file import File
+
+
+def bridge_post(client, user, **kwargs):
+    return sogs_post(
+        client,
+        "/bridge/room/test-room/message",
+        {
+            "data": utils.encode_base64(b'bridged data'),
+            "signature": utils.encode_base64(pad64(b'bridge sig')),
+            **kwargs,
+        },
+        user,
+    )
+
+
+def test_bridge_post(client, room, user, user2, no_rate_limit):
+    with config_override(BRIDGE_IDS={user.session_id}):
+        r = bridge_post(client, user2, network="matrix", external_id="$abc")
+        assert r.status_code == 403
+
+        r = bridge_post(client, user, network="matrix")
+        assert r.status_code == 400
+
+        r = bridge_post(
+            client,
+            user,
+            network="matrix",
+            external_id="$abc",
+            external_user="@alice:example.org",
+            display_name="Alice",
+        )
+        assert r.status_code == 201
+        msg_id = r.json['id']
+        assert r.json['session_id'] == user.session_id
+        assert r.json['bridged'] == {
+            'network': 'matrix',
+            'external_id': '$abc',
+            'external_user': '@alice:example.org',
+            'display_name': 'Alice',
+        }
+
+        # Retrying the same external message is refused:
+        r = bridge_post(client, user, network="matrix", external_id="$abc")
+        assert r.status_code == 409
+        assert r.json == {'id': msg_id}
+
+        room.add_post(user2, b'native data', pad64(b'native sig'))
+
+        r = sogs_get(client, "/bridge/room/test-room/events/since/0", user)
+        assert r.status_code == 200
+        assert [m['id'] for m in r.json] == [msg_id, msg_id + 1]
+        assert r.json[0]['bridged']['display_name'] == 'Alice'
+        assert r.json[0]['event_id'] == f"test-room:{msg_id}:{r.json[0]['seqno']}"
+        assert 'bridged' not in r.json[1]
+        assert r.json[1]['event_id'] == f"test-room:{msg_id + 1}:{r.json[1]['seqno']}"
+
+        assert sogs_get(client, "/bridge/room/test-room/events/since/0", user2).status_code == 403
+
+
+
+def test_bridge_post_race(client, room, user, user2, monkeypatch, no_rate_limit):
+    import sogs.model.room
+    from sogs import db, web
+    from sogs.model.room import Room
+
+    notified = []
+
+    def send_mule(*args):
+        # Only notified once the post is committed:
+        assert not web.appdb.in_transaction()
+        notified.append(args)
+
+    monkeypatch.setattr(sogs.model.room, 'send_mule', send_mule)
+
+    with config_override(BRIDGE_IDS={user.session_id}):
+        r = bridge_post(client, user, network="matrix", external_id="$abc")
+        assert r.status_code == 201
+        assert notified == [("message_posted", r.json['id'])]
+
+        # A concurrent request recording the same external message after we checked for it makes
+        # our post fail, without leaving it behind or notifying anyone about it:
+        other = room.add_post(user2, b'other data', pad64(b'other sig'))
+        check_signature = Room._check_signature
+
+        def racing_check_signature(self, *args):
+            db.query(
+                """
+                INSERT INTO bridged_messages (message, network, external_id)
+                VALUES (:m, 'matrix', '$race')
+                """,
+                m=other['id'],
+            )
+            return check_signature(self, *args)
+
+        monkeypatch.setattr(Room, '_check_signature', racing_check_signature)
+        notified.clear()
+        r = bridge_post(client, user, network="matrix", external_id="$race")
+        assert r.status_code == 409
+        assert r.json == {'id': other['id']}
+        assert notified == []
+        assert [m['id'] for m in room.get_messages_for(user, recent=True)] == [
+            other['id'],
+            other['id'] - 1,
+        ]
+
+def test_bridge_media(client, room, user, monkeypatch):
+    import sogs.routes.bridge
+
+    fetched = []
+
+    def fake_fetch(url):
+        fetched.append(url)
+        return b'remote file contents'
+
+    monkeypatch.setattr(sogs.routes.bridge, 'fetch_media', fake_fetch)
+
+    url = "/bridge/room/test-room/file"
+    media = "https://matrix.example.org/_matrix/media/r0/download/example.org/cat%20pic.png"
+
+    assert sogs_post(client, url, {"url": media}, user).status_code == 403
+
+    with config_override(BRIDGE_IDS={user.session_id}):
+        assert sogs_post(client, url, {"url": "file:///etc/passwd"}, user).status_code == 400
+        assert sogs_post(client, url, {}, user).status_code == 400
+        assert not fetched
+
+        r = sogs_post(client, url, {"url": media}, user)
+        assert r.status_code == 201
+        assert fetched == [media]
+        f = File(id=r.json['id'])
+        assert f.filename == 'cat pic.png'
+        assert f.read() == b'remote file contents'
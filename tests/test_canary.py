@@ -0,0 +1,80 @@
+from sogs import canary, metrics
+from sogs.web import app
+from flask import g, jsonify
+from util import config_override
+
+
+def test_rollout_assignment():
+    clients = [f'client{i}' for i in range(1000)]
+    with config_override(CANARY_ROLLOUTS={'t': 25}):
+        canaried = {c for c in clients if canary.in_rollout('t', c)}
+        assert 150 < len(canaried) < 350
+        assert not canary.in_rollout('other', clients[0])
+    # Raising the percentage only adds clients:
+    with config_override(CANARY_ROLLOUTS={'t': 50}):
+        assert canaried < {c for c in clients if canary.in_rollout('t', c)}
+    with config_override(CANARY_ROLLOUTS={'t': 100}):
+        assert all(canary.in_rollout('t', c) for c in clients)
+    with config_override(CANARY_ROLLOUTS={'t': 0}):
+        assert not any(canary.in_rollout('t', c) for c in clients)
+
+
+def test_rollout(client):
+    calls = []
+    result = {'x': 1}
+
+    @canary.rollout('test')
+    def existing():
+        calls.append('existing')
+        return jsonify({'x': 1})
+
+    @canary.candidate('test')
+    def new():
+        calls.append('new')
+        if result is None:
+            raise RuntimeError("oops")
+        return jsonify(result)
+
+    with app.test_request_context('/test'):
+        g.user = None
+        assert existing().json == {'x': 1}
+        assert calls == ['existing']
+
+        with config_override(CANARY_ROLLOUTS={'test': 100}):
+            calls.clear()
+            assert existing().json == {'x': 1}
+            assert calls == ['new']
+
+            with config_override(CANARY_COMPARE=True):
+                mismatches = metrics.counter('canary.test.mismatch')
+                calls.clear()
+                assert existing().json == {'x': 1}
+                assert calls == ['new', 'existing']
+                assert metrics.counter('canary.test.mismatch') == mismatches
+
+                result = {'x': 2}
+                assert existing().json == {'x': 2}
+                assert metrics.counter('canary.test.mismatch') == mismatches + 1
+
+            # A failing candidate falls back to the existing handler:
+            result = None
+            errors = metrics.counter('canary.test.error')
+            calls.clear()
+            assert existing().json == {'x': 1}
+            assert calls == ['new', 'existing']
+            assert metrics.counter('canary.test.error') == errors + 1
+
+
+def test_rollout_endpoint(client, room, user, monkeypatch):
+    from request import sogs_get
+    from util import pad64
+
+    room.add_post(user, b'hello', pad64(b'sig'))
+    monkeypatch.setitem(canary._candidates, 'messages_recent', lambda room: jsonify(['new']))
+
+    url = '/room/test-room/messages/recent'
+    assert len(sogs_get(client, url, user).json) == 1
+    with config_override(CANARY_ROLLOUTS={'messages_recent': 100}):
+        assert sogs_get(client, url, user).json == ['new']
+        # Other endpoints are unaffected:
+        assert len(sogs_get(client, '/room/test-room/messages/since/0', user).json) == 1
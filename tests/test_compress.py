@@ -0,0 +1,80 @@
+import pytest
+from sogs import compress
+from sogs.db import query
+from util import pad64, config_override
+
+pytest.importorskip('zstandard')
+
+
+def sample_post(i):
+    return (
+        f"Message {i} from the test room: the quick brown fox jumps over the lazy dog while "
+        f"everyone in the room discusses release {i % 7} and the upcoming meetup."
+    ).encode()
+
+
+def count_messages(room, where):
+    return query(f"SELECT COUNT(*) FROM messages WHERE room = :r AND {where}", r=room.id).first()[0]
+
+
+def test_compress_room(room, room2, user, no_rate_limit):
+    ids = [room.add_post(user, sample_post(i), pad64(f"sig {i}"))['id'] for i in range(200)]
+    small = room2.add_post(user, sample_post(0), pad64("sig small"))['id']
+
+    room.edit_post(user, ids[0], b'edited message', pad64("sig edited"))
+    seqnos = dict(query("SELECT id, seqno FROM messages WHERE room = :r", r=room.id))
+
+    # Not enough messages to train a dictionary:
+    assert compress.compress_room(room2.id) is None
+
+    stats = compress.compress_room(room.id)
+    assert stats['messages'] == 200
+    assert stats['after'] < stats['before']
+
+    # Recompression isn't an edit:
+    assert dict(query("SELECT id, seqno FROM messages WHERE room = :r", r=room.id)) == seqnos
+    assert count_messages(room, "edited IS NOT NULL") == 1
+    assert count_messages(room, "data_dict IS NOT NULL") >= 199
+    assert query("SELECT data_dict FROM messages WHERE id = :m", m=small).first()[0] is None
+
+    msgs = room.get_messages_for(user, recent=True, limit=256)
+    assert {m['id']: m['data'] for m in msgs} == {
+        **{ids[i]: sample_post(i) for i in range(1, 200)},
+        ids[0]: b'edited message',
+    }
+    assert [h['data'] for h in room.message_history(user, ids[0])] == [sample_post(0)]
+
+    # New posts and edits are compressed only when enabled:
+    new = room.add_post(user, sample_post(1000), pad64("sig new"))['id']
+    assert query("SELECT data_dict FROM messages WHERE id = :m", m=new).first()[0] is None
+    with config_override(MESSAGE_COMPRESSION=True):
+        new2 = room.add_post(user, sample_post(1001), pad64("sig new2"))['id']
+        room.edit_post(user, ids[1], sample_post(2000), pad64("sig edit 2"))
+    dict_id = compress.room_dict(room.id)
+    assert query("SELECT data_dict FROM messages WHERE id = :m", m=new2).first()[0] == dict_id
+    assert query("SELECT data_dict FROM messages WHERE id = :m", m=ids[1]).first()[0] == dict_id
+
+    assert room.get_messages_for(user, single=new2)[0]['data'] == sample_post(1001)
+    assert room.get_messages_for(user, single=ids[1])[0]['data'] == sample_post(2000)
+    assert [h['data'] for h in room.message_history(user, ids[1])] == [sample_post(1)]
+
+    # Recompressing again replaces the no-longer-used dictionary:
+    assert compress.compress_room(room.id)['messages'] == 202
+    assert compress.room_dict(room.id) != dict_id
+    assert [
+        d for (d,) in query("SELECT id FROM message_dicts WHERE room = :r", r=room.id)
+    ] == [compress.room_dict(room.id)]
+    assert room.get_messages_for(user, single=ids[1])[0]['data'] == sample_post(2000)
+
+
+def test_compressed_room_root(room, user, no_rate_limit):
+    from sogs import journal
+
+    for i in range(200):
+        room.add_post(user, sample_post(i), pad64(f"sig {i}"))
+    root = journal.room_root(room)
+
+    # The journal's room checkpoints don't depend on whether messages are stored compressed:
+    assert compress.compress_room(room.id)['messages'] == 200
+    assert count_messages(room, "data_dict IS NOT NULL") > 0
+    assert journal.room_root(room) == root
@@ -0,0 +1,56 @@
+import time
+from util import config_override
+
+
+def test_pool_stats(db):
+    # The db fixture holds a connection (web.appdb) for the duration of the test:
+    before = db.pool_stats()
+    assert before['checked_out'] >= 1
+    assert before['checkouts'] >= 1
+
+    conn = db.get_conn()
+    stats = db.pool_stats()
+    assert stats['checkout_wait']['count'] == before['checkout_wait']['count'] + 1
+    conn.close()
+
+    assert db.check_held_connections() == 0
+
+    with config_override(DB_CONN_HOLD_WARNING=0.01):
+        time.sleep(0.02)
+        assert db.check_held_connections() >= 1
+        long_held = db.pool_stats()['long_held']
+        assert long_held > before['long_held']
+        assert db.pool_stats()['longest_held'] >= 0.02
+
+        # We only warn about (and count) each held connection once:
+        db.check_held_connections()
+        assert db.pool_stats()['long_held'] == long_held
+
+
+def test_query_timeout(client, db):
+    from sogs.web import app
+    import pytest
+
+    slow = """
+        WITH RECURSIVE r(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM r WHERE i < 100000000)
+        SELECT COUNT(*) FROM r
+        """
+
+    with config_override(DB_QUERY_TIMEOUT=0.1):
+        # Not limited outside of a request:
+        assert db.query("SELECT 42").first()[0] == 42
+
+        with app.test_request_context():
+            before = db.metrics.counter('db.query_timeouts')
+            start = time.time()
+            with pytest.raises(db.QueryTimeout):
+                db.query(slow).first()
+            assert time.time() - start < 5
+            assert db.metrics.counter('db.query_timeouts') == before + 1
+
+            # The connection is still usable afterwards:
+            assert db.query("SELECT 42").first()[0] == 42
+
+    with config_override(DB_QUERY_TIMEOUT=0):
+        with app.test_request_context():
+            assert db.query("SELECT 42").first()[0] == 42
@@ -0,0 +1,81 @@
+import base64
+import json
+import pytest
+import urllib.request
+from util import config_override
+from sogs import crypto, directory
+from nacl.signing import VerifyKey
+
+
+def test_directory_announce(client, room, room2, monkeypatch):
+    sent = []
+
+    class FakeResponse:
+        def __enter__(self):
+            return self
+
+        def __exit__(self, *args):
+            pass
+
+        def read(self, limit=-1):
+            return b''
+
+    def fake_urlopen(req, timeout):
+        sent.append(req)
+        return FakeResponse()
+
+    monkeypatch.setattr(urllib.request, 'urlopen', fake_urlopen)
+
+    # Not configured, so nothing is sent:
+    assert directory.announce() == 0
+    assert sent == []
+
+    room.tags = ['testing']
+    room2.default_read = False
+
+    with config_override(
+        DIRECTORY_URL='https://directory.example/announce', URL_BASE='https://sogs.example'
+    ):
+        assert directory.announce() == 1
+    assert len(sent) == 1
+    req = sent[0]
+    assert req.full_url == 'https://directory.example/announce'
+    assert req.get_method() == 'POST'
+
+    pubkey = req.get_header(directory.PUBKEY_HEADER.capitalize())
+    assert pubkey == crypto.server_verifykey.encode().hex()
+    sig = base64.b64decode(req.get_header(directory.SIGNATURE_HEADER.capitalize()))
+    VerifyKey(bytes.fromhex(pubkey)).verify(req.data, sig)
+
+    body = json.loads(req.data)
+    assert body['server'] == {
+        'url': 'https://sogs.example',
+        'pubkey': crypto.server_pubkey_hex,
+        'ed25519_pubkey': pubkey,
+    }
+    assert body['rooms'] == [
+        {
+            'token': 'test-room',
+            'name': 'Test room',
+            'description': 'Test suite testing room',
+            'active_users': 0,
+            'tags': ['testing'],
+            'join_url': f'https://sogs.example/test-room?public_key={crypto.server_pubkey_hex}',
+        }
+    ]
+
+    # Rooms can opt out of the directory:
+    with config_override(
+        DIRECTORY_URL='https://directory.example/announce',
+        ROOM_OVERRIDES={'test-room': {'directory': False}},
+    ):
+        assert directory.announce() == 0
+    assert json.loads(sent[1].data)['rooms'] == []
+
+    def broken_urlopen(req, timeout):
+        raise OSError("connection refused")
+
+    monkeypatch.setattr(urllib.request, 'urlopen', broken_urlopen)
+    with config_override(DIRECTORY_URL='https://directory.example/announce'):
+        with pytest.raises(directory.AnnounceFailed):
+            directory.announce()
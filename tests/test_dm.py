@@ -1,11 +1,11 @@
-from request import sogs_get, sogs_post, sogs_delete
+from request import sogs_get, sogs_post, sogs_put, sogs_delete
 from sogs import config
 from sogs.hashing import blake2b
 from sogs.utils import encode_base64
 from sogs.model.user import SystemUser
 import nacl.bindings as sodium
 from nacl.utils import random
-from util import from_now
+from util import config_override, from_now
 from itertools import product
 
 
@@ -129,3 +129,68 @@ def test_dm_delete(client, blind_user, blind_user2):
         assert r.status_code == 200
         posts = r.json
         assert posts == []
+
+
+def test_dm_inbox_limits(client, blind_user, blind_user2):
+    url = f'/inbox/{blind_user2.session_id}'
+
+    def send():
+        post = make_post(b'bep', sender=blind_user, to=blind_user2)
+        return sogs_post(client, url, post, blind_user)
+
+    with config_override(DM_INBOX_MAX_MESSAGES=2, DM_INBOX_MAX_BYTES=None):
+        assert send().status_code == 201
+        assert send().status_code == 201
+        r = send()
+        assert r.status_code == 429
+        assert r.json['scope'] == 'inbox_messages'
+        # Retrying is possible once the oldest message expires:
+        assert r.json['reset'] == from_now.seconds(config.DM_EXPIRY)
+        assert 'Retry-After' in r.headers
+
+        # Emptying the inbox makes room again:
+        assert sogs_delete(client, '/inbox', blind_user2).json == {'deleted': 2}
+        assert send().status_code == 201
+
+    # Each message is 76 bytes (version byte, 3-byte message + 32-byte pubkey + 16-byte MAC, and
+    # 24-byte nonce):
+    with config_override(DM_INBOX_MAX_MESSAGES=None, DM_INBOX_MAX_BYTES=200):
+        assert send().status_code == 201
+        r = send()
+        assert r.status_code == 507
+        assert (r.json['scope'], r.json['limit'], r.json['used']) == ('inbox_storage', 200, 152)
+
+    assert len(sogs_get(client, '/inbox', blind_user2).json) == 2
+
+
+def test_dm_blocks(client, blind_user, blind_user2):
+    url = f'/inbox/{blind_user2.session_id}'
+    blocks = '/inbox/blocks'
+
+    def send():
+        post = make_post(b'bep', sender=blind_user, to=blind_user2)
+        return sogs_post(client, url, post, blind_user)
+
+    assert sogs_get(client, blocks, blind_user2).json == []
+    r = sogs_put(client, f'{blocks}/{blind_user.session_id}', {}, blind_user2)
+    assert r.json == {'blocked': True}
+    r = sogs_put(client, f'{blocks}/{blind_user.session_id}', {}, blind_user2)
+    assert r.json == {'blocked': False}
+    r = sogs_get(client, blocks, blind_user2)
+    assert r.json == [{'session_id': blind_user.session_id, 'blocked_at': from_now.now()}]
+
+    assert send().status_code == 403
+    with config_override(DM_BLOCKED_SENDS='drop'):
+        r = send()
+        assert r.status_code == 201
+        assert 'id' not in r.json
+        assert r.json['recipient'] == blind_user2.session_id
+    assert sogs_get(client, '/inbox', blind_user2).json == []
+
+    r = sogs_delete(client, f'{blocks}/{blind_user.session_id}', blind_user2)
+    assert r.json == {'unblocked': True}
+    assert sogs_delete(client, f'{blocks}/{blind_user.session_id}', blind_user2).json == {
+        'unblocked': False
+    }
+    assert send().status_code == 201
+    assert len(sogs_get(client, '/inbox', blind_user2).json) == 1
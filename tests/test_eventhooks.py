@@ -0,0 +1,102 @@
+import json
+import time
+from sogs import eventhooks, outbound, utils
+from sogs.db import query
+from util import pad64, config_override
+
+URL = 'https://hooks.example/sogs'
+
+
+def queued():
+    return query(
+        "SELECT url, body, attempts, next_attempt FROM event_deliveries ORDER BY id"
+    ).fetchall()
+
+
+def test_event_hooks(room, user, user2, mod, monkeypatch):
+    sent = []
+
+    def fake_fetch(req, *, timeout, limit=-1, public_only=False):
+        sent.append(req)
+        return b''
+
+    monkeypatch.setattr(outbound, 'fetch', fake_fetch)
+
+    # Nothing is queued unless configured:
+    room.add_post(user, b'not delivered', pad64('sig 0'))
+    assert queued() == []
+
+    with config_override(EVENT_HOOK_URLS=[URL], EVENT_HOOK_SECRET='hunter2'):
+        msg = room.add_post(user, b'hello', pad64('sig 1'))
+        room.edit_post(user, msg['id'], b'edited', pad64('sig 2'))  # Not a default event
+        room.delete_posts([msg['id']], user)
+        room.ban_user(user2, mod=mod)
+
+        events = [json.loads(r['body']) for r in queued()]
+        assert [e['event'] for e in events] == ['message_posted', 'messages_deleted', 'user_banned']
+        assert events[0]['id'] == msg['id']
+        assert utils.decode_base64(events[0]['data']) == b'hello'
+        assert events[1]['ids'] == [msg['id']]
+        assert events[2]['session_id'] == user2.session_id
+        assert events[2]['by'] == mod.session_id
+
+        assert eventhooks.deliver() == 3
+        assert queued() == []
+        assert [json.loads(r.data) for r in sent] == events
+        for r in sent:
+            assert r.full_url == URL
+            assert r.get_method() == 'POST'
+            assert r.get_header('X-sogs-signature') == eventhooks.signature(
+                r.get_header('X-sogs-timestamp'), r.data
+            )
+
+        # Failed deliveries are retried later, and eventually dropped:
+        def failing_fetch(req, **kwargs):
+            sent.append(req)
+            raise outbound.OutboundError("nope")
+
+        monkeypatch.setattr(outbound, 'fetch', failing_fetch)
+        sent.clear()
+        with config_override(EVENT_HOOK_EVENTS={'*'}, EVENT_HOOK_MAX_ATTEMPTS=2):
+            room.unban_user(user2, mod=mod)
+            assert eventhooks.deliver() == 0
+            [(_, _, attempts, next_attempt)] = queued()
+            assert attempts == 1
+            assert next_attempt > time.time()
+
+            # Not due yet:
+            assert eventhooks.deliver() == 0
+            assert len(sent) == 1
+
+            query("UPDATE event_deliveries SET next_attempt = 0")
+            assert eventhooks.deliver() == 0
+            assert len(sent) == 2
+            assert queued() == []
+
+
+def test_event_hooks_failing_receiver(room, user, monkeypatch, no_rate_limit):
+    bad = 'https://broken.example/sogs'
+    sent = []
+
+    def fetch(req, **kwargs):
+        sent.append(req.full_url)
+        if req.full_url == bad:
+            raise outbound.CircuitOpen("broken.example")
+        return b''
+
+    monkeypatch.setattr(outbound, 'fetch', fetch)
+
+    with config_override(EVENT_HOOK_URLS=[bad, URL], EVENT_HOOK_EVENTS={'*'}):
+        with config_override(EVENT_HOOK_URLS=[bad]):
+            for i in range(eventhooks.BATCH_SIZE):
+                room.add_post(user, f'backlog {i}'.encode(), pad64(f'sig {i}'))
+        room.add_post(user, b'hello', pad64('sig'))
+
+        # The first run only gets to the broken receiver's backlog, but holds it back, so that the
+        # next run gets to the working receiver:
+        assert eventhooks.deliver() == 0
+        assert sent == [bad]
+        assert eventhooks.deliver() == 1
+        assert sent == [bad, URL]
+        assert all(r['url'] == bad and r['next_attempt'] > time.time() for r in queued())
+        assert len(queued()) == eventhooks.BATCH_SIZE + 1
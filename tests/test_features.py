@@ -0,0 +1,51 @@
+from request import sogs_get, sogs_put, sogs_delete
+from util import config_override, pad64
+
+
+def test_feature_flags(client, room, room2, user, admin, global_admin):
+    url = '/admin/features'
+    assert 'reactions' in client.get('/capabilities').json['capabilities']
+    m1 = room.add_post(user, b'hello', pad64('sig 1'))['id']
+    m2 = room2.add_post(user, b'hi', pad64('sig 2'))['id']
+    react1 = f'/room/{room.token}/reaction/{m1}/🍍'
+    react2 = f'/room/{room2.token}/reaction/{m2}/🍍'
+
+    assert sogs_put(client, f'{url}/reactions', {'enabled': False}, admin).status_code == 403
+    assert sogs_put(client, f'{url}/reactions', {'enabled': 0}, global_admin).status_code == 400
+    assert sogs_put(client, f'{url}/magic', {'enabled': False}, global_admin).status_code == 400
+    r = sogs_put(client, f'{url}/dms', {'enabled': False, 'room': room.token}, global_admin)
+    assert r.status_code == 400
+    r = sogs_put(client, f'{url}/reactions', {'enabled': False, 'room': 'nope'}, global_admin)
+    assert r.status_code == 404
+
+    # Disabled server-wide, but re-enabled in one room:
+    assert sogs_put(client, f'{url}/reactions', {'enabled': False}, global_admin).status_code == 200
+    r = sogs_put(client, f'{url}/reactions', {'enabled': True, 'room': room.token}, global_admin)
+    assert r.status_code == 200
+    assert 'reactions' not in client.get('/capabilities').json['capabilities']
+    assert sogs_put(client, react1, {}, user).status_code == 200
+    assert sogs_put(client, react2, {}, user).status_code == 404
+    assert 'disabled_features' not in sogs_get(client, f'/room/{room.token}', user).json
+    assert sogs_get(client, f'/room/{room2.token}', user).json['disabled_features'] == ['reactions']
+
+    r = sogs_get(client, url, global_admin)
+    assert r.json['features']['reactions'] is False and r.json['features']['dms'] is True
+    assert [(f['feature'], f['room'], f['enabled']) for f in r.json['flags']] == [
+        ('reactions', None, False),
+        ('reactions', room.token, True),
+    ]
+    assert r.json['flags'][0]['updated_by'] == global_admin.session_id
+
+    assert sogs_delete(client, f'{url}/reactions', global_admin).status_code == 200
+    assert sogs_put(client, react2, {}, user).status_code == 200
+    assert 'reactions' in client.get('/capabilities').json['capabilities']
+
+    # Config settings, which runtime flags override:
+    with config_override(FEATURES_DISABLED={'dms'}):
+        assert sogs_get(client, '/inbox', user).status_code == 404
+        assert sogs_put(client, f'{url}/dms', {'enabled': True}, global_admin).status_code == 200
+        assert sogs_get(client, '/inbox', user).status_code != 404
+    with config_override(ROOM_OVERRIDES={room2.token: {'disabled_features': {'reactions'}}}):
+        assert sogs_put(client, react2, {}, user).status_code == 404
+        assert sogs_put(client, react1, {}, user).status_code == 200
+        assert sogs_delete(client, f'{url}/reactions?room={room.token}', global_admin).json == {}
@@ -1,5 +1,6 @@
 from request import sogs_get, sogs_post, sogs_put, sogs_post_raw, sogs_delete
 from util import config_override, from_now, pad64
+from auth import x_sogs_for
 from sogs.model.file import File
 import sogs.model.exc
 from sogs import utils
@@ -11,6 +12,7 @@ from nacl.utils import random
 from random import Random
 import pytest
 import re
+import time
 
 
 def _make_file_upload(filename):
@@ -175,7 +177,8 @@ def _file_upload(client, room, user, *, unsafe=False, utf=False, filename):
     r = sogs_get(client, f'/room/{room.token}/file/{id}', user)
     assert r.status_code == 200
     assert r.data == file_content
-    expected = ('attachment', {'filename': filename.replace('\0', '\ufffd').replace('/', '\ufffd')})
+    sanitized = utils.sanitize_filename(filename)
+    expected = ('attachment', {'filename': sanitized} if sanitized else {})
     assert parse_options_header(r.headers.get('content-disposition')) == expected
     f = File(id=id)
     if unsafe or utf:
@@ -224,6 +227,105 @@ def test_file_upload_emoji_unsafe(client, room, user):
     _file_upload(client, room, user, filename='%00🎉.🎉---../../../asd', unsafe=True, utf=True)
 
 
+def test_sanitize_filename():
+    assert utils.sanitize_filename('normal.txt') == 'normal.txt'
+    assert utils.sanitize_filename('🎉 party.txt') == '🎉 party.txt'
+    assert utils.sanitize_filename('a/b\0c\nd') == 'a\ufffdb\ufffdc\ufffdd'
+    # Right-to-left override, which would display as "invoiceexe.txt":
+    assert utils.sanitize_filename('invoice\u202etxt.exe') == 'invoice\ufffdtxt.exe'
+    assert utils.sanitize_filename(' . .bashrc ') == 'bashrc'
+    assert utils.sanitize_filename(' ... ') is None
+    long = utils.sanitize_filename('x' * 500 + '.jpeg')
+    assert len(long) == utils.FILENAME_MAX_LENGTH
+    assert long.endswith('x.jpeg')
+
+
+def test_file_content_types(client, room, user):
+    url_post = f"/room/{room.token}/file"
+    png = random(1024)
+    r = sogs_post_raw(
+        client,
+        url_post,
+        png,
+        user,
+        ctype='image/png',
+        extra_headers={"Content-Disposition": ('attachment', {'filename': 'pic.png'})},
+    )
+    assert r.status_code == 201
+    id = r.json['id']
+    assert File(id=id).content_type == 'image/png'
+
+    def download():
+        r = sogs_get(client, f'/room/{room.token}/file/{id}', user)
+        assert r.status_code == 200
+        assert r.data == png
+        assert r.headers['X-Content-Type-Options'] == 'nosniff'
+        assert 'sandbox' in r.headers['Content-Security-Policy']
+        return r.content_type, parse_options_header(r.headers['Content-Disposition'])
+
+    # By default everything is served as an octet-stream attachment:
+    assert download() == ('application/octet-stream', ('attachment', {'filename': 'pic.png'}))
+
+    with config_override(FILES_INLINE_TYPES={'image/png'}):
+        assert download() == ('image/png', ('inline', {'filename': 'pic.png'}))
+    with config_override(FILES_INLINE_TYPES={'image/jpeg'}):
+        assert download() == ('application/octet-stream', ('attachment', {'filename': 'pic.png'}))
+
+
+def test_file_content_host(client, room, user, user2, mod):
+    import urllib.parse
+
+    filedata, fheaders = _make_file_upload('doc.pdf')
+    r = sogs_post_raw(client, f'/room/{room.token}/file', filedata, user, extra_headers=fheaders)
+    assert r.status_code == 201
+    url = f"/room/{room.token}/file/{r.json['id']}"
+
+    def get(url, **kwargs):
+        return client.get(url, headers=x_sogs_for(user, "GET", url), **kwargs)
+
+    # Only readable by `user`, so downloads have to be authenticated:
+    room.default_read = False
+    room.set_permissions(user, mod=mod, read=True)
+
+    content_host = 'https://files.example.net'
+    with config_override(FILES_CONTENT_URL=content_host + '/'):
+        # Downloads from the main address are redirected to the content address, with a token
+        # standing in for the request signature (whose nonce has been used up):
+        headers = x_sogs_for(user, "GET", url)
+        r = client.get(url, headers=headers)
+        assert r.status_code == 302
+        loc = urllib.parse.urlsplit(r.headers['Location'])
+        assert f"{loc.scheme}://{loc.netloc}{loc.path}" == f'{content_host}{url}'
+        query = urllib.parse.parse_qs(loc.query)
+        assert list(query) == ['content_token']
+
+        # Following the redirect re-sends the original headers, which is fine:
+        r = client.get(url, headers=headers, query_string=loc.query, base_url=content_host)
+        assert r.status_code == 200
+        assert r.data == filedata
+
+        # The token is only good for that file:
+        token = query['content_token'][0]
+        r = sogs_post_raw(
+            client, f'/room/{room.token}/file', filedata, user, extra_headers=fheaders
+        )
+        assert r.status_code == 201
+        other = f"/room/{room.token}/file/{r.json['id']}"
+        assert client.get(other, query_string=loc.query, base_url=content_host).status_code == 401
+        forged = token.replace(user.session_id, user2.session_id)
+        r = client.get(url, query_string={'content_token': forged}, base_url=content_host)
+        assert r.status_code == 401
+
+        # Signed requests can also be made to the content address directly:
+        r = get(url, base_url=content_host)
+        assert r.status_code == 200
+        assert r.data == filedata
+
+        # The content address serves nothing else:
+        assert get(f"/room/{room.token}").status_code == 200
+        assert get(f"/room/{room.token}", base_url=content_host).status_code == 404
+
+
 def test_file_upload_banned_user(client, room, banned_user):
     url_post = f"/room/{room.token}/file"
     r = sogs_post_raw(client, url_post, random(1024), banned_user)
@@ -308,3 +410,293 @@ def test_file_unexpiring(client, room, user):
         f = File(id=f.id)
         assert f.post_id == post_id
         assert f.expiry is None
+
+
+class FakeS3:
+    class exceptions:
+        class NoSuchKey(Exception):
+            pass
+
+    def __init__(self):
+        self.objects = {}
+
+    def upload_fileobj(self, f, bucket, key, ExtraArgs=None):
+        self.objects[(bucket, key)] = f.read()
+
+    def get_object(self, Bucket, Key):
+        from io import BytesIO
+
+        if (Bucket, Key) not in self.objects:
+            raise self.exceptions.NoSuchKey()
+        return {'Body': BytesIO(self.objects[(Bucket, Key)])}
+
+    def delete_object(self, Bucket, Key):
+        self.objects.pop((Bucket, Key), None)
+
+    def generate_presigned_url(self, method, Params, ExpiresIn):
+        return f"https://s3.example.com/{Params['Bucket']}/{Params['Key']}?expires={ExpiresIn}"
+
+
+def test_file_s3_storage(client, room, user, monkeypatch):
+    import sogs.storage
+
+    s3 = sogs.storage.S3Storage()
+    s3._client = FakeS3()
+    monkeypatch.setattr(sogs.storage, '_store', s3)
+
+    with config_override(STORAGE_BACKEND='s3', S3_BUCKET='uploads', S3_PREFIX='sogs/'):
+        filedata, headers = _make_file_upload('s3.txt')
+        r = sogs_post_raw(client, f'/room/{room.token}/file', filedata, user, extra_headers=headers)
+        assert r.status_code == 201
+        id = r.json['id']
+
+        f = File(id=id)
+        assert f.path == f'sogs/{room.token}/{id}_s3.txt'
+        assert s3.client.objects == {('uploads', f.path): filedata}
+
+        r = sogs_get(client, f'/room/{room.token}/file/{id}', user)
+        assert r.status_code == 200
+        assert r.data == filedata
+
+        with config_override(S3_PRESIGN=True, S3_PRESIGN_EXPIRY=60):
+            r = sogs_get(client, f'/room/{room.token}/file/{id}', user)
+            assert r.status_code == 302
+            assert r.headers['Location'] == f"https://s3.example.com/uploads/{f.path}?expires=60"
+
+        f.set_expiry(-1)
+        from sogs.cleanup import prune_files
+
+        assert prune_files() == 1
+        assert s3.client.objects == {}
+
+
+def test_file_egress(client, room, room2, user, mod):
+    filedata, headers = _make_file_upload('big.bin')
+    r = sogs_post_raw(client, f'/room/{room.token}/file', filedata, user, extra_headers=headers)
+    assert r.status_code == 201
+    id = r.json['id']
+    url = f'/room/{room.token}/file/{id}'
+
+    for _ in range(2):
+        assert sogs_get(client, url, user).status_code == 200
+
+    assert room.egress_used() == 2048
+    assert room2.egress_used() == 0
+
+    assert sogs_get(client, f'/room/{room.token}/egress', user).status_code == 403
+    r = sogs_get(client, f'/room/{room.token}/egress', mod)
+    assert r.status_code == 200
+    period = time.strftime('%Y-%m', time.gmtime())
+    assert r.json == {
+        'period': period,
+        'bytes': 2048,
+        'cap': None,
+        'history': {period: 2048},
+        'top_files': [{'id': id, 'size': 1024, 'downloads': 2, 'egress': 2048}],
+    }
+
+    with config_override(ROOM_OVERRIDES={room.token: {'egress_cap': 3000}}):
+        # Under the cap, so this one is served (putting us over):
+        assert sogs_get(client, url, user).status_code == 200
+        r = sogs_get(client, url, user)
+        assert r.status_code == 429
+        assert 0 < int(r.headers['Retry-After']) <= 31 * 86400
+        assert r.json['scope'] == 'room_egress'
+        assert (r.json['limit'], r.json['remaining']) == (3000, 0)
+        assert r.headers['RateLimit-Reset'] == r.headers['Retry-After']
+        assert room.egress_used() == 3072
+
+    with config_override(ROOM_EGRESS_CAP=3000):
+        assert sogs_get(client, url, user).status_code == 429
+
+    assert sogs_get(client, url, user).status_code == 200
+
+
+def test_inline_files(client, room, user, user2):
+    small, big = random(100), random(5000)
+    ids = []
+    for content in (small, big):
+        r = sogs_post_raw(client, f'/room/{room.token}/file', content, user)
+        assert r.status_code == 201
+        ids.append(r.json['id'])
+
+    d, s = (utils.encode_base64(x) for x in (b"stickers", pad64("sig")))
+    r = sogs_post(
+        client, f'/room/{room.token}/message', {'data': d, 'signature': s, 'files': ids}, user
+    )
+    assert r.status_code == 201
+    msg_id = r.json['id']
+    url = f'/room/{room.token}/message/{msg_id}'
+
+    # Only included when requested:
+    assert 'inline_files' not in sogs_get(client, url, user2).json
+    assert room.egress_used() == 0
+
+    # Only files under the size threshold are inlined:
+    r = sogs_get(client, url + '?inline=1', user2)
+    assert r.json['inline_files'] == [{'id': ids[0], 'data': utils.encode_base64(small)}]
+    assert room.egress_used() == 100
+
+    r = sogs_get(client, f'/room/{room.token}/messages/recent?inline=1', user2)
+    assert [f['id'] for f in r.json[0]['inline_files']] == [ids[0]]
+
+    with config_override(FILE_INLINE_MAX_SIZE=8192):
+        r = sogs_get(client, f'/room/{room.token}/messages/since/0?inline=1', user2)
+        assert [f['id'] for f in r.json[0]['inline_files']] == ids
+        assert utils.decode_base64(r.json[0]['inline_files'][1]['data']) == big
+
+    with config_override(FILE_INLINE_MAX_SIZE=0):
+        assert 'inline_files' not in sogs_get(client, url + '?inline=1', user2).json
+
+    # Not inlined once the room's egress cap is reached:
+    with config_override(ROOM_EGRESS_CAP=room.egress_used()):
+        assert 'inline_files' not in sogs_get(client, url + '?inline=1', user2).json
+
+
+def test_file_storage_cap(client, room, room2, user):
+    def upload(room):
+        filedata, headers = _make_file_upload('big.bin')
+        url = f'/room/{room.token}/file'
+        return sogs_post_raw(client, url, filedata, user, extra_headers=headers)
+
+    ids = [upload(room).json['id'] for _ in range(2)]
+    assert room.storage_used() == 2048
+
+    with config_override(ROOM_OVERRIDES={room.token: {'storage_cap': 2500}}):
+        r = upload(room)
+        assert r.status_code == 507
+        assert r.json == {
+            'error': 'Room file storage limit reached',
+            'scope': 'room_storage',
+            'limit': 2500,
+            'used': 2048,
+            'remaining': 452,
+            'reset': None,
+        }
+        assert upload(room2).status_code == 201
+
+    with config_override(SERVER_STORAGE_CAP=3500):
+        r = upload(room2)
+        assert r.status_code == 507
+        assert (r.json['scope'], r.json['used']) == ('server_storage', 3072)
+
+        # Expired files don't count:
+        File(id=ids[0]).set_expiry(-1)
+        assert upload(room2).status_code == 201
+
+
+def test_file_upload_limits(client, room, room2, user, user2):
+    url = f'/room/{room.token}/file'
+    filedata, headers = _make_file_upload('big.bin')
+
+    with config_override(UPLOAD_FILE_MAX_SIZE=1000):
+        assert sogs_post_raw(client, url, filedata, user, extra_headers=headers).status_code == 413
+
+    with config_override(UPLOAD_CONTENT_TYPES={'application/octet-stream', 'image/png'}):
+        r = sogs_post_raw(client, url, filedata, user, ctype='text/plain', extra_headers=headers)
+        assert r.status_code == 415
+        r = sogs_post_raw(client, url, filedata, user, ctype='image/png', extra_headers=headers)
+        assert r.status_code == 201
+
+    with config_override(USER_STORAGE_CAP=2500):
+        assert sogs_post_raw(client, url, filedata, user, extra_headers=headers).status_code == 201
+        # The per-user cap applies across rooms:
+        r = sogs_post_raw(
+            client, f'/room/{room2.token}/file', filedata, user, extra_headers=headers
+        )
+        assert r.status_code == 507
+        assert (r.json['scope'], r.json['limit'], r.json['used']) == ('user_storage', 2500, 2048)
+        assert sogs_post_raw(client, url, filedata, user2, extra_headers=headers).status_code == 201
+
+
+def _make_image(size=(64, 48), fmt='PNG', *, flip=False):
+    import io
+    import PIL.Image
+
+    img = PIL.Image.new('L', (64, 48))
+    img.putdata([(63 - x if flip else x) * 4 for y in range(48) for x in range(64)])
+    if size != img.size:
+        img = img.resize(size)
+    out = io.BytesIO()
+    img.save(out, format=fmt)
+    return out.getvalue()
+
+
+def test_image_ban(client, room, room2, user, user2, mod, global_admin):
+    headers = {"Content-Disposition": ('attachment', {'filename': 'meme.png'})}
+    image = _make_image()
+    r = sogs_post_raw(client, f'/room/{room.token}/file', image, user, extra_headers=headers)
+    assert r.status_code == 201
+    id = r.json['id']
+    assert File(id=id).phash is not None
+
+    url = f'/room/{room.token}/file/{id}'
+    assert sogs_post(client, f'{url}/ban', {}, user).status_code == 403
+    r = sogs_post(client, f'{url}/ban', {'reason': 'spam'}, mod)
+    assert r.status_code == 201
+    ban_id = r.json['id']
+    assert r.json['phash'] == File(id=id).phash
+
+    # The banned file is no longer served, except to mods:
+    assert sogs_get(client, url, user2).status_code == 404
+    assert sogs_get(client, url, mod).status_code == 200
+
+    # Non-images can't be banned:
+    filedata, headers2 = _make_file_upload('random.bin')
+    r = sogs_post_raw(client, f'/room/{room.token}/file', filedata, user, extra_headers=headers2)
+    r = sogs_post(client, f'/room/{room.token}/file/{r.json["id"]}/ban', {}, mod)
+    assert r.status_code == 400
+
+    # A resized and re-encoded copy is rejected, in any room:
+    copy = _make_image(size=(128, 96), fmt='JPEG')
+    r = sogs_post_raw(client, f'/room/{room2.token}/file', copy, user2, extra_headers=headers)
+    assert r.status_code == 429
+
+    # An unrelated image is fine:
+    other = _make_image(flip=True)
+    r = sogs_post_raw(client, f'/room/{room2.token}/file', other, user2, extra_headers=headers)
+    assert r.status_code == 201
+
+    with config_override(IMAGE_BAN_ACTION='quarantine'):
+        r = sogs_post_raw(client, f'/room/{room.token}/file', copy, user2, extra_headers=headers)
+        assert r.status_code == 201
+        assert File(id=r.json['id']).quarantined
+        assert sogs_get(client, f'/room/{room.token}/file/{r.json["id"]}', user).status_code == 404
+
+    assert sogs_get(client, '/admin/image_bans', mod).status_code == 403
+    r = sogs_get(client, '/admin/image_bans', global_admin)
+    assert r.status_code == 200
+    assert [(b['id'], b['room'], b['banned_by'], b['reason']) for b in r.json] == [
+        (ban_id, room.token, mod.session_id, 'spam')
+    ]
+
+    assert sogs_delete(client, f'/admin/image_ban/{ban_id}', global_admin).status_code == 200
+    assert sogs_delete(client, f'/admin/image_ban/{ban_id}', global_admin).status_code == 404
+    r = sogs_post_raw(client, f'/room/{room2.token}/file', copy, user2, extra_headers=headers)
+    assert r.status_code == 201
+
+
+def test_thumbnail(client, room, user, mod):
+    import io
+    import PIL.Image
+
+    headers = {"Content-Disposition": ('attachment', {'filename': 'big.png'})}
+    r = sogs_post_raw(
+        client, f'/room/{room.token}/file', _make_image((640, 480)), user, extra_headers=headers
+    )
+    assert r.status_code == 201
+    url = f'/room/{room.token}/thumbnail/{r.json["id"]}'
+    r = sogs_get(client, url, user)
+    assert r.status_code == 200
+    assert r.headers['Content-Type'] == 'image/jpeg'
+    assert PIL.Image.open(io.BytesIO(r.data)).size == (256, 192)
+
+    with config_override(THUMBNAIL_SIZE=0):
+        assert sogs_get(client, url, user).status_code == 404
+
+    # Non-images (such as encrypted attachments) have no thumbnail:
+    filedata, headers = _make_file_upload('random.bin')
+    r = sogs_post_raw(client, f'/room/{room.token}/file', filedata, user, extra_headers=headers)
+    assert sogs_get(client, f'/room/{room.token}/thumbnail/{r.json["id"]}', user).status_code == 404
+
+    assert sogs_get(client, f'/room/{room.token}/thumbnail/12345', user).status_code == 404
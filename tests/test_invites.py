@@ -0,0 +1,65 @@
+from request import sogs_get, sogs_post, sogs_delete
+from sogs.model.room import Room
+from util import pad64
+
+
+def test_invites(client, room, user, user2, mod, no_rate_limit):
+    room.default_accessible = False
+    room.default_read = False
+    room.default_write = False
+    room.add_post(mod, b'members only', pad64('sig'))
+
+    url = f"/room/{room.token}/invites"
+    recent = f"/room/{room.token}/messages/recent"
+    assert sogs_post(client, url, {}, user).status_code == 403
+    assert sogs_post(client, url, {'max_uses': 'lots'}, mod).status_code == 400
+
+    r = sogs_post(client, url, {'max_uses': 1}, mod)
+    assert r.status_code == 201
+    invite = r.json
+    code = invite.pop('code')
+    assert (invite['write'], invite['max_uses'], invite['uses']) == (True, 1, 0)
+    assert invite['usable']
+    assert sogs_get(client, url, mod).json == [invite]
+
+    assert sogs_get(client, recent, user).status_code == 404
+    assert sogs_post(client, "/invite", {'code': 'not-a-code'}, user).status_code == 404
+    r = sogs_post(client, "/invite", {'code': code}, user)
+    assert r.status_code == 200
+    assert r.json == {'room': room.token, 'write': True}
+    assert sogs_get(client, recent, user).status_code == 200
+    assert Room(id=room.id).check_write(user)
+
+    # Redeeming again doesn't use up another use, but the code is now used up for anyone else:
+    assert sogs_post(client, "/invite", {'code': code}, user).status_code == 200
+    assert sogs_post(client, "/invite", {'code': code}, user2).status_code == 404
+
+    r = sogs_get(client, f"/room/{room.token}/invite/{invite['id']}", mod)
+    assert (r.json['uses'], r.json['usable']) == (1, False)
+    assert [u['session_id'] for u in r.json['redeemed_by']] == [user.session_id]
+
+    # Redeeming again doesn't undo a moderator's later changes to the user's permissions:
+    room.set_permissions(user, mod=mod, write=False)
+    assert sogs_post(client, "/invite", {'code': code}, user).status_code == 200
+    assert not Room(id=room.id).check_write(user)
+    room.set_permissions(user, mod=mod, read=False)
+    assert sogs_post(client, "/invite", {'code': code}, user).status_code == 200
+    assert sogs_get(client, recent, user).status_code == 403
+
+    # Read-only, expiring codes:
+    r = sogs_post(client, url, {'write': False, 'lifetime': 3600}, mod)
+    ro_id, ro_code = r.json['id'], r.json['code']
+    assert r.json['expires'] is not None
+    assert sogs_post(client, "/invite", {'code': ro_code}, user2).json['write'] is False
+    fresh = Room(id=room.id)
+    assert fresh.check_read(user2) and not fresh.check_write(user2)
+
+    # Revocation:
+    revoke = f"/room/{room.token}/invite/{ro_id}"
+    assert sogs_delete(client, revoke, user).status_code == 403
+    assert sogs_delete(client, revoke, mod).status_code == 200
+    assert sogs_get(client, revoke, mod).json['revoked'] is not None
+    room.ban_user(user2, mod=mod)
+    assert sogs_post(client, "/invite", {'code': ro_code}, user2).status_code == 403
+    room.unban_user(user2, mod=mod)
+    assert sogs_post(client, "/invite", {'code': ro_code}, user2).status_code == 404
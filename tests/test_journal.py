@@ -0,0 +1,109 @@
+import json
+import os
+from sogs import db, journal, utils
+from sogs.model.room import Room
+from sogs.model.user import SystemUser
+from util import config_override, pad64
+
+
+def _events(path):
+    with open(path) as f:
+        return [json.loads(line) for line in f]
+
+
+def test_journal(client, room, user, user2, mod, tmp_path, no_rate_limit):
+    path = str(tmp_path / 'events.jsonl')
+
+    # Disabled by default:
+    room.add_post(user, b'not journalled', pad64('sig0'))
+
+    with config_override(JOURNAL_PATH=path):
+        msg = room.add_post(user, b'hello', pad64('sig1'))
+        room.edit_post(user, msg['id'], b'hello again', pad64('sig2'))
+        room.delete_posts([msg['id']], mod)
+        room.ban_user(user2, mod=mod, timeout=60)
+        room.unban_user(user2, mod=mod)
+        room.set_permissions(user2, mod=mod, write=False)
+
+    ev = _events(path)
+    assert [e['event'] for e in ev] == [
+        'message_posted',
+        'message_edited',
+        'messages_deleted',
+        'user_banned',
+        'user_unbanned',
+        'permissions_changed',
+    ]
+    assert all(e['room'] == room.token for e in ev)
+    assert ev[0]['id'] == msg['id']
+    assert ev[0]['session_id'] == user.session_id
+    assert utils.decode_base64(ev[0]['data']) == b'hello'
+    assert utils.decode_base64(ev[1]['signature']) == pad64('sig2')
+    assert ev[2]['ids'] == [msg['id']]
+    assert ev[2]['by'] == mod.session_id
+    assert ev[3]['session_id'] == user2.session_id
+    assert ev[3]['timeout'] == 60
+    assert ev[5]['write'] is False
+    assert ev[0]['time'] <= ev[5]['time']
+    assert not any('actor' in e for e in ev)
+
+
+def test_journal_actor(room, user, user2, tmp_path):
+    path = str(tmp_path / 'events.jsonl')
+
+    with config_override(JOURNAL_PATH=path):
+        with journal.acting_as('cli:alice'):
+            room.ban_user(user2, mod=SystemUser())
+            room.set_permissions(user2, mod=SystemUser(), upload=False)
+        room.unban_user(user2, mod=SystemUser())
+
+    ev = _events(path)
+    assert [e['event'] for e in ev] == ['user_banned', 'permissions_changed', 'user_unbanned']
+    assert all(e['by'] == SystemUser().session_id for e in ev)
+    assert [e.get('actor') for e in ev] == ['cli:alice', 'cli:alice', None]
+
+
+
+def test_journal_rollback(room, user2, tmp_path):
+    path = str(tmp_path / 'events.jsonl')
+
+    class Abort(Exception):
+        pass
+
+    with config_override(JOURNAL_PATH=path):
+        try:
+            with db.transaction():
+                room.ban_user(user2, mod=SystemUser())
+                raise Abort()
+        except Abort:
+            pass
+        assert Room(token=room.token).check_unbanned(user2)
+
+        with db.transaction():
+            with db.transaction():
+                room.set_permissions(user2, mod=SystemUser(), upload=False)
+            try:
+                with db.transaction():
+                    room.ban_user(user2, mod=SystemUser())
+                    raise Abort()
+            except Abort:
+                pass
+            # Not written until the outer transaction commits:
+            assert not os.path.exists(path)
+        assert Room(token=room.token).check_unbanned(user2)
+
+    assert [e['event'] for e in _events(path)] == ['permissions_changed']
+
+def test_journal_rotation(client, room, user, tmp_path, no_rate_limit):
+    path = str(tmp_path / 'events.jsonl')
+
+    with config_override(JOURNAL_PATH=path, JOURNAL_ROTATE_SIZE=1000, JOURNAL_KEEP=2):
+        ids = [room.add_post(user, b'x' * 100, pad64(f'sig{i}'))['id'] for i in range(20)]
+
+    rotated = [_events(f"{path}.{i}") for i in (2, 1)]
+    current = _events(path)
+    assert not (tmp_path / 'events.jsonl.3').exists()
+    for journal in rotated:
+        assert 0 < len(journal) <= 3
+    journalled = [e['id'] for journal in rotated + [current] for e in journal]
+    assert journalled == ids[-len(journalled) :]
@@ -0,0 +1,61 @@
+import nacl.pwhash
+import os
+import pytest
+import resource
+from sogs import crypto, keystore
+from util import config_override
+
+
+def test_file_keystore(tmp_path):
+    path = str(tmp_path / 'key_x25519')
+    store = keystore.FileKeyStore(path)
+    assert store.load() is None
+
+    privkey = os.urandom(32)
+    store.store(privkey)
+    assert store.load() == privkey
+    with open(path, 'rb') as f:
+        assert f.read() == privkey
+
+    with pytest.raises(keystore.KeyStoreError):
+        store.store(os.urandom(32))
+    with pytest.raises(keystore.KeyStoreError):
+        keystore.FileKeyStore(str(tmp_path / 'other')).store(b'short')
+
+
+def test_encrypted_keystore(tmp_path, monkeypatch):
+    # Keep the test fast:
+    monkeypatch.setattr(
+        keystore.EncryptedFileKeyStore, 'OPSLIMIT', nacl.pwhash.argon2id.OPSLIMIT_INTERACTIVE
+    )
+    monkeypatch.setattr(
+        keystore.EncryptedFileKeyStore, 'MEMLIMIT', nacl.pwhash.argon2id.MEMLIMIT_INTERACTIVE
+    )
+
+    path = str(tmp_path / 'key_x25519')
+    privkey = os.urandom(32)
+    keystore.EncryptedFileKeyStore(path, passphrase='hunter2').store(privkey)
+
+    with open(path, 'rb') as f:
+        data = f.read()
+    assert data.startswith(keystore.EncryptedFileKeyStore.MAGIC)
+    assert privkey not in data
+
+    assert keystore.EncryptedFileKeyStore(path, passphrase='hunter2').load() == privkey
+    with pytest.raises(keystore.KeyStoreError):
+        keystore.EncryptedFileKeyStore(path, passphrase='hunter3').load()
+
+    monkeypatch.setenv(keystore.PASSPHRASE_ENV, 'hunter2')
+    assert keystore.EncryptedFileKeyStore(path).load() == privkey
+
+    # A raw key file isn't mistaken for an encrypted one:
+    raw = str(tmp_path / 'raw')
+    keystore.FileKeyStore(raw).store(privkey)
+    with pytest.raises(keystore.KeyStoreError):
+        keystore.EncryptedFileKeyStore(raw).load()
+
+
+def test_core_dumps_disabled():
+    with config_override(CORE_DUMPS=False):
+        crypto.disable_core_dumps()
+    assert resource.getrlimit(resource.RLIMIT_CORE) == (0, 0)
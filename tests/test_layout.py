@@ -0,0 +1,79 @@
+import os
+import pytest
+import sqlite3
+from util import config_override
+from sogs import db, layout
+
+
+def test_layout_problems(tmp_path):
+    with config_override(
+        DB_URL=f'sqlite:///{tmp_path}/sogs.db',
+        STORAGE_BACKEND='local',
+        UPLOAD_PATH=str(tmp_path / 'uploads'),
+        UPLOAD_COLD_PATH=None,
+        JOURNAL_PATH=str(tmp_path / 'journal' / 'events.log'),
+    ):
+        assert layout.problems() == []
+
+        (tmp_path / 'not-a-dir').write_text('')
+        with config_override(UPLOAD_PATH=str(tmp_path / 'not-a-dir')):
+            (problem,) = layout.problems()
+            assert 'is not a directory' in problem
+            with pytest.raises(RuntimeError):
+                layout.validate()
+
+        with config_override(JOURNAL_PATH=str(tmp_path / 'not-a-dir' / 'events.log')):
+            (problem,) = layout.problems()
+            assert 'cannot be created' in problem
+
+
+def test_relocate_db(tmp_path):
+    src = tmp_path / 'sogs.db'
+    with sqlite3.connect(src) as conn:
+        conn.execute("CREATE TABLE t (x INTEGER)")
+        conn.execute("INSERT INTO t VALUES (42)")
+    dest = tmp_path / 'disk2' / 'sogs.db'
+    os.mkdir(tmp_path / 'disk2')
+
+    with config_override(DB_URL=f'sqlite:///{src}'):
+        assert layout.relocate_db(str(dest)) == f'sqlite:///{dest}'
+        with pytest.raises(RuntimeError):
+            layout.relocate_db(str(dest))
+    with sqlite3.connect(dest) as conn:
+        assert conn.execute("SELECT x FROM t").fetchall() == [(42,)]
+
+
+def test_relocate_files(client, room, tmp_path):
+    old = str(tmp_path / 'uploads')
+    new = str(tmp_path / 'disk2')
+    os.makedirs(os.path.join(old, room.token))
+    path = os.path.join(old, room.token, '1_hello.txt')
+    with open(path, 'w') as f:
+        f.write('hello')
+    db.query("INSERT INTO files (room, size, path) VALUES (:r, 5, :p)", r=room.id, p=path)
+    db.query("INSERT INTO files (room, size, path) VALUES (:r, 5, 'elsewhere/2_x')", r=room.id)
+
+    with config_override(STORAGE_BACKEND='local', UPLOAD_PATH=old):
+        assert layout.relocate_files(new) == 1
+        with pytest.raises(RuntimeError):
+            layout.relocate_files(new)
+
+    moved = os.path.join(new, room.token, '1_hello.txt')
+    with open(moved) as f:
+        assert f.read() == 'hello'
+    assert os.path.exists(path)  # The original is left in place
+    paths = [r[0] for r in db.query("SELECT path FROM files ORDER BY id")]
+    assert paths == [moved, 'elsewhere/2_x']
+
+
+def test_relocate_journal(tmp_path):
+    src = str(tmp_path / 'events.log')
+    for suffix in ('', '.1', '.2'):
+        with open(src + suffix, 'w') as f:
+            f.write(f'journal{suffix}\n')
+    dest = str(tmp_path / 'events-new.log')
+
+    with config_override(JOURNAL_PATH=src, JOURNAL_KEEP=10):
+        assert layout.relocate_journal(dest) == 3
+    with open(dest + '.2') as f:
+        assert f.read() == 'journal.2\n'
@@ -0,0 +1,32 @@
+from sogs import normalize
+
+
+def test_normalize():
+    assert normalize.normalize(None) is None
+    assert normalize.normalize('Caf\u00e9\t\n') == 'Caf\u00e9\t\n'
+    assert normalize.normalize('Cafe\u0301') == 'Caf\u00e9'
+    assert normalize.normalize('ad\u200bmin\u202e\x07\x85') == 'admin'
+    # Zero width joiners in emoji sequences are kept:
+    family = '\U0001f468\u200d\U0001f469\u200d\U0001f467'
+    assert normalize.normalize(family) == family
+
+    assert not normalize.suspicious('Jos\u00e9')
+    assert normalize.suspicious('Jose\u0301')
+    assert normalize.suspicious('ad\u2060min')
+
+
+def test_fold():
+    assert normalize.fold('F\u200bU\u200dC\u00c9 \uff2b') == 'fuce k'
+    # Arabic presentation forms fold to the base letter:
+    assert normalize.fold('\ufb8a') == '\u0698'
+
+
+def test_graphemes():
+    g = normalize.graphemes
+    assert g('') == 0
+    assert g('hello') == 5
+    assert g('Cafe\u0301') == 4
+    assert g('\U0001f468\u200d\U0001f469\u200d\U0001f467') == 1
+    assert g('\U0001f44d\U0001f3fd!') == 2
+    assert g('\U0001f1e8\U0001f1e6\U0001f1fa\U0001f1f8') == 2
+    assert g('a\r\nb') == 3
@@ -2,6 +2,7 @@ from sogs.web import app
 from sogs import crypto
 from sogs.hashing import blake2b
 from sogs import utils
+from sogs.routes.onion_request import parse_v4_onionreq_plaintext
 from nacl.bindings import (
     crypto_scalarmult,
     crypto_aead_xchacha20poly1305_ietf_encrypt,
@@ -9,6 +10,7 @@ from nacl.bindings import (
 )
 from Cryptodome.Cipher import AES
 import nacl.utils
+import pytest
 import struct
 import json
 import auth
@@ -317,3 +319,57 @@ def test_onion_url_encoding(room, user, client):
     info, body = decrypt_reply(r.data, v=4, enc_type="xchacha20")
     assert info == {'code': 200, 'headers': {'content-type': 'application/json'}}
     assert json.loads(body) == {"p": "❤️"}
+
+
+def test_onion_limits(room, client, monkeypatch):
+    from sogs import metrics
+    from sogs.routes import onion_request
+    from util import config_override
+    import threading
+
+    metrics.reset()
+    req = {'method': 'GET', 'endpoint': '/room/test-room'}
+    data = build_payload(req, v=4, enc_type="xchacha20")
+    assert client.post("/oxen/v4/lsrpc", data=data).status_code == 200
+
+    with config_override(ONION_MAX_SIZE=len(data) - 1):
+        assert client.post("/oxen/v4/lsrpc", data=data).status_code == 413
+        assert client.post("/loki/v3/lsrpc", data=data).status_code == 413
+
+    assert client.post("/oxen/v4/lsrpc", data=b'garbage' + data).status_code == 400
+
+    # No free decryption slots:
+    monkeypatch.setattr(onion_request, '_decrypt_slots', threading.Semaphore(0))
+    assert client.post("/oxen/v4/lsrpc", data=data).status_code == 503
+
+    counters = metrics.snapshot()['counters']
+    assert counters['onion.requests'] == 5
+    assert counters['onion.rejected.size'] == 2
+    assert counters['onion.rejected.malformed'] == 1
+    assert counters['onion.rejected.busy'] == 1
+
+
+def test_v4_parse():
+    def bencode_list(*parts):
+        return b'l' + b''.join(str(len(p)).encode() + b':' + p for p in parts) + b'e'
+
+    req = bencode_list(b'{"method":"GET","endpoint":"/capabilities"}')
+    meta, body = parse_v4_onionreq_plaintext(req)
+    assert meta == {'method': 'GET', 'endpoint': '/capabilities', 'headers': {}}
+    assert body == b''
+
+    for bad in (
+        b'',
+        b'le',
+        b'l0:e',
+        b'l99:{}e',
+        bencode_list(b'[]'),
+        bencode_list(b'{"method":"GET"}'),
+        bencode_list(b'{"method":1,"endpoint":"/x"}'),
+        bencode_list(b'{"method":"GET","endpoint":"x"}'),
+        bencode_list(b'{"method":"GET","endpoint":"/x","headers":[]}'),
+        bencode_list(b'{"method":"GET","endpoint":"/x","headers":{"a":1}}'),
+        bencode_list(b'{"method":"GET","endpoint":"/x"}', b'', b''),
+    ):
+        with pytest.raises(ValueError):
+            parse_v4_onionreq_plaintext(bad)
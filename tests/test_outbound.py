@@ -0,0 +1,189 @@
+import http.server
+import io
+import pytest
+import socket
+import threading
+import time
+import urllib.error
+import urllib.request
+from util import config_override
+from sogs import outbound
+
+
+def test_circuit_breaker(monkeypatch):
+    outbound.reset()
+    calls = []
+    fail = [True]
+
+    def fake_urlopen(req, timeout):
+        calls.append(req)
+        if fail[0]:
+            raise OSError("connection timed out")
+        return io.BytesIO(b'hello')
+
+    monkeypatch.setattr(urllib.request, 'urlopen', fake_urlopen)
+
+    url = 'https://slow.example:8443/api'
+    with config_override(OUTBOUND_FAILURE_THRESHOLD=3, OUTBOUND_COOLDOWN=0.2):
+        for _ in range(3):
+            with pytest.raises(outbound.OutboundError):
+                outbound.fetch(url, timeout=1)
+        assert outbound.status() == {'slow.example:8443': {'failures': 3, 'open': True}}
+
+        # The breaker is open, so we fail without making a request:
+        with pytest.raises(outbound.CircuitOpen):
+            outbound.fetch(url, timeout=1)
+        assert len(calls) == 3
+
+        # Other destinations are unaffected:
+        fail[0] = False
+        assert outbound.fetch('https://fast.example/', timeout=1) == b'hello'
+
+        # After the cooldown a trial request is let through, which closes the breaker:
+        time.sleep(0.25)
+        assert outbound.fetch(url, timeout=1, limit=3) == b'hel'
+        assert outbound.status()['slow.example:8443'] == {'failures': 0, 'open': False}
+
+    def not_found(req, timeout):
+        raise urllib.error.HTTPError(req, 404, "Not Found", {}, None)
+
+    monkeypatch.setattr(urllib.request, 'urlopen', not_found)
+    with config_override(OUTBOUND_FAILURE_THRESHOLD=1):
+        # Client errors mean the destination is up, and so don't count against it:
+        with pytest.raises(outbound.OutboundError):
+            outbound.fetch(url, timeout=1)
+        assert outbound.status()['slow.example:8443'] == {'failures': 0, 'open': False}
+
+    outbound.reset()
+
+
+def test_outbound_rate_limit(monkeypatch):
+    outbound.reset()
+    monkeypatch.setattr(urllib.request, 'urlopen', lambda req, timeout: io.BytesIO(b'ok'))
+
+    with config_override(OUTBOUND_RATE_LIMIT=2, OUTBOUND_RATE_INTERVAL=0.2):
+        assert outbound.fetch('https://a.example/1', timeout=1) == b'ok'
+        assert outbound.fetch('https://a.example/2', timeout=1) == b'ok'
+        with pytest.raises(outbound.RateLimited):
+            outbound.fetch('https://a.example/3', timeout=1)
+        assert outbound.fetch('https://b.example/', timeout=1) == b'ok'
+        time.sleep(0.25)
+        assert outbound.fetch('https://a.example/4', timeout=1) == b'ok'
+
+    outbound.reset()
+
+
+def test_outbound_proxy(monkeypatch):
+    outbound.reset()
+    opened = []
+
+    class FakeOpener:
+        def open(self, req, timeout):
+            opened.append(req)
+            return io.BytesIO(b'proxied')
+
+    def direct(req, timeout):
+        raise AssertionError("request bypassed the proxy")
+
+    monkeypatch.setattr(urllib.request, 'urlopen', direct)
+    monkeypatch.setattr(outbound, '_proxy_opener', lambda proxy: FakeOpener())
+    with config_override(OUTBOUND_PROXY='socks5h://127.0.0.1:9050'):
+        assert outbound.fetch('https://example.org/', timeout=1) == b'proxied'
+    assert opened == ['https://example.org/']
+
+    monkeypatch.undo()
+    opener = outbound._proxy_opener('http://proxy.example:3128')
+    (handler,) = [h for h in opener.handlers if isinstance(h, urllib.request.ProxyHandler)]
+    assert handler.proxies == {
+        'http': 'http://proxy.example:3128',
+        'https': 'http://proxy.example:3128',
+    }
+    outbound.reset()
+
+
+def test_public_only(monkeypatch):
+    outbound.reset()
+    for addr in (
+        '127.0.0.1',
+        '10.1.2.3',
+        '172.16.0.1',
+        '192.168.1.1',
+        '169.254.169.254',
+        '100.64.0.1',
+        '0.0.0.0',
+        '224.0.0.1',
+        '::1',
+        '::',
+        'fe80::1%eth0',
+        'fd00:ec2::254',
+        '::ffff:127.0.0.1',
+        '2002:7f00:1::1',
+    ):
+        assert not outbound.is_public(addr), addr
+    for addr in ('93.184.216.34', '1.1.1.1', '2606:4700::1111'):
+        assert outbound.is_public(addr), addr
+
+    # Including alternative spellings of loopback addresses, and non-http schemes:
+    for url in (
+        'http://127.1/',
+        'http://2130706433/',
+        'http://0x7f000001/',
+        'http://[::1]:8080/',
+        'http://localhost/',
+        'file:///etc/passwd',
+        'gopher://example.com/',
+    ):
+        with pytest.raises(outbound.UnsafeDestination):
+            outbound.fetch(url, timeout=1, public_only=True)
+    assert all(s['failures'] == 0 for s in outbound.status().values())
+
+    # DNS rebinding: a host that resolves to a public address when checked, and then to a private
+    # one, gets connected to at the address that was checked (and is only resolved once):
+    answers = [['93.184.216.34'], ['127.0.0.1']]
+    resolved, connected = [], []
+
+    def fake_getaddrinfo(host, port, *args, **kwargs):
+        resolved.append(host)
+        return [(socket.AF_INET, socket.SOCK_STREAM, 6, '', (a, port)) for a in answers.pop(0)]
+
+    def fake_create_connection(address, *args):
+        connected.append(address)
+        raise OSError("no network in tests")
+
+    monkeypatch.setattr(socket, 'getaddrinfo', fake_getaddrinfo)
+    monkeypatch.setattr(socket, 'create_connection', fake_create_connection)
+    with pytest.raises(outbound.OutboundError):
+        outbound.fetch('http://rebind.example/', timeout=1, public_only=True)
+    assert resolved == ['rebind.example']
+    assert connected == [('93.184.216.34', 80)]
+
+    # A host with both public and private addresses is refused:
+    answers = [['93.184.216.34', '10.0.0.1']]
+    with pytest.raises(outbound.UnsafeDestination):
+        outbound.fetch('http://multi.example/', timeout=1, public_only=True)
+    assert len(connected) == 1
+    monkeypatch.undo()
+    outbound.reset()
+
+
+def test_public_only_redirect(monkeypatch):
+    outbound.reset()
+
+    class Redirector(http.server.BaseHTTPRequestHandler):
+        def do_GET(self):
+            self.send_response(302)
+            self.send_header('Location', 'http://10.0.0.1/latest/meta-data/')
+            self.end_headers()
+
+        def log_message(self, *args):
+            pass
+
+    server = http.server.HTTPServer(('127.0.0.1', 0), Redirector)
+    threading.Thread(target=server.handle_request, daemon=True).start()
+
+    # Pretend that our test server is public; the redirect to a private address must be refused:
+    monkeypatch.setattr(outbound, 'is_public', lambda addr: addr == '127.0.0.1')
+    with pytest.raises(outbound.UnsafeDestination):
+        outbound.fetch(f'http://127.0.0.1:{server.server_port}/', timeout=5, public_only=True)
+    server.server_close()
+    outbound.reset()
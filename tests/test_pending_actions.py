@@ -0,0 +1,101 @@
+import pytest
+import user as test_user
+from request import sogs_get, sogs_post, sogs_delete
+from util import config_override, pad64
+from sogs.model.exc import NoSuchRoom
+from sogs.model.room import Room
+from sogs.model.user import User, SystemUser
+
+
+def test_two_person_rule(
+    client, room, room2, user, mod, admin, global_admin, global_mod, no_rate_limit
+):
+    room.add_post(user, b'data', pad64('sig'))
+    admin2 = test_user.User()
+    admin2.set_moderator(added_by=SystemUser(), admin=True)
+
+    with config_override(TWO_PERSON_RULE=True):
+        r = sogs_delete(client, f'/room/{room2.token}', global_admin)
+        assert r.status_code == 202
+        pa = r.json['pending_action']
+        assert pa['action'] == 'delete_room'
+        assert pa['room'] == room2.token
+        assert pa['requested_by'] == global_admin.session_id
+        assert pa['expires'] > pa['requested']
+        Room(token=room2.token)  # Not deleted yet
+
+        assert sogs_get(client, '/admin/pending', global_admin).json == [pa]
+        assert sogs_get(client, '/admin/pending', admin2).json == [pa]
+        assert sogs_get(client, '/admin/pending', global_mod).json == []
+
+        # Must be confirmed by a *second* user with the same authority:
+        url = f"/admin/pending/{pa['id']}"
+        assert sogs_post(client, url, {}, global_admin).status_code == 403
+        assert sogs_post(client, url, {}, global_mod).status_code == 404
+        r = sogs_post(client, url, {}, admin2)
+        assert r.status_code == 200
+        assert r.json == {}
+        with pytest.raises(NoSuchRoom):
+            Room(token=room2.token)
+        assert sogs_post(client, url, {}, admin2).status_code == 404
+
+        r = sogs_delete(client, f'/room/{room.token}/all/{user.session_id}', mod)
+        assert r.status_code == 202
+        pa = r.json['pending_action']
+        assert pa['action'] == 'purge'
+        assert pa['session_id'] == user.session_id
+        assert pa['rooms'] == [room.token]
+        url = f"/admin/pending/{pa['id']}"
+        assert sogs_post(client, url, {}, mod).status_code == 403
+        r = sogs_post(client, url, {}, admin)
+        assert r.status_code == 200
+        assert r.json == {'total': 1, 'rooms': {room.token: 1}}
+
+        r = sogs_post(
+            client, f'/user/{user.session_id}/ban', {'global': True, 'timeout': 60}, global_mod
+        )
+        assert r.status_code == 202
+        pa = r.json['pending_action']
+        assert pa['action'] == 'global_ban'
+        assert pa['timeout'] == 60
+        assert not User(id=user.id).banned
+
+        # The requester, or anyone who could confirm, can cancel:
+        url = f"/admin/pending/{pa['id']}"
+        assert sogs_delete(client, url, mod).status_code == 404
+        assert sogs_delete(client, url, global_admin).status_code == 200
+        assert sogs_get(client, '/admin/pending', global_mod).json == []
+        assert not User(id=user.id).banned
+
+    # Without the two-person rule actions happen immediately:
+    r = sogs_post(client, f'/user/{user.session_id}/ban', {'global': True}, global_mod)
+    assert r.status_code == 200
+    assert User(id=user.id).banned
+
+
+def test_clear_room(client, room, room2, user, admin, global_admin, no_rate_limit):
+    for i in range(3):
+        room.add_post(user, f'data {i}'.encode(), pad64(f'sig {i}'))
+    room2.add_post(user, b'data', pad64('sig'))
+    room.pin(1, admin)
+    url = f'/admin/room/{room.token}/clear'
+
+    assert sogs_post(client, url, {}, admin).status_code == 403
+
+    global_admin2 = test_user.User()
+    global_admin2.set_moderator(added_by=SystemUser(), admin=True)
+    with config_override(TWO_PERSON_RULE=True):
+        r = sogs_post(client, url, {}, global_admin)
+        assert r.status_code == 202
+        pa = r.json['pending_action']
+        assert (pa['action'], pa['room']) == ('clear_room', room.token)
+        assert len(room.get_messages_for(user, recent=True)) == 3
+        r = sogs_post(client, f"/admin/pending/{pa['id']}", {}, global_admin2)
+        assert r.json == {'deleted': 3}
+
+    assert room.get_messages_for(user, recent=True) == []
+    assert Room(id=room.id).pinned_messages == []
+    assert len(room2.get_messages_for(user, recent=True)) == 1
+    assert sogs_post(client, f'/admin/room/{room2.token}/clear', {}, global_admin).json == {
+        'deleted': 1
+    }
@@ -0,0 +1,20 @@
+from util import config_override, pad64
+
+
+def test_prometheus_metrics(client, room, user):
+    room.add_post(user, b'hello', pad64('sig'))
+    assert client.get('/metrics').status_code == 404
+
+    with config_override(METRICS_TOKEN='sekrit'):
+        assert client.get('/metrics').status_code == 401
+        r = client.get('/metrics', headers={'Authorization': 'Bearer wrong'})
+        assert r.status_code == 401
+        r = client.get('/metrics', headers={'Authorization': 'Bearer sekrit'})
+        assert r.status_code == 200
+        assert r.mimetype == 'text/plain'
+        text = r.get_data(as_text=True)
+        assert '# TYPE sogs_http_requests_total counter' in text
+        assert 'sogs_http_requests_total{endpoint="admin.prometheus_metrics",' in text
+        assert '# TYPE sogs_http_request_duration_seconds histogram' in text
+        assert 'sogs_room_messages{room="test-room"} 1\n' in text
+        assert 'sogs_room_messages_posted_total{room="test-room"} 1\n' in text
@@ -0,0 +1,77 @@
+from sogs import ratelimit, session_pb2 as protobuf
+from sogs.routes import public
+from util import config_override, pad64
+
+
+def _post(room, user, body, name="Archivist"):
+    msg = protobuf.Content()
+    msg.dataMessage.body = body
+    msg.dataMessage.profile.displayName = name
+    return room.add_post(user, msg.SerializeToString(), pad64(b'fake sig'))
+
+
+def test_public_api(client, room, room2, user, no_rate_limit):
+    ratelimit.reset()
+    public._cache.clear()
+    posts = [_post(room, user, f"Message {i}") for i in range(5)]
+    room.add_post(user, b'not a protobuf', pad64(b'sig'))
+
+    # Not available unless enabled for the room:
+    assert client.get("/public/rooms").json == []
+    assert client.get("/public/room/test-room").status_code == 404
+    assert client.get("/public/room/test-room/messages").status_code == 404
+
+    with config_override(ROOM_OVERRIDES={'test-room': {'public_api': True}}, PUBLIC_API_CACHE=0):
+        r = client.get("/public/rooms")
+        assert [x['token'] for x in r.json] == ['test-room']
+        assert 'Cache-Control' not in r.headers
+        r = client.get("/public/room/test-room")
+        assert r.status_code == 200
+        assert r.json['name'] == room.name
+        assert client.get("/public/room/room2").status_code == 404
+
+        r = client.get("/public/room/test-room/messages")
+        assert r.status_code == 200
+        assert [(m['id'], m['author'], m['text']) for m in r.json] == [
+            (p['id'], "Archivist", f"Message {i}") for i, p in reversed(list(enumerate(posts)))
+        ]
+        # No user identifiers:
+        assert all(user.session_id not in str(m) for m in r.json)
+        assert set(r.json[0].keys()) == {'id', 'posted', 'edited', 'author', 'text'}
+
+        r = client.get(f"/public/room/test-room/messages?before={posts[3]['id']}&limit=2")
+        assert [m['id'] for m in r.json] == [posts[2]['id'], posts[1]['id']]
+
+        # Not available for rooms that aren't publicly readable:
+        room.default_read = False
+        assert client.get("/public/room/test-room/messages").status_code == 404
+        room.default_read = True
+
+        # Nor for sensitive rooms, which can only be read after acknowledging the flag:
+        room.sensitive = True
+        assert client.get("/public/room/test-room/messages").status_code == 404
+        assert client.get("/public/rooms").json == []
+        room.sensitive = False
+
+    with config_override(ROOM_OVERRIDES={'test-room': {'public_api': True}}, PUBLIC_API_CACHE=60):
+        r = client.get("/public/room/test-room/messages?limit=1")
+        assert r.headers['Cache-Control'] == 'public, max-age=60'
+        assert r.json[0]['id'] == posts[4]['id']
+
+        # Cached responses don't see new messages until they expire:
+        _post(room, user, "Message 5")
+        assert client.get("/public/room/test-room/messages?limit=1").json == r.json
+        public._cache.clear()
+        assert client.get("/public/room/test-room/messages?limit=1").json[0]['text'] == "Message 5"
+
+
+def test_public_api_rate_limit(client, room):
+    ratelimit.reset()
+    with config_override(ROOM_OVERRIDES={'test-room': {'public_api': True}}, PUBLIC_API_LIMIT=3):
+        for _ in range(3):
+            assert client.get("/public/room/test-room").status_code == 200
+        r = client.get("/public/rooms")
+        assert r.status_code == 429
+        assert r.json['scope'] == 'public_api'
+        assert int(r.headers['Retry-After']) > 0
+    ratelimit.reset()
@@ -0,0 +1,88 @@
+from util import pad64
+from sogs import push
+from sogs.model.room import Room
+
+
+def test_push_subscriptions(client, room, room2, user, mod):
+    push.reset()
+    room2.default_read = False
+    now = 1_000_000
+    seqno = room.message_sequence
+
+    assert push.subscribe('c1', ['test-room', 'room2', 'nope'], now=now) == {
+        'test-room': seqno,
+        'room2': None,
+        'nope': None,
+    }
+    assert push.subscribe('c2', ['test-room'], now=now + 1000) == {'test-room': seqno}
+    assert push.subscribers('test-room', now=now) == ['c1', 'c2']
+    assert push.subscribers('room2', now=now) == []
+
+    # Subscriptions expire unless renewed:
+    assert push.subscribers('test-room', now=now + push.SUBSCRIPTION_TTL) == ['c2']
+    push.subscribe('c1', ['test-room'], now=now + push.SUBSCRIPTION_TTL)
+    assert push.subscribers('test-room', now=now + push.SUBSCRIPTION_TTL) == ['c2', 'c1']
+
+    push.unsubscribe('c2')
+    assert push.subscribers('test-room', now=now + push.SUBSCRIPTION_TTL) == ['c1']
+    push.unsubscribe('c1', ['test-room'])
+    assert push.subscribers('test-room', now=now) == []
+
+    m1 = room.add_post(user, b'hello', pad64(b'hello'))
+    m2 = room.add_post(user, b'world', pad64(b'world'))
+    room.delete_posts([m1['id'], m2['id']], mod)
+    seqno = Room(id=room.id).message_sequence
+    assert push.notifications('deleted', [m2['id'], m1['id']]) == [
+        (
+            'test-room',
+            {
+                'room': 'test-room',
+                'event': 'deleted',
+                'ids': [m1['id'], m2['id']],
+                'seqno': seqno,
+                'prev_seqno': None,
+            },
+        )
+    ]
+    assert push.notifications('posted', []) == []
+
+    # prev_seqno lets clients detect missed events:
+    m3 = room.add_post(user, b'again', pad64(b'again'))
+    ((_, n),) = push.notifications('posted', [m3['id']])
+    assert (n['prev_seqno'], n['seqno']) == (seqno, seqno + 1)
+    push.reset()
+
+
+def test_push_queues(client, room, monkeypatch):
+    from sogs import metrics
+
+    push.reset()
+    monkeypatch.setattr(push, 'QUEUE_SIZE', 3)
+    monkeypatch.setattr(push, 'SEND_BURST', 2)
+    push.subscribe('fast', ['test-room'])
+    push.subscribe('slow', ['test-room'])
+    dropped = metrics.counter('push.dropped')
+
+    sent = []
+
+    def send(conn, command, n):
+        if conn == 'slow':
+            raise RuntimeError("send failed")
+        sent.append((conn, command, n))
+
+    assert push.enqueue('test-room', {'seqno': 1}) == 2
+    assert push.flush(send) == 1
+    assert sent == [('fast', 'notify.room', {'seqno': 1})]
+    # The subscriber whose send failed was dropped:
+    assert push.subscribers('test-room') == ['fast']
+
+    sent.clear()
+    for i in range(2, 6):
+        push.enqueue('test-room', {'seqno': i})
+    # The fourth notification overflowed the queue, so the subscriber was dropped to resync:
+    assert push.subscribers('test-room') == []
+    assert metrics.counter('push.dropped') == dropped + 1
+    assert push.flush(send) == 0
+    assert sent == [('fast', 'notify.resync', None)]
+    assert push.flush(send) == 0
+    push.reset()
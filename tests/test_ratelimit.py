@@ -0,0 +1,123 @@
+from request import sogs_get, sogs_post, sogs_post_raw
+from util import config_override, pad64
+from sogs import ratelimit, utils, web
+import pytest
+
+
+def test_anonymous_read_limit(client, room, user):
+    ratelimit.reset()
+    url = f"/room/{room.token}/messages/recent"
+    anon = web.app.test_client(use_cookies=False)
+
+    # Disabled by default:
+    r = anon.get(url)
+    assert r.status_code == 200
+    assert ratelimit.TOKEN_HEADER not in r.headers
+
+    with config_override(ANON_READ_LIMIT=2, ANON_TOKEN_LIMIT=2):
+        r = anon.get(url)
+        assert r.status_code == 200
+        token = r.headers[ratelimit.TOKEN_HEADER]
+        assert ratelimit.verify_token(token) is not None
+
+        # Clients are limited per token rather than per address:
+        h = {ratelimit.TOKEN_HEADER: token}
+        r = anon.get(url, headers=h)
+        assert r.status_code == 200
+        assert ratelimit.TOKEN_HEADER not in r.headers
+        r = anon.get(url, headers=h)
+        assert r.status_code == 429
+        assert 0 < int(r.headers['Retry-After']) <= 61
+        assert r.json['scope'] == 'anonymous_read'
+        assert (r.json['limit'], r.json['remaining']) == (2, 0)
+        assert r.json['retry_after'] == int(r.headers['Retry-After'])
+
+        r = anon.get(url)
+        assert r.status_code == 200
+        token2 = r.headers[ratelimit.TOKEN_HEADER]
+        assert token2 != token
+
+        # Once the address has been issued too many tokens, token-less requests share a limit:
+        r = anon.get(url)
+        assert r.status_code == 200
+        assert ratelimit.TOKEN_HEADER not in r.headers
+        assert anon.get(url).status_code == 200
+        assert anon.get(url).status_code == 429
+
+        # Invalid tokens are ignored:
+        assert anon.get(url, headers={ratelimit.TOKEN_HEADER: token2[:-2]}).status_code == 429
+        assert anon.get(url, headers={ratelimit.TOKEN_HEADER: token2}).status_code == 200
+
+        # Browsers get the token as a cookie:
+        ratelimit.reset()
+        assert ratelimit.TOKEN_HEADER in client.get(url).headers
+        assert ratelimit.TOKEN_HEADER not in client.get(url).headers
+        assert client.get(url).status_code == 429
+
+        # Authenticated requests aren't limited:
+        for _ in range(5):
+            assert sogs_get(client, url, user).status_code == 200
+
+        # Behind a trusted proxy, clients are identified by their forwarded address:
+        ratelimit.reset()
+        with config_override(TRUSTED_PROXIES={'127.0.0.1'}):
+            a = {'X-Forwarded-For': '203.0.113.1'}
+            b = {'X-Forwarded-For': '203.0.113.1, 203.0.113.2'}
+            for _ in range(4):
+                assert anon.get(url, headers=a).status_code == 200
+            assert anon.get(url, headers=a).status_code == 429
+            assert anon.get(url, headers=b).status_code == 200
+
+    ratelimit.reset()
+
+
+def test_token_bucket():
+    ratelimit.reset()
+    now = 1000.0
+    for _ in range(3):
+        assert ratelimit.take('k', 3, 60, now=now) is None
+    assert ratelimit.take('k', 3, 60, now=now) == pytest.approx(20)
+    assert ratelimit.take('other', 3, 60, now=now) is None
+    assert ratelimit.take('k', 3, 60, now=now + 10) == pytest.approx(10)
+    assert ratelimit.take('k', 3, 60, now=now + 20) is None
+    assert ratelimit.take('k', 3, 60, now=now + 20) is not None
+    assert ratelimit.take('k', 0, 60, now=now) == 60
+
+
+def test_window_intervals():
+    ratelimit.reset()
+    now = 1000.0
+    assert ratelimit.allow('hourly', 1, 3600, now=now) is None
+    assert ratelimit.allow('hourly', 1, 3600, now=now) == pytest.approx(3600)
+
+    # Pruning for a limit with a shorter interval must not reset the longer interval's window:
+    assert ratelimit.allow('minutely', 1, 60, now=now + 120) is None
+    assert ratelimit.allow('hourly', 1, 3600, now=now + 120) == pytest.approx(3480)
+
+    assert ratelimit.allow('minutely', 1, 60, now=now + 3600) is None
+    assert ratelimit.allow('hourly', 1, 3600, now=now + 3600) is None
+    ratelimit.reset()
+
+
+def test_post_upload_throttle(client, room, user, global_mod, no_rate_limit):
+    ratelimit.reset()
+    url = f"/room/{room.token}/message"
+    post = {'data': utils.encode_base64(b'post'), 'signature': utils.encode_base64(pad64(b'sig'))}
+
+    with config_override(POST_RATE_LIMIT=2, UPLOAD_RATE_LIMIT=1):
+        for _ in range(2):
+            assert sogs_post(client, url, post, user).status_code == 201
+        r = sogs_post(client, url, post, user)
+        assert r.status_code == 429
+        assert r.json['scope'] == 'post_rate'
+        assert 0 < int(r.headers['Retry-After']) <= 31
+
+        # Global moderators are exempt:
+        for _ in range(3):
+            assert sogs_post(client, url, post, global_mod).status_code == 201
+
+        files = f"/room/{room.token}/file"
+        assert sogs_post_raw(client, files, b'abc', user).status_code == 201
+        r = sogs_post_raw(client, files, b'abc', user)
+        assert r.status_code == 429
+        assert r.json['scope'] == 'upload_rate'
@@ -397,3 +397,48 @@ def test_reaction_ordering(client, room, user, user2):
         {'id': 2, 'reactions': exp_reacts_2, 'seqno': seqno_2},
         {'id': 1, 'reactions': exp_reacts_1, 'seqno': seqno},
     ]
+
+
+def test_reaction_counts(room, user, user2, mod):
+    from sogs.db import query
+
+    def counts():
+        return {
+            (m, r): c
+            for m, r, c in query(
+                """
+                SELECT message, r.reaction, c.count
+                FROM reactions r JOIN reaction_counts c ON c.reaction = r.id
+                """
+            )
+        }
+
+    def actual():
+        return {
+            (m, r): c
+            for m, r, c in query(
+                'SELECT message, reaction, COUNT("user") FROM message_reactions'
+                ' GROUP BY message, reaction'
+            )
+        }
+
+    ids = [room.add_post(user, f"post {i}".encode(), pad64(f"sig {i}"))['id'] for i in range(3)]
+    for u in (user, user2, mod):
+        room.add_reaction(u, ids[0], "👍")
+    room.add_reaction(user, ids[0], "🍍")
+    room.add_reaction(user2, ids[1], "👍")
+    room.add_reaction(mod, ids[2], "🍍")
+    assert counts() == actual() == {
+        (ids[0], "👍"): 3,
+        (ids[0], "🍍"): 1,
+        (ids[1], "👍"): 1,
+        (ids[2], "🍍"): 1,
+    }
+    assert sorted(room.reactions_counts()) == [("🍍", 2), ("👍", 4)]
+
+    room.delete_reaction(user2, ids[0], "👍")
+    room.delete_reaction(user, ids[0], "🍍")
+    room.delete_all_reactions(mod, ids[1])
+    room.delete_posts([ids[2]], user)
+    assert counts() == actual() == {(ids[0], "👍"): 2}
+    assert room.get_reactions([ids[0]], user)[ids[0]]["👍"] == {"count": 2, "you": True, "index": 0}
@@ -0,0 +1,75 @@
+from sogs import redact
+from sogs.model import exc
+from sogs.web import app
+from nacl.signing import SigningKey
+import base64
+import logging
+import secrets
+
+
+def test_redact():
+    sk = SigningKey.generate()
+    sig = sk.sign(b'hello').signature
+    session_id = '05' + sk.verify_key.encode().hex()
+    api_key = secrets.token_urlsafe(32)
+
+    for secret in (sk.encode().hex(), sig.hex(), base64.b64encode(sig).decode(), api_key):
+        assert redact.redact(f"oops: {secret}!") == f"oops: {redact.REDACTED}!"
+    assert redact.redact("Authorization: Bearer xyz") == f"Authorization: Bearer {redact.REDACTED}"
+    assert redact.redact("X-SOGS-Api-Key: abc") == f"X-SOGS-Api-Key: {redact.REDACTED}"
+
+    r = redact.redact(
+        "(sqlite3.IntegrityError) UNIQUE constraint failed\n"
+        "[SQL: INSERT INTO messages (data) VALUES (?)]\n"
+        "[parameters: (b'secret message',)]"
+    )
+    assert 'secret message' not in r
+    assert '[parameters: <redacted>]' in r
+
+    # Public values and ordinary text are left alone:
+    for text in (
+        f"Rejecting message from {session_id}",
+        'File "/usr/lib/python3/dist-packages/sqlalchemy/engine/base.py", line 1234',
+        "Message 123 has no translatable content",
+    ):
+        assert redact.redact(text) == text
+
+
+def test_redacted_error_paths(client, monkeypatch, caplog):
+    sig = SigningKey.generate().sign(b'hi').signature.hex()
+
+    def boom():
+        raise RuntimeError(f"failed to verify token {sig}")
+
+    monkeypatch.setitem(app.view_functions, 'general.get_caps', boom)
+    with caplog.at_level(logging.INFO, logger=app.logger.name):
+        r = client.get("/capabilities")
+    assert r.status_code == 500
+    assert sig not in r.get_data(as_text=True)
+    assert 'Unhandled exception' in caplog.text
+    assert sig not in caplog.text
+    assert redact.REDACTED in caplog.text
+
+    def bad():
+        raise exc.InvalidData(f"Invalid key {sig}")
+
+    monkeypatch.setitem(app.view_functions, 'general.get_caps', bad)
+    r = client.get("/capabilities")
+    assert r.status_code == 400
+    assert r.get_data(as_text=True) == f"Invalid key {redact.REDACTED}"
+
+    # Debug logging is left intact:
+    with caplog.at_level(logging.DEBUG, logger=app.logger.name):
+        app.logger.debug(f"debug {sig}")
+        app.logger.info(f"info {sig}")
+    assert f"debug {sig}" in caplog.text
+    assert f"info {sig}" not in caplog.text
+
+    # Records of child loggers (which bypass their parents' filters) and other loggers are too:
+    caplog.clear()
+    with caplog.at_level(logging.INFO):
+        logging.getLogger(f"{app.logger.name}.child").warning(f"child {sig}")
+        logging.getLogger("sqlalchemy.engine").warning(f"engine {sig}")
+    assert f"child {redact.REDACTED}" in caplog.text
+    assert f"engine {redact.REDACTED}" in caplog.text
+    assert sig not in caplog.text
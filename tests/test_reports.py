@@ -0,0 +1,78 @@
+from request import sogs_get, sogs_post, sogs_delete
+from util import pad64
+from sogs import stats
+import time
+
+
+def test_reports(client, room, user, user2, mod, admin, global_mod, no_rate_limit):
+    m1, m2, m3 = (
+        room.add_post(user, f'msg {i}'.encode(), pad64(f'sig {i}'))['id'] for i in (1, 2, 3)
+    )
+
+    url = f"/room/{room.token}/reports"
+    report = f"/room/{room.token}/report"
+    r = sogs_post(client, f"{report}/{m1}", {'reason': 'spam'}, user2)
+    assert r.status_code == 200
+    assert r.json == {'reported': True}
+    assert sogs_post(client, f"{report}/{m1}", {}, user2).json == {'reported': False}
+    assert sogs_post(client, f"{report}/999", {}, user2).status_code == 404
+    assert sogs_post(client, f"{report}/{m1}", {'reason': 123}, user2).status_code == 400
+
+    assert sogs_get(client, url, user).status_code == 403
+    r = sogs_get(client, url, mod)
+    assert r.status_code == 200
+    assert [
+        (q['id'], q['reports'], q['priority'], q['reasons'], q['claimed_by']) for q in r.json
+    ] == [(m1, 1, 0.5, [{'reason': 'spam', 'count': 1}], None)]
+
+    # A dismissed report lowers the reporter's trust, and so the priority of their later reports:
+    sogs_post(client, f"{report}/{m2}", {}, user)
+    assert sogs_post(client, f"{url}/{m2}/resolve", {'resolution': 'meh'}, mod).status_code == 400
+    r = sogs_post(client, f"{url}/{m2}/resolve", {'resolution': 'dismissed'}, mod)
+    assert r.json == {'resolved': 1}
+    r = sogs_post(client, f"{url}/{m2}/resolve", {'resolution': 'dismissed'}, mod)
+    assert r.status_code == 404
+    sogs_post(client, f"{report}/{m3}", {}, user)
+    assert [(q['id'], q['priority']) for q in sogs_get(client, url, mod).json] == [
+        (m1, 0.5),
+        (m3, 1 / 3),
+    ]
+
+    # Claims: other (non-admin) moderators can't take over, resolve, or release claimed reports
+    assert sogs_post(client, f"{url}/{m1}/claim", {}, mod).status_code == 200
+    assert sogs_post(client, f"{url}/{m1}/claim", {}, global_mod).status_code == 409
+    r = sogs_post(client, f"{url}/{m1}/resolve", {'resolution': 'actioned'}, global_mod)
+    assert r.status_code == 409
+    assert sogs_delete(client, f"{url}/{m1}/claim", global_mod).status_code == 409
+
+    # A new report of a claimed message joins the claim, and identical reasons are merged:
+    sogs_post(client, f"{report}/{m1}", {'reason': ' spam '}, user)
+    sogs_post(client, f"{report}/{m1}", {'reason': 'rude'}, admin)
+    q1 = sogs_get(client, url, mod).json[0]
+    assert (q1['id'], q1['reports']) == (m1, 3)
+    assert q1['reasons'] == [{'reason': 'spam', 'count': 2}, {'reason': 'rude', 'count': 1}]
+    assert q1['priority'] == 0.5 + 1 / 3 + 0.5
+    assert q1['claimed_by'] == mod.session_id
+
+    assert sogs_delete(client, f"{url}/{m1}/claim", mod).status_code == 200
+    assert sogs_get(client, url, mod).json[0]['claimed_by'] is None
+    assert sogs_post(client, f"{url}/{m1}/claim", {}, global_mod).status_code == 200
+    r = sogs_post(client, f"{url}/{m1}/resolve", {'resolution': 'actioned'}, admin)
+    assert r.json == {'resolved': 3}
+
+    # Assignment:
+    r = sogs_post(client, f"{url}/{m3}/claim", {'assign': user2.session_id}, mod)
+    assert r.status_code == 400
+    r = sogs_post(client, f"{url}/{m3}/claim", {'assign': global_mod.session_id}, mod)
+    assert r.status_code == 200
+    assert sogs_get(client, url, mod).json[0]['claimed_by'] == global_mod.session_id
+
+    r = sogs_post(client, f"{url}/{m3}/resolve", {'resolution': 'actioned'}, global_mod)
+    assert r.json == {'resolved': 1}
+    assert sogs_get(client, url, mod).json == []
+
+    # Service level statistics are part of the room's daily statistics:
+    stats.rollup(now=time.time() + stats.DAY)
+    today = sogs_get(client, f"/room/{room.token}/stats", mod).json[-1]
+    assert (today['reports'], today['reports_resolved'], today['reports_actioned']) == (5, 5, 4)
+    assert 0 <= today['median_claim'] < 10 and 0 <= today['median_resolution'] < 10
@@ -0,0 +1,96 @@
+import time
+from sogs import journal
+from sogs.restore import replay
+from util import config_override, pad64
+
+
+def test_restore(client, room, user, user2, mod, tmp_path, no_rate_limit):
+    from sogs.db import query
+
+    path = str(tmp_path / 'events.jsonl')
+
+    with config_override(JOURNAL_PATH=path):
+        m1 = room.add_post(user, b'one', pad64('sig1'))
+        backup_time = time.time()
+
+        m2 = room.add_post(user, b'two', pad64('sig2'))
+        room.edit_post(user, m2['id'], b'two (edited)', pad64('sig2b'))
+        m3 = room.add_post(user2, b'three', pad64('sig3'))
+        assert journal.checkpoint() == 1
+        room.delete_posts([m3['id']], mod)
+        room.ban_user(user2, mod=mod)
+        until = time.time()
+
+        m4 = room.add_post(user, b'four', pad64('sig4'))
+
+    expected_root = journal.room_root(room)
+
+    # Simulate restoring a backup taken just after m1 was posted:
+    query(
+        "DELETE FROM messages WHERE id IN :ids",
+        ids=[m2['id'], m3['id'], m4['id']],
+        bind_expanding=['ids'],
+    )
+    query('DELETE FROM user_permission_overrides WHERE "user" = :u', u=user2.id)
+
+    result = replay(path, since=backup_time, until=until)
+    assert result == {'replayed': 5, 'skipped': 0, 'verified': 1, 'mismatched': []}
+
+    rows = {
+        id: None if data is None else bytes(data)
+        for id, data in query("SELECT id, data FROM messages WHERE room = :r", r=room.id)
+    }
+    assert rows == {m1['id']: b'one', m2['id']: b'two (edited)', m3['id']: None}
+    assert room.get_bans() == [user2.session_id]
+
+    # Replaying the rest gets us back to the current state:
+    result = replay(path, since=until)
+    assert result['replayed'] == 1
+    assert journal.room_root(room) == expected_root
+
+    # Posts that already exist are skipped, and the replay itself isn't journalled:
+    with config_override(JOURNAL_PATH=path):
+        assert replay(path, since=0)['skipped'] == 4
+    assert len(list(journal.read_events(path))) == 8
+
+
+def test_restore_mismatch(client, room, user, tmp_path, no_rate_limit):
+    path = str(tmp_path / 'events.jsonl')
+
+    with config_override(JOURNAL_PATH=path):
+        start = time.time()
+        room.add_post(user, b'one', pad64('sig1'))
+        journal.checkpoint()
+
+    # Something got changed outside of the journal:
+    room.add_post(user, b'unjournalled', pad64('sig2'))
+
+    result = replay(path, since=start)
+    assert result['verified'] == 0
+    assert len(result['mismatched']) == 1
+    assert result['mismatched'][0]['room'] == room.token
+    assert result['mismatched'][0]['actual'] == journal.room_root(room)
+
+
+def test_checkpoint_root(room, user, no_rate_limit):
+    from sogs.db import query
+
+    ids = [room.add_post(user, f'msg {i}'.encode(), pad64(f'sig {i}'))['id'] for i in range(5)]
+    assert journal.checkpoint_root(room) == journal.room_root(room)
+
+    # Rooms without changes since the last checkpoint reuse the stored root:
+    query("UPDATE journal_checkpoints SET root = 'unchanged' WHERE room = :r", r=room.id)
+    assert journal.checkpoint_root(room) == 'unchanged'
+
+    # Otherwise only the changed messages are hashed:
+    query("UPDATE journal_leaves SET hash = :h WHERE message = :m", h=b'\0' * 32, m=ids[0])
+    room.edit_post(user, ids[1], b'edited', pad64('sig e'))
+    room.delete_posts([ids[2]], user)
+    room.add_post(user, b'new', pad64('sig n'))
+    root = journal.checkpoint_root(room)
+    query("DELETE FROM journal_leaves WHERE message = :m", m=ids[0])
+    assert root != journal.room_root(room)
+
+    # Missing leaves mean that we can't trust the stored ones, and so rebuild them all:
+    room.add_post(user, b'newer', pad64('sig nn'))
+    assert journal.checkpoint_root(room) == journal.room_root(room)
@@ -1,10 +1,12 @@
 import time
 from sogs.model.room import Room
 from sogs.model.file import File
+from sogs.model.post import Post
 from sogs import utils, crypto
 import sogs.config
 from util import pad64, from_now, config_override
 from auth import x_sogs
+from nacl.signing import VerifyKey
 from request import sogs_get, sogs_post, sogs_put, sogs_post_raw, sogs_delete
 import json
 
@@ -34,6 +36,7 @@ def test_list(client, room, room2, user, user2, admin, mod, global_mod, global_a
         "created": room2.created,
         "active_users": 0,
         "active_users_cutoff": int(sogs.config.ROOM_DEFAULT_ACTIVE_THRESHOLD),
+        "online_users": 0,
         "moderators": [],
         "admins": [],
         "read": True,
@@ -55,6 +58,7 @@ def test_list(client, room, room2, user, user2, admin, mod, global_mod, global_a
         "created": room.created,
         "active_users": 0,
         "active_users_cutoff": int(sogs.config.ROOM_DEFAULT_ACTIVE_THRESHOLD),
+        "online_users": 0,
         "moderators": [mod.session_id],
         "admins": [admin.session_id],
         "read": True,
@@ -71,6 +75,7 @@ def test_list(client, room, room2, user, user2, admin, mod, global_mod, global_a
         "created": room3.created,
         "active_users": 0,
         "active_users_cutoff": int(sogs.config.ROOM_DEFAULT_ACTIVE_THRESHOLD),
+        "online_users": 0,
         "moderators": [],
         "admins": [],
         "read": False,
@@ -93,6 +98,7 @@ def test_list(client, room, room2, user, user2, admin, mod, global_mod, global_a
         "created": room4.created,
         "active_users": 0,
         "active_users_cutoff": int(sogs.config.ROOM_DEFAULT_ACTIVE_THRESHOLD),
+        "online_users": 0,
         "moderators": [],
         "admins": [],
         "read": False,
@@ -194,6 +200,7 @@ def test_visible_global_mods(client, room, user, mod, global_mod, global_admin):
         "created": room.created,
         "active_users": 0,
         "active_users_cutoff": int(sogs.config.ROOM_DEFAULT_ACTIVE_THRESHOLD),
+        "online_users": 0,
         "moderators": [mod.session_id],
         "admins": [],
         "read": True,
@@ -204,6 +211,8 @@ def test_visible_global_mods(client, room, user, mod, global_mod, global_admin):
     assert r.status_code == 200
     assert r.json == expect_room
 
+    # Polling counts as presence in the room:
+    expect_room["online_users"] = 1
     expected_for_moderator = {
         **expect_room,
         **{'default_' + x: True for x in ('accessible', 'read', 'write', 'upload')},
@@ -218,6 +227,7 @@ def test_visible_global_mods(client, room, user, mod, global_mod, global_admin):
     assert r.json == {
         'token': 'test-room',
         'active_users': 0,
+        'online_users': 1,
         'details': expected_for_moderator,
         'read': True,
         'write': True,
@@ -263,6 +273,7 @@ def test_updates(client, room, user, user2, mod, admin, global_mod, global_admin
         "created": room.created,
         "active_users": 0,
         "active_users_cutoff": int(sogs.config.ROOM_DEFAULT_ACTIVE_THRESHOLD),
+        "online_users": 0,
         "moderators": [mod.session_id],
         "admins": [admin.session_id],
         "read": True,
@@ -439,7 +450,14 @@ def test_polling(client, room, user, user2, mod, admin, global_mod, global_admin
     info_up = r.json['info_updates']
     assert info_up == 4
 
-    basic = {'token': 'test-room', 'active_users': 0, 'read': True, 'write': True, 'upload': True}
+    basic = {
+        'token': 'test-room',
+        'active_users': 0,
+        'online_users': 1,
+        'read': True,
+        'write': True,
+        'upload': True,
+    }
     details = {
         "token": "test-room",
         "name": "Test room",
@@ -449,6 +467,7 @@ def test_polling(client, room, user, user2, mod, admin, global_mod, global_admin
         "created": room.created,
         "active_users": 0,
         "active_users_cutoff": int(sogs.config.ROOM_DEFAULT_ACTIVE_THRESHOLD),
+        "online_users": 1,
         "moderators": [mod.session_id],
         "admins": [admin.session_id],
         "read": True,
@@ -484,6 +503,8 @@ def test_polling(client, room, user, user2, mod, admin, global_mod, global_admin
 
     info_up += 1
     cleanup()
+    basic['online_users'] += 1
+    details['online_users'] += 1
     r = sogs_get(client, f"/room/test-room/pollInfo/{info_up}", mod)
     assert r.status_code == 200
     assert r.json == {**basic, 'moderator': True, **defs}
@@ -491,6 +512,8 @@ def test_polling(client, room, user, user2, mod, admin, global_mod, global_admin
     cleanup()
     basic['active_users'] += 1
     details['active_users'] += 1
+    basic['online_users'] += 1
+    details['online_users'] += 1
 
     r = sogs_get(client, f"/room/test-room/pollInfo/{info_up}", admin)
     assert r.status_code == 200
@@ -622,6 +645,37 @@ def test_polling(client, room, user, user2, mod, admin, global_mod, global_admin
     assert r.json['message_sequence'] == details['message_sequence']
 
 
+def test_room_head(client, room, user, no_rate_limit):
+    room.add_post(user, b'hello', pad64(b'hello'))
+    r = client.head("/room/test-room")
+    assert r.status_code == 200
+    assert r.data == b''
+    assert r.headers['X-SOGS-Message-Sequence'] == str(Room(id=room.id).message_sequence)
+    assert r.headers['X-SOGS-Info-Updates'] == str(room.info_updates)
+
+
+def test_active_users_windows(client, room, user, user2):
+    from sogs.db import query
+
+    url = "/room/test-room"
+    assert 'active_users_windows' not in sogs_get(client, url, user).json
+
+    for u in (user, user2):
+        assert sogs_get(client, "/room/test-room/messages/recent", u).status_code == 200
+    query(
+        'UPDATE room_users SET last_active = :t WHERE "user" = :u',
+        t=time.time() - 3 * 86400,
+        u=user2.id,
+    )
+
+    with config_override(ROOM_ACTIVE_WINDOWS=[86400.0, 7 * 86400.0]):
+        r = sogs_get(client, url, user)
+    assert r.json['active_users_windows'] == [
+        {'cutoff': 86400, 'active_users': 1},
+        {'cutoff': 604800, 'active_users': 2},
+    ]
+
+
 def test_fetch_since(client, room, user, no_rate_limit):
     top_fetched = 0
     fetches = 0
@@ -764,6 +818,44 @@ def test_fetch_since_skip_deletions(client, room, user, no_rate_limit):
     assert get_and_clean_since(15) == []
 
 
+def test_fetch_since_limits(client, db, room, room2, user, no_rate_limit):
+    for i in range(1, 31):
+        room.add_post(user, f"fake data {i}".encode(), pad64(f"fake sig {i}"))
+        room2.add_post(user, f"fake data {i}".encode(), pad64(f"fake sig {i}"))
+
+    def since(r, seqno, query=''):
+        resp = sogs_get(client, f"/room/{r.token}/messages/since/{seqno}{query}", user)
+        assert resp.status_code == 200
+        return resp.json
+
+    with config_override(ROOM_POLL_LIMIT=10, ROOM_POLL_LIMIT_MAX=20):
+        assert [m['seqno'] for m in since(room, 0)] == list(range(1, 11))
+        assert len(since(room, 0, '?limit=25')) == 20
+        assert len(since(room, 0, '?limit=5')) == 5
+
+        with config_override(ROOM_OVERRIDES={room.token: {'poll_limit': 25}}):
+            # The default never exceeds the maximum:
+            assert len(since(room, 0)) == 20
+            assert len(since(room2, 0)) == 10
+
+    # Backdate the messages to one per day, so that the first 22 are past a 7.5 day retention
+    # period:
+    db.query(
+        "UPDATE messages SET posted = posted - 86400 * (30 - seqno) WHERE room = :r", r=room.id
+    )
+    assert since(room, 5, '?t=h')[0]['seqno'] == 6
+    with config_override(ROOM_MESSAGE_RETENTION=7.5 * 86400):
+        mroom = Room(token=room.token)
+        assert mroom.history_horizon() == 22
+        truncated = [{'history_truncated': True, 'seqno': mroom.message_sequence}]
+        assert since(room, 5, '?t=h') == truncated
+        assert since(room, 21, '?t=hr') == truncated
+        assert since(room, 22, '?t=h')[0]['seqno'] == 23
+        # Only for clients that ask for the marker:
+        assert since(room, 5)[0]['seqno'] == 6
+        assert since(room2, 5, '?t=h')[0]['seqno'] == 6
+
+
 def test_fetch_before(client, room, user, no_rate_limit):
     for i in range(1000):
         room.add_post(user, f"data-{i}".encode(), pad64(f"fake sig {i}"))
@@ -810,6 +902,59 @@ def test_fetch_before(client, room, user, no_rate_limit):
     assert len(r.json) == 0
 
 
+def test_fetch_around(client, room, user, no_rate_limit):
+    from sogs.db import query
+
+    for i in range(1, 21):
+        room.add_post(user, f"data-{i}".encode(), pad64(f"fake sig {i}"))
+        query("UPDATE messages SET posted = :t WHERE id = :m", t=1_600_000_000 + 10 * i, m=i)
+    room.delete_posts([11], user)
+
+    def ids(r):
+        assert r.status_code == 200
+        return [m['id'] for m in r.json]
+
+    url = "/room/test-room/messages/around"
+    assert ids(sogs_get(client, f"{url}/10?limit=5", user)) == [13, 12, 10, 9, 8]
+    assert ids(sogs_get(client, f"{url}/10?limit=1", user)) == [10]
+    assert ids(sogs_get(client, f"{url}/19?limit=6", user)) == [20, 19, 18, 17]
+    assert ids(sogs_get(client, f"{url}/2", user)) == [i for i in range(20, 0, -1) if i != 11]
+    # A deleted message isn't included, but we still get the messages around it:
+    assert ids(sogs_get(client, f"{url}/11?limit=4", user)) == [13, 12, 10, 9]
+
+    url = "/room/test-room/messages/posted_before"
+    assert ids(sogs_get(client, f"{url}/{1_600_000_000 + 55}?limit=3", user)) == [5, 4, 3]
+    assert ids(sogs_get(client, f"{url}/{1_600_000_000 + 50}?limit=3", user)) == [4, 3, 2]
+    assert ids(sogs_get(client, f"{url}/{1_600_000_000}", user)) == []
+    assert len(sogs_get(client, f"{url}/{2_000_000_000}", user).json) == 19
+
+
+def test_permalink(client, room, user, user2, mod, no_rate_limit):
+    for i in range(1, 11):
+        room.add_post(mod if i == 6 else user, f"data-{i}".encode(), pad64(f"fake sig {i}"))
+
+    url = f"/room/{room.token}/permalink"
+    r = sogs_get(client, f"{url}/5?context=2", user2)
+    assert r.status_code == 200
+    assert r.json['room'] == sogs_get(client, f"/room/{room.token}", user2).json
+    assert r.json['message'] == sogs_get(client, f"/room/{room.token}/message/5", user2).json
+    assert r.json['author'] == {'session_id': user.session_id}
+    assert [m['id'] for m in r.json['before']] == [4, 3]
+    assert [m['id'] for m in r.json['after']] == [6, 7]
+
+    r = sogs_get(client, f"{url}/6?context=0", user2)
+    assert r.json['author'] == {'session_id': mod.session_id, 'moderator': True}
+    assert r.json['before'] == [] and r.json['after'] == []
+
+    r = sogs_get(client, f"{url}/2", user2)
+    assert [m['id'] for m in r.json['before']] == [1]
+    assert [m['id'] for m in r.json['after']] == [3, 4, 5, 6, 7]
+
+    room.delete_posts([7], user)
+    assert sogs_get(client, f"{url}/7", user2).status_code == 404
+    assert sogs_get(client, f"{url}/11", user2).status_code == 404
+
+
 def test_fetch_one(client, room, user, no_rate_limit):
     posts = [room.add_post(user, f"data-{i}".encode(), pad64(f"fake sig {i}")) for i in range(10)]
 
@@ -823,6 +968,308 @@ def test_fetch_one(client, room, user, no_rate_limit):
         assert r.json == p
 
 
+def test_query_params(client, room, user, no_rate_limit):
+    for i in range(5):
+        room.add_post(user, f"data-{i}".encode(), pad64(f"fake sig {i}"))
+
+    url = "/room/test-room/messages/recent"
+    r = sogs_get(client, url + "?limit=abc", user)
+    assert r.status_code == 400
+    assert r.data == b"Invalid query parameter `limit`: expected an integer"
+
+    # Without strict validation out-of-range values are clamped and unknown parameters ignored:
+    assert len(sogs_get(client, url + "?limit=0", user).json) == 1
+    assert len(sogs_get(client, url + "?limit=1000&foo=1", user).json) == 5
+
+    with config_override(STRICT_QUERY_PARAMS=True):
+        assert len(sogs_get(client, url + "?limit=3&reactors=0", user).json) == 3
+
+        r = sogs_get(client, url + "?limit=0", user)
+        assert r.status_code == 400
+        assert r.data == b"Invalid query parameter `limit`: must be at least 1"
+
+        r = sogs_get(client, url + "?limit=1000", user)
+        assert r.status_code == 400
+        assert r.data == b"Invalid query parameter `limit`: must be at most 256"
+
+        r = sogs_get(client, url + "?limit=3&foo=1&bar=2", user)
+        assert r.status_code == 400
+        assert r.data == (
+            b"Unknown query parameters `bar`, `foo`; accepted parameters: `limit`, `reactors`"
+        )
+
+        r = sogs_get(client, "/room/test-room/message/1?t=r", user)
+        assert r.status_code == 400
+        assert r.data == b"Unknown query parameter `t`; accepted parameters: `reactors`"
+
+
+def test_message_counts(client, room, user, user2, mod, no_rate_limit):
+    from sogs.db import query
+
+    day = 86400
+    t0 = 1_600_000_000 // day * day
+    times = [t0 + 5, t0 + 100, t0 + day - 1, t0 + day, t0 + 3 * day + 3600, t0 + 3 * day + 7200]
+    ids = [room.add_post(user, f"data-{i}".encode(), pad64(f"sig {i}"))['id'] for i in times]
+    for i, t in zip(ids, times):
+        query("UPDATE messages SET posted = :t WHERE id = :m", t=t, m=i)
+
+    # Deletions and whispers aren't counted:
+    room.delete_posts([ids[1]], user)
+    room.add_post(mod, b'whisper', pad64('whisper sig'), whisper_to=user2)
+
+    url = f"/room/{room.token}/message_counts"
+    r = sogs_get(client, url, user2)
+    assert r.status_code == 200
+    assert r.json['granularity'] == 'day'
+    assert r.json['interval'] == day
+    assert r.json['counts'] == [[t0, 2], [t0 + day, 1], [t0 + 3 * day, 2]]
+
+    r = sogs_get(client, url + f"?granularity=hour&until={t0 + day}", user2)
+    assert r.json['counts'] == [[t0, 1], [t0 + day - 3600, 1]]
+
+    r = sogs_get(client, url + f"?granularity=week&since={t0 + day}&until={t0 + 4 * day}", user2)
+    assert sum(c for _, c in r.json['counts']) == 3
+
+    r = sogs_get(client, url + "?granularity=month", user2)
+    assert r.status_code == 400
+    assert r.data == b"Invalid query parameter `granularity`: expected one of: hour, day, week"
+
+
+def test_room_stats(client, room, user, user2, mod, no_rate_limit):
+    from sogs.db import query
+    from sogs import stats
+
+    day = 86400
+    t0 = 1_600_000_000 // day * day
+    times = [t0 + 5, t0 + 100, t0 + day - 1, t0 + 2 * day + 3600]
+    posters = [user, user2, user, user2]
+    ids = [
+        room.add_post(u, f"data-{t}".encode(), pad64(f"sig {t}"))['id']
+        for u, t in zip(posters, times)
+    ]
+    for i, t in zip(ids, times):
+        query("UPDATE messages SET posted = :t WHERE id = :m", t=t, m=i)
+    # Deleted posts still count as activity on the day they were posted:
+    room.delete_posts([ids[1]], user2)
+
+    f = room.upload_file(b'abc' * 100, user, filename='x.bin')
+    query("UPDATE files SET uploaded = :t WHERE id = :f", t=t0 + 2 * day + 60, f=f)
+
+    url = f"/room/{room.token}/stats"
+    assert sogs_get(client, url, mod).json == []
+
+    # Days are only rolled up once they are complete:
+    assert stats.rollup(now=t0 + 2 * day + 7200) >= 2
+    no_reports = {
+        'reports': 0,
+        'reports_resolved': 0,
+        'reports_actioned': 0,
+        'median_claim': None,
+        'median_resolution': None,
+    }
+    zero = {'messages': 0, 'posters': 0, 'uploads': 0, 'upload_bytes': 0, **no_reports}
+    day0 = {'day': t0, **zero, 'messages': 3, 'posters': 2}
+    assert sogs_get(client, url, mod).json == [day0, {'day': t0 + day, **zero}]
+
+    assert stats.rollup(now=t0 + 3 * day)
+    day2 = {'day': t0 + 2 * day, **zero, 'messages': 1, 'posters': 1, 'uploads': 1}
+    day2['upload_bytes'] = 300
+    assert sogs_get(client, url, mod).json == [day0, {'day': t0 + day, **zero}, day2]
+    # Already rolled up, so nothing more to do:
+    assert stats.rollup(now=t0 + 3 * day) == 0
+
+    r = sogs_get(client, url + f"?since={t0 + day}&until={t0 + 2 * day}", mod)
+    assert r.json == [{'day': t0 + day, **zero}]
+
+    assert sogs_get(client, url, user).status_code == 403
+
+
+def test_member_stats(client, room, user, user2, mod, no_rate_limit):
+    from sogs.db import query
+
+    ids = [room.add_post(user, f"data-{i}".encode(), pad64(f"sig {i}"))['id'] for i in range(3)]
+    for i, t in zip(ids, (1000, 2000, 3000)):
+        query("UPDATE messages SET posted = :t WHERE id = :m", t=t, m=i)
+    room.delete_posts([ids[2]], user)
+    # user2 has never posted, but has accessed the room:
+    user2.update_room_activity(room)
+
+    def blinded(u):
+        if u.session_id.startswith('05'):
+            return crypto.compute_blinded_abs_id(u.session_id)
+        return crypto.blinded_abs(u.session_id)
+
+    url = f"/room/{room.token}/member_stats"
+    assert sogs_get(client, url, user).status_code == 403
+    r = sogs_get(client, url, mod)
+    assert r.status_code == 200
+    stats = {s['session_id']: s for s in r.json}
+    assert all(sid.startswith('15') for sid in stats)
+    assert user.session_id not in stats
+    s = stats[blinded(user)]
+    assert (s['posts'], s['deleted'], s['first_post'], s['last_post']) == (2, 1, 1000, 3000)
+    s = stats[blinded(user2)]
+    assert (s['posts'], s['deleted'], s['first_post'], s['last_post']) == (0, 0, None, None)
+    assert s['last_active'] is not None
+
+    r = sogs_get(client, url + "?format=csv", mod)
+    assert r.status_code == 200
+    assert r.headers['content-type'].startswith('text/csv')
+    lines = r.data.decode().splitlines()
+    assert lines[0] == 'session_id,posts,deleted,first_post,last_post,last_active'
+    assert any(line.startswith(f"{blinded(user)},2,1,") for line in lines[1:])
+
+    assert sogs_get(client, url + "?format=xml", mod).status_code == 400
+
+
+def test_translate(client, room, user, user2, monkeypatch, no_rate_limit):
+    import sogs.translate
+    from sogs import session_pb2 as protobuf
+
+    def make_post(body):
+        msg = protobuf.Content()
+        msg.dataMessage.body = body
+        return room.add_post(user, msg.SerializeToString(), pad64(b'fake sig'))
+
+    calls = []
+
+    def fake_translate(text, lang):
+        calls.append((text, lang))
+        return f"[{lang}] {text}"
+
+    monkeypatch.setattr(sogs.translate, 'translate', fake_translate)
+
+    p1 = make_post("hello world")
+    url = f"/room/test-room/message/{p1['id']}/translate"
+
+    # Translation disabled:
+    assert sogs_get(client, url + "?lang=de", user2).status_code == 404
+
+    with config_override(TRANSLATE_URL='http://localhost:1/translate'):
+        r = sogs_get(client, url + "?lang=de", user2)
+        assert r.status_code == 200
+        assert r.json == {'id': p1['id'], 'lang': 'de', 'text': '[de] hello world'}
+        assert calls == [("hello world", "de")]
+
+        # Cached:
+        r = sogs_get(client, url + "?lang=de", user)
+        assert r.json['text'] == '[de] hello world'
+        assert len(calls) == 1
+
+        r = sogs_get(client, url + "?lang=pt-BR", user)
+        assert r.json['text'] == '[pt-BR] hello world'
+        assert len(calls) == 2
+
+        # Editing invalidates the cache:
+        msg = protobuf.Content()
+        msg.dataMessage.body = "goodbye"
+        room.edit_post(user, p1['id'], msg.SerializeToString(), pad64(b'fake sig 2'))
+        r = sogs_get(client, url + "?lang=de", user2)
+        assert r.json['text'] == '[de] goodbye'
+        assert calls[-1] == ("goodbye", "de")
+
+        for bad in ("", "?lang=", "?lang=german!", "?lang=x"):
+            assert sogs_get(client, url + bad, user2).status_code == 400
+
+        r = sogs_get(client, "/room/test-room/message/99/translate?lang=de", user)
+        assert r.status_code == 404
+
+        def broken_translate(text, lang):
+            raise sogs.translate.TranslationFailed("oops")
+
+        monkeypatch.setattr(sogs.translate, 'translate', broken_translate)
+        r = sogs_get(client, url + "?lang=fr", user2)
+        assert r.status_code == 502
+
+
+def test_room_feed(client, room, user, no_rate_limit):
+    from sogs import session_pb2 as protobuf
+
+    for body in ("First announcement", "Second <b>announcement</b>\nwith details"):
+        msg = protobuf.Content()
+        msg.dataMessage.body = body
+        msg.dataMessage.profile.displayName = "Announcer"
+        room.add_post(user, msg.SerializeToString(), pad64(b'fake sig'))
+
+    # Feeds aren't available unless enabled for the room:
+    assert client.get("/rooms/test-room/feed.xml").status_code == 404
+
+    with config_override(ROOM_OVERRIDES={'test-room': {'feed': True}}):
+        r = client.get("/rooms/test-room/feed.xml")
+        assert r.status_code == 200
+        assert r.content_type.startswith('application/atom+xml')
+        xml = r.data.decode()
+        assert xml.count('<entry>') == 2
+        assert '<title>Second &lt;b&gt;announcement&lt;/b&gt;</title>' in xml
+        assert '<name>Announcer</name>' in xml
+        assert xml.index('Second') < xml.index('First')
+
+        # Not available for sensitive rooms, nor for rooms that aren't publicly readable:
+        room.sensitive = True
+        assert client.get("/rooms/test-room/feed.xml").status_code == 404
+        room.sensitive = False
+        room.default_read = False
+        assert client.get("/rooms/test-room/feed.xml").status_code == 404
+
+
+def test_preview_mask(client, room, user, tmp_path, monkeypatch, no_rate_limit):
+    from sogs import session_pb2 as protobuf
+    import sogs.preview
+
+    words = tmp_path / "mask.txt"
+    words.write_text("darn\nheck\n")
+    monkeypatch.setattr(sogs.preview, '_masker', None)
+
+    msg = protobuf.Content()
+    msg.dataMessage.body = "Well darn it all to heck"
+    msg.dataMessage.profile.displayName = "Darn Announcer"
+    post = room.add_post(user, msg.SerializeToString(), pad64(b'fake sig'))
+
+    r = client.get("/r/test-room/recent.json")
+    assert r.status_code == 200
+    assert [(m['id'], m['author'], m['text']) for m in r.json] == [
+        (post['id'], "Darn Announcer", "Well darn it all to heck")
+    ]
+
+    overrides = {'test-room': {'feed': True}}
+    with config_override(
+        PREVIEW_MASK=True, PREVIEW_MASK_WORDS=str(words), ROOM_OVERRIDES=overrides
+    ):
+        r = client.get("/r/test-room/recent.json")
+        assert [(m['author'], m['text']) for m in r.json] == [
+            ("**** Announcer", "Well **** it all to ****")
+        ]
+        assert 'window.preview_url' in client.get("/r/test-room/").data.decode()
+
+        xml = client.get("/rooms/test-room/feed.xml").data.decode()
+        assert '<title>Well **** it all to ****</title>' in xml
+        assert 'darn' not in xml.lower()
+
+        # The messages themselves are unaffected:
+        r = sogs_get(client, f"/room/test-room/message/{post['id']}", user)
+        assert Post(raw=utils.decode_base64(r.json['data'])).text == "Well darn it all to heck"
+
+        overrides['test-room']['preview_mask'] = False
+        assert client.get("/r/test-room/recent.json").json[0]['text'] == "Well darn it all to heck"
+        assert 'window.preview_url' not in client.get("/r/test-room/").data.decode()
+
+        room.default_read = False
+        assert client.get("/r/test-room/recent.json").status_code == 403
+
+
+def test_sensitive_room_views(client, room, user, no_rate_limit):
+    room.add_post(user, b'data', pad64(b'fake sig'))
+    for path in ("/r/test-room/", "/r/test-room/recent.json", "/r/test-room/invite.png"):
+        assert client.get(path).status_code == 200
+
+    # Sensitive rooms need to be acknowledged before they can be read, so the public pages aren't
+    # available for them:
+    room.sensitive = True
+    assert client.get("/r/test-room/").status_code == 403
+    assert client.get("/r/test-room/recent.json").status_code == 403
+    assert client.get("/r/test-room/invite.png").status_code == 403
+
+
 time_fields = {'posted', 'edited', 'pinned_at', 'at'}
 
 
@@ -926,6 +1373,13 @@ def test_pinning(client, room, user, admin, no_rate_limit):
     assert rpm[0]['pinned_at'] < rpm[1]['pinned_at']
     assert rpm[1]['pinned_at'] == from_now.now()
 
+    r = sogs_delete(client, "/room/test-room/pin/7", admin)
+    assert r.status_code == 200
+    assert r.json['unpinned']
+    assert filter_timestamps(room_json()['pinned_messages']) == [
+        {'id': 3, 'pinned_by': admin.session_id}
+    ]
+
     url = "/room/test-room/unpin/all"
     r = sogs_post(client, url, {}, admin)
     assert r.status_code == 200
@@ -933,6 +1387,51 @@ def test_pinning(client, room, user, admin, no_rate_limit):
     assert 'pinned_messages' not in room_json()
 
 
+def test_pin_order_and_limit(client, room, user, admin, no_rate_limit):
+    for i in range(5):
+        room.add_post(user, f"data-{i}".encode(), pad64(f"fake sig {i}"))
+    for i in (2, 4, 1):
+        assert sogs_post(client, f"/room/test-room/pin/{i}", {}, admin).status_code == 200
+
+    def pins():
+        r = sogs_get(client, "/room/test-room", user)
+        return [p['id'] for p in r.json['pinned_messages']], r.json['info_updates']
+
+    ids, updates = pins()
+    assert ids == [2, 4, 1]
+
+    url = "/room/test-room/pins"
+    assert sogs_put(client, url, {'order': [1, 2, 4]}, user).status_code == 403
+    for bad in ([1, 2], [1, 2, 4, 5], [1, 2, 2], [1, 2, 4, 4], "1,2,4"):
+        assert sogs_put(client, url, {'order': bad}, admin).status_code == 400
+    assert pins() == (ids, updates)
+
+    r = sogs_put(client, url, {'order': [1, 2, 4]}, admin)
+    assert r.status_code == 200
+    assert r.json['info_updates'] > updates
+    ids, updates = pins()
+    assert ids == [1, 2, 4]
+    assert r.json['info_updates'] == updates
+
+    # An unchanged order doesn't update the room:
+    assert sogs_put(client, url, {'order': [1, 2, 4]}, admin).json['info_updates'] == updates
+
+    # New pins go after the existing ones:
+    assert sogs_post(client, "/room/test-room/pin/3", {}, admin).status_code == 200
+    assert pins()[0] == [1, 2, 4, 3]
+
+    with config_override(ROOM_MAX_PINS=4):
+        r = sogs_post(client, "/room/test-room/pin/5", {}, admin)
+        assert r.status_code == 409
+        # Re-pinning an already pinned message is still allowed:
+        assert sogs_post(client, "/room/test-room/pin/2", {}, admin).status_code == 200
+        assert pins()[0] == [1, 4, 3, 2]
+
+        assert sogs_delete(client, "/room/test-room/pin/4", admin).status_code == 200
+        assert sogs_post(client, "/room/test-room/pin/5", {}, admin).status_code == 200
+        assert pins()[0] == [1, 3, 2, 5]
+
+
 def test_posting(client, room, user, user2, mod, global_mod):
 
     url_post = "/room/test-room/message"
@@ -956,6 +1455,342 @@ def test_posting(client, room, user, user2, mod, global_mod):
     assert r.json == [p1]
 
 
+def test_posting_rate_limited(client, room, user):
+    import sogs.model.room as mroom
+
+    url_post = "/room/test-room/message"
+    for i in range(mroom.rate_limit_size + 1):
+        d, s = (utils.encode_base64(x) for x in (f"post {i}".encode(), pad64(f"sig {i}")))
+        r = sogs_post(client, url_post, {"data": d, "signature": s}, user)
+    assert r.status_code == 429
+    assert r.json['scope'] == 'room_post'
+    assert r.json['limit'] == mroom.rate_limit_size
+    assert r.json['remaining'] == 0
+    assert 0 < r.json['retry_after'] <= mroom.rate_limit_interval + 1
+    assert r.json['reset'] == from_now.seconds(mroom.rate_limit_interval, 2)
+    assert r.headers['Retry-After'] == str(r.json['retry_after'])
+    assert r.headers['RateLimit-Limit'] == str(mroom.rate_limit_size)
+    assert r.headers['RateLimit-Remaining'] == '0'
+
+
+def test_posting_commit_failure(client, room, user, monkeypatch):
+    import sys
+    import sqlalchemy.exc
+    from sogs import db
+
+    real_transaction = db.transaction
+    failures = []
+
+    class FailingCommit:
+        # Runs the transaction as normal, but then rolls it back instead of committing and fails
+        # the way a failed commit would.
+        def __init__(self, tx):
+            self.tx = tx
+
+        def __enter__(self):
+            return self.tx.__enter__()
+
+        def __exit__(self, *exc):
+            if exc[0] is not None:
+                return self.tx.__exit__(*exc)
+            failures.append(True)
+            err = sqlalchemy.exc.OperationalError("COMMIT", {}, Exception("disk I/O error"))
+            self.tx.__exit__(type(err), err, None)
+            raise err
+
+    def transaction(dbconn=None):
+        tx = real_transaction(dbconn)
+        return FailingCommit(tx) if sys._getframe(1).f_code.co_name == 'add_post' else tx
+
+    monkeypatch.setattr(db, 'transaction', transaction)
+
+    url_post = "/room/test-room/message"
+    d, s = (utils.encode_base64(x) for x in (b"post 1", pad64("sig 1")))
+    r = sogs_post(client, url_post, {"data": d, "signature": s}, user)
+    assert failures == [True]
+    assert r.status_code == 503
+    assert r.headers['Retry-After'] == '1'
+    assert 'id' not in (r.json or {})
+    assert sogs_get(client, "/room/test-room/messages/since/0", user).json == []
+    assert Room(token='test-room').message_sequence == 0
+
+    # Re-sending the same post once the database recovers stores it exactly once:
+    monkeypatch.setattr(db, 'transaction', real_transaction)
+    r = sogs_post(client, url_post, {"data": d, "signature": s}, user)
+    assert r.status_code == 201
+    assert r.json['seqno'] == 1
+    assert sogs_get(client, "/room/test-room/messages/since/0", user).json == [r.json]
+
+
+def test_posting_idempotency_key(client, room, room2, user, user2, no_rate_limit):
+    url_post = "/room/test-room/message"
+
+    def post(u, data, key, url=url_post):
+        d, s = (utils.encode_base64(x) for x in (data, pad64(data)))
+        return sogs_post(client, url, {"data": d, "signature": s, "idempotency_key": key}, u)
+
+    r = post(user, b"post 1", "key-1")
+    assert r.status_code == 201
+    p1 = r.json
+
+    # A retry of the same post returns the original message without posting it again:
+    r = post(user, b"post 1", "key-1")
+    assert r.status_code == 201
+    assert r.json == p1
+    assert sogs_get(client, "/room/test-room/messages/since/0", user).json == [p1]
+
+    # Keys are per user and per room:
+    r = post(user2, b"post 2", "key-1")
+    assert r.status_code == 201
+    assert r.json['id'] != p1['id']
+    r = post(user, b"post 3", "key-1", url="/room/room2/message")
+    assert r.status_code == 201
+    assert r.json['id'] != p1['id']
+
+    r = post(user, b"post 4", "key-2")
+    assert r.status_code == 201
+    assert r.json['id'] != p1['id']
+    assert len(sogs_get(client, "/room/test-room/messages/since/0", user).json) == 3
+
+    for bad in ("", "x" * 65, 123):
+        assert post(user, b"post 5", bad).status_code == 400
+
+    # Retrying a post that has since been deleted is refused, rather than re-posting it:
+    room.delete_posts([p1['id']], user)
+    r = post(user, b"post 1", "key-1")
+    assert r.status_code == 409
+    assert r.json == {'id': p1['id']}
+
+
+def test_first_post_challenge(client, room, user, user2, mod, no_rate_limit):
+    from sogs import challenge
+    from sogs.hashing import blake2b
+
+    def solve(c, difficulty):
+        n = 0
+        while True:
+            sol = n.to_bytes(8, 'big')
+            if challenge.leading_zero_bits(blake2b((c, sol), person=b'sogs.pow')) >= difficulty:
+                return sol
+            n += 1
+
+    def post(u, data, c=None, sol=None, url="/room/test-room/message"):
+        d, s = (utils.encode_base64(x) for x in (data, pad64(data)))
+        body = {"data": d, "signature": s}
+        if c is not None:
+            body['challenge'] = utils.encode_base64(c)
+            body['challenge_solution'] = utils.encode_base64(sol)
+        return sogs_post(client, url, body, u)
+
+    # Not required by default:
+    assert sogs_get(client, "/room/test-room/challenge", user).json == {'difficulty': 0}
+    assert post(user, b"hi").status_code == 201
+
+    with config_override(ROOM_OVERRIDES={'test-room': {'first_post_pow': 8}}):
+        # Users who have already posted don't need to solve one:
+        assert sogs_get(client, "/room/test-room/challenge", user).json == {'difficulty': 0}
+        assert post(user, b"hi again").status_code == 201
+        # Nor do moderators:
+        assert post(mod, b"mod post").status_code == 201
+
+        r = post(user2, b"first post")
+        assert r.status_code == 428
+        assert r.json['difficulty'] == 8
+
+        r = sogs_get(client, "/room/test-room/challenge", user2)
+        assert r.status_code == 200
+        assert r.json['difficulty'] == 8
+        assert r.json['expires'] > time.time()
+        c = utils.decode_base64(r.json['challenge'])
+        sol = solve(c, 8)
+
+        # An unsolved challenge, or one issued for another user or room, isn't accepted:
+        bad = b'\0'
+        while challenge.verify('test-room', user2.session_id, c, bad, 8):
+            bad += b'\0'
+        assert post(user2, b"first post", c, bad).status_code == 428
+        c2 = challenge.issue('room2', user2.session_id)[0]
+        assert post(user2, b"first post", c2, solve(c2, 8)).status_code == 428
+        assert not challenge.verify('test-room', user.session_id, c, sol, 8)
+
+        assert post(user2, b"first post", c, sol).status_code == 201
+        # After which no more are needed:
+        assert post(user2, b"second post").status_code == 201
+
+    # Expired challenges are rejected:
+    c, expiry = challenge.issue('test-room', user2.session_id, now=time.time() - 3600)
+    assert expiry < time.time()
+    assert not challenge.verify('test-room', user2.session_id, c, solve(c, 1), 1)
+
+
+def test_raid_mode(client, room, user, user2, mod, no_rate_limit, tmp_path):
+    from sogs import session_pb2 as protobuf
+    from sogs.db import query
+    import user as test_user
+
+    def post(u, body):
+        msg = protobuf.Content()
+        msg.dataMessage.body = body
+        d, s = (utils.encode_base64(x) for x in (msg.SerializeToString(), pad64(body)))
+        return sogs_post(client, "/room/test-room/message", {"data": d, "signature": s}, u)
+
+    query(
+        "UPDATE users SET created = :t WHERE id IN (:u1, :u2)",
+        t=time.time() - 2 * 86400,
+        u1=user.id,
+        u2=user2.id,
+    )
+    url = "/room/test-room/raid_mode"
+    assert sogs_post(client, url, {}, user).status_code == 403
+    assert sogs_post(client, url, {'duration': -5}, mod).status_code == 400
+
+    info_updates = room.info_updates
+    journal_path = tmp_path / 'events.jsonl'
+    with config_override(JOURNAL_PATH=str(journal_path)):
+        r = sogs_post(client, url, {'duration': 600}, mod)
+    assert r.status_code == 200
+    assert r.json == {'raid_mode_until': from_now.seconds(600)}
+
+    # Activation is recorded in the audit journal:
+    [ev] = [json.loads(line) for line in journal_path.read_text().splitlines()]
+    assert ev['event'] == 'raid_mode_started'
+    assert ev['room'] == 'test-room'
+    assert ev['by'] == mod.session_id
+    assert ev['until'] == r.json['raid_mode_until']
+
+    r = sogs_get(client, "/room/test-room", user)
+    assert r.json['raid_mode_until'] == from_now.seconds(600)
+    assert r.json['min_account_age'] == 86400
+    assert r.json['info_updates'] > info_updates
+    assert not r.json['upload']
+
+    assert post(user, "hello").status_code == 201
+    r = post(user, "hello again")
+    assert r.status_code == 429
+    assert r.json['limit'] == 1
+    assert post(user2, "check out https://example.com").status_code == 429
+    assert post(user2, "or example.org/spam").status_code == 429
+    assert post(user2, "no links here").status_code == 201
+    assert post(mod, "mods can post https://example.com").status_code == 201
+    assert post(mod, "as often as they like").status_code == 201
+
+    # Brand new users can't post at all:
+    user3 = test_user.User()
+    assert post(user3, "hi").status_code == 403
+
+    assert sogs_delete(client, url, user).status_code == 403
+    with config_override(JOURNAL_PATH=str(journal_path)):
+        assert sogs_delete(client, url, mod).status_code == 200
+    ev = json.loads(journal_path.read_text().splitlines()[-1])
+    assert ev['event'] == 'raid_mode_ended'
+    assert ev['by'] == mod.session_id
+    r = sogs_get(client, "/room/test-room", user)
+    assert 'raid_mode_until' not in r.json
+    assert r.json['upload']
+    assert post(user, "hello again").status_code == 201
+    assert post(user3, "hi").status_code == 201
+
+    # Raid mode ends on its own:
+    room.start_raid_mode(mod, 0.001)
+    time.sleep(0.01)
+    assert room.raid_mode_until is None
+    assert post(user2, "https://example.com").status_code == 201
+
+
+def test_role_badges(client, room, user, user2, mod, admin, global_mod, no_rate_limit):
+    for u in (user, user2, mod, admin, global_mod):
+        room.add_post(u, f"from {u.session_id}".encode(), pad64(u.session_id))
+
+    def roles(who):
+        r = sogs_get(client, "/room/test-room/messages/since/0", who)
+        assert r.status_code == 200
+        return [(m['session_id'], m.get('role')) for m in r.json]
+
+    # Off by default:
+    assert all(role is None for _, role in roles(user))
+
+    with config_override(ROOM_ROLE_BADGES=True, BRIDGE_IDS={user2.session_id}):
+        expected = [
+            (user.session_id, 'regular'),
+            (user2.session_id, 'bot'),
+            (mod.session_id, 'moderator'),
+            (admin.session_id, 'admin'),
+            # The global mod is hidden, so only mods get to see that they are a moderator:
+            (global_mod.session_id, 'regular'),
+        ]
+        assert roles(user) == expected
+        expected[4] = (global_mod.session_id, 'moderator')
+        assert roles(mod) == expected
+
+        # Role changes show up right away:
+        room.remove_moderator(mod, removed_by=admin)
+        assert roles(user)[2] == (mod.session_id, 'regular')
+
+    with config_override(ROOM_OVERRIDES={room.token: {'role_badges': True}}):
+        assert roles(user)[3] == (admin.session_id, 'admin')
+
+
+def test_anonymous_room(client, room, user, user2, mod, admin, no_rate_limit):
+    def post(u, data):
+        d, s = (utils.encode_base64(x) for x in (data, pad64(data)))
+        r = sogs_post(client, "/room/test-room/message", {"data": d, "signature": s}, u)
+        assert r.status_code == 201
+        return r.json
+
+    with config_override(ROOM_OVERRIDES={room.token: {'anonymous': True}}):
+        m1 = post(user, b'first')
+        m2 = post(user, b'second')
+        m3 = post(user2, b'third')
+        m4 = post(mod, b'from a mod')
+
+        # Pseudonyms are stable (within a window) and distinct for each poster, and posts are
+        # re-signed with the pseudonym's key:
+        pseudonym = m1['session_id']
+        assert pseudonym.startswith('15') and pseudonym != user.session_id
+        assert m2['session_id'] == pseudonym
+        assert m3['session_id'] not in (pseudonym, user2.session_id)
+        for m, data in ((m1, b'first'), (m3, b'third')):
+            VerifyKey(bytes.fromhex(m['session_id'][2:])).verify(
+                data, utils.decode_base64(m['signature'])
+            )
+
+        # Moderators post as themselves:
+        assert m4['session_id'] == mod.session_id
+
+        r = sogs_get(client, f"/room/test-room/message/{m1['id']}", user2)
+        assert r.json['session_id'] == pseudonym
+
+        # A new window gives a new pseudonym:
+        with config_override(ROOM_ANONYMOUS_WINDOW=1.0):
+            assert post(user, b'later')['session_id'] != pseudonym
+
+        # Only room admins can look up the real author:
+        for u in (user, mod):
+            r = sogs_get(client, f"/room/test-room/message/{m1['id']}/author", u)
+            assert r.status_code == 403
+        r = sogs_get(client, f"/room/test-room/message/{m1['id']}/author", admin)
+        assert r.status_code == 200
+        assert r.json == {'session_id': user.session_id}
+        r = sogs_get(client, f"/room/test-room/message/{m3['id']}/author", admin)
+        assert r.json == {'session_id': user2.session_id}
+        r = sogs_get(client, f"/room/test-room/message/{m4['id']}/author", admin)
+        assert r.status_code == 404
+
+        # Anonymous posts can't be edited or deleted by their (real) author:
+        d, s = (utils.encode_base64(x) for x in (b'edited', pad64(b'edited')))
+        r = sogs_put(
+            client, f"/room/test-room/message/{m1['id']}", {"data": d, "signature": s}, user
+        )
+        assert r.status_code == 403
+        r = sogs_delete(client, f"/room/test-room/message/{m1['id']}", user)
+        assert r.status_code == 403
+
+    # The room's posts keep their pseudonyms once it is no longer anonymous:
+    r = sogs_get(client, f"/room/test-room/message/{m2['id']}", user2)
+    assert r.json['session_id'] == pseudonym
+    assert post(user, b'named')['session_id'] == user.session_id
+
+
 def test_whisper_to(client, room, user, user2, mod, global_mod):
 
     url_post = "/room/test-room/message"
@@ -1239,6 +2074,59 @@ def test_edits(client, room, user, user2, mod, global_admin):
     assert r.json == []
 
 
+def test_edit_history(client, room, user, user2, mod):
+    url_post = "/room/test-room/message"
+    d1, s1 = (utils.encode_base64(x) for x in (b"post 1", pad64("sig 1")))
+    r = sogs_post(client, url_post, {"data": d1, "signature": s1}, user)
+    assert r.status_code == 201
+    id = r.json['id']
+
+    url_hist = f"/room/test-room/message/{id}/history"
+    r = sogs_get(client, url_hist, user)
+    assert r.status_code == 200
+    assert r.json == []
+
+    d2, s2 = (utils.encode_base64(x) for x in (b"post 1b", pad64("sig 1b")))
+    r = sogs_put(client, f"/room/test-room/message/{id}", {"data": d2, "signature": s2}, user)
+    assert r.status_code == 200
+
+    for u in (user, mod):
+        r = sogs_get(client, url_hist, u)
+        assert r.status_code == 200
+        assert [(h['data'], h['signature']) for h in r.json] == [(d1, s1)]
+        assert r.json[0]['replaced'] == from_now.now()
+
+    # Only the author and moderators can see the earlier versions:
+    r = sogs_get(client, url_hist, user2)
+    assert r.status_code == 403
+
+    r = sogs_get(client, "/room/test-room/message/999/history", mod)
+    assert r.status_code == 404
+
+
+def test_edit_history_signature(client, room, user):
+    # Stored message data has its padding stripped, but the history must return the padded data so
+    # that the original signature still verifies:
+    data = b"post 1" + b'\x80' + b'\x00' * 9
+    d, s = (utils.encode_base64(x) for x in (data, user.ed_key.sign(data).signature))
+    r = sogs_post(client, "/room/test-room/message", {"data": d, "signature": s}, user)
+    assert r.status_code == 201
+    id = r.json['id']
+
+    data2 = b"post 1b" + b'\x80' + b'\x00' * 8
+    d2, s2 = (utils.encode_base64(x) for x in (data2, user.ed_key.sign(data2).signature))
+    r = sogs_put(client, f"/room/test-room/message/{id}", {"data": d2, "signature": s2}, user)
+    assert r.status_code == 200
+
+    r = sogs_get(client, f"/room/test-room/message/{id}/history", user)
+    assert r.status_code == 200
+    [h] = r.json
+    assert utils.decode_base64(h['data']) == data
+    VerifyKey(user.ed_key.verify_key.encode()).verify(
+        utils.decode_base64(h['data']), utils.decode_base64(h['signature'])
+    )
+
+
 def _make_dummy_post(room, user):
     msg = room.add_post(user, b'data', b'a' * 64)
     return msg.get('id')
@@ -1287,8 +2175,11 @@ def test_remove_all_posts_from_room(client, room, user, mod, no_rate_limit):
     assert len(room.get_messages_for(user, recent=True)) == 256
     r = sogs_delete(client, f'/room/{room.token}/all/{user.session_id}', mod)
     assert r.status_code == 200
+    assert r.json == {'deleted': 256}
     assert len(room.get_messages_for(user, recent=True)) == 0
     assert room.check_unbanned(user)
+    deleted = [m for m in room.get_messages_for(mod, sequence=0, limit=512) if m.get('deleted')]
+    assert len(deleted) == 256
 
 
 def test_remove_all_posts_from_room_not_allowed(client, room, user, user2, no_rate_limit):
@@ -1696,3 +2587,172 @@ def test_set_room_perms_blinding(client, db, room, user, user2, mod):
         )
         assert r2.status_code == 200
         assert r2.json == r.json
+
+
+def test_room_rules_markdown(client, room, user, admin):
+    url = "/room/test-room"
+    r = sogs_put(
+        client,
+        url,
+        {
+            "description": "The **best** room <script>alert(1)</script>",
+            "rules": "# Rules\n- Be *nice*\n- No [spam](javascript:alert(1))\x07",
+        },
+        admin,
+    )
+    assert r.status_code == 200
+
+    r = sogs_get(client, url, user)
+    assert r.json['description'] == "The **best** room <script>alert(1)</script>"
+    assert r.json['rules'] == "# Rules\n- Be *nice*\n- No [spam](javascript:alert(1))"
+    assert 'description_html' not in r.json and 'rules_html' not in r.json
+
+    r = sogs_get(client, url + "?html=1", user)
+    assert r.json['description_html'] == (
+        "<p>The <strong>best</strong> room &lt;script&gt;alert(1)&lt;/script&gt;</p>"
+    )
+    assert r.json['rules_html'] == (
+        "<h3>Rules</h3>\n<ul><li>Be <em>nice</em></li><li>No [spam](javascript:alert(1))</li></ul>"
+    )
+
+    page = client.get("/r/test-room/").data.decode()
+    assert "<strong>best</strong>" in page and "<script>alert" not in page
+
+    info_updates = r.json['info_updates']
+    assert sogs_put(client, url, {"rules": ""}, admin).status_code == 200
+    r = sogs_get(client, url, user)
+    assert 'rules' not in r.json
+    assert r.json['info_updates'] == info_updates + 1
+    assert sogs_put(client, url, {"rules": 42}, admin).status_code == 400
+
+
+def test_room_tags(client, room, room2, user, admin, global_admin):
+    url = "/room/test-room"
+    assert sogs_put(client, url, {"tags": ["Gaming", "de"]}, admin).status_code == 200
+    assert sogs_get(client, url, user).json['tags'] == ['de', 'gaming']
+    r = sogs_put(client, "/room/room2", {"tags": ["de", "music"]}, global_admin)
+    assert r.status_code == 200
+
+    for bad in ("de", ["de", 7], ["no spaces"], ["x" * 33], [f"t{i}" for i in range(17)]):
+        assert sogs_put(client, url, {"tags": bad}, admin).status_code == 400
+    assert room.tags == ['de', 'gaming']
+
+    def listed(qs):
+        r = sogs_get(client, "/rooms" + qs, user)
+        assert r.status_code == 200
+        return [x['token'] for x in r.json]
+
+    assert listed("") == ['room2', 'test-room']
+    assert listed("?tag=de") == ['room2', 'test-room']
+    assert listed("?tag=DE&tag=gaming") == ['test-room']
+    assert listed("?tag=de&tag=music&tag=gaming") == []
+    assert listed("?tag=nope") == []
+
+    assert sogs_put(client, url, {"tags": []}, admin).status_code == 200
+    assert 'tags' not in sogs_get(client, url, user).json
+    assert listed("?tag=gaming") == []
+
+
+def test_room_transfer(client, room, user, user2, mod, admin, no_rate_limit):
+    from sogs.model.room import transfer_message
+
+    url = f'/room/{room.token}/transfer'
+    assert sogs_get(client, url, admin).status_code == 404
+    assert sogs_post(client, f'{url}/{user2.session_id}', {}, mod).status_code == 403
+    r = sogs_post(client, f'{url}/{user2.session_id}', {'transfer_admins': 'yes'}, admin)
+    assert r.status_code == 400
+
+    r = sogs_post(client, f'{url}/{user2.session_id}', {'transfer_admins': True}, admin)
+    assert r.status_code == 201
+    t = r.json
+    assert t['from'] == admin.session_id
+    assert t['to'] == user2.session_id
+    assert t['transfer_admins'] is True
+    assert t['expires'] > t['created']
+    assert sogs_get(client, url, user2).json == t
+    assert sogs_get(client, url, user).status_code == 404
+
+    def acceptance(u, msg):
+        return {
+            'pubkey': u.ed_key.verify_key.encode().hex(),
+            'signature': u.ed_key.sign(msg).signature.hex(),
+        }
+
+    msg = transfer_message(room.token, utils.decode_base64(t['nonce']))
+    accept = f'{url}/accept'
+    # Only the recipient can accept, and only with a valid signature by their own key:
+    assert sogs_post(client, accept, acceptance(user, msg), user).status_code == 403
+    assert sogs_post(client, accept, acceptance(user, msg), user2).status_code == 400
+    assert sogs_post(client, accept, acceptance(user2, b'nope'), user2).status_code == 400
+    assert sogs_post(client, accept, {'pubkey': 'abc'}, user2).status_code == 400
+
+    r = sogs_post(client, accept, acceptance(user2, msg), user2)
+    assert r.status_code == 200
+    assert sogs_get(client, url, user2).status_code == 404
+
+    room._refresh(perms=True)
+    assert room.owner.id == user2.id
+    assert room.check_admin(user2)
+    # transfer_admins demotes the other admins to moderators:
+    assert not room.check_admin(admin)
+    assert room.check_moderator(admin)
+
+    assert sogs_get(client, f'/room/{room.token}', user2).json['owner'] == user2.session_id
+    assert 'owner' not in sogs_get(client, f'/room/{room.token}', user).json
+
+    # The recipient of a transfer can decline it:
+    assert sogs_post(client, f'{url}/{user2.session_id}', {}, user2).status_code == 400
+    assert sogs_post(client, f'{url}/{user.session_id}', {}, user2).status_code == 201
+    assert sogs_delete(client, url, user).status_code == 200
+    assert sogs_delete(client, url, user).status_code == 404
+    assert room.owner.id == user2.id
+
+
+def test_message_lookup(client, room, user, user2, mod, no_rate_limit):
+    url_post = "/room/test-room/message"
+    sigs = {}
+    for i in range(1, 4):
+        sigs[i] = pad64(f"sig {i}")
+        d, s = (utils.encode_base64(x) for x in (f"post {i}".encode(), sigs[i]))
+        r = sogs_post(client, url_post, {"data": d, "signature": s}, user)
+        assert r.status_code == 201
+    d, s = (utils.encode_base64(x) for x in (b"whisper", pad64("whisper sig")))
+    p = {"data": d, "signature": s, "whisper_to": user2.session_id}
+    r = sogs_post(client, url_post, p, mod)
+    assert r.status_code == 201
+    whisper_id = r.json['id']
+
+    # Edit message 2 and delete message 3:
+    d, s = (utils.encode_base64(x) for x in (b"post 2 edited", pad64("sig 2 edited")))
+    r = sogs_put(client, "/room/test-room/message/2", {"data": d, "signature": s}, user)
+    assert r.status_code == 200
+    assert sogs_delete(client, "/room/test-room/message/3", user).status_code == 200
+
+    url = "/room/test-room/messages/lookup"
+    lookup = [
+        sigs[1],
+        sigs[2],
+        pad64("sig 2 edited"),
+        sigs[3],
+        pad64("never posted"),
+        pad64("whisper sig"),
+    ]
+    r = sogs_post(client, url, {"signatures": [utils.encode_base64(s) for s in lookup]}, user)
+    assert r.status_code == 200
+    assert r.json == [
+        {'id': 1, 'status': 'exists'},
+        {'id': 2, 'status': 'edited'},
+        {'id': 2, 'status': 'exists'},
+        {'id': 3, 'status': 'deleted'},
+        None,
+        None,  # A whisper to someone else
+    ]
+
+    # The whisper's recipient can see it:
+    r = sogs_post(client, url, {"signatures": [utils.encode_base64(pad64("whisper sig"))]}, user2)
+    assert r.json == [{'id': whisper_id, 'status': 'exists'}]
+
+    for bad in ({}, {"signatures": []}, {"signatures": ["not base64!"]}, {"signatures": ["AAAA"]}):
+        assert sogs_post(client, url, bad, user).status_code == 400
+    r = sogs_post(client, url, {"signatures": [utils.encode_base64(sigs[1])] * 257}, user)
+    assert r.status_code == 400
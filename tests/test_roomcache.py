@@ -0,0 +1,73 @@
+from sogs import metrics, roomcache
+from sogs.model.room import Room
+from util import pad64, config_override
+
+
+def recent(room, user):
+    return [(m['id'], m['data']) for m in room.get_messages_for(user, recent=True)]
+
+
+def test_message_cache(room, user, user2, mod, no_rate_limit):
+    roomcache.clear()
+    ids = [room.add_post(user, f"msg {i}".encode(), pad64(f"sig {i}"))['id'] for i in range(5)]
+    w = room.add_post(mod, b'whisper', pad64('whisper sig'), whisper_to=user2)['id']
+    wm = room.add_post(mod, b'mods only', pad64('mod whisper sig'), whisper_mods=True)['id']
+
+    expected = {u: recent(room, u) for u in (user, user2, mod)}
+    assert w not in [i for i, _ in expected[user]]
+    assert w in [i for i, _ in expected[user2]]
+    assert wm not in [i for i, _ in expected[user2]]
+    assert wm in [i for i, _ in expected[mod]]
+
+    with config_override(ROOM_MESSAGE_CACHE=256):
+        hits = metrics.counter('roomcache.messages.cache_hits')
+        misses = metrics.counter('roomcache.messages.cache_misses')
+        for u in (user, user2, mod):
+            assert recent(room, u) == expected[u]
+        assert metrics.counter('roomcache.messages.cache_misses') == misses + 1
+        assert metrics.counter('roomcache.messages.cache_hits') == hits + 2
+
+        # Any change to the room replaces the cached messages:
+        room.edit_post(user, ids[0], b'edited', pad64('edit sig'))
+        assert (ids[0], b'edited') in recent(room, user)
+        assert metrics.counter('roomcache.messages.cache_misses') == misses + 2
+
+        room.delete_posts([ids[1]], user)
+        assert ids[1] not in [i for i, _ in recent(room, user)]
+
+        new = room.add_post(user2, b'new post', pad64('new sig'))['id']
+        assert recent(room, user)[0] == (new, b'new post')
+
+        # Requests for more than we cache go to the database:
+        assert [m['id'] for m in room.get_messages_for(user, recent=True, limit=300)] == [
+            i for i, _ in recent(room, user)
+        ]
+        assert metrics.counter('roomcache.messages.cache_misses') == misses + 4
+
+
+def mods(room, user):
+    # A fresh Room, as Room objects keep their own copy of what they've fetched:
+    return Room(token=room.token).get_mods(user)
+
+
+def test_info_cache(room, user, user2, mod, admin):
+    roomcache.clear()
+    with config_override(ROOM_INFO_CACHE=True):
+        hits = metrics.counter('roomcache.info.cache_hits')
+        assert mods(room, user) == ([mod.session_id], [admin.session_id], [], [])
+        assert mods(room, mod) == ([mod.session_id], [admin.session_id], [], [])
+        assert metrics.counter('roomcache.info.cache_hits') == hits + 1
+
+        room.set_moderator(user2, added_by=admin, visible=False)
+        assert mods(room, user) == ([mod.session_id], [admin.session_id], [], [])
+        assert mods(room, mod) == (
+            [mod.session_id],
+            [admin.session_id],
+            [user2.session_id],
+            [],
+        )
+
+        msg = room.add_post(user, b'pin me', pad64('pin sig'))['id']
+        assert Room(token=room.token).pinned_messages == []
+        room.pin(msg, admin)
+        assert [p['id'] for p in Room(token=room.token).pinned_messages] == [msg]
@@ -4,8 +4,8 @@ import sogs.model.exc as exc
 from sogs.model.room import Room, get_rooms
 from sogs.model.file import File
 from sogs import config
-from request import sogs_put
-from util import pad64, from_now
+from request import sogs_get, sogs_post, sogs_put
+from util import pad64, from_now, config_override
 
 
 def test_create(room, room2):
@@ -35,6 +35,24 @@ def test_create(room, room2):
         Room.create('room2', name='x', description=None)
 
 
+def test_room_limits(room, room2, user, user2, global_admin):
+    from sogs.db import query
+
+    with config_override(ROOM_MAX_COUNT=2):
+        with pytest.raises(exc.RoomLimitReached):
+            Room.create('room3', name='Room 3')
+        Room.create('room3', name='Room 3', ignore_limits=True)
+    Room.create('room4', name='Room 4')
+
+    query("UPDATE rooms SET owner = :u WHERE id = :r", u=user2.id, r=room.id)
+    with config_override(ROOM_MAX_OWNED=1):
+        with pytest.raises(exc.RoomLimitReached):
+            room2.start_transfer(user2, by=global_admin)
+        room2.start_transfer(user, by=global_admin)
+        room2.start_transfer(global_admin, by=global_admin)
+    room2.start_transfer(user2, by=global_admin)
+
+
 def test_token_insensitive(room):
 
     r = Room.create('Test_Ro-om', name='TR2', description='Test suite testing room2')
@@ -511,6 +529,115 @@ def test_upload_expiry(room, user):
     assert not os.path.exists(file.path)
 
 
+def test_message_retention(room, room2, user, admin, no_rate_limit):
+    from sogs.cleanup import cleanup
+    from sogs.db import query
+
+    fid = room.upload_file(content=b'abc', uploader=user, filename="abc.txt")
+    old = room.add_post(user, b'old', pad64('old'), files=[fid])['id']
+    pinned = room.add_post(user, b'pinned', pad64('pinned'))['id']
+    room.pin(pinned, admin)
+    old2 = room2.add_post(user, b'old2', pad64('old2'))['id']
+    query(
+        "UPDATE messages SET posted = posted - 3 * 86400 WHERE id IN :ids",
+        ids=[old, pinned, old2],
+        bind_expanding=['ids'],
+    )
+    new = room.add_post(user, b'new', pad64('new'))['id']
+
+    assert room.message_retention is None
+    assert room.delete_expired_posts() == []
+
+    with config_override(
+        ROOM_MESSAGE_RETENTION=2 * 86400.0, ROOM_OVERRIDES={'room2': {'message_retention': 0}}
+    ):
+        assert room.message_retention == 2 * 86400.0
+        assert room2.message_retention is None
+        assert cleanup() == (1, 0, 0, 0, 0)
+
+    assert [m['id'] for m in room.get_messages_for(user, recent=True)] == [new, pinned]
+    assert [m['id'] for m in room2.get_messages_for(user, recent=True)] == [old2]
+    with pytest.raises(exc.NoSuchFile):
+        File(id=fid)
+
+
+def test_upload_dedup(room, room2, user, user2):
+
+    import os
+    from sogs.cleanup import cleanup
+    from sogs.db import query
+
+    f1 = File(id=room.upload_file(content=b'meme', uploader=user, filename="a.jpg", lifetime=-1))
+    f2 = File(id=room2.upload_file(content=b'meme', uploader=user2, filename="b.jpg"))
+    f3 = File(id=room.upload_file(content=b'other', uploader=user, filename="a.jpg"))
+
+    assert f1.path == f'{config.UPLOAD_PATH}/{room.token}/{f1.id}_a.jpg'
+    assert f2.path == f1.path
+    assert f2.filename == 'b.jpg'
+    assert f2.room.id == room2.id
+    assert f3.path != f1.path
+    assert query("SELECT COUNT(*) FROM file_blobs").first()[0] == 2
+
+    with config_override(UPLOAD_DEDUP=False):
+        f4 = File(id=room.upload_file(content=b'meme', uploader=user))
+        assert f4.path == f'{config.UPLOAD_PATH}/{room.token}/{f4.id}_(unnamed)'
+
+    # f1 expired, but the content is still used by f2 so must stick around:
+    assert cleanup() == (1, 0, 0, 0, 0)
+    assert os.path.isfile(f2.path)
+    assert File(id=f2.id).read() == b'meme'
+
+    f2.set_expiry(-1)
+    assert cleanup() == (1, 0, 0, 0, 0)
+    assert not os.path.exists(f2.path)
+    assert query("SELECT COUNT(*) FROM file_blobs").first()[0] == 1
+    assert os.path.isfile(f3.path)
+    assert os.path.isfile(f4.path)
+
+
+def test_cold_storage(room, user, tmp_path):
+
+    import os
+    from sogs import storage
+    from sogs.db import query
+
+    content = b'compressible ' * 1000
+    f1 = File(id=room.upload_file(content=content, uploader=user, filename="a.txt", lifetime=None))
+    f2 = File(id=room.upload_file(content=b'recent', uploader=user, lifetime=None))
+    query("UPDATE files SET uploaded = uploaded - 40 * 86400 WHERE id = :f", f=f1.id)
+
+    assert storage.archive_cold_files() == 0  # Disabled by default
+
+    with config_override(UPLOAD_COLD_AFTER=30 * 86400.0, UPLOAD_COLD_PATH=str(tmp_path)):
+        assert storage.archive_cold_files() == 1
+        assert not os.path.exists(f1.path)
+        assert os.path.isfile(f2.path)
+
+        cold_path, compressed, stored_size = query(
+            "SELECT cold_path, compressed, stored_size FROM cold_files WHERE path = :p", p=f1.path
+        ).first()
+        assert cold_path.startswith(f"{tmp_path}/cold/")
+        assert compressed
+        assert os.path.getsize(cold_path) == stored_size < len(content)
+
+        assert storage.tier_stats() == {
+            'hot': {'files': 1, 'size': 6},
+            'cold': {'files': 1, 'size': len(content), 'stored_size': stored_size},
+        }
+
+        # Already cold, so nothing more to do:
+        assert storage.archive_cold_files() == 0
+
+        # Accessing it restores it to regular storage:
+        assert File(id=f1.id).read() == content
+        assert os.path.isfile(f1.path)
+        assert not os.path.exists(cold_path)
+        assert storage.tier_stats()['cold'] == {'files': 0, 'size': 0, 'stored_size': 0}
+
+        # Recently restored, so it doesn't go straight back to cold storage:
+        assert storage.archive_cold_files() == 0
+
+
 def test_image(room, user):
 
     assert room.image is None
@@ -642,3 +769,256 @@ def test_active_users(room, user, user2):
 
     assert room.active_users == 2
     assert room.active_users_last(1) == 2
+
+
+def test_online_users(room, room2, user, user2):
+    assert room.online_users == 0
+    user.update_room_activity(room)
+    assert room.online_users == 1  # Updates immediately, unlike active_users
+    user.update_room_activity(room)
+    assert room.online_users == 1
+    user2.update_room_activity(room)
+    assert room.online_users == 2
+    assert room2.online_users == 0
+
+    with config_override(ROOM_PRESENCE_TIMEOUT=0.01):
+        time.sleep(0.02)
+        assert room.online_users == 0
+        user2.update_room_activity(room2)
+        assert room2.online_users == 1
+
+
+def test_min_account_age(room, room2, user, user2, mod, no_rate_limit):
+    user.created = time.time() - 7200
+
+    with config_override(ROOM_MIN_ACCOUNT_AGE=3600):
+        assert room.min_account_age == 3600
+        room.add_post(user, b'hello', pad64('sig1'))
+        with pytest.raises(exc.AccountTooNew) as e:
+            room.add_post(user2, b'hello', pad64('sig2'))
+        assert e.value.allowed_at == from_now.seconds(3600, 2)
+        with pytest.raises(exc.BadPermission):
+            room.upload_file(b'abc', user2)
+
+        # Moderators, and users explicitly granted write permission, are exempt:
+        room.add_post(mod, b'hello', pad64('sig3'))
+        room.set_permissions(user2, mod=mod, write=True)
+        room.add_post(user2, b'hello', pad64('sig4'))
+        room.upload_file(b'abc', user2)
+
+    with config_override(ROOM_OVERRIDES={room2.token: {'min_account_age': 3 * 3600}}):
+        assert room.min_account_age is None
+        assert room2.min_account_age == 3 * 3600
+        with pytest.raises(exc.AccountTooNew):
+            room2.add_post(user, b'hello', pad64('sig5'))
+
+    # A room override of 0 disables the server-wide minimum:
+    overrides = {room2.token: {'min_account_age': 0}}
+    with config_override(ROOM_MIN_ACCOUNT_AGE=3600, ROOM_OVERRIDES=overrides):
+        room2.add_post(user2, b'hello', pad64('sig6'))
+
+
+def test_link_domains():
+    from sogs.model.room import link_domains
+
+    assert link_domains("visit Example.COM, or example.org/x.") == ['example.com', 'example.org']
+    assert link_domains("https://a.example/ www.b.example sub.c.example/") == [
+        'a.example',
+        'www.b.example',
+        'sub.c.example',
+    ]
+    assert link_domains("not links: e.g. version 1.2.3, or bob@example.com...ok") == []
+
+
+def test_link_policy(room, room2, user, mod, no_rate_limit):
+    from sogs import session_pb2 as protobuf
+
+    def post(r, u, body):
+        msg = protobuf.Content()
+        msg.dataMessage.body = body
+        return r.add_post(u, msg.SerializeToString(), pad64(body))
+
+    post(room, user, "anything goes: https://example.com/x and evil.net/y")
+
+    with config_override(LINK_POLICY='none'):
+        with pytest.raises(exc.PostRejected, match='links are not permitted in this room'):
+            post(room, user, "see www.example.com")
+        post(room, user, "no links, e.g. this one")
+        post(room, mod, "mods may link https://example.com")
+
+    with config_override(LINK_POLICY='allowlist', LINK_DOMAINS={'getsession.org'}):
+        post(room, user, "https://getsession.org and https://docs.getsession.org/x")
+        with pytest.raises(exc.PostRejected, match='links to getsession.org.evil.net are not'):
+            post(room, user, "https://getsession.org.evil.net/")
+        with pytest.raises(exc.PostRejected, match='links to this location are not'):
+            post(room, user, "file:///etc/passwd")
+
+    overrides = {room2.token: {'link_policy': 'denylist', 'link_domains': {'evil.net'}}}
+    with config_override(ROOM_OVERRIDES=overrides):
+        assert room.link_policy == ('allow', set())
+        assert room2.link_policy == ('denylist', {'evil.net'})
+        post(room, user, "evil.net/y")
+        post(room2, user, "https://example.com")
+        with pytest.raises(exc.PostRejected, match='links to www.evil.net are not'):
+            post(room2, user, "https://getsession.org and http://WWW.Evil.Net:8080/")
+
+        # Edits are checked too:
+        msg = post(room2, user, "no link")
+        edit = protobuf.Content()
+        edit.dataMessage.body = "now with sub.evil.net/ link"
+        with pytest.raises(exc.PostRejected):
+            room2.edit_post(user, msg['id'], edit.SerializeToString(), pad64('sig'))
+
+
+def test_text_limits(room, user, mod, no_rate_limit):
+    from sogs import session_pb2 as protobuf
+
+    def post(u, body, name='Alice'):
+        msg = protobuf.Content()
+        msg.dataMessage.body = body
+        msg.dataMessage.profile.displayName = name
+        return room.add_post(u, msg.SerializeToString(), pad64(body))
+
+    flags = '\U0001f1e8\U0001f1e6' * 5
+    with config_override(MESSAGE_MAX_GRAPHEMES=5, DISPLAY_NAME_MAX_GRAPHEMES=8):
+        post(user, flags)
+        with pytest.raises(exc.PostRejected, match='message is too long'):
+            post(user, flags + '!')
+        with pytest.raises(exc.PostRejected, match='display name is too long'):
+            post(user, 'hi', name='Alice Smith')
+        post(mod, 'mods are not limited', name='Moderator Bob')
+
+    post(user, 'hi', name='Adm\u200bin')
+    with config_override(DISPLAY_NAME_STRICT=True):
+        with pytest.raises(exc.PostRejected, match='display name contains invalid characters'):
+            post(user, 'hi', name='Adm\u200bin')
+        post(user, 'hi', name='Jos\u00e9')
+
+    # Server-stored text is normalized:
+    room.name = 'Caf\u00e9\u202e Room'
+    assert Room(id=room.id).name == 'Caf\u00e9 Room'
+
+
+def test_signature_verification(room, user, no_rate_limit):
+    import sogs.crypto
+
+    data = b'Hello world'
+    # Not verified by default:
+    room.add_post(user, data, pad64('not a signature'))
+
+    with config_override(MESSAGE_VERIFY_SIGNATURES=True):
+        with pytest.raises(exc.InvalidData):
+            room.add_post(user, data, pad64('not a signature'))
+        msg = room.add_post(user, data, sogs.crypto.xed25519_sign(user.a, data))
+
+        edit = b'Hello again'
+        with pytest.raises(exc.InvalidData):
+            room.edit_post(user, msg['id'], edit, sogs.crypto.xed25519_sign(user.a, data))
+        room.edit_post(user, msg['id'], edit, sogs.crypto.xed25519_sign(user.a, edit))
+
+
+def test_sensitive(client, room, user, mod, no_rate_limit):
+    assert not room.sensitive
+    room.sensitive = True
+    assert Room(id=room.id).sensitive
+    assert not room.check_read(user) and not room.check_write(user)
+    assert room.check_accessible(user)
+    assert room.check_read(mod) and room.check_write(mod)
+
+    url = f"/room/{room.token}"
+    r = sogs_get(client, url, user)
+    assert (r.json['sensitive'], r.json['acknowledged'], r.json['read']) == (True, False, False)
+    assert sogs_get(client, f"{url}/messages/recent", user).status_code == 403
+
+    r = sogs_post(client, f"{url}/acknowledge", {}, user)
+    assert r.status_code == 200
+    assert r.json['read'] and r.json['write']
+    assert sogs_get(client, url, user).json['acknowledged']
+    assert sogs_get(client, f"{url}/messages/recent", user).status_code == 200
+    Room(id=room.id).add_post(user, b'data', pad64('sig'))
+
+    room.sensitive = False
+    assert 'sensitive' not in sogs_get(client, url, user).json
+
+
+def test_message_kinds(room, user, mod, admin, no_rate_limit):
+    import json
+    from sogs import crypto
+
+    m1 = room.add_post(user, b'hello', pad64('sig1'))
+    assert 'kind' not in m1
+    m2 = room.add_post(user, b'beep boop', pad64('sig2'), kind='bot')
+    assert m2['kind'] == 'bot'
+    for kind in ('system', 'image', 'nonsense'):
+        with pytest.raises(exc.InvalidData):
+            room.add_post(user, b'hi', pad64('sig3'), kind=kind)
+
+    # System messages are off by default:
+    room.ban_user(user, mod=mod, timeout=60)
+    assert [m.get('kind') for m in room.get_messages_for(mod, recent=True)] == ['bot', None]
+
+    with config_override(ROOM_OVERRIDES={room.token: {'system_messages': True}}):
+        assert room.system_messages
+        room.unban_user(user, mod=mod)
+        room.ban_user(user, mod=mod, timeout=60)
+        room.name = 'Renamed Room'
+        room.pin(m1['id'], admin)
+
+    msgs = room.get_messages_for(None, after=m2['id'])
+    assert [m.get('kind') for m in msgs] == ['system'] * 3
+    assert {m['session_id'] for m in msgs} == {'ff' + crypto.server_pubkey_hex}
+    assert [json.loads(bytes(m['data'])) for m in msgs] == [
+        {'event': 'user_banned', 'session_id': user.session_id, 'timeout': 60},
+        {'event': 'room_renamed', 'name': 'Renamed Room'},
+        {'event': 'message_pinned', 'id': m1['id']},
+    ]
+    for m in msgs:
+        crypto.server_verifykey.verify(bytes(m['data']), bytes(m['signature']))
+
+    assert room.get_messages_for(None, after=m2['id'], system=False) == []
+
+
+def test_moderation_notices(room, user, user2, mod, admin, no_rate_limit):
+    import json
+    import user as test_user
+
+    def notices(after):
+        return [
+            json.loads(bytes(m['data']))
+            for m in room.get_messages_for(None, after=after)
+            if m.get('kind') == 'system'
+        ]
+
+    m1 = room.add_post(user, b'spam', pad64('sig1'))
+    m2 = room.add_post(mod, b'oops', pad64('sig2'))
+    m3 = room.add_post(user2, b'more spam', pad64('sig3'))
+
+    with config_override(ROOM_SYSTEM_MESSAGES=True):
+        room.delete_posts([m1['id'], m2['id']], mod)
+        room.delete_all_posts(user2, deleter=mod)
+        room.description = 'New description'
+        room.default_upload = False
+        room.set_moderator(test_user.User(), added_by=admin, visible=False)
+        new_mod = test_user.User()
+        room.set_moderator(new_mod, added_by=admin, admin=True)
+
+    assert notices(m3['id']) == [
+        {'event': 'messages_deleted', 'ids': [m1['id']]},
+        {'event': 'messages_deleted', 'ids': [m3['id']]},
+        {'event': 'settings_changed', 'description': 'New description'},
+        {'event': 'settings_changed', 'upload': False},
+        {'event': 'moderator_added', 'session_id': new_mod.session_id, 'admin': True},
+    ]
+
+    # Changes made on the server's behalf are attributed to the administrator making them:
+    from sogs import journal
+    from sogs.model.user import SystemUser
+
+    with config_override(ROOM_SYSTEM_MESSAGES=True), journal.acting_as('cli:alice'):
+        room.ban_user(user2, mod=SystemUser())
+    assert notices(m3['id'])[-1] == {
+        'event': 'user_banned',
+        'session_id': user2.session_id,
+        'timeout': None,
+        'actor': 'cli:alice',
+    }
@@ -1,6 +1,10 @@
 from sogs.model import capabilities as core_caps
-from sogs import utils
+from sogs import __version__, crypto, utils
 from sogs.web import app
+from auth import x_sogs_for
+from util import config_override
+import os
+import time
 
 
 def test_capabilities(client):
@@ -25,6 +29,41 @@ def test_capabilities(client):
     assert r.json == {"capabilities": sorted(core_caps)}
 
 
+def test_version(client):
+    r = client.get("/version")
+    assert r.status_code == 200
+    assert r.json['version'] == __version__
+    assert r.json['onion_request_versions'] == [3, 4]
+    assert r.json['capabilities'] == sorted(core_caps)
+    commit = r.json['commit']
+    assert commit is None or len(commit) == 40 and all(c in '0123456789abcdef' for c in commit)
+
+
+def test_time(client):
+    before = time.time()
+    r = client.get("/time")
+    assert r.status_code == 200
+    assert before <= r.json['timestamp'] <= time.time()
+    assert r.json['timestamp_ms'] == int(r.json['timestamp'] * 1000)
+    assert 'signature' not in r.json
+
+    nonce = os.urandom(16)
+    r = client.get(f"/time?nonce={nonce.hex()}")
+    assert r.status_code == 200
+    assert r.json['ed25519_pubkey'] == crypto.server_verifykey.encode().hex()
+    crypto.server_verifykey.verify(
+        b'sogs.time' + nonce + str(r.json['timestamp_ms']).encode(),
+        utils.decode_base64(r.json['signature']),
+    )
+
+    r = client.get(f"/time?nonce={utils.encode_base64(nonce)}")
+    assert r.status_code == 200
+    assert 'signature' in r.json
+
+    r = client.get("/time?nonce=abcd")
+    assert r.status_code == 400
+
+
 def expected_result(code, body, ct="application/json"):
     return {"code": code, "headers": {"content-type": ct}, "body": body}
 
@@ -169,3 +208,103 @@ def test_sequence(client):
     d3, b3_exp = batch_data3()
     s3 = client.post("/sequence", json=d3)
     assert s3.json == until_bad_code(b3_exp)
+
+
+def test_unhandled_exception(client, monkeypatch):
+    from sogs import metrics
+
+    def boom():
+        raise RuntimeError("oops")
+
+    monkeypatch.setitem(app.view_functions, 'general.get_caps', boom)
+    before = metrics.counter('http.unhandled_exceptions')
+
+    r = client.get("/capabilities")
+    assert r.status_code == 500
+    assert r.json['error'] == 'Internal server error'
+    assert r.json['request_id'] == r.headers['X-Request-ID']
+    assert len(r.json['request_id']) == 16
+    assert metrics.counter('http.unhandled_exceptions') == before + 1
+
+    # Regular http errors are unaffected:
+    r = client.get("/no/such/endpoint")
+    assert r.status_code == 404
+    assert 'X-Request-ID' in r.headers
+    assert metrics.counter('http.unhandled_exceptions') == before + 1
+
+
+def test_json_errors(client, room, user, monkeypatch):
+    import sqlalchemy.exc
+
+    json_accept = {'Accept': 'application/json'}
+
+    # Plain text errors by default:
+    url = "/room/test-room/messages/recent?limit=x"
+    r = client.get(url, headers=x_sogs_for(user, "GET", url))
+    assert r.status_code == 400
+    assert r.data == b"Invalid query parameter `limit`: expected an integer"
+
+    # ... but JSON if the client prefers it:
+    r = client.get(url, headers={**x_sogs_for(user, "GET", url), **json_accept})
+    assert r.status_code == 400
+    assert r.json == {
+        'status_code': 400,
+        'error': "Invalid query parameter `limit`: expected an integer",
+    }
+
+    r = client.get("/no/such/endpoint", headers=json_accept)
+    assert r.json == {'status_code': 404, 'error': 'Not Found'}
+
+    r = client.get("/room/no-such-room", headers=json_accept)
+    assert r.status_code == 404
+    assert r.json['status_code'] == 404
+
+    # Successful responses are untouched:
+    r = client.get("/capabilities", headers=json_accept)
+    assert r.status_code == 200
+    assert 'status_code' not in r.json
+
+    # Database failures are reported as temporary:
+    def db_down():
+        raise sqlalchemy.exc.OperationalError("SELECT", {}, Exception("database is locked"))
+
+    monkeypatch.setitem(app.view_functions, 'general.get_caps', db_down)
+    r = client.get("/capabilities")
+    assert r.status_code == 503
+    assert r.headers['Retry-After'] == '5'
+    r = client.get("/capabilities", headers=json_accept)
+    assert r.json == {'status_code': 503, 'error': 'Database temporarily unavailable'}
+
+
+def test_robots(client, room):
+    r = client.get("/robots.txt")
+    assert r.status_code == 200
+    assert r.content_type.startswith('text/plain')
+    assert r.data.decode().splitlines() == [
+        "User-agent: *",
+        "Allow: /$",
+        "Allow: /r/",
+        "Allow: /static/",
+        "Disallow: /",
+    ]
+    assert 'X-Robots-Tag' not in client.get("/r/test-room/").headers
+
+    with config_override(WEB_ROBOTS='noindex'):
+        assert "Disallow: /" in client.get("/robots.txt").data.decode().splitlines()
+        assert client.get("/r/test-room/").headers['X-Robots-Tag'] == 'noindex, nofollow'
+
+    with config_override(WEB_ROBOTS='deny'):
+        assert client.get("/robots.txt").data.decode().splitlines() == [
+            "User-agent: *",
+            "Disallow: /",
+        ]
+        assert client.get("/r/test-room/").headers['X-Robots-Tag'] == 'noindex, nofollow'
+
+
+def test_static_caching(client, room):
+    page = client.get("/r/test-room/").data.decode()
+    assert f'/static/view_room.js?v={__version__}' in page
+
+    r = client.get(f"/static/view_room.js?v={__version__}")
+    assert r.status_code == 200
+    assert r.cache_control.max_age == 86400
@@ -0,0 +1,86 @@
+import calendar
+import pytest
+from request import sogs_get, sogs_post
+from util import config_override
+from sogs import scheduler
+from sogs.cron import Schedule
+
+
+def ts(*args):
+    return calendar.timegm(args + (0,) * (6 - len(args)))
+
+
+def test_cron_schedule():
+    now = ts(2026, 10, 14, 12, 0, 3)  # A Wednesday
+    assert Schedule('*/10 * * * * *').next_after(now) == ts(2026, 10, 14, 12, 0, 10)
+    assert Schedule('* * * * *').next_after(now) == ts(2026, 10, 14, 12, 1)
+    assert Schedule('*/15 * * * *').next_after(now) == ts(2026, 10, 14, 12, 15)
+    assert Schedule('0 4 * * *').next_after(now) == ts(2026, 10, 15, 4)
+    assert Schedule('0 12 * * *').next_after(ts(2026, 10, 14, 12)) == ts(2026, 10, 15, 12)
+    assert Schedule('30 2 * * 0').next_after(now) == ts(2026, 10, 18, 2, 30)
+    assert Schedule('30 2 * * 7').next_after(now) == ts(2026, 10, 18, 2, 30)
+    assert Schedule('0 0 1-7/3 * *').next_after(now) == ts(2026, 11, 1)
+    assert Schedule('0 9,17 * 1 *').next_after(now) == ts(2027, 1, 1, 9)
+    # Restricting both day-of-month and day-of-week matches either:
+    assert Schedule('0 0 1 * 1').next_after(now) == ts(2026, 10, 19)
+    assert Schedule('0 0 29 2 *').next_after(now) == ts(2028, 2, 29)
+    assert Schedule('0 0 31 2 *').next_after(now) is None
+
+    for bad in ('* * *', '61 * * * *', '*/0 * * * *', 'a * * * *', '0 0 0 * *', '5-1 * * * *'):
+        with pytest.raises(ValueError):
+            Schedule(bad)
+
+
+def test_jobs(client, db, user, global_admin, monkeypatch):
+    calls = []
+    monkeypatch.setitem(
+        scheduler.JOBS, 'digests', (lambda: calls.append(1) or 42, 'SCHEDULE_DIGESTS', "Digests")
+    )
+    monkeypatch.setattr(scheduler, '_state', {})
+
+    assert sogs_get(client, '/admin/jobs', user).status_code == 403
+    r = sogs_get(client, '/admin/jobs', global_admin)
+    assert r.status_code == 200
+    assert set(r.json) == {
+        'cleanup',
+        'cold_storage',
+        'digests',
+        'directory',
+        'event_hooks',
+        'import_backfill',
+        'journal_checkpoint',
+        'room_archive',
+        'stats_rollup',
+        'upgrade_check',
+        'vacuum',
+    }
+    assert r.json['cleanup']['schedule'] == '*/10 * * * * *'
+    assert r.json['vacuum'] == {
+        'description': "Vacuums the database",
+        'schedule': None,
+        'next_run': None,
+    }
+
+    assert sogs_post(client, '/admin/jobs/digests', {}, user).status_code == 403
+    assert sogs_post(client, '/admin/jobs/nope', {}, global_admin).status_code == 404
+    r = sogs_post(client, '/admin/jobs/digests', {}, global_admin)
+    assert r.status_code == 200
+    assert r.json['job'] == 'digests'
+    assert r.json['result'] == 42
+    assert calls == [1]
+    assert 'last_run' in sogs_get(client, '/admin/jobs', global_admin).json['digests']
+
+    # Jobs only run from tick() once due:
+    state = scheduler._state['digests']
+    scheduler.tick()
+    assert calls == [1]
+    state['next'] = 0
+    scheduler.tick()
+    assert calls == [1, 1]
+    assert state['next'] > 0
+
+    # Changing the schedule takes effect, and an empty schedule disables the job:
+    with config_override(SCHEDULE_DIGESTS=None):
+        scheduler.tick()
+        assert scheduler._state['digests']['next'] is None
+        assert sogs_get(client, '/admin/jobs', global_admin).json['digests']['schedule'] is None
@@ -0,0 +1,67 @@
+from sogs import shutdown
+
+
+def test_shutdown_hooks(monkeypatch):
+    monkeypatch.setattr(shutdown, '_hooks', [])
+    monkeypatch.setattr(shutdown, '_done', False)
+
+    calls = []
+
+    @shutdown.on_shutdown
+    def first():
+        calls.append('first')
+
+    @shutdown.on_shutdown
+    def broken():
+        calls.append('broken')
+        raise RuntimeError("oops")
+
+    @shutdown.on_shutdown
+    def last():
+        calls.append('last')
+
+    # Hooks run in reverse order, and a failing hook doesn't stop the others:
+    shutdown.run_hooks()
+    assert calls == ['last', 'broken', 'first']
+
+    # Only the first call runs them:
+    shutdown.run_hooks()
+    assert calls == ['last', 'broken', 'first']
+
+
+def test_shutdown_phases(monkeypatch):
+    monkeypatch.setattr(shutdown, '_hooks', [])
+    monkeypatch.setattr(shutdown, '_done', False)
+    monkeypatch.setattr(shutdown, '_deadline', None)
+
+    calls = []
+
+    @shutdown.on_shutdown
+    def close_db():
+        calls.append(('close_db', shutdown.stopping()))
+
+    @shutdown.on_shutdown(phase=shutdown.FLUSH)
+    def late_flush():
+        calls.append(('late_flush', shutdown.remaining() > 0))
+
+    @shutdown.on_shutdown(phase=shutdown.FLUSH)
+    def slow_flush():
+        calls.append(('slow_flush', shutdown.remaining() > 0))
+        monkeypatch.setattr(shutdown, '_deadline', shutdown._deadline - 3600)
+
+    @shutdown.on_shutdown(phase=shutdown.INTAKE)
+    def stop_intake():
+        calls.append(('stop_intake', shutdown.stopping()))
+
+    assert not shutdown.stopping()
+    assert shutdown.remaining() is None
+
+    # Phases run in order regardless of registration order; FLUSH hooks not yet started when the
+    # timeout is reached are skipped, but CLOSE hooks still run:
+    shutdown.run_hooks()
+    assert calls == [
+        ('stop_intake', True),
+        ('slow_flush', True),
+        ('close_db', True),
+    ]
+    assert shutdown.remaining() == 0
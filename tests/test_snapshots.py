@@ -0,0 +1,113 @@
+from request import sogs_get, sogs_post, sogs_put, sogs_delete, sogs_post_raw
+from snapshot import assert_snapshot
+from sogs import utils
+from nacl.utils import random
+from util import pad64
+
+
+# Response format snapshots (see tests/snapshot.py) of the endpoints used by Session clients, so
+# that unintentional changes to what clients receive get caught.
+
+
+def _post(client, user, data=b'post', sig='sig', **extra):
+    d, s = (utils.encode_base64(x) for x in (data, pad64(sig)))
+    r = sogs_post(client, "/room/test-room/message", {"data": d, "signature": s, **extra}, user)
+    assert r.status_code == 201
+    return r.json
+
+
+def test_snapshot_general(client, user):
+    assert_snapshot('capabilities', client.get("/capabilities").json)
+
+    r = client.post("/batch", json=[{"method": "GET", "path": "/capabilities"}])
+    assert_snapshot('batch', r.json)
+
+
+def test_snapshot_rooms(client, room, user, mod, admin, no_rate_limit):
+    _post(client, mod)
+    room.pin(1, admin)
+
+    for who, u in (('user', user), ('mod', mod), ('admin', admin)):
+        r = sogs_get(client, "/room/test-room", u)
+        assert r.status_code == 200
+        assert_snapshot(f'room_{who}', r.json)
+
+    assert_snapshot('rooms', sogs_get(client, "/rooms", user).json)
+    assert_snapshot('poll_info', sogs_get(client, "/room/test-room/pollInfo/0", user).json)
+    assert_snapshot('poll_info_mod', sogs_get(client, "/room/test-room/pollInfo/0", mod).json)
+
+
+def test_snapshot_messages(client, room, user, user2, mod, no_rate_limit):
+    filedata = random(256)
+    headers = {"Content-Disposition": ('attachment', {'filename': 'a.bin'})}
+    r = sogs_post_raw(client, "/room/test-room/file", filedata, user, extra_headers=headers)
+    assert r.status_code == 201
+    assert_snapshot('file_upload', r.json)
+    file_id = r.json['id']
+
+    m1 = _post(client, user, b'one', 'sig1', files=[file_id])
+    assert_snapshot('message_posted', m1)
+    m2 = _post(client, user2, b'two', 'sig2')
+    m3 = _post(client, user, b'three', 'sig3')
+    d, s = (utils.encode_base64(x) for x in (b'two!', pad64('sig2e')))
+    r = sogs_put(client, f"/room/test-room/message/{m2['id']}", {"data": d, "signature": s}, user2)
+    assert r.status_code == 200
+    assert_snapshot('message_edited', r.json)
+
+    r = sogs_put(client, f"/room/test-room/reaction/{m2['id']}/🍆", {}, user)
+    assert r.status_code == 200
+    assert_snapshot('reaction_added', r.json)
+    sogs_put(client, f"/room/test-room/reaction/{m2['id']}/🎂", {}, user2)
+    r = sogs_delete(client, f"/room/test-room/reaction/{m2['id']}/🎂", user2)
+    assert r.status_code == 200
+    assert_snapshot('reaction_removed', r.json)
+    r = sogs_get(client, f"/room/test-room/reactors/{m2['id']}/🍆", user)
+    assert_snapshot('reactors', r.json)
+    sogs_put(client, f"/room/test-room/reaction/{m1['id']}/👍", {}, user2)
+
+    r = sogs_delete(client, f"/room/test-room/message/{m3['id']}", user)
+    assert r.status_code == 200
+    assert_snapshot('message_deleted', r.json)
+
+    # Polling returns new, edited, and deleted messages, and reaction-only updates (for m1):
+    assert_snapshot('since', sogs_get(client, "/room/test-room/messages/since/0?t=r", user).json)
+    r = sogs_get(client, f"/room/test-room/messages/since/{m3['seqno']}?t=r", user)
+    assert_snapshot('since_reactions', r.json)
+    for name, url in (
+        ('recent', "/room/test-room/messages/recent"),
+        ('before', f"/room/test-room/messages/before/{m3['id']}"),
+        ('message', f"/room/test-room/message/{m2['id']}"),
+    ):
+        assert_snapshot(name, sogs_get(client, url, user).json)
+    r = sogs_get(client, f"/room/test-room/message/{m2['id']}", mod)
+    assert_snapshot('message_mod', r.json)
+
+
+def test_snapshot_moderation(client, room, user, mod, global_admin):
+    r = sogs_post(
+        client, f"/user/{user.session_id}/ban", {'rooms': ['test-room'], 'timeout': 60}, mod
+    )
+    assert r.status_code == 200
+    assert_snapshot('user_banned', r.json)
+
+    r = sogs_post(client, f"/user/{user.session_id}/unban", {'rooms': ['test-room']}, mod)
+    assert r.status_code == 200
+    assert_snapshot('user_unbanned', r.json)
+
+    r = sogs_post(
+        client,
+        f"/user/{user.session_id}/moderator",
+        {'rooms': ['test-room'], 'moderator': True},
+        global_admin,
+    )
+    assert r.status_code == 200
+    assert_snapshot('moderator_added', r.json)
+
+
+def test_snapshot_dms(client, blind_user, blind_user2):
+    msg = {'message': utils.encode_base64(random(100))}
+    r = sogs_post(client, f"/inbox/{blind_user2.session_id}", msg, blind_user)
+    assert r.status_code == 201
+    assert_snapshot('dm_sent', r.json)
+    assert_snapshot('inbox', sogs_get(client, "/inbox", blind_user2).json)
+    assert_snapshot('outbox', sogs_get(client, "/outbox", blind_user).json)
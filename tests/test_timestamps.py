@@ -0,0 +1,63 @@
+from datetime import datetime
+from request import sogs_get, sogs_post
+from sogs import config, utils
+from sogs.routes.timestamps import add_formats, iso8601
+from util import config_override, pad64
+
+
+def test_add_formats():
+    t = 1672628645.678901
+    assert iso8601(t) == '2023-01-02T03:04:05.678Z'
+    v = {'posted': t, 'edited': None, 'seqno': 12, 'x': [{'created': 1700000000}]}
+    assert add_formats(v) == {
+        'posted': t,
+        'posted_ms': 1672628645678,
+        'posted_iso': '2023-01-02T03:04:05.678Z',
+        'edited': None,
+        'seqno': 12,
+        'x': [
+            {
+                'created': 1700000000,
+                'created_ms': 1700000000000,
+                'created_iso': '2023-11-14T22:13:20.000Z',
+            }
+        ],
+    }
+    # Not timestamps: unlisted keys, booleans, and values out of the plausible range
+    for v in ({'foo': t}, {'at': True}, {'posted': 12345}, {'timestamp_ms': 1672628645678}):
+        assert add_formats(dict(v)) == v
+    # Existing fields are kept:
+    assert add_formats({'timestamp': t, 'timestamp_ms': 1}) == {
+        'timestamp': t,
+        'timestamp_ms': 1,
+        'timestamp_iso': '2023-01-02T03:04:05.678Z',
+    }
+
+
+def test_extended_timestamps(client, room, user, monkeypatch):
+    d, s = (utils.encode_base64(x) for x in (b'hello', pad64('sig')))
+    r = sogs_post(client, "/room/test-room/message", {"data": d, "signature": s}, user)
+    assert r.status_code == 201
+    assert 'posted_ms' not in r.json
+
+    url = "/room/test-room/messages/recent"
+    with config_override(EXTENDED_TIMESTAMPS=True):
+        msgs = sogs_get(client, url, user).json
+        info = sogs_get(client, "/room/test-room", user).json
+        batch = client.post("/batch", json=[{"method": "GET", "path": "/time"}]).json
+    m = msgs[0]
+    assert m['posted_ms'] == int(m['posted'] * 1000)
+    assert m['posted_iso'].endswith('Z')
+    iso = datetime.fromisoformat(m['posted_iso'][:-1] + '+00:00')
+    assert round(iso.timestamp() * 1000) == m['posted_ms']
+    assert info['created_ms'] == int(info['created'] * 1000)
+    assert 'timestamp_iso' in batch[0]['body']
+
+    # Rooms can enable (or disable) it individually:
+    monkeypatch.setitem(config.ROOM_OVERRIDES, room.token, {'extended_timestamps': True})
+    assert 'posted_iso' in sogs_get(client, url, user).json[0]
+    assert 'timestamp_iso' not in client.get("/time").json
+    monkeypatch.setitem(config.ROOM_OVERRIDES, room.token, {'extended_timestamps': False})
+    with config_override(EXTENDED_TIMESTAMPS=True):
+        assert 'posted_iso' not in sogs_get(client, url, user).json[0]
+        assert 'timestamp_iso' in client.get("/time").json
@@ -0,0 +1,55 @@
+import pytest
+from sogs import db, tracing
+from request import sogs_get
+from util import config_override
+
+pytest.importorskip('opentelemetry.sdk')
+
+
+@pytest.fixture
+def spans(monkeypatch):
+    from opentelemetry.sdk.trace import TracerProvider
+    from opentelemetry.sdk.trace.export import SimpleSpanProcessor
+    from opentelemetry.sdk.trace.export.in_memory_span_exporter import InMemorySpanExporter
+
+    exporter = InMemorySpanExporter()
+    provider = TracerProvider()
+    provider.add_span_processor(SimpleSpanProcessor(exporter))
+    monkeypatch.setattr(tracing, '_tracer', provider.get_tracer('test'))
+    with config_override(OTLP_ENDPOINT='http://localhost:4318'):
+        tracing.instrument_engine(db.engine)
+    return exporter
+
+
+def test_request_tracing(client, room, user, spans):
+    from opentelemetry.trace import SpanKind
+
+    r = sogs_get(client, "/room/test-room", user)
+    assert r.status_code == 200
+
+    finished = spans.get_finished_spans()
+    (req,) = [s for s in finished if s.kind == SpanKind.SERVER]
+    assert req.name == 'GET /room/<Room:room>'
+    assert req.attributes['http.route'] == '/room/<Room:room>'
+    assert req.attributes['http.status_code'] == 200
+    assert len(req.attributes['sogs.request_id']) == 16
+
+    queries = [s for s in finished if s.name == 'db.query']
+    assert queries
+    assert all(q.parent.span_id == req.context.span_id for q in queries)
+    assert all(q.context.trace_id == req.context.trace_id for q in queries)
+
+    # Continues the trace of a caller's traceparent:
+    spans.clear()
+    trace_id, parent_id = 'ab' * 16, 'cd' * 8
+    r = client.get("/capabilities", headers={'traceparent': f'00-{trace_id}-{parent_id}-01'})
+    assert r.status_code == 200
+    (req,) = [s for s in spans.get_finished_spans() if s.kind == SpanKind.SERVER]
+    assert req.context.trace_id == int(trace_id, 16)
+    assert req.parent.span_id == int(parent_id, 16)
+
+    # Doesn't record anything when not sampled by the caller:
+    spans.clear()
+    r = client.get("/capabilities", headers={'traceparent': f'00-{trace_id}-{parent_id}-00'})
+    assert r.status_code == 200
+    assert spans.get_finished_spans() == ()
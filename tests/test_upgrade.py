@@ -0,0 +1,71 @@
+import io
+import json
+import pytest
+import urllib.request
+from request import sogs_get
+from util import config_override
+from sogs import outbound, upgrade
+
+
+def test_parse_version():
+    v = upgrade.parse_version
+    assert v('0.3.8.dev0') < v('0.3.8') < v('0.3.8.1') < v('0.3.9') < v('0.10') < v('1.0')
+    assert v('v1.0') == v('1') == v('1.0.0')
+    with pytest.raises(ValueError):
+        v('latest')
+
+
+def test_upgrade_check(client, user, global_admin, monkeypatch):
+    outbound.reset()
+    feed = {
+        'releases': [
+            {'version': '0.3.8', 'url': 'https://example.org/0.3.8'},
+            {'version': '0.3.9', 'security': True},
+            {'version': '0.4.0', 'url': 'https://example.org/0.4.0'},
+            {'version': '0.3.7', 'security': True},
+        ]
+    }
+    monkeypatch.setattr(
+        urllib.request, 'urlopen', lambda req, timeout: io.BytesIO(json.dumps(feed).encode())
+    )
+
+    # Disabled by default:
+    assert upgrade.check() is None
+    assert upgrade.status() == {'enabled': False, 'current': upgrade.__version__}
+    assert [r['version'] for r in upgrade.newer_releases(feed, '0.3.8')] == ['0.4.0', '0.3.9']
+
+    url = 'https://releases.example/sogs.json'
+    with config_override(UPGRADE_FEED_URL=url):
+        monkeypatch.setattr(upgrade, '__version__', '0.3.8')
+        assert upgrade.check() == '0.4.0'
+
+        assert sogs_get(client, '/admin/upgrade', user).status_code == 403
+        r = sogs_get(client, '/admin/upgrade', global_admin)
+        assert r.status_code == 200
+        assert r.json.pop('checked') > 0
+        assert r.json == {
+            'enabled': True,
+            'current': '0.3.8',
+            'latest': '0.4.0',
+            'security': True,
+            'url': 'https://example.org/0.4.0',
+            'error': None,
+        }
+
+        # A failed check keeps the last result:
+        monkeypatch.setattr(urllib.request, 'urlopen', lambda req, timeout: io.BytesIO(b'junk'))
+        with pytest.raises(ValueError):
+            upgrade.check()
+        st = upgrade.status()
+        assert st['latest'] == '0.4.0'
+        assert st['error']
+
+        feed['releases'] = feed['releases'][:1]
+        monkeypatch.setattr(
+            urllib.request, 'urlopen', lambda req, timeout: io.BytesIO(json.dumps(feed).encode())
+        )
+        assert upgrade.check() == ''
+        st = upgrade.status()
+        assert (st['latest'], st['security'], st['url'], st['error']) == (None, False, None, None)
+
+    outbound.reset()
@@ -1,6 +1,6 @@
 from sogs import db, utils
-from request import sogs_get, sogs_post
-from util import pad64
+from request import sogs_get, sogs_post, sogs_delete
+from util import pad64, config_override
 import time
 
 
@@ -766,3 +766,69 @@ def test_ban_timeouts(client, room, room2, user, mod, global_mod):
 
     r = sogs_post(client, "/room/test-room/message", post, user)
     assert r.status_code == 201
+
+
+def test_mod_digest(client, room, user, user2, mod, monkeypatch, tmp_path, no_rate_limit):
+    import sogs.digest
+    from sogs.model.report import file_report
+
+    assert sogs_post(client, '/user/digest', {'email': 'mod@example.com'}, mod).status_code == 404
+
+    with config_override(
+        DIGEST_SMTP_HOST='localhost',
+        DIGEST_INTERVAL=0,
+        DIGEST_FLOOD_POSTS=2,
+        JOURNAL_PATH=str(tmp_path / 'events.jsonl'),
+    ):
+        r = sogs_post(client, '/user/digest', {'email': 'user@example.com'}, user)
+        assert r.status_code == 403
+        r = sogs_post(client, '/user/digest', {'email': 'not an email'}, mod)
+        assert r.status_code == 400
+
+        r = sogs_post(client, '/user/digest', {'email': 'mod@example.com'}, mod)
+        assert r.status_code == 200
+        assert r.json['email'] == 'mod@example.com'
+        assert r.json['last_sent'] is None
+
+        msg = room.add_post(user, b'data', pad64(b'sig'))
+        file_report(room, user2, msg['id'], 'spam')
+        for i in range(3):
+            room.add_post(user2, f'flood {i}'.encode(), pad64(f'sig {i}'.encode()))
+        room.ban_user(user2, mod=mod)
+
+        sent = []
+        monkeypatch.setattr(sogs.digest, 'send_email', lambda *args: sent.append(args))
+        assert sogs.digest.send_digests() == 1
+        assert len(sent) == 1
+        to, subject, body = sent[0]
+        assert to == 'mod@example.com'
+        assert f"{room.name} ({room.token}):" in body
+        assert "New posts: 4" in body
+        assert "Open reports: 1 (1 new, 0 resolved)" in body
+        assert "Floods: 1 (from 1 users)" in body
+        assert f"user banned {user2.session_id} by {mod.session_id}" in body
+
+        r = sogs_get(client, '/user/digest', mod)
+        assert r.status_code == 200
+        assert r.json['last_sent'] is not None
+
+        r = sogs_delete(client, '/user/digest', mod)
+        assert r.json == {'unsubscribed': True}
+        assert sogs_get(client, '/user/digest', mod).status_code == 404
+        assert sogs.digest.send_digests() == 0
+
+
+def test_list_bans(client, room, room2, user, user2, mod, global_admin):
+    room.ban_user(user, mod=global_admin)
+    room2.ban_user(user2, mod=global_admin, timeout=60)
+    user2.ban(banned_by=global_admin)
+
+    assert sogs_get(client, '/admin/bans', mod).status_code == 403
+    r = sogs_get(client, '/admin/bans', global_admin)
+    assert [(b['session_id'], b['room']) for b in r.json] == [
+        (user2.session_id, None),
+        (user2.session_id, room2.token),
+        (user.session_id, room.token),
+    ]
+    assert r.json[0]['until'] is None and r.json[2]['until'] is None
+    assert time.time() < r.json[1]['until'] <= time.time() + 60
@@ -0,0 +1,59 @@
+from request import sogs_get, sogs_post, sogs_delete
+from util import config_override
+from sogs import utils
+from sogs.model.post import Post
+from sogs.model.user import SystemUser
+from nacl.signing import VerifyKey
+
+
+def test_webhooks(client, room, room2, user, mod, admin, no_rate_limit):
+    url = f"/room/{room.token}/webhooks"
+    assert sogs_post(client, url, {'display_name': 'CI'}, mod).status_code == 403
+    assert sogs_post(client, url, {}, admin).status_code == 400
+
+    with config_override(URL_BASE='https://sogs.example'):
+        r = sogs_post(client, url, {'display_name': 'CI'}, admin)
+    assert r.status_code == 201
+    hook = r.json
+    assert hook['display_name'] == 'CI'
+    assert hook['last_used'] is None
+    assert hook['url'].startswith('https://sogs.example/webhook/')
+    hook_url = hook.pop('url')[len('https://sogs.example') :]
+
+    assert sogs_get(client, url, mod).status_code == 403
+    assert sogs_get(client, url, admin).json == [hook]
+
+    r = client.post(hook_url, json={'text': 'Build #123 passed'})
+    assert r.status_code == 201
+    msg_id = r.json['id']
+    r = client.post(hook_url, json={'text': 'Build #124 failed', 'display_name': 'CI (main)'})
+    assert r.status_code == 201
+
+    msgs = sogs_get(client, f"/room/{room.token}/messages/recent", user).json
+    assert [m['id'] for m in msgs] == [msg_id + 1, msg_id]
+    expected = [('Build #124 failed', 'CI (main)'), ('Build #123 passed', 'CI')]
+    for m, (text, name) in zip(msgs, expected):
+        assert m['session_id'] == hook['session_id']
+        data = utils.decode_base64(m['data'])
+        VerifyKey(bytes.fromhex(m['session_id'][2:])).verify(
+            data, utils.decode_base64(m['signature'])
+        )
+        post = Post(raw=data)
+        assert (post.text, post.username) == (text, name)
+
+    assert sogs_get(client, url, admin).json[0]['last_used'] is not None
+
+    assert client.post(hook_url, json={}).status_code == 400
+    assert client.post(hook_url, json={'text': 'hi', 'display_name': 42}).status_code == 400
+    assert client.post('/webhook/not-a-real-secret', json={'text': 'hi'}).status_code == 404
+
+    # Can't delete via another room:
+    other = f"/room/{room2.token}/webhook/{hook['id']}"
+    room2.set_moderator(admin, added_by=SystemUser(), admin=True)
+    assert sogs_delete(client, other, admin).status_code == 404
+
+    assert sogs_delete(client, f"/room/{room.token}/webhook/{hook['id']}", mod).status_code == 403
+    r = sogs_delete(client, f"/room/{room.token}/webhook/{hook['id']}", admin)
+    assert r.status_code == 200
+    assert sogs_get(client, url, admin).json == []
+    assert client.post(hook_url, json={'text': 'hi'}).status_code == 404